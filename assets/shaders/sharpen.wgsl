@@ -0,0 +1,39 @@
+// Sharpens the incoming stream while it's scaled up to the stream window.
+// A light take on contrast adaptive sharpening: the flatter the neighbourhood,
+// the more it's sharpened, so the edges don't get halos.
+#import bevy_ui::ui_vertex_output::UiVertexOutput
+
+struct SharpenSettings {
+    // x - strength, yz - size of a texel of the stream
+    params: vec4<f32>,
+}
+
+@group(1) @binding(0) var<uniform> settings: SharpenSettings;
+@group(1) @binding(1) var stream_texture: texture_2d<f32>;
+@group(1) @binding(2) var stream_sampler: sampler;
+
+fn sample(uv: vec2<f32>) -> vec3<f32> {
+    return textureSample(stream_texture, stream_sampler, uv).rgb;
+}
+
+@fragment
+fn fragment(in: UiVertexOutput) -> @location(0) vec4<f32> {
+    // Mirrored like the plain image
+    let uv = vec2<f32>(1.0 - in.uv.x, in.uv.y);
+    let texel = settings.params.yz;
+
+    let center = sample(uv);
+    let up = sample(uv - vec2<f32>(0.0, texel.y));
+    let down = sample(uv + vec2<f32>(0.0, texel.y));
+    let left = sample(uv - vec2<f32>(texel.x, 0.0));
+    let right = sample(uv + vec2<f32>(texel.x, 0.0));
+
+    let lowest = min(center, min(min(up, down), min(left, right)));
+    let highest = max(center, max(max(up, down), max(left, right)));
+    let contrast = clamp(highest - lowest, vec3<f32>(0.0), vec3<f32>(1.0));
+    let amount = settings.params.x * (vec3<f32>(1.0) - contrast);
+
+    let blurred = (up + down + left + right) * 0.25;
+    let sharpened = center + (center - blurred) * amount;
+    return vec4<f32>(clamp(sharpened, vec3<f32>(0.0), vec3<f32>(1.0)), 1.0);
+}
@@ -0,0 +1,231 @@
+//! Statistics of the call that just ended, shown in a panel once it's over.
+//! The panel can export them as JSON, to attach to a bug report.
+//! See `eye_spy_core::stats::CallSummary` for what's counted.
+
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use eye_spy_core::stats::{CallSummary, StreamSummary};
+
+use crate::plugin::CallManagerBevy;
+use crate::ui::UiSpawner;
+
+const SUMMARY_FONT_SIZE: f32 = 24.;
+
+pub struct CallSummaryPlugin;
+
+impl Plugin for CallSummaryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ShownCallSummary>();
+        app.add_systems(PostStartup, spawn_call_summary_panel);
+        app.add_systems(
+            Update,
+            (
+                show_call_summary,
+                check_export_summary_button,
+                check_close_summary_button,
+            )
+                .chain(),
+        );
+    }
+}
+
+/// Directory the summaries are exported to
+#[derive(Resource, Debug, Default, Clone)]
+pub struct CallSummaryExport(pub PathBuf);
+
+/// The summary in the panel, so it's only shown once for every call
+#[derive(Resource, Default)]
+struct ShownCallSummary(Option<CallSummary>);
+
+#[derive(Component)]
+struct CallSummaryPanel;
+
+#[derive(Component)]
+struct CallSummaryText;
+
+/// Where the summary was exported to
+#[derive(Component)]
+struct CallSummaryExportText;
+
+#[derive(Component)]
+struct ExportSummaryButton;
+
+#[derive(Component)]
+struct CloseSummaryButton;
+
+/// i.e. 1.2 Mbit/s or 850 kbit/s
+fn format_bitrate(bits_per_second: u64) -> String {
+    if bits_per_second >= 1_000_000 {
+        format!("{:.1} Mbit/s", bits_per_second as f64 / 1e6)
+    } else {
+        format!("{} kbit/s", bits_per_second / 1000)
+    }
+}
+
+fn describe_stream(stream: &StreamSummary) -> String {
+    let mut line = format!(
+        "{} average, {} peak, {} packets",
+        format_bitrate(stream.average_bitrate),
+        format_bitrate(stream.peak_bitrate),
+        stream.packets
+    );
+    if let Some(lost) = stream.packets_lost {
+        let share = lost as f64 * 100. / (stream.packets + lost).max(1) as f64;
+        line.push_str(&format!(", {lost} lost ({share:.1}%)"));
+    }
+    line
+}
+
+/// The summary as lines of text for the panel
+fn describe(summary: &CallSummary) -> String {
+    let seconds = summary.duration_secs as u64;
+    [
+        format!("Duration: {}:{:02}", seconds / 60, seconds % 60),
+        format!("Sent: {}", describe_stream(&summary.sent)),
+        format!("Received: {}", describe_stream(&summary.received)),
+        format!("Decoder resets: {}", summary.decoder_resets),
+        format!("Resolution changes: {}", summary.resolution_changes),
+    ]
+    .join("\n")
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn spawn_call_summary_panel(mut spawner: UiSpawner) {
+    let title = spawner
+        .spawn_translated_text("call_summary", SUMMARY_FONT_SIZE)
+        .id();
+    let text = spawner
+        .spawn_pretty_text("", SUMMARY_FONT_SIZE)
+        .insert(CallSummaryText)
+        .id();
+    let export_text = spawner
+        .spawn_pretty_text("", SUMMARY_FONT_SIZE)
+        .insert(CallSummaryExportText)
+        .id();
+    let mut export = spawner.spawn_translated_button("export_json", SUMMARY_FONT_SIZE);
+    export.insert(ExportSummaryButton);
+    let export = export.id();
+    let mut close = spawner.spawn_translated_button("close", SUMMARY_FONT_SIZE);
+    close.insert(CloseSummaryButton);
+    let close = close.id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Percent(20.),
+                left: Val::Percent(30.),
+                width: Val::Percent(40.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            6,
+        )
+        .insert(CallSummaryPanel)
+        .push_children(&[title, text, export_text, export, close]);
+}
+/// `CallManager` sums the call up once it's over, whoever ended it
+fn show_call_summary(
+    call: Res<CallManagerBevy>,
+    mut shown: ResMut<ShownCallSummary>,
+    mut panels: Query<&mut Style, With<CallSummaryPanel>>,
+    mut texts: Query<&mut Text, With<CallSummaryText>>,
+    mut export_texts: Query<&mut Text, (With<CallSummaryExportText>, Without<CallSummaryText>)>,
+) {
+    let Some(summary) = call.0.last_call_summary() else {
+        return;
+    };
+    if shown.0.as_ref() == Some(summary) {
+        return;
+    }
+    shown.0 = Some(summary.clone());
+    for mut text in &mut texts {
+        text.sections[0].value = describe(summary);
+    }
+    for mut text in &mut export_texts {
+        text.sections[0].value.clear();
+    }
+    for mut style in &mut panels {
+        style.display = Display::Flex;
+    }
+}
+fn check_export_summary_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<ExportSummaryButton>)>,
+    shown: Res<ShownCallSummary>,
+    export: Res<CallSummaryExport>,
+    mut texts: Query<&mut Text, With<CallSummaryExportText>>,
+) {
+    if !query
+        .iter()
+        .any(|interaction| interaction == &Interaction::Pressed)
+    {
+        return;
+    }
+    let Some(ref summary) = shown.0 else {
+        return;
+    };
+    let path = export.0.join(format!(
+        "call-summary-{}.json",
+        eye_spy_core::av_sync::timestamp_now() / 1_000_000
+    ));
+    let message = match std::fs::create_dir_all(&export.0)
+        .and_then(|()| std::fs::write(&path, summary.to_json()))
+    {
+        Ok(()) => {
+            info!("Exported the call summary to {}", path.display());
+            format!("Exported to {}", path.display())
+        }
+        Err(e) => {
+            warn!("Cannot export the call summary: {e}");
+            format!("Cannot export: {e}")
+        }
+    };
+    for mut text in &mut texts {
+        text.sections[0].value = message.clone();
+    }
+}
+fn check_close_summary_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<CloseSummaryButton>)>,
+    mut panels: Query<&mut Style, With<CallSummaryPanel>>,
+) {
+    if !query
+        .iter()
+        .any(|interaction| interaction == &Interaction::Pressed)
+    {
+        return;
+    }
+    for mut style in &mut panels {
+        style.display = Display::None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eye_spy_core::stats::{CallSummary, StreamSummary};
+
+    use super::{describe, format_bitrate};
+
+    #[test]
+    fn test_describe_summary() {
+        assert_eq!(format_bitrate(850_000), "850 kbit/s");
+        assert_eq!(format_bitrate(1_260_000), "1.3 Mbit/s");
+        let summary = CallSummary {
+            duration_secs: 65.4,
+            received: StreamSummary {
+                packets: 990,
+                packets_lost: Some(10),
+                ..Default::default()
+            },
+            decoder_resets: 1,
+            ..Default::default()
+        };
+        let text = describe(&summary);
+        assert!(text.starts_with("Duration: 1:05\n"));
+        assert!(text.contains("990 packets, 10 lost (1.0%)"));
+        assert!(text.contains("Decoder resets: 1"));
+    }
+}
@@ -0,0 +1,747 @@
+//! Command line arguments of the app, parsed with clap.
+//! The first argument may also be one of the subcommands of `Command`, which run without
+//! the window, i.e. `eye-spy call 192.168.1.12:60102`. The flags go along with them.
+//! A bad argument is reported with the usage and the app exits, rather than open the window.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{CommandFactory, Parser, Subcommand};
+use eye_spy_core::mdns::Advertisement;
+use eye_spy_core::scp_client::link_local;
+use eye_spy_core::{
+    AutoAnswer, CodecBackend, Dscp, Invite, MonthlyCap, MotionMode, RtpConfig, SocketTuning,
+    StillFallback, VideoEncoding,
+};
+use tracing::Level;
+
+/// What the app does without the window, see `crate::headless`
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Print the hosts found over mDNS
+    List,
+    /// Call the peer until either side hangs up
+    Call {
+        /// ip:port of the peer, or an invite
+        #[arg(value_parser = peer_addr)]
+        addr: SocketAddr,
+    },
+    /// Answer the calls by itself until stopped, given --auto or --auto-answer
+    Answer,
+    /// Call the peer and record the video of the call into the MP4 file, there's no audio yet
+    Record {
+        /// ip:port of the peer, or an invite
+        #[arg(value_parser = peer_addr)]
+        addr: SocketAddr,
+        out: PathBuf,
+    },
+    /// Answer like `answer`, as a systemd service
+    Daemon,
+    /// Save the hosts found and the static peers into the JSON file,
+    /// for other machines to import with --import-peers
+    ExportPeers { out: PathBuf },
+}
+
+/// Arguments the app was started with. The docs of the fields are the help of the flags
+#[derive(Debug, Clone, Parser)]
+#[command(name = "eye-spy", version, about = "Video calls over the LAN")]
+pub struct Args {
+    /// Run without the window. None opens the window
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Invite to call right after the start, the app may be opened with an invite link
+    #[arg(value_name = "INVITE", value_parser = invite)]
+    link: Option<Invite>,
+    /// Name of this instance, lets multiple apps run side by side on one machine
+    #[arg(long, global = true)]
+    pub instance: Option<String>,
+    /// Advertise and look for the peers under this mDNS service only,
+    /// i.e. `_eye-spy-dev._tcp.local.` so a test cluster stays apart from the other clients
+    #[arg(long, global = true, value_name = "SERVICE")]
+    pub mdns_service: Option<String>,
+    /// Also advertise and look for the peers with UDP broadcast beacons on this port,
+    /// on networks that filter the multicast of mDNS
+    #[arg(long, global = true, value_name = "PORT", value_parser = positive::<u16>)]
+    pub beacon_port: Option<u16>,
+    /// JSON file of static peers to add to the saved ones, i.e. one handed out by an admin,
+    /// see `export-peers`
+    #[arg(long, global = true, value_name = "FILE")]
+    pub import_peers: Option<PathBuf>,
+    /// The most verbose level that gets logged
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<Level>,
+    /// Call yourself right after the start
+    #[arg(long, global = true)]
+    pub loopback: bool,
+    /// `.h264` file sent in place of the camera
+    #[arg(long, global = true, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+    /// Directory to dump the received streams into
+    #[arg(long, global = true, value_name = "DIR")]
+    pub dump: Option<PathBuf>,
+    /// Address to serve the Prometheus metrics on
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub metrics: Option<SocketAddr>,
+    /// Invite to call right after the start
+    #[arg(long = "call", global = true, value_name = "INVITE", value_parser = invite)]
+    call: Option<Invite>,
+    /// Answer the calls by itself, anyone's, or only the ones of the peers listed
+    /// as `--auto-answer=<ip or fingerprint>,...`
+    #[arg(
+        long,
+        visible_alias = "auto",
+        global = true,
+        value_name = "PEERS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "",
+        value_parser = auto_answer
+    )]
+    pub auto_answer: Option<AutoAnswer>,
+    /// kbit/s the camera mode is picked for
+    #[arg(long, global = true, value_name = "KBPS", value_parser = positive::<u32>)]
+    pub max_bitrate: Option<u32>,
+    /// Custom label the watermark of the outgoing video can show
+    #[arg(long, global = true, value_name = "LABEL")]
+    pub watermark: Option<String>,
+    /// Look for motion in front of the camera and report it,
+    /// `--motion=gate` also sends the video only while there's motion
+    #[arg(
+        long,
+        global = true,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "report",
+        value_parser = motion
+    )]
+    pub motion: Option<MotionMode>,
+    /// Bytes of video in a stream packet, in place of probing the path to the peer
+    #[arg(long, global = true, value_name = "BYTES", value_parser = positive::<usize>)]
+    pub packet_size: Option<usize>,
+    /// Frames between the keyframes sent, 0 leaves them to the encoder
+    #[arg(long, global = true, value_name = "FRAMES")]
+    pub keyframe_interval: Option<u32>,
+    /// KB of the largest NAL unit taken from the peers, larger ones are dropped
+    #[arg(long, global = true, value_name = "KB", value_parser = positive::<usize>)]
+    pub max_nal_size: Option<usize>,
+    /// KB of the send buffer of the stream sockets
+    #[arg(long, global = true, value_name = "KB", value_parser = positive::<usize>)]
+    send_buffer: Option<usize>,
+    /// KB of the receive buffer of the stream sockets
+    #[arg(long, global = true, value_name = "KB", value_parser = positive::<usize>)]
+    recv_buffer: Option<usize>,
+    /// DSCP class the video is marked with
+    #[arg(long, global = true, ignore_case = true, value_parser = ["ef", "af41", "off"])]
+    dscp: Option<String>,
+    /// Backend the video is encoded with, the ones not built in fall back to OpenH264
+    #[arg(
+        long,
+        global = true,
+        value_name = "BACKEND",
+        default_value = "openh264",
+        value_parser = codec_backend
+    )]
+    pub encoder: CodecBackend,
+    /// Backend the video is decoded with, the ones not built in fall back to OpenH264
+    #[arg(
+        long,
+        global = true,
+        value_name = "BACKEND",
+        default_value = "openh264",
+        value_parser = codec_backend
+    )]
+    pub decoder: CodecBackend,
+    /// Encoding the video is preferably taken in, h264, vp8 or av1. VP8 needs the `vp8` feature
+    /// and libvpx, AV1, the low-bitrate mode, the `av1` feature with rav1e and dav1d
+    #[arg(long, global = true, value_name = "CODEC", value_parser = video_codec)]
+    pub video_codec: Option<VideoEncoding>,
+    /// Seconds a call rings before it ends as not answered
+    #[arg(long, global = true, value_name = "SECS", value_parser = seconds)]
+    pub ring_timeout: Option<Duration>,
+    /// `.h264` file shared as the screen along with the video, there's no screen capture yet
+    #[arg(long, global = true, value_name = "FILE")]
+    pub share_screen: Option<PathBuf>,
+    /// Record the video of every call into this directory, side by side.
+    /// The layout can be changed in the settings
+    #[arg(long, global = true, value_name = "DIR")]
+    pub record: Option<PathBuf>,
+    /// MB the calls may use in a month, i.e. on a metered connection.
+    /// The user is told as the month nears it
+    #[arg(long, global = true, value_name = "MB", value_parser = positive::<u64>)]
+    pub monthly_cap: Option<u64>,
+    /// Also cut the video down as the month nears the cap
+    #[arg(long, global = true)]
+    pub downgrade_at_cap: bool,
+    /// Republish the received video as RTP to this address, i.e. for VLC, OBS or an NVR
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub rtp: Option<SocketAddr>,
+    /// Republish the video sent to the peer instead
+    #[arg(long, global = true)]
+    pub rtp_local: bool,
+    /// Serve the page a browser joins the calls from here. A port alone is served on localhost only
+    #[cfg(feature = "webrtc")]
+    #[arg(long, global = true, value_name = "ADDR", value_parser = local_addr)]
+    pub webrtc: Option<SocketAddr>,
+    /// The incoming frames are uploaded to the GPU this many times a second at most
+    #[arg(long, global = true, value_name = "FPS", value_parser = positive::<u32>)]
+    pub upload_fps: Option<u32>,
+    /// MB of the incoming frames uploaded to the GPU a second at most
+    #[arg(long, global = true, value_name = "MB", value_parser = positive::<u64>)]
+    pub upload_budget: Option<u64>,
+    /// kbit/s of the peer's video below which it's asked for still images
+    /// sent over SCP instead, 0 never to ask
+    #[arg(long, global = true, value_name = "KBPS")]
+    pub still_threshold: Option<u32>,
+    /// Seconds between the still images sent to the peers asking for them
+    #[arg(long, global = true, value_name = "SECS", value_parser = seconds)]
+    pub still_interval: Option<Duration>,
+    /// Directory the files sent by the peers are saved into, `<config dir>/downloads` unless given
+    #[arg(long, global = true, value_name = "DIR")]
+    pub downloads: Option<PathBuf>,
+}
+
+impl Args {
+    /// Parses the arguments of the current process. A bad one is reported along with the usage
+    /// and the app exits with code 2, so it doesn't open the window as if nothing was asked for.
+    /// The help and the version are printed and exited on too
+    pub fn parse() -> Self {
+        match Self::try_parse() {
+            Ok(args) => args,
+            Err(e) if e.use_stderr() => {
+                eprint!("{}", report(&e));
+                std::process::exit(e.exit_code());
+            }
+            Err(e) => e.exit(),
+        }
+    }
+    /// Directory the app keeps its state in, i.e. the identity.
+    /// Every instance gets its own, so instances on one machine can call each other
+    pub fn config_dir(&self) -> PathBuf {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .unwrap_or_else(std::env::temp_dir);
+        let dir = base.join("eye-spy");
+        match self.instance {
+            Some(ref instance) => dir.join(instance),
+            None => dir,
+        }
+    }
+    /// Invite to call right after the start, given as `--call <invite>` or on its own
+    pub fn call(&self) -> Option<&Invite> {
+        self.call.as_ref().or(self.link.as_ref())
+    }
+    /// The socket buffers of `--send-buffer` and `--recv-buffer` and the class of `--dscp`,
+    /// the defaults unless given
+    pub fn socket_tuning(&self) -> SocketTuning {
+        let default = SocketTuning::default();
+        let bytes = |kilobytes: usize| kilobytes.saturating_mul(1024);
+        SocketTuning {
+            send_buffer: self.send_buffer.map(bytes).or(default.send_buffer),
+            recv_buffer: self.recv_buffer.map(bytes).or(default.recv_buffer),
+            dscp: match self.dscp {
+                // `off` leaves them unmarked
+                Some(ref name) => Dscp::from_name(name),
+                None => default.dscp,
+            },
+        }
+    }
+    /// The cap of `monthly_cap`, if one was given
+    pub fn monthly_cap(&self) -> Option<MonthlyCap> {
+        self.monthly_cap.map(|megabytes| MonthlyCap {
+            bytes: megabytes.saturating_mul(1_000_000),
+            downgrade: self.downgrade_at_cap,
+        })
+    }
+    /// When the still images stand in for the video, the defaults unless given
+    pub fn still_fallback(&self) -> StillFallback {
+        let default = StillFallback::default();
+        StillFallback {
+            threshold: self.still_threshold.unwrap_or(default.threshold),
+            interval: self.still_interval.unwrap_or(default.interval),
+        }
+    }
+    /// The stream republished by `rtp`, if an address was given, its SDP saved into `sdp_path`
+    pub fn rtp(&self, sdp_path: PathBuf) -> Option<RtpConfig> {
+        self.rtp.map(|addr| RtpConfig {
+            addr,
+            local: self.rtp_local,
+            sdp_path: Some(sdp_path),
+        })
+    }
+    /// The profiles this client is advertised with over mDNS, the ones saved by name unless
+    /// `mdns_service` replaces them
+    pub fn advertisements(&self, profiles: BTreeMap<String, Advertisement>) -> Vec<Advertisement> {
+        match self.mdns_service {
+            Some(ref service) => vec![Advertisement {
+                service: service.clone(),
+                ..Default::default()
+            }],
+            None => profiles.into_values().collect(),
+        }
+    }
+}
+
+/// The error `e` with the usage after it, if it doesn't show it already
+fn report(e: &clap::Error) -> String {
+    let mut message = e.render().to_string();
+    if !message.contains("Usage:") {
+        message.push_str(&format!("\n{}\n", Args::command().render_usage()));
+    }
+    message
+}
+
+/// A number above 0
+fn positive<T: FromStr + Default + PartialEq>(value: &str) -> Result<T, String> {
+    match value.parse() {
+        Ok(number) if number != T::default() => Ok(number),
+        _ => Err("expected a number above 0".to_string()),
+    }
+}
+
+/// Whole seconds, above 0
+fn seconds(value: &str) -> Result<Duration, String> {
+    positive(value).map(Duration::from_secs)
+}
+
+fn invite(value: &str) -> Result<Invite, String> {
+    value.parse().map_err(|e: anyhow::Error| e.to_string())
+}
+
+/// ip:port of the peer, a link-local one with its interface, or an invite to it
+fn peer_addr(value: &str) -> Result<SocketAddr, String> {
+    if Invite::is_invite(value) {
+        return invite(value).map(|invite| invite.addr);
+    }
+    link_local::parse_addr(value).ok_or_else(|| "expected ip:port or an invite".to_string())
+}
+
+/// Comma separated IPs and identity fingerprints, anyone if there are none
+fn auto_answer(list: &str) -> Result<AutoAnswer, String> {
+    let (mut ips, mut fingerprints) = (Vec::new(), Vec::new());
+    for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.parse() {
+            Ok(ip) => ips.push(ip),
+            Err(_) => fingerprints.push(entry.to_string()),
+        }
+    }
+    if ips.is_empty() && fingerprints.is_empty() {
+        return Ok(AutoAnswer::Anyone);
+    }
+    Ok(AutoAnswer::Allowlist { ips, fingerprints })
+}
+
+fn motion(value: &str) -> Result<MotionMode, String> {
+    match value {
+        "report" => Ok(MotionMode::Report),
+        "gate" => Ok(MotionMode::Gate),
+        _ => Err("expected report or gate".to_string()),
+    }
+}
+
+fn codec_backend(value: &str) -> Result<CodecBackend, String> {
+    match CodecBackend::from_name(value) {
+        // Pass-through is what the replayed files are sent as, not a choice,
+        // and the backends of VP8 and AV1 are picked along with them
+        Some(backend @ (CodecBackend::OpenH264 | CodecBackend::X264)) => Ok(backend),
+        _ => Err("expected openh264 or x264".to_string()),
+    }
+}
+
+fn video_codec(value: &str) -> Result<VideoEncoding, String> {
+    VideoEncoding::from_name(value).ok_or_else(|| "expected h264, vp8 or av1".to_string())
+}
+
+/// An address, or a port alone on localhost, so it's only reachable from elsewhere when asked for
+#[cfg(feature = "webrtc")]
+fn local_addr(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse() {
+        return Ok(addr);
+    }
+    match value.parse() {
+        Ok(port) => Ok(SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, port))),
+        Err(_) => Err("expected ip:port or a port".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use clap::error::ErrorKind;
+    use clap::Parser;
+    use eye_spy_core::mdns::Advertisement;
+    use eye_spy_core::{
+        AutoAnswer, CodecBackend, Dscp, MonthlyCap, MotionMode, RtpConfig, SocketTuning,
+        StillFallback, VideoEncoding,
+    };
+    use tracing::Level;
+
+    use super::{report, Args, Command};
+
+    fn try_parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("eye-spy").chain(args.iter().copied()))
+    }
+    fn parse(args: &[&str]) -> Args {
+        try_parse(args).unwrap()
+    }
+    fn rejected(args: &[&str]) -> bool {
+        try_parse(args).is_err()
+    }
+    #[test]
+    fn test_instance_flag() {
+        assert_eq!(
+            parse(&["--instance", "alice"]).instance.as_deref(),
+            Some("alice")
+        );
+        assert_eq!(parse(&["--instance=bob"]).instance.as_deref(), Some("bob"));
+        assert!(parse(&[]).instance.is_none());
+    }
+    #[test]
+    fn test_log_level_flag() {
+        assert_eq!(
+            parse(&["--log-level", "debug"]).log_level,
+            Some(Level::DEBUG)
+        );
+        assert_eq!(parse(&["--log-level=WARN"]).log_level, Some(Level::WARN));
+        assert!(rejected(&["--log-level", "loud"]));
+    }
+    #[test]
+    fn test_loopback_flag() {
+        assert!(parse(&["--loopback"]).loopback);
+        assert!(!parse(&["--instance", "a"]).loopback);
+    }
+    #[test]
+    fn test_replay_and_dump_flags() {
+        let args = parse(&[
+            "--replay",
+            "call.h264",
+            "--dump=dumps",
+            "--share-screen=slides.h264",
+            "--record",
+            "calls",
+            "--downloads=received",
+        ]);
+        assert_eq!(args.replay, Some(PathBuf::from("call.h264")));
+        assert_eq!(args.dump, Some(PathBuf::from("dumps")));
+        assert_eq!(args.share_screen, Some(PathBuf::from("slides.h264")));
+        assert_eq!(args.record, Some(PathBuf::from("calls")));
+        assert_eq!(args.downloads, Some(PathBuf::from("received")));
+    }
+    #[test]
+    fn test_metrics_flag() {
+        assert_eq!(
+            parse(&["--metrics", "0.0.0.0:9100"]).metrics,
+            Some("0.0.0.0:9100".parse().unwrap())
+        );
+        assert!(rejected(&["--metrics=9100"]));
+    }
+    #[test]
+    fn test_call_invite() {
+        let invite = "eyespy://192.168.1.12:60102?name=Alice";
+        for args in [
+            &["--call", invite][..],
+            &[invite],
+            &["--call=eyespy://192.168.1.12:60102?name=Alice"],
+        ] {
+            let args = parse(args);
+            let call = args.call().unwrap();
+            assert_eq!(call.addr, "192.168.1.12:60102".parse().unwrap());
+            assert_eq!(call.name.as_deref(), Some("Alice"));
+        }
+        assert!(parse(&[]).call().is_none());
+        assert!(rejected(&["--call", "192.168.1.12:60102"]));
+        assert!(rejected(&["192.168.1.12:60102"]));
+    }
+    #[test]
+    fn test_auto_answer_flag() {
+        assert!(parse(&[]).auto_answer.is_none());
+        let args = parse(&["--auto-answer", "--loopback"]);
+        assert_eq!(args.auto_answer, Some(AutoAnswer::Anyone));
+        assert!(args.loopback);
+        assert_eq!(
+            parse(&["--auto-answer=10.0.0.2, 3F2A 91C0 77DE 0B14"]).auto_answer,
+            Some(AutoAnswer::Allowlist {
+                ips: vec!["10.0.0.2".parse().unwrap()],
+                fingerprints: vec!["3F2A 91C0 77DE 0B14".into()],
+            })
+        );
+        // The allowlist is only taken inline, a bare flag is followed by other arguments
+        assert!(rejected(&["--auto-answer", "10.0.0.2"]));
+    }
+    #[test]
+    fn test_max_bitrate_flag() {
+        assert_eq!(parse(&["--max-bitrate", "800"]).max_bitrate, Some(800));
+        assert_eq!(parse(&["--max-bitrate=2000"]).max_bitrate, Some(2000));
+        assert!(rejected(&["--max-bitrate", "0"]));
+        assert!(rejected(&["--max-bitrate=fast"]));
+    }
+    #[test]
+    fn test_motion_flag() {
+        assert!(parse(&[]).motion.is_none());
+        assert_eq!(parse(&["--motion"]).motion, Some(MotionMode::Report));
+        assert_eq!(parse(&["--motion=gate"]).motion, Some(MotionMode::Gate));
+        assert!(rejected(&["--motion=maybe"]));
+    }
+    #[test]
+    fn test_packet_size_flag() {
+        assert_eq!(parse(&["--packet-size", "1200"]).packet_size, Some(1200));
+        assert_eq!(parse(&["--packet-size=504"]).packet_size, Some(504));
+        assert!(rejected(&["--packet-size", "0"]));
+        assert!(parse(&[]).packet_size.is_none());
+    }
+    #[test]
+    fn test_keyframe_interval_flag() {
+        assert_eq!(
+            parse(&["--keyframe-interval", "30"]).keyframe_interval,
+            Some(30)
+        );
+        assert_eq!(parse(&["--keyframe-interval=0"]).keyframe_interval, Some(0));
+        assert!(rejected(&["--keyframe-interval", "often"]));
+        assert!(parse(&[]).keyframe_interval.is_none());
+    }
+    #[test]
+    fn test_max_nal_size_flag() {
+        assert_eq!(parse(&["--max-nal-size", "8192"]).max_nal_size, Some(8192));
+        assert_eq!(parse(&["--max-nal-size=512"]).max_nal_size, Some(512));
+        assert!(rejected(&["--max-nal-size", "0"]));
+        assert!(parse(&[]).max_nal_size.is_none());
+    }
+    #[test]
+    fn test_socket_tuning_flags() {
+        let parsed = parse(&["--send-buffer", "1024", "--recv-buffer=2048", "--dscp=EF"]);
+        assert_eq!(
+            parsed.socket_tuning(),
+            SocketTuning {
+                send_buffer: Some(1024 * 1024),
+                recv_buffer: Some(2048 * 1024),
+                dscp: Some(Dscp::Ef),
+            }
+        );
+        assert_eq!(parse(&["--dscp", "off"]).socket_tuning().dscp, None);
+        assert!(rejected(&["--dscp=cs1"]));
+        assert!(rejected(&["--send-buffer", "0"]));
+        let huge = usize::MAX.to_string();
+        assert_eq!(
+            parse(&["--recv-buffer", &huge]).socket_tuning().recv_buffer,
+            Some(usize::MAX)
+        );
+        assert_eq!(parse(&[]).socket_tuning(), SocketTuning::default());
+    }
+    #[test]
+    fn test_codec_backend_flags() {
+        let parsed = parse(&["--encoder", "x264", "--decoder=OpenH264"]);
+        assert_eq!(parsed.encoder, CodecBackend::X264);
+        assert_eq!(parsed.decoder, CodecBackend::OpenH264);
+        assert!(rejected(&["--encoder=vaapi"]));
+        assert_eq!(parse(&[]).encoder, CodecBackend::OpenH264);
+        assert!(rejected(&["--encoder=passthrough"]));
+        assert!(rejected(&["--decoder=ffmpeg"]));
+        assert!(rejected(&["--encoder=libvpx"]));
+        assert!(rejected(&["--decoder=dav1d"]));
+    }
+    #[test]
+    fn test_video_codec_flag() {
+        assert_eq!(
+            parse(&["--video-codec", "vp8"]).video_codec,
+            Some(VideoEncoding::Vp8)
+        );
+        assert_eq!(
+            parse(&["--video-codec=H.264"]).video_codec,
+            Some(VideoEncoding::H264)
+        );
+        assert_eq!(
+            parse(&["--video-codec=AV1"]).video_codec,
+            Some(VideoEncoding::Av1)
+        );
+        assert!(rejected(&["--video-codec=h265"]));
+        assert_eq!(parse(&[]).video_codec, None);
+    }
+    #[test]
+    fn test_ring_timeout_flag() {
+        assert_eq!(
+            parse(&["--ring-timeout", "10"]).ring_timeout,
+            Some(Duration::from_secs(10))
+        );
+        // Nobody could answer in time
+        assert!(rejected(&["--ring-timeout=0"]));
+        assert!(parse(&[]).ring_timeout.is_none());
+    }
+    #[test]
+    fn test_upload_flags() {
+        let args = parse(&["--upload-fps", "15", "--upload-budget=60"]);
+        assert_eq!(args.upload_fps, Some(15));
+        assert_eq!(args.upload_budget, Some(60));
+        assert!(rejected(&["--upload-fps=0"]));
+        assert!(rejected(&["--upload-budget", "lots"]));
+    }
+    #[test]
+    fn test_config_dir_per_instance() {
+        let default = parse(&[]).config_dir();
+        assert!(default.ends_with("eye-spy"));
+        assert_eq!(
+            parse(&["--instance", "alice"]).config_dir(),
+            default.join("alice")
+        );
+    }
+    #[test]
+    fn test_monthly_cap_flags() {
+        assert!(parse(&[]).monthly_cap().is_none());
+        assert!(rejected(&["--monthly-cap", "0"]));
+        assert!(parse(&["--downgrade-at-cap"]).monthly_cap().is_none());
+        assert_eq!(
+            parse(&["--downgrade-at-cap", "--monthly-cap=5000"]).monthly_cap(),
+            Some(MonthlyCap {
+                bytes: 5_000_000_000,
+                downgrade: true,
+            })
+        );
+        let cap = parse(&["--monthly-cap", "200"]).monthly_cap().unwrap();
+        assert!(!cap.downgrade);
+    }
+    #[test]
+    fn test_still_flags() {
+        assert_eq!(parse(&[]).still_fallback(), StillFallback::default());
+        let args = parse(&["--still-threshold", "64", "--still-interval=2"]);
+        assert_eq!(
+            args.still_fallback(),
+            StillFallback {
+                threshold: 64,
+                interval: Duration::from_secs(2),
+            }
+        );
+        // 0 never asks for them, but they are sent at some interval
+        assert_eq!(
+            parse(&["--still-threshold=0"]).still_fallback().threshold,
+            0
+        );
+        assert!(rejected(&["--still-interval", "0"]));
+        assert!(rejected(&["--still-threshold=slow"]));
+    }
+    #[test]
+    fn test_rtp_flags() {
+        let sdp = PathBuf::from("stream.sdp");
+        assert!(parse(&["--rtp-local"]).rtp(sdp.clone()).is_none());
+        assert!(rejected(&["--rtp", "5004"]));
+        assert_eq!(
+            parse(&["--rtp", "239.0.0.1:5004", "--rtp-local"]).rtp(sdp.clone()),
+            Some(RtpConfig {
+                addr: "239.0.0.1:5004".parse().unwrap(),
+                local: true,
+                sdp_path: Some(sdp.clone()),
+            })
+        );
+        let rtp = parse(&["--rtp=127.0.0.1:5004"]).rtp(sdp).unwrap();
+        assert!(!rtp.local);
+    }
+    #[test]
+    fn test_mdns_service_flag() {
+        let work = Advertisement {
+            instance: Some("desk".to_string()),
+            ..Default::default()
+        };
+        let profiles = BTreeMap::from([("work".to_string(), work.clone())]);
+        assert_eq!(parse(&[]).advertisements(profiles.clone()), [work]);
+        let args = parse(&["--mdns-service", "_eye-spy-dev._tcp.local."]);
+        assert_eq!(
+            args.advertisements(profiles),
+            [Advertisement {
+                service: "_eye-spy-dev._tcp.local.".to_string(),
+                ..Default::default()
+            }]
+        );
+        assert!(parse(&[]).advertisements(BTreeMap::new()).is_empty());
+    }
+    #[test]
+    fn test_import_peers_flag() {
+        assert_eq!(
+            parse(&["--import-peers", "lab.json"]).import_peers,
+            Some(PathBuf::from("lab.json"))
+        );
+        assert!(parse(&[]).import_peers.is_none());
+    }
+    #[test]
+    fn test_beacon_port_flag() {
+        assert_eq!(parse(&["--beacon-port", "60110"]).beacon_port, Some(60110));
+        assert_eq!(parse(&["--beacon-port=60110"]).beacon_port, Some(60110));
+        assert!(rejected(&["--beacon-port", "0"]));
+        assert!(rejected(&["--beacon-port", "mdns"]));
+        assert!(parse(&[]).beacon_port.is_none());
+    }
+    #[cfg(feature = "webrtc")]
+    #[test]
+    fn test_webrtc_flag() {
+        assert_eq!(
+            parse(&["--webrtc=8443"]).webrtc,
+            Some("127.0.0.1:8443".parse().unwrap())
+        );
+        assert_eq!(
+            parse(&["--webrtc", "192.168.1.12:8443"]).webrtc,
+            Some("192.168.1.12:8443".parse().unwrap())
+        );
+        assert!(rejected(&["--webrtc", "localhost"]));
+    }
+    #[test]
+    fn test_subcommands() {
+        let addr = "192.168.1.12:60102".parse().unwrap();
+        assert!(parse(&[]).command.is_none());
+        assert_eq!(parse(&["list"]).command, Some(Command::List));
+        let args = parse(&["call", "--instance", "bob", "192.168.1.12:60102"]);
+        assert_eq!(args.command, Some(Command::Call { addr }));
+        assert_eq!(args.instance.as_deref(), Some("bob"));
+        assert_eq!(
+            parse(&["call", "eyespy://192.168.1.12:60102?name=Alice"]).command,
+            Some(Command::Call { addr })
+        );
+        // A peer on the same link, with the interface it's on
+        assert_eq!(
+            parse(&["call", "[fe80::1%3]:60102"]).command,
+            Some(Command::Call {
+                addr: "[fe80::1%3]:60102".parse().unwrap()
+            })
+        );
+        let args = parse(&["answer", "--auto"]);
+        assert_eq!(args.command, Some(Command::Answer));
+        assert_eq!(args.auto_answer, Some(AutoAnswer::Anyone));
+        assert_eq!(parse(&["daemon"]).command, Some(Command::Daemon));
+        assert_eq!(
+            parse(&["export-peers", "lab.json"]).command,
+            Some(Command::ExportPeers {
+                out: PathBuf::from("lab.json")
+            })
+        );
+        assert_eq!(
+            parse(&["record", "192.168.1.12:60102", "out.mp4"]).command,
+            Some(Command::Record {
+                addr,
+                out: PathBuf::from("out.mp4"),
+            })
+        );
+        // The flags may come first too
+        assert_eq!(parse(&["--loopback", "list"]).command, Some(Command::List));
+    }
+    #[test]
+    fn test_bad_subcommands() {
+        // Missing, invalid or extra arguments, reported with the usage rather than ignored
+        for args in [
+            &["call"][..],
+            &["call", "kitchen"],
+            &["record", "192.168.1.12:60102"],
+            &["list", "all"],
+            &["export-peers"],
+            &["dial", "192.168.1.12:60102"],
+        ] {
+            let e = try_parse(args).unwrap_err();
+            assert_eq!(e.exit_code(), 2, "{args:?}");
+            assert!(report(&e).contains("Usage: eye-spy"), "{args:?}");
+        }
+        let e = try_parse(&["--help"]).unwrap_err();
+        assert_eq!((e.kind(), e.exit_code()), (ErrorKind::DisplayHelp, 0));
+    }
+}
@@ -3,11 +3,15 @@
 use std::net::IpAddr;
 
 use bevy::prelude::*;
-use scp_client::client::SessionConfig;
+use eye_spy_core::h264_stream::outgoing::{OutgoingStreamEvent, StreamControls};
+use eye_spy_core::{CallEvent, Invite};
+use scp_client::client::{ScpConnectionError, SessionConfig};
+use scp_client::file_transfer::TransferEvent;
+use scp_client::scp::ControlMessage;
 
-use crate::h264_stream::incoming::{H264IncomingStreamControls, IncomingStreamControls};
-use crate::h264_stream::outgoing::{H264StreamControls, StreamControls};
-use crate::{IncomingVideoStreamControls, OutgoingVideoStreamControls, STREAM_IMAGE_HANDLE};
+use crate::locale::tr;
+use crate::plugin::{CallManagerBevy, STREAM_IMAGE_HANDLE};
+use crate::toast::{ToastEvent, ToastsMuted};
 
 #[derive(States, Default, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum OutgoingVideoStreamState {
@@ -27,13 +31,45 @@ pub enum ScpConnectionState {
     #[default]
     Off,
     Connecting,
+    /// The peer was reached and asks its user to answer
+    Ringing,
     Connected,
 }
 
 #[derive(Event)]
-pub struct ConnectionEvent(SessionConfig);
+pub struct ConnectionEvent(pub SessionConfig);
 #[derive(Event)]
 pub struct IncomingConnectionEvent(IpAddr);
+/// Call yourself: the outgoing stream is sent straight to the local incoming stream, without SCP.
+/// Handy to check the camera, encoder and renderer on a single machine.
+#[derive(Event)]
+pub struct LoopbackCallEvent;
+/// Call the peer that made the invite
+#[derive(Event)]
+pub struct DialEvent(pub Invite);
+/// Health of the outgoing stream, forwarded from the stream thread
+#[derive(Event, Deref)]
+pub struct OutgoingStreamHealthEvent(pub OutgoingStreamEvent);
+/// Motion started (true) or stopped in front of the camera, see `MotionDetection`
+#[derive(Event)]
+pub struct MotionEvent(pub bool);
+/// A request received from the peer of the current session.
+/// Requests about the outgoing stream are already taken care of by `CallManager`
+#[derive(Event, Deref)]
+pub struct ControlMessageEvent(pub ControlMessage);
+/// What happened to a file sent to the peer or offered by it, see `crate::file_transfer`
+#[derive(Event, Deref)]
+pub struct FileTransferEvent(pub TransferEvent);
+/// Text the peer sent for the clipboard, see `crate::shared_clipboard`
+#[derive(Event, Deref)]
+pub struct ClipboardTextEvent(pub String);
+/// Send a request to the peer of the current session
+#[derive(Event)]
+pub struct SendControlEvent(pub ControlMessage);
+
+/// Name or address of the peer called, while `ScpConnectionState::Connecting` or `Ringing`
+#[derive(Resource, Debug, Default)]
+pub struct CallingPeer(pub String);
 
 pub struct ConnectionStatePlugin;
 
@@ -42,16 +78,26 @@ impl Plugin for ConnectionStatePlugin {
         app.init_state::<OutgoingVideoStreamState>();
         app.init_state::<IncomingVideoStreamState>();
         app.init_state::<ScpConnectionState>();
+        app.init_resource::<CallingPeer>();
         app.add_event::<ConnectionEvent>();
         app.add_event::<IncomingConnectionEvent>();
+        app.add_event::<LoopbackCallEvent>();
+        app.add_event::<DialEvent>();
+        app.add_event::<OutgoingStreamHealthEvent>();
+        app.add_event::<MotionEvent>();
+        app.add_event::<ControlMessageEvent>();
+        app.add_event::<SendControlEvent>();
+        app.add_event::<FileTransferEvent>();
+        app.add_event::<ClipboardTextEvent>();
 
         app.add_systems(
             OnEnter(OutgoingVideoStreamState::Off),
             on_disconnect_out_stream,
         );
+        app.add_systems(OnEnter(ScpConnectionState::Off), forget_calling_peer);
         app.add_systems(
             OnEnter(IncomingVideoStreamState::Off),
-            on_disconnect_in_stream,
+            (on_disconnect_in_stream, unmute_toasts),
         );
 
         app.add_systems(
@@ -61,27 +107,175 @@ impl Plugin for ConnectionStatePlugin {
             },
             on_fail_connection,
         );
+        app.add_systems(
+            OnTransition {
+                exited: ScpConnectionState::Ringing,
+                entered: ScpConnectionState::Off,
+            },
+            on_fail_connection,
+        );
+        app.add_systems(
+            Update,
+            on_loopback_call.run_if(on_event::<LoopbackCallEvent>()),
+        );
+        app.add_systems(Update, on_dial.run_if(on_event::<DialEvent>()));
+        app.add_systems(
+            Update,
+            send_control_messages.run_if(on_event::<SendControlEvent>()),
+        );
+        app.add_systems(PreUpdate, poll_calls);
+        app.add_systems(Last, shutdown_streams.run_if(on_event::<AppExit>()));
     }
 }
 
 // CHANGING STATE SYSTEMS, TODO
 
-fn on_disconnect_out_stream(mut os: ResMut<OutgoingVideoStreamControls<H264StreamControls>>) {
-    os.0.disconnect();
+fn on_disconnect_out_stream(mut call: ResMut<CallManagerBevy>) {
+    call.0.outgoing_mut().disconnect();
 }
-fn on_disconnect_in_stream(
-    mut is: ResMut<IncomingVideoStreamControls<H264IncomingStreamControls>>,
-    mut images: ResMut<Assets<Image>>,
-) {
-    is.0.refuse();
+/// Ends the call with the peer too, if it's still on
+fn on_disconnect_in_stream(mut call: ResMut<CallManagerBevy>, mut images: ResMut<Assets<Image>>) {
+    call.0.hang_up();
     if let Some(image) = images.get_mut(&STREAM_IMAGE_HANDLE) {
         image.data.iter_mut().for_each(|e| *e = 0u8);
     }
 }
 
+/// The peer's request to mute the notifications lasts for its call only
+fn unmute_toasts(mut muted: ResMut<ToastsMuted>) {
+    muted.0 = false;
+}
+/// The next call may be an incoming one
+fn forget_calling_peer(mut calling: ResMut<CallingPeer>) {
+    calling.0.clear();
+}
 fn on_fail_connection() {
     warn!("Failed a connection.");
 }
+/// Turns what happened to the calls into bevy events and states.
+/// The streams themselves are started and stopped by `CallManager`
+#[allow(clippy::too_many_arguments)]
+fn poll_calls(
+    mut call: ResMut<CallManagerBevy>,
+    mut health: EventWriter<OutgoingStreamHealthEvent>,
+    mut motion: EventWriter<MotionEvent>,
+    mut controls: EventWriter<ControlMessageEvent>,
+    mut files: EventWriter<FileTransferEvent>,
+    mut clipboard: EventWriter<ClipboardTextEvent>,
+    mut connections: EventWriter<ConnectionEvent>,
+    mut incoming: EventWriter<IncomingConnectionEvent>,
+    mut toasts: EventWriter<ToastEvent>,
+    mut toasts_muted: ResMut<ToastsMuted>,
+    mut scp_state: ResMut<NextState<ScpConnectionState>>,
+    mut stream_in_state: ResMut<NextState<IncomingVideoStreamState>>,
+    mut stream_out_state: ResMut<NextState<OutgoingVideoStreamState>>,
+) {
+    for event in call.0.poll() {
+        match event {
+            CallEvent::Incoming(ip) => {
+                incoming.send(IncomingConnectionEvent(ip));
+            }
+            CallEvent::Ringing => {
+                info!("The peer is ringing");
+                scp_state.set(ScpConnectionState::Ringing);
+            }
+            CallEvent::Established(config) => {
+                scp_state.set(ScpConnectionState::Connected);
+                stream_in_state.set(IncomingVideoStreamState::On);
+                stream_out_state.set(OutgoingVideoStreamState::On);
+                toasts.send(ToastEvent::info(tr!("peer_joined", ip = config.ip)));
+                connections.send(ConnectionEvent(config));
+            }
+            CallEvent::Failed(ScpConnectionError::Cancelled) => {
+                info!("Call cancelled");
+                scp_state.set(ScpConnectionState::Off);
+            }
+            CallEvent::Failed(e) => {
+                warn!("Call failed: {e}");
+                toasts.send(ToastEvent::error(tr!("call_failed", error = e)));
+                scp_state.set(ScpConnectionState::Off);
+            }
+            CallEvent::Ended => {
+                // Before the toast, it's queued in this frame yet
+                toasts_muted.0 = false;
+                toasts.send(ToastEvent::info(tr!("peer_left")));
+                scp_state.set(ScpConnectionState::Off);
+                stream_in_state.set(IncomingVideoStreamState::Off);
+                stream_out_state.set(OutgoingVideoStreamState::Off);
+            }
+            CallEvent::Control(control) => {
+                if let ControlMessage::MuteNotifications(mute) = control {
+                    toasts_muted.0 = mute;
+                }
+                controls.send(ControlMessageEvent(control));
+            }
+            CallEvent::File(event) => {
+                files.send(FileTransferEvent(event));
+            }
+            CallEvent::Clipboard(text) => {
+                clipboard.send(ClipboardTextEvent(text));
+            }
+            CallEvent::OutgoingStream(OutgoingStreamEvent::MotionStarted) => {
+                motion.send(MotionEvent(true));
+            }
+            CallEvent::OutgoingStream(OutgoingStreamEvent::MotionStopped) => {
+                motion.send(MotionEvent(false));
+            }
+            CallEvent::OutgoingStream(event) => {
+                health.send(OutgoingStreamHealthEvent(event));
+            }
+            // Logged by `CallManager`, the settings show it too
+            CallEvent::DataCapNear(_) => {}
+            // Like a loopback call, there's no session
+            #[cfg(feature = "webrtc")]
+            CallEvent::BrowserJoined(_) => {
+                scp_state.set(ScpConnectionState::Connected);
+                stream_in_state.set(IncomingVideoStreamState::On);
+                stream_out_state.set(OutgoingVideoStreamState::On);
+            }
+        }
+    }
+}
+fn send_control_messages(mut events: EventReader<SendControlEvent>, call: Res<CallManagerBevy>) {
+    for event in events.read() {
+        call.0.send_control(event.0);
+    }
+}
+/// Join the stream threads before the app exits, so a stuck thread gets reported
+fn shutdown_streams(world: &mut World) {
+    if let Some(call) = world.remove_resource::<CallManagerBevy>() {
+        if let Err(e) = call.0.shutdown() {
+            error!("Stream thread failed to exit: {e}");
+        }
+    }
+}
+fn on_loopback_call(
+    mut call: ResMut<CallManagerBevy>,
+    mut stream_in_state: ResMut<NextState<IncomingVideoStreamState>>,
+    mut stream_out_state: ResMut<NextState<OutgoingVideoStreamState>>,
+) {
+    if let Err(e) = call.0.loopback() {
+        error!("Cannot start a loopback call: {e}");
+        return;
+    }
+    stream_in_state.set(IncomingVideoStreamState::On);
+    stream_out_state.set(OutgoingVideoStreamState::On);
+}
+fn on_dial(
+    mut events: EventReader<DialEvent>,
+    call: Res<CallManagerBevy>,
+    mut calling: ResMut<CallingPeer>,
+    mut scp_state: ResMut<NextState<ScpConnectionState>>,
+) {
+    for event in events.read() {
+        call.0.call_invite(&event.0);
+        calling.0 = match event.0.name {
+            Some(ref name) => name.clone(),
+            None => event.0.addr.to_string(),
+        };
+        scp_state.set(ScpConnectionState::Connecting);
+    }
+}
 fn on_connection_event() {
     // init the streams
     // change state to connected
@@ -0,0 +1,295 @@
+//! Running as a systemd service, i.e. on a camera node answering the calls unattended.
+//! `eye-spy daemon --auto` answers like `eye-spy answer`, and also:
+//! * tells systemd once it's ready and pings its watchdog, see `Notifier`
+//! * logs with the syslog priorities journald reads, when stderr goes to the journal
+//! * restarts the capture of a camera that keeps failing during a call, and exits
+//!   for systemd to restart the service if that doesn't help, see `CaptureWatch`
+//!
+//! A unit running it:
+//! ```ini
+//! [Service]
+//! Type=notify
+//! ExecStart=/usr/bin/eye-spy daemon --auto --instance camera
+//! WatchdogSec=30
+//! Restart=on-failure
+//! ```
+
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use bevy::log::tracing_subscriber::fmt::format::{Format, Full, Writer};
+use bevy::log::tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use bevy::log::tracing_subscriber::registry::LookupSpan;
+use eye_spy_core::h264_stream::outgoing::OutgoingStreamEvent;
+use eye_spy_core::{CallEvent, CallManager};
+use tracing::{error, warn, Event, Level, Subscriber};
+
+/// How long the capture may fail during a call before it's restarted
+const CAPTURE_RESTART_AFTER: Duration = Duration::from_secs(10);
+/// Restarts of the capture that didn't bring the frames back, before the service gives up
+const MAX_CAPTURE_RESTARTS: u32 = 3;
+
+/// Whether stderr goes to the journal, systemd tells the services so
+pub fn logs_to_journal() -> bool {
+    std::env::var_os("JOURNAL_STREAM").is_some()
+}
+
+/// Log lines prefixed with their syslog priority, i.e. `<4>` for a warning, which journald
+/// takes off and keeps as the priority of the line. The journal has its own timestamps
+pub struct JournalFormat(Format<Full, ()>);
+
+impl Default for JournalFormat {
+    fn default() -> Self {
+        Self(Format::default().without_time().with_level(false))
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for JournalFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let priority = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        write!(writer, "<{priority}>")?;
+        self.0.format_event(ctx, writer, event)
+    }
+}
+
+/// Tells systemd about the service, see sd_notify(3). Does nothing when not run by systemd
+/// * `watchdog` - how often the watchdog is pinged, half of the timeout systemd set
+pub struct Notifier {
+    socket: Option<UnixDatagram>,
+    watchdog: Option<Duration>,
+    pinged: Instant,
+}
+
+impl Notifier {
+    /// The socket and the watchdog systemd passed in the environment
+    pub fn from_env() -> Self {
+        let socket = std::env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            let socket = UnixDatagram::unbound().ok()?;
+            let path = path.to_string_lossy();
+            let connected = match path.strip_prefix('@') {
+                // An abstract socket, i.e. in a container
+                #[cfg(target_os = "linux")]
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    std::os::unix::net::SocketAddr::from_abstract_name(name)
+                        .and_then(|addr| socket.connect_addr(&addr))
+                }
+                _ => socket.connect(&*path),
+            };
+            connected
+                .inspect_err(|e| warn!("Cannot reach systemd at {path}: {e}"))
+                .ok()
+                .map(|()| socket)
+        });
+        // Meant for another process if it names one
+        let for_us =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| for_us)
+            .map(|usec| Duration::from_micros(usec) / 2);
+        Self {
+            socket,
+            watchdog,
+            pinged: Instant::now(),
+        }
+    }
+    /// The service is up, with `status` shown by `systemctl status`
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={status}"));
+    }
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={status}"));
+    }
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+    /// Ping the watchdog if it's time, so systemd knows the service isn't stuck
+    pub fn ping_watchdog(&mut self) {
+        if self
+            .watchdog
+            .is_some_and(|every| self.pinged.elapsed() >= every)
+        {
+            self.notify("WATCHDOG=1");
+            self.pinged = Instant::now();
+        }
+    }
+    fn notify(&self, state: &str) {
+        if let Some(ref socket) = self.socket {
+            if let Err(e) = socket.send(state.as_bytes()) {
+                warn!("Cannot notify systemd: {e}");
+            }
+        }
+    }
+}
+
+/// What to do about the capture, see `CaptureWatch::check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureAction {
+    Nothing,
+    Restart,
+    GiveUp,
+}
+
+/// Watches the capture during the calls. The stream re-opens a lost camera by itself,
+/// this restarts it when it keeps failing all the same
+/// * `failing` - since when the capture fails, None while it works
+/// * `restarts` - restarts since the frames last came
+/// * `frames` - frames encoded when last checked
+#[derive(Debug, Default)]
+pub struct CaptureWatch {
+    failing: Option<Instant>,
+    restarts: u32,
+    frames: u64,
+}
+
+impl CaptureWatch {
+    pub fn on_event(&mut self, event: &OutgoingStreamEvent, now: Instant) {
+        match event {
+            OutgoingStreamEvent::CaptureError(_) | OutgoingStreamEvent::DeviceLost(_) => {
+                self.failing.get_or_insert(now);
+            }
+            OutgoingStreamEvent::DeviceRecovered | OutgoingStreamEvent::Reconfigured(_) => {
+                self.failing = None;
+            }
+            _ => {}
+        }
+    }
+    /// What to do about the capture
+    /// * `in_call` - the frames are only captured during the calls
+    /// * `frames` - frames encoded so far, the capture works while they come
+    pub fn check(&mut self, in_call: bool, frames: u64, now: Instant) -> CaptureAction {
+        if std::mem::replace(&mut self.frames, frames) != frames || !in_call {
+            self.failing = None;
+            self.restarts = 0;
+            return CaptureAction::Nothing;
+        }
+        match self.failing {
+            Some(since) if now.duration_since(since) >= CAPTURE_RESTART_AFTER => {
+                if self.restarts >= MAX_CAPTURE_RESTARTS {
+                    return CaptureAction::GiveUp;
+                }
+                self.restarts += 1;
+                self.failing = Some(now);
+                CaptureAction::Restart
+            }
+            _ => CaptureAction::Nothing,
+        }
+    }
+}
+
+/// Everything the service does besides answering the calls
+pub struct Daemon {
+    notifier: Notifier,
+    capture: CaptureWatch,
+}
+
+impl Daemon {
+    /// Run by systemd as passed in the environment
+    pub fn from_env() -> Self {
+        Self {
+            notifier: Notifier::from_env(),
+            capture: CaptureWatch::default(),
+        }
+    }
+    /// The calls are answered from now on
+    pub fn ready(&self, status: &str) {
+        self.notifier.ready(status);
+    }
+    /// Keep the status of the service and the health of the capture up to date
+    pub fn on_event(&mut self, event: &CallEvent) {
+        match event {
+            CallEvent::Established(config) => {
+                self.notifier
+                    .status(&format!("In a call with {}", config.ip));
+            }
+            CallEvent::Ended => self.notifier.status("Answering the calls"),
+            CallEvent::OutgoingStream(event) => self.capture.on_event(event, Instant::now()),
+            _ => {}
+        }
+    }
+    /// Look after the capture and ping the watchdog, once a poll
+    /// # Errors
+    /// Returns an error once restarting the capture didn't help, for systemd to restart the service
+    pub fn tick(&mut self, calls: &CallManager) -> anyhow::Result<()> {
+        let frames = calls
+            .outgoing()
+            .stats()
+            .frames_encoded
+            .load(Ordering::Relaxed);
+        match self
+            .capture
+            .check(calls.session().is_some(), frames, Instant::now())
+        {
+            CaptureAction::Nothing => {}
+            CaptureAction::Restart => match calls.outgoing().video_mode() {
+                Some(mode) => {
+                    warn!("The capture keeps failing, restarting it");
+                    if let Err(e) = calls.outgoing().reconfigure(mode) {
+                        error!("Cannot restart the capture: {e}");
+                    }
+                }
+                None => warn!("The capture keeps failing and cannot be restarted"),
+            },
+            CaptureAction::GiveUp => {
+                self.notifier.stopping();
+                bail!("The capture keeps failing after {MAX_CAPTURE_RESTARTS} restarts");
+            }
+        }
+        self.notifier.ping_watchdog();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use eye_spy_core::h264_stream::outgoing::OutgoingStreamEvent;
+
+    use super::{CaptureAction, CaptureWatch, CAPTURE_RESTART_AFTER, MAX_CAPTURE_RESTARTS};
+
+    #[test]
+    fn test_capture_watch() {
+        let start = Instant::now();
+        let later = |secs| start + Duration::from_secs(secs);
+        let mut watch = CaptureWatch::default();
+        assert_eq!(watch.check(true, 10, start), CaptureAction::Nothing);
+        watch.on_event(&OutgoingStreamEvent::CaptureError("EIO".into()), start);
+        assert_eq!(watch.check(true, 10, later(1)), CaptureAction::Nothing);
+        // The frames came back by themselves
+        assert_eq!(watch.check(true, 11, later(20)), CaptureAction::Nothing);
+
+        watch.on_event(&OutgoingStreamEvent::DeviceLost("ENODEV".into()), later(20));
+        let mut now = later(20);
+        for _ in 0..MAX_CAPTURE_RESTARTS {
+            now += CAPTURE_RESTART_AFTER;
+            assert_eq!(watch.check(true, 11, now), CaptureAction::Restart);
+            // The camera opens again, but captures nothing
+            watch.on_event(&OutgoingStreamEvent::DeviceRecovered, now);
+            watch.on_event(&OutgoingStreamEvent::CaptureError("EIO".into()), now);
+        }
+        now += CAPTURE_RESTART_AFTER;
+        assert_eq!(watch.check(true, 11, now), CaptureAction::GiveUp);
+        // Nothing is captured between the calls
+        assert_eq!(watch.check(false, 11, now), CaptureAction::Nothing);
+        assert_eq!(watch.restarts, 0);
+    }
+}
@@ -0,0 +1,505 @@
+//! In-app debug console showing the latest log lines. Toggled with the ` key.
+//! The lines are gathered by a tracing layer registered in `LogPlugin::custom_layer`.
+//! Under them the latencies of the video pipeline and the busiest threads are shown,
+//! F9 exports them as JSON, see `eye_spy_core::profile`.
+//! Commands are typed into the field at the bottom, see `ConsoleCommand`, `help` lists them.
+//! They go through the same controls as the UI, and answer in the log, so among the lines.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bevy::log::tracing_subscriber::layer::Context;
+use bevy::log::tracing_subscriber::Layer;
+use bevy::log::BoxedLayer;
+use bevy::prelude::*;
+use eye_spy_core::profile::ThreadCpuSampler;
+use eye_spy_core::scp_client::link_local;
+use eye_spy_core::{CallManager, Invite, PipelineProfile};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+
+use crate::connection_state_bevy::DialEvent;
+use crate::plugin::CallManagerBevy;
+use crate::ui::{TextInput, TextInputFocus, TextInputSubmitted, UiSpawner};
+
+/// How many of the latest log lines are kept for the console
+const LOG_LINES_KEPT: usize = 20;
+const CONSOLE_FONT_SIZE: f32 = 16.;
+/// How often the profile is sampled while the console is open
+const PROFILE_INTERVAL: Duration = Duration::from_secs(1);
+/// How many of the busiest threads are shown
+const THREADS_SHOWN: usize = 5;
+
+pub struct DebugConsolePlugin;
+
+impl Plugin for DebugConsolePlugin {
+    fn build(&self, app: &mut App) {
+        // Only present already if the console layer was registered in LogPlugin
+        app.init_resource::<LogLines>();
+        app.init_resource::<ProfileExport>();
+        app.init_resource::<LastProfile>();
+        app.add_systems(PostStartup, spawn_debug_console);
+        app.add_systems(
+            Update,
+            (
+                toggle_debug_console,
+                run_console_commands,
+                update_debug_console,
+                update_profile,
+                export_profile,
+            )
+                .chain(),
+        );
+    }
+}
+
+/// Directory the profiles are exported to
+#[derive(Resource, Debug, Default, Clone)]
+pub struct ProfileExport(pub PathBuf);
+
+/// The profile last shown, exported as it is
+#[derive(Resource, Default)]
+struct LastProfile(PipelineProfile);
+
+/// The latest log lines, shared between the tracing layer and the console UI
+#[derive(Resource, Clone, Default)]
+pub struct LogLines(Arc<Mutex<VecDeque<String>>>);
+
+impl LogLines {
+    fn push(&self, line: String) {
+        if let Ok(mut lines) = self.0.lock() {
+            if lines.len() == LOG_LINES_KEPT {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+    /// All kept lines, oldest first, joined with newlines
+    pub fn joined(&self) -> String {
+        match self.0.lock() {
+            Ok(lines) => lines.iter().cloned().collect::<Vec<_>>().join("\n"),
+            Err(_) => String::new(),
+        }
+    }
+}
+
+/// Pass it to `LogPlugin::custom_layer` to feed the console with log lines
+pub fn console_layer(app: &mut App) -> Option<BoxedLayer> {
+    let lines = LogLines::default();
+    app.insert_resource(lines.clone());
+    Some(Box::new(ConsoleLayer(lines)))
+}
+
+struct ConsoleLayer(LogLines);
+
+impl<S: Subscriber> Layer<S> for ConsoleLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut line = format!("{} {}: ", meta.level(), meta.target());
+        event.record(&mut LineVisitor(&mut line));
+        self.0.push(line);
+    }
+}
+
+/// Writes the message and the fields of an event into a single line
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{value:?}");
+        } else {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+}
+
+#[derive(Component)]
+struct DebugConsole;
+
+#[derive(Component)]
+struct DebugConsoleText;
+
+#[derive(Component)]
+struct ProfileText;
+
+/// Marker for the field the commands are typed into
+#[derive(Component)]
+struct ConsoleField;
+
+/// What can be typed into the console
+/// * `Connect` - call an invite or an address, like the dial field
+/// * `Stats` - log the counters of both streams
+/// * `ForceIdr` - send a keyframe next
+/// * `SetBitrate` - kbit/s the camera mode is picked for, see `CallManager::set_bitrate_budget`
+/// * `Loss` - drop this share of the packets received, with the `netsim` feature only
+#[derive(Debug, Clone, PartialEq)]
+enum ConsoleCommand {
+    Help,
+    Connect(Invite),
+    Stats,
+    ForceIdr,
+    SetBitrate(u32),
+    Loss(f64),
+}
+
+const HELP: &str =
+    "connect <invite, ip:port or ip>, stats, force-idr, set-bitrate <800k>, loss <5%>";
+
+impl ConsoleCommand {
+    /// A bare IP after `connect` is called on `port`, the one this client listens on,
+    /// the peers usually share it
+    fn parse(line: &str, port: u16) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let argument = words.next();
+        if words.next().is_some() {
+            return Err(format!("Too many arguments: {line}"));
+        }
+        let command = match (name, argument) {
+            ("help", None) => Self::Help,
+            ("connect", Some(target)) => Self::Connect(parse_target(target, port)?),
+            ("stats", None) => Self::Stats,
+            ("force-idr", None) => Self::ForceIdr,
+            ("set-bitrate", Some(bitrate)) => Self::SetBitrate(parse_bitrate(bitrate)?),
+            ("loss", Some(loss)) => Self::Loss(parse_percent(loss)?),
+            ("connect" | "set-bitrate" | "loss", None) => {
+                return Err(format!("{name} needs an argument"))
+            }
+            ("help" | "stats" | "force-idr", Some(_)) => {
+                return Err(format!("{name} takes no argument"))
+            }
+            _ => return Err(format!("Unknown command: {name}, try help")),
+        };
+        Ok(command)
+    }
+}
+
+fn parse_target(target: &str, port: u16) -> Result<Invite, String> {
+    if Invite::is_invite(target) {
+        return target.parse().map_err(|e| format!("{e}"));
+    }
+    let addr = match target.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => link_local::parse_addr(target)
+            .ok_or_else(|| format!("Not an invite nor an address: {target}"))?,
+    };
+    Ok(Invite { addr, name: None })
+}
+
+/// kbit/s of i.e. `800k`, `2M` or `64000`, in bit/s without a suffix
+fn parse_bitrate(bitrate: &str) -> Result<u32, String> {
+    let (number, scale) = match bitrate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&bitrate[..i], 1.),
+        Some((i, 'm' | 'M')) => (&bitrate[..i], 1000.),
+        _ => (bitrate, 0.001),
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number > 0. => Ok((number * scale).round() as u32),
+        _ => Err(format!("Not a bitrate: {bitrate}")),
+    }
+}
+
+/// 0.0..=1.0 of i.e. `5%` or `5`
+fn parse_percent(percent: &str) -> Result<f64, String> {
+    match percent.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0. ..=100.).contains(&percent) => Ok(percent / 100.),
+        _ => Err(format!("Not a percentage: {percent}")),
+    }
+}
+
+/// The counters of both streams in a line
+fn describe_stats(call: &CallManager) -> String {
+    let sent = call.outgoing().stats();
+    let received = call.incoming().stats();
+    let peer = match call.session() {
+        Some(session) => session.ip.to_string(),
+        None => "nobody".to_string(),
+    };
+    let path = match call.path_budget() {
+        Some(budget) => format!("{budget} kbit/s"),
+        None => "unmeasured".to_string(),
+    };
+    format!(
+        "Call with {peer}, path {path}. Sent {} kbit/s, {} frames, {} packets. Received {} kbit/s, {} packets, {} lost, {} frames, {} decode errors",
+        sent.bitrate() / 1000,
+        sent.frames_encoded.load(Ordering::Relaxed),
+        sent.packets_sent.load(Ordering::Relaxed),
+        received.bitrate() / 1000,
+        received.packets_received.load(Ordering::Relaxed),
+        received.packets_lost.load(Ordering::Relaxed),
+        received.frames_decoded.load(Ordering::Relaxed),
+        received.decode_errors.load(Ordering::Relaxed),
+    )
+}
+
+/// i.e. 4.2 ms, or - without samples
+fn format_latency(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.1} ms", latency.as_secs_f64() * 1000.),
+        None => "-".to_string(),
+    }
+}
+
+/// A line for each stage of the pipeline, then the busiest threads
+fn describe_profile(profile: &PipelineProfile) -> String {
+    let mut lines = vec!["Pipeline latency: mean, p50, p95 (samples)".to_string()];
+    for (stage, latency) in profile.stages() {
+        lines.push(format!(
+            "{}: {}, {}, {} ({})",
+            stage.replace('_', " "),
+            format_latency(latency.mean()),
+            format_latency(latency.quantile(0.5)),
+            format_latency(latency.quantile(0.95)),
+            latency.count
+        ));
+    }
+    if !profile.threads.is_empty() {
+        let threads: Vec<String> = profile
+            .threads
+            .iter()
+            .take(THREADS_SHOWN)
+            .map(|thread| format!("{} {:.0}%", thread.name, thread.usage * 100.))
+            .collect();
+        lines.push(format!("Threads: {}", threads.join(", ")));
+    }
+    lines.push("F9 exports the profile".to_string());
+    lines.join("\n")
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn spawn_debug_console(mut spawner: UiSpawner) {
+    let text = spawner
+        .spawn_pretty_text("", CONSOLE_FONT_SIZE)
+        .insert(DebugConsoleText)
+        .id();
+    let profile = spawner
+        .spawn_pretty_text("", CONSOLE_FONT_SIZE)
+        .insert(ProfileText)
+        .id();
+    let field = spawner
+        .spawn_text_input(TextInput::new("console_placeholder"), CONSOLE_FONT_SIZE)
+        .insert(ConsoleField)
+        .id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                bottom: Val::Px(0.),
+                left: Val::Px(0.),
+                width: Val::Percent(100.),
+                max_height: Val::Percent(40.),
+                overflow: Overflow::clip(),
+                flex_direction: FlexDirection::Column,
+                ..Default::default()
+            },
+            10,
+        )
+        .insert(DebugConsole)
+        .push_children(&[text, profile, field]);
+}
+
+/// The field is typed into while the console is open, the ` key closes it from there too
+fn toggle_debug_console(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut query: Query<&mut Style, With<DebugConsole>>,
+    mut fields: Query<(Entity, &mut TextInput), With<ConsoleField>>,
+    mut focus: ResMut<TextInputFocus>,
+) {
+    if !keys.just_pressed(KeyCode::Backquote) {
+        return;
+    }
+    let typing_command = focus.0.is_some_and(|focused| fields.contains(focused));
+    if focus.is_typing() && !typing_command {
+        return;
+    }
+    for mut style in &mut query {
+        style.display = match style.display {
+            Display::None => Display::Flex,
+            _ => Display::None,
+        };
+        let opened = style.display == Display::Flex;
+        for (entity, mut field) in &mut fields {
+            field.clear();
+            focus.0 = opened.then_some(entity);
+        }
+    }
+}
+fn run_console_commands(
+    mut submitted: EventReader<TextInputSubmitted>,
+    mut fields: Query<&mut TextInput, With<ConsoleField>>,
+    mut call: ResMut<CallManagerBevy>,
+    mut dial: EventWriter<DialEvent>,
+) {
+    for TextInputSubmitted(entity) in submitted.read() {
+        let Ok(mut field) = fields.get_mut(*entity) else {
+            continue;
+        };
+        let line = field.text().to_string();
+        field.clear();
+        if line.trim().is_empty() {
+            continue;
+        }
+        info!("> {line}");
+        let port = call.0.invite().addr.port();
+        let command = match ConsoleCommand::parse(&line, port) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("{e}");
+                continue;
+            }
+        };
+        match command {
+            ConsoleCommand::Help => info!("Commands: {HELP}"),
+            ConsoleCommand::Connect(invite) => {
+                dial.send(DialEvent(invite));
+            }
+            ConsoleCommand::Stats => info!("{}", describe_stats(&call.0)),
+            ConsoleCommand::ForceIdr => {
+                call.0.outgoing().request_keyframe();
+                info!("Sending a keyframe next");
+            }
+            ConsoleCommand::SetBitrate(budget) => {
+                call.0.set_bitrate_budget(budget);
+                info!("Picking the camera mode for {budget} kbit/s");
+            }
+            #[cfg(feature = "netsim")]
+            ConsoleCommand::Loss(loss) => {
+                call.0.incoming().set_simulated_loss(loss);
+                info!("Dropping {:.0}% of the packets received", loss * 100.);
+            }
+            #[cfg(not(feature = "netsim"))]
+            ConsoleCommand::Loss(_) => {
+                warn!("Built without the netsim feature, nothing is dropped")
+            }
+        }
+    }
+}
+
+fn update_debug_console(
+    lines: Res<LogLines>,
+    console: Query<&Style, With<DebugConsole>>,
+    mut text: Query<&mut Text, With<DebugConsoleText>>,
+) {
+    // Don't bother formatting the lines when nobody looks at them
+    if console.iter().all(|style| style.display == Display::None) {
+        return;
+    }
+    let joined = lines.joined();
+    for mut text in &mut text {
+        text.sections[0].value.clone_from(&joined);
+    }
+}
+/// Sampled every `PROFILE_INTERVAL` while the console is open, so the thread usage is averaged over it
+fn update_profile(
+    call: Res<CallManagerBevy>,
+    console: Query<&Style, With<DebugConsole>>,
+    mut text: Query<&mut Text, With<ProfileText>>,
+    mut last: ResMut<LastProfile>,
+    mut sampler: Local<ThreadCpuSampler>,
+    mut sampled_at: Local<Option<Instant>>,
+) {
+    if console.iter().all(|style| style.display == Display::None) {
+        return;
+    }
+    if sampled_at.is_some_and(|at| at.elapsed() < PROFILE_INTERVAL) {
+        return;
+    }
+    *sampled_at = Some(Instant::now());
+    let mut profile = call.0.pipeline_profile();
+    profile.threads = sampler.sample().unwrap_or_default();
+    let description = describe_profile(&profile);
+    last.0 = profile;
+    for mut text in &mut text {
+        text.sections[0].value.clone_from(&description);
+    }
+}
+fn export_profile(
+    keys: Res<ButtonInput<KeyCode>>,
+    last: Res<LastProfile>,
+    export: Res<ProfileExport>,
+) {
+    if !keys.just_pressed(KeyCode::F9) {
+        return;
+    }
+    let path = export.0.join(format!(
+        "profile-{}.json",
+        eye_spy_core::av_sync::timestamp_now() / 1_000_000
+    ));
+    match std::fs::create_dir_all(&export.0).and_then(|()| std::fs::write(&path, last.0.to_json()))
+    {
+        Ok(()) => info!("Exported the pipeline profile to {}", path.display()),
+        Err(e) => warn!("Cannot export the pipeline profile: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use eye_spy_core::profile::ThreadCpu;
+    use eye_spy_core::PipelineProfile;
+
+    use super::{describe_profile, format_latency, ConsoleCommand};
+
+    #[test]
+    fn test_describe_profile() {
+        assert_eq!(format_latency(None), "-");
+        assert_eq!(format_latency(Some(Duration::from_micros(4260))), "4.3 ms");
+        let mut profile = PipelineProfile::default();
+        profile.encode_to_send.count = 2;
+        profile.encode_to_send.sum_us = 3000;
+        profile.threads = vec![ThreadCpu {
+            tid: 1,
+            name: "incoming_decoder".to_string(),
+            usage: 0.35,
+        }];
+        let text = describe_profile(&profile);
+        assert!(text.contains("\ncapture to encode: -, -, - (0)\n"));
+        assert!(text.contains("\nencode to send: 1.5 ms, -, - (2)\n"));
+        assert!(text.contains("\nThreads: incoming_decoder 35%\n"));
+    }
+
+    #[test]
+    fn test_console_command() {
+        let parse = |line| ConsoleCommand::parse(line, 5000);
+        assert_eq!(parse(" stats "), Ok(ConsoleCommand::Stats));
+        assert_eq!(parse("force-idr"), Ok(ConsoleCommand::ForceIdr));
+        assert_eq!(
+            parse("set-bitrate 800k"),
+            Ok(ConsoleCommand::SetBitrate(800))
+        );
+        assert_eq!(
+            parse("set-bitrate 1.5M"),
+            Ok(ConsoleCommand::SetBitrate(1500))
+        );
+        assert_eq!(
+            parse("set-bitrate 64000"),
+            Ok(ConsoleCommand::SetBitrate(64))
+        );
+        assert_eq!(parse("loss 5%"), Ok(ConsoleCommand::Loss(0.05)));
+        assert_eq!(parse("loss 0"), Ok(ConsoleCommand::Loss(0.)));
+        let Ok(ConsoleCommand::Connect(invite)) = parse("connect 10.0.0.2") else {
+            panic!("A bare IP is called");
+        };
+        assert_eq!(invite.addr.to_string(), "10.0.0.2:5000");
+        let Ok(ConsoleCommand::Connect(invite)) = parse("connect 10.0.0.2:6000") else {
+            panic!("An address is called");
+        };
+        assert_eq!(invite.addr.to_string(), "10.0.0.2:6000");
+        assert!(parse("connect").is_err());
+        assert!(parse("connect kitchen").is_err());
+        assert!(parse("stats now").is_err());
+        assert!(parse("set-bitrate fast").is_err());
+        assert!(parse("loss 150%").is_err());
+        assert!(parse("reboot").is_err());
+    }
+}
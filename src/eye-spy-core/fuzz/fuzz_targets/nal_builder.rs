@@ -0,0 +1,19 @@
+//! Run with `cargo +nightly fuzz run nal_builder` from `src/eye-spy-core`.
+//! Whatever the packets are, the builder must not panic.
+
+#![no_main]
+
+use eye_spy_core::nal::NalBuilder;
+use libfuzzer_sys::fuzz_target;
+use scp_client::client::PacketFormat;
+
+fuzz_target!(|packets: Vec<Vec<u8>>| {
+    for format in [PacketFormat::Legacy, PacketFormat::Header] {
+        let mut builder = NalBuilder::new();
+        builder.set_format(format);
+        // Every datagram goes through as is, like the ones read from the socket
+        for packet in &packets {
+            let _ = builder.push_packet(packet);
+        }
+    }
+});
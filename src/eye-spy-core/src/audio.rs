@@ -0,0 +1,52 @@
+//! Channel layout of the call audio. Each client sets the channels it captures and plays in,
+//! and the call goes with the fewer of the two peers, see `scp_client::client::AudioCodec`.
+//! There's no audio stream yet, so only the layout is negotiated for now.
+
+/// Channels of the audio
+/// * `Mono` - a single channel, half the bandwidth of stereo
+/// * `Stereo` - left and right, interleaved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AudioChannels {
+    #[default]
+    Mono,
+    Stereo,
+}
+
+impl AudioChannels {
+    /// Channels shared with the peers
+    pub fn count(self) -> u8 {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo => 2,
+        }
+    }
+    /// The channels negotiated with a peer. Anything above two is taken as stereo
+    pub fn from_count(count: u8) -> Self {
+        if count >= 2 {
+            Self::Stereo
+        } else {
+            Self::Mono
+        }
+    }
+}
+
+impl std::fmt::Display for AudioChannels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mono => write!(f, "mono"),
+            Self::Stereo => write!(f, "stereo"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AudioChannels;
+
+    #[test]
+    fn test_channel_count() {
+        assert_eq!(AudioChannels::from_count(2), AudioChannels::Stereo);
+        assert_eq!(AudioChannels::from_count(0), AudioChannels::Mono);
+        assert_eq!(AudioChannels::Stereo.count(), 2);
+    }
+}
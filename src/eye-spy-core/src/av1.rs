@@ -0,0 +1,495 @@
+//! AV1 through the rav1e encoder and the dav1d decoder of the system, loaded as they're opened,
+//! see `crate::codec`. It's the low-bitrate mode, for uplinks too constrained for the others:
+//! the encoder is set up for `BITRATE` at the fastest speed preset, with the least lookahead,
+//! so every frame comes out as it's encoded.
+//! The parameters of rav1e are set by their names, with `rav1e_config_parse`, the settings of
+//! dav1d are filled in by the library and only the start of its pictures is laid out here.
+//! A temporal unit, the frame with the OBUs around it, goes out as a single unit,
+//! see `crate::codec::units`.
+
+use std::ffi::{c_char, c_int, c_uint, c_void, CStr, CString};
+use std::ptr;
+
+use anyhow::{anyhow, bail};
+use libloading::Library;
+
+use crate::codec::{
+    write_i420_rgba8, CodecBackend, DecodedPicture, VideoDecoder, VideoEncoder, VideoEncoding,
+    YuvFrame,
+};
+
+/// Names the libraries go by, the unversioned ones are only there along with the headers
+const RAV1E_LIBRARIES: [&str; 2] = ["librav1e.so", "librav1e.so.0"];
+const DAV1D_LIBRARIES: [&str; 4] = [
+    "libdav1d.so",
+    "libdav1d.so.7",
+    "libdav1d.so.6",
+    "libdav1d.so.5",
+];
+/// bit/s the video is encoded at
+const BITRATE: u32 = 300_000;
+/// The fastest preset of rav1e, the only one keeping up with the camera on a laptop
+const SPEED: &CStr = c"10";
+/// Frame rate the rate control is set up for, the stream paces the frames itself
+const FPS: u64 = 30;
+const RA_ENCODER_STATUS_SUCCESS: c_int = 0;
+const RA_ENCODER_STATUS_ENCODED: c_int = 4;
+const RA_FRAME_TYPE_OVERRIDE_KEY: c_int = 1;
+/// 8 byte words reserved for `Dav1dSettings`, `Dav1dData` and `Dav1dPicture`
+const DAV1D_WORDS: usize = 64;
+/// `DAV1D_ERR(EAGAIN)`, the picture has to be taken before more data is
+const DAV1D_EAGAIN: c_int = -11;
+const DAV1D_PIXEL_LAYOUT_I420: c_int = 1;
+
+/// `RaRational`
+#[repr(C)]
+struct Rational {
+    num: u64,
+    den: u64,
+}
+
+/// `RaPacket`
+#[repr(C)]
+struct Packet {
+    data: *const u8,
+    len: usize,
+    input_frameno: u64,
+    frame_type: c_int,
+    opaque: *mut c_void,
+}
+
+/// The start of `Dav1dSettings`, as laid out since dav1d 1.0
+#[repr(C)]
+struct Settings {
+    n_threads: c_int,
+    max_frame_delay: c_int,
+    rest: [u64; DAV1D_WORDS],
+}
+
+/// The start of `Dav1dData`, the rest is left to the library
+#[repr(C)]
+struct Data {
+    data: *const u8,
+    size: usize,
+    rest: [u64; DAV1D_WORDS],
+}
+
+/// The start of `Dav1dPicture`, up to its parameters
+#[repr(C)]
+struct Picture {
+    seq_hdr: *const c_void,
+    frame_hdr: *const c_void,
+    data: [*const u8; 3],
+    stride: [isize; 2],
+    width: c_int,
+    height: c_int,
+    layout: c_int,
+    bpc: c_int,
+    rest: [u64; DAV1D_WORDS],
+}
+
+type ConfigDefault = unsafe extern "C" fn() -> *mut c_void;
+type ConfigParse = unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int;
+type ConfigSetTimeBase = unsafe extern "C" fn(*mut c_void, Rational);
+type ConfigUnref = unsafe extern "C" fn(*mut c_void);
+type ContextNew = unsafe extern "C" fn(*const c_void) -> *mut c_void;
+type ContextUnref = unsafe extern "C" fn(*mut c_void);
+type FrameNew = unsafe extern "C" fn(*const c_void) -> *mut c_void;
+type FrameSetType = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type FrameFillPlane = unsafe extern "C" fn(*mut c_void, c_int, *const u8, usize, isize, c_int);
+type FrameUnref = unsafe extern "C" fn(*mut c_void);
+type SendFrame = unsafe extern "C" fn(*mut c_void, *const c_void) -> c_int;
+type ReceivePacket = unsafe extern "C" fn(*mut c_void, *mut *mut Packet) -> c_int;
+type PacketUnref = unsafe extern "C" fn(*mut Packet);
+
+type Version = unsafe extern "C" fn() -> *const c_char;
+type DefaultSettings = unsafe extern "C" fn(*mut Settings);
+type Open = unsafe extern "C" fn(*mut *mut c_void, *const Settings) -> c_int;
+type DataCreate = unsafe extern "C" fn(*mut Data, usize) -> *mut u8;
+type SendData = unsafe extern "C" fn(*mut c_void, *mut Data) -> c_int;
+type GetPicture = unsafe extern "C" fn(*mut c_void, *mut Picture) -> c_int;
+type PictureUnref = unsafe extern "C" fn(*mut Picture);
+type DataUnref = unsafe extern "C" fn(*mut Data);
+type Close = unsafe extern "C" fn(*mut *mut c_void);
+
+/// The functions of rav1e, valid as long as it's loaded
+struct Rav1e {
+    config_default: ConfigDefault,
+    config_parse: ConfigParse,
+    config_set_time_base: ConfigSetTimeBase,
+    config_unref: ConfigUnref,
+    context_new: ContextNew,
+    context_unref: ContextUnref,
+    frame_new: FrameNew,
+    frame_set_type: FrameSetType,
+    frame_fill_plane: FrameFillPlane,
+    frame_unref: FrameUnref,
+    send_frame: SendFrame,
+    receive_packet: ReceivePacket,
+    packet_unref: PacketUnref,
+    _library: Library,
+}
+
+impl Rav1e {
+    fn load() -> anyhow::Result<Self> {
+        let library = load_any(&RAV1E_LIBRARIES).ok_or_else(|| anyhow!("rav1e isn't installed"))?;
+        // SAFETY: the types are the ones of rav1e.h
+        unsafe {
+            Ok(Self {
+                config_default: *library.get(b"rav1e_config_default\0")?,
+                config_parse: *library.get(b"rav1e_config_parse\0")?,
+                config_set_time_base: *library.get(b"rav1e_config_set_time_base\0")?,
+                config_unref: *library.get(b"rav1e_config_unref\0")?,
+                context_new: *library.get(b"rav1e_context_new\0")?,
+                context_unref: *library.get(b"rav1e_context_unref\0")?,
+                frame_new: *library.get(b"rav1e_frame_new\0")?,
+                frame_set_type: *library.get(b"rav1e_frame_set_type\0")?,
+                frame_fill_plane: *library.get(b"rav1e_frame_fill_plane\0")?,
+                frame_unref: *library.get(b"rav1e_frame_unref\0")?,
+                send_frame: *library.get(b"rav1e_send_frame\0")?,
+                receive_packet: *library.get(b"rav1e_receive_packet\0")?,
+                packet_unref: *library.get(b"rav1e_packet_unref\0")?,
+                _library: library,
+            })
+        }
+    }
+}
+
+/// The functions of dav1d, valid as long as it's loaded
+struct Dav1d {
+    version: Version,
+    default_settings: DefaultSettings,
+    open: Open,
+    data_create: DataCreate,
+    send_data: SendData,
+    get_picture: GetPicture,
+    picture_unref: PictureUnref,
+    data_unref: DataUnref,
+    close: Close,
+    _library: Library,
+}
+
+impl Dav1d {
+    fn load() -> anyhow::Result<Self> {
+        let library = load_any(&DAV1D_LIBRARIES).ok_or_else(|| anyhow!("dav1d isn't installed"))?;
+        // SAFETY: the types are the ones of dav1d.h
+        unsafe {
+            Ok(Self {
+                version: *library.get(b"dav1d_version\0")?,
+                default_settings: *library.get(b"dav1d_default_settings\0")?,
+                open: *library.get(b"dav1d_open\0")?,
+                data_create: *library.get(b"dav1d_data_create\0")?,
+                send_data: *library.get(b"dav1d_send_data\0")?,
+                get_picture: *library.get(b"dav1d_get_picture\0")?,
+                picture_unref: *library.get(b"dav1d_picture_unref\0")?,
+                data_unref: *library.get(b"dav1d_data_unref\0")?,
+                close: *library.get(b"dav1d_close\0")?,
+                _library: library,
+            })
+        }
+    }
+}
+
+fn load_any(names: &[&str]) -> Option<Library> {
+    // SAFETY: neither library runs code as it's loaded
+    names
+        .iter()
+        .find_map(|name| unsafe { Library::new(name) }.ok())
+}
+
+/// Whether rav1e and dav1d are there to encode and decode AV1
+pub(crate) fn available() -> bool {
+    Rav1e::load().is_ok() && Dav1d::load().is_ok()
+}
+
+/// A rav1e encoder, for frames of a single size
+/// * `force_intra` - the next frame is made a keyframe
+pub(crate) struct Rav1eEncoder {
+    api: Rav1e,
+    context: *mut c_void,
+    width: usize,
+    height: usize,
+    force_intra: bool,
+}
+
+impl Rav1eEncoder {
+    /// # Errors
+    /// Returns an error if the library isn't there, or it doesn't take the parameters
+    pub(crate) fn open(width: usize, height: usize) -> anyhow::Result<Self> {
+        let api = Rav1e::load()?;
+        let value = |value: String| CString::new(value).unwrap();
+        let (width_value, height_value) = (value(width.to_string()), value(height.to_string()));
+        let bitrate = value(BITRATE.to_string());
+        let settings: [(&CStr, &CStr); 6] = [
+            (c"width", &width_value),
+            (c"height", &height_value),
+            (c"speed", SPEED),
+            (c"low_latency", c"true"),
+            (c"rdo_lookahead_frames", c"1"),
+            (c"bitrate", &bitrate),
+        ];
+        // SAFETY: the configuration is only touched by the library, and dropped once the
+        // context is created from it
+        let context = unsafe {
+            let config = (api.config_default)();
+            if config.is_null() {
+                bail!("rav1e has no default configuration");
+            }
+            for (name, value) in settings {
+                if (api.config_parse)(config, name.as_ptr(), value.as_ptr()) != 0 {
+                    (api.config_unref)(config);
+                    bail!("rav1e doesn't take {name:?} of {value:?}");
+                }
+            }
+            (api.config_set_time_base)(config, Rational { num: 1, den: FPS });
+            let context = (api.context_new)(config);
+            (api.config_unref)(config);
+            context
+        };
+        if context.is_null() {
+            bail!("Cannot open the rav1e encoder for {width}x{height}");
+        }
+        Ok(Self {
+            api,
+            context,
+            width,
+            height,
+            force_intra: false,
+        })
+    }
+}
+
+impl VideoEncoder for Rav1eEncoder {
+    fn encode(&mut self, frame: &YuvFrame) -> anyhow::Result<Vec<u8>> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            bail!(
+                "The rav1e encoder takes {}x{}, not {}x{}",
+                self.width,
+                self.height,
+                frame.width,
+                frame.height
+            );
+        }
+        if !frame.is_complete() {
+            bail!("The planes of the frame are short of its size");
+        }
+        let planes = [
+            (frame.y, frame.strides.0),
+            (frame.u, frame.strides.1),
+            (frame.v, frame.strides.2),
+        ];
+        // SAFETY: the planes are copied into the frame of the library, which is handed over
+        // and dropped. The packets are copied out and dropped before the next one is taken
+        unsafe {
+            let picture = (self.api.frame_new)(self.context);
+            if picture.is_null() {
+                bail!("rav1e has no frame to encode into");
+            }
+            if std::mem::take(&mut self.force_intra) {
+                (self.api.frame_set_type)(picture, RA_FRAME_TYPE_OVERRIDE_KEY);
+            }
+            for (index, (plane, stride)) in planes.into_iter().enumerate() {
+                (self.api.frame_fill_plane)(
+                    picture,
+                    index as c_int,
+                    plane.as_ptr(),
+                    plane.len(),
+                    stride as isize,
+                    1,
+                );
+            }
+            let status = (self.api.send_frame)(self.context, picture);
+            (self.api.frame_unref)(picture);
+            if status != RA_ENCODER_STATUS_SUCCESS {
+                bail!("rav1e refused the frame, status {status}");
+            }
+            let mut encoded = Vec::new();
+            loop {
+                let mut packet: *mut Packet = ptr::null_mut();
+                match (self.api.receive_packet)(self.context, &mut packet) {
+                    RA_ENCODER_STATUS_SUCCESS => {
+                        if let Some(packet) = packet.as_ref() {
+                            encoded.extend_from_slice(std::slice::from_raw_parts(
+                                packet.data,
+                                packet.len,
+                            ));
+                        }
+                        (self.api.packet_unref)(packet);
+                    }
+                    // A frame was encoded without a packet coming out yet
+                    RA_ENCODER_STATUS_ENCODED => continue,
+                    // Needs more frames, or has none to give
+                    _ => break,
+                }
+            }
+            Ok(encoded)
+        }
+    }
+    fn force_intra_frame(&mut self) {
+        self.force_intra = true;
+    }
+    fn backend(&self) -> CodecBackend {
+        CodecBackend::Rav1e
+    }
+    fn encoding(&self) -> VideoEncoding {
+        VideoEncoding::Av1
+    }
+}
+
+impl Drop for Rav1eEncoder {
+    fn drop(&mut self) {
+        // SAFETY: created by this library, and dropped only here
+        unsafe { (self.api.context_unref)(self.context) };
+    }
+}
+
+/// A dav1d decoder, the temporal units are taken whole
+/// * `picture` - the last one decoded, borrowed until the next unit is decoded
+pub(crate) struct Dav1dDecoder {
+    api: Dav1d,
+    context: *mut c_void,
+    picture: Option<Box<Picture>>,
+}
+
+impl Dav1dDecoder {
+    /// # Errors
+    /// Returns an error if the library isn't there, or cannot create the decoder
+    pub(crate) fn open() -> anyhow::Result<Self> {
+        let api = Dav1d::load()?;
+        // SAFETY: the settings are filled in by the library, within the words reserved.
+        // The frame delay is only set where it's laid out as here
+        unsafe {
+            let mut settings: Box<Settings> = Box::new(std::mem::zeroed());
+            (api.default_settings)(&mut *settings);
+            let version = (api.version)();
+            let major = if version.is_null() {
+                0
+            } else {
+                let version = CStr::from_ptr(version).to_string_lossy();
+                version
+                    .split('.')
+                    .next()
+                    .and_then(|major| major.parse::<c_uint>().ok())
+                    .unwrap_or(0)
+            };
+            if major >= 1 {
+                // A picture for every unit, without waiting on the next ones
+                settings.max_frame_delay = 1;
+            }
+            let mut context = ptr::null_mut();
+            let status = (api.open)(&mut context, &*settings);
+            if status != 0 || context.is_null() {
+                bail!("Cannot open the dav1d decoder, status {status}");
+            }
+            Ok(Self {
+                api,
+                context,
+                picture: None,
+            })
+        }
+    }
+    fn release_picture(&mut self) {
+        if let Some(mut picture) = self.picture.take() {
+            // SAFETY: taken from this decoder, and released only once
+            unsafe { (self.api.picture_unref)(&mut *picture) };
+        }
+    }
+}
+
+/// A picture of the decoder
+struct Dav1dPicture<'a>(&'a Picture);
+
+impl DecodedPicture for Dav1dPicture<'_> {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.0.width.max(0) as usize, self.0.height.max(0) as usize)
+    }
+    fn write_rgba8(&self, target: &mut [u8]) {
+        let (width, height) = self.dimensions();
+        let (luma, chroma) = (
+            self.0.stride[0].max(0) as usize,
+            self.0.stride[1].max(0) as usize,
+        );
+        let plane = |index: usize, stride: usize, rows: usize| {
+            // SAFETY: the library holds `rows` rows of the plane, `stride` bytes apart
+            unsafe { std::slice::from_raw_parts(self.0.data[index], stride * rows) }
+        };
+        let chroma_rows = height.div_ceil(2);
+        write_i420_rgba8(
+            (
+                plane(0, luma, height),
+                plane(1, chroma, chroma_rows),
+                plane(2, chroma, chroma_rows),
+            ),
+            (luma, chroma, chroma),
+            (width, height),
+            target,
+        );
+    }
+}
+
+impl VideoDecoder for Dav1dDecoder {
+    fn decode(&mut self, unit: &[u8]) -> anyhow::Result<Option<Box<dyn DecodedPicture + '_>>> {
+        self.release_picture();
+        // SAFETY: the unit is copied into the data of the library, which takes it over once
+        // it's sent. The picture is kept until the next unit, and released then
+        unsafe {
+            let mut data: Box<Data> = Box::new(std::mem::zeroed());
+            let buffer = (self.api.data_create)(&mut *data, unit.len());
+            if buffer.is_null() {
+                bail!("dav1d has no buffer for the unit");
+            }
+            ptr::copy_nonoverlapping(unit.as_ptr(), buffer, unit.len());
+            let mut picture: Box<Picture> = Box::new(std::mem::zeroed());
+            let mut decoded = false;
+            while data.size > 0 {
+                match (self.api.send_data)(self.context, &mut *data) {
+                    0 => {}
+                    DAV1D_EAGAIN => {
+                        // The decoder holds a picture, it takes the rest once that's out
+                        if decoded {
+                            (self.api.picture_unref)(&mut *picture);
+                        }
+                        decoded = (self.api.get_picture)(self.context, &mut *picture) == 0;
+                        if !decoded {
+                            break;
+                        }
+                    }
+                    status => {
+                        (self.api.data_unref)(&mut *data);
+                        bail!("dav1d refused the unit, status {status}");
+                    }
+                }
+            }
+            if data.size > 0 {
+                (self.api.data_unref)(&mut *data);
+            }
+            if !decoded {
+                match (self.api.get_picture)(self.context, &mut *picture) {
+                    0 => {}
+                    DAV1D_EAGAIN => return Ok(None),
+                    status => bail!("dav1d failed to decode the unit, status {status}"),
+                }
+            }
+            if picture.layout != DAV1D_PIXEL_LAYOUT_I420 || picture.bpc != 8 {
+                (self.api.picture_unref)(&mut *picture);
+                bail!("dav1d decoded the unit into another layout than 8 bit I420");
+            }
+            let picture = self.picture.insert(picture);
+            Ok(Some(Box::new(Dav1dPicture(picture))))
+        }
+    }
+    fn backend(&self) -> CodecBackend {
+        CodecBackend::Dav1d
+    }
+    fn encoding(&self) -> VideoEncoding {
+        VideoEncoding::Av1
+    }
+}
+
+impl Drop for Dav1dDecoder {
+    fn drop(&mut self) {
+        self.release_picture();
+        // SAFETY: opened by this library, and closed only here
+        unsafe { (self.api.close)(&mut self.context) };
+    }
+}
@@ -0,0 +1,304 @@
+//! Lip-sync between the audio and video streams received from a peer.
+//! Both streams carry capture timestamps taken from the same clock of the sender, so the difference
+//! between arrival and capture time tells which stream is ahead. The one ahead gets delayed
+//! until both stay within `SYNC_TOLERANCE` of each other.
+//! The clocks don't run at quite the same rate either, so over a long call the transit times
+//! walk away. `ClockDrift` estimates the rate from the timestamps and takes it out of the transit
+//! times, so neither stream is held back for what's only the drift of a clock.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How far apart audio and video may drift before the stream ahead gets delayed
+pub const SYNC_TOLERANCE: Duration = Duration::from_millis(60);
+/// A stream that lags more than this is broken rather than slow, don't hold the other one back for it
+const MAX_SYNC_DELAY: Duration = Duration::from_secs(1);
+/// The lowest transit time is taken from windows this long, in microseconds.
+/// Jitter only ever adds to the transit, the lowest one follows the clocks
+const DRIFT_WINDOW_US: u64 = 10_000_000;
+/// Windows the drift is fitted over, the last few minutes of the call
+const DRIFT_WINDOWS: usize = 30;
+/// More than that is a clock being set rather than drifting, it's not followed
+const MAX_DRIFT_PPM: f64 = 1000.;
+
+pub type SharedAvSync = Arc<Mutex<AvSync>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+/// Microseconds since the UNIX epoch. Used to stamp captured media and its arrival
+pub fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Estimates how fast the clock stamping a stream runs against the local one,
+/// from the capture and arrival timestamps of the stream.
+/// The lowest transit time of every `DRIFT_WINDOW_US` is fitted with a line, its slope is the drift
+#[derive(Debug, Default, Clone)]
+pub struct ClockDrift {
+    /// Arrival of the first report, the windows are counted from it
+    start: Option<u64>,
+    /// Middle of each window since `start` and the lowest transit in it, in microseconds
+    windows: VecDeque<(f64, f64)>,
+    /// The window being filled and the lowest transit in it so far
+    current: Option<(u64, i64)>,
+    /// Microseconds of transit gained every microsecond
+    slope: f64,
+}
+
+impl ClockDrift {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Report a piece of media captured at `capture_ts` that arrived at `arrival_ts`
+    pub fn report(&mut self, capture_ts: u64, arrival_ts: u64) {
+        let transit = arrival_ts as i64 - capture_ts as i64;
+        let start = *self.start.get_or_insert(arrival_ts);
+        let window = arrival_ts.saturating_sub(start) / DRIFT_WINDOW_US;
+        self.current = match self.current {
+            Some((current, lowest)) if current == window => Some((window, lowest.min(transit))),
+            Some((current, lowest)) => {
+                self.close_window(current, lowest);
+                Some((window, transit))
+            }
+            None => Some((window, transit)),
+        };
+    }
+    /// Parts per million the transit time grows by, negative if it shrinks.
+    /// Positive when the clock of the stream runs slower than the local one
+    pub fn drift_ppm(&self) -> f64 {
+        self.slope * 1e6
+    }
+    /// The transit time in microseconds without the drift since the first report,
+    /// as if the clocks ran at the same rate
+    pub fn compensate(&self, capture_ts: u64, arrival_ts: u64) -> i64 {
+        let transit = arrival_ts as i64 - capture_ts as i64;
+        let elapsed = arrival_ts.saturating_sub(self.start.unwrap_or(arrival_ts));
+        transit - (self.slope * elapsed as f64) as i64
+    }
+
+    fn close_window(&mut self, window: u64, lowest: i64) {
+        let middle = (window as f64 + 0.5) * DRIFT_WINDOW_US as f64;
+        self.windows.push_back((middle, lowest as f64));
+        if self.windows.len() > DRIFT_WINDOWS {
+            self.windows.pop_front();
+        }
+        if let Some(slope) = fit_slope(&self.windows) {
+            if slope.abs() * 1e6 <= MAX_DRIFT_PPM {
+                self.slope = slope;
+            }
+        }
+    }
+}
+
+/// Least squares slope of the points, None for less than two of them
+fn fit_slope(points: &VecDeque<(f64, f64)>) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0., 0.);
+    for (x, y) in points {
+        covariance += (x - mean_x) * (y - mean_y);
+        variance += (x - mean_x) * (x - mean_x);
+    }
+    (variance > 0.).then(|| covariance / variance)
+}
+
+/// Tracks how long each stream takes from capture to arrival.
+/// The clocks of the peers don't have to agree, the constant offset cancels out between the streams.
+/// The drift of the clocks is taken out of each stream, see `ClockDrift`
+#[derive(Debug, Default)]
+pub struct AvSync {
+    /// Smoothed transit time of video in microseconds
+    video_transit: Option<i64>,
+    /// Smoothed transit time of audio in microseconds
+    audio_transit: Option<i64>,
+    video_drift: ClockDrift,
+    audio_drift: ClockDrift,
+}
+
+impl AvSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report a piece of media captured at `capture_ts` that arrived at `arrival_ts`.
+    /// Both are microseconds, see `timestamp_now()`
+    pub fn report(&mut self, kind: MediaKind, capture_ts: u64, arrival_ts: u64) {
+        let (smoothed, drift) = match kind {
+            MediaKind::Audio => (&mut self.audio_transit, &mut self.audio_drift),
+            MediaKind::Video => (&mut self.video_transit, &mut self.video_drift),
+        };
+        drift.report(capture_ts, arrival_ts);
+        let transit = drift.compensate(capture_ts, arrival_ts);
+        // Moving average, so a single late packet doesn't make the other stream jump
+        *smoothed = Some(match *smoothed {
+            Some(old) => old + (transit - old) / 8,
+            None => transit,
+        });
+    }
+
+    /// Drift of the clock stamping the media of given kind, see `ClockDrift::drift_ppm`
+    pub fn drift_ppm(&self, kind: MediaKind) -> f64 {
+        match kind {
+            MediaKind::Audio => self.audio_drift.drift_ppm(),
+            MediaKind::Video => self.video_drift.drift_ppm(),
+        }
+    }
+    /// How long the media of given kind should be held back before playing it
+    pub fn delay_for(&self, kind: MediaKind) -> Duration {
+        let (Some(video), Some(audio)) = (self.video_transit, self.audio_transit) else {
+            // Nothing to sync with
+            return Duration::ZERO;
+        };
+        let (own, other) = match kind {
+            MediaKind::Audio => (audio, video),
+            MediaKind::Video => (video, audio),
+        };
+        if own >= other {
+            return Duration::ZERO;
+        }
+        let ahead_by = Duration::from_micros((other - own) as u64);
+        if ahead_by <= SYNC_TOLERANCE {
+            return Duration::ZERO;
+        }
+        ahead_by.min(MAX_SYNC_DELAY)
+    }
+}
+
+/// Holds the media back until its playout time. The order of the items is always kept.
+#[derive(Debug)]
+pub struct PlayoutQueue<T> {
+    queue: VecDeque<(Instant, T)>,
+}
+
+impl<T> Default for PlayoutQueue<T> {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> PlayoutQueue<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Queue the item to be released after `delay` from `now`
+    pub fn push(&mut self, item: T, delay: Duration, now: Instant) {
+        let mut release = now + delay;
+        // A shrinking delay must not let the item overtake the ones queued before it
+        if let Some((last, _)) = self.queue.back() {
+            release = release.max(*last);
+        }
+        self.queue.push_back((release, item));
+    }
+    /// Take the next item if its playout time has come
+    pub fn pop_ready(&mut self, now: Instant) -> Option<T> {
+        match self.queue.front() {
+            Some((release, _)) if *release <= now => self.queue.pop_front().map(|(_, item)| item),
+            _ => None,
+        }
+    }
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{AvSync, ClockDrift, MediaKind, PlayoutQueue};
+
+    /// Capture and arrival timestamps of an hour of a stream at 30 fps, stamped by a clock
+    /// `ppm` slower than the local one. The transit is 20ms, up to 30ms more with the jitter
+    fn drifting_stream(ppm: i64) -> impl Iterator<Item = (u64, u64)> {
+        let start = 1_700_000_000_000_000u64;
+        (0..30 * 3600u64).map(move |frame| {
+            let elapsed = frame * 33_333;
+            let jitter = (frame * 7919) % 30_000;
+            let drift = elapsed as i64 * ppm / 1_000_000;
+            let capture = (start as i64 + elapsed as i64 - drift) as u64;
+            (capture, start + elapsed + 20_000 + jitter)
+        })
+    }
+
+    #[test]
+    fn test_no_delay_for_single_stream() {
+        let mut sync = AvSync::new();
+        sync.report(MediaKind::Video, 1_000, 500_000);
+        assert_eq!(sync.delay_for(MediaKind::Video), Duration::ZERO);
+    }
+    #[test]
+    fn test_stream_ahead_is_delayed() {
+        let mut sync = AvSync::new();
+        // Video arrives 20ms after capture, audio 220ms after
+        sync.report(MediaKind::Video, 1_000_000, 1_020_000);
+        sync.report(MediaKind::Audio, 1_000_000, 1_220_000);
+        assert_eq!(sync.delay_for(MediaKind::Video), Duration::from_millis(200));
+        assert_eq!(sync.delay_for(MediaKind::Audio), Duration::ZERO);
+    }
+    #[test]
+    fn test_within_tolerance() {
+        let mut sync = AvSync::new();
+        sync.report(MediaKind::Video, 1_000_000, 1_020_000);
+        sync.report(MediaKind::Audio, 1_000_000, 1_060_000);
+        assert_eq!(sync.delay_for(MediaKind::Video), Duration::ZERO);
+        assert_eq!(sync.delay_for(MediaKind::Audio), Duration::ZERO);
+    }
+    #[test]
+    fn test_clock_drift_estimate() {
+        let mut drift = ClockDrift::new();
+        let mut last = None;
+        for (capture, arrival) in drifting_stream(100) {
+            drift.report(capture, arrival);
+            last = Some((capture, arrival));
+        }
+        assert!(
+            (drift.drift_ppm() - 100.).abs() < 5.,
+            "{}",
+            drift.drift_ppm()
+        );
+        // 360ms of drift after the hour, only the transit and the jitter are left of it
+        let (capture, arrival) = last.unwrap();
+        assert!(arrival - capture > 350_000);
+        let compensated = drift.compensate(capture, arrival);
+        assert!((10_000..60_000).contains(&compensated), "{compensated}");
+    }
+    #[test]
+    fn test_drift_doesnt_hold_the_other_stream() {
+        let mut sync = AvSync::new();
+        // Audio stamped by a clock of its own, drifting away from the one of the video
+        for ((video, video_arrival), (audio, audio_arrival)) in
+            drifting_stream(0).zip(drifting_stream(200))
+        {
+            sync.report(MediaKind::Video, video, video_arrival);
+            sync.report(MediaKind::Audio, audio, audio_arrival);
+        }
+        assert!(sync.drift_ppm(MediaKind::Audio) > 190.);
+        assert_eq!(sync.delay_for(MediaKind::Video), Duration::ZERO);
+        assert_eq!(sync.delay_for(MediaKind::Audio), Duration::ZERO);
+    }
+    #[test]
+    fn test_playout_queue_keeps_order() {
+        let now = Instant::now();
+        let mut queue = PlayoutQueue::new();
+        queue.push(1, Duration::from_millis(100), now);
+        queue.push(2, Duration::ZERO, now);
+        assert_eq!(queue.pop_ready(now), None);
+        let later = now + Duration::from_millis(100);
+        assert_eq!(queue.pop_ready(later), Some(1));
+        assert_eq!(queue.pop_ready(later), Some(2));
+    }
+}
@@ -0,0 +1,162 @@
+//! Bandwidth probing as a call starts, so the camera mode is picked for what the path to the peer
+//! carries instead of `CallConfig::bitrate_budget` alone. A burst of padding packets is sent
+//! at once to the incoming stream of the peer, and the path spreads it over the time it takes
+//! to carry it. The peer tells over SCP how many came through and how far apart the first
+//! and the last arrived, see `ControlMessage::BandwidthReport`.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::nal::{DEFAULT_PACKET_DATA_SIZE, PACKET_META_LEN};
+
+/// Starts every packet of a burst. Stream packets start with a NAL unit, the end of one or the header
+const BURST_MAGIC: &[u8] = b"BWPROBE\0";
+/// Packets of a burst, about 260 kbit as long as a stream packet of the default size
+const BURST_PACKETS: usize = 64;
+/// The peer reports a burst once no packet of it came for that long
+const BURST_QUIET: Duration = Duration::from_millis(200);
+/// A burst not reported by then leaves the budget as it is, i.e. the peer doesn't time them
+const REPORT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Bursts spread over less than that came too fast to tell the capacity of the path
+const MIN_SPAN: Duration = Duration::from_millis(1);
+/// Share of the capacity the video is picked for, the rest is left to the keyframes and the audio
+const VIDEO_SHARE: u64 = 80;
+
+/// Whether the packet is a part of a burst, not of the stream
+pub(crate) fn is_burst_packet(packet: &[u8]) -> bool {
+    packet.starts_with(BURST_MAGIC)
+}
+
+/// A packet of a burst, as long as a stream packet of the default size
+fn burst_packet() -> Vec<u8> {
+    let mut packet = vec![0; DEFAULT_PACKET_DATA_SIZE + PACKET_META_LEN];
+    packet[..BURST_MAGIC.len()].copy_from_slice(BURST_MAGIC);
+    packet
+}
+
+/// A burst sent to the peer of a call, waiting for its report
+pub(crate) struct BandwidthProbe {
+    sent_at: Instant,
+}
+
+impl BandwidthProbe {
+    /// Send a burst to the incoming stream of the peer at `target`
+    /// # Errors
+    /// Returns an error if the socket of the burst cannot be set up
+    pub(crate) fn start(target: SocketAddr) -> io::Result<Self> {
+        let any = match target.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0))?;
+        let packet = burst_packet();
+        for _ in 0..BURST_PACKETS {
+            // The ones lost on the first hop already tell as much as the ones lost on the way
+            let _ = socket.send_to(&packet, target);
+        }
+        Ok(Self {
+            sent_at: Instant::now(),
+        })
+    }
+    /// Whether the peer gave up reporting, or never does
+    pub(crate) fn timed_out(&self, now: Instant) -> bool {
+        now.duration_since(self.sent_at) >= REPORT_TIMEOUT
+    }
+    /// kbit/s the video may take on the path, given the report of the peer.
+    /// None if the burst came through too fast to tell, or too little of it to time
+    pub(crate) fn video_budget(&self, packets: u16, span_us: u32) -> Option<u32> {
+        video_budget(packets, Duration::from_micros(span_us.into()))
+    }
+}
+
+fn video_budget(packets: u16, span: Duration) -> Option<u32> {
+    if packets < 2 || span < MIN_SPAN {
+        return None;
+    }
+    // The span is what the path took to carry all but the first
+    let bits = (u64::from(packets) - 1) * (burst_packet().len() as u64) * 8;
+    let capacity = bits * 1000 / span.as_micros() as u64;
+    u32::try_from(capacity * VIDEO_SHARE / 100).ok()
+}
+
+/// Times the bursts of the peer, to report them once they're over
+#[derive(Debug, Default)]
+pub(crate) struct BurstTimer {
+    /// When the first and the last packet of the burst came, None between the bursts
+    arrivals: Option<(Instant, Instant)>,
+    packets: u16,
+}
+
+impl BurstTimer {
+    pub(crate) fn on_packet(&mut self, at: Instant) {
+        self.arrivals = match self.arrivals {
+            Some((first, last)) => Some((first, last.max(at))),
+            None => Some((at, at)),
+        };
+        self.packets = self.packets.saturating_add(1);
+    }
+    /// Packets of the burst that came through, and microseconds between the first and the last.
+    /// None until it's over
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<(u16, u32)> {
+        let (first, last) = self.arrivals?;
+        if now.duration_since(last) < BURST_QUIET {
+            return None;
+        }
+        self.arrivals = None;
+        let span = u32::try_from(last.duration_since(first).as_micros()).unwrap_or(u32::MAX);
+        Some((std::mem::take(&mut self.packets), span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        burst_packet, is_burst_packet, video_budget, BandwidthProbe, BurstTimer, BURST_PACKETS,
+        BURST_QUIET,
+    };
+
+    #[test]
+    fn test_video_budget() {
+        // 63 packets of 514 bytes in 200 ms is 1295 kbit/s, 80% of it for the video
+        assert_eq!(video_budget(64, Duration::from_millis(200)), Some(1036));
+        // Twice as fast
+        assert_eq!(video_budget(64, Duration::from_millis(100)), Some(2072));
+        assert_eq!(video_budget(1, Duration::from_millis(200)), None);
+        assert_eq!(video_budget(64, Duration::from_micros(300)), None);
+    }
+    #[test]
+    fn test_burst_timer() {
+        let mut timer = BurstTimer::default();
+        let start = Instant::now();
+        assert_eq!(timer.poll(start), None);
+        for i in 0..10 {
+            timer.on_packet(start + Duration::from_millis(i * 5));
+        }
+        let last = start + Duration::from_millis(45);
+        assert_eq!(timer.poll(last), None);
+        assert_eq!(timer.poll(last + BURST_QUIET), Some((10, 45_000)));
+        // Reported once
+        assert_eq!(timer.poll(last + BURST_QUIET * 2), None);
+    }
+    #[test]
+    fn test_burst_over_loopback() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let probe = BandwidthProbe::start(peer.local_addr().unwrap()).unwrap();
+        let mut timer = BurstTimer::default();
+        let mut buf = [0; 2048];
+        while let Ok(size) = peer.recv(&mut buf) {
+            assert!(is_burst_packet(&buf[..size]));
+            timer.on_packet(Instant::now());
+        }
+        let (packets, _) = timer.poll(Instant::now() + BURST_QUIET).unwrap();
+        assert_eq!(usize::from(packets), BURST_PACKETS);
+        assert!(!probe.timed_out(Instant::now()));
+        assert!(!is_burst_packet(&burst_packet()[1..]));
+    }
+}
@@ -0,0 +1,233 @@
+//! Discovery over UDP broadcast, for the networks that filter multicast and so mDNS.
+//! A client advertised with a beacon port sends its beacon to the broadcast address
+//! every `BEACON_INTERVAL`, see `mdns::Registration::start_beacon`. The ones looking for peers
+//! listen on the port for a while, and the beacons are parsed into the same `DiscoveredPeer`s
+//! as the mDNS records, so the two are merged in one list, see `mdns::find_all_hosts`.
+//!
+//! Beacon: <MAGIC(8 bytes)><key=value\n>...
+//!
+//! The keys are the ones of the TXT record, see `mdns::TxtRecord`, along with the `service`
+//! and the `host` name the client is advertised as.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tracing::{debug, warn};
+
+use crate::mdns::{DiscoveredPeer, TxtRecord};
+
+/// Starts every beacon, so other broadcasts on the port aren't taken for one
+pub const BEACON_MAGIC: &[u8; 8] = b"EYESPYB1";
+/// How often a client sends its beacon
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+/// Listened for the beacons, long enough to hear every client even if one beacon is lost
+const LISTEN_TIME: Duration = Duration::from_millis(2500);
+/// Larger beacons are cut short
+const MAX_BEACON_LEN: usize = 1500;
+
+/// Sends the beacon of a client until it's dropped
+pub struct Beacon {
+    payload: Arc<Mutex<Vec<u8>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Beacon {
+    /// # Errors
+    /// Returns an error if the socket cannot be made to broadcast
+    pub(crate) fn start(port: u16, payload: Vec<u8>) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| socket.set_broadcast(true).map(|()| socket))
+            .context("Cannot open the beacon socket")?;
+        let payload = Arc::new(Mutex::new(payload));
+        let stop = Arc::new(AtomicBool::new(false));
+        let beacon = Self {
+            payload: Arc::clone(&payload),
+            stop: Arc::clone(&stop),
+        };
+        std::thread::spawn(move || {
+            // Logged once, the network may come back
+            let mut failing = false;
+            while !stop.load(Ordering::Relaxed) {
+                let sent = {
+                    let payload = payload.lock().unwrap();
+                    socket.send_to(&payload, (Ipv4Addr::BROADCAST, port))
+                };
+                match sent {
+                    Ok(_) => failing = false,
+                    Err(e) if !failing => {
+                        warn!("Cannot broadcast the beacon on port {port}: {e}");
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+                std::thread::sleep(BEACON_INTERVAL);
+            }
+        });
+        Ok(beacon)
+    }
+    /// The beacon sent from now on
+    pub(crate) fn set_payload(&self, payload: Vec<u8>) {
+        *self.payload.lock().unwrap() = payload;
+    }
+}
+
+impl Drop for Beacon {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The beacon of a client advertised as `service` at `host_name`
+pub(crate) fn encode(service: &str, host_name: &str, txt: &TxtRecord) -> Vec<u8> {
+    let mut payload = BEACON_MAGIC.to_vec();
+    let fields = [
+        ("service", service.to_string()),
+        ("host", host_name.to_string()),
+    ];
+    for (key, value) in fields.into_iter().chain(txt.properties()) {
+        // A line for every field
+        let value = value.replace('\n', " ");
+        payload.extend_from_slice(format!("{key}={value}\n").as_bytes());
+    }
+    payload
+}
+
+/// The service of a beacon received from `from` and the peer it advertises.
+/// None if it's not a beacon, or it lacks the SCP port
+fn decode(beacon: &[u8], from: IpAddr) -> Option<(String, DiscoveredPeer)> {
+    let fields = std::str::from_utf8(beacon.strip_prefix(BEACON_MAGIC)?).ok()?;
+    let fields: BTreeMap<&str, &str> = fields
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+    let txt = TxtRecord::from_fields(|key| fields.get(key).copied());
+    let port = txt.scp_port?;
+    let host_name = match fields.get("host") {
+        Some(host_name) => host_name.to_string(),
+        None => format!("{from}.local."),
+    };
+    let peer = DiscoveredPeer {
+        host_name,
+        addrs: vec![SocketAddr::new(from, port)],
+        txt,
+    };
+    Some((fields.get("service")?.to_string(), peer))
+}
+
+/// Finds the clients of `service` sending their beacons to `port`.
+/// # Blocking
+/// This function listens for `LISTEN_TIME`
+pub fn find_all_hosts(service: &str, port: u16) -> Vec<DiscoveredPeer> {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Cannot listen for the beacons on port {port}: {e}");
+            return Vec::new();
+        }
+    };
+    let deadline = Instant::now() + LISTEN_TIME;
+    let mut peers: Vec<DiscoveredPeer> = Vec::new();
+    let mut buf = [0; MAX_BEACON_LEN];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if left.is_zero() || socket.set_read_timeout(Some(left)).is_err() {
+            break;
+        }
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            break;
+        };
+        let Some((beacon_service, peer)) = decode(&buf[..len], from.ip()) else {
+            debug!("Not a beacon from {from}");
+            continue;
+        };
+        if beacon_service != service {
+            continue;
+        }
+        // The newest beacon of a client replaces the ones before
+        match peers.iter_mut().find(|found| found.addrs == peer.addrs) {
+            Some(found) => *found = peer,
+            None => peers.push(peer),
+        }
+    }
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    use super::{decode, encode, find_all_hosts, BEACON_MAGIC};
+    use crate::mdns::{Capabilities, TxtRecord, DEFAULT_SERVICE_NAME};
+
+    fn txt() -> TxtRecord {
+        TxtRecord {
+            proto_ver: Some(2),
+            scp_port: Some(60102),
+            name: Some("kitchen\nsink".to_string()),
+            caps: Capabilities {
+                screen: true,
+                srtp: true,
+            },
+            in_call: false,
+        }
+    }
+
+    #[test]
+    fn test_beacons() {
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 12));
+        let beacon = encode(DEFAULT_SERVICE_NAME, "desk.local.", &txt());
+        let (service, peer) = decode(&beacon, from).unwrap();
+        assert_eq!(service, DEFAULT_SERVICE_NAME);
+        assert_eq!(peer.host_name, "desk.local.");
+        assert_eq!(peer.addrs, [SocketAddr::new(from, 60102)]);
+        assert_eq!(peer.name(), "kitchen sink");
+        assert_eq!(
+            peer.txt,
+            TxtRecord {
+                name: Some("kitchen sink".to_string()),
+                ..txt()
+            }
+        );
+
+        assert!(decode(&beacon[1..], from).is_none());
+        let no_port = encode(
+            DEFAULT_SERVICE_NAME,
+            "desk.local.",
+            &TxtRecord {
+                scp_port: None,
+                ..txt()
+            },
+        );
+        assert!(decode(&no_port, from).is_none());
+        assert!(decode(BEACON_MAGIC, from).is_none());
+    }
+    #[test]
+    fn test_find_all_hosts() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sender = std::thread::spawn(move || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let other = encode("_eye-spy-dev._tcp.local.", "desk.local.", &txt());
+            let beacon = encode(DEFAULT_SERVICE_NAME, "desk.local.", &txt());
+            for _ in 0..5 {
+                std::thread::sleep(Duration::from_millis(100));
+                socket
+                    .send_to(b"not a beacon", ("127.0.0.1", port))
+                    .unwrap();
+                socket.send_to(&other, ("127.0.0.1", port)).unwrap();
+                socket.send_to(&beacon, ("127.0.0.1", port)).unwrap();
+            }
+        });
+        let peers = find_all_hosts(DEFAULT_SERVICE_NAME, port);
+        sender.join().unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].host_name, "desk.local.");
+    }
+}
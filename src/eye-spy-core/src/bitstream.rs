@@ -0,0 +1,191 @@
+//! Tooling to reproduce stream bugs offline.
+//! `BitstreamDump` records the received H.264 elementary stream along with a trace of the packets,
+//! `FileSource` replays a recorded `.h264` file in place of the camera.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use openh264::nal_units;
+
+use crate::av_sync::timestamp_now;
+use crate::h264_stream::CustomStream;
+
+/// Frame rate of the replayed file, `.h264` files don't store one
+const REPLAY_FPS: u32 = 30;
+
+/// Dump of a single incoming stream.
+/// * `<name>.h264` - the rebuilt NAL units, playable with e.g. `ffplay`
+/// * `<name>.trace.csv` - every received packet: microseconds since the start, its size and kind
+pub struct BitstreamDump {
+    h264: BufWriter<File>,
+    trace: BufWriter<File>,
+    start: Instant,
+}
+
+impl BitstreamDump {
+    /// Creates the dump files in `dir`, named after the current time
+    /// # Errors
+    /// Returns an error if the files cannot be created
+    pub fn create(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let name = format!("stream-{}", timestamp_now());
+        let h264 = File::create(dir.join(format!("{name}.h264")))?;
+        let mut trace = BufWriter::new(File::create(dir.join(format!("{name}.trace.csv")))?);
+        writeln!(trace, "elapsed_us,size,kind")?;
+        Ok(Self {
+            h264: BufWriter::new(h264),
+            trace,
+            start: Instant::now(),
+        })
+    }
+    /// Record a packet as it came from the socket. Only the trace is written, not the data
+    pub fn record_packet(&mut self, packet: &[u8], kind: &str) {
+        let elapsed = self.start.elapsed().as_micros();
+        let _ = writeln!(self.trace, "{elapsed},{},{kind}", packet.len());
+    }
+    /// Record a rebuilt NAL unit, start code included
+    pub fn record_unit(&mut self, unit: &[u8]) {
+        let _ = self.h264.write_all(unit);
+    }
+}
+
+impl Drop for BitstreamDump {
+    fn drop(&mut self) {
+        let _ = self.h264.flush();
+        let _ = self.trace.flush();
+    }
+}
+
+/// Replays a recorded H.264 elementary stream as if it came live from a camera.
+/// Frames are released at `REPLAY_FPS` and the file starts over once it ends.
+pub struct FileSource {
+    data: Vec<u8>,
+    /// Access units of the file: the parameter sets and slices making up a single frame
+    frames: Vec<Range<usize>>,
+    next_frame: usize,
+    last_frame: Option<Instant>,
+    frame_interval: Duration,
+}
+
+impl FileSource {
+    /// # Errors
+    /// Returns an error if the file cannot be read or contains no frames
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        let frames = split_frames(&data);
+        if frames.is_empty() {
+            anyhow::bail!("No H.264 frames found in {}", path.display());
+        }
+        Ok(Self {
+            data,
+            frames,
+            next_frame: 0,
+            last_frame: None,
+            frame_interval: Duration::from_secs(1) / REPLAY_FPS,
+        })
+    }
+    /// Next frame of the file, waiting until its time has come
+    fn next_frame(&mut self) -> &[u8] {
+        if let Some(elapsed) = self.last_frame.map(|last| last.elapsed()) {
+            if elapsed < self.frame_interval {
+                std::thread::sleep(self.frame_interval - elapsed);
+            }
+        }
+        self.last_frame = Some(Instant::now());
+
+        let range = self.frames[self.next_frame].clone();
+        self.next_frame = (self.next_frame + 1) % self.frames.len();
+        &self.data[range]
+    }
+}
+
+impl CustomStream<'_, File> for FileSource {
+    fn next(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let frame = self.next_frame();
+        let len = frame.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&frame[..len]);
+        Some(len)
+    }
+    fn try_next_vec(&mut self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.next_frame().to_vec())
+    }
+}
+
+/// Type of the NAL unit, skipping its start code
+fn nal_type(unit: &[u8]) -> Option<u8> {
+    let start_code_end = unit.iter().position(|b| *b != 0)?;
+    unit.get(start_code_end + 1).map(|header| header & 0x1F)
+}
+
+/// Groups the NAL units of the stream into frames. A frame ends with its first slice (types 1-5),
+/// so parameter sets are sent along with the frame following them. The frames follow each other
+/// without gaps, whichever unit the zero bytes of a start code are counted to.
+fn split_frames(data: &[u8]) -> Vec<Range<usize>> {
+    let mut frames = Vec::new();
+    let mut frame_start = 0;
+    for unit in nal_units(data) {
+        if nal_type(unit).is_some_and(|t| (1..=5).contains(&t)) {
+            let frame_end = unit.as_ptr() as usize - data.as_ptr() as usize + unit.len();
+            frames.push(frame_start..frame_end);
+            frame_start = frame_end;
+        }
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::{nal_type, BitstreamDump, FileSource};
+    use crate::h264_stream::CustomStream;
+
+    #[test]
+    fn test_file_source_replays_in_loop() {
+        let bytes = include_bytes!("../test.h264");
+        let mut source =
+            FileSource::open(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264"))).unwrap();
+        source.frame_interval = Duration::ZERO;
+        let frame_count = source.frames.len();
+        assert!(frame_count > 1);
+
+        let first = source.try_next_vec().unwrap();
+        // Parameter sets come along with the first frame
+        assert_eq!(nal_type(&first), Some(7));
+        assert!(bytes.starts_with(&first));
+        let mut replayed = first.clone();
+        for _ in 1..frame_count {
+            replayed.extend(source.try_next_vec().unwrap());
+        }
+        assert_eq!(replayed, bytes);
+        assert_eq!(source.try_next_vec().unwrap(), first);
+    }
+    #[test]
+    fn test_dump_writes_stream_and_trace() {
+        let dir = std::env::temp_dir().join(format!("eye-spy-dump-{}", std::process::id()));
+        {
+            let mut dump = BitstreamDump::create(&dir).unwrap();
+            dump.record_packet(&[0; 12], "data");
+            dump.record_unit(&[0, 0, 0, 1, 0x65]);
+        }
+        let mut h264 = None;
+        let mut trace = None;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let contents = std::fs::read(&path).unwrap();
+            if path.to_string_lossy().ends_with(".trace.csv") {
+                trace = Some(String::from_utf8(contents).unwrap());
+            } else {
+                h264 = Some(contents);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(h264.unwrap(), [0, 0, 0, 1, 0x65]);
+        let trace = trace.unwrap();
+        assert!(trace.lines().nth(1).unwrap().ends_with(",12,data"));
+    }
+}
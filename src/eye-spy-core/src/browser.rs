@@ -0,0 +1,533 @@
+//! Browsers joining the calls over WebRTC, built with the `webrtc` feature.
+//! The bridge serves a page at its address. The page posts the SDP offer of the browser to `/offer`
+//! and gets the answer back with every ICE candidate in it, so that exchange is all the signaling.
+//! ICE, DTLS-SRTP and RTP are left to the webrtc crate.
+//!
+//! The bridge stands in for the peer on this machine. The outgoing stream is sent to its socket
+//! like to any peer and goes on to the browser as H.264 samples. The video of the browser is
+//! packetized for the incoming stream, see `crate::nal`. So `CallManager` runs the call like
+//! a loopback one, with one browser at a time and only while there's no other call.
+//! A browser joining rings like a peer calling, and the peer list and `CallConfig::auto_answer`
+//! apply to it by its IP.
+//! There's no audio in the calls yet, so the browser gets none.
+//! Browsers only give the camera to the pages of `localhost` or HTTPS ones.
+//! A browser on another machine only watches, unless the bridge is put behind a TLS proxy.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::runtime::{Handle, Runtime};
+use tracing::{debug, info, info_span, warn};
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+use webrtc::api::{APIBuilder, API};
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::Sample;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp::codecs::h264::H264Packet;
+use webrtc::rtp::packetizer::Depacketizer;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+use webrtc::track::track_remote::TrackRemote;
+
+use crate::av_sync::timestamp_now;
+use crate::h264_stream::join_with_timeout;
+use crate::nal::{NalBuilder, PacketBuffer};
+
+/// How long a browser may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the threads check if they should stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+/// Longest request read, an offer takes a few kB
+const MAX_REQUEST_LEN: usize = 65536;
+/// How often the browser is asked for a keyframe. A browser answers the losses on its own,
+/// but the incoming stream has no way to ask it
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(2);
+/// A frame isn't shown longer than this, however long the camera paused
+const MAX_FRAME_DURATION: Duration = Duration::from_millis(500);
+/// The page the browsers join from
+const PAGE: &str = include_str!("browser.html");
+
+/// What happened to the browser of the bridge, see `BrowserBridge::poll_events`
+/// * `Joined` - a browser connected from this address, it's asked about like a peer calling
+/// * `Left` - it's gone, or the connection to it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrowserEvent {
+    Joined(SocketAddr),
+    Left,
+}
+
+/// What the threads of the bridge share. The connections hold it weakly, as it holds them
+/// * `runtime` - of the bridge, blocked on by the threads outside of it
+/// * `track` - the outgoing video, the same track goes to every browser
+/// * `socket` - the outgoing stream is sent to it, the video of the browser is sent from it
+/// * `incoming` - address of the incoming stream
+/// * `busy` - a call is going on, so no browser may join
+/// * `peer` - the browser in the call
+struct Shared {
+    runtime: Handle,
+    api: API,
+    track: Arc<TrackLocalStaticSample>,
+    socket: UdpSocket,
+    incoming: SocketAddr,
+    session_id: AtomicU32,
+    busy: AtomicBool,
+    stop: AtomicBool,
+    peer: Mutex<Option<Arc<RTCPeerConnection>>>,
+    events: Mutex<Sender<BrowserEvent>>,
+}
+
+/// Lets a browser join the calls, serving until it's shut down
+pub struct BrowserBridge {
+    shared: Arc<Shared>,
+    /// Dropped once the threads are gone, out of the runtime
+    runtime: Runtime,
+    /// None after the threads were shut down
+    t_handles: Option<(JoinHandle<()>, JoinHandle<()>)>,
+    events: Mutex<Receiver<BrowserEvent>>,
+    /// Address the page is served at
+    pub address: SocketAddr,
+    /// Address the outgoing stream is sent to and the incoming one accepted from
+    pub video_address: SocketAddr,
+}
+
+impl BrowserBridge {
+    /// Serve the page at `addr`, relaying the video through a socket bound to `video_addr`
+    /// and the incoming stream at `incoming`
+    /// # Errors
+    /// Returns an error if either address cannot be bound, or the threads started
+    pub fn start(
+        addr: SocketAddr,
+        video_addr: SocketAddr,
+        incoming: SocketAddr,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let socket = UdpSocket::bind(video_addr)?;
+        socket.set_read_timeout(Some(ACCEPT_INTERVAL))?;
+        let video_address = socket.local_addr()?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("webrtc")
+            .enable_all()
+            .build()?;
+        let mut media = MediaEngine::default();
+        media.register_default_codecs()?;
+        let interceptors = register_default_interceptors(Registry::new(), &mut media)?;
+        let api = APIBuilder::new()
+            .with_media_engine(media)
+            .with_interceptor_registry(interceptors)
+            .build();
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_owned(),
+                clock_rate: 90_000,
+                ..Default::default()
+            },
+            "video".to_owned(),
+            "eye-spy".to_owned(),
+        ));
+        let (sender, events) = channel();
+        let shared = Arc::new(Shared {
+            runtime: runtime.handle().clone(),
+            api,
+            track,
+            socket,
+            incoming,
+            session_id: AtomicU32::new(0),
+            busy: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            peer: Mutex::new(None),
+            events: Mutex::new(sender),
+        });
+        let shared_clone = Arc::clone(&shared);
+        let signaling = thread::Builder::new()
+            .name("browser_signaling".into())
+            .spawn(move || {
+                let _span = info_span!("browser", addr = %address).entered();
+                info!("Browsers can join at http://{address}/");
+                while !shared_clone.stop.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, peer)) => {
+                            if let Err(e) = respond(stream, peer, &shared_clone) {
+                                debug!("Cannot answer {peer}: {e}");
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            thread::sleep(ACCEPT_INTERVAL);
+                        }
+                        Err(e) => debug!("Cannot accept a connection: {e}"),
+                    }
+                }
+            })?;
+        let shared_clone = Arc::clone(&shared);
+        let relay = thread::Builder::new()
+            .name("browser_relay".into())
+            .spawn(move || relay_outgoing(&shared_clone))?;
+        Ok(Self {
+            shared,
+            runtime,
+            t_handles: Some((signaling, relay)),
+            events: Mutex::new(events),
+            address,
+            video_address,
+        })
+    }
+    /// What happened since the last call
+    pub fn poll_events(&self) -> Vec<BrowserEvent> {
+        match self.events.lock() {
+            Ok(events) => events.try_iter().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+    /// Turn the browsers away while another call goes on
+    pub fn set_busy(&self, busy: bool) {
+        self.shared.busy.store(busy, Ordering::Relaxed);
+    }
+    /// Session ID the video of the call is stamped with both ways, see `SessionConfig::session_id`
+    pub fn set_session_id(&self, session_id: u32) {
+        self.shared.session_id.store(session_id, Ordering::Relaxed);
+    }
+    /// Whether a browser is in the call
+    pub fn in_call(&self) -> bool {
+        self.shared.peer.lock().is_ok_and(|peer| peer.is_some())
+    }
+    /// Close the connection to the browser in the call, if any. No `BrowserEvent::Left` follows
+    pub fn hang_up(&self) {
+        let peer = self
+            .shared
+            .peer
+            .lock()
+            .ok()
+            .and_then(|mut peer| peer.take());
+        if let Some(peer) = peer {
+            if let Err(e) = self.runtime.block_on(peer.close()) {
+                debug!("Cannot close the connection to the browser: {e}");
+            }
+        }
+    }
+    /// Stop serving and wait for the threads to exit
+    /// # Errors
+    /// Returns an error if a thread panicked or didn't exit in time
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.stop()
+    }
+    fn stop(&mut self) -> anyhow::Result<()> {
+        let Some((signaling, relay)) = self.t_handles.take() else {
+            return Ok(());
+        };
+        self.hang_up();
+        self.shared.stop.store(true, Ordering::SeqCst);
+        let timeout = REQUEST_TIMEOUT + ACCEPT_INTERVAL * 2;
+        join_with_timeout(signaling, timeout).and(join_with_timeout(relay, timeout))
+    }
+}
+
+impl Drop for BrowserBridge {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Puts the NAL units of a frame back together, the browser takes a frame a sample
+#[derive(Debug, Default)]
+struct FrameAssembler {
+    frame: Vec<u8>,
+    timestamp: Option<u64>,
+}
+
+impl FrameAssembler {
+    /// Add a unit of the frame captured at `capture_ts`. Once it starts the next frame,
+    /// returns the previous one and how long it's shown
+    fn push(&mut self, unit: &[u8], capture_ts: u64) -> Option<(Vec<u8>, Duration)> {
+        let done = match self.timestamp {
+            Some(previous) if previous != capture_ts => {
+                let duration = Duration::from_micros(capture_ts.saturating_sub(previous));
+                Some((
+                    std::mem::take(&mut self.frame),
+                    duration.min(MAX_FRAME_DURATION),
+                ))
+            }
+            _ => None,
+        };
+        self.timestamp = Some(capture_ts);
+        self.frame.extend_from_slice(unit);
+        done
+    }
+}
+
+/// Put the outgoing stream back together and send it on to the browser, until the bridge stops
+fn relay_outgoing(shared: &Shared) {
+    let mut builder = NalBuilder::new();
+    let mut session_id = 0;
+    let mut frames = FrameAssembler::default();
+    let mut buf = [0; 2048];
+    while !shared.stop.load(Ordering::SeqCst) {
+        let Ok((size, _)) = shared.socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let current = shared.session_id.load(Ordering::Relaxed);
+        if current != session_id {
+            session_id = current;
+            builder.set_session_id(session_id);
+            frames = FrameAssembler::default();
+        }
+        let Ok(Some(unit)) = builder.push_packet(&buf[..size]) else {
+            continue;
+        };
+        let capture_ts = unit.timestamp.unwrap_or_else(timestamp_now);
+        if let Some((frame, duration)) = frames.push(unit.data, capture_ts) {
+            let sample = Sample {
+                data: Bytes::from(frame),
+                duration,
+                ..Default::default()
+            };
+            // Nothing to do if no browser listens
+            let _ = shared.runtime.block_on(shared.track.write_sample(&sample));
+        }
+    }
+}
+
+/// Read the request and answer it, closing the connection
+fn respond(mut stream: TcpStream, from: SocketAddr, shared: &Arc<Shared>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let (method, path, body) = read_request(&mut stream)?;
+    let (status, content_type, body) = match (method.as_str(), path.as_str()) {
+        ("GET", "/") => ("200 OK", "text/html", PAGE.to_string()),
+        ("POST", "/offer") if shared.busy.load(Ordering::Relaxed) => (
+            "409 Conflict",
+            "text/plain",
+            "A call is going on, try again once it's over\n".to_string(),
+        ),
+        ("POST", "/offer") => {
+            let offer = String::from_utf8_lossy(&body).into_owned();
+            match shared
+                .runtime
+                .block_on(answer(Arc::clone(shared), offer, from))
+            {
+                Ok(answer) => ("200 OK", "application/sdp", answer),
+                Err(e) => {
+                    warn!("Cannot answer the browser at {from}: {e:#}");
+                    ("400 Bad Request", "text/plain", format!("{e:#}\n"))
+                }
+            }
+        }
+        _ => ("404 Not Found", "text/plain", "Nothing here\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Method, path and body of the request
+fn read_request(stream: &mut impl Read) -> io::Result<(String, String, Vec<u8>)> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 4096];
+    let head_len = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if request.len() >= MAX_REQUEST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too long",
+            ));
+        }
+        match stream.read(&mut chunk)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            size => request.extend_from_slice(&chunk[..size]),
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+    let body_len = content_length(&head).min(MAX_REQUEST_LEN);
+    while request.len() < head_len + body_len {
+        match stream.read(&mut chunk)? {
+            0 => break,
+            size => request.extend_from_slice(&chunk[..size]),
+        }
+    }
+    let mut parts = head.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    request.truncate(head_len + body_len);
+    Ok((method, path, request.split_off(head_len)))
+}
+
+/// Length of the body the head announces, 0 if it doesn't
+fn content_length(head: &str) -> usize {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Connect to the browser offering `offer`, the answer returned once the candidates are gathered
+async fn answer(shared: Arc<Shared>, offer: String, from: SocketAddr) -> anyhow::Result<String> {
+    if shared.peer.lock().is_ok_and(|peer| peer.is_some()) {
+        anyhow::bail!("Another browser is in the call");
+    }
+    let peer = Arc::new(
+        shared
+            .api
+            .new_peer_connection(RTCConfiguration::default())
+            .await?,
+    );
+    let sender = peer
+        .add_track(Arc::clone(&shared.track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await?;
+    // The interceptors only work while the RTCP is read
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1500];
+        while sender.read(&mut buf).await.is_ok() {}
+    });
+    let weak = Arc::downgrade(&peer);
+    let weak_shared = Arc::downgrade(&shared);
+    peer.on_track(Box::new(move |track, _, _| {
+        let (shared, weak) = (Weak::clone(&weak_shared), Weak::clone(&weak));
+        Box::pin(async move {
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            if track.kind() == RTPCodecType::Video {
+                tokio::spawn(request_keyframes(weak, track.ssrc()));
+                relay_incoming(&shared, &track).await;
+            }
+        })
+    }));
+    let weak = Arc::downgrade(&peer);
+    let weak_shared = Arc::downgrade(&shared);
+    peer.on_peer_connection_state_change(Box::new(move |state| {
+        let (shared, weak) = (Weak::clone(&weak_shared), Weak::clone(&weak));
+        Box::pin(async move {
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            let event = match state {
+                RTCPeerConnectionState::Connected => BrowserEvent::Joined(from),
+                RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed => {
+                    // Only the browser in the call leaves it, one hung up on is gone already
+                    let mut current = shared.peer.lock().unwrap();
+                    let ours = weak
+                        .upgrade()
+                        .zip(current.as_ref())
+                        .is_some_and(|(peer, current)| Arc::ptr_eq(&peer, current));
+                    if !ours {
+                        return;
+                    }
+                    *current = None;
+                    BrowserEvent::Left
+                }
+                _ => return,
+            };
+            let Ok(events) = shared.events.lock() else {
+                return;
+            };
+            let _ = events.send(event);
+        })
+    }));
+    peer.set_remote_description(RTCSessionDescription::offer(offer)?)
+        .await?;
+    let answer = peer.create_answer(None).await?;
+    let mut gathered = peer.gathering_complete_promise().await;
+    peer.set_local_description(answer).await?;
+    let _ = gathered.recv().await;
+    let local = peer
+        .local_description()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("No local description"))?;
+    *shared.peer.lock().unwrap() = Some(peer);
+    Ok(local.sdp)
+}
+
+/// Ask the browser for a keyframe every so often, until it's gone
+async fn request_keyframes(peer: Weak<RTCPeerConnection>, media_ssrc: u32) {
+    let mut interval = tokio::time::interval(KEYFRAME_REQUEST_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(peer) = peer.upgrade() else {
+            return;
+        };
+        let pli = PictureLossIndication {
+            sender_ssrc: 0,
+            media_ssrc,
+        };
+        if peer.write_rtcp(&[Box::new(pli)]).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Packetize the video of the browser for the incoming stream, until the track ends
+async fn relay_incoming(shared: &Shared, track: &TrackRemote) {
+    let mut depacketizer = H264Packet::default();
+    let mut packets = PacketBuffer::default();
+    while let Ok((packet, _)) = track.read_rtp().await {
+        // Empty until the last fragment of a unit
+        let Ok(units) = depacketizer.depacketize(&packet.payload) else {
+            continue;
+        };
+        let session_id = shared.session_id.load(Ordering::Relaxed);
+        let capture_ts = timestamp_now();
+        for unit in openh264::nal_units(&units) {
+            packets.clear();
+            packets.push_unit(unit, capture_ts, session_id);
+            for packet in packets.packets() {
+                let _ = shared.socket.send_to(packet, shared.incoming);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{content_length, read_request, FrameAssembler, MAX_FRAME_DURATION};
+
+    #[test]
+    fn test_frame_assembler() {
+        let mut frames = FrameAssembler::default();
+        assert!(frames.push(&[0, 0, 0, 1, 0x67], 1_000).is_none());
+        assert!(frames.push(&[0, 0, 0, 1, 0x65], 1_000).is_none());
+        let (frame, duration) = frames.push(&[0, 0, 0, 1, 0x41], 34_333).unwrap();
+        assert_eq!(frame, [0, 0, 0, 1, 0x67, 0, 0, 0, 1, 0x65]);
+        assert_eq!(duration, Duration::from_micros(33_333));
+        // The camera paused
+        let (frame, duration) = frames.push(&[0, 0, 0, 1, 0x41], 10_000_000).unwrap();
+        assert_eq!(frame, [0, 0, 0, 1, 0x41]);
+        assert_eq!(duration, MAX_FRAME_DURATION);
+    }
+    #[test]
+    fn test_read_request() {
+        assert_eq!(
+            content_length("POST / HTTP/1.1\r\ncontent-LENGTH: 12\r\n"),
+            12
+        );
+        assert_eq!(content_length("GET / HTTP/1.1\r\nHost: x\r\n"), 0);
+        let request = b"POST /offer HTTP/1.1\r\nContent-Length: 5\r\n\r\nv=0\r\nleftover";
+        let (method, path, body) = read_request(&mut &request[..]).unwrap();
+        assert_eq!((method.as_str(), path.as_str()), ("POST", "/offer"));
+        assert_eq!(body, b"v=0\r\n");
+        assert!(read_request(&mut &b"GET / HTTP/1.1\r\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,2012 @@
+//! Calls driven by any frontend. `CallManager` owns the SCP client and both video streams,
+//! starting and stopping the streams as the sessions come and go.
+//! Nothing blocks, the frontend calls `CallManager::poll` regularly and reacts to the events.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use scp_client::client::{
+    new_session_id, ConnectionEvent, MediaKeys, PacketFormat, ScpClient, ScpClientBuilder,
+    ScpConnectionError, SessionConfig, VideoMode, DEFAULT_AUDIO_BITRATE, DEFAULT_RING_TIMEOUT,
+};
+use scp_client::file_transfer::TransferEvent;
+use scp_client::identity::{normalize_fingerprint, Identity, KnownPeers, PeerIdentity, PeerTrust};
+use scp_client::peer_list::{PeerEntry, PeerList};
+use scp_client::probe::PeerCapabilities;
+use scp_client::resume::ResumeTicket;
+use scp_client::scp::{ClipboardText, ControlMessage};
+use tracing::{info, warn};
+
+use crate::audio::AudioChannels;
+use crate::bandwidth::{BandwidthProbe, BurstTimer};
+#[cfg(feature = "webrtc")]
+use crate::browser::{BrowserBridge, BrowserEvent};
+use crate::camera::{camera_modes, pick_mode, DEFAULT_BITRATE_BUDGET};
+use crate::codec::{
+    resolve_decoder, resolve_encoder, video_encodings, CodecBackend, VideoEncoding,
+};
+use crate::h264_stream::incoming::{
+    init_incoming_h264_stream, init_incoming_source_stream, FrameSink, H264IncomingStreamControls,
+    IncomingStreamControls,
+};
+use crate::h264_stream::outgoing::{
+    init_h264_video_stream, H264StreamControls, OutgoingStreamEvent, StreamControls, StreamLayer,
+    VideoBackend,
+};
+use crate::h264_stream::{StreamSource, DEFAULT_KEYFRAME_INTERVAL, HEIGHT, WIDTH};
+use crate::invite::Invite;
+use crate::mdns::{self, Advertisement, Capabilities, Registration};
+use crate::metrics::{MetricsServer, MetricsSources};
+use crate::mtu::MtuProbe;
+use crate::nal::{DEFAULT_MAX_NAL_UNIT_SIZE, DEFAULT_PACKET_DATA_SIZE, MAX_PACKET_DATA_SIZE};
+use crate::profile::PipelineProfile;
+use crate::qos::SocketTuning;
+use crate::quality::VideoQuality;
+use crate::recording::{CallRecorder, RecordingConfig};
+use crate::rtp::{RtpConfig, RtpOutput};
+use crate::srtp::SrtpContext;
+use crate::static_peers::StaticPeer;
+use crate::stats::{CallSummary, LossMonitor, StatsSnapshot};
+use crate::still::{self, StillFallback, StillSender, ThroughputMonitor};
+use crate::usage::{DataUsage, MonthlyCap, Usage};
+
+/// There's no audio stream yet, the port and the audio encodings are only negotiated with the peers
+const AUDIO_PORT: u16 = 7001;
+/// How often the resume ticket of the ongoing call is saved again,
+/// well within `scp_client::resume::RESUME_WINDOW`
+const RESUME_REFRESH: Duration = Duration::from_secs(10);
+/// How often the peer is asked for a keyframe while its video stays frozen
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a `CallManager` is set up with
+/// * `video` - where the outgoing video comes from
+/// * `dump_dir` - directory to dump the incoming streams to, see `crate::bitstream::BitstreamDump`
+/// * `identity` - identity shared with the peers
+/// * `known_peers` - keys of the peers seen so far
+/// * `peer_list` - peers allowed or denied to call
+/// * `bind_ip` - IP the streams are bound to. The ports are picked by the OS.
+///   An unspecified IPv6 one takes the peers of any kind, link-local ones included
+/// * `advertise` - advertise this client over mDNS, so the peers can find it
+/// * `instance` - suffix of the advertised instance name, to tell instances on one machine apart.
+///   Also the name in the invites
+/// * `advertisements` - profiles this client is advertised with, i.e. a work and a home one.
+///   None advertises the default one, see `mdns::Advertisement`. The peers are looked for
+///   under their services, see `CallManager::discovery_services`
+/// * `beacon_port` - also advertise the profiles and look for the peers with broadcast beacons
+///   on this port, for the networks that filter multicast, see `crate::beacon`
+/// * `static_peers` - peers listed rather than found, shown along with the ones found,
+///   see `crate::static_peers`
+/// * `metrics_addr` - serve the stream stats in the Prometheus format there, see `crate::metrics`
+/// * `auto_answer` - incoming calls answered without asking, i.e. for a camera node
+/// * `bitrate_budget` - kbit/s the camera mode is picked for, see `crate::camera::pick_mode`
+/// * `packet_size` - data of the stream packets. None probes the path to each peer for it,
+///   see `crate::mtu`
+/// * `keyframe_interval` - frames between the keyframes of the outgoing streams,
+///   0 leaves them to the encoder
+/// * `max_unit_size` - bytes of the largest NAL unit taken from the peers, larger ones are dropped
+/// * `socket_tuning` - buffers and DSCP marks of the stream sockets, see `crate::qos`
+/// * `encoder`, `decoder` - backends the video is encoded and decoded with, OpenH264 if they
+///   cannot. Told to the peers, see `crate::codec`
+/// * `video_encoding` - the encoding the video is preferably taken in, H.264 with the peers
+///   that don't take it, or if it isn't available here
+/// * `audio_channels` - channels the audio is captured and played in, negotiated with the peers
+/// * `ring_timeout` - how long a call rings before it ends as not answered, on either side
+/// * `resume_path` - where the ticket of the ongoing call is kept, to resume the call after
+///   a restart. None not to resume calls, see `scp_client::resume`
+/// * `screen` - screen shared with the peers along with the video, if any
+/// * `data_usage` - data the calls used so far, counted on from there, see `crate::usage`
+/// * `monthly_cap` - data the calls may use in a month, None for no cap
+/// * `rtp` - republish a stream as RTP for the other players, see `crate::rtp`
+/// * `browser_addr` - let a browser join the calls from the page served there, see `crate::browser`
+/// * `still_fallback` - when still images stand in for the video that doesn't get through,
+///   see `crate::still`
+/// * `downloads_dir` - where the files the peers send are received into, None declines them,
+///   see `scp_client::file_transfer`
+pub struct CallConfig {
+    pub video: VideoBackend,
+    pub dump_dir: Option<PathBuf>,
+    pub identity: Identity,
+    pub known_peers: KnownPeers,
+    pub peer_list: PeerList,
+    pub bind_ip: IpAddr,
+    pub advertise: bool,
+    pub instance: Option<String>,
+    pub advertisements: Vec<Advertisement>,
+    pub beacon_port: Option<u16>,
+    pub static_peers: Vec<StaticPeer>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub auto_answer: AutoAnswer,
+    pub bitrate_budget: u32,
+    pub packet_size: Option<usize>,
+    pub keyframe_interval: u32,
+    pub max_unit_size: usize,
+    pub socket_tuning: SocketTuning,
+    pub encoder: CodecBackend,
+    pub decoder: CodecBackend,
+    pub video_encoding: VideoEncoding,
+    pub audio_channels: AudioChannels,
+    pub ring_timeout: Duration,
+    pub resume_path: Option<PathBuf>,
+    pub screen: Option<VideoBackend>,
+    pub data_usage: DataUsage,
+    pub monthly_cap: Option<MonthlyCap>,
+    pub rtp: Option<RtpConfig>,
+    #[cfg(feature = "webrtc")]
+    pub browser_addr: Option<SocketAddr>,
+    pub still_fallback: StillFallback,
+    pub downloads_dir: Option<PathBuf>,
+}
+
+impl Default for CallConfig {
+    fn default() -> Self {
+        Self {
+            video: VideoBackend::Camera,
+            dump_dir: None,
+            identity: Identity::generate(),
+            known_peers: KnownPeers::in_memory(),
+            peer_list: PeerList::in_memory(),
+            bind_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            advertise: false,
+            instance: None,
+            advertisements: Vec::new(),
+            beacon_port: None,
+            static_peers: Vec::new(),
+            metrics_addr: None,
+            auto_answer: AutoAnswer::Never,
+            bitrate_budget: DEFAULT_BITRATE_BUDGET,
+            packet_size: None,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            max_unit_size: DEFAULT_MAX_NAL_UNIT_SIZE,
+            socket_tuning: SocketTuning::default(),
+            encoder: CodecBackend::OpenH264,
+            decoder: CodecBackend::OpenH264,
+            video_encoding: VideoEncoding::H264,
+            audio_channels: AudioChannels::default(),
+            ring_timeout: DEFAULT_RING_TIMEOUT,
+            resume_path: None,
+            screen: None,
+            data_usage: DataUsage::in_memory(),
+            monthly_cap: None,
+            rtp: None,
+            #[cfg(feature = "webrtc")]
+            browser_addr: None,
+            still_fallback: StillFallback::default(),
+            downloads_dir: None,
+        }
+    }
+}
+
+/// Which incoming calls `CallManager` answers by itself
+/// * `Never` - the frontend answers every call
+/// * `Anyone` - every call is answered
+/// * `Allowlist` - calls from these IPs, or from the peers proving one of these identities
+///   by their fingerprint, are answered. The frontend answers the rest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AutoAnswer {
+    #[default]
+    Never,
+    Anyone,
+    Allowlist {
+        ips: Vec<IpAddr>,
+        fingerprints: Vec<String>,
+    },
+}
+
+impl AutoAnswer {
+    /// Whether the call of `ip` is answered by itself
+    /// * `identity` - identity the caller proved to hold
+    pub fn answers(&self, ip: IpAddr, identity: Option<&PeerIdentity>) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Anyone => true,
+            Self::Allowlist { ips, fingerprints } => {
+                let fingerprint =
+                    identity.map(|identity| normalize_fingerprint(&identity.fingerprint()));
+                ips.contains(&ip)
+                    || fingerprint.is_some_and(|fingerprint| {
+                        fingerprints
+                            .iter()
+                            .any(|f| normalize_fingerprint(f) == fingerprint)
+                    })
+            }
+        }
+    }
+}
+
+/// What happened since the last `CallManager::poll`
+/// * `Incoming` - a peer is calling, `accept()` or `refuse()` it
+/// * `Ringing` - the peer called was reached and asks its user to answer
+/// * `Established` - the call started and the streams with it
+/// * `Failed` - the call couldn't be placed
+/// * `Ended` - the peer ended the call, the streams are stopped
+/// * `Control` - a request of the peer. Requests about the stream are already taken care of
+/// * `OutgoingStream` - health of the outgoing stream
+/// * `DataCapNear` - the data used this month, now near `CallConfig::monthly_cap`
+/// * `File` - what happened to a file sent or received, see `send_file()` and `accept_file()`
+/// * `Clipboard` - text the peer sent for the clipboard, see `send_clipboard()`.
+///   It's up to the user whether it's copied
+/// * `BrowserJoined` - a browser joined from this address and the streams started, see `crate::browser`.
+///   It comes as `Incoming` first, unless it's answered by itself. `Ended` follows once it leaves
+#[derive(Debug, Clone)]
+pub enum CallEvent {
+    Incoming(IpAddr),
+    Ringing,
+    Established(SessionConfig),
+    Failed(ScpConnectionError),
+    Ended,
+    Control(ControlMessage),
+    OutgoingStream(OutgoingStreamEvent),
+    DataCapNear(Usage),
+    File(TransferEvent),
+    Clipboard(String),
+    #[cfg(feature = "webrtc")]
+    BrowserJoined(SocketAddr),
+}
+
+/// Places, answers and ends calls
+pub struct CallManager {
+    scp: ScpClient,
+    outgoing: H264StreamControls,
+    incoming: H264IncomingStreamControls,
+    /// The screen shared by the peer, decoded next to its camera
+    screen: H264IncomingStreamControls,
+    /// The screen shared with the peer, if any
+    screen_out: Option<H264StreamControls>,
+    /// Session of the ongoing call, None for loopback calls too
+    session: Option<SessionConfig>,
+    /// 1 while `session` is set, for the metrics
+    active_sessions: Arc<AtomicU64>,
+    metrics: Option<MetricsServer>,
+    /// Name put into the invites
+    name: Option<String>,
+    /// Services the peers are looked for under
+    discovery_services: Vec<String>,
+    /// Profiles this client is advertised with, told whether it's in a call
+    advertised: Vec<Registration>,
+    /// Port of the broadcast beacons, if they're sent and listened for
+    beacon_port: Option<u16>,
+    static_peers: Vec<StaticPeer>,
+    auto_answer: AutoAnswer,
+    /// The peer of `CallEvent::Incoming` until it's answered, and the identity it proved
+    ringing: Option<(IpAddr, Option<PeerIdentity>)>,
+    /// The peer of `CallEvent::Ringing` until it answers, and the identity it proved
+    called: Option<(IpAddr, Option<PeerIdentity>)>,
+    /// Fixed packet data size, None to probe it in every call
+    packet_size: Option<usize>,
+    /// Probing the path to the peer of the ongoing call
+    probe: Option<MtuProbe>,
+    /// The burst sent to the peer of the ongoing call, until it's reported
+    bandwidth_probe: Option<BandwidthProbe>,
+    /// Times the bursts of the peer of the ongoing call
+    bursts: BurstTimer,
+    /// kbit/s the video may take on the path to the peer of the ongoing call, as measured
+    /// as it started. None if it wasn't, or the path is faster than a burst tells
+    path_budget: Option<u32>,
+    /// How the calls are recorded, None not to record them
+    recording: Option<RecordingConfig>,
+    /// Recording the ongoing call
+    recorder: Option<CallRecorder>,
+    /// File the last call was recorded into
+    last_recording: Option<PathBuf>,
+    /// The counters of the streams when the ongoing call started
+    call_start: Option<StatsSnapshot>,
+    /// Low or full resolution asked for in the ongoing call, either way
+    resolution_changes: AtomicU64,
+    /// Statistics of the last call that ended
+    last_summary: Option<CallSummary>,
+    /// Layer the next call starts sending, picked for the peer in `call_probed`
+    start_layer: StreamLayer,
+    /// Modes the camera captures in, empty if it has none or isn't known
+    modes: Vec<VideoMode>,
+    /// Video encodings told to the peers, the preferred one first, the loopback calls go in it
+    video_encodings: Vec<VideoEncoding>,
+    /// Where the resume ticket of the ongoing call is kept, if anywhere
+    resume_path: Option<PathBuf>,
+    /// When the resume ticket of the ongoing call was last saved
+    resume_saved: Instant,
+    /// Quality picked for the outgoing video, anything but `Auto` keeps the layer as it is
+    quality: VideoQuality,
+    /// kbit/s the camera mode is picked for under `VideoQuality::Auto`
+    bitrate_budget: u32,
+    /// The peer of the ongoing call is on a weak link, so `VideoQuality::Auto` sends it
+    /// the cheapest video, see `ControlMessage::LowBandwidth`
+    peer_low_bandwidth: bool,
+    /// Tells when the link to the peer of the ongoing call gets weak
+    loss: LossMonitor,
+    /// The peer of the ongoing call was asked for its cheapest video
+    low_bandwidth: bool,
+    /// ... by the user, so it isn't taken back once the packet loss drops
+    low_bandwidth_pinned: bool,
+    /// When the peer was last asked for a keyframe, None while its video isn't frozen
+    keyframe_requested: Option<Instant>,
+    /// kbit/s of the video of the peer below which it's asked for still images, 0 never to ask
+    still_threshold: u32,
+    /// Tells when the video of the peer of the ongoing call doesn't get through anymore
+    throughput: ThroughputMonitor,
+    /// Sends the still images while the peer of the ongoing call asks for them
+    stills: StillSender,
+    usage: DataUsage,
+    monthly_cap: Option<MonthlyCap>,
+    /// The byte counters of the streams as last counted into `usage`
+    counted: Usage,
+    /// The data used this month is near `monthly_cap`
+    near_cap: bool,
+    #[cfg(feature = "webrtc")]
+    browser: Option<BrowserBridge>,
+    /// The browser that joined, until it's answered. It's in `ringing` too
+    #[cfg(feature = "webrtc")]
+    browser_ringing: Option<SocketAddr>,
+    /// The browser answered, its streams start on the next poll
+    #[cfg(feature = "webrtc")]
+    browser_accepted: Option<SocketAddr>,
+}
+
+/// The ticket of the call this client was in until just now, i.e. before it crashed.
+/// Taken once, a resumed call gets a new one
+fn take_resume_ticket(path: &Path) -> Option<ResumeTicket> {
+    let ticket = ResumeTicket::load(path)
+        .inspect_err(|e| warn!("Cannot load the resume ticket: {e}"))
+        .ok()
+        .flatten();
+    if let Err(e) = ResumeTicket::remove(path) {
+        warn!("Cannot remove the resume ticket: {e}");
+    }
+    ticket.filter(ResumeTicket::is_fresh)
+}
+
+/// Layer to start sending the peer, from what it told about itself.
+/// A peer capturing at half the rate of this client or slower is likely on a slow machine,
+/// so it gets the base layer. It asks for the full one if it keeps up, see `ControlMessage::LowResolution`
+fn layer_for_peer(mode: Option<VideoMode>, capabilities: &PeerCapabilities) -> StreamLayer {
+    match (mode, capabilities.best_mode()) {
+        (Some(ours), Some(theirs)) if theirs.fps * 2 <= ours.fps => StreamLayer::Base,
+        _ => StreamLayer::Full,
+    }
+}
+
+impl CallManager {
+    /// Starts the SCP client and the stream threads. The camera is only probed for its modes,
+    /// it isn't opened until a call starts. The picked mode is shared with the peers
+    /// # Errors
+    /// Returns an error if the stream sockets or the metrics endpoint cannot be bound,
+    /// or their threads spawned
+    /// # Panics
+    /// Panics if the SCP client cannot listen, or mDNS cannot advertise it
+    pub fn new(config: CallConfig) -> anyhow::Result<Self> {
+        let modes = match config.video {
+            VideoBackend::Camera => camera_modes().unwrap_or_else(|e| {
+                warn!("Cannot probe the camera modes: {e}");
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+        let mode = pick_mode(&modes, config.bitrate_budget);
+        if let Some(mode) = mode {
+            info!("Capturing {mode}");
+        }
+        // Only the camera frames are encoded here, the other sources come encoded already.
+        // They're encoded in the size of the mode, the camera's default one without it
+        let (width, height) = mode.map_or((WIDTH, HEIGHT), |mode| {
+            (mode.width.into(), mode.height.into())
+        });
+        let encoder = match config.video {
+            VideoBackend::Camera => resolve_encoder(config.encoder, width, height),
+            _ => CodecBackend::Passthrough,
+        };
+        let decoder = resolve_decoder(config.decoder);
+        info!("Encoding with {encoder}, decoding with {decoder}");
+        let encodings = video_encodings(config.video_encoding);
+        if encodings[0] != config.video_encoding {
+            warn!(
+                "{} isn't available, the video is taken in {}",
+                config.video_encoding, encodings[0]
+            );
+        }
+        let resume = config.resume_path.as_deref().and_then(take_resume_ticket);
+        // A resumed call goes on with the ports the peer sends to
+        let (video_port, screen_port) = resume.as_ref().map_or((0, 0), |ticket| {
+            let preferences = &ticket.preferences;
+            (
+                preferences.port_in_video,
+                preferences.port_in_screen.unwrap_or(0),
+            )
+        });
+        let outgoing =
+            init_h264_video_stream(SocketAddr::new(config.bind_ip, 0), config.video, mode)?;
+        let incoming = init_incoming_h264_stream(
+            SocketAddr::new(config.bind_ip, video_port),
+            config.dump_dir.clone(),
+        )
+        .or_else(|e| {
+            warn!("Cannot take the video port of the resumed call back: {e}");
+            init_incoming_h264_stream(SocketAddr::new(config.bind_ip, 0), config.dump_dir)
+        })?;
+        let screen = init_incoming_source_stream(
+            SocketAddr::new(config.bind_ip, screen_port),
+            None,
+            StreamSource::Screen,
+        )
+        .or_else(|e| {
+            warn!("Cannot take the screen port of the resumed call back: {e}");
+            init_incoming_source_stream(
+                SocketAddr::new(config.bind_ip, 0),
+                None,
+                StreamSource::Screen,
+            )
+        })?;
+        let screen_out = match config.screen {
+            Some(backend) => Some(init_h264_video_stream(
+                SocketAddr::new(config.bind_ip, 0),
+                backend,
+                None,
+            )?),
+            None => None,
+        };
+        if let Some(size) = config.packet_size {
+            outgoing.set_packet_size(size);
+            if let Some(ref screen_out) = screen_out {
+                screen_out.set_packet_size(size);
+            }
+        }
+        let keyframe_interval = (config.keyframe_interval > 0).then_some(config.keyframe_interval);
+        outgoing.set_keyframe_interval(keyframe_interval);
+        if let Some(ref screen_out) = screen_out {
+            screen_out.set_keyframe_interval(keyframe_interval);
+        }
+        // Paced for the budget until the path to a peer is measured, see `pace_streams`
+        outgoing.set_pacing_rate(Some(config.bitrate_budget));
+        if let Some(ref screen_out) = screen_out {
+            screen_out.set_pacing_rate(Some(config.bitrate_budget));
+        }
+        incoming.set_max_unit_size(config.max_unit_size);
+        screen.set_max_unit_size(config.max_unit_size);
+        outgoing.set_encoder(encoder);
+        incoming.set_decoder(decoder);
+        screen.set_decoder(decoder);
+        let tuned = [
+            outgoing.tune_socket(&config.socket_tuning),
+            incoming.tune_socket(&config.socket_tuning),
+            screen.tune_socket(&config.socket_tuning),
+        ];
+        let screen_tuned = screen_out
+            .as_ref()
+            .map(|screen_out| screen_out.tune_socket(&config.socket_tuning));
+        for result in tuned.into_iter().chain(screen_tuned) {
+            if let Err(e) = result {
+                warn!("Cannot tune a stream socket: {e}");
+            }
+        }
+        let scp = ScpClientBuilder::builder()
+            .identity(config.identity)
+            .known_peers(config.known_peers)
+            .peer_list(config.peer_list)
+            .audio_port(AUDIO_PORT)
+            .audio_quality(DEFAULT_AUDIO_BITRATE, config.audio_channels.count())
+            .video_port(incoming.address.port())
+            .screen_port(Some(screen.address.port()))
+            .video_mode(mode)
+            .video_modes(modes.clone())
+            .codec_backends(encoder, decoder)
+            .video_encodings(encodings.clone())
+            .ring_timeout(config.ring_timeout)
+            .downloads_dir(config.downloads_dir)
+            .port_scp(0)
+            .try_build()?;
+        if let Some(ticket) = resume {
+            info!("Resuming the call with {}", ticket.peer);
+            incoming.expect(ticket.peer.ip());
+            screen.expect(ticket.peer.ip());
+            scp.resume(ticket);
+        }
+        let mut advertisements = config.advertisements;
+        if advertisements.is_empty() {
+            advertisements.push(Advertisement::default());
+        }
+        let mut advertised = Vec::new();
+        if config.advertise {
+            let caps = Capabilities {
+                screen: screen_out.is_some(),
+                srtp: true,
+            };
+            for advertisement in &advertisements {
+                let advertisement = Advertisement {
+                    instance: advertisement
+                        .instance
+                        .clone()
+                        .or_else(|| config.instance.clone()),
+                    ..advertisement.clone()
+                };
+                match mdns::start_service(&advertisement, scp.local_addr().port(), caps) {
+                    Ok(mut registration) => {
+                        if let Some(port) = config.beacon_port {
+                            if let Err(e) = registration.start_beacon(port) {
+                                warn!("{e:#}");
+                            }
+                        }
+                        advertised.push(registration);
+                    }
+                    Err(e) => warn!("{e:#}"),
+                }
+            }
+        }
+        if let Some(ref rtp) = config.rtp {
+            let output = RtpOutput::new(rtp.addr)?;
+            if let Some(ref path) = rtp.sdp_path {
+                std::fs::write(path, output.sdp())
+                    .with_context(|| format!("Cannot save the SDP to {}", path.display()))?;
+            }
+            let side = if rtp.local { "sent" } else { "received" };
+            info!("Republishing the {side} video to {}", rtp.addr);
+            if rtp.local {
+                outgoing.set_unit_sink(Some(Box::new(output)));
+            } else {
+                incoming.set_unit_sink(Some(Box::new(output)));
+            }
+        }
+        #[cfg(feature = "webrtc")]
+        let browser = match config.browser_addr {
+            Some(addr) => Some(BrowserBridge::start(
+                addr,
+                SocketAddr::new(config.bind_ip, 0),
+                incoming.address,
+            )?),
+            None => None,
+        };
+        let active_sessions = Arc::new(AtomicU64::new(0));
+        let metrics = match config.metrics_addr {
+            Some(addr) => Some(MetricsServer::start(
+                addr,
+                MetricsSources {
+                    outgoing: outgoing.stats(),
+                    incoming: incoming.stats(),
+                    active_sessions: Arc::clone(&active_sessions),
+                },
+            )?),
+            None => None,
+        };
+        Ok(Self {
+            scp,
+            outgoing,
+            incoming,
+            screen,
+            screen_out,
+            session: None,
+            active_sessions,
+            metrics,
+            name: config.instance,
+            discovery_services: mdns::services(&advertisements),
+            advertised,
+            beacon_port: config.beacon_port,
+            static_peers: config.static_peers,
+            auto_answer: config.auto_answer,
+            ringing: None,
+            called: None,
+            packet_size: config.packet_size,
+            probe: None,
+            bandwidth_probe: None,
+            bursts: BurstTimer::default(),
+            path_budget: None,
+            recording: None,
+            recorder: None,
+            last_recording: None,
+            call_start: None,
+            resolution_changes: AtomicU64::new(0),
+            last_summary: None,
+            start_layer: StreamLayer::Full,
+            modes,
+            video_encodings: encodings,
+            resume_path: config.resume_path,
+            resume_saved: Instant::now(),
+            quality: VideoQuality::Auto,
+            bitrate_budget: config.bitrate_budget,
+            peer_low_bandwidth: false,
+            loss: LossMonitor::default(),
+            low_bandwidth: false,
+            low_bandwidth_pinned: false,
+            keyframe_requested: None,
+            still_threshold: config.still_fallback.threshold,
+            throughput: ThroughputMonitor::default(),
+            stills: StillSender::new(config.still_fallback.interval),
+            usage: config.data_usage,
+            monthly_cap: config.monthly_cap,
+            counted: Usage::default(),
+            near_cap: false,
+            #[cfg(feature = "webrtc")]
+            browser,
+            #[cfg(feature = "webrtc")]
+            browser_ringing: None,
+            #[cfg(feature = "webrtc")]
+            browser_accepted: None,
+        })
+    }
+    /// Call the peer listening at `addr`, i.e. the service port it advertises over mDNS.
+    /// The outcome comes as `CallEvent::Established` or `CallEvent::Failed`
+    pub fn call(&self, addr: SocketAddr) {
+        info!("Calling {addr}");
+        // The peer starts streaming as soon as it accepts, before the session is established here
+        self.incoming.expect(addr.ip());
+        self.screen.expect(addr.ip());
+        self.scp.attempt_connection(addr);
+    }
+    /// Call the peer with the settings that suit what it answered to a probe, see `scp_client::probe`
+    pub fn call_probed(&mut self, addr: SocketAddr, capabilities: &PeerCapabilities) {
+        if capabilities.busy {
+            warn!("{addr} is busy, the call is likely to be refused");
+        }
+        if !capabilities.compatible() {
+            warn!(
+                "{addr} speaks version {} of the protocol, the call might fail",
+                capabilities.protocol_version
+            );
+        }
+        if self.quality == VideoQuality::Auto {
+            self.start_layer = layer_for_peer(self.outgoing.video_mode(), capabilities);
+        }
+        if self.start_layer != StreamLayer::Full {
+            info!(
+                "Sending {addr} the {:?} layer until it asks for more",
+                self.start_layer
+            );
+        }
+        self.call(addr);
+    }
+    /// Call the peer that made the invite
+    pub fn call_invite(&self, invite: &Invite) {
+        if let Some(ref name) = invite.name {
+            info!("Accepting the invite of {name}");
+        }
+        self.call(invite.addr);
+    }
+    /// Give up on the call placed with `call` before the peer answers it.
+    /// The peer stops ringing and `CallEvent::Failed(Cancelled)` follows
+    pub fn cancel(&self) {
+        if self.session.is_some() {
+            warn!("The call is answered already, hang up instead");
+            return;
+        }
+        info!("Cancelling the call");
+        self.scp.cancel();
+    }
+    /// Hand the ongoing call over to another device of the user listening at `addr`, i.e. one found
+    /// on the LAN. The call ends here with `CallEvent::Ended` once the peer streams to the device
+    pub fn transfer(&self, addr: SocketAddr) {
+        if self.session.is_none() {
+            warn!("No call to hand over to {addr}");
+            return;
+        }
+        info!("Handing the call over to {addr}");
+        self.scp.transfer(addr);
+    }
+    /// Services the peers are looked for under, the ones this client is advertised as
+    pub fn discovery_services(&self) -> &[String] {
+        &self.discovery_services
+    }
+    /// Port the beacons of the peers are listened for on, see `CallConfig::beacon_port`
+    pub fn beacon_port(&self) -> Option<u16> {
+        self.beacon_port
+    }
+    /// Peers listed rather than found, see `CallConfig::static_peers`
+    pub fn static_peers(&self) -> &[StaticPeer] {
+        &self.static_peers
+    }
+    /// Invite to call this client, to share with the peers mDNS doesn't reach
+    pub fn invite(&self) -> Invite {
+        Invite {
+            addr: self.scp.local_addr(),
+            name: self.name.clone(),
+        }
+    }
+    /// Name put into the invites, `CallConfig::instance` at first
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    /// Put `name` into the invites from now on, or none with None.
+    /// The advertised instance name stays as it was
+    pub fn set_name(&mut self, name: Option<String>) {
+        self.name = name;
+    }
+    /// Answer the call of `CallEvent::Incoming`
+    pub fn accept(&mut self) {
+        self.ringing = None;
+        #[cfg(feature = "webrtc")]
+        if let Some(addr) = self.browser_ringing.take() {
+            self.browser_accepted = Some(addr);
+            return;
+        }
+        self.scp.accept();
+    }
+    /// Turn down the call of `CallEvent::Incoming`
+    pub fn refuse(&mut self) {
+        self.ringing = None;
+        #[cfg(feature = "webrtc")]
+        if self.browser_ringing.take().is_some() {
+            if let Some(ref browser) = self.browser {
+                browser.hang_up();
+            }
+            return;
+        }
+        self.scp.refuse_incoming_connection();
+        if self.session.is_none() {
+            self.incoming.refuse();
+            self.screen.refuse();
+        }
+    }
+    /// Turn down the call of `CallEvent::Incoming` and deny the caller from now on,
+    /// by its identity if it proved one
+    pub fn block(&mut self) {
+        if let Some((ip, identity)) = self.ringing.take() {
+            let entry = PeerEntry::of_peer(ip, identity.as_ref());
+            info!("Denying {entry} from now on");
+            self.scp.peer_list().deny(entry);
+        }
+        self.refuse();
+    }
+    /// The peer calling, until the call is answered or given up
+    pub fn ringing(&self) -> Option<&(IpAddr, Option<PeerIdentity>)> {
+        self.ringing.as_ref()
+    }
+    /// The peer called, from `CallEvent::Ringing` until it answers or the call is given up
+    pub fn called(&self) -> Option<&(IpAddr, Option<PeerIdentity>)> {
+        self.called.as_ref()
+    }
+    /// Trust the key of the peer calling, called or in the call, once the user compared
+    /// its fingerprint out of band. A changed key reads as known from now on
+    pub fn trust_peer(&mut self) {
+        let peers = [
+            self.ringing.as_mut().map(|(ip, identity)| (*ip, identity)),
+            self.called.as_mut().map(|(ip, identity)| (*ip, identity)),
+            self.session
+                .as_mut()
+                .map(|session| (session.ip, &mut session.peer_identity)),
+        ];
+        let Some((ip, Some(identity))) = peers.into_iter().flatten().next() else {
+            warn!("No peer identity to trust");
+            return;
+        };
+        info!("Trusting the key of {ip}, {}", identity.fingerprint());
+        self.scp.trust_peer(ip, identity.public_key);
+        identity.trust = PeerTrust::Known;
+    }
+    /// End the ongoing call, loopback and browser calls included
+    pub fn hang_up(&mut self) {
+        if self.set_session(None).is_some() {
+            self.scp.end_connection();
+        }
+        #[cfg(feature = "webrtc")]
+        if let Some(ref browser) = self.browser {
+            self.browser_ringing = None;
+            self.browser_accepted = None;
+            browser.hang_up();
+        }
+        self.stop_streams();
+    }
+    /// Call yourself: the outgoing stream is sent straight to the incoming one, without SCP.
+    /// Handy to check the camera, encoder and renderer on a single machine
+    /// # Errors
+    /// Returns an error if the incoming stream cannot accept the outgoing one
+    pub fn loopback(&mut self) -> anyhow::Result<()> {
+        // Packets of a previous loopback call may still be on their way
+        self.outgoing.set_video_encoding(self.video_encodings[0]);
+        self.start_streams(
+            new_session_id(),
+            PacketFormat::NEWEST,
+            self.incoming.address,
+            self.outgoing.address,
+            Some(self.screen.address),
+            None,
+        )?;
+        info!("Calling myself at {}", self.incoming.address);
+        Ok(())
+    }
+    /// Send a request to the peer of the ongoing call
+    pub fn send_control(&self, control: ControlMessage) {
+        if let ControlMessage::LowResolution(_) = control {
+            self.resolution_changes.fetch_add(1, Ordering::Relaxed);
+        }
+        self.scp.send_control(control);
+    }
+    /// Offer the file at `path` to the peer of the ongoing call. Returns the ID of the transfer,
+    /// what happens to it comes with `CallEvent::File`. None without a call to send it in
+    pub fn send_file(&self, path: PathBuf) -> Option<u32> {
+        if self.session.is_none() {
+            warn!("No call to send {} in", path.display());
+            return None;
+        }
+        Some(self.scp.send_file(path))
+    }
+    /// Send the text to the peer of the ongoing call for its clipboard, i.e. a link.
+    /// Returns false without a call, or if the text is empty or longer than `ClipboardText::MAX_LEN`
+    pub fn send_clipboard(&self, text: String) -> bool {
+        if self.session.is_none() {
+            warn!("No call to send the clipboard in");
+            return false;
+        }
+        if text.is_empty() || text.len() > ClipboardText::MAX_LEN {
+            warn!("Cannot send {} bytes of the clipboard", text.len());
+            return false;
+        }
+        self.scp.send_clipboard(ClipboardText(text));
+        true
+    }
+    /// Take the file the peer offered with `TransferEvent::Offered`, into `CallConfig::downloads_dir`
+    pub fn accept_file(&self, id: u32) {
+        self.scp.accept_file(id);
+    }
+    /// Turn down the file the peer offered with `TransferEvent::Offered`
+    pub fn decline_file(&self, id: u32) {
+        self.scp.decline_file(id);
+    }
+    /// Give up on the file sent or received
+    pub fn cancel_file(&self, id: u32) {
+        self.scp.cancel_file(id);
+    }
+    /// Hand every decoded frame of the incoming stream to `sink`, see `FrameSink`. None to stop
+    pub fn set_frame_sink(&self, sink: Option<Box<dyn FrameSink>>) {
+        self.incoming.set_frame_sink(sink);
+    }
+    /// Everything that happened since the last call. The streams follow the calls on their own
+    pub fn poll(&mut self) -> Vec<CallEvent> {
+        let mut events = Vec::new();
+        if let Some(event) = self.scp.poll_event() {
+            events.extend(self.on_connection_event(event));
+        }
+        for control in self.scp.poll_controls() {
+            self.on_control(control);
+            events.push(CallEvent::Control(control));
+        }
+        events.extend(self.scp.poll_transfers().into_iter().map(CallEvent::File));
+        if let Some(text) = self.scp.poll_clipboard() {
+            events.push(CallEvent::Clipboard(text.0));
+        }
+        self.poll_probes();
+        self.poll_loss();
+        self.poll_freeze();
+        self.poll_stills();
+        events.extend(self.poll_usage());
+        #[cfg(feature = "webrtc")]
+        events.extend(self.poll_browser());
+        self.refresh_resume_ticket();
+        for event in self.outgoing.poll_events() {
+            if let OutgoingStreamEvent::Reconfigured(mode) = event {
+                self.on_reconfigured(mode);
+            }
+            events.push(CallEvent::OutgoingStream(event));
+        }
+        events
+    }
+    /// Modes the camera captures in, empty if the video doesn't come from a camera
+    pub fn camera_modes(&self) -> &[VideoMode] {
+        &self.modes
+    }
+    /// Switch the camera to `mode`, mid-call too. The stream goes on, the peer is told
+    /// once the camera captures in it, see `OutgoingStreamEvent::Reconfigured`
+    /// # Errors
+    /// Returns an error if the video doesn't come from a camera
+    pub fn set_video_mode(&mut self, mode: VideoMode) -> anyhow::Result<()> {
+        self.outgoing.reconfigure(mode)
+    }
+    /// Send the video in `quality` from now on, mid-call too. A preset switches the camera
+    /// to the best mode within it, which the peer is told about like with `set_video_mode`.
+    /// The peer gets the full layer and its requests for a lower resolution are ignored
+    /// until `VideoQuality::Auto` is picked again
+    pub fn set_quality(&mut self, quality: VideoQuality) {
+        self.quality = quality;
+        let mode = match quality.preset() {
+            Some(preset) => {
+                for destination in self.outgoing.destinations() {
+                    self.outgoing.set_layer(destination.addr, StreamLayer::Full);
+                }
+                preset.pick_mode(&self.modes)
+            }
+            None => self.auto_mode(),
+        };
+        self.switch_mode(mode);
+    }
+    pub fn quality(&self) -> VideoQuality {
+        self.quality
+    }
+    /// kbit/s the camera mode is picked for, see `CallConfig::bitrate_budget`.
+    /// The camera switches right away if `VideoQuality::Auto` picks its mode
+    pub fn set_bitrate_budget(&mut self, budget: u32) {
+        self.bitrate_budget = budget;
+        self.pace_streams();
+        if self.quality == VideoQuality::Auto {
+            self.switch_mode(self.auto_mode());
+        }
+    }
+    pub fn bitrate_budget(&self) -> u32 {
+        self.bitrate_budget
+    }
+    /// kbit/s the video may take on the path to the peer of the ongoing call, measured as
+    /// the call started, see `crate::bandwidth`. `VideoQuality::Auto` keeps within it too
+    pub fn path_budget(&self) -> Option<u32> {
+        self.path_budget
+    }
+    /// Ask the peer of the ongoing call for its cheapest video (true), i.e. on a weak link,
+    /// or for its usual video again. It's asked by itself while many packets get lost,
+    /// see `stats::LossMonitor`, but what's asked here isn't taken back once they don't
+    pub fn request_low_bandwidth(&mut self, low: bool) {
+        self.low_bandwidth_pinned = low;
+        self.ask_low_bandwidth(low);
+    }
+    /// Whether the peer of the ongoing call was asked for its cheapest video
+    pub fn low_bandwidth_requested(&self) -> bool {
+        self.low_bandwidth
+    }
+    /// Whether the peer of the ongoing call was asked for still images, as its video
+    /// doesn't get through. They are shown in place of the video, see `crate::still`
+    pub fn still_images_requested(&self) -> bool {
+        self.throughput.is_starved()
+    }
+    /// Data the calls used, the ongoing one too
+    pub fn data_usage(&self) -> &DataUsage {
+        &self.usage
+    }
+    pub fn monthly_cap(&self) -> Option<MonthlyCap> {
+        self.monthly_cap
+    }
+    /// Session of the ongoing call
+    pub fn session(&self) -> Option<&SessionConfig> {
+        self.session.as_ref()
+    }
+    pub fn scp(&self) -> &ScpClient {
+        &self.scp
+    }
+    pub fn outgoing(&self) -> &H264StreamControls {
+        &self.outgoing
+    }
+    pub fn outgoing_mut(&mut self) -> &mut H264StreamControls {
+        &mut self.outgoing
+    }
+    pub fn incoming(&self) -> &H264IncomingStreamControls {
+        &self.incoming
+    }
+    pub fn incoming_mut(&mut self) -> &mut H264IncomingStreamControls {
+        &mut self.incoming
+    }
+    /// The screen shared by the peer. Nothing is decoded while the peer doesn't share it
+    pub fn screen(&self) -> &H264IncomingStreamControls {
+        &self.screen
+    }
+    /// The screen shared with the peer, None unless `CallConfig::screen` is set
+    pub fn screen_out(&self) -> Option<&H264StreamControls> {
+        self.screen_out.as_ref()
+    }
+    /// Record the calls as set up in `recording` from the next one on, None not to record them.
+    /// Turning the recording off also finishes the recording of the ongoing call
+    pub fn set_recording(&mut self, recording: Option<RecordingConfig>) {
+        if recording.is_none() {
+            self.finish_recording();
+        }
+        self.recording = recording;
+    }
+    /// Capture and play the audio in `channels` from the next call on.
+    /// Calls with a peer taking fewer channels go with those, see `crate::audio`
+    pub fn set_audio_channels(&mut self, channels: AudioChannels) {
+        self.scp.set_audio_channels(channels.count());
+    }
+    pub fn audio_channels(&self) -> AudioChannels {
+        AudioChannels::from_count(self.scp.preferences().audio_channels)
+    }
+    /// Statistics of the last call that ended, loopback calls included
+    pub fn last_call_summary(&self) -> Option<&CallSummary> {
+        self.last_summary.as_ref()
+    }
+    /// Latencies of the stages of the camera streams in both directions, since the start.
+    /// The threads aren't sampled, see `profile::ThreadCpuSampler`
+    pub fn pipeline_profile(&self) -> PipelineProfile {
+        PipelineProfile::take(&self.outgoing.stats(), &self.incoming.stats())
+    }
+    /// Whether the ongoing call is recorded
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+    /// File the last recorded call that ended went into, None if it couldn't be finished
+    pub fn last_recording(&self) -> Option<&Path> {
+        self.last_recording.as_deref()
+    }
+    /// End the ongoing call and wait for the stream threads to exit
+    /// # Errors
+    /// Returns an error if a stream thread panicked or didn't exit in time
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.hang_up();
+        if let Some(metrics) = self.metrics.take() {
+            metrics.shutdown()?;
+        }
+        #[cfg(feature = "webrtc")]
+        if let Some(browser) = self.browser.take() {
+            browser.shutdown()?;
+        }
+        let outgoing = self.outgoing.shutdown();
+        let screen_out = self.screen_out.map_or(Ok(()), H264StreamControls::shutdown);
+        let screen = self.screen.shutdown();
+        self.incoming
+            .shutdown()
+            .and(outgoing)
+            .and(screen_out)
+            .and(screen)
+    }
+    /// Address of the metrics endpoint, if it's served
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().map(|metrics| metrics.address)
+    }
+
+    /// Returns the previous session
+    fn set_session(&mut self, session: Option<SessionConfig>) -> Option<SessionConfig> {
+        let active = u64::from(session.is_some());
+        self.active_sessions.store(active, Ordering::Relaxed);
+        for registration in &mut self.advertised {
+            if let Err(e) = registration.set_in_call(session.is_some()) {
+                warn!("{e:#}");
+            }
+        }
+        if let Some(ref path) = self.resume_path {
+            // A call ended here cannot be resumed
+            let kept = match session.as_ref().and_then(|session| session.resume.as_ref()) {
+                Some(ticket) => ticket.save(path),
+                None => ResumeTicket::remove(path).map_err(Into::into),
+            };
+            if let Err(e) = kept {
+                warn!("Cannot keep the resume ticket: {e}");
+            }
+            self.resume_saved = Instant::now();
+        }
+        std::mem::replace(&mut self.session, session)
+    }
+    /// Save the resume ticket of the ongoing call again, so it stays fresh until a crash
+    fn refresh_resume_ticket(&mut self) {
+        let Some(ref path) = self.resume_path else {
+            return;
+        };
+        let Some(ticket) = self
+            .session
+            .as_mut()
+            .and_then(|session| session.resume.as_mut())
+        else {
+            return;
+        };
+        if self.resume_saved.elapsed() < RESUME_REFRESH {
+            return;
+        }
+        ticket.refresh();
+        if let Err(e) = ticket.save(path) {
+            warn!("Cannot keep the resume ticket: {e}");
+        }
+        self.resume_saved = Instant::now();
+    }
+    fn on_connection_event(&mut self, event: ConnectionEvent) -> Option<CallEvent> {
+        match event {
+            ConnectionEvent::ConnectionIncoming(ip, identity) => {
+                // The caller starts streaming as soon as it hears the call is accepted,
+                // which may be before this side is done setting up
+                self.incoming.expect(ip);
+                self.screen.expect(ip);
+                if self.session.is_none() && self.auto_answer.answers(ip, identity.as_ref()) {
+                    info!("Answering the call of {ip} automatically");
+                    self.scp.accept();
+                    // `CallEvent::Established` follows, there's nothing to ask the user
+                    return None;
+                }
+                self.ringing = Some((ip, identity));
+                Some(CallEvent::Incoming(ip))
+            }
+            ConnectionEvent::Ringing(ip, identity) => {
+                self.called = Some((ip, identity));
+                Some(CallEvent::Ringing)
+            }
+            ConnectionEvent::ConnectionEstablished(config) => {
+                self.ringing = None;
+                self.called = None;
+                // The peer sends from a port picked by its OS, any port of the peer goes
+                let peer_in = SocketAddr::new(config.ip, 0);
+                let peer_out = config.endpoints.video;
+                // Peers that cannot show a shared screen don't give a port for it
+                let peer_screen = config.endpoints.screen;
+                match config.codecs.audio {
+                    Some(audio) => {
+                        info!("Audio negotiated: {audio}");
+                        let own = self.audio_channels();
+                        let negotiated = AudioChannels::from_count(audio.channels);
+                        if own != negotiated {
+                            info!("The peer takes fewer channels, the call goes in {negotiated} rather than {own}");
+                        }
+                    }
+                    None => info!(
+                        "No audio encoding in common with the peer, the call goes without audio"
+                    ),
+                }
+                if let (Some(encoder), Some(decoder)) =
+                    (config.codecs.peer_encoder, config.codecs.peer_decoder)
+                {
+                    info!("The peer encodes with {encoder} and decodes with {decoder}");
+                }
+                info!("The video is sent in {}", config.codecs.video);
+                self.outgoing.set_video_encoding(config.codecs.video);
+                if config.media_keys.is_none() {
+                    info!("The streams of the call go unprotected");
+                }
+                if let Err(e) = self.start_streams(
+                    config.session_id,
+                    config.codecs.packet_format,
+                    peer_out,
+                    peer_in,
+                    peer_screen,
+                    config.media_keys.as_deref().copied(),
+                ) {
+                    warn!("Cannot start the streams of the call: {e}");
+                }
+                if self.packet_size.is_none() {
+                    // The size found for the previous peer may not fit this one
+                    self.set_packet_size(DEFAULT_PACKET_DATA_SIZE);
+                    self.probe = MtuProbe::start(peer_out, MAX_PACKET_DATA_SIZE)
+                        .inspect_err(|e| warn!("Cannot probe the path MTU: {e}"))
+                        .ok();
+                }
+                self.bandwidth_probe = BandwidthProbe::start(peer_out)
+                    .inspect_err(|e| warn!("Cannot probe the bandwidth: {e}"))
+                    .ok();
+                self.set_session(Some(config.clone()));
+                Some(CallEvent::Established(config))
+            }
+            ConnectionEvent::ConnectionFailed(e) => {
+                self.ringing = None;
+                self.called = None;
+                self.start_layer = StreamLayer::Full;
+                if self.session.is_none() {
+                    self.incoming.refuse();
+                    self.screen.refuse();
+                }
+                Some(CallEvent::Failed(e))
+            }
+            ConnectionEvent::ConnectionEnd => {
+                // The caller may give up before it's answered
+                self.ringing = None;
+                self.called = None;
+                self.set_session(None);
+                self.stop_streams();
+                Some(CallEvent::Ended)
+            }
+        }
+    }
+    /// Mode the camera captures in under `VideoQuality::Auto`
+    fn auto_mode(&self) -> Option<VideoMode> {
+        if self.peer_low_bandwidth || self.capped() {
+            let preset = VideoQuality::Low.preset()?;
+            preset.pick_mode(&self.modes)
+        } else {
+            pick_mode(&self.modes, self.video_budget())
+        }
+    }
+    /// kbit/s the video may take, within the path to the peer once it's measured
+    fn video_budget(&self) -> u32 {
+        match self.path_budget {
+            Some(path) => self.bitrate_budget.min(path),
+            None => self.bitrate_budget,
+        }
+    }
+    /// Pace the outgoing streams for the budget of the video, see `crate::pacer`
+    fn pace_streams(&self) {
+        let budget = Some(self.video_budget());
+        self.outgoing.set_pacing_rate(budget);
+        if let Some(ref screen_out) = self.screen_out {
+            screen_out.set_pacing_rate(budget);
+        }
+    }
+    /// Switch the camera to `mode`, unless it captures in it already.
+    /// Without camera modes there's nothing to switch, the video goes on as it is
+    fn switch_mode(&self, mode: Option<VideoMode>) {
+        if let Some(mode) = mode.filter(|mode| Some(*mode) != self.outgoing.video_mode()) {
+            info!("Switching to {mode}");
+            if let Err(e) = self.outgoing.reconfigure(mode) {
+                warn!("Cannot switch the camera mode: {e}");
+            }
+        }
+    }
+    fn ask_low_bandwidth(&mut self, low: bool) {
+        self.low_bandwidth = low;
+        self.resolution_changes.fetch_add(1, Ordering::Relaxed);
+        self.scp.send_control(ControlMessage::LowBandwidth(low));
+    }
+    /// Ask the peer for less while the packet loss of its stream stays high
+    fn poll_loss(&mut self) {
+        if self.session.is_none() {
+            return;
+        }
+        match self.loss.sample(&self.incoming.stats()) {
+            Some(true) if !self.low_bandwidth => {
+                info!("Losing many packets, asking the peer for its cheapest video");
+                self.ask_low_bandwidth(true);
+            }
+            Some(false) if self.low_bandwidth && !self.low_bandwidth_pinned && !self.capped() => {
+                info!("Hardly losing packets anymore, asking the peer for its usual video");
+                self.ask_low_bandwidth(false);
+            }
+            _ => {}
+        }
+    }
+    /// Ask the peer for a keyframe while its video is frozen, the packets of one were likely lost
+    fn poll_freeze(&mut self) {
+        if self.session.is_none() || !self.incoming.is_frozen() {
+            self.keyframe_requested = None;
+            return;
+        }
+        match self.keyframe_requested {
+            Some(at) if at.elapsed() < KEYFRAME_REQUEST_INTERVAL => return,
+            Some(_) => {}
+            None => info!("The video of the peer froze, asking for a keyframe"),
+        }
+        self.keyframe_requested = Some(Instant::now());
+        self.scp.send_control(ControlMessage::RequestKeyframe);
+    }
+    /// Ask the peer for still images while its video doesn't get through, show the ones it sends
+    /// and send ours while it asks, see `crate::still`
+    fn poll_stills(&mut self) {
+        if self.session.is_none() {
+            return;
+        }
+        if self.still_threshold > 0 {
+            match self
+                .throughput
+                .sample(&self.incoming.stats(), self.still_threshold)
+            {
+                Some(true) => {
+                    info!("The video of the peer doesn't get through, asking for still images");
+                    self.scp.send_control(ControlMessage::StillImages(true));
+                }
+                Some(false) => {
+                    info!("The video of the peer is back, no more still images");
+                    self.scp.send_control(ControlMessage::StillImages(false));
+                }
+                None => {}
+            }
+        }
+        if let Some(still) = self.scp.poll_still_image() {
+            match still::decode(&still) {
+                Ok(picture) => self.incoming.show_still(&picture),
+                Err(e) => warn!("Cannot decode the still image of the peer: {e}"),
+            }
+        }
+        if let Some(still) = self.stills.poll(Instant::now()) {
+            self.scp.send_still_image(still);
+        }
+    }
+    /// Bytes the streams sent and received so far, the shared screens included
+    fn stream_counters(&self) -> Usage {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let screen_sent = self
+            .screen_out
+            .as_ref()
+            .map_or(0, |screen_out| load(&screen_out.stats().bytes_sent));
+        Usage {
+            sent: load(&self.outgoing.stats().bytes_sent) + screen_sent,
+            received: load(&self.incoming.stats().bytes_received)
+                + load(&self.screen.stats().bytes_received),
+        }
+    }
+    /// The video is cut down as the data used this month nears the cap, see `MonthlyCap::downgrade`
+    fn capped(&self) -> bool {
+        self.near_cap && self.monthly_cap.is_some_and(|cap| cap.downgrade)
+    }
+    /// Count what the streams sent and received since the last time
+    fn count_usage(&mut self) {
+        let counters = self.stream_counters();
+        self.usage.record(
+            counters.sent.saturating_sub(self.counted.sent),
+            counters.received.saturating_sub(self.counted.received),
+        );
+        self.counted = counters;
+    }
+    /// Count the data used, and tell once the month nears the cap
+    fn poll_usage(&mut self) -> Option<CallEvent> {
+        self.count_usage();
+        let month = self.usage.this_month();
+        let near = self.monthly_cap.is_some_and(|cap| cap.is_near(month));
+        let event = (near && !self.near_cap).then(|| {
+            warn!("{} bytes used this month, nearing the cap", month.total());
+            CallEvent::DataCapNear(month)
+        });
+        // Goes back once the next month starts
+        if near != self.near_cap {
+            self.near_cap = near;
+            if self.quality == VideoQuality::Auto {
+                self.switch_mode(self.auto_mode());
+            }
+        }
+        if self.capped() && self.session.is_some() && !self.low_bandwidth {
+            info!("Nearing the monthly data cap, asking the peer for its cheapest video");
+            self.ask_low_bandwidth(true);
+        }
+        event
+    }
+    fn on_reconfigured(&self, mode: VideoMode) {
+        if self.session.is_some() {
+            self.scp.send_control(ControlMessage::VideoMode(mode));
+        }
+    }
+    fn on_control(&mut self, control: ControlMessage) {
+        match control {
+            ControlMessage::RequestKeyframe => self.outgoing.request_keyframe(),
+            ControlMessage::LowResolution(low) => {
+                self.resolution_changes.fetch_add(1, Ordering::Relaxed);
+                if self.quality != VideoQuality::Auto {
+                    info!("Keeping the {} quality the peer can't change", self.quality);
+                    return;
+                }
+                let layer = if low {
+                    StreamLayer::Base
+                } else {
+                    StreamLayer::Full
+                };
+                for destination in self.outgoing.destinations() {
+                    self.outgoing.set_layer(destination.addr, layer);
+                }
+            }
+            ControlMessage::ProbeAck(size) => {
+                if let Some(ref mut probe) = self.probe {
+                    probe.on_ack(size.into());
+                }
+            }
+            ControlMessage::BandwidthReport { packets, span_us } => {
+                let Some(probe) = self.bandwidth_probe.take() else {
+                    return;
+                };
+                let Some(budget) = probe.video_budget(packets, span_us) else {
+                    info!("The path to the peer is faster than a burst tells");
+                    return;
+                };
+                info!("The path to the peer carries video of about {budget} kbit/s");
+                self.path_budget = Some(budget);
+                self.pace_streams();
+                if self.quality == VideoQuality::Auto {
+                    self.switch_mode(self.auto_mode());
+                }
+            }
+            ControlMessage::LowBandwidth(low) => {
+                self.resolution_changes.fetch_add(1, Ordering::Relaxed);
+                self.peer_low_bandwidth = low;
+                if self.quality != VideoQuality::Auto {
+                    info!("Keeping the {} quality the peer can't change", self.quality);
+                    return;
+                }
+                self.switch_mode(self.auto_mode());
+            }
+            ControlMessage::VideoMode(mode) => {
+                if let Some(ref mut session) = self.session {
+                    session.video_mode = Some(mode);
+                }
+            }
+            ControlMessage::StillImages(stills) => self.stills.set_active(stills),
+            // The frontend holds its notifications back, see `CallEvent::Control`
+            ControlMessage::MuteNotifications(_) => {}
+        }
+    }
+    /// Acknowledge the probes of the peer and report its bursts, and probe the path to it
+    fn poll_probes(&mut self) {
+        for size in self.incoming.poll_probes() {
+            let size = u16::try_from(size).unwrap_or(u16::MAX);
+            self.scp.send_control(ControlMessage::ProbeAck(size));
+        }
+        if let Some(size) = self.probe.as_mut().and_then(MtuProbe::poll) {
+            info!("Sending packets of {size} bytes of data");
+            self.set_packet_size(size);
+            self.probe = None;
+        }
+        let now = Instant::now();
+        for at in self.incoming.poll_bursts() {
+            self.bursts.on_packet(at);
+        }
+        if let Some((packets, span_us)) = self.bursts.poll(now) {
+            self.scp
+                .send_control(ControlMessage::BandwidthReport { packets, span_us });
+        }
+        if self
+            .bandwidth_probe
+            .as_ref()
+            .is_some_and(|probe| probe.timed_out(now))
+        {
+            info!("The bandwidth probe wasn't reported, keeping the budget");
+            self.bandwidth_probe = None;
+        }
+    }
+    /// Ring as a browser joins, start the streams once it's answered and stop them as it leaves.
+    /// A browser may only join while there's no other call
+    #[cfg(feature = "webrtc")]
+    fn poll_browser(&mut self) -> Vec<CallEvent> {
+        let Some(ref browser) = self.browser else {
+            return Vec::new();
+        };
+        browser.set_busy(self.session.is_some() || self.ringing.is_some());
+        let browser_events = browser.poll_events();
+        let mut events = Vec::new();
+        if let Some(addr) = self.browser_accepted.take() {
+            events.extend(self.join_browser(addr));
+        }
+        for event in browser_events {
+            match event {
+                BrowserEvent::Joined(addr) => events.extend(self.on_browser_joined(addr)),
+                BrowserEvent::Left => {
+                    info!("The browser left");
+                    // It may give up before it's answered
+                    if self.browser_ringing.take().is_some() {
+                        self.ringing = None;
+                    }
+                    self.browser_accepted = None;
+                    self.stop_streams();
+                    events.push(CallEvent::Ended);
+                }
+            }
+        }
+        events
+    }
+    /// Ask about the browser that joined from `addr` like about a peer calling,
+    /// unless the peer list turns it away or it's answered by itself
+    #[cfg(feature = "webrtc")]
+    fn on_browser_joined(&mut self, addr: SocketAddr) -> Option<CallEvent> {
+        let ip = addr.ip();
+        if self.session.is_some()
+            || self.ringing.is_some()
+            || !self.scp.peer_list().admits_anonymous(ip)
+        {
+            info!("Turning away the browser at {addr}");
+            if let Some(ref browser) = self.browser {
+                browser.hang_up();
+            }
+            return None;
+        }
+        if self.auto_answer.answers(ip, None) {
+            info!("Answering the browser at {addr} automatically");
+            return self.join_browser(addr);
+        }
+        self.browser_ringing = Some(addr);
+        self.ringing = Some((ip, None));
+        Some(CallEvent::Incoming(ip))
+    }
+    /// Start the streams for the browser answered
+    #[cfg(feature = "webrtc")]
+    fn join_browser(&mut self, addr: SocketAddr) -> Option<CallEvent> {
+        let browser = self.browser.as_ref()?;
+        let video = browser.video_address;
+        let session_id = new_session_id();
+        browser.set_session_id(session_id);
+        // The browser bridge takes the packets and the video every peer takes
+        let format = PacketFormat::Legacy;
+        self.outgoing.set_video_encoding(VideoEncoding::H264);
+        match self.start_streams(session_id, format, video, video, None, None) {
+            Ok(()) => {
+                info!("{addr} joined from a browser");
+                Some(CallEvent::BrowserJoined(addr))
+            }
+            Err(e) => {
+                warn!("Cannot start the streams for the browser at {addr}: {e}");
+                self.hang_up();
+                None
+            }
+        }
+    }
+    /// Packet data size of the outgoing streams, the shared screen included
+    fn set_packet_size(&self, size: usize) {
+        self.outgoing.set_packet_size(size);
+        if let Some(ref screen_out) = self.screen_out {
+            screen_out.set_packet_size(size);
+        }
+    }
+    /// * `packet_format` - how the video streams are split into packets, both ways
+    /// * `send_to` - where the outgoing stream goes
+    /// * `accept_from` - where the incoming streams come from
+    /// * `screen_to` - where the shared screen goes, None if the peer cannot show it
+    fn start_streams(
+        &mut self,
+        session_id: u32,
+        packet_format: PacketFormat,
+        send_to: SocketAddr,
+        accept_from: SocketAddr,
+        screen_to: Option<SocketAddr>,
+        media_keys: Option<MediaKeys>,
+    ) -> anyhow::Result<()> {
+        self.protect_streams(media_keys);
+        self.incoming.set_session_id(session_id);
+        self.outgoing.set_session_id(session_id);
+        self.screen.set_session_id(session_id);
+        self.incoming.set_packet_format(packet_format);
+        self.outgoing.set_packet_format(packet_format);
+        self.screen.set_packet_format(packet_format);
+        self.incoming.accept(accept_from)?;
+        // Sent from a port of its own
+        self.screen.accept(SocketAddr::new(accept_from.ip(), 0))?;
+        // Taken before connecting, the stream thread wakes up and sends right away
+        self.call_start = Some(StatsSnapshot::take(
+            &self.outgoing.stats(),
+            &self.incoming.stats(),
+        ));
+        self.resolution_changes.store(0, Ordering::Relaxed);
+        // What's left of the previous call
+        self.count_usage();
+        self.usage.start_call();
+        self.outgoing.connect(send_to);
+        let layer = std::mem::take(&mut self.start_layer);
+        if layer != StreamLayer::Full {
+            self.outgoing.set_layer(send_to, layer);
+        }
+        if let (Some(screen_out), Some(screen_to)) = (self.screen_out.as_mut(), screen_to) {
+            screen_out.set_session_id(session_id);
+            screen_out.set_packet_format(packet_format);
+            screen_out.connect(screen_to);
+        }
+        self.loss.reset();
+        self.low_bandwidth = false;
+        self.low_bandwidth_pinned = false;
+        self.throughput.reset();
+        // A call coming before the previous one ended
+        self.finish_recording();
+        if let Some(ref recording) = self.recording {
+            match CallRecorder::start(recording) {
+                Ok(recorder) => {
+                    info!("Recording the call into {}", recorder.path().display());
+                    self.recorder = Some(recorder);
+                }
+                Err(e) => warn!("Cannot record the call: {e}"),
+            }
+        }
+        Ok(())
+    }
+    /// Protect the streams as SRTP with the keys of the session, None to send and take them as they are
+    fn protect_streams(&self, media_keys: Option<MediaKeys>) {
+        let context = |receive: bool, source| {
+            media_keys.map(|keys| {
+                let key = if receive { keys.receive } else { keys.send };
+                SrtpContext::new(&key, source)
+            })
+        };
+        self.outgoing
+            .set_protection(context(false, StreamSource::Camera));
+        self.incoming
+            .set_protection(context(true, StreamSource::Camera));
+        self.screen
+            .set_protection(context(true, StreamSource::Screen));
+        if let Some(ref screen_out) = self.screen_out {
+            screen_out.set_protection(context(false, StreamSource::Screen));
+        }
+    }
+    fn stop_streams(&mut self) {
+        self.probe = None;
+        self.stills.set_active(false);
+        self.bandwidth_probe = None;
+        self.bursts = BurstTimer::default();
+        // The next peer may have a better link
+        let limited = std::mem::take(&mut self.peer_low_bandwidth);
+        let measured = self.path_budget.take().is_some();
+        self.pace_streams();
+        if (limited || measured) && self.quality == VideoQuality::Auto {
+            self.switch_mode(self.auto_mode());
+        }
+        self.outgoing.disconnect();
+        self.incoming.refuse();
+        self.screen.refuse();
+        if let Some(ref mut screen_out) = self.screen_out {
+            screen_out.disconnect();
+        }
+        self.protect_streams(None);
+        self.finish_recording();
+        self.count_usage();
+        self.usage.save();
+        if let Some(start) = self.call_start.take() {
+            let summary = CallSummary::since(
+                &start,
+                &self.outgoing.stats(),
+                &self.incoming.stats(),
+                self.resolution_changes.load(Ordering::Relaxed),
+            );
+            info!("Call summary: {summary:?}");
+            self.last_summary = Some(summary);
+        }
+    }
+    fn finish_recording(&mut self) {
+        match self.recorder.take().map(CallRecorder::finish) {
+            Some(Ok(path)) => {
+                info!("Recorded the call into {}", path.display());
+                self.last_recording = Some(path);
+            }
+            Some(Err(e)) => {
+                warn!("{e}");
+                self.last_recording = None;
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use scp_client::client::{ScpConnectionError, VideoEncoding, VideoMode};
+    use scp_client::identity::{Identity, PeerIdentity, PeerTrust};
+    use scp_client::peer_list::PeerEntry;
+    use scp_client::probe::PeerCapabilities;
+    use scp_client::scp::{ControlMessage, PROTOCOL_VERSION};
+
+    use super::{layer_for_peer, AutoAnswer, CallConfig, CallEvent, CallManager};
+    use crate::h264_stream::incoming::{H264IncomingStreamControls, IncomingStreamControls};
+    use crate::h264_stream::outgoing::{StreamLayer, VideoBackend};
+    use crate::quality::VideoQuality;
+    use crate::recording::{RecordingConfig, RecordingLayout};
+    use crate::usage::MonthlyCap;
+
+    fn manager() -> CallManager {
+        manager_answering(AutoAnswer::Never)
+    }
+    fn manager_answering(auto_answer: AutoAnswer) -> CallManager {
+        CallManager::new(CallConfig {
+            video: VideoBackend::File(concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264").into()),
+            auto_answer,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+    /// Polls until `matches` holds for an event, up to a few seconds
+    fn wait_for(manager: &mut CallManager, matches: impl Fn(&CallEvent) -> bool) -> bool {
+        (0..100).any(|_| {
+            std::thread::sleep(Duration::from_millis(30));
+            manager.poll().iter().any(&matches)
+        })
+    }
+    #[test]
+    fn test_call_between_managers() {
+        let (mut caller, mut callee) = (manager(), manager());
+        caller.call(callee.scp().local_addr());
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Incoming(_)
+        )));
+        assert!(wait_for(&mut caller, |e| matches!(e, CallEvent::Ringing)));
+        // The caller sees the identity of the callee while it rings
+        let (_, identity) = caller.called().unwrap();
+        assert_eq!(
+            identity.map(|identity| identity.public_key),
+            Some(callee.scp().identity().public_key())
+        );
+        callee.accept();
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Established(_)
+        )));
+        assert!(wait_for(&mut caller, |e| matches!(
+            e,
+            CallEvent::Established(_)
+        )));
+        assert!(caller.called().is_none());
+
+        // Both streams run, each going to the other's incoming stream
+        let session_id = caller.session().unwrap().session_id;
+        assert_eq!(callee.session().unwrap().session_id, session_id);
+        let sent_to = caller.outgoing().destinations()[0].addr;
+        assert_eq!(sent_to.port(), callee.incoming().address.port());
+        // The stream thread picks the peer up on its next round
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(callee.incoming().is_receiving());
+
+        caller.hang_up();
+        assert!(wait_for(&mut callee, |e| matches!(e, CallEvent::Ended)));
+        assert!(callee.session().is_none());
+        caller.shutdown().unwrap();
+        callee.shutdown().unwrap();
+    }
+    #[test]
+    fn test_loopback_screen_share() {
+        let test_file = concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264");
+        let mut manager = CallManager::new(CallConfig {
+            video: VideoBackend::File(test_file.into()),
+            screen: Some(VideoBackend::File(test_file.into())),
+            ..Default::default()
+        })
+        .unwrap();
+        manager.loopback().unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        // Received on a socket of its own, next to the camera
+        let received = |stream: &H264IncomingStreamControls| {
+            stream.stats().packets_received.load(Ordering::Relaxed)
+        };
+        assert!(received(manager.screen()) > 0);
+        assert!(received(manager.incoming()) > 0);
+        assert_ne!(manager.screen().address, manager.incoming().address);
+        manager.shutdown().unwrap();
+    }
+    #[test]
+    fn test_loopback_call_summary() {
+        let test_file = concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264");
+        let mut manager = CallManager::new(CallConfig {
+            video: VideoBackend::File(test_file.into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(manager.last_call_summary().is_none());
+        manager.loopback().unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        manager.hang_up();
+        let summary = manager.last_call_summary().unwrap();
+        assert!(summary.duration_secs >= 0.5);
+        assert!(summary.sent.packets > 0);
+        assert_eq!(summary.received.packets, summary.sent.packets);
+        assert!(summary.sent.average_bitrate > 0);
+        manager.shutdown().unwrap();
+    }
+    #[test]
+    fn test_locked_quality() {
+        let mut manager = manager();
+        manager.loopback().unwrap();
+        let layers = |manager: &CallManager| -> Vec<StreamLayer> {
+            let destinations = manager.outgoing().destinations();
+            destinations
+                .iter()
+                .map(|destination| destination.layer)
+                .collect()
+        };
+        manager.set_quality(VideoQuality::Low);
+        manager.on_control(ControlMessage::LowResolution(true));
+        assert_eq!(layers(&manager), [StreamLayer::Full]);
+
+        manager.set_quality(VideoQuality::Auto);
+        manager.on_control(ControlMessage::LowResolution(true));
+        assert_eq!(layers(&manager), [StreamLayer::Base]);
+        // Picking a preset sends the full layer again
+        manager.set_quality(VideoQuality::High);
+        assert_eq!(layers(&manager), [StreamLayer::Full]);
+        manager.shutdown().unwrap();
+    }
+    #[test]
+    fn test_low_bandwidth_request() {
+        let mode = |width, height, fps| VideoMode { width, height, fps };
+        let mut manager = manager();
+        manager.modes = vec![mode(640, 480, 30), mode(320, 240, 30)];
+        assert_eq!(manager.auto_mode(), Some(mode(640, 480, 30)));
+        manager.on_control(ControlMessage::LowBandwidth(true));
+        assert_eq!(manager.auto_mode(), Some(mode(320, 240, 15)));
+        manager.on_control(ControlMessage::LowBandwidth(false));
+        assert_eq!(manager.auto_mode(), Some(mode(640, 480, 30)));
+
+        manager.loopback().unwrap();
+        manager.request_low_bandwidth(true);
+        assert!(manager.low_bandwidth_requested());
+        // A new call starts with the usual video
+        manager.hang_up();
+        manager.loopback().unwrap();
+        assert!(!manager.low_bandwidth_requested());
+        manager.shutdown().unwrap();
+    }
+    #[test]
+    fn test_monthly_cap() {
+        let mode = |width, height, fps| VideoMode { width, height, fps };
+        let mut manager = CallManager::new(CallConfig {
+            video: VideoBackend::File(concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264").into()),
+            monthly_cap: Some(MonthlyCap {
+                bytes: 1,
+                downgrade: true,
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        manager.modes = vec![mode(640, 480, 30), mode(320, 240, 30)];
+        assert_eq!(manager.auto_mode(), Some(mode(640, 480, 30)));
+        manager.loopback().unwrap();
+        assert!(wait_for(&mut manager, |e| matches!(
+            e,
+            CallEvent::DataCapNear(_)
+        )));
+        assert_eq!(manager.auto_mode(), Some(mode(320, 240, 15)));
+        manager.hang_up();
+        let usage = manager.data_usage();
+        assert!(usage.call().sent > 0);
+        assert_eq!(usage.this_month(), usage.all_time());
+        // Told only once
+        assert!(!wait_for(&mut manager, |e| matches!(
+            e,
+            CallEvent::DataCapNear(_)
+        )));
+        manager.shutdown().unwrap();
+    }
+    #[test]
+    fn test_loopback_recording() {
+        let dir = std::env::temp_dir().join(format!("eye-spy-recordings-{}", std::process::id()));
+        let mut manager = manager();
+        manager.set_recording(Some(RecordingConfig {
+            dir: dir.clone(),
+            layout: RecordingLayout::PictureInPicture,
+        }));
+        assert!(!manager.is_recording());
+        manager.loopback().unwrap();
+        assert!(manager.is_recording());
+        std::thread::sleep(Duration::from_millis(200));
+        manager.hang_up();
+        assert!(!manager.is_recording());
+        let recordings: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(recordings.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+        manager.shutdown().unwrap();
+    }
+    #[test]
+    fn test_auto_answer_allowlist() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        let caller = Identity::generate();
+        let identity = PeerIdentity {
+            public_key: caller.public_key(),
+            trust: PeerTrust::New,
+        };
+        let allowlist = AutoAnswer::Allowlist {
+            ips: vec![ip],
+            fingerprints: vec![caller.fingerprint().replace(' ', "").to_lowercase()],
+        };
+        assert!(allowlist.answers(ip, None));
+        assert!(allowlist.answers(other, Some(&identity)));
+        assert!(!allowlist.answers(other, None));
+        let stranger = PeerIdentity {
+            public_key: Identity::generate().public_key(),
+            trust: PeerTrust::New,
+        };
+        assert!(!allowlist.answers(other, Some(&stranger)));
+        assert!(AutoAnswer::Anyone.answers(other, None));
+        assert!(!AutoAnswer::Never.answers(ip, Some(&identity)));
+    }
+    #[test]
+    fn test_auto_answered_call() {
+        let (caller, mut callee) = (manager(), manager_answering(AutoAnswer::Anyone));
+        caller.call(callee.scp().local_addr());
+        // Nothing to accept, the streams start right away
+        assert!(wait_for(&mut callee, |e| match e {
+            CallEvent::Incoming(_) => panic!("The call should be answered by itself"),
+            e => matches!(e, CallEvent::Established(_)),
+        }));
+        assert!(callee.session().is_some());
+        caller.shutdown().unwrap();
+        callee.shutdown().unwrap();
+    }
+    #[test]
+    fn test_resumed_call() {
+        let resume_path =
+            std::env::temp_dir().join(format!("eye-spy-resume-call-{}", std::process::id()));
+        let resuming = || {
+            CallManager::new(CallConfig {
+                video: VideoBackend::File(concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264").into()),
+                resume_path: Some(resume_path.clone()),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let (mut caller, mut callee) = (resuming(), manager());
+        caller.call(callee.scp().local_addr());
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Incoming(_)
+        )));
+        callee.accept();
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Established(_)
+        )));
+        assert!(wait_for(&mut caller, |e| matches!(
+            e,
+            CallEvent::Established(_)
+        )));
+        assert!(resume_path.exists());
+        let session_id = caller.session().unwrap().session_id;
+
+        // The caller crashed, without hanging up, and came back
+        let mut restarted = resuming();
+        assert!(wait_for(&mut restarted, |e| matches!(
+            e,
+            CallEvent::Established(config) if config.session_id == session_id
+        )));
+        assert!(callee.session().is_some());
+        restarted.hang_up();
+        assert!(!resume_path.exists());
+        assert!(wait_for(&mut callee, |e| matches!(e, CallEvent::Ended)));
+        // Crashed, it never hangs up
+        std::mem::forget(caller);
+        restarted.shutdown().unwrap();
+        callee.shutdown().unwrap();
+    }
+    #[test]
+    fn test_unanswered_call() {
+        let mut caller = manager();
+        let mut callee = CallManager::new(CallConfig {
+            video: VideoBackend::File(concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264").into()),
+            ring_timeout: Duration::from_millis(500),
+            ..Default::default()
+        })
+        .unwrap();
+        caller.call(callee.scp().local_addr());
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Incoming(_)
+        )));
+        // Nobody answers, both sides give up
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Failed(ScpConnectionError::NotAnswered)
+        )));
+        assert!(callee.ringing().is_none());
+        assert!(wait_for(&mut caller, |e| matches!(
+            e,
+            CallEvent::Failed(ScpConnectionError::NotAnswered)
+        )));
+        assert!(caller.session().is_none());
+        caller.shutdown().unwrap();
+        callee.shutdown().unwrap();
+    }
+    #[test]
+    fn test_cancelled_call() {
+        let (mut caller, mut callee) = (manager(), manager());
+        caller.call(callee.scp().local_addr());
+        assert!(wait_for(&mut caller, |e| matches!(e, CallEvent::Ringing)));
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Incoming(_)
+        )));
+        assert!(callee.ringing().is_some());
+        caller.cancel();
+        assert!(wait_for(&mut caller, |e| matches!(
+            e,
+            CallEvent::Failed(ScpConnectionError::Cancelled)
+        )));
+        assert!(caller.session().is_none());
+        // The callee stops ringing
+        assert!(wait_for(&mut callee, |e| matches!(e, CallEvent::Ended)));
+        assert!(callee.ringing().is_none());
+        caller.shutdown().unwrap();
+        callee.shutdown().unwrap();
+    }
+    #[test]
+    fn test_blocked_caller() {
+        let (caller, mut callee) = (manager(), manager());
+        caller.call(callee.scp().local_addr());
+        assert!(wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Incoming(_)
+        )));
+        callee.block();
+        assert!(callee.ringing().is_none());
+        let fingerprint = caller.scp().identity().fingerprint();
+        assert_eq!(
+            callee.scp().peer_list().denied(),
+            [PeerEntry::identity(&fingerprint)]
+        );
+
+        // Refused before the user hears of it
+        caller.call(callee.scp().local_addr());
+        assert!(!wait_for(&mut callee, |e| matches!(
+            e,
+            CallEvent::Incoming(_)
+        )));
+        caller.shutdown().unwrap();
+        callee.shutdown().unwrap();
+    }
+    #[test]
+    fn test_layer_for_peer() {
+        let mode = |fps| VideoMode {
+            width: 640,
+            height: 480,
+            fps,
+        };
+        let capabilities = |modes| PeerCapabilities {
+            protocol_version: PROTOCOL_VERSION,
+            video_encodings: vec![VideoEncoding::H264],
+            audio_encodings: Vec::new(),
+            video_modes: modes,
+            busy: false,
+        };
+        let slow = capabilities(vec![mode(10), mode(15)]);
+        assert_eq!(layer_for_peer(Some(mode(30)), &slow), StreamLayer::Base);
+        assert_eq!(layer_for_peer(Some(mode(20)), &slow), StreamLayer::Full);
+        // Nothing to compare
+        assert_eq!(layer_for_peer(None, &slow), StreamLayer::Full);
+        let unknown = capabilities(Vec::new());
+        assert_eq!(layer_for_peer(Some(mode(30)), &unknown), StreamLayer::Full);
+    }
+}
@@ -0,0 +1,427 @@
+//! Picture controls of the camera, i.e. brightness or exposure, set through V4L2.
+//! The driver keeps them while the camera stays plugged in, streaming or not,
+//! so they are set on a handle of their own next to the stream.
+//! Values picked for a camera can be saved as its profile, see `CameraProfiles`.
+//! The mode the camera captures in is picked from the ones it supports, see `pick_mode`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use scp_client::client::VideoMode;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use v4l::control::Value;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::video::Capture;
+use v4l::{Control, Device, Fraction};
+
+use crate::h264_stream::FOURCC;
+
+/// Bitrate the video is encoded at by default, in kbit/s
+pub const DEFAULT_BITRATE_BUDGET: u32 = 1500;
+/// Roughly what the encoder needs for a pixel of a frame, in bits
+const BITS_PER_PIXEL: f32 = 0.1;
+/// Sizes probed of the cameras taking any size within a range, every step is far too many
+const STEPWISE_SIZES: [(u32, u32); 4] = [(320, 240), (640, 480), (1280, 720), (1920, 1080)];
+
+/// Control IDs of `linux/v4l2-controls.h`
+const V4L2_CID_BASE: u32 = 0x0098_0900;
+const V4L2_CID_CAMERA_CLASS_BASE: u32 = 0x009a_0900;
+const V4L2_CID_AUTO_WHITE_BALANCE: u32 = V4L2_CID_BASE + 12;
+const V4L2_CID_EXPOSURE_AUTO: u32 = V4L2_CID_CAMERA_CLASS_BASE + 1;
+/// Value of `V4L2_CID_EXPOSURE_AUTO` turning the automatic exposure off
+const V4L2_EXPOSURE_MANUAL: i64 = 1;
+
+/// A picture control the user can set
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraControl {
+    Brightness,
+    Contrast,
+    Saturation,
+    Exposure,
+    WhiteBalance,
+}
+
+impl CameraControl {
+    pub const ALL: [Self; 5] = [
+        Self::Brightness,
+        Self::Contrast,
+        Self::Saturation,
+        Self::Exposure,
+        Self::WhiteBalance,
+    ];
+    fn id(self) -> u32 {
+        match self {
+            Self::Brightness => V4L2_CID_BASE,
+            Self::Contrast => V4L2_CID_BASE + 1,
+            Self::Saturation => V4L2_CID_BASE + 2,
+            Self::Exposure => V4L2_CID_CAMERA_CLASS_BASE + 2,
+            Self::WhiteBalance => V4L2_CID_BASE + 26,
+        }
+    }
+    /// The control the camera sets this one by itself with, it's turned off to set it by hand
+    fn auto(self) -> Option<Control> {
+        match self {
+            Self::Exposure => Some(Control {
+                id: V4L2_CID_EXPOSURE_AUTO,
+                value: Value::Integer(V4L2_EXPOSURE_MANUAL),
+            }),
+            Self::WhiteBalance => Some(Control {
+                id: V4L2_CID_AUTO_WHITE_BALANCE,
+                value: Value::Boolean(false),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CameraControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Brightness => "Brightness",
+            Self::Contrast => "Contrast",
+            Self::Saturation => "Saturation",
+            Self::Exposure => "Exposure",
+            Self::WhiteBalance => "White balance",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Values the camera accepts for a control
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControlRange {
+    pub min: i64,
+    pub max: i64,
+    pub step: i64,
+    pub default: i64,
+}
+
+impl ControlRange {
+    /// The value at `fraction` of the range, on a step
+    pub fn at(&self, fraction: f32) -> i64 {
+        let span = (self.max - self.min) as f32;
+        let steps = (fraction.clamp(0., 1.) * span / self.step as f32).round() as i64;
+        (self.min + steps * self.step).min(self.max)
+    }
+    /// Where `value` is in the range, from 0 to 1
+    pub fn fraction(&self, value: i64) -> f32 {
+        if self.max == self.min {
+            return 0.;
+        }
+        (value - self.min) as f32 / (self.max - self.min) as f32
+    }
+}
+
+/// Values picked for the controls of a camera, the ones not in it are left as they are
+pub type CameraProfile = BTreeMap<CameraControl, i64>;
+
+/// Controls of the camera the outgoing stream captures from
+pub struct CameraControls {
+    device: Device,
+    name: String,
+    ranges: BTreeMap<CameraControl, ControlRange>,
+}
+
+impl CameraControls {
+    /// Opens the controls of the camera the outgoing stream uses, see `outgoing::open_camera`
+    /// # Errors
+    /// Returns an error if there's no camera or it cannot be queried
+    pub fn open() -> anyhow::Result<Self> {
+        let device = crate::h264_stream::outgoing::open_camera()?;
+        let name = device.query_caps().context("Cannot query the camera")?.card;
+        let ranges = device
+            .query_controls()
+            .context("Cannot query the camera controls")?
+            .into_iter()
+            .filter_map(|description| {
+                let control = CameraControl::ALL
+                    .into_iter()
+                    .find(|c| c.id() == description.id)?;
+                let range = ControlRange {
+                    min: description.minimum,
+                    max: description.maximum,
+                    step: (description.step as i64).max(1),
+                    default: description.default,
+                };
+                Some((control, range))
+            })
+            .collect();
+        Ok(Self {
+            device,
+            name,
+            ranges,
+        })
+    }
+    /// Name of the camera, the profiles are saved under it
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Values the control accepts, None if the camera doesn't have it
+    pub fn range(&self, control: CameraControl) -> Option<ControlRange> {
+        self.ranges.get(&control).copied()
+    }
+    /// # Errors
+    /// Returns an error if the camera doesn't have the control or it cannot be read
+    pub fn get(&self, control: CameraControl) -> anyhow::Result<i64> {
+        match self.device.control(control.id())?.value {
+            Value::Integer(value) => Ok(value),
+            Value::Boolean(value) => Ok(value.into()),
+            value => anyhow::bail!("Unexpected value of {control}: {value:?}"),
+        }
+    }
+    /// Sets the control, turning off the automatic mode it has
+    /// # Errors
+    /// Returns an error if the camera doesn't have the control or refused the value
+    pub fn set(&self, control: CameraControl, value: i64) -> anyhow::Result<()> {
+        let range = self
+            .range(control)
+            .with_context(|| format!("The camera has no {control} control"))?;
+        if let Some(auto) = control.auto() {
+            self.device
+                .set_control(auto)
+                .with_context(|| format!("Cannot turn off the automatic {control}"))?;
+        }
+        let value = value.clamp(range.min, range.max);
+        self.device
+            .set_control(Control {
+                id: control.id(),
+                value: Value::Integer(value),
+            })
+            .with_context(|| format!("Cannot set {control} to {value}"))
+    }
+    /// Current values of all the controls the camera has
+    pub fn profile(&self) -> CameraProfile {
+        self.ranges
+            .keys()
+            .filter_map(|&control| Some((control, self.get(control).ok()?)))
+            .collect()
+    }
+    /// Sets the controls of the profile the camera has, the rest are skipped
+    pub fn apply(&self, profile: &CameraProfile) {
+        for (&control, &value) in profile {
+            if self.ranges.contains_key(&control) {
+                if let Err(e) = self.set(control, value) {
+                    warn!("{e}");
+                }
+            }
+        }
+    }
+    /// Back to the defaults of the driver, the automatic modes included
+    pub fn reset(&self) {
+        let autos = [V4L2_CID_EXPOSURE_AUTO, V4L2_CID_AUTO_WHITE_BALANCE];
+        for description in self.device.query_controls().unwrap_or_default() {
+            let known = self.ranges.keys().any(|c| c.id() == description.id);
+            if !known && !autos.contains(&description.id) {
+                continue;
+            }
+            let control = Control {
+                id: description.id,
+                value: Value::Integer(description.default),
+            };
+            if let Err(e) = self.device.set_control(control) {
+                warn!("Cannot reset the camera control {}: {e}", description.name);
+            }
+        }
+    }
+}
+
+/// The modes the camera captures the frames the stream encodes in
+/// # Errors
+/// Returns an error if the camera cannot be queried or doesn't capture YUYV at all
+pub fn probe_modes(device: &Device) -> anyhow::Result<Vec<VideoMode>> {
+    let formats = device
+        .enum_formats()
+        .context("Cannot query the camera formats")?;
+    if !formats.iter().any(|format| format.fourcc == FOURCC) {
+        anyhow::bail!("The camera doesn't capture {FOURCC}");
+    }
+    let mut sizes = Vec::new();
+    for size in device.enum_framesizes(FOURCC)? {
+        match size.size {
+            FrameSizeEnum::Discrete(size) => sizes.push((size.width, size.height)),
+            FrameSizeEnum::Stepwise(size) => {
+                let fits = |value: u32, min: u32, max: u32, step: u32| {
+                    (min..=max).contains(&value) && (value - min).is_multiple_of(step)
+                };
+                sizes.extend(STEPWISE_SIZES.into_iter().filter(|&(width, height)| {
+                    fits(width, size.min_width, size.max_width, size.step_width)
+                        && fits(height, size.min_height, size.max_height, size.step_height)
+                }));
+            }
+        }
+    }
+    let mut modes = Vec::new();
+    for (width, height) in sizes {
+        // The frames are encoded in 4:2:0, two by two pixels share their colour
+        if width % 2 != 0 || height % 2 != 0 {
+            continue;
+        }
+        let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+            continue;
+        };
+        for interval in device.enum_frameintervals(FOURCC, width, height)? {
+            let fastest = match interval.interval {
+                FrameIntervalEnum::Discrete(interval) => interval,
+                FrameIntervalEnum::Stepwise(interval) => interval.min,
+            };
+            if let Some(fps) = fps(fastest) {
+                modes.push(VideoMode {
+                    width: w,
+                    height: h,
+                    fps,
+                });
+            }
+        }
+    }
+    Ok(modes)
+}
+
+/// Frames a second of a frame interval
+fn fps(interval: Fraction) -> Option<u16> {
+    (interval.numerator > 0)
+        .then(|| (interval.denominator / interval.numerator).try_into().ok())
+        .flatten()
+        .filter(|&fps| fps > 0)
+}
+
+/// Estimated bitrate of the video in the mode, in kbit/s
+pub fn estimated_bitrate(mode: &VideoMode) -> u32 {
+    let pixels = mode.width as f32 * mode.height as f32 * mode.fps as f32;
+    (pixels * BITS_PER_PIXEL / 1000.) as u32
+}
+
+/// The largest, then the fastest, mode within `budget` kbit/s. The stream is encoded in its size.
+/// The cheapest one if none fits it, None only if there are no modes
+pub fn pick_mode(modes: &[VideoMode], budget: u32) -> Option<VideoMode> {
+    modes
+        .iter()
+        .filter(|mode| estimated_bitrate(mode) <= budget)
+        .max_by_key(|mode| (mode.width as u32 * mode.height as u32, mode.fps))
+        .or_else(|| modes.iter().min_by_key(|mode| estimated_bitrate(mode)))
+        .copied()
+}
+
+/// Probes the camera the outgoing stream uses for the best mode within `budget` kbit/s
+/// # Errors
+/// Returns an error if there's no camera, it cannot be probed or has no modes
+pub fn best_mode(budget: u32) -> anyhow::Result<VideoMode> {
+    let modes = camera_modes()?;
+    pick_mode(&modes, budget).context("The camera has no modes")
+}
+
+/// Probes the camera the outgoing stream uses for its modes, see `probe_modes`
+/// # Errors
+/// Returns an error if there's no camera or it cannot be probed
+pub fn camera_modes() -> anyhow::Result<Vec<VideoMode>> {
+    let device = crate::h264_stream::outgoing::open_camera()?;
+    probe_modes(&device)
+}
+
+/// Profiles of the cameras by their names, see `CameraControls::name`.
+/// Saved as JSON after every change when loaded from a file
+#[derive(Debug, Default)]
+pub struct CameraProfiles {
+    path: Option<PathBuf>,
+    profiles: BTreeMap<String, CameraProfile>,
+}
+
+impl CameraProfiles {
+    /// Profiles that aren't saved anywhere
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+    /// Loads the profiles stored at `path`. The file is created once a profile is saved
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read or parsed
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let profiles = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            profiles,
+        })
+    }
+    pub fn get(&self, camera: &str) -> Option<&CameraProfile> {
+        self.profiles.get(camera)
+    }
+    /// Remember the profile of the camera, replacing the one it had
+    pub fn save(&mut self, camera: &str, profile: CameraProfile) {
+        self.profiles.insert(camera.to_string(), profile);
+        if let Err(e) = self.try_save() {
+            warn!("Cannot save the camera profiles: {e}");
+        }
+    }
+    fn try_save(&self) -> anyhow::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(&self.profiles)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scp_client::client::VideoMode;
+
+    use super::{pick_mode, CameraControl, CameraProfile, CameraProfiles, ControlRange};
+
+    #[test]
+    fn test_control_range_steps() {
+        let range = ControlRange {
+            min: -64,
+            max: 64,
+            step: 4,
+            default: 0,
+        };
+        assert_eq!(range.at(0.), -64);
+        assert_eq!(range.at(0.5), 0);
+        assert_eq!(range.at(0.52), 4);
+        assert_eq!(range.at(2.), 64);
+        assert_eq!(range.fraction(range.at(0.25)), 0.25);
+    }
+    #[test]
+    fn test_pick_mode() {
+        let mode = |width, height, fps| VideoMode { width, height, fps };
+        let modes = [
+            mode(1280, 720, 30),
+            mode(640, 480, 30),
+            mode(640, 480, 15),
+            mode(320, 240, 30),
+        ];
+        assert_eq!(pick_mode(&modes, u32::MAX), Some(mode(1280, 720, 30)));
+        assert_eq!(pick_mode(&modes, 1000), Some(mode(640, 480, 30)));
+        assert_eq!(pick_mode(&modes, 500), Some(mode(640, 480, 15)));
+        assert_eq!(pick_mode(&modes, 300), Some(mode(320, 240, 30)));
+        assert_eq!(pick_mode(&modes, 1), Some(mode(320, 240, 30)));
+        // The cheapest one if none fits
+        assert_eq!(pick_mode(&modes[..1], 1000), Some(mode(1280, 720, 30)));
+        assert_eq!(pick_mode(&[], 1000), None);
+    }
+    #[test]
+    fn test_camera_profiles_persist() {
+        let path = std::env::temp_dir().join(format!("camera-profiles-{}", std::process::id()));
+        let profile = CameraProfile::from([
+            (CameraControl::Brightness, 12),
+            (CameraControl::WhiteBalance, 4600),
+        ]);
+        CameraProfiles::load(&path)
+            .unwrap()
+            .save("Integrated Camera", profile.clone());
+        let loaded = CameraProfiles::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get("Integrated Camera"), Some(&profile));
+        assert_eq!(loaded.get("USB Camera"), None);
+    }
+}
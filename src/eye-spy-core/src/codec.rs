@@ -0,0 +1,432 @@
+//! Encoding and decoding of the video behind `VideoEncoder` and `VideoDecoder`, so the backend
+//! is picked at runtime, see `CodecBackend`. OpenH264 is always there, x264 is built in with
+//! the `x264` feature. Its library is loaded as it's opened, so a build with it still runs
+//! on systems without.
+//! A backend that isn't built in or cannot be opened falls back to OpenH264 with a warning,
+//! see `open_encoder` and `open_decoder`. The backends in use are told to the peers over SCP.
+//! Sources encoded already, i.e. `crate::bitstream::FileSource`, go out as they are,
+//! which is told as `CodecBackend::Passthrough`.
+//! The video may be VP8 instead, through libvpx with the `vp8` feature, for the peers that
+//! negotiate it, see `VideoEncoding`. A VP8 frame goes out as a single unit, and the receiver
+//! tells the encodings apart by the start codes of the H.264 units, see `encoding_of`,
+//! so a sender falling back to H.264 is still decoded.
+//! AV1 is the low-bitrate mode, through rav1e and dav1d with the `av1` feature, for the peers
+//! on constrained uplinks that negotiate it. Its temporal units go out whole, like VP8 frames.
+
+use std::iter;
+
+use openh264::decoder::{DecodedYUV, Decoder};
+use openh264::encoder::Encoder;
+use openh264::formats::{YUVSlices, YUVSource};
+use openh264::nal_units;
+use tracing::warn;
+
+pub use scp_client::client::{CodecBackend, VideoEncoding};
+
+/// A frame in planar YUV 4:2:0, as the encoders take it
+/// * `y`, `u`, `v` - the planes, their rows `strides` apart
+/// * `width`, `height` - of the luma plane, the chroma planes are half of it both ways
+pub struct YuvFrame<'a> {
+    pub y: &'a [u8],
+    pub u: &'a [u8],
+    pub v: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    pub strides: (usize, usize, usize),
+}
+
+impl YuvFrame<'_> {
+    /// Whether the planes hold every row the size and the strides tell
+    pub fn is_complete(&self) -> bool {
+        let fits = |plane: &[u8], stride: usize, width: usize, height: usize| {
+            height == 0 || plane.len() >= stride * (height - 1) + width
+        };
+        let (chroma_width, chroma_height) = (self.width / 2, self.height / 2);
+        fits(self.y, self.strides.0, self.width, self.height)
+            && fits(self.u, self.strides.1, chroma_width, chroma_height)
+            && fits(self.v, self.strides.2, chroma_width, chroma_height)
+    }
+}
+
+/// Encodes the frames of a stream
+pub trait VideoEncoder {
+    /// The frame as NAL units, start codes included, or a whole VP8 frame
+    /// # Errors
+    /// Returns an error if the backend fails, the next frame may still be encoded
+    fn encode(&mut self, frame: &YuvFrame) -> anyhow::Result<Vec<u8>>;
+    /// Make the next frame a keyframe
+    fn force_intra_frame(&mut self);
+    fn backend(&self) -> CodecBackend;
+    fn encoding(&self) -> VideoEncoding {
+        VideoEncoding::H264
+    }
+}
+
+/// A decoded picture, borrowed from its decoder until the next unit is decoded
+pub trait DecodedPicture {
+    fn dimensions(&self) -> (usize, usize);
+    /// Writes the picture as RGBA into `target`, 4 bytes for every pixel of `dimensions`
+    fn write_rgba8(&self, target: &mut [u8]);
+}
+
+/// Decodes the units of a stream
+pub trait VideoDecoder {
+    /// Decode a NAL unit, start code included, or a VP8 frame. A picture once the unit
+    /// completes one
+    /// # Errors
+    /// Returns an error if the unit cannot be decoded, i.e. its references were lost
+    fn decode(&mut self, unit: &[u8]) -> anyhow::Result<Option<Box<dyn DecodedPicture + '_>>>;
+    fn backend(&self) -> CodecBackend;
+    fn encoding(&self) -> VideoEncoding {
+        VideoEncoding::H264
+    }
+}
+
+struct OpenH264Encoder(Encoder);
+
+impl OpenH264Encoder {
+    fn boxed() -> anyhow::Result<Box<dyn VideoEncoder>> {
+        let encoder = Encoder::new()
+            .map_err(|e| anyhow::Error::msg(format!("Cannot create a h264 encoder: {e}")))?;
+        Ok(Box::new(Self(encoder)))
+    }
+}
+
+impl VideoEncoder for OpenH264Encoder {
+    fn encode(&mut self, frame: &YuvFrame) -> anyhow::Result<Vec<u8>> {
+        let slices = YUVSlices::new(
+            (frame.y, frame.u, frame.v),
+            (frame.width, frame.height),
+            frame.strides,
+        );
+        let bitstream = self
+            .0
+            .encode(&slices)
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        let mut vec = Vec::new();
+        bitstream
+            .write(&mut vec)
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        Ok(vec)
+    }
+    fn force_intra_frame(&mut self) {
+        self.0.force_intra_frame();
+    }
+    fn backend(&self) -> CodecBackend {
+        CodecBackend::OpenH264
+    }
+}
+
+struct OpenH264Decoder(Decoder);
+
+impl OpenH264Decoder {
+    fn boxed() -> anyhow::Result<Box<dyn VideoDecoder>> {
+        let decoder = Decoder::new()
+            .map_err(|e| anyhow::Error::msg(format!("Cannot create a h264 decoder: {e}")))?;
+        Ok(Box::new(Self(decoder)))
+    }
+}
+
+impl VideoDecoder for OpenH264Decoder {
+    fn decode(&mut self, unit: &[u8]) -> anyhow::Result<Option<Box<dyn DecodedPicture + '_>>> {
+        match self.0.decode(unit) {
+            Ok(picture) => Ok(picture.map(|picture| Box::new(picture) as Box<dyn DecodedPicture>)),
+            Err(e) => Err(anyhow::Error::msg(e.to_string())),
+        }
+    }
+    fn backend(&self) -> CodecBackend {
+        CodecBackend::OpenH264
+    }
+}
+
+impl DecodedPicture for DecodedYUV<'_> {
+    fn dimensions(&self) -> (usize, usize) {
+        YUVSource::dimensions(self)
+    }
+    fn write_rgba8(&self, target: &mut [u8]) {
+        DecodedYUV::write_rgba8(self, target);
+    }
+}
+
+/// An encoder of `encoding` for frames of `width` x `height`, the H.264 one of `backend`.
+/// H.264 of OpenH264 if either isn't built in or cannot be opened
+/// # Errors
+/// Returns an error if the OpenH264 encoder cannot be created either
+#[cfg_attr(
+    not(any(feature = "x264", feature = "vp8", feature = "av1")),
+    allow(unused_variables)
+)]
+pub fn open_encoder(
+    encoding: VideoEncoding,
+    backend: CodecBackend,
+    width: usize,
+    height: usize,
+) -> anyhow::Result<Box<dyn VideoEncoder>> {
+    if encoding != VideoEncoding::H264 {
+        let opened: anyhow::Result<Box<dyn VideoEncoder>> =
+            match encoding {
+                #[cfg(feature = "vp8")]
+                VideoEncoding::Vp8 => crate::vpx::VpxEncoder::open(width, height)
+                    .map(|encoder| Box::new(encoder) as _),
+                #[cfg(feature = "av1")]
+                VideoEncoding::Av1 => crate::av1::Rav1eEncoder::open(width, height)
+                    .map(|encoder| Box::new(encoder) as _),
+                _ => Err(anyhow::anyhow!("It isn't built in")),
+            };
+        match opened {
+            Ok(encoder) => return Ok(encoder),
+            Err(e) => warn!("Cannot encode {encoding}, falling back to H.264: {e}"),
+        }
+    }
+    let opened: anyhow::Result<Box<dyn VideoEncoder>> = match backend {
+        CodecBackend::OpenH264 => return OpenH264Encoder::boxed(),
+        #[cfg(feature = "x264")]
+        CodecBackend::X264 => {
+            crate::x264::X264Encoder::open(width, height).map(|encoder| Box::new(encoder) as _)
+        }
+        CodecBackend::Passthrough => Err(anyhow::anyhow!(
+            "It only stands for the sources encoded already"
+        )),
+        CodecBackend::Libvpx | CodecBackend::Rav1e | CodecBackend::Dav1d => {
+            Err(anyhow::anyhow!("It doesn't encode H.264"))
+        }
+        _ => Err(anyhow::anyhow!("It isn't built in")),
+    };
+    opened.or_else(|e| {
+        warn!("Cannot encode with {backend}, falling back to OpenH264: {e}");
+        OpenH264Encoder::boxed()
+    })
+}
+
+/// A decoder of `encoding`, the H.264 one of `backend`, or an OpenH264 one if that isn't
+/// built in or cannot be opened. There's no falling back for the other encodings
+/// # Errors
+/// Returns an error if there's no decoder of `encoding`, or the OpenH264 one cannot be created
+pub fn open_decoder(
+    encoding: VideoEncoding,
+    backend: CodecBackend,
+) -> anyhow::Result<Box<dyn VideoDecoder>> {
+    match encoding {
+        VideoEncoding::H264 => {}
+        #[cfg(feature = "vp8")]
+        VideoEncoding::Vp8 => {
+            return crate::vpx::VpxDecoder::open().map(|decoder| Box::new(decoder) as _)
+        }
+        #[cfg(feature = "av1")]
+        VideoEncoding::Av1 => {
+            return crate::av1::Dav1dDecoder::open().map(|decoder| Box::new(decoder) as _)
+        }
+        _ => anyhow::bail!("Decoding {encoding} isn't built in"),
+    }
+    let opened: anyhow::Result<Box<dyn VideoDecoder>> = match backend {
+        CodecBackend::OpenH264 => return OpenH264Decoder::boxed(),
+        CodecBackend::X264 | CodecBackend::Passthrough => Err(anyhow::anyhow!("It doesn't decode")),
+        CodecBackend::Libvpx | CodecBackend::Rav1e | CodecBackend::Dav1d => {
+            Err(anyhow::anyhow!("It doesn't decode H.264"))
+        }
+        _ => Err(anyhow::anyhow!("It isn't built in")),
+    };
+    opened.or_else(|e| {
+        warn!("Cannot decode with {backend}, falling back to OpenH264: {e}");
+        OpenH264Decoder::boxed()
+    })
+}
+
+/// The backend the H.264 frames of `width` x `height` end up encoded with, asking for `backend`
+pub fn resolve_encoder(backend: CodecBackend, width: usize, height: usize) -> CodecBackend {
+    open_encoder(VideoEncoding::H264, backend, width, height)
+        .map_or(backend, |encoder| encoder.backend())
+}
+
+/// The backend the H.264 units end up decoded with, asking for `backend`
+pub fn resolve_decoder(backend: CodecBackend) -> CodecBackend {
+    open_decoder(VideoEncoding::H264, backend).map_or(backend, |decoder| decoder.backend())
+}
+
+/// Whether this build encodes and decodes `encoding` on this system
+pub fn is_available(encoding: VideoEncoding) -> bool {
+    match encoding {
+        VideoEncoding::H264 => true,
+        #[cfg(feature = "vp8")]
+        VideoEncoding::Vp8 => crate::vpx::available(),
+        #[cfg(feature = "av1")]
+        VideoEncoding::Av1 => crate::av1::available(),
+        _ => false,
+    }
+}
+
+/// The encodings told to the peers, `preferred` first if it's available, H.264 always
+pub fn video_encodings(preferred: VideoEncoding) -> Vec<VideoEncoding> {
+    let mut encodings = Vec::new();
+    let others = [VideoEncoding::H264, VideoEncoding::Vp8, VideoEncoding::Av1];
+    for encoding in iter::once(preferred).chain(others) {
+        if is_available(encoding) && !encodings.contains(&encoding) {
+            encodings.push(encoding);
+        }
+    }
+    encodings
+}
+
+/// The encoding of a unit of the stream. H.264 units start with a start code, a VP8 frame
+/// never does: its first byte has the bit of a shown frame set. An AV1 temporal unit starts
+/// with its delimiter, which only a VP8 keyframe could too, but that has its own start code
+pub fn encoding_of(unit: &[u8]) -> VideoEncoding {
+    if unit.starts_with(&[0, 0, 1]) || unit.starts_with(&[0, 0, 0, 1]) || unit.is_empty() {
+        VideoEncoding::H264
+    } else if unit.starts_with(&[0x12, 0]) && unit.get(3..6) != Some(&[0x9d, 0x01, 0x2a]) {
+        VideoEncoding::Av1
+    } else {
+        VideoEncoding::Vp8
+    }
+}
+
+/// The units an encoded frame is sent as, its NAL units or the whole frame of the others
+pub fn units(frame: &[u8]) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+    match encoding_of(frame) {
+        VideoEncoding::H264 => Box::new(nal_units(frame)),
+        _ => Box::new(iter::once(frame)),
+    }
+}
+
+/// Writes a picture in planar YUV 4:2:0 as RGBA into `target`, 4 bytes for every pixel,
+/// with the BT.601 coefficients of the limited range
+#[cfg_attr(not(any(feature = "vp8", feature = "av1")), allow(dead_code))]
+pub(crate) fn write_i420_rgba8(
+    (y, u, v): (&[u8], &[u8], &[u8]),
+    strides: (usize, usize, usize),
+    (width, height): (usize, usize),
+    target: &mut [u8],
+) {
+    for (row, pixels) in target.chunks_exact_mut(width * 4).take(height).enumerate() {
+        for (column, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+            let [r, g, b] = yuv_to_rgb(
+                y[row * strides.0 + column],
+                u[row / 2 * strides.1 + column / 2],
+                v[row / 2 * strides.2 + column / 2],
+            );
+            pixel.copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+}
+
+/// A sample of the limited range of BT.601, as the cameras and decoders give them, in RGB
+pub(crate) fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let clamp = |value: i32| (value >> 8).clamp(0, 255) as u8;
+    let c = 298 * (i32::from(y) - 16);
+    let d = i32::from(u) - 128;
+    let e = i32::from(v) - 128;
+    [
+        clamp(c + 409 * e + 128),
+        clamp(c - 100 * d - 208 * e + 128),
+        clamp(c + 516 * d + 128),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encoding_of, is_available, open_decoder, open_encoder, resolve_decoder, units,
+        video_encodings, write_i420_rgba8, CodecBackend, VideoEncoding, YuvFrame,
+    };
+
+    #[test]
+    fn test_fallback() {
+        // Whatever is asked for, there's an encoder and a decoder
+        for backend in [
+            CodecBackend::OpenH264,
+            CodecBackend::X264,
+            CodecBackend::Libvpx,
+            CodecBackend::Rav1e,
+            CodecBackend::Dav1d,
+            CodecBackend::Passthrough,
+            CodecBackend::Unknown,
+        ] {
+            open_encoder(VideoEncoding::H264, backend, 64, 48).unwrap();
+            open_decoder(VideoEncoding::H264, backend).unwrap();
+        }
+        let encoder = open_encoder(VideoEncoding::H264, CodecBackend::Passthrough, 64, 48).unwrap();
+        assert_eq!(encoder.backend(), CodecBackend::OpenH264);
+        // H.264 is sent if VP8 cannot be, but it cannot be decoded as H.264
+        let encoder = open_encoder(VideoEncoding::Vp8, CodecBackend::OpenH264, 64, 48).unwrap();
+        let vp8 = is_available(VideoEncoding::Vp8);
+        assert_eq!(encoder.encoding() == VideoEncoding::Vp8, vp8);
+        assert_eq!(
+            open_decoder(VideoEncoding::Vp8, CodecBackend::OpenH264).is_ok(),
+            vp8
+        );
+        let encoder = open_encoder(VideoEncoding::Av1, CodecBackend::OpenH264, 64, 48).unwrap();
+        let av1 = is_available(VideoEncoding::Av1);
+        assert_eq!(encoder.encoding() == VideoEncoding::Av1, av1);
+        assert!(open_decoder(VideoEncoding::Unknown, CodecBackend::OpenH264).is_err());
+        assert_eq!(resolve_decoder(CodecBackend::X264), CodecBackend::OpenH264);
+        assert_eq!(
+            resolve_decoder(CodecBackend::OpenH264),
+            CodecBackend::OpenH264
+        );
+    }
+    #[test]
+    fn test_video_encodings() {
+        assert_eq!(video_encodings(VideoEncoding::H264)[0], VideoEncoding::H264);
+        let encodings = video_encodings(VideoEncoding::Vp8);
+        assert!(encodings.contains(&VideoEncoding::H264));
+        assert_eq!(
+            encodings[0] == VideoEncoding::Vp8,
+            is_available(VideoEncoding::Vp8)
+        );
+        assert!(!video_encodings(VideoEncoding::Unknown).contains(&VideoEncoding::Unknown));
+    }
+    #[test]
+    fn test_units() {
+        let h264 = [0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3];
+        assert_eq!(encoding_of(&h264), VideoEncoding::H264);
+        assert_eq!(units(&h264).count(), 2);
+        // A shown VP8 keyframe, the start code of the keyframes after the frame tag
+        let vp8 = [0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a, 0, 0, 1];
+        assert_eq!(encoding_of(&vp8), VideoEncoding::Vp8);
+        assert_eq!(units(&vp8).collect::<Vec<_>>(), [&vp8[..]]);
+        // The temporal delimiter, then a sequence header
+        let av1 = [0x12, 0x00, 0x0a, 0x0b, 0, 0, 0];
+        assert_eq!(encoding_of(&av1), VideoEncoding::Av1);
+        assert_eq!(units(&av1).count(), 1);
+        // A VP8 keyframe of version 1, starting like the delimiter
+        let vp8 = [0x12, 0x00, 0x00, 0x9d, 0x01, 0x2a, 0, 0];
+        assert_eq!(encoding_of(&vp8), VideoEncoding::Vp8);
+    }
+    #[test]
+    fn test_i420_rgba() {
+        let (y, u, v) = ([16, 235, 16, 235], [128], [128]);
+        let mut rgba = [0; 16];
+        write_i420_rgba8((&y, &u, &v), (2, 1, 1), (2, 2), &mut rgba);
+        assert_eq!(rgba[..4], [0, 0, 0, 255]);
+        assert_eq!(rgba[4..8], [255, 255, 255, 255]);
+        // Red, off by the rounding at most
+        let mut pixel = [0; 4];
+        write_i420_rgba8((&[81], &[90], &[240]), (1, 1, 1), (1, 1), &mut pixel);
+        assert!(pixel[0] >= 254 && pixel[1] <= 1 && pixel[2] <= 1);
+    }
+    #[test]
+    fn test_complete_frame() {
+        let (y, chroma) = (vec![0; 64 * 48], vec![128; 32 * 24]);
+        let frame = YuvFrame {
+            y: &y,
+            u: &chroma,
+            v: &chroma,
+            width: 64,
+            height: 48,
+            strides: (64, 32, 32),
+        };
+        assert!(frame.is_complete());
+        // The last row doesn't need the whole stride
+        let frame = YuvFrame {
+            strides: (64, 33, 32),
+            ..frame
+        };
+        assert!(!frame.is_complete());
+        let short = YuvFrame {
+            y: &y[..64 * 47],
+            strides: (64, 32, 32),
+            ..frame
+        };
+        assert!(!short.is_complete());
+    }
+}
@@ -0,0 +1,363 @@
+//! Filters applied to the camera frames before they are encoded, see `FrameFilter`.
+//! They run on the stream thread for every frame, so they have to be cheap.
+//!
+//! `BackgroundBlur` tells the person from the background by the chroma of the skin,
+//! without any model: the face and the hands, and everything under them, are kept.
+//! `Watermark` writes a label and the time into the corner, with a built-in 5x7 font.
+
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Filters set on the outgoing stream, applied in order
+pub type SharedFrameFilters = Arc<Mutex<Vec<Box<dyn FrameFilter>>>>;
+
+/// Changes a captured frame before it's encoded
+pub trait FrameFilter: Send {
+    /// Filter a YUYV frame of `width` x `height` in place
+    fn apply(&mut self, frame: &mut [u8], width: usize, height: usize);
+}
+
+/// Side of the square blocks the frame is blurred and segmented in, in pixels
+const BLOCK: usize = 8;
+/// Cb and Cr ranges of the skin tones, the usual ones of the YCbCr skin detectors
+const SKIN_CB: (u8, u8) = (77, 127);
+const SKIN_CR: (u8, u8) = (133, 173);
+
+/// Blurs everything but the person in front of the camera.
+/// The frame is downscaled to blocks, blurred with a box blur and scaled back
+/// for the blocks that don't belong to the person
+/// * `radius` - of the box blur, in blocks
+/// * `spread` - how many blocks to the sides of the skin count as the person too
+pub struct BackgroundBlur {
+    pub radius: usize,
+    pub spread: usize,
+    /// Average Y, U and V of each block, reused for every frame
+    blocks: Vec<[u8; 3]>,
+    blurred: Vec<[u8; 3]>,
+    person: Vec<bool>,
+}
+
+impl Default for BackgroundBlur {
+    fn default() -> Self {
+        Self {
+            radius: 3,
+            spread: 3,
+            blocks: Vec::new(),
+            blurred: Vec::new(),
+            person: Vec::new(),
+        }
+    }
+}
+
+impl BackgroundBlur {
+    fn downscale(&mut self, frame: &[u8], width: usize, columns: usize, rows: usize) {
+        self.blocks.clear();
+        for row in 0..rows {
+            for column in 0..columns {
+                let mut sum = [0u32; 3];
+                for y in row * BLOCK..(row + 1) * BLOCK {
+                    let line = &frame[(y * width + column * BLOCK) * 2..][..BLOCK * 2];
+                    for pixel in line.chunks_exact(4) {
+                        sum[0] += pixel[0] as u32 + pixel[2] as u32;
+                        sum[1] += pixel[1] as u32;
+                        sum[2] += pixel[3] as u32;
+                    }
+                }
+                let (luma, chroma) = ((BLOCK * BLOCK) as u32, (BLOCK * BLOCK / 2) as u32);
+                self.blocks.push([
+                    (sum[0] / luma) as u8,
+                    (sum[1] / chroma) as u8,
+                    (sum[2] / chroma) as u8,
+                ]);
+            }
+        }
+    }
+    /// Skin blocks and the ones under them, as the body is usually below the face
+    fn segment(&mut self, columns: usize, rows: usize) {
+        let is_skin = |[_, cb, cr]: [u8; 3]| {
+            (SKIN_CB.0..=SKIN_CB.1).contains(&cb) && (SKIN_CR.0..=SKIN_CR.1).contains(&cr)
+        };
+        self.person.clear();
+        self.person.resize(columns * rows, false);
+        for row in 0..rows {
+            for column in 0..columns {
+                if !is_skin(self.blocks[row * columns + column]) {
+                    continue;
+                }
+                let from = column.saturating_sub(self.spread);
+                let to = (column + self.spread).min(columns - 1);
+                for below in row.saturating_sub(1)..rows {
+                    self.person[below * columns + from..=below * columns + to].fill(true);
+                }
+            }
+        }
+    }
+    /// Box blur of the blocks, separable
+    fn blur(&mut self, columns: usize, rows: usize) {
+        let radius = self.radius;
+        let average = |values: &mut dyn Iterator<Item = [u8; 3]>| {
+            let (mut sum, mut count) = ([0u32; 3], 0);
+            for value in values {
+                (0..3).for_each(|i| sum[i] += value[i] as u32);
+                count += 1;
+            }
+            sum.map(|s| (s / count.max(1)) as u8)
+        };
+        let mut horizontal = Vec::with_capacity(self.blocks.len());
+        for row in 0..rows {
+            for column in 0..columns {
+                let span = column.saturating_sub(radius)..(column + radius + 1).min(columns);
+                horizontal.push(average(&mut span.map(|c| self.blocks[row * columns + c])));
+            }
+        }
+        self.blurred.clear();
+        for row in 0..rows {
+            for column in 0..columns {
+                let span = row.saturating_sub(radius)..(row + radius + 1).min(rows);
+                self.blurred
+                    .push(average(&mut span.map(|r| horizontal[r * columns + column])));
+            }
+        }
+    }
+}
+
+impl FrameFilter for BackgroundBlur {
+    fn apply(&mut self, frame: &mut [u8], width: usize, height: usize) {
+        let (columns, rows) = (width / BLOCK, height / BLOCK);
+        if columns == 0 || rows == 0 || frame.len() < width * height * 2 {
+            return;
+        }
+        self.downscale(frame, width, columns, rows);
+        self.segment(columns, rows);
+        self.blur(columns, rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let block = row * columns + column;
+                if self.person[block] {
+                    continue;
+                }
+                let [y, u, v] = self.blurred[block];
+                for line in row * BLOCK..(row + 1) * BLOCK {
+                    let line = &mut frame[(line * width + column * BLOCK) * 2..][..BLOCK * 2];
+                    for pixel in line.chunks_exact_mut(4) {
+                        pixel.copy_from_slice(&[y, u, y, v]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Luma of the watermark text and of the box under it
+const TEXT_LUMA: u8 = 235;
+const BOX_LUMA: u8 = 16;
+/// Neutral chroma, so the watermark stays grey
+const NO_CHROMA: u8 = 128;
+/// Size of a glyph of the font, with a column of spacing
+const GLYPH_WIDTH: usize = 6;
+const GLYPH_HEIGHT: usize = 7;
+
+/// Writes a label and the time into the top left corner of the frames,
+/// i.e. the name of the camera node and when the frame was captured
+/// * `label` - written first, if any
+/// * `time` - write the time of capture, in UTC as there's no time zone database at hand
+/// * `scale` - pixels of the frame for a pixel of the font
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    pub label: Option<String>,
+    pub time: bool,
+    pub scale: usize,
+}
+
+impl Default for Watermark {
+    fn default() -> Self {
+        Self {
+            label: None,
+            time: true,
+            scale: 2,
+        }
+    }
+}
+
+impl Watermark {
+    fn text(&self) -> String {
+        let time = self.time.then(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format_utc(now.as_secs())
+        });
+        let text = [self.label.clone(), time].into_iter().flatten();
+        text.collect::<Vec<_>>().join("  ").to_uppercase()
+    }
+}
+
+impl FrameFilter for Watermark {
+    fn apply(&mut self, frame: &mut [u8], width: usize, height: usize) {
+        let text = self.text();
+        let scale = self.scale.max(1);
+        let margin = 2 * scale;
+        // As much of the text as fits the frame
+        let chars = ((width.saturating_sub(3 * margin)) / (GLYPH_WIDTH * scale)).min(text.len());
+        let box_width = (chars * GLYPH_WIDTH * scale + 2 * margin).min(width - margin);
+        let box_height = GLYPH_HEIGHT * scale + 2 * margin;
+        if chars == 0 || frame.len() < width * height * 2 || box_height + margin > height {
+            return;
+        }
+        let mut set = |x: usize, y: usize, luma: u8| {
+            let at = (y * width + x) * 2;
+            frame[at] = luma;
+            frame[(at & !3) + 1] = NO_CHROMA;
+            frame[(at & !3) + 3] = NO_CHROMA;
+        };
+        for y in margin..margin + box_height {
+            for x in margin..margin + box_width {
+                set(x, y, BOX_LUMA);
+            }
+        }
+        for (i, c) in text.chars().take(chars).enumerate() {
+            let (left, top) = (2 * margin + i * GLYPH_WIDTH * scale, 2 * margin);
+            for (row, bits) in glyph(c).into_iter().enumerate() {
+                for column in (0..5).filter(|column| bits & (0x10 >> column) != 0) {
+                    for (dx, dy) in (0..scale).flat_map(|dx| (0..scale).map(move |dy| (dx, dy))) {
+                        set(
+                            left + column * scale + dx,
+                            top + row * scale + dy,
+                            TEXT_LUMA,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS` of a UNIX timestamp, in UTC
+fn format_utc(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil date of a day number, see Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Rows of a 5x7 glyph, the highest of the 5 bits is the leftmost pixel.
+/// Only upper case letters, digits and a little punctuation, `?` stands for the rest
+#[rustfmt::skip]
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        ' ' => [0x00; GLYPH_HEIGHT],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_utc, BackgroundBlur, FrameFilter, Watermark, BLOCK, BOX_LUMA, TEXT_LUMA};
+
+    #[test]
+    fn test_background_blur() {
+        let (width, height) = (BLOCK * 16, BLOCK * 12);
+        // Noisy grey background with a skin coloured square in the middle
+        let mut frame = Vec::with_capacity(width * height * 2);
+        for y in 0..height {
+            for x in (0..width).step_by(2) {
+                let face =
+                    (BLOCK * 6..BLOCK * 8).contains(&x) && (BLOCK * 4..BLOCK * 6).contains(&y);
+                let luma = if (x / 2 + y) % 2 == 0 { 40 } else { 200 };
+                match face {
+                    true => frame.extend_from_slice(&[150, 110, 150, 150]),
+                    false => frame.extend_from_slice(&[luma, 128, luma, 128]),
+                }
+            }
+        }
+        let original = frame.clone();
+        BackgroundBlur::default().apply(&mut frame, width, height);
+
+        let pixel = |frame: &[u8], x: usize, y: usize| frame[(y * width + x) * 2];
+        // The face and the body under it are kept
+        assert_eq!(pixel(&frame, BLOCK * 6, BLOCK * 4), 150);
+        assert_eq!(
+            pixel(&frame, BLOCK * 7, height - 1),
+            pixel(&original, BLOCK * 7, height - 1)
+        );
+        // The noise above it is gone
+        assert_eq!(pixel(&frame, 0, 0), pixel(&frame, 1, 1));
+        assert_ne!(pixel(&original, 0, 0), pixel(&original, 0, 1));
+    }
+    #[test]
+    fn test_watermark() {
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14 22:13:20");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00");
+
+        let (width, height) = (320, 240);
+        let mut frame = vec![100; width * height * 2];
+        let mut watermark = Watermark {
+            label: Some("Cam 1".into()),
+            time: false,
+            scale: 2,
+        };
+        watermark.apply(&mut frame, width, height);
+        let luma = |x: usize, y: usize| frame[(y * width + x) * 2];
+        // The box starts at the margin, the top left pixel of `C` sits in it
+        assert_eq!(luma(4, 4), BOX_LUMA);
+        assert_eq!(luma(8 + 2, 8), TEXT_LUMA);
+        assert_eq!(luma(8, 8), BOX_LUMA);
+        assert_eq!(luma(0, 0), 100);
+        assert_eq!(luma(width - 1, height - 1), 100);
+    }
+}
@@ -0,0 +1,3115 @@
+//! This module is responsible for configuring, encoding, decoding  H264 streams from and to the user.
+//! It provides basic controls via <!TODO!>
+//! To get a received frame. It works outside any renderer.
+
+use lazy_static::lazy_static;
+
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use v4l::FourCC;
+
+use crate::codec::{open_encoder, CodecBackend, VideoEncoder, VideoEncoding, YuvFrame};
+use crate::filter::SharedFrameFilters;
+use crate::motion::MotionState;
+use crate::recording::LOCAL_FRAME_BUFFER;
+use crate::srtp::SrtpContext;
+
+use v4l::buffer::Type;
+use v4l::io::traits::CaptureStream;
+use v4l::prelude::MmapStream;
+use v4l::video::Capture;
+use v4l::Device;
+
+// The packets are put together and taken apart in `nal`, for both streams
+pub use crate::nal::{packetize, PacketBuffer, DEFAULT_PACKET_DATA_SIZE, MAX_PACKET_DATA_SIZE};
+
+/// Size the camera captures in when no mode was picked. The frames kept for the recordings
+/// and the still images are scaled to it, see `LOCAL_FRAME_BUFFER`
+pub const WIDTH: usize = 640;
+pub const HEIGHT: usize = 480;
+// Using YUV
+pub(crate) const FOURCC: FourCC = FourCC { repr: *b"YUYV" };
+/// Frames between the keyframes of the outgoing stream, 2 s at 30 fps.
+/// A peer joining or losing packets in between waits for the next one at most that long
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 60;
+/// How long `shutdown()` waits for a stream thread to exit
+const THREAD_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Receives the H.264 NAL units of a stream, as they are sent or put back together,
+/// i.e. to republish them, see `crate::rtp`. Called on the stream thread, so it should be quick
+pub trait UnitSink: Send {
+    /// A NAL unit, start code included
+    /// * `capture_ts` - when its frame was captured, see `av_sync::timestamp_now`.
+    ///   The same for all the units of a frame
+    fn unit(&mut self, unit: &[u8], capture_ts: u64);
+}
+type SharedUnitSink = Arc<Mutex<Option<Box<dyn UnitSink>>>>;
+/// SRTP context of a stream, None while its packets go out and are taken as they are
+type SharedProtection = Arc<Mutex<Option<SrtpContext>>>;
+
+mod ssignal {
+
+    /// Stream Signal None - no signal to stream thread
+    pub const SSIGNAL_NONE: u8 = 0;
+    /// Stream Signal Disconnect - signal stream to stop until another connection
+    pub const SSIGNAL_DISCONNECT: u8 = 1 << 1;
+    /// Stream Signal Pause - signal stream thread to pause
+    pub const SSIGNAL_PAUSE: u8 = 1 << 2;
+    /// Stream Signal Resume - signal stream thread to resume/start  
+    pub const SSIGNAL_RESUME: u8 = 1 << 3;
+    /// Stream Signal Resume - signal stream thread to resume/start
+    /// Loads the SocketAddr from the mutex inside StreamControls.data  
+    pub const SSIGNAL_CONNECT: u8 = 1 << 4;
+    /// Stream Signal Terminate - signal stream thread to exit loop and terminate  
+    pub const SSIGNAL_TERMINATE: u8 = 1 << 5;
+    /// Stream Signal Keyframe - signal stream thread to send a keyframe next
+    pub const SSIGNAL_KEYFRAME: u8 = 1 << 6;
+    /// Stream Signal Expect - signal incoming stream to buffer the packets of a peer
+    /// until it's accepted. Loads the IP from the mutex inside the controls
+    pub const SSIGNAL_EXPECT: u8 = 1 << 7;
+}
+
+/// Waits for the thread to finish, giving up after `timeout`.
+/// A thread that doesn't exit in time is left running detached.
+pub(crate) fn join_with_timeout(handle: JoinHandle<()>, timeout: Duration) -> anyhow::Result<()> {
+    let name = handle.thread().name().unwrap_or("unnamed").to_string();
+    let deadline = Instant::now() + timeout;
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            anyhow::bail!("Thread {name} didn't exit within {timeout:?}");
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    handle
+        .join()
+        .map_err(|_| anyhow::Error::msg(format!("Thread {name} panicked")))
+}
+
+/// RGBA8 frame of the size it was decoded in. The peers send the size their camera captures in,
+/// which changes when it's switched to another mode
+/// * `data` - `width * height * 4` bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbaFrame {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl RgbaFrame {
+    /// Black and transparent, of `WIDTH` x `HEIGHT` until the first frame is decoded
+    pub fn blank() -> Self {
+        Self {
+            width: WIDTH,
+            height: HEIGHT,
+            data: vec![0; WIDTH * HEIGHT * 4],
+        }
+    }
+    /// Make room for a frame of `width` x `height`. Returns whether the size changed,
+    /// the pixels are left as they are if it didn't
+    pub fn resize(&mut self, width: usize, height: usize) -> bool {
+        if (width, height) == (self.width, self.height) {
+            return false;
+        }
+        self.width = width;
+        self.height = height;
+        self.data.resize(width * height * 4, 0);
+        true
+    }
+    /// The pixels at `width` x `height`, nearest neighbour scaled if it's another size
+    pub fn scaled(&self, width: usize, height: usize) -> Cow<'_, [u8]> {
+        if (width, height) == (self.width, self.height) {
+            return Cow::Borrowed(&self.data);
+        }
+        let mut scaled = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            let row = (y * self.height / height) * self.width;
+            for x in 0..width {
+                let at = (row + x * self.width / width) * 4;
+                scaled.extend_from_slice(self.data.get(at..at + 4).unwrap_or(&[0; 4]));
+            }
+        }
+        Cow::Owned(scaled)
+    }
+}
+
+/// The latest decoded frame of an incoming stream
+pub type FrameBuffer = Mutex<RgbaFrame>;
+
+// Static buffers so the borrow checker doesn't complain
+lazy_static! {
+    // Only one frame, keep it light-weight and real-time
+    pub static ref RGB_FRAME_BUFFER: FrameBuffer = Mutex::new(RgbaFrame::blank());
+    // The screen shared by the peer, next to its camera
+    pub static ref SCREEN_FRAME_BUFFER: FrameBuffer = Mutex::new(RgbaFrame::blank());
+}
+
+/// What an incoming stream shows. The peer sends each to its own port, see `Preferences::port_in_screen`
+/// * `Camera` - the video of the call, decoded into `RGB_FRAME_BUFFER`
+/// * `Screen` - the screen shared by the peer, decoded into `SCREEN_FRAME_BUFFER`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamSource {
+    Camera,
+    Screen,
+}
+
+impl StreamSource {
+    pub fn frame_buffer(self) -> &'static FrameBuffer {
+        match self {
+            Self::Camera => &RGB_FRAME_BUFFER,
+            Self::Screen => &SCREEN_FRAME_BUFFER,
+        }
+    }
+}
+
+/// Trait for consistent interfaces accross streams
+/// It should be utilized on a wrapper struct for the original stream
+pub trait CustomStream<'a, T> {
+    fn next(&mut self, buffer: &mut [u8]) -> Option<usize>;
+    /// Like `next_vec()`, but tells why there's no data
+    fn try_next_vec(&mut self) -> anyhow::Result<Vec<u8>>;
+    fn next_vec(&mut self) -> Option<Vec<u8>> {
+        self.try_next_vec().ok()
+    }
+}
+
+/// A captured frame encoded for each temporal layer
+/// * `full` - the frame in the full rate stream
+/// * `base` - the frame in the half rate stream, None if the frame isn't part of it
+/// * `captured_at` - when the frame was captured, or read for sources that don't capture
+pub struct LayeredFrame {
+    pub full: Vec<u8>,
+    pub base: Option<Vec<u8>>,
+    pub captured_at: Instant,
+}
+
+pub struct H264Stream<'a> {
+    stream: MmapStream<'a>,
+    frames: FrameEncoder,
+}
+impl<'a> H264Stream<'a> {
+    /// # Errors
+    /// Returns an error if the device cannot stream or the encoder cannot be created
+    pub fn new(device: &Device) -> anyhow::Result<Self> {
+        Self::with_encoder(device, VideoEncoding::H264, CodecBackend::OpenH264)
+    }
+    /// Like `new`, encoding in `encoding` with `backend` for H.264, or H.264 of OpenH264
+    /// if it cannot, see `crate::codec`. The frames are encoded in the size the device captures
+    /// # Errors
+    /// Returns an error if the device cannot stream or no encoder can be created
+    pub fn with_encoder(
+        device: &Device,
+        encoding: VideoEncoding,
+        backend: CodecBackend,
+    ) -> anyhow::Result<Self> {
+        let format = device.format()?;
+        let stream = MmapStream::with_buffers(device, Type::VideoCapture, 4)?;
+        let size = (format.width as usize, format.height as usize);
+        Ok(Self {
+            stream,
+            frames: FrameEncoder::new(encoding, backend, size)?,
+        })
+    }
+    /// Filter the frames with these, shared with the controls so they can be changed any time
+    pub fn set_filters(&mut self, filters: SharedFrameFilters) {
+        self.frames.filters = filters;
+    }
+    /// Detect the motion with this, shared with the controls like the filters
+    pub(crate) fn set_motion(&mut self, motion: Arc<MotionState>) {
+        self.frames.motion = motion;
+    }
+    /// The backend the frames are encoded with, after any fallback
+    pub fn encoder_backend(&self) -> CodecBackend {
+        self.frames.encoder.backend()
+    }
+    /// The encoding of the frames, after any fallback
+    pub fn encoding(&self) -> VideoEncoding {
+        self.frames.encoder.encoding()
+    }
+    /// Capture the next frame and encode it for each temporal layer, see `FrameEncoder::encode`.
+    /// Capture errors keep their `std::io::Error`, so the caller can tell a lost device apart
+    pub fn next_layers(&mut self, with_base: bool) -> anyhow::Result<LayeredFrame> {
+        let captured = self.stream.next()?.0;
+        let captured_at = Instant::now();
+        let (full, base) = self.frames.encode(captured, with_base)?;
+        Ok(LayeredFrame {
+            full,
+            base,
+            captured_at,
+        })
+    }
+}
+
+/// Encodes the YUYV frames of a camera for each temporal layer, in the size they're captured in.
+/// A camera capturing in another mode gets an encoder of its own, see `H264Stream::with_encoder`
+struct FrameEncoder {
+    encoder: Box<dyn VideoEncoder>,
+    /// Encoder of the half rate stream, of the same backend. Created once a destination asks
+    /// for it, as a separate stream it stays decodable without the dropped frames
+    base_encoder: Option<Box<dyn VideoEncoder>>,
+    /// Captured frames so far, every other one goes to the base layer
+    frame_count: u64,
+    /// Size of the captured frames, and of the encoded ones
+    width: usize,
+    height: usize,
+    /// Applied to every frame before it's encoded, see `H264Stream::set_filters`
+    filters: SharedFrameFilters,
+    /// Looks for motion in the frames before they are filtered, see `H264Stream::set_motion`
+    motion: Arc<MotionState>,
+}
+impl FrameEncoder {
+    /// # Errors
+    /// Returns an error if no encoder can be created
+    fn new(
+        encoding: VideoEncoding,
+        backend: CodecBackend,
+        (width, height): (usize, usize),
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            encoder: open_encoder(encoding, backend, width, height)?,
+            base_encoder: None,
+            frame_count: 0,
+            width,
+            height,
+            filters: SharedFrameFilters::default(),
+            motion: Arc::default(),
+        })
+    }
+    #[inline]
+    /// Allocates the buffers for the y u v slices and returns the data.\
+    /// # Performance
+    /// Compilator actually makes it faster than using statically allocated buffers, somehow...
+    fn prepare_yuv_slices(
+        raw_buf: &[u8],
+        width: usize,
+        height: usize,
+    ) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut y = Vec::with_capacity(width * height);
+        let mut u = Vec::with_capacity(width * height / 2);
+        let mut v = Vec::with_capacity(width * height / 2);
+
+        // Process the raw YUYV data
+        for chunk in raw_buf.chunks(4) {
+            // YUYV format: Y1 U Y2 V
+            let y0 = chunk[0];
+            let u0 = chunk[1];
+            let y1 = chunk[2];
+            let v0 = chunk[3];
+            y.push(y0);
+            y.push(y1);
+            u.push(u0);
+            v.push(v0);
+        }
+        (y, u, v)
+    }
+
+    /// Nearest neighbour scaling of a YUYV frame to `WIDTH` x `HEIGHT`,
+    /// the size the recordings and the still images take the frames of the camera in
+    fn scale_yuyv(raw_buf: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let mut scaled = Vec::with_capacity(WIDTH * HEIGHT * 2);
+        for y in 0..HEIGHT {
+            let row = (y * height / HEIGHT) * width * 2;
+            // A macropixel at a time, Y1 U Y2 V
+            for x in (0..WIDTH).step_by(2) {
+                let (x0, x1) = (x * width / WIDTH, (x + 1) * width / WIDTH);
+                let chroma = row + (x0 & !1) * 2;
+                let luma = |x: usize| raw_buf.get(row + x * 2).copied().unwrap_or_default();
+                let chroma = |offset: usize| raw_buf.get(chroma + offset).copied().unwrap_or(128);
+                scaled.extend_from_slice(&[luma(x0), chroma(1), luma(x1), chroma(3)]);
+            }
+        }
+        scaled
+    }
+
+    /// Encode a captured frame for each temporal layer, the full one and the base one if it's
+    /// part of it. The base layer is encoded only `with_base`, otherwise its encoder is dropped
+    /// and starts over with a keyframe next time
+    /// # Errors
+    /// Returns an error if the frame isn't of the size captured or cannot be encoded
+    fn encode(
+        &mut self,
+        captured: &[u8],
+        with_base: bool,
+    ) -> anyhow::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let (width, height) = (self.width, self.height);
+        if captured.len() < width * height * 2 {
+            anyhow::bail!(
+                "Captured {} bytes, not a frame of {width}x{height}",
+                captured.len()
+            );
+        }
+        let mut buffer = Cow::Borrowed(&captured[..width * height * 2]);
+        // The filters would count as motion, i.e. the time of the watermark.
+        // Without motion nothing may have been sent for a while, so the peer needs a keyframe
+        if self.motion.update(&buffer, width, height) && self.motion.is_gated() {
+            self.encoder.force_intra_frame();
+        }
+        if let Ok(mut filters) = self.filters.lock() {
+            for filter in filters.iter_mut() {
+                filter.apply(buffer.to_mut(), width, height);
+            }
+        }
+        // Kept for the recording of the call, as the peer gets it
+        if let Ok(mut local) = LOCAL_FRAME_BUFFER.lock() {
+            if let Some(ref mut local) = *local {
+                local.clear();
+                if (width, height) == (WIDTH, HEIGHT) {
+                    local.extend_from_slice(&buffer);
+                } else {
+                    local.extend(Self::scale_yuyv(&buffer, width, height));
+                }
+            }
+        }
+
+        let slices = Self::prepare_yuv_slices(&buffer, width, height);
+        let frame = YuvFrame {
+            y: &slices.0,
+            u: &slices.1,
+            v: &slices.2,
+            width,
+            height,
+            strides: (width, width, width),
+        };
+
+        let full = self.encoder.encode(&frame)?;
+        if !with_base {
+            self.base_encoder = None;
+        }
+        let base = if with_base && self.frame_count % 2 == 0 {
+            let encoder = match self.base_encoder {
+                Some(ref mut encoder) => encoder,
+                None => self.base_encoder.insert(open_encoder(
+                    self.encoder.encoding(),
+                    self.encoder.backend(),
+                    width,
+                    height,
+                )?),
+            };
+            Some(encoder.encode(&frame)?)
+        } else {
+            None
+        };
+        self.frame_count += 1;
+        Ok((full, base))
+    }
+}
+// H264YUVStream should be thread safe, as it gets data from the ether (/dev/video)
+unsafe impl<'a> Send for H264Stream<'a> {}
+
+impl CustomStream<'_, MmapStream<'_>> for H264Stream<'_> {
+    fn next(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let frame = self.try_next_vec().ok()?;
+        if frame.len() > buffer.len() {
+            warn!("Cannot write the encoded bitstream: buffer too small");
+            return None;
+        }
+        buffer[..frame.len()].copy_from_slice(&frame);
+        Some(frame.len())
+    }
+    fn try_next_vec(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.next_layers(false).map(|frame| frame.full)
+    }
+}
+
+/// Signals passed to the stream thread. The thread will read them the next time the stream loop will run, before any action
+/// It will cause delay, but it's easier this way,
+/// After reading the signal, it will be set back to SignalNone,
+pub mod outgoing {
+
+    use std::fmt::Debug;
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant};
+
+    use super::ssignal::*;
+    use super::{
+        join_with_timeout, CustomStream, H264Stream, LayeredFrame, DEFAULT_KEYFRAME_INTERVAL,
+        THREAD_EXIT_TIMEOUT,
+    };
+    use super::{SharedProtection, SharedUnitSink, SrtpContext, UnitSink};
+    use tracing::{error, info, info_span, warn};
+
+    use crate::av_sync::timestamp_now;
+    use crate::bitstream::FileSource;
+    use crate::camera::CameraControls;
+    use crate::codec::{encoding_of, units, CodecBackend, VideoEncoding};
+    use crate::filter::{FrameFilter, SharedFrameFilters};
+    use crate::motion::{MotionMode, MotionState};
+    use crate::nal::{PacketBuffer, DEFAULT_PACKET_DATA_SIZE, MAX_PACKET_DATA_SIZE};
+    use crate::pacer::{send_paced, Pacer};
+    use crate::qos::{self, SocketSettings, SocketTuning};
+    use crate::srtp::SRTP_OVERHEAD;
+    use crate::stats::SharedOutgoingStats;
+    use scp_client::client::{PacketFormat, VideoMode};
+    use scp_client::link_local::resolve_scope;
+    use v4l::video::capture::Parameters;
+    use v4l::video::Capture;
+    use v4l::{Device, Format};
+
+    /// Consecutive capture failures after which the camera counts as lost
+    const MAX_CAPTURE_ERRORS: u32 = 10;
+    /// Delay before the first attempt to re-open a lost camera. Doubled after every failed attempt
+    const REOPEN_BACKOFF_MIN: Duration = Duration::from_millis(500);
+    const REOPEN_BACKOFF_MAX: Duration = Duration::from_secs(8);
+    /// errno of a device that was unplugged
+    const ENODEV: i32 = 19;
+    /// The thread is parked while there's no call, the controls wake it with every signal.
+    /// It looks at the signal this often anyway
+    const IDLE_PARK: Duration = Duration::from_secs(1);
+    /// The thread waits that long between the frames, less the time it spent pacing the last one
+    const FRAME_SLEEP: Duration = Duration::from_millis(30);
+    /// Frame rate the packets are paced for while the mode of the source isn't known
+    const DEFAULT_FPS: u32 = 30;
+
+    /// Health of the outgoing stream, reported by the stream thread.
+    /// Errors are reported once per run of failures, not for every frame.
+    /// * `CaptureError` - a frame couldn't be captured or encoded
+    /// * `DeviceLost` - the camera is gone, the stream keeps trying to re-open it
+    /// * `DeviceRecovered` - the camera was re-opened after `DeviceLost`
+    /// * `SendError` - the packets cannot be sent to the peer
+    /// * `MotionStarted`, `MotionStopped` - seen by the camera, see `set_motion_mode`
+    /// * `Reconfigured` - the camera captures in this mode from now on, see `reconfigure`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum OutgoingStreamEvent {
+        CaptureError(String),
+        DeviceLost(String),
+        DeviceRecovered,
+        SendError(String),
+        MotionStarted,
+        MotionStopped,
+        Reconfigured(VideoMode),
+    }
+
+    /// Opens a custom `VideoSource`, every time the stream connects or the source was lost
+    pub type VideoSourceFactory =
+        Arc<dyn Fn() -> anyhow::Result<Box<dyn VideoSource>> + Send + Sync>;
+
+    /// Where the outgoing video comes from
+    /// * `Camera` - the first available video device, encoded on the fly
+    /// * `File` - a recorded `.h264` file replayed in a loop, see `FileSource`
+    /// * `Custom` - any other source, i.e. a screen capture of an embedding app
+    #[derive(Clone, Default)]
+    pub enum VideoBackend {
+        #[default]
+        Camera,
+        File(PathBuf),
+        Custom(VideoSourceFactory),
+    }
+    impl Debug for VideoBackend {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Camera => write!(f, "Camera"),
+                Self::File(path) => f.debug_tuple("File").field(path).finish(),
+                Self::Custom(_) => write!(f, "Custom"),
+            }
+        }
+    }
+
+    /// Temporal layer of the stream sent to a destination
+    /// * `Full` - every captured frame
+    /// * `Base` - every other frame, for peers on a slow link or a slow machine
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum StreamLayer {
+        #[default]
+        Full,
+        Base,
+    }
+
+    /// A peer the stream is sent to
+    /// * `addr` - where the packets go
+    /// * `layer` - which layer of the stream the peer gets
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Destination {
+        pub addr: SocketAddr,
+        pub layer: StreamLayer,
+    }
+
+    /// An opened `VideoBackend`, producing the encoded frames of the outgoing stream.
+    /// Used only by the stream thread, once it's opened there
+    pub trait VideoSource {
+        /// The next frame encoded for each temporal layer, see `H264Stream::next_layers`.
+        /// May block until the frame is there, the stream sends it right away
+        /// # Errors
+        /// A `std::io::Error` with `ENODEV` tells the device is gone, the source is opened again
+        fn next_layers(&mut self, with_base: bool) -> anyhow::Result<LayeredFrame>;
+        /// Make the next frame a keyframe, if the source encodes the frames itself
+        fn force_intra_frame(&mut self) {}
+    }
+
+    /// The camera and the stream captured from it
+    struct CameraSource<'a> {
+        stream: H264Stream<'a>,
+        _device: Device,
+    }
+    impl VideoSource for CameraSource<'_> {
+        fn next_layers(&mut self, with_base: bool) -> anyhow::Result<LayeredFrame> {
+            self.stream.next_layers(with_base)
+        }
+        fn force_intra_frame(&mut self) {
+            self.stream.frames.encoder.force_intra_frame();
+        }
+    }
+    /// Recorded files have a single layer, base layer destinations get all of it.
+    /// They are sent as they are, keyframes come when the file has them
+    impl VideoSource for FileSource {
+        fn next_layers(&mut self, with_base: bool) -> anyhow::Result<LayeredFrame> {
+            let full = self.try_next_vec()?;
+            let base = with_base.then(|| full.clone());
+            Ok(LayeredFrame {
+                full,
+                base,
+                captured_at: Instant::now(),
+            })
+        }
+    }
+
+    /// The filters and the motion detection apply only to the frames of a camera,
+    /// the other sources are encoded
+    fn open_source<'a>(
+        backend: &VideoBackend,
+        mode: Option<VideoMode>,
+        encoding: VideoEncoding,
+        encoder: CodecBackend,
+        filters: &SharedFrameFilters,
+        motion: &Arc<MotionState>,
+    ) -> anyhow::Result<Box<dyn VideoSource + 'a>> {
+        match backend {
+            VideoBackend::Camera => {
+                let (mut stream, device) = init_inner_stream(mode, encoding, encoder)?;
+                info!(
+                    "Encoding {} with {}",
+                    stream.encoding(),
+                    stream.encoder_backend()
+                );
+                stream.set_filters(Arc::clone(filters));
+                stream.set_motion(Arc::clone(motion));
+                Ok(Box::new(CameraSource {
+                    stream,
+                    _device: device,
+                }))
+            }
+            VideoBackend::File(path) => Ok(Box::new(FileSource::open(path)?)),
+            VideoBackend::Custom(factory) => factory(),
+        }
+    }
+
+    /// Context of the thread running the outgoing stream.
+    struct OutgoingH264StreamContext<'a> {
+        backend: VideoBackend,
+        /// Mode the camera is opened in, None for its default
+        mode: Option<VideoMode>,
+        /// Mode to switch the camera to, see `H264StreamControls::reconfigure`
+        reconfigure: Arc<Mutex<Option<VideoMode>>>,
+        /// Backend the camera frames are encoded with, see `H264StreamControls::set_encoder`
+        encoder: Arc<Mutex<CodecBackend>>,
+        /// Encoding of the camera frames, see `H264StreamControls::set_video_encoding`
+        encoding: Arc<Mutex<VideoEncoding>>,
+        filters: SharedFrameFilters,
+        motion: Arc<MotionState>,
+        /// Motion last reported, None while the detection is off
+        moving: Option<bool>,
+        source: Option<Box<dyn VideoSource + 'a>>,
+        /// Privacy mode, see `H264StreamControls::set_privacy`
+        privacy: Arc<AtomicBool>,
+        /// Whether the source was closed for the privacy mode, it's opened again as it ends
+        shutter_closed: bool,
+        socket: UdpSocket,
+        signal: Arc<AtomicU8>,
+        destinations: Arc<Mutex<Vec<Destination>>>,
+        session_id: Arc<AtomicU32>,
+        /// Version of the `PacketFormat` the packets are sent in
+        packet_format: Arc<AtomicU8>,
+        /// Frame rate limit, 0 for none
+        max_fps: Arc<AtomicU32>,
+        /// kbit/s the packets are paced for, 0 sends them at once
+        pacing_rate: Arc<AtomicU32>,
+        pacer: Pacer,
+        /// Time spent pacing the last frame, the thread sleeps that much less
+        paced_for: Duration,
+        /// Frames between the keyframes, 0 leaves them to the encoder
+        keyframe_interval: Arc<AtomicU32>,
+        /// Frames encoded since the last forced keyframe
+        frames_since_keyframe: u32,
+        /// Data of a packet, see `H264StreamControls::set_packet_size`
+        packet_size: Arc<AtomicUsize>,
+        events: Sender<OutgoingStreamEvent>,
+        streaming: bool,
+        addr_bound: bool,
+        /// Failed captures in a row
+        capture_errors: u32,
+        /// Whether the last packet failed to send
+        send_failing: bool,
+        /// When to try re-opening the lost camera next
+        reopen_at: Option<Instant>,
+        reopen_backoff: Duration,
+        last_capture: Option<Instant>,
+        /// Packets of the current frame for each layer, reused for every frame
+        full_packets: PacketBuffer,
+        base_packets: PacketBuffer,
+        stats: SharedOutgoingStats,
+        units: SharedUnitSink,
+        protection: SharedProtection,
+    }
+    impl OutgoingH264StreamContext<'_> {
+        #[allow(clippy::too_many_arguments)]
+        fn new(
+            backend: VideoBackend,
+            mode: Option<VideoMode>,
+            reconfigure: Arc<Mutex<Option<VideoMode>>>,
+            encoder: Arc<Mutex<CodecBackend>>,
+            encoding: Arc<Mutex<VideoEncoding>>,
+            filters: SharedFrameFilters,
+            motion: Arc<MotionState>,
+            privacy: Arc<AtomicBool>,
+            socket: UdpSocket,
+            signal: Arc<AtomicU8>,
+            destinations: Arc<Mutex<Vec<Destination>>>,
+            session_id: Arc<AtomicU32>,
+            packet_format: Arc<AtomicU8>,
+            max_fps: Arc<AtomicU32>,
+            pacing_rate: Arc<AtomicU32>,
+            keyframe_interval: Arc<AtomicU32>,
+            packet_size: Arc<AtomicUsize>,
+            events: Sender<OutgoingStreamEvent>,
+            stats: SharedOutgoingStats,
+            units: SharedUnitSink,
+            protection: SharedProtection,
+        ) -> Self {
+            Self {
+                backend,
+                mode,
+                reconfigure,
+                encoder,
+                encoding,
+                filters,
+                motion,
+                moving: None,
+                source: None,
+                privacy,
+                shutter_closed: false,
+                socket,
+                signal,
+                destinations,
+                session_id,
+                packet_format,
+                max_fps,
+                pacing_rate,
+                pacer: Pacer::default(),
+                paced_for: Duration::ZERO,
+                keyframe_interval,
+                frames_since_keyframe: 0,
+                packet_size,
+                events,
+                addr_bound: false,
+                streaming: false,
+                capture_errors: 0,
+                send_failing: false,
+                reopen_at: None,
+                reopen_backoff: REOPEN_BACKOFF_MIN,
+                last_capture: None,
+                full_packets: PacketBuffer::default(),
+                base_packets: PacketBuffer::default(),
+                stats,
+                units,
+                protection,
+            }
+        }
+        fn process_signals(&mut self) {
+            // Kept apart from the signal, so another signal doesn't overwrite it
+            let mode = self
+                .reconfigure
+                .lock()
+                .ok()
+                .and_then(|mut mode| mode.take());
+            if let Some(mode) = mode {
+                self.switch_mode(mode);
+            }
+            self.apply_privacy();
+            let signal_value = self.signal.load(std::sync::atomic::Ordering::SeqCst);
+            let mut op_performed = false;
+
+            match signal_value {
+                SSIGNAL_PAUSE => {
+                    self.streaming = false;
+                    op_performed = true;
+                }
+                SSIGNAL_DISCONNECT | SSIGNAL_TERMINATE => {
+                    self.source.take();
+                    self.addr_bound = false;
+                    self.streaming = false;
+                    self.capture_errors = 0;
+                    self.send_failing = false;
+                    self.reopen_at = None;
+                    self.reopen_backoff = REOPEN_BACKOFF_MIN;
+                    op_performed = signal_value == SSIGNAL_DISCONNECT;
+                }
+                SSIGNAL_CONNECT => {
+                    let Ok(destinations) = self.destinations.lock().map(|d| d.clone()) else {
+                        return;
+                    };
+                    info!("Streaming to {destinations:?}");
+
+                    self.streaming = true;
+                    self.addr_bound = true;
+                    if self.source.is_none() && !self.shutter_closed {
+                        if let Err(e) = self.open_source() {
+                            error!("Cannot open the video source: {e}");
+                            self.report(OutgoingStreamEvent::DeviceLost(e.to_string()));
+                        }
+                    }
+                    // New and reconnected peers cannot decode anything before a keyframe
+                    self.force_keyframe();
+
+                    op_performed = true;
+                }
+                SSIGNAL_RESUME => {
+                    // The peer dropped whatever it had while the stream was paused
+                    self.streaming = true;
+                    self.force_keyframe();
+                    op_performed = true;
+                }
+                SSIGNAL_KEYFRAME => {
+                    self.force_keyframe();
+                    op_performed = true;
+                }
+                _ => {}
+            }
+
+            // Reset the signal if an operation was performed
+            if op_performed {
+                self.signal
+                    .store(SSIGNAL_NONE, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        /// Close the source as the privacy mode starts, so the device is released and its light
+        /// goes off. Opened again as it ends, if a peer is still there
+        fn apply_privacy(&mut self) {
+            let privacy = self.privacy.load(Ordering::Relaxed);
+            if privacy == self.shutter_closed {
+                return;
+            }
+            self.shutter_closed = privacy;
+            if privacy {
+                self.source.take();
+                self.reopen_at = None;
+                self.reopen_backoff = REOPEN_BACKOFF_MIN;
+                info!("Privacy mode on, the video source is closed");
+                return;
+            }
+            info!("Privacy mode off");
+            if !self.addr_bound || self.source.is_some() {
+                return;
+            }
+            match self.open_source() {
+                Ok(()) => self.force_keyframe(),
+                Err(e) => {
+                    error!("Cannot open the video source: {e}");
+                    self.report(OutgoingStreamEvent::DeviceLost(e.to_string()));
+                }
+            }
+        }
+
+        /// Make the next frame a keyframe and count the interval to the next one from there
+        fn force_keyframe(&mut self) {
+            if let Some(ref mut source) = self.source {
+                source.force_intra_frame();
+            }
+            self.frames_since_keyframe = 0;
+        }
+
+        fn report(&self, event: OutgoingStreamEvent) {
+            // Nobody listens only when the controls are gone, the thread is about to exit then
+            let _ = self.events.send(event);
+        }
+
+        /// Open the video source. On failure the next attempt is scheduled with a longer backoff
+        fn open_source(&mut self) -> anyhow::Result<()> {
+            let encoder = self
+                .encoder
+                .lock()
+                .map(|encoder| *encoder)
+                .unwrap_or_default();
+            let encoding = self
+                .encoding
+                .lock()
+                .map(|encoding| *encoding)
+                .unwrap_or(VideoEncoding::H264);
+            match open_source(
+                &self.backend,
+                self.mode,
+                encoding,
+                encoder,
+                &self.filters,
+                &self.motion,
+            ) {
+                Ok(source) => {
+                    self.source = Some(source);
+                    self.capture_errors = 0;
+                    self.reopen_at = None;
+                    self.reopen_backoff = REOPEN_BACKOFF_MIN;
+                    Ok(())
+                }
+                Err(e) => {
+                    self.reopen_at = Some(Instant::now() + self.reopen_backoff);
+                    self.reopen_backoff = (self.reopen_backoff * 2).min(REOPEN_BACKOFF_MAX);
+                    Err(e)
+                }
+            }
+        }
+
+        /// Open the camera again in `mode`. The socket and the destinations stay as they are,
+        /// the new encoder starts with a keyframe carrying the SPS and PPS of the new mode.
+        /// A camera that isn't open yet is opened in `mode` once the stream connects
+        fn switch_mode(&mut self, mode: VideoMode) {
+            self.mode = Some(mode);
+            if self.source.is_none() {
+                self.report(OutgoingStreamEvent::Reconfigured(mode));
+                return;
+            }
+            // The device cannot be opened twice
+            self.source.take();
+            match self.open_source() {
+                Ok(()) => {
+                    info!("Capturing {mode}");
+                    self.force_keyframe();
+                    self.report(OutgoingStreamEvent::Reconfigured(mode));
+                }
+                Err(e) => {
+                    error!("Cannot capture {mode}: {e}");
+                    self.report(OutgoingStreamEvent::DeviceLost(e.to_string()));
+                }
+            }
+        }
+
+        fn try_reopen_device(&mut self) {
+            if self.reopen_at.is_some_and(|at| Instant::now() < at) {
+                return;
+            }
+            match self.open_source() {
+                Ok(()) => {
+                    info!("Camera re-opened");
+                    self.force_keyframe();
+                    self.report(OutgoingStreamEvent::DeviceRecovered);
+                }
+                Err(e) => warn!("Cannot re-open the camera: {e}"),
+            }
+        }
+
+        /// Whether capturing now would exceed the frame rate limit
+        fn is_throttled(&self) -> bool {
+            let max_fps = self.max_fps.load(Ordering::Relaxed);
+            if max_fps == 0 {
+                return false;
+            }
+            let interval = Duration::from_secs(1) / max_fps;
+            self.last_capture
+                .is_some_and(|last| last.elapsed() < interval)
+        }
+        /// Time between the frames of the source, the packets of a frame are sent within it
+        fn frame_interval(&self) -> Duration {
+            let fps = self.mode.map_or(DEFAULT_FPS, |mode| u32::from(mode.fps));
+            let fps = match self.max_fps.load(Ordering::Relaxed) {
+                0 => fps,
+                max_fps => fps.min(max_fps),
+            };
+            Duration::from_secs(1) / fps.max(1)
+        }
+
+        /// Capture, encode and send a single frame.
+        /// Re-opens the camera instead if it was lost.
+        fn capture_and_send(&mut self) {
+            if self.is_throttled() {
+                // The camera drops the frames nobody takes
+                return;
+            }
+            if self.source.is_none() {
+                self.try_reopen_device();
+                return;
+            }
+            let interval = self.keyframe_interval.load(Ordering::Relaxed);
+            if interval > 0 && self.frames_since_keyframe >= interval {
+                self.force_keyframe();
+            }
+            let Some(ref mut source) = self.source else {
+                return;
+            };
+            let Ok(destinations) = self.destinations.lock().map(|d| d.clone()) else {
+                return;
+            };
+            self.last_capture = Some(Instant::now());
+            let capture_ts = timestamp_now();
+            let with_base = destinations.iter().any(|d| d.layer == StreamLayer::Base);
+            match source.next_layers(with_base) {
+                Ok(frame) => {
+                    self.capture_errors = 0;
+                    self.frames_since_keyframe += 1;
+                    self.stats.frames_encoded.fetch_add(1, Ordering::Relaxed);
+                    let encoded_at = Instant::now();
+                    self.stats
+                        .capture_to_encode
+                        .record(encoded_at.duration_since(frame.captured_at));
+                    if !self.check_motion() {
+                        return;
+                    }
+                    // The sinks republish H.264, the VP8 frames are left out
+                    if let Some(ref mut sink) = *self.units.lock().unwrap() {
+                        if encoding_of(&frame.full) == VideoEncoding::H264 {
+                            for unit in units(&frame.full) {
+                                sink.unit(unit, capture_ts);
+                            }
+                        }
+                    }
+                    let session_id = self.session_id.load(Ordering::Relaxed);
+                    let window = self.frame_interval();
+                    let format =
+                        PacketFormat::from_version(self.packet_format.load(Ordering::Relaxed))
+                            .unwrap_or_default();
+                    let mut protection = self.protection.lock().unwrap();
+                    let mut packet_size = self.packet_size.load(Ordering::Relaxed);
+                    if protection.is_some() {
+                        // The protected packets stay within the size the peer takes
+                        packet_size = packet_size.saturating_sub(SRTP_OVERHEAD);
+                    }
+                    let packetize_frame = |buffer: &mut PacketBuffer, frame: &[u8]| {
+                        buffer.clear();
+                        buffer.set_format(format);
+                        buffer.set_packet_size(packet_size);
+                        for unit in units(frame) {
+                            buffer.push_unit(unit, capture_ts, session_id);
+                        }
+                    };
+                    packetize_frame(&mut self.full_packets, &frame.full);
+                    let mut full: Vec<&[u8]> = self.full_packets.packets().collect();
+                    let mut base: Option<Vec<&[u8]>> = frame.base.as_deref().map(|base| {
+                        packetize_frame(&mut self.base_packets, base);
+                        self.base_packets.packets().collect()
+                    });
+                    // Protected once, whoever they are sent to
+                    let protected = protection.as_mut().map(|srtp| {
+                        let mut protect = |packets: &[&[u8]]| -> Vec<Vec<u8>> {
+                            packets
+                                .iter()
+                                .map(|packet| srtp.protect(packet, capture_ts))
+                                .collect()
+                        };
+                        (protect(&full), base.as_deref().map(protect))
+                    });
+                    drop(protection);
+                    if let Some((ref protected_full, ref protected_base)) = protected {
+                        full = protected_full.iter().map(Vec::as_slice).collect();
+                        base = protected_base
+                            .as_ref()
+                            .map(|base| base.iter().map(Vec::as_slice).collect());
+                    }
+                    // Base layer destinations skip the frames that aren't part of it
+                    let sends: Vec<(SocketAddr, &Vec<&[u8]>)> = destinations
+                        .iter()
+                        .filter_map(|destination| {
+                            let packets = match destination.layer {
+                                StreamLayer::Full => Some(&full),
+                                StreamLayer::Base => base.as_ref(),
+                            };
+                            packets.map(|packets| (destination.addr, packets))
+                        })
+                        .collect();
+                    // The destinations share the uplink, so they're paced as one
+                    let sizes: Vec<usize> = sends
+                        .iter()
+                        .flat_map(|(_, packets)| packets.iter().map(|packet| packet.len()))
+                        .collect();
+                    self.pacer
+                        .set_rate(Some(self.pacing_rate.load(Ordering::Relaxed)));
+                    let pacing_start = Instant::now();
+                    let times = self.pacer.schedule(&sizes, window, pacing_start);
+                    let mut times = times.as_slice();
+                    let sent: Vec<io::Result<()>> = sends
+                        .iter()
+                        .map(|(addr, packets)| {
+                            self.stats.record_sent(packets);
+                            let (due, rest) = times.split_at(packets.len());
+                            times = rest;
+                            send_paced(&self.socket, packets, due, *addr)
+                        })
+                        .collect();
+                    self.paced_for = pacing_start.elapsed();
+                    self.stats.encode_to_send.record(encoded_at.elapsed());
+                    for sent in sent {
+                        self.on_sent(sent);
+                    }
+                }
+                Err(e) => {
+                    self.capture_errors += 1;
+                    if is_device_gone(&e) || self.capture_errors >= MAX_CAPTURE_ERRORS {
+                        error!("Camera lost: {e}");
+                        self.source.take();
+                        self.capture_errors = 0;
+                        self.reopen_at = Some(Instant::now() + self.reopen_backoff);
+                        self.report(OutgoingStreamEvent::DeviceLost(e.to_string()));
+                    } else if self.capture_errors == 1 {
+                        warn!("Cannot capture a frame: {e}");
+                        self.report(OutgoingStreamEvent::CaptureError(e.to_string()));
+                    }
+                }
+            }
+        }
+
+        /// Report the motion once it starts or stops.
+        /// Returns whether the frame is sent, a gated stream sends only while there's motion
+        fn check_motion(&mut self) -> bool {
+            let moving = self.motion.moving();
+            if moving.unwrap_or(false) != self.moving.unwrap_or(false) {
+                if moving == Some(true) {
+                    info!("Motion started");
+                    self.report(OutgoingStreamEvent::MotionStarted);
+                } else {
+                    info!("Motion stopped");
+                    self.report(OutgoingStreamEvent::MotionStopped);
+                }
+            }
+            self.moving = moving;
+            !self.motion.is_gated() || moving == Some(true)
+        }
+
+        /// Report the first of the failed sends in a row
+        fn on_sent(&mut self, sent: io::Result<()>) {
+            match sent {
+                Ok(()) => self.send_failing = false,
+                Err(e) => {
+                    if !self.send_failing {
+                        warn!("Cannot send the stream: {e}");
+                        self.report(OutgoingStreamEvent::SendError(e.to_string()));
+                    }
+                    self.send_failing = true;
+                }
+            }
+        }
+    }
+
+    /// `addr` on the interface it's reached through, if it's an IPv6 link-local one given
+    /// without, see `scp_client::link_local`. As it is if the interface cannot be told
+    fn scoped(addr: SocketAddr) -> SocketAddr {
+        resolve_scope(addr).unwrap_or_else(|e| {
+            warn!("{e}");
+            addr
+        })
+    }
+
+    /// Whether the capture failed because the camera was unplugged
+    fn is_device_gone(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.raw_os_error() == Some(ENODEV))
+    }
+
+    pub trait StreamControls {
+        /// Connect to an address to send data to from given port
+        /// After connecting the stream will be in paused state.
+        fn connect(&mut self, addr: SocketAddr);
+        /// Disconnect and terminate the stream thread.
+        fn disconnect(&mut self);
+        /// Pause the stream if connected, with ability to unpause later
+        fn pause(&mut self);
+        /// Unpause the stream after pausing, or start if just connected
+        fn unpause(&mut self);
+    }
+
+    pub struct H264StreamControls {
+        /// None after the thread was shut down
+        t_handle: Option<JoinHandle<()>>,
+        /// Atomic for frequent reads
+        signal: Arc<AtomicU8>,
+        /// Peers the stream is sent to, read by the thread on every frame
+        destinations: Arc<Mutex<Vec<Destination>>>,
+        session_id: Arc<AtomicU32>,
+        packet_format: Arc<AtomicU8>,
+        max_fps: Arc<AtomicU32>,
+        pacing_rate: Arc<AtomicU32>,
+        keyframe_interval: Arc<AtomicU32>,
+        packet_size: Arc<AtomicUsize>,
+        /// Mutex only to make the controls `Sync`
+        events: Mutex<Receiver<OutgoingStreamEvent>>,
+        stats: SharedOutgoingStats,
+        /// Whether the video comes from the camera, see `camera_controls`
+        camera: bool,
+        /// Set by the thread while it holds the camera open, see `camera_in_use`
+        camera_in_use: Arc<AtomicBool>,
+        privacy: Arc<AtomicBool>,
+        /// Mode the camera captures in, None if it's not known
+        mode: Mutex<Option<VideoMode>>,
+        /// Mode the thread switches the camera to next
+        reconfigure: Arc<Mutex<Option<VideoMode>>>,
+        encoder: Arc<Mutex<CodecBackend>>,
+        encoding: Arc<Mutex<VideoEncoding>>,
+        filters: SharedFrameFilters,
+        motion: Arc<MotionState>,
+        units: SharedUnitSink,
+        protection: SharedProtection,
+        /// Clone of the stream socket, to tune it from outside the thread
+        socket: UdpSocket,
+        pub address: SocketAddr,
+    }
+    impl H264StreamControls {
+        #[allow(clippy::too_many_arguments)]
+        fn new(
+            t: JoinHandle<()>,
+            camera: bool,
+            camera_in_use: Arc<AtomicBool>,
+            privacy: Arc<AtomicBool>,
+            mode: Option<VideoMode>,
+            reconfigure: Arc<Mutex<Option<VideoMode>>>,
+            encoder: Arc<Mutex<CodecBackend>>,
+            encoding: Arc<Mutex<VideoEncoding>>,
+            filters: SharedFrameFilters,
+            motion: Arc<MotionState>,
+            signal: Arc<AtomicU8>,
+            destinations: Arc<Mutex<Vec<Destination>>>,
+            session_id: Arc<AtomicU32>,
+            packet_format: Arc<AtomicU8>,
+            max_fps: Arc<AtomicU32>,
+            pacing_rate: Arc<AtomicU32>,
+            keyframe_interval: Arc<AtomicU32>,
+            packet_size: Arc<AtomicUsize>,
+            events: Receiver<OutgoingStreamEvent>,
+            stats: SharedOutgoingStats,
+            units: SharedUnitSink,
+            protection: SharedProtection,
+            socket: UdpSocket,
+            address: SocketAddr,
+        ) -> Self {
+            Self {
+                t_handle: Some(t),
+                signal,
+                destinations,
+                session_id,
+                packet_format,
+                max_fps,
+                pacing_rate,
+                keyframe_interval,
+                packet_size,
+                events: Mutex::new(events),
+                stats,
+                camera,
+                camera_in_use,
+                privacy,
+                mode: Mutex::new(mode),
+                reconfigure,
+                encoder,
+                encoding,
+                filters,
+                motion,
+                units,
+                protection,
+                socket,
+                address,
+            }
+        }
+        /// Filter the camera frames with these from the next frame on, in order.
+        /// An empty list sends the frames as they are captured
+        pub fn set_filters(&self, filters: Vec<Box<dyn FrameFilter>>) {
+            if let Ok(mut current) = self.filters.lock() {
+                *current = filters;
+            }
+        }
+        /// Look for motion in the camera frames, see `MotionMode`.
+        /// The frames are captured only during calls, so is the motion
+        pub fn set_motion_mode(&self, mode: MotionMode) {
+            self.motion.set_mode(mode);
+        }
+        /// Hand the NAL units sent to the peers to `sink` too, None to stop.
+        /// Replaces the previous sink
+        pub fn set_unit_sink(&self, sink: Option<Box<dyn UnitSink>>) {
+            if let Ok(mut current) = self.units.lock() {
+                *current = sink;
+            }
+        }
+        /// Send the packets protected as SRTP from the next frame on, None to send them as they are.
+        /// The packets carry less data then, so they still fit the packet size, see `set_packet_size`
+        pub fn set_protection(&self, protection: Option<SrtpContext>) {
+            if let Ok(mut current) = self.protection.lock() {
+                *current = protection;
+            }
+        }
+        /// Whether the camera is open, so it captures. It's opened as a call starts and closed
+        /// as it ends, the app may show it's in use. Always false for the other video sources
+        pub fn camera_in_use(&self) -> bool {
+            self.camera_in_use.load(Ordering::Relaxed)
+        }
+        /// Privacy mode closes the video source, the camera included, instead of only pausing
+        /// the stream. Nothing is sent while it's on, kept across the calls.
+        /// The source is opened again as it ends, the peer gets a keyframe then
+        pub fn set_privacy(&self, on: bool) {
+            self.privacy.store(on, Ordering::Relaxed);
+            self.wake();
+        }
+        pub fn privacy(&self) -> bool {
+            self.privacy.load(Ordering::Relaxed)
+        }
+        /// Mode the camera was set up to capture in, None if the video doesn't come from it
+        /// or it couldn't be probed. The driver may still pick a close one
+        pub fn video_mode(&self) -> Option<VideoMode> {
+            self.mode.lock().ok().and_then(|mode| *mode)
+        }
+        /// Capture in `mode` from now on, without stopping the stream. The thread opens the camera
+        /// again and reports `OutgoingStreamEvent::Reconfigured` once it captures in the new mode.
+        /// The frames are encoded in the size of the new mode from its first keyframe on,
+        /// the peer's decoder picks the size up from it
+        /// # Errors
+        /// Returns an error if the video doesn't come from a camera
+        pub fn reconfigure(&self, mode: VideoMode) -> anyhow::Result<()> {
+            if !self.camera {
+                anyhow::bail!("The video doesn't come from a camera");
+            }
+            if let Ok(mut current) = self.mode.lock() {
+                *current = Some(mode);
+            }
+            if let Ok(mut reconfigure) = self.reconfigure.lock() {
+                *reconfigure = Some(mode);
+            }
+            self.wake();
+            Ok(())
+        }
+        /// Encode the camera frames with `backend` from the next time the camera is opened,
+        /// i.e. as the next call starts. OpenH264 is used if it cannot, see `crate::codec`.
+        /// The other sources come encoded already
+        pub fn set_encoder(&self, backend: CodecBackend) {
+            if let Ok(mut encoder) = self.encoder.lock() {
+                *encoder = backend;
+            }
+        }
+        /// Encode the camera frames in `encoding` from the next time the camera is opened,
+        /// i.e. as the call connects, the one negotiated with the peer.
+        /// H.264 is sent if it cannot, the peer tells them apart
+        pub fn set_video_encoding(&self, encoding: VideoEncoding) {
+            if let Ok(mut current) = self.encoding.lock() {
+                *current = encoding;
+            }
+        }
+        /// Picture controls of the camera the stream captures from.
+        /// They can be set whether the stream runs or not
+        /// # Errors
+        /// Returns an error if the video doesn't come from a camera or it cannot be opened
+        pub fn camera_controls(&self) -> anyhow::Result<CameraControls> {
+            if !self.camera {
+                anyhow::bail!("The video doesn't come from a camera");
+            }
+            CameraControls::open()
+        }
+        /// Counters of the stream, kept up to date by the stream thread
+        pub fn stats(&self) -> SharedOutgoingStats {
+            Arc::clone(&self.stats)
+        }
+        /// Session ID stamped into the packets sent from now on, see `SessionConfig::session_id`
+        pub fn set_session_id(&self, session_id: u32) {
+            self.session_id.store(session_id, Ordering::Relaxed);
+        }
+        /// Format of the packets sent from the next frame on, see `SessionConfig::codecs`
+        pub fn set_packet_format(&self, format: PacketFormat) {
+            self.packet_format
+                .store(format.version(), Ordering::Relaxed);
+        }
+        /// Limit the frame rate of the stream, e.g. to save CPU while nobody looks at the app.
+        /// None captures as fast as the video source allows
+        pub fn set_max_fps(&self, max_fps: Option<u32>) {
+            self.max_fps.store(max_fps.unwrap_or(0), Ordering::Relaxed);
+        }
+        /// Spread the packets of the large frames for a bitrate budget of `kbps`, so keyframes
+        /// don't overflow the router queues on the way, see `crate::pacer`.
+        /// None sends every frame at once
+        pub fn set_pacing_rate(&self, kbps: Option<u32>) {
+            self.pacing_rate.store(kbps.unwrap_or(0), Ordering::Relaxed);
+        }
+        /// Force a keyframe every `interval` frames, so a peer losing packets recovers without
+        /// asking for one. None leaves the keyframes to the encoder, which sends them rarely
+        pub fn set_keyframe_interval(&self, interval: Option<u32>) {
+            self.keyframe_interval
+                .store(interval.unwrap_or(0), Ordering::Relaxed);
+        }
+        /// Set the socket the packets are sent from up with `tuning`, see `crate::qos`
+        /// # Errors
+        /// Returns an error if the settings of the socket cannot be read back
+        pub fn tune_socket(&self, tuning: &SocketTuning) -> io::Result<SocketSettings> {
+            qos::tune_socket(&self.socket, tuning)
+        }
+        /// Split the frames into packets with this much data from the next frame on,
+        /// clamped to `MAX_PACKET_DATA_SIZE`. Larger packets than the path allows are lost,
+        /// see `crate::mtu` for finding out the size
+        pub fn set_packet_size(&self, size: usize) {
+            self.packet_size
+                .store(size.min(MAX_PACKET_DATA_SIZE), Ordering::Relaxed);
+        }
+        pub fn packet_size(&self) -> usize {
+            self.packet_size.load(Ordering::Relaxed)
+        }
+        /// Send the stream to one more peer, or change the layer of a peer already receiving it.
+        /// The stream starts over with a keyframe, so the new peer can decode it right away
+        pub fn add_destination(&self, destination: Destination) {
+            let destination = Destination {
+                addr: scoped(destination.addr),
+                ..destination
+            };
+            if let Ok(mut destinations) = self.destinations.lock() {
+                match destinations.iter_mut().find(|d| d.addr == destination.addr) {
+                    Some(d) => d.layer = destination.layer,
+                    None => destinations.push(destination),
+                }
+            }
+            self.send_signal(SSIGNAL_CONNECT);
+        }
+        /// Stop sending the stream to a peer. The stream disconnects once nobody is left
+        pub fn remove_destination(&self, addr: SocketAddr) {
+            let Ok(mut destinations) = self.destinations.lock() else {
+                return;
+            };
+            let addr = scoped(addr);
+            destinations.retain(|d| d.addr != addr);
+            if destinations.is_empty() {
+                self.send_signal(SSIGNAL_DISCONNECT);
+            }
+        }
+        /// Switch the layer a peer receives, e.g. when it reports falling behind
+        pub fn set_layer(&self, addr: SocketAddr, layer: StreamLayer) {
+            self.add_destination(Destination { addr, layer });
+        }
+        /// Peers the stream is sent to
+        pub fn destinations(&self) -> Vec<Destination> {
+            match self.destinations.lock() {
+                Ok(destinations) => destinations.clone(),
+                Err(_) => Vec::new(),
+            }
+        }
+        /// Send a keyframe next, i.e. when a peer cannot decode the stream after losing packets
+        pub fn request_keyframe(&self) {
+            self.send_signal(SSIGNAL_KEYFRAME);
+        }
+        /// Health events reported by the stream thread since the last call
+        pub fn poll_events(&self) -> Vec<OutgoingStreamEvent> {
+            match self.events.lock() {
+                Ok(events) => events.try_iter().collect(),
+                Err(_) => Vec::new(),
+            }
+        }
+        /// Terminate the stream thread and wait for it to exit.
+        /// # Errors
+        /// Returns an error if the thread panicked or didn't exit within `THREAD_EXIT_TIMEOUT`
+        pub fn shutdown(mut self) -> anyhow::Result<()> {
+            self.stop()
+        }
+        fn stop(&mut self) -> anyhow::Result<()> {
+            let Some(t_handle) = self.t_handle.take() else {
+                return Ok(());
+            };
+            // The thread never blocks for long, it will see the signal on its next loop
+            self.signal.store(SSIGNAL_TERMINATE, Ordering::SeqCst);
+            t_handle.thread().unpark();
+            join_with_timeout(t_handle, THREAD_EXIT_TIMEOUT)
+        }
+        /// Hand the thread `signal`, waking it if it's parked
+        fn send_signal(&self, signal: u8) {
+            self.signal.store(signal, Ordering::SeqCst);
+            self.wake();
+        }
+        fn wake(&self) {
+            if let Some(ref t_handle) = self.t_handle {
+                t_handle.thread().unpark();
+            }
+        }
+    }
+    impl StreamControls for H264StreamControls {
+        fn connect(&mut self, addr: SocketAddr) {
+            if let Ok(mut destinations) = self.destinations.lock() {
+                *destinations = vec![Destination {
+                    addr: scoped(addr),
+                    layer: StreamLayer::Full,
+                }];
+            }
+            self.send_signal(SSIGNAL_CONNECT);
+        }
+
+        fn disconnect(&mut self) {
+            self.send_signal(SSIGNAL_DISCONNECT);
+        }
+
+        fn pause(&mut self) {
+            self.send_signal(SSIGNAL_PAUSE);
+        }
+
+        fn unpause(&mut self) {
+            self.send_signal(SSIGNAL_RESUME);
+        }
+    }
+    impl Drop for H264StreamControls {
+        fn drop(&mut self) {
+            if let Err(e) = self.stop() {
+                error!("Outgoing stream thread failed to exit: {e}");
+            }
+        }
+    }
+    /// Inits a new stream, including opening the video device.
+    /// The camera captures in `mode` if given, it's `WIDTH` x `HEIGHT` at its own pace otherwise
+
+    fn init_inner_stream<'a>(
+        mode: Option<VideoMode>,
+        encoding: VideoEncoding,
+        encoder: CodecBackend,
+    ) -> anyhow::Result<(H264Stream<'a>, Device)> {
+        let dev = open_camera()?;
+        let (width, height) = mode.map_or((super::WIDTH as u32, super::HEIGHT as u32), |mode| {
+            (mode.width.into(), mode.height.into())
+        });
+        dev.set_format(&Format::new(width, height, super::FOURCC))?;
+        if let Some(mode) = mode {
+            if let Err(e) = dev.set_params(&Parameters::with_fps(mode.fps.into())) {
+                warn!("Cannot set the frame rate to {}: {e}", mode.fps);
+            }
+        }
+
+        let stream = H264Stream::with_encoder(&dev, encoding, encoder)?;
+        Ok((stream, dev))
+    }
+    /// Opens the first available video device, the one `VideoBackend::Camera` captures from
+    pub(crate) fn open_camera() -> io::Result<Device> {
+        Device::new(0).or_else(|_| Device::new(1))
+    }
+    /// Init the video stream. Returns controls to the stream, or Error
+    /// The socket will be created at given address. Use port 0 to let the OS pick one,
+    /// the actual address is available in `H264StreamControls::address`
+    /// The video comes from `backend`, opened once the stream connects.
+    /// A camera captures in `mode`, see `crate::camera::pick_mode`
+    pub fn init_h264_video_stream(
+        addr: SocketAddr,
+        backend: VideoBackend,
+        mode: Option<VideoMode>,
+    ) -> anyhow::Result<H264StreamControls> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let addr = socket.local_addr()?;
+        let socket_clone = socket.try_clone()?;
+
+        let signal = Arc::new(AtomicU8::new(SSIGNAL_NONE));
+        let camera = matches!(backend, VideoBackend::Camera);
+        let camera_in_use = Arc::new(AtomicBool::new(false));
+        let camera_in_use_clone = Arc::clone(&camera_in_use);
+        let privacy = Arc::new(AtomicBool::new(false));
+        let privacy_clone = Arc::clone(&privacy);
+        let filters = SharedFrameFilters::default();
+        let filters_clone = Arc::clone(&filters);
+        let motion = Arc::new(MotionState::default());
+        let motion_clone = Arc::clone(&motion);
+        let reconfigure = Arc::new(Mutex::new(None));
+        let reconfigure_clone = Arc::clone(&reconfigure);
+        let encoder = Arc::new(Mutex::new(CodecBackend::default()));
+        let encoder_clone = Arc::clone(&encoder);
+        let encoding = Arc::new(Mutex::new(VideoEncoding::H264));
+        let encoding_clone = Arc::clone(&encoding);
+
+        let destinations = Arc::new(Mutex::new(Vec::new()));
+
+        // Clone Arc to be used in the thread
+        let signal_clone = Arc::clone(&signal);
+        let destinations_clone = Arc::clone(&destinations);
+        let session_id = Arc::new(AtomicU32::new(0));
+        let session_id_clone = Arc::clone(&session_id);
+        let packet_format = Arc::new(AtomicU8::new(PacketFormat::Legacy.version()));
+        let packet_format_clone = Arc::clone(&packet_format);
+        let max_fps = Arc::new(AtomicU32::new(0));
+        let max_fps_clone = Arc::clone(&max_fps);
+        let pacing_rate = Arc::new(AtomicU32::new(0));
+        let pacing_rate_clone = Arc::clone(&pacing_rate);
+        let keyframe_interval = Arc::new(AtomicU32::new(DEFAULT_KEYFRAME_INTERVAL));
+        let keyframe_interval_clone = Arc::clone(&keyframe_interval);
+        let packet_size = Arc::new(AtomicUsize::new(DEFAULT_PACKET_DATA_SIZE));
+        let packet_size_clone = Arc::clone(&packet_size);
+        let (events_tx, events_rx) = mpsc::channel();
+        let stats = SharedOutgoingStats::default();
+        let stats_clone = Arc::clone(&stats);
+        let units: SharedUnitSink = Arc::new(Mutex::new(None));
+        let units_clone = Arc::clone(&units);
+        let protection: SharedProtection = Arc::new(Mutex::new(None));
+        let protection_clone = Arc::clone(&protection);
+
+        // Spawn a thread to control the stream
+        let t = std::thread::Builder::new()
+            .name("outgoing_stream".into())
+            .spawn(move || {
+                let _span = info_span!("outgoing_stream", addr = %addr).entered();
+                let mut stream_context = OutgoingH264StreamContext::new(
+                    backend,
+                    mode,
+                    reconfigure_clone,
+                    encoder_clone,
+                    encoding_clone,
+                    filters_clone,
+                    motion_clone,
+                    privacy_clone,
+                    socket,
+                    signal_clone,
+                    destinations_clone,
+                    session_id_clone,
+                    packet_format_clone,
+                    max_fps_clone,
+                    pacing_rate_clone,
+                    keyframe_interval_clone,
+                    packet_size_clone,
+                    events_tx,
+                    stats_clone,
+                    units_clone,
+                    protection_clone,
+                );
+
+                loop {
+                    stream_context.process_signals();
+                    camera_in_use_clone
+                        .store(camera && stream_context.source.is_some(), Ordering::Relaxed);
+
+                    if !stream_context.streaming
+                        || !stream_context.addr_bound
+                        || stream_context.shutter_closed
+                    {
+                        //  signal terminate won't be "taken" after reading, persisting after processing
+                        //  process_signals() only shuts down the thing, breaking has to be done inside the loop
+                        if stream_context.signal.load(Ordering::Relaxed) == SSIGNAL_TERMINATE {
+                            break;
+                        }
+                        // Paused mid-call the camera stays open, so the stream resumes right away
+                        let idle = match stream_context.source {
+                            Some(_) => Duration::from_millis(30),
+                            None => IDLE_PARK,
+                        };
+                        std::thread::park_timeout(idle);
+
+                        continue;
+                    }
+
+                    stream_context.capture_and_send();
+                    let paced_for = std::mem::take(&mut stream_context.paced_for);
+                    std::thread::sleep(FRAME_SLEEP.saturating_sub(paced_for));
+                }
+            })?;
+
+        let controls = H264StreamControls::new(
+            t,
+            camera,
+            camera_in_use,
+            privacy,
+            mode,
+            reconfigure,
+            encoder,
+            encoding,
+            filters,
+            motion,
+            signal,
+            destinations,
+            session_id,
+            packet_format,
+            max_fps,
+            pacing_rate,
+            keyframe_interval,
+            packet_size,
+            events_rx,
+            stats,
+            units,
+            protection,
+            socket_clone,
+            addr,
+        );
+        Ok(controls)
+    }
+}
+
+//////////////////////////////////
+//INCOMING STREAM CONTROLS ///////
+//////////////////////////////////
+pub mod incoming {
+
+    use anyhow::Error;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    use tracing::{debug, error, info, info_span, warn};
+
+    use super::ssignal::*;
+    use super::{join_with_timeout, RgbaFrame, StreamSource, THREAD_EXIT_TIMEOUT};
+    use super::{SharedProtection, SharedUnitSink, SrtpContext, UnitSink};
+    use crate::av_sync::{timestamp_now, AvSync, MediaKind, PlayoutQueue, SharedAvSync};
+    use crate::bandwidth::is_burst_packet;
+    use crate::bitstream::BitstreamDump;
+    use crate::codec::{encoding_of, open_decoder, CodecBackend, DecodedPicture, VideoEncoding};
+    use crate::mtu::{is_probe, probe_data_size};
+    use crate::nal::{
+        ends_unit, NalBuilder, PacketError, DEFAULT_MAX_NAL_UNIT_SIZE, MAX_PACKET_DATA_SIZE,
+        MAX_PACKET_OVERHEAD,
+    };
+    use crate::qos::{self, SocketSettings, SocketTuning};
+    use crate::stats::SharedIncomingStats;
+    use scp_client::client::PacketFormat;
+
+    const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+    const SINGLE_READ_TIMEOUT: Duration = Duration::from_millis(100);
+    /// How long the packets of an expected peer are kept until it's accepted
+    const PREBUFFER_TIMEOUT: Duration = Duration::from_secs(1);
+    /// Upper bound of the buffered packets, in case the peer sends a lot more than video
+    const PREBUFFER_MAX_PACKETS: usize = 4096;
+    /// NAL units waiting for the decoder, about a second of video
+    const DECODE_QUEUE_LEN: usize = 64;
+    /// NAL units refused in a row before the decoder is created again, its state may be broken
+    const DECODER_RESET_ERRORS: u32 = 30;
+    /// Room for the largest packet, with some to spare for peers sending larger ones
+    const RECV_BUF_LEN: usize = 2 * (MAX_PACKET_DATA_SIZE + MAX_PACKET_OVERHEAD);
+    /// No frame decoded for this long while the packets keep coming, the video is frozen.
+    /// Likely the packets of a keyframe were lost, the frames after it cannot be decoded
+    pub const FREEZE_AFTER: Duration = Duration::from_secs(1);
+
+    /// If no new frames arrive within this time, the connection is dropped
+    // const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A decoded frame copied out of the incoming stream
+    /// * `sequence` - number of the frame decoded by the stream, starting at 1
+    /// * `timestamp` - when the frame was decoded
+    #[derive(Clone, Debug)]
+    pub struct Frame {
+        pub rgba: RgbaFrame,
+        pub sequence: u64,
+        pub timestamp: Instant,
+    }
+
+    /// Sequence number and timestamp of the latest decoded frame
+    #[derive(Clone, Copy, Debug)]
+    struct FrameInfo {
+        sequence: u64,
+        timestamp: Instant,
+    }
+
+    /// Receives the frames decoded by the incoming stream, i.e. to render them in another frontend.
+    /// Called on the stream thread while the frame buffer of the stream is locked, so it should be quick
+    pub trait FrameSink: Send {
+        /// A decoded frame, of the size the peer sends
+        /// * `sequence` - number of the frame decoded by the stream, starting at 1
+        fn frame(&mut self, rgba: &RgbaFrame, sequence: u64);
+    }
+    impl<F: FnMut(&RgbaFrame, u64) + Send> FrameSink for F {
+        fn frame(&mut self, rgba: &RgbaFrame, sequence: u64) {
+            self(rgba, sequence)
+        }
+    }
+    type SharedFrameSink = Arc<Mutex<Option<Box<dyn FrameSink>>>>;
+
+    pub trait IncomingStreamControls {
+        /// The piece of data the stream produces (frame, audio fragment)
+        type Data;
+        /// Accept connections from a host. Port 0 accepts any port of the host,
+        /// i.e. when the port the peer sends from isn't known
+        fn accept(&mut self, addr: SocketAddr) -> anyhow::Result<()>;
+        /// Refuse the connection (i.e. stop)
+        fn refuse(&mut self);
+        /// Get the latest WHOLE piece of data (frame, audio fragment)
+        /// # Errors
+        /// Returns an error if nothing was received yet
+        fn get_data(&self) -> anyhow::Result<Self::Data>;
+        /// Check if the host might be down
+        fn is_receiving(&self) -> bool;
+    }
+    /// Peer to accept the stream from, read by the thread on `SSIGNAL_CONNECT`
+    /// * `addr` - address the peer sends the stream from
+    /// * `session_id` - only packets of this session are accepted, 0 for any
+    /// * `packet_format` - format the packets come in, see `SessionConfig::codecs`
+    /// * `max_unit_size` - larger NAL units are dropped, see `NalBuilder::set_max_unit_size`
+    /// * `expected` - host to buffer the packets of until it's accepted, read on `SSIGNAL_EXPECT`
+    struct AcceptTarget {
+        addr: SocketAddr,
+        session_id: u32,
+        packet_format: PacketFormat,
+        max_unit_size: usize,
+        expected: Option<IpAddr>,
+    }
+    /// Controls for incoming stream.
+
+    pub struct H264IncomingStreamControls {
+        /// None after the thread was shut down
+        t_handle: Option<JoinHandle<()>>,
+        signal: Arc<AtomicU8>,
+        signal_data: Arc<Mutex<AcceptTarget>>,
+        conn_status: Arc<AtomicBool>,
+        /// Written by the stream thread while holding the lock of the frame buffer
+        frame_info: Arc<Mutex<Option<FrameInfo>>>,
+        /// Frames shown so far, decoded or still images, counted while holding the lock
+        /// of the frame buffer
+        sequence: Arc<AtomicU64>,
+        /// When the last packet of the accepted peer came, None if none did since it was accepted
+        last_packet: Arc<Mutex<Option<Instant>>>,
+        /// Set by the decoder thread for every frame, cleared by `take_new_frame`
+        new_frame: Arc<AtomicBool>,
+        sink: SharedFrameSink,
+        units: SharedUnitSink,
+        protection: SharedProtection,
+        av_sync: SharedAvSync,
+        stats: SharedIncomingStats,
+        /// Data sizes of the MTU probes received, mutex only to make the controls `Sync`
+        probes: Mutex<Receiver<usize>>,
+        /// When the packets of the bandwidth probes came, see `crate::bandwidth`
+        bursts: Mutex<Receiver<Instant>>,
+        /// Backend the decoder thread switches to next, see `set_decoder`
+        decoder: Arc<Mutex<Option<CodecBackend>>>,
+        /// Simulated network the packets go through before they're taken, see `set_simulated_loss`
+        #[cfg(feature = "netsim")]
+        impairment: Arc<Mutex<Option<netsim::Impairment>>>,
+        /// Clone of the stream socket, to tune it from outside the thread
+        socket: UdpSocket,
+        /// Address the incoming stream socket is bound to
+        pub address: SocketAddr,
+        /// What the stream shows, and so where it's decoded to
+        pub source: StreamSource,
+    }
+
+    impl H264IncomingStreamControls {
+        /// Create new UDP socket to listen to incoming video stream on const VIDEO_STREAM_PORT
+        /// Additionally, it spawns a thread to listen to incoming data
+        /// # Errors
+        /// Might return an error if the socket cannot be bound
+        #[allow(clippy::too_many_arguments)]
+        fn new(
+            t_handle: JoinHandle<()>,
+            signal: Arc<AtomicU8>,
+            signal_data: Arc<Mutex<AcceptTarget>>,
+            conn_status: Arc<AtomicBool>,
+            frame_info: Arc<Mutex<Option<FrameInfo>>>,
+            sequence: Arc<AtomicU64>,
+            last_packet: Arc<Mutex<Option<Instant>>>,
+            new_frame: Arc<AtomicBool>,
+            sink: SharedFrameSink,
+            units: SharedUnitSink,
+            protection: SharedProtection,
+            av_sync: SharedAvSync,
+            stats: SharedIncomingStats,
+            probes: Receiver<usize>,
+            bursts: Receiver<Instant>,
+            decoder: Arc<Mutex<Option<CodecBackend>>>,
+            socket: UdpSocket,
+            address: SocketAddr,
+            source: StreamSource,
+        ) -> Self {
+            Self {
+                socket,
+                address,
+                source,
+                probes: Mutex::new(probes),
+                bursts: Mutex::new(bursts),
+                decoder,
+                #[cfg(feature = "netsim")]
+                impairment: Arc::default(),
+                frame_info,
+                sequence,
+                last_packet,
+                new_frame,
+                sink,
+                units,
+                protection,
+                av_sync,
+                stats,
+                conn_status,
+                t_handle: Some(t_handle),
+                signal,
+                signal_data,
+            }
+        }
+        /// Lip-sync state of the video. Streams received from the same peer (audio)
+        /// should report to it, so video is held back when it's ahead of them
+        pub fn av_sync(&self) -> SharedAvSync {
+            Arc::clone(&self.av_sync)
+        }
+        /// Counters of the stream, kept up to date by the stream threads
+        pub fn stats(&self) -> SharedIncomingStats {
+            Arc::clone(&self.stats)
+        }
+        /// Packet data sizes of the MTU probes received since the last call, see `crate::mtu`
+        pub fn poll_probes(&self) -> Vec<usize> {
+            match self.probes.lock() {
+                Ok(probes) => probes.try_iter().collect(),
+                Err(_) => Vec::new(),
+            }
+        }
+        /// When the packets of the bandwidth probes received since the last call came,
+        /// see `crate::bandwidth`
+        pub fn poll_bursts(&self) -> Vec<Instant> {
+            match self.bursts.lock() {
+                Ok(bursts) => bursts.try_iter().collect(),
+                Err(_) => Vec::new(),
+            }
+        }
+        /// How long ago the last frame was decoded, None if none was since the stream was accepted.
+        /// Tells a paused peer or a lost connection from a still picture
+        pub fn last_frame_age(&self) -> Option<Duration> {
+            let info = self.frame_info.lock().ok()?;
+            info.map(|info| info.timestamp.elapsed())
+        }
+        /// How long ago the last packet of the accepted peer came, None if none did since
+        pub fn last_packet_age(&self) -> Option<Duration> {
+            let last_packet = self.last_packet.lock().ok()?;
+            last_packet.map(|at| at.elapsed())
+        }
+        /// Whether the packets keep coming but no frame was decoded for `FREEZE_AFTER`.
+        /// Unlike a paused peer, a frozen one likely needs a keyframe
+        pub fn is_frozen(&self) -> bool {
+            is_frozen(self.last_frame_age(), self.last_packet_age())
+        }
+        /// Number of the last frame decoded, None if none was since the stream was accepted.
+        /// A frontend only needs to copy the frame buffer when it changes
+        pub fn last_frame_sequence(&self) -> Option<u64> {
+            let info = self.frame_info.lock().ok()?;
+            info.map(|info| info.sequence)
+        }
+        /// Whether a frame was decoded since the last `take_new_frame`
+        pub fn has_new_frame(&self) -> bool {
+            self.new_frame.load(Ordering::Acquire)
+        }
+        /// Whether a frame was decoded since the last call, so the frame buffer is worth copying
+        pub fn take_new_frame(&self) -> bool {
+            self.new_frame.swap(false, Ordering::AcqRel)
+        }
+        /// Show a still image of the peer in place of its video, see `crate::still`.
+        /// It counts as a frame, the frame sink gets it too
+        pub fn show_still(&self, picture: &dyn DecodedPicture) {
+            let mut frame = self.source.frame_buffer().lock().unwrap();
+            let (width, height) = picture.dimensions();
+            frame.resize(width, height);
+            picture.write_rgba8(&mut frame.data);
+            let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+            *self.frame_info.lock().unwrap() = Some(FrameInfo {
+                sequence,
+                timestamp: Instant::now(),
+            });
+            self.new_frame.store(true, Ordering::Release);
+            if let Some(ref mut sink) = *self.sink.lock().unwrap() {
+                sink.frame(&frame, sequence);
+            }
+        }
+        /// Hand every decoded frame to `sink` too. None to stop. Replaces the previous sink
+        pub fn set_frame_sink(&self, sink: Option<Box<dyn FrameSink>>) {
+            if let Ok(mut current) = self.sink.lock() {
+                *current = sink;
+            }
+        }
+        /// Hand the NAL units received to `sink` too, before they're decoded. None to stop.
+        /// Replaces the previous sink
+        pub fn set_unit_sink(&self, sink: Option<Box<dyn UnitSink>>) {
+            if let Ok(mut current) = self.units.lock() {
+                *current = sink;
+            }
+        }
+        /// Take only the packets protected as SRTP from now on, None to take them as they are.
+        /// The packets that fail to open are dropped
+        pub fn set_protection(&self, protection: Option<SrtpContext>) {
+            if let Ok(mut current) = self.protection.lock() {
+                *current = protection;
+            }
+        }
+        /// Drop this share (0.0..=1.0) of the packets received, as a lossy network would,
+        /// i.e. to see how the pipeline copes without a bad link at hand. 0 takes them all again
+        #[cfg(feature = "netsim")]
+        pub fn set_simulated_loss(&self, loss: f64) {
+            let config = netsim::NetSimConfig {
+                loss,
+                ..Default::default()
+            };
+            if let Ok(mut impairment) = self.impairment.lock() {
+                *impairment = (loss > 0.).then(|| netsim::Impairment::new(config));
+            }
+        }
+        /// Session ID of the packets to accept, see `SessionConfig::session_id`.
+        /// Takes effect on the next `accept()`
+        pub fn set_session_id(&self, session_id: u32) {
+            if let Ok(mut target) = self.signal_data.lock() {
+                target.session_id = session_id;
+            }
+        }
+        /// Format of the packets to accept, see `SessionConfig::codecs`.
+        /// Takes effect on the next `accept()`
+        pub fn set_packet_format(&self, format: PacketFormat) {
+            if let Ok(mut target) = self.signal_data.lock() {
+                target.packet_format = format;
+            }
+        }
+        /// Set the socket the packets come to up with `tuning`, see `crate::qos`
+        /// # Errors
+        /// Returns an error if the settings of the socket cannot be read back
+        pub fn tune_socket(&self, tuning: &SocketTuning) -> io::Result<SocketSettings> {
+            qos::tune_socket(&self.socket, tuning)
+        }
+        /// Decode with `backend` from the next NAL unit on, OpenH264 is used if it cannot,
+        /// see `crate::codec`. The picture comes back with the next keyframe
+        pub fn set_decoder(&self, backend: CodecBackend) {
+            if let Ok(mut decoder) = self.decoder.lock() {
+                *decoder = Some(backend);
+            }
+        }
+        /// Drop the NAL units larger than `size` bytes, i.e. to bound the memory a peer may take.
+        /// Takes effect on the next `accept()`
+        pub fn set_max_unit_size(&self, size: usize) {
+            if let Ok(mut target) = self.signal_data.lock() {
+                target.max_unit_size = size;
+            }
+        }
+        /// Buffer the packets from `ip` until it's accepted, keeping the last `PREBUFFER_TIMEOUT` of them.
+        /// The peer usually starts streaming before the session is accepted here,
+        /// its first keyframe would be lost otherwise. Replaced by the next `accept()` or `refuse()`
+        pub fn expect(&self, ip: IpAddr) {
+            if let Ok(mut target) = self.signal_data.lock() {
+                target.expected = Some(ip);
+            }
+            self.signal.store(SSIGNAL_EXPECT, Ordering::SeqCst);
+        }
+        /// Terminate the stream thread and wait for it to exit.
+        /// # Errors
+        /// Returns an error if the thread panicked or didn't exit within `THREAD_EXIT_TIMEOUT`
+        pub fn shutdown(mut self) -> anyhow::Result<()> {
+            self.stop()
+        }
+        fn stop(&mut self) -> anyhow::Result<()> {
+            let Some(t_handle) = self.t_handle.take() else {
+                return Ok(());
+            };
+            self.signal.store(SSIGNAL_TERMINATE, Ordering::SeqCst);
+            self.wake();
+            join_with_timeout(t_handle, THREAD_EXIT_TIMEOUT)
+        }
+        /// Send an empty datagram to the stream socket, so the thread doesn't sit in `recv` until it times out.
+        /// A socket accepting a peer filters it out, then `SINGLE_READ_TIMEOUT` bounds the wait anyway.
+        fn wake(&self) {
+            let mut target = self.address;
+            if target.ip().is_unspecified() {
+                target.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            }
+            let local = SocketAddr::new(target.ip(), 0);
+            if let Ok(socket) = UdpSocket::bind(local) {
+                let _ = socket.send_to(&[], target);
+            }
+        }
+    }
+    impl Drop for H264IncomingStreamControls {
+        fn drop(&mut self) {
+            if let Err(e) = self.stop() {
+                error!("Incoming stream thread failed to exit: {e}");
+            }
+        }
+    }
+
+    impl IncomingStreamControls for H264IncomingStreamControls {
+        type Data = Frame;
+
+        /// Accept a new connection. If a connection exists, it's overridden.
+        fn accept(&mut self, addr: SocketAddr) -> anyhow::Result<()> {
+            let lock = self.signal_data.lock();
+            // stupid error cannot be send between threads safely
+            if lock.is_err() {
+                return Err(Error::msg(
+                    "Cannot acquire the signal lock for incoming h.264 stream.",
+                ));
+            }
+            let mut lock = lock.unwrap();
+            lock.addr = addr;
+            self.signal
+                .store(SSIGNAL_CONNECT, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        /// Disconnect from the current stream.
+        fn refuse(&mut self) {
+            self.signal
+                .store(SSIGNAL_DISCONNECT, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        /// Copy of the most recently decoded frame
+        fn get_data(&self) -> anyhow::Result<Frame> {
+            let lock = self
+                .source
+                .frame_buffer()
+                .lock()
+                .map_err(|_| Error::msg("Mutex poisoned"))?;
+            let info = self
+                .frame_info
+                .lock()
+                .map_err(|_| Error::msg("Mutex poisoned"))?
+                .ok_or_else(|| Error::msg("No frame decoded yet"))?;
+            Ok(Frame {
+                rgba: lock.clone(),
+                sequence: info.sequence,
+                timestamp: info.timestamp,
+            })
+        }
+
+        fn is_receiving(&self) -> bool {
+            self.conn_status.load(Ordering::SeqCst)
+        }
+    }
+
+    /// See `H264IncomingStreamControls::is_frozen`. Nothing is frozen before the first frame
+    pub(crate) fn is_frozen(frame_age: Option<Duration>, packet_age: Option<Duration>) -> bool {
+        matches!(
+            (frame_age, packet_age),
+            (Some(frame), Some(packet)) if frame >= FREEZE_AFTER && packet < FREEZE_AFTER
+        )
+    }
+
+    /// Bounded queue of the NAL units waiting for the decoder thread.
+    /// When the decoder falls behind, the oldest units are dropped to make room,
+    /// so the socket keeps being drained and the video catches up with the next keyframe
+    pub(crate) struct DecodeQueue {
+        /// The units and when they were queued
+        units: Mutex<VecDeque<(Vec<u8>, Instant)>>,
+        available: Condvar,
+        capacity: usize,
+        closed: AtomicBool,
+    }
+
+    impl DecodeQueue {
+        pub fn new(capacity: usize) -> Self {
+            Self {
+                units: Mutex::new(VecDeque::with_capacity(capacity)),
+                available: Condvar::new(),
+                capacity,
+                closed: AtomicBool::new(false),
+            }
+        }
+        /// Queue the unit. Returns true if the oldest one was dropped to make room for it
+        pub fn push(&self, unit: Vec<u8>) -> bool {
+            let mut units = self.units.lock().unwrap();
+            let dropped = units.len() >= self.capacity && units.pop_front().is_some();
+            units.push_back((unit, Instant::now()));
+            self.available.notify_one();
+            dropped
+        }
+        /// The oldest unit and when it was queued, waiting up to `timeout` for one.
+        /// None on timeout or once closed
+        pub fn pop(&self, timeout: Duration) -> Option<(Vec<u8>, Instant)> {
+            let units = self.units.lock().unwrap();
+            let (mut units, _) = self
+                .available
+                .wait_timeout_while(units, timeout, |units| {
+                    units.is_empty() && !self.is_closed()
+                })
+                .unwrap();
+            units.pop_front()
+        }
+        pub fn clear(&self) {
+            self.units.lock().unwrap().clear();
+        }
+        /// Wake up the decoder thread, so it can exit
+        pub fn close(&self) {
+            self.closed.store(true, Ordering::SeqCst);
+            self.available.notify_all();
+        }
+        pub fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Whether a packet from `from` belongs to the stream accepted from `addr`
+    fn is_accepted(addr: SocketAddr, from: SocketAddr) -> bool {
+        addr.ip() == from.ip() && (addr.port() == 0 || addr.port() == from.port())
+    }
+
+    /// Initializes the required parts to get an incoming stream working.
+    /// The socket is bound to `addr`. Use port 0 to let the OS pick one,
+    /// the actual address is available in `H264IncomingStreamControls::address`
+    /// Returns controls to the incoming stream.
+    pub fn init_incoming_h264_stream(
+        addr: SocketAddr,
+        dump_dir: Option<PathBuf>,
+    ) -> anyhow::Result<H264IncomingStreamControls> {
+        init_incoming_source_stream(addr, dump_dir, StreamSource::Camera)
+    }
+
+    /// Like `init_incoming_h264_stream`, for the stream of the given source.
+    /// Every stream decodes on its own threads, so the streams of a peer are decoded side by side
+    pub fn init_incoming_source_stream(
+        addr: SocketAddr,
+        dump_dir: Option<PathBuf>,
+        source: StreamSource,
+    ) -> anyhow::Result<H264IncomingStreamControls> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(SINGLE_READ_TIMEOUT)).unwrap();
+        let addr = socket.local_addr()?;
+        let socket_clone = socket.try_clone()?;
+
+        let signal = Arc::new(AtomicU8::new(SSIGNAL_NONE));
+        let signal_data = Arc::new(Mutex::new(AcceptTarget {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000),
+            session_id: 0,
+            packet_format: PacketFormat::Legacy,
+            max_unit_size: DEFAULT_MAX_NAL_UNIT_SIZE,
+            expected: None,
+        }));
+        let conn_status = Arc::new(AtomicBool::new(false));
+        let frame_info = Arc::new(Mutex::new(None));
+        let sequence = Arc::new(AtomicU64::new(0));
+        let sequence_clone = Arc::clone(&sequence);
+        let sink: SharedFrameSink = Arc::new(Mutex::new(None));
+        let units: SharedUnitSink = Arc::new(Mutex::new(None));
+        let units_clone = Arc::clone(&units);
+        let protection: SharedProtection = Arc::new(Mutex::new(None));
+        let protection_clone = Arc::clone(&protection);
+        let av_sync = Arc::new(Mutex::new(AvSync::new()));
+
+        let signal_clone = Arc::clone(&signal);
+        let signal_data_clone = Arc::clone(&signal_data);
+        let conn_status_clone = Arc::clone(&conn_status);
+        let frame_info_clone = Arc::clone(&frame_info);
+        let frame_info_reader = Arc::clone(&frame_info);
+        let last_packet = Arc::new(Mutex::new(None));
+        let last_packet_clone = Arc::clone(&last_packet);
+        let new_frame = Arc::new(AtomicBool::new(false));
+        let new_frame_clone = Arc::clone(&new_frame);
+        let sink_clone = Arc::clone(&sink);
+        let av_sync_clone = Arc::clone(&av_sync);
+        let decode_queue = Arc::new(DecodeQueue::new(DECODE_QUEUE_LEN));
+        let decode_queue_clone = Arc::clone(&decode_queue);
+        let stats = SharedIncomingStats::default();
+        let stats_decoder = Arc::clone(&stats);
+        let stats_clone = Arc::clone(&stats);
+        let (probes_tx, probes_rx) = mpsc::channel();
+        let (bursts_tx, bursts_rx) = mpsc::channel();
+        let decoder_switch: Arc<Mutex<Option<CodecBackend>>> = Arc::default();
+        let decoder_switch_clone = Arc::clone(&decoder_switch);
+        #[cfg(feature = "netsim")]
+        let impairment: Arc<Mutex<Option<netsim::Impairment>>> = Arc::default();
+        #[cfg(feature = "netsim")]
+        let impairment_clone = Arc::clone(&impairment);
+
+        // Decoding is slower than reading the packets, so it's done on its own thread.
+        // It exits once the queue is closed by the reading thread
+        let decoder_t = thread::Builder::new()
+            .name("incoming_decoder".into())
+            .spawn(move || {
+                let _span = info_span!("incoming_decoder", addr = %addr, ?source).entered();
+                // The H.264 backend asked for, the units of the other encodings have their own
+                let mut backend = CodecBackend::OpenH264;
+                let mut decoder = open_decoder(VideoEncoding::H264, backend).unwrap();
+                // An encoding that cannot be decoded here, tried again once another one comes
+                let mut undecodable = None;
+                let mut errors_in_row = 0;
+                while !decode_queue_clone.is_closed() {
+                    let Some((unit, queued_at)) = decode_queue_clone.pop(SINGLE_READ_TIMEOUT)
+                    else {
+                        continue;
+                    };
+                    let switch = decoder_switch_clone
+                        .lock()
+                        .ok()
+                        .and_then(|mut backend| backend.take());
+                    let switched = switch.is_some_and(|switch| switch != backend);
+                    backend = switch.unwrap_or(backend);
+                    // The peer may send another encoding than negotiated, i.e. falling back
+                    let encoding = encoding_of(&unit);
+                    let reopen = encoding != decoder.encoding()
+                        || (switched && encoding == VideoEncoding::H264);
+                    if reopen && undecodable != Some(encoding) {
+                        match open_decoder(encoding, backend) {
+                            Ok(new) => {
+                                info!("Decoding {encoding} with {}", new.backend());
+                                decoder = new;
+                                undecodable = None;
+                            }
+                            Err(e) => {
+                                error!("Cannot create a new decoder: {e}");
+                                undecodable = Some(encoding);
+                            }
+                        }
+                    }
+                    // The picture borrows the decoder, it is done with before a reset
+                    match decoder.decode(&unit) {
+                        Ok(decoded) => {
+                            errors_in_row = 0;
+                            if let Some(d) = decoded {
+                                stats_decoder.frames_decoded.fetch_add(1, Ordering::Relaxed);
+                                stats_decoder.receive_to_decode.record(queued_at.elapsed());
+                                let mut frame = source.frame_buffer().lock().unwrap();
+                                let (width, height) = d.dimensions();
+                                if frame.resize(width, height) {
+                                    info!("Decoding {width}x{height}");
+                                }
+                                d.write_rgba8(&mut frame.data);
+                                let sequence = sequence_clone.fetch_add(1, Ordering::Relaxed) + 1;
+                                *frame_info_clone.lock().unwrap() = Some(FrameInfo {
+                                    sequence,
+                                    timestamp: Instant::now(),
+                                });
+                                new_frame_clone.store(true, Ordering::Release);
+                                if let Some(ref mut sink) = *sink_clone.lock().unwrap() {
+                                    sink.frame(&frame, sequence);
+                                }
+                            }
+                        }
+                        Err(_) => {
+                            stats_decoder.decode_errors.fetch_add(1, Ordering::Relaxed);
+                            errors_in_row += 1;
+                        }
+                    }
+                    if errors_in_row >= DECODER_RESET_ERRORS {
+                        warn!(
+                            "The decoder refused {errors_in_row} NAL units in a row, resetting it"
+                        );
+                        match open_decoder(decoder.encoding(), decoder.backend()) {
+                            Ok(new) => {
+                                decoder = new;
+                                stats_decoder.decoder_resets.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => error!("Cannot create a new decoder: {e}"),
+                        }
+                        errors_in_row = 0;
+                    }
+                }
+            })?;
+
+        // Spawn the data processing thread
+        let t = thread::Builder::new()
+            .name("incoming_stream".into())
+            .spawn(move || {
+                let _span = info_span!("incoming_stream", addr = %addr, ?source).entered();
+                let mut recv_buf: [u8; RECV_BUF_LEN] = [0; RECV_BUF_LEN];
+                let mut nal_builder = NalBuilder::new();
+                let mut packet_format = PacketFormat::Legacy;
+                let mut last_packet = Instant::now();
+                // When the previous NAL unit was put together, for the gaps between them
+                let mut last_unit: Option<Instant> = None;
+                // NAL units dropped since the start, because the decoder fell behind
+                let mut dropped: u64 = 0;
+                // Packets dropped since the start as replays, i.e. re-injected by someone on the path
+                let mut replayed: u64 = 0;
+                // NAL units held back to keep the video in sync with audio
+                let mut playout = PlayoutQueue::new();
+                // Dump of the current connection, if enabled
+                let mut dump: Option<BitstreamDump> = None;
+                // Where the packets are accepted from, a port of 0 for any port of the host
+                let mut accepted = None;
+                // Host expected to stream soon, and its packets received so far.
+                // The peer often starts before the session is accepted here, keyframe included
+                let mut expected: Option<IpAddr> = None;
+                let mut prebuffer: VecDeque<(Instant, SocketAddr, Vec<u8>)> = VecDeque::new();
+                // Buffered packets of the accepted peer, handled before the live ones
+                let mut backlog: VecDeque<Vec<u8>> = VecDeque::new();
+
+                loop {
+                    // read signals first
+                    match signal_clone.load(std::sync::atomic::Ordering::SeqCst) {
+                        SSIGNAL_CONNECT => {
+                            //get addr from signal_data_clone.
+                            let target = signal_data_clone.lock().unwrap();
+
+                            info!(
+                                "Accepting stream from {} in session {:08x}",
+                                target.addr, target.session_id
+                            );
+                            accepted = Some(target.addr);
+                            backlog = prebuffer
+                                .drain(..)
+                                .filter(|(received, from, _)| {
+                                    received.elapsed() < PREBUFFER_TIMEOUT
+                                        && is_accepted(target.addr, *from)
+                                })
+                                .map(|(_, _, packet)| packet)
+                                .collect();
+                            expected = None;
+                            signal_clone.store(SSIGNAL_NONE, Ordering::SeqCst);
+                            nal_builder.set_session_id(target.session_id);
+                            nal_builder.set_format(target.packet_format);
+                            nal_builder.set_max_unit_size(target.max_unit_size);
+                            packet_format = target.packet_format;
+                            dump = dump_dir.as_deref().and_then(|dir| {
+                                BitstreamDump::create(dir)
+                                    .inspect_err(|e| warn!("Cannot dump the stream: {e}"))
+                                    .ok()
+                            });
+                            playout.clear();
+                            decode_queue.clear();
+                            // The frames of the previous stream don't count
+                            *frame_info_reader.lock().unwrap() = None;
+                            *last_packet_clone.lock().unwrap() = None;
+                            last_unit = None;
+                            let _ = socket.take_error();
+                            conn_status_clone.store(true, Ordering::SeqCst);
+                        }
+                        SSIGNAL_DISCONNECT => {
+                            signal_clone.store(SSIGNAL_NONE, Ordering::SeqCst);
+                            info!("Incoming stream disconnected");
+                            dump = None;
+                            accepted = None;
+                            expected = None;
+                            prebuffer.clear();
+                            backlog.clear();
+                            decode_queue.clear();
+
+                            conn_status_clone.store(false, Ordering::SeqCst);
+                        }
+
+                        SSIGNAL_EXPECT => {
+                            signal_clone.store(SSIGNAL_NONE, Ordering::SeqCst);
+                            expected = signal_data_clone.lock().unwrap().expected;
+                            prebuffer.clear();
+                            if let Some(ip) = expected {
+                                debug!("Buffering the stream expected from {ip}");
+                            }
+                        }
+                        SSIGNAL_TERMINATE => {
+                            break;
+                        }
+                        _ => (),
+                    };
+
+                    if !conn_status_clone.load(Ordering::Relaxed) {
+                        let Some(ip) = expected else {
+                            // Sleep briefly if not connected
+                            thread::sleep(Duration::from_millis(100));
+                            continue;
+                        };
+                        // Blocks for SINGLE_READ_TIMEOUT at most
+                        if let Ok((size, from)) = socket.recv_from(&mut recv_buf) {
+                            if from.ip() == ip {
+                                prebuffer.push_back((
+                                    Instant::now(),
+                                    from,
+                                    recv_buf[..size].to_vec(),
+                                ));
+                            }
+                        }
+                        while prebuffer
+                            .front()
+                            .is_some_and(|(received, _, _)| received.elapsed() >= PREBUFFER_TIMEOUT)
+                            || prebuffer.len() > PREBUFFER_MAX_PACKETS
+                        {
+                            prebuffer.pop_front();
+                        }
+                        continue;
+                    }
+                    // Data reception - timeout is 100ms
+
+                    let received = match backlog.pop_front() {
+                        Some(packet) => {
+                            recv_buf[..packet.len()].copy_from_slice(&packet);
+                            Some(packet.len())
+                        }
+                        // The socket isn't connected, so a peer sending from any port can be accepted
+                        None => socket
+                            .recv_from(&mut recv_buf)
+                            .ok()
+                            .filter(|(_, from)| {
+                                accepted.is_some_and(|addr| is_accepted(addr, *from))
+                            })
+                            .map(|(size, _)| size),
+                    };
+                    #[cfg(feature = "netsim")]
+                    let received =
+                        received.filter(|size| match &mut *impairment_clone.lock().unwrap() {
+                            Some(impairment) => !impairment.process(&recv_buf[..*size]).is_empty(),
+                            None => true,
+                        });
+                    // Opened in place, the MTU and bandwidth probes aren't protected
+                    let received = match (received, &mut *protection_clone.lock().unwrap()) {
+                        (Some(size), Some(srtp))
+                            if !is_probe(&recv_buf[..size])
+                                && !is_burst_packet(&recv_buf[..size]) =>
+                        {
+                            let opened = srtp.unprotect(&mut recv_buf[..size]);
+                            if opened.is_none() {
+                                debug!("Packet failing to open as SRTP dropped");
+                            }
+                            opened
+                        }
+                        (received, _) => received,
+                    };
+                    if let Some(size) = received.filter(|size| is_probe(&recv_buf[..*size])) {
+                        // Acknowledged over SCP by `CallManager`, it's not a part of the stream
+                        let _ = probes_tx.send(probe_data_size(&recv_buf[..size]));
+                    } else if received.is_some_and(|size| is_burst_packet(&recv_buf[..size])) {
+                        // Timed and reported by `CallManager` as well
+                        let _ = bursts_tx.send(Instant::now());
+                    } else if let Some(bytes_read) = received {
+                        last_packet = Instant::now();
+                        *last_packet_clone.lock().unwrap() = Some(last_packet);
+                        let packet = &recv_buf[0..bytes_read];
+                        if let Some(ref mut dump) = dump {
+                            let kind = if ends_unit(packet, packet_format) {
+                                "end"
+                            } else {
+                                "data"
+                            };
+                            dump.record_packet(packet, kind);
+                        }
+                        stats_clone.record_received(packet);
+                        match nal_builder.push_packet(packet) {
+                            Ok(Some(unit)) => {
+                                if let Some(ref mut dump) = dump {
+                                    dump.record_unit(unit.data);
+                                }
+                                // The sinks republish H.264, the VP8 frames are left out
+                                if let Some(ref mut sink) = *units_clone.lock().unwrap() {
+                                    if encoding_of(unit.data) == VideoEncoding::H264 {
+                                        sink.unit(
+                                            unit.data,
+                                            unit.timestamp.unwrap_or_else(timestamp_now),
+                                        );
+                                    }
+                                }
+                                if let Some(previous) = last_unit.replace(last_packet) {
+                                    stats_clone.frame_gaps.record(last_packet - previous);
+                                }
+                                let delay = match unit.timestamp {
+                                    Some(capture_ts) => {
+                                        let mut sync = av_sync_clone.lock().unwrap();
+                                        sync.report(MediaKind::Video, capture_ts, timestamp_now());
+                                        sync.delay_for(MediaKind::Video)
+                                    }
+                                    None => Duration::ZERO,
+                                };
+                                playout.push(unit.data.to_vec(), delay, Instant::now());
+                            }
+                            Err(
+                                e @ (PacketError::Truncated
+                                | PacketError::InvalidIdentifier
+                                | PacketError::Overflow
+                                | PacketError::UnsupportedVersion(_)),
+                            ) => debug!("Malformed packet dropped: {e}"),
+                            Err(PacketError::Replayed) => {
+                                replayed += 1;
+                                // Logged less and less often, so a flood of them doesn't flood the log
+                                if replayed.is_power_of_two() {
+                                    warn!("Replayed packets dropped, {replayed} so far");
+                                }
+                            }
+                            // Lost, late or duplicated, normal over UDP
+                            Ok(None) | Err(_) => {}
+                        }
+                        stats_clone
+                            .packets_lost
+                            .store(nal_builder.packets_lost(), Ordering::Relaxed);
+                    } else if last_packet.duration_since(Instant::now()) > CONNECTION_TIMEOUT {
+                        conn_status_clone.store(false, Ordering::SeqCst);
+                    }
+
+                    while let Some(unit) = playout.pop_ready(Instant::now()) {
+                        if decode_queue.push(unit) {
+                            dropped += 1;
+                            // Logged less and less often, the decoder is overloaded already
+                            if dropped.is_power_of_two() {
+                                warn!("Decoder falling behind, {dropped} NAL units dropped so far");
+                            }
+                        }
+                    }
+                }
+                decode_queue.close();
+                if let Err(e) = join_with_timeout(decoder_t, THREAD_EXIT_TIMEOUT) {
+                    error!("Decoder thread failed to exit: {e}");
+                }
+            })?;
+        let controls = H264IncomingStreamControls::new(
+            t,
+            signal,
+            signal_data,
+            conn_status,
+            frame_info,
+            sequence,
+            last_packet,
+            new_frame,
+            sink,
+            units,
+            protection,
+            av_sync,
+            stats,
+            probes_rx,
+            bursts_rx,
+            decoder_switch,
+            socket_clone,
+            addr,
+            source,
+        );
+        // Shared with the thread spawned before the controls
+        #[cfg(feature = "netsim")]
+        let controls = {
+            let mut controls = controls;
+            controls.impairment = impairment;
+            controls
+        };
+        Ok(controls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use openh264::decoder::Decoder;
+    use v4l::video::Capture;
+    use v4l::Device;
+
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use netsim::{Impairment, NetSimConfig, UdpProxy};
+    use scp_client::client::VideoMode;
+
+    use crate::camera::pick_mode;
+    use crate::codec::{open_decoder, CodecBackend, VideoDecoder, VideoEncoding};
+    use crate::h264_stream::{FOURCC, HEIGHT, WIDTH};
+    use crate::nal::NalBuilder;
+    use crate::quality::VideoQuality;
+
+    use super::incoming::{
+        init_incoming_h264_stream, is_frozen, DecodeQueue, IncomingStreamControls,
+    };
+    use super::outgoing::{
+        init_h264_video_stream, Destination, StreamControls, StreamLayer, VideoBackend, VideoSource,
+    };
+    use super::{
+        join_with_timeout, packetize, CustomStream, FrameEncoder, H264Stream, LayeredFrame,
+        PacketBuffer, RgbaFrame,
+    };
+
+    /// How many NAL units in a row may be lost before the stream counts as not recovering
+    const RECOVERY_NAL_UNITS: usize = 5;
+    const TEST_SESSION: u32 = 0xC0FFEE;
+
+    /// Synthetic NAL units of a few packets each, with distinguishable contents
+    fn synthetic_nal_units(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| (0..1500 + i * 7).map(|b| (b * 31 + i) as u8).collect())
+            .collect()
+    }
+    /// Packets of each unit, numbered on across the units like in a stream.
+    /// The capture timestamp of each unit is its index.
+    fn packetize_stream(units: &[Vec<u8>]) -> Vec<Vec<Vec<u8>>> {
+        let mut buffer = PacketBuffer::default();
+        units
+            .iter()
+            .enumerate()
+            .map(|(i, unit)| {
+                buffer.clear();
+                buffer.push_unit(unit, i as u64, TEST_SESSION);
+                buffer.packets().map(<[u8]>::to_vec).collect()
+            })
+            .collect()
+    }
+    /// Feeds the packets to a NalBuilder and returns which of the units were rebuilt intact.
+    /// The capture timestamp of each unit is its index.
+    fn rebuild(units: &[Vec<u8>], packets: impl IntoIterator<Item = Vec<u8>>) -> Vec<bool> {
+        let mut builder = NalBuilder::new();
+        builder.set_session_id(TEST_SESSION);
+        let mut rebuilt = vec![false; units.len()];
+        for packet in packets {
+            let Ok(Some(unit)) = builder.push_packet(&packet) else {
+                continue;
+            };
+            if let Some(ts) = unit.timestamp {
+                if units
+                    .get(ts as usize)
+                    .is_some_and(|u| u.as_slice() == unit.data)
+                {
+                    rebuilt[ts as usize] = true;
+                }
+            }
+        }
+        rebuilt
+    }
+    fn longest_loss_run(rebuilt: &[bool]) -> usize {
+        rebuilt
+            .split(|ok| *ok)
+            .map(|run| run.len())
+            .max()
+            .unwrap_or(0)
+    }
+    #[test]
+    fn test_nal_builder_recovers_on_lossy_network() {
+        let units = synthetic_nal_units(300);
+        let packets = packetize_stream(&units).concat();
+        let config = NetSimConfig {
+            loss: 0.01,
+            duplicate: 0.01,
+            reorder: 0.01,
+            ..Default::default()
+        };
+        let arrived = Impairment::new(config).process_all(packets.iter().map(Vec::as_slice));
+
+        let rebuilt = rebuild(&units, arrived);
+        assert!(
+            rebuilt.iter().any(|ok| !ok),
+            "The network should hurt some units"
+        );
+        assert!(
+            longest_loss_run(&rebuilt) <= RECOVERY_NAL_UNITS,
+            "Stream didn't recover within {RECOVERY_NAL_UNITS} NAL units"
+        );
+    }
+    #[test]
+    fn test_nal_builder_discards_stale_session() {
+        let units = synthetic_nal_units(2);
+        // A packet of the previous call arrives in the middle of the new one
+        let mut current = packetize(&units[0], 0, TEST_SESSION);
+        let stale = packetize(&units[1], 1, TEST_SESSION + 1);
+        current.insert(1, stale[0].clone());
+        current.extend(stale);
+
+        let rebuilt = rebuild(&units, current);
+        assert_eq!(rebuilt, [true, false]);
+    }
+    #[test]
+    fn test_nal_builder_drops_duplicates() {
+        let units = synthetic_nal_units(20);
+        let packets = packetize_stream(&units).concat();
+        // Every packet arrives twice, the copy right away or a few packets later
+        let mut arrived = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            arrived.push(packet.clone());
+            if i % 2 == 0 {
+                arrived.push(packet.clone());
+            } else if let Some(earlier) = i.checked_sub(3).map(|i| &packets[i]) {
+                arrived.push(earlier.clone());
+            }
+        }
+        let mut builder = NalBuilder::new();
+        builder.set_session_id(TEST_SESSION);
+        let mut rebuilt = Vec::new();
+        for packet in arrived {
+            rebuilt.extend(
+                builder
+                    .push_packet(&packet)
+                    .ok()
+                    .flatten()
+                    .map(|unit| unit.data.to_vec()),
+            );
+        }
+        assert_eq!(rebuilt, units);
+        assert_eq!(builder.packets_lost(), 0);
+    }
+    #[test]
+    fn test_nal_builder_through_udp_proxy() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let config = NetSimConfig {
+            loss: 0.02,
+            ..Default::default()
+        };
+        let proxy = UdpProxy::start(receiver.local_addr().unwrap(), config).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(proxy.addr()).unwrap();
+
+        let units = synthetic_nal_units(50);
+        let reader = std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let mut arrived = Vec::new();
+            while let Ok(size) = receiver.recv(&mut buf) {
+                arrived.push(buf[..size].to_vec());
+            }
+            arrived
+        });
+        for packets in packetize_stream(&units) {
+            for packet in packets {
+                sender.send(&packet).unwrap();
+            }
+            // Roughly the pace of a real stream, so the loopback buffers don't overflow
+            std::thread::sleep(Duration::from_millis(2));
+        }
+
+        let rebuilt = rebuild(&units, reader.join().unwrap());
+        assert!(longest_loss_run(&rebuilt) <= RECOVERY_NAL_UNITS);
+    }
+
+    #[test]
+    fn test_scale_yuyv() {
+        // Every macropixel of the small frame holds its own column and row
+        let (width, height) = (WIDTH / 2, HEIGHT / 2);
+        let mut small = Vec::with_capacity(width * height * 2);
+        for y in 0..height {
+            for x in (0..width).step_by(2) {
+                small.extend_from_slice(&[x as u8, y as u8, (x + 1) as u8, 200]);
+            }
+        }
+        let scaled = FrameEncoder::scale_yuyv(&small, width, height);
+        assert_eq!(scaled.len(), WIDTH * HEIGHT * 2);
+        // Second row of the second macropixel, both pixels taken from column 1
+        let at = (WIDTH + 2) * 2;
+        assert_eq!(&scaled[at..at + 4], &[1, 0, 1, 200]);
+        assert_eq!(scaled[scaled.len() - 3], (height - 1) as u8);
+    }
+    /// The size `decoder` gets the frames of `encoder` in, a grey frame of `width` x `height` encoded
+    fn encoded_size(
+        encoder: &mut FrameEncoder,
+        decoder: &mut dyn VideoDecoder,
+        (width, height): (usize, usize),
+    ) -> (usize, usize) {
+        let (encoded, _) = encoder
+            .encode(&[128; 2].repeat(width * height), false)
+            .unwrap();
+        let mut size = (0, 0);
+        for unit in openh264::nal_units(&encoded) {
+            if let Ok(Some(picture)) = decoder.decode(unit) {
+                size = picture.dimensions();
+            }
+        }
+        size
+    }
+    #[test]
+    fn test_encoded_in_picked_mode() {
+        let mode = |width, height| VideoMode {
+            width,
+            height,
+            fps: 30,
+        };
+        let modes = [mode(1280, 720), mode(640, 480), mode(320, 240)];
+        // Only the smallest mode fits the budget, the stream is as small
+        let picked = pick_mode(&modes, 300).unwrap();
+        assert_eq!(picked, mode(320, 240));
+        let size = (picked.width.into(), picked.height.into());
+        let mut encoder =
+            FrameEncoder::new(VideoEncoding::H264, CodecBackend::OpenH264, size).unwrap();
+        let mut decoder = open_decoder(VideoEncoding::H264, CodecBackend::OpenH264).unwrap();
+        assert_eq!(
+            encoded_size(&mut encoder, decoder.as_mut(), size),
+            (320, 240)
+        );
+        // A frame of another size isn't taken for one of the mode
+        assert!(encoder.encode(&[128; 64], false).is_err());
+    }
+    #[test]
+    fn test_encoded_size_follows_mode() {
+        let mode = |width, height| VideoMode {
+            width,
+            height,
+            fps: 30,
+        };
+        let modes = [mode(1280, 720), mode(640, 480), mode(320, 240)];
+        // The peer keeps its decoder across the switches, as it does during a call
+        let mut decoder = open_decoder(VideoEncoding::H264, CodecBackend::OpenH264).unwrap();
+        for (quality, expected) in [
+            (VideoQuality::High, (1280, 720)),
+            (VideoQuality::Low, (320, 240)),
+            (VideoQuality::Medium, (640, 480)),
+        ] {
+            let picked = quality.preset().unwrap().pick_mode(&modes).unwrap();
+            // A switch opens the camera again, with an encoder in the size of the new mode
+            let size = (picked.width.into(), picked.height.into());
+            let mut encoder =
+                FrameEncoder::new(VideoEncoding::H264, CodecBackend::OpenH264, size).unwrap();
+            assert_eq!(
+                encoded_size(&mut encoder, decoder.as_mut(), size),
+                expected,
+                "{quality}"
+            );
+        }
+    }
+    #[test]
+    fn test_rgba_frame() {
+        let mut frame = RgbaFrame::blank();
+        assert!(!frame.resize(WIDTH, HEIGHT));
+        // Every pixel of the large frame holds its own column and row
+        let (width, height) = (WIDTH * 2, HEIGHT * 2);
+        assert!(frame.resize(width, height));
+        assert_eq!(frame.data.len(), width * height * 4);
+        for (at, pixel) in frame.data.chunks_exact_mut(4).enumerate() {
+            pixel.copy_from_slice(&[(at % width) as u8, (at / width) as u8, 0, 255]);
+        }
+        assert_eq!(frame.scaled(width, height).as_ref(), frame.data.as_slice());
+        let scaled = frame.scaled(WIDTH, HEIGHT);
+        assert_eq!(scaled.len(), WIDTH * HEIGHT * 4);
+        // Second pixel of the second row, taken from column 2 of row 2
+        let at = (WIDTH + 1) * 4;
+        assert_eq!(&scaled[at..at + 4], &[2, 2, 0, 255]);
+        assert!(scaled.chunks_exact(4).all(|pixel| pixel[3] == 255));
+    }
+    #[test]
+    fn test_frame_encoding() {
+        let device = Device::new(0).unwrap();
+        let format = v4l::Format::new(WIDTH as u32, HEIGHT as u32, FOURCC);
+        device.set_format(&format).unwrap();
+
+        let mut stream = H264Stream::new(&device).unwrap();
+        let buf = stream.next_vec().unwrap();
+
+        assert!(!buf.is_empty(), "Buffer is empty after encoding");
+        assert!(
+            buf.starts_with(&[0x00, 0x00, 0x00, 0x01]) || buf.starts_with(&[0x00, 0x00, 0x01]),
+            "Encoded frame does not start with a valid H264 NAL unit start code"
+        );
+    }
+    #[test]
+    fn test_frame_decoding() {
+        // encoded h264 stream
+        let bytes = include_bytes!("../test.h264");
+        let mut decoder = Decoder::new().unwrap();
+
+        let mut frame_ref: [u8; WIDTH * HEIGHT * 4] = [0; WIDTH * HEIGHT * 4];
+        let mut accumulated_data: Vec<u8> = Vec::new(); // Buffer to accumulate NAL units
+
+        // Flags to track if SPS/PPS have been processed
+        // Iterate over NAL units
+        for unit in openh264::nal_units(bytes) {
+            // Accumulate NAL units
+            accumulated_data.extend_from_slice(unit);
+
+            // Only start decoding after both SPS and PPS have been processed
+            match decoder.decode(&accumulated_data) {
+                Ok(Some(frame)) => {
+                    if frame_ref.is_empty() {
+                        frame.write_rgba8(&mut frame_ref);
+                    }
+                    accumulated_data.clear(); // Clear accumulated data after successful decode
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    panic!("Decoder error: {:?}", e);
+                }
+            }
+        }
+        assert!(
+            !frame_ref.is_empty(),
+            "Couldn't recover even one frame from the stream."
+        );
+    }
+    #[test]
+    fn test_join_with_timeout() {
+        let quick = std::thread::spawn(|| ());
+        assert!(join_with_timeout(quick, Duration::from_secs(1)).is_ok());
+
+        let stuck = std::thread::spawn(|| std::thread::sleep(Duration::from_secs(2)));
+        assert!(join_with_timeout(stuck, Duration::from_millis(50)).is_err());
+    }
+    #[test]
+    fn test_incoming_stream_shutdown() {
+        let controls =
+            init_incoming_h264_stream(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), None)
+                .unwrap();
+        // Nothing to show yet
+        assert_eq!(controls.last_frame_sequence(), None);
+        assert_eq!(controls.last_packet_age(), None);
+        assert!(!controls.take_new_frame());
+        assert!(!controls.is_frozen());
+        let start = Instant::now();
+        controls.shutdown().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+    #[test]
+    fn test_freeze_detection() {
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert!(!is_frozen(ms(40), ms(10)));
+        assert!(is_frozen(ms(1500), ms(10)));
+        // Nothing comes at all, the peer paused or the connection is lost
+        assert!(!is_frozen(ms(1500), ms(1500)));
+        // Still waiting for the first frame
+        assert!(!is_frozen(None, ms(10)));
+    }
+    #[test]
+    fn test_decode_queue_drops_oldest() {
+        let queue = DecodeQueue::new(2);
+        assert!(!queue.push(vec![1]));
+        assert!(!queue.push(vec![2]));
+        assert!(queue.push(vec![3]));
+        let pop = || queue.pop(Duration::ZERO).map(|(unit, _)| unit);
+        assert_eq!(pop(), Some(vec![2]));
+        assert_eq!(pop(), Some(vec![3]));
+
+        // A closed queue doesn't keep the decoder waiting
+        queue.close();
+        let start = Instant::now();
+        assert_eq!(queue.pop(Duration::from_secs(1)), None);
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+    #[test]
+    fn test_incoming_stream_prebuffers_expected_peer() {
+        let dir = std::env::temp_dir().join(format!("eye-spy-prebuffer-{}", std::process::id()));
+        let mut controls = init_incoming_h264_stream(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            Some(dir.clone()),
+        )
+        .unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let units = synthetic_nal_units(2);
+        let packets = packetize_stream(&units);
+        let send = |i: usize| {
+            for packet in &packets[i] {
+                sender.send_to(packet, controls.address).unwrap();
+            }
+        };
+        controls.expect(Ipv4Addr::LOCALHOST.into());
+        std::thread::sleep(Duration::from_millis(150));
+        // Too old by the time the peer is accepted
+        send(0);
+        std::thread::sleep(Duration::from_millis(1200));
+        send(1);
+        std::thread::sleep(Duration::from_millis(100));
+        controls.set_session_id(TEST_SESSION);
+        controls.accept(sender.local_addr().unwrap()).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        controls.shutdown().unwrap();
+
+        let dump = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "h264"))
+            .map(|path| std::fs::read(path).unwrap())
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dumped = |unit: &[u8]| dump.windows(unit.len()).any(|w| w == unit);
+        assert!(!dumped(&units[0]));
+        assert!(dumped(&units[1]));
+    }
+    #[test]
+    fn test_outgoing_stream_fan_out() {
+        let receiver = || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            socket
+        };
+        let (full, base) = (receiver(), receiver());
+        let replay = concat!(env!("CARGO_MANIFEST_DIR"), "/test.h264");
+        let mut controls = init_h264_video_stream(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            VideoBackend::File(replay.into()),
+            None,
+        )
+        .unwrap();
+        // Only a camera can switch modes
+        let mode = VideoMode {
+            width: 640,
+            height: 480,
+            fps: 30,
+        };
+        assert!(controls.reconfigure(mode).is_err());
+        assert_eq!(controls.video_mode(), None);
+        controls.connect(full.local_addr().unwrap());
+        controls.add_destination(Destination {
+            addr: base.local_addr().unwrap(),
+            layer: StreamLayer::Base,
+        });
+
+        let mut buf = [0; 65535];
+        assert!(full.recv(&mut buf).is_ok());
+        assert!(base.recv(&mut buf).is_ok());
+
+        controls.remove_destination(base.local_addr().unwrap());
+        // Drain what was sent before the removal
+        std::thread::sleep(Duration::from_millis(100));
+        while base.recv(&mut buf).is_ok() {}
+        assert!(full.recv(&mut buf).is_ok());
+        controls.shutdown().unwrap();
+    }
+    /// Source of empty frames counting the keyframes forced
+    struct KeyframeCounter(Arc<AtomicU32>);
+    impl VideoSource for KeyframeCounter {
+        fn next_layers(&mut self, with_base: bool) -> anyhow::Result<LayeredFrame> {
+            let full = vec![0, 0, 0, 1, 0x41];
+            Ok(LayeredFrame {
+                base: with_base.then(|| full.clone()),
+                full,
+                captured_at: Instant::now(),
+            })
+        }
+        fn force_intra_frame(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    #[test]
+    fn test_outgoing_stream_keyframes() {
+        let forced = Arc::new(AtomicU32::new(0));
+        let forced_clone = Arc::clone(&forced);
+        let backend = VideoBackend::Custom(Arc::new(move || {
+            Ok(Box::new(KeyframeCounter(Arc::clone(&forced_clone))) as Box<dyn VideoSource>)
+        }));
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut controls = init_h264_video_stream(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            backend,
+            None,
+        )
+        .unwrap();
+        controls.set_keyframe_interval(None);
+        controls.connect(receiver.local_addr().unwrap());
+        std::thread::sleep(Duration::from_millis(200));
+        // Only the one the peer joined with
+        assert_eq!(forced.load(Ordering::Relaxed), 1);
+
+        controls.pause();
+        std::thread::sleep(Duration::from_millis(100));
+        controls.unpause();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(forced.load(Ordering::Relaxed), 2);
+
+        // A frame every 30 ms, a keyframe every other one
+        controls.set_keyframe_interval(Some(2));
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(forced.load(Ordering::Relaxed) >= 4);
+        controls.shutdown().unwrap();
+    }
+    /// Source of empty frames keeping count of the sources open
+    struct OpenSource(Arc<AtomicU32>);
+    impl VideoSource for OpenSource {
+        fn next_layers(&mut self, with_base: bool) -> anyhow::Result<LayeredFrame> {
+            let full = vec![0, 0, 0, 1, 0x41];
+            Ok(LayeredFrame {
+                base: with_base.then(|| full.clone()),
+                full,
+                captured_at: Instant::now(),
+            })
+        }
+        fn force_intra_frame(&mut self) {}
+    }
+    impl Drop for OpenSource {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    #[test]
+    fn test_outgoing_stream_privacy() {
+        let open = Arc::new(AtomicU32::new(0));
+        let open_clone = Arc::clone(&open);
+        let backend = VideoBackend::Custom(Arc::new(move || {
+            open_clone.fetch_add(1, Ordering::Relaxed);
+            Ok(Box::new(OpenSource(Arc::clone(&open_clone))) as Box<dyn VideoSource>)
+        }));
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut controls = init_h264_video_stream(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            backend,
+            None,
+        )
+        .unwrap();
+        // Not opened before a call, nor during one in privacy mode
+        controls.set_privacy(true);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(open.load(Ordering::Relaxed), 0);
+        controls.connect(receiver.local_addr().unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(open.load(Ordering::Relaxed), 0);
+
+        let mut buf = [0; 65535];
+        controls.set_privacy(false);
+        assert!(receiver.recv(&mut buf).is_ok());
+        assert_eq!(open.load(Ordering::Relaxed), 1);
+
+        controls.set_privacy(true);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(open.load(Ordering::Relaxed), 0);
+        while receiver.recv(&mut buf).is_ok() {}
+        assert!(controls.privacy());
+        controls.shutdown().unwrap();
+    }
+}
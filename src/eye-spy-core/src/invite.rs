@@ -0,0 +1,150 @@
+//! Invites, everything needed to call someone in one line of text,
+//! i.e. `eyespy://192.168.1.12:60102?name=Alice`.
+//! They can be shared over any other channel, for peers mDNS doesn't find.
+//! An IPv6 link-local address names the interface, i.e. `eyespy://[fe80::1%wlan0]:60102`,
+//! which only means something on the machine the invite is for, see `scp_client::link_local`.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use scp_client::link_local;
+
+/// What every invite starts with
+pub const SCHEME: &str = "eyespy://";
+
+/// An invite to call the peer that made it
+/// * `addr` - where the SCP client of the peer listens
+/// * `name` - what the peer calls itself, only shown to the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub addr: SocketAddr,
+    pub name: Option<String>,
+}
+
+impl Invite {
+    /// Whether `text` looks like an invite rather than, say, a path
+    pub fn is_invite(text: &str) -> bool {
+        text.trim()
+            .get(..SCHEME.len())
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case(SCHEME))
+    }
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SCHEME}{}", self.addr)?;
+        if let Some(ref name) = self.name {
+            write!(f, "?name=")?;
+            // Percent-encode everything but the unreserved characters of RFC 3986
+            for byte in name.bytes() {
+                match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                        write!(f, "{}", byte as char)?;
+                    }
+                    _ => write!(f, "%{byte:02X}")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Invite {
+    type Err = anyhow::Error;
+
+    /// Parses an invite, surrounding whitespace and unknown parameters are ignored
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let text = text.trim();
+        if !Self::is_invite(text) {
+            bail!("An invite starts with {SCHEME}");
+        }
+        let text = &text[SCHEME.len()..];
+        let (addr, query) = text.split_once('?').unwrap_or((text, ""));
+        let addr = addr.trim_end_matches('/');
+        let addr = link_local::parse_addr(addr)
+            .with_context(|| format!("Invalid address in the invite: {addr}"))?;
+
+        let mut name = None;
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            if key == "name" {
+                name = Some(percent_decode(value)?).filter(|name| !name.is_empty());
+            }
+        }
+        Ok(Self { addr, name })
+    }
+}
+
+fn percent_decode(text: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [iter.next(), iter.next()];
+                let decoded = match hex {
+                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                let decoded =
+                    decoded.ok_or_else(|| anyhow!("Invalid escape in the invite: {text}"))?;
+                bytes.push(decoded);
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).context("The name in the invite isn't UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Invite;
+
+    #[test]
+    fn test_invite_round_trip() {
+        let invite = Invite {
+            addr: "192.168.1.12:60102".parse().unwrap(),
+            name: Some("Alice".into()),
+        };
+        assert_eq!(invite.to_string(), "eyespy://192.168.1.12:60102?name=Alice");
+        assert_eq!(invite.to_string().parse::<Invite>().unwrap(), invite);
+
+        let invite = Invite {
+            addr: "[fe80::1]:7000".parse().unwrap(),
+            name: Some("Żaneta & Bob".into()),
+        };
+        assert_eq!(
+            invite.to_string(),
+            "eyespy://[fe80::1]:7000?name=%C5%BBaneta%20%26%20Bob"
+        );
+        assert_eq!(invite.to_string().parse::<Invite>().unwrap(), invite);
+    }
+    #[test]
+    fn test_invite_parsing() {
+        let invite: Invite = " EyeSpy://10.0.0.2:5000/?v=2&name=Bob+B \n"
+            .parse()
+            .unwrap();
+        assert_eq!(invite.addr, "10.0.0.2:5000".parse().unwrap());
+        assert_eq!(invite.name.as_deref(), Some("Bob B"));
+        assert_eq!(
+            "eyespy://10.0.0.2:5000".parse::<Invite>().unwrap().name,
+            None
+        );
+
+        // The interface of a link-local address, by its index or its name
+        let scoped: Invite = "eyespy://[fe80::1%3]:7000".parse().unwrap();
+        assert_eq!(scoped.addr, "[fe80::1%3]:7000".parse().unwrap());
+        assert_eq!(scoped.to_string().parse::<Invite>().unwrap(), scoped);
+        assert!("eyespy://[fe80::1%no-such-interface]:7000"
+            .parse::<Invite>()
+            .is_err());
+
+        assert!("10.0.0.2:5000".parse::<Invite>().is_err());
+        assert!("eyespy://10.0.0.2".parse::<Invite>().is_err());
+        assert!("eyespy://10.0.0.2:5000?name=%G1".parse::<Invite>().is_err());
+    }
+}
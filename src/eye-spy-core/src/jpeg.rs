@@ -0,0 +1,149 @@
+//! The JPEGs of the still images sent over SCP, see `crate::still`, through the `image` crate.
+//! They're baseline JFIF in the full range, so any viewer shows them as they are.
+//! The frames of the camera are turned into RGB first, with the BT.601 of `crate::codec`.
+
+use std::io::Cursor;
+
+use anyhow::bail;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ExtendedColorType, ImageFormat, ImageReader, Limits, RgbaImage};
+
+use crate::codec::{yuv_to_rgb, DecodedPicture};
+
+/// Largest width and height of the images taken from the peers, larger ones aren't decoded
+const MAX_SIDE: u32 = 4096;
+
+/// Encode a frame as the camera captures it, YUYV, at `quality` from 1 to 100
+/// # Errors
+/// Returns an error if `yuyv` isn't a frame of `width` x `height`, or it cannot be encoded
+pub(crate) fn encode_yuyv(
+    yuyv: &[u8],
+    (width, height): (usize, usize),
+    quality: u8,
+) -> anyhow::Result<Vec<u8>> {
+    if width % 2 != 0 || yuyv.len() < width * height * 2 {
+        bail!("Not a YUYV frame of {width}x{height}");
+    }
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for pixels in yuyv[..width * height * 2].chunks_exact(4) {
+        let &[y0, u, y1, v] = pixels else {
+            unreachable!()
+        };
+        rgb.extend(yuv_to_rgb(y0, u, v));
+        rgb.extend(yuv_to_rgb(y1, u, v));
+    }
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality.clamp(1, 100)).encode(
+        &rgb,
+        u32::try_from(width)?,
+        u32::try_from(height)?,
+        ExtendedColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
+
+/// A decoded JPEG
+pub(crate) struct JpegPicture(RgbaImage);
+
+impl DecodedPicture for JpegPicture {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.0.width() as usize, self.0.height() as usize)
+    }
+    fn write_rgba8(&self, target: &mut [u8]) {
+        let len = target.len().min(self.0.len());
+        target[..len].copy_from_slice(&self.0.as_raw()[..len]);
+    }
+}
+
+/// Decode a JPEG of a peer
+/// # Errors
+/// Returns an error if the image is broken, isn't a JPEG or is larger than `MAX_SIDE`
+pub(crate) fn decode(jpeg: &[u8]) -> anyhow::Result<JpegPicture> {
+    let mut reader = ImageReader::with_format(Cursor::new(jpeg), ImageFormat::Jpeg);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SIDE);
+    limits.max_image_height = Some(MAX_SIDE);
+    reader.limits(limits);
+    Ok(JpegPicture(reader.decode()?.into_rgba8()))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::ExtendedColorType;
+    use quickcheck::quickcheck;
+
+    use super::{decode, encode_yuyv, MAX_SIDE};
+    use crate::codec::DecodedPicture;
+
+    /// A YUYV frame of a gradient with some texture
+    fn frame(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| {
+                let y = (40 + (i % width) * 3 + (i / width) * 2 + (i % 7) * 4) as u8;
+                let chroma = if i % 2 == 0 {
+                    90 + i % 40
+                } else {
+                    170 - i % 30
+                };
+                [y, chroma as u8]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_yuyv() {
+        // A flat grey frame stays grey
+        let yuyv = [128, 128, 128, 128].repeat(8 * 6);
+        let jpeg = encode_yuyv(&yuyv, (16, 6), 75).unwrap();
+        // JFIF
+        assert_eq!(jpeg[..4], [0xFF, 0xD8, 0xFF, 0xE0]);
+        assert_eq!(jpeg[6..11], *b"JFIF\0");
+        let picture = decode(&jpeg).unwrap();
+        assert_eq!(picture.dimensions(), (16, 6));
+        let mut rgba = vec![0; 16 * 6 * 4];
+        picture.write_rgba8(&mut rgba);
+        assert!(rgba
+            .chunks_exact(4)
+            .all(|p| p[..3].iter().all(|&c| c.abs_diff(130) <= 1) && p[3] == 255));
+        assert!(encode_yuyv(&yuyv, (16, 8), 75).is_err());
+        assert!(encode_yuyv(&yuyv, (15, 6), 75).is_err());
+    }
+    #[test]
+    fn test_quality() {
+        let yuyv = frame(40, 24);
+        let good = encode_yuyv(&yuyv, (40, 24), 90).unwrap();
+        let small = encode_yuyv(&yuyv, (40, 24), 20).unwrap();
+        // Less quality, fewer bytes
+        assert!(small.len() < good.len());
+        assert_eq!(decode(&small).unwrap().dimensions(), (40, 24));
+    }
+    #[test]
+    fn test_refused() {
+        assert!(decode(b"GIF89a").is_err());
+        assert!(decode(&[0xFF, 0xD8]).is_err());
+        // Too large, refused before the pixels are decoded
+        let side = MAX_SIDE + 8;
+        let mut large = Vec::new();
+        JpegEncoder::new(&mut large)
+            .encode(&vec![0; side as usize * 8], side, 8, ExtendedColorType::L8)
+            .unwrap();
+        assert!(decode(&large).is_err());
+    }
+    #[test]
+    fn test_broken_jpegs() {
+        /// Whatever the peer sends, the JPEG decodes or is refused, without panicking
+        fn prop(cut: usize, flips: Vec<(usize, u8)>) -> bool {
+            let mut jpeg = encode_yuyv(&frame(16, 16), (16, 16), 50).unwrap();
+            jpeg.truncate(cut % (jpeg.len() + 1));
+            for (i, bits) in flips {
+                if let Some(byte) = jpeg.get_mut(i % 400) {
+                    *byte ^= bits;
+                }
+            }
+            let _ = decode(&jpeg);
+            true
+        }
+        quickcheck(prop as fn(usize, Vec<(usize, u8)>) -> bool);
+    }
+}
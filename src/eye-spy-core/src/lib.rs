@@ -0,0 +1,86 @@
+//! The calling part of eye-spy, without any frontend.
+//! Everything a call needs runs on its own threads: the SCP client negotiating the sessions,
+//! mDNS discovery and the H.264 streams in both directions. Frontends only drive it.
+//!
+//! * `CallManager` - places, answers and ends calls, starting and stopping the streams with them.
+//!   Incoming calls can be answered without the frontend, see `AutoAnswer`
+//! * `VideoSource` - where the outgoing video comes from, a camera by default
+//! * `CameraControls` - brightness, exposure and the like of the camera, see `camera`
+//! * `VideoEncoder`, `VideoDecoder` - the backends the video is encoded and decoded with,
+//!   x264 with the `x264` feature, VP8 with `vp8` and AV1 with `av1`, see `codec`
+//! * `FrameFilter` - changes the camera frames before they are encoded, i.e. `BackgroundBlur`
+//! * `MotionDetector` - tells when something moves in front of the camera, see `motion`
+//! * `FrameSink` - where the decoded incoming frames go, besides `h264_stream::RGB_FRAME_BUFFER`
+//! * `RecordingConfig` - where and how the calls are recorded, see `recording`
+//! * `AudioChannels` - whether the call audio is mono or stereo, see `audio`
+//! * `PipelineProfile` - how long the frames take through the streams, see `profile`
+//! * `VideoQuality` - the quality of the outgoing video the user picks, see `quality`
+//! * `DataUsage` - how much data the calls used, this month too, see `usage`
+//! * `SocketTuning` - buffers and DSCP marks of the stream sockets, see `qos`
+//! * `RtpConfig` - republishing a stream as RTP for the other players, see `rtp`
+//! * `VirtualCamera` - the peer's video as a webcam for the other apps, see `virtual_camera`
+//! * `Invite` - a line of text to call this client with, shared outside the app
+//! * `StillFallback` - still images sent over SCP while the video doesn't get through, see `still`
+//! * `BrowserBridge` - browsers joining the calls over WebRTC, with the `webrtc` feature, see `browser`
+//!
+//! The streams and the SCP client can still be used on their own, see the modules.
+
+pub mod audio;
+#[cfg(feature = "av1")]
+mod av1;
+pub mod av_sync;
+mod bandwidth;
+pub mod beacon;
+pub mod bitstream;
+#[cfg(feature = "webrtc")]
+pub mod browser;
+mod call;
+pub mod camera;
+pub mod codec;
+pub mod filter;
+pub mod h264_stream;
+mod invite;
+mod jpeg;
+pub mod mdns;
+pub mod metrics;
+pub mod motion;
+mod mp4;
+mod mtu;
+pub mod nal;
+mod pacer;
+pub mod profile;
+pub mod qos;
+pub mod quality;
+pub mod recording;
+pub mod rtp;
+pub mod srtp;
+pub mod static_peers;
+pub mod stats;
+pub mod still;
+mod udp_batch;
+pub mod usage;
+pub mod virtual_camera;
+#[cfg(feature = "vp8")]
+mod vpx;
+#[cfg(feature = "x264")]
+mod x264;
+
+pub use audio::AudioChannels;
+#[cfg(feature = "webrtc")]
+pub use browser::BrowserBridge;
+pub use call::{AutoAnswer, CallConfig, CallEvent, CallManager};
+pub use codec::{CodecBackend, VideoDecoder, VideoEncoder, VideoEncoding};
+pub use filter::FrameFilter;
+pub use h264_stream::incoming::FrameSink;
+pub use h264_stream::outgoing::VideoSource;
+pub use invite::Invite;
+pub use motion::{MotionDetector, MotionMode};
+pub use profile::PipelineProfile;
+pub use qos::{Dscp, SocketTuning};
+pub use quality::VideoQuality;
+pub use recording::{RecordingConfig, RecordingLayout};
+pub use rtp::RtpConfig;
+pub use scp_client;
+pub use still::StillFallback;
+pub use usage::{DataUsage, MonthlyCap};
+pub use virtual_camera::VirtualCamera;
@@ -0,0 +1,538 @@
+//! This module manages recognition and connections with other apps using mDNS and SCP.
+//! A client can be advertised with several profiles, i.e. a work and a home one, or under
+//! a service of its own, so test clients don't show up among the others, see `Advertisement`.
+//!
+//! The TXT record of a client tells the others what they need before calling it, see `TxtRecord`.
+//! The peers found are parsed into `DiscoveredPeer`s, the fields they don't know are skipped
+//! and the ones they lack left out, so clients of other versions still find each other.
+
+use anyhow::Context;
+use get_if_addrs::get_if_addrs;
+use lazy_static::lazy_static;
+use mdns_sd::ServiceDaemon;
+pub use mdns_sd::ServiceInfo;
+use scp_client::scp::PROTOCOL_VERSION;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::beacon::{self, Beacon};
+
+/// Service the clients are advertised as and looked for, unless told otherwise
+pub const DEFAULT_SERVICE_NAME: &str = "_eye-spy._tcp.local.";
+
+lazy_static! {
+    pub static ref MDNS: ServiceDaemon = ServiceDaemon::new().expect("Failed to create daemon");
+}
+
+fn get_local_ip() -> Option<IpAddr> {
+    let interfaces = get_if_addrs().expect("Failed to get network interfaces");
+
+    for iface in interfaces {
+        if !iface.is_loopback() {
+            if let IpAddr::V4(ipv4) = iface.ip() {
+                return Some(IpAddr::V4(ipv4));
+            }
+        }
+    }
+
+    None
+}
+
+/// How this client is advertised. Every field but the service falls back to what the client has
+/// * `service` - service type, i.e. `_eye-spy-dev._tcp.local.` for a test cluster
+/// * `instance` - appended to the mDNS instance name
+/// * `host_name` - host name to advertise, `<ip>.local.` of the first interface that isn't loopback if None
+/// * `port` - SCP port to advertise, i.e. one forwarded to the client. The one it listens on if None
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advertisement {
+    #[serde(default = "default_service")]
+    pub service: String,
+    #[serde(default)]
+    pub instance: Option<String>,
+    #[serde(default)]
+    pub host_name: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+impl Default for Advertisement {
+    fn default() -> Self {
+        Self {
+            service: default_service(),
+            instance: None,
+            host_name: None,
+            port: None,
+        }
+    }
+}
+
+fn default_service() -> String {
+    DEFAULT_SERVICE_NAME.to_string()
+}
+
+/// Loads the advertisement profiles stored at `path`, a JSON object of them by name, i.e.
+/// `{"work": {}, "home": {"service": "_eye-spy-home._tcp.local.", "instance": "den"}}`.
+/// None if the file doesn't exist
+/// # Errors
+/// Returns an error if the file exists but cannot be read or parsed
+pub fn load_profiles(path: &Path) -> anyhow::Result<BTreeMap<String, Advertisement>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Services the profiles are advertised as, each once. The default one if there's no profile
+pub fn services(profiles: &[Advertisement]) -> Vec<String> {
+    let mut services: Vec<String> = Vec::new();
+    for profile in profiles {
+        if !services.contains(&profile.service) {
+            services.push(profile.service.clone());
+        }
+    }
+    if services.is_empty() {
+        services.push(default_service());
+    }
+    services
+}
+
+/// What a client can do besides a plain call, advertised as a comma separated `caps`, i.e. `screen,srtp`.
+/// Unknown ones are skipped, so newer clients can add theirs
+/// * `screen` - shares a screen along with the video
+/// * `srtp` - protects the streams, see `crate::srtp`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub screen: bool,
+    pub srtp: bool,
+}
+
+impl Capabilities {
+    fn parse(caps: &str) -> Self {
+        let mut parsed = Self::default();
+        for cap in caps.split(',').map(str::trim) {
+            match cap {
+                "screen" => parsed.screen = true,
+                "srtp" => parsed.srtp = true,
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let caps: Vec<&str> = [(self.screen, "screen"), (self.srtp, "srtp")]
+            .into_iter()
+            .filter_map(|(on, cap)| on.then_some(cap))
+            .collect();
+        write!(f, "{}", caps.join(","))
+    }
+}
+
+/// The TXT record of a client. Every field is optional to the parser, clients predating
+/// the schema only advertised `scp_port` and `in_call`
+/// * `proto_ver` - SCP protocol version the client speaks, see `scp_client::scp::PROTOCOL_VERSION`.
+///   None from clients predating the schema
+/// * `scp_port` - port the ScpClient listens on, the service port if None
+/// * `name` - name the client goes by, its instance
+/// * `caps` - see `Capabilities`
+/// * `in_call` - the client is in a call, so it's busy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxtRecord {
+    pub proto_ver: Option<u16>,
+    pub scp_port: Option<u16>,
+    pub name: Option<String>,
+    pub caps: Capabilities,
+    pub in_call: bool,
+}
+
+impl TxtRecord {
+    /// The record of a found service. Values that don't parse are taken as missing
+    pub fn parse(info: &ServiceInfo) -> Self {
+        Self::from_fields(|key| info.get_property_val_str(key))
+    }
+    /// The record out of its fields by key, i.e. the ones of a beacon, see `crate::beacon`
+    pub(crate) fn from_fields<'a>(field: impl Fn(&str) -> Option<&'a str>) -> Self {
+        Self {
+            proto_ver: field("proto_ver").and_then(|ver| ver.parse().ok()),
+            scp_port: field("scp_port").and_then(|port| port.parse().ok()),
+            name: field("name")
+                .filter(|name| !name.is_empty())
+                .map(str::to_string),
+            caps: field("caps").map(Capabilities::parse).unwrap_or_default(),
+            in_call: field("in_call").is_some_and(|in_call| in_call == "true"),
+        }
+    }
+    pub(crate) fn properties(&self) -> Vec<(&'static str, String)> {
+        let mut properties = vec![
+            ("caps", self.caps.to_string()),
+            ("in_call", self.in_call.to_string()),
+        ];
+        if let Some(ver) = self.proto_ver {
+            properties.push(("proto_ver", ver.to_string()));
+        }
+        if let Some(port) = self.scp_port {
+            properties.push(("scp_port", port.to_string()));
+        }
+        if let Some(ref name) = self.name {
+            properties.push(("name", name.clone()));
+        }
+        properties
+    }
+}
+
+/// A client found over mDNS, or by its beacon
+/// * `host_name` - the host it advertised, i.e. `192.168.1.12.local.`
+/// * `addrs` - where its ScpClient listens, one for every IPv4 address it advertised
+/// * `txt` - what it advertised about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    pub host_name: String,
+    pub addrs: Vec<SocketAddr>,
+    pub txt: TxtRecord,
+}
+
+impl DiscoveredPeer {
+    pub fn from_info(info: &ServiceInfo) -> Self {
+        let txt = TxtRecord::parse(info);
+        let port = txt.scp_port.unwrap_or_else(|| info.get_port());
+        let mut addrs: Vec<SocketAddr> = info
+            .get_addresses_v4()
+            .into_iter()
+            .map(|ip| SocketAddr::new(IpAddr::V4(*ip), port))
+            .collect();
+        addrs.sort();
+        Self {
+            host_name: info.get_hostname().to_string(),
+            addrs,
+            txt,
+        }
+    }
+    /// The name the peer goes by, its host name if it has none
+    pub fn name(&self) -> &str {
+        self.txt.name.as_deref().unwrap_or(&self.host_name)
+    }
+}
+
+/// A profile advertised by `start_service`, kept to update its TXT record
+/// * `beacon` - the profile broadcast for the networks without multicast, see `start_beacon`
+pub struct Registration {
+    service: String,
+    instance_name: String,
+    host_name: String,
+    ip: IpAddr,
+    port: u16,
+    txt: TxtRecord,
+    beacon: Option<Beacon>,
+}
+
+impl Registration {
+    /// Tell the peers whether this client is in a call, see `TxtRecord::in_call`
+    /// # Errors
+    /// Returns an error if the record cannot be advertised again
+    pub fn set_in_call(&mut self, in_call: bool) -> anyhow::Result<()> {
+        if self.txt.in_call == in_call {
+            return Ok(());
+        }
+        self.txt.in_call = in_call;
+        if let Some(ref beacon) = self.beacon {
+            beacon.set_payload(self.beacon_payload());
+        }
+        self.register()
+    }
+    /// Also broadcast the profile as a beacon to `port`, see `crate::beacon`
+    /// # Errors
+    /// Returns an error if the beacon cannot be broadcast
+    pub fn start_beacon(&mut self, port: u16) -> anyhow::Result<()> {
+        self.beacon = Some(Beacon::start(port, self.beacon_payload())?);
+        Ok(())
+    }
+    fn beacon_payload(&self) -> Vec<u8> {
+        beacon::encode(&self.service, &self.host_name, &self.txt)
+    }
+    /// Advertises the record, replacing the one advertised before
+    fn register(&self) -> anyhow::Result<()> {
+        let my_service = ServiceInfo::new(
+            &self.service,
+            &self.instance_name,
+            &self.host_name,
+            self.ip,
+            self.port,
+            &self.txt.properties()[..],
+        )
+        .with_context(|| format!("Cannot advertise as {}", self.service))?;
+        MDNS.register(my_service)
+            .context("Failed to register our service")?;
+        Ok(())
+    }
+}
+
+/// Starts the mDNS service at this machine, once for every profile.
+/// It should be run once at the start somewhere in main()
+/// * `advertisement` - the profile to advertise with, its instance is the name of the client
+/// * `scp_port` - port the ScpClient listens on, advertised as the service port
+/// * `caps` - what the client can do, see `Capabilities`
+/// # Errors
+/// Returns an error if there's no network interface to advertise on, or the service isn't valid
+pub fn start_service(
+    advertisement: &Advertisement,
+    scp_port: u16,
+    caps: Capabilities,
+) -> anyhow::Result<Registration> {
+    let instance_name = match advertisement.instance {
+        Some(ref suffix) => format!("{}-{}", uuid::Uuid::new_v4(), suffix),
+        None => uuid::Uuid::new_v4().to_string(),
+    };
+    let ip = get_local_ip().context("Cannot find a network interface that isn't loopback")?;
+    let host_name = match advertisement.host_name {
+        Some(ref host_name) => host_name.clone(),
+        None => format!("{}.local.", ip),
+    };
+    let port = advertisement.port.unwrap_or(scp_port);
+    let registration = Registration {
+        service: advertisement.service.clone(),
+        instance_name,
+        host_name,
+        ip,
+        port,
+        txt: TxtRecord {
+            proto_ver: Some(PROTOCOL_VERSION),
+            scp_port: Some(port),
+            name: advertisement.instance.clone(),
+            caps,
+            in_call: false,
+        },
+        beacon: None,
+    };
+    registration.register()?;
+    Ok(registration)
+}
+/// Round trip time to the ScpClient listening at `addr`, as long as the TCP handshake takes.
+/// None if it doesn't accept the connection within `timeout`
+pub fn measure_rtt(addr: SocketAddr, timeout: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    let stream = TcpStream::connect_timeout(&addr, timeout)
+        .inspect_err(|e| debug!("Cannot reach {addr}: {e}"))
+        .ok()?;
+    let rtt = start.elapsed();
+    // Nothing is sent, the peer drops the connection as one that never said hello
+    let _ = stream.shutdown(Shutdown::Both);
+    Some(rtt)
+}
+/// Finds all hosts of the mDNS service `service` in the network, see `DEFAULT_SERVICE_NAME`.
+/// With a `beacon_port`, the beacons broadcast to it are listened for meanwhile, and the peers
+/// found by them merged in, see `crate::beacon`
+/// # Blocking
+/// This function blocks the execution until the hosts are found. It has an internal timeout in case something happens.
+pub fn find_all_hosts(service: &str, beacon_port: Option<u16>) -> Vec<DiscoveredPeer> {
+    let beacons = beacon_port.map(|port| {
+        let service = service.to_string();
+        std::thread::spawn(move || beacon::find_all_hosts(&service, port))
+    });
+    let mut hosts = browse(service);
+    if let Some(beacons) = beacons {
+        for peer in beacons.join().unwrap_or_default() {
+            merge_peer(&mut hosts, peer);
+        }
+    }
+    hosts
+}
+
+/// Adds a peer found by its beacon to the ones found over mDNS. The addresses of one
+/// found both ways are merged, it's the same client if it's at the same host and SCP port
+fn merge_peer(peers: &mut Vec<DiscoveredPeer>, peer: DiscoveredPeer) {
+    let same = peers.iter_mut().find(|found| {
+        found.host_name == peer.host_name
+            && found
+                .addrs
+                .iter()
+                .any(|addr| peer.addrs.iter().any(|other| addr.port() == other.port()))
+    });
+    match same {
+        Some(found) => {
+            found.addrs.extend(peer.addrs);
+            found.addrs.sort();
+            found.addrs.dedup();
+        }
+        None => peers.push(peer),
+    }
+}
+
+fn browse(service: &str) -> Vec<DiscoveredPeer> {
+    let Ok(receiver) = MDNS.browse(service) else {
+        warn!("Cannot browse for {service}");
+        return Vec::new();
+    };
+
+    info!("Browsing for mDNS services...");
+    let mut new_hosts = Vec::new();
+
+    // Increase the duration for better discovery in larger networks
+
+    while let Ok(service_event) = receiver.recv_timeout(Duration::from_secs(1)) {
+        // Increased timeout
+
+        match service_event {
+            mdns_sd::ServiceEvent::ServiceResolved(service_info) => {
+                debug!("Resolved service: {:?}", service_info);
+                new_hosts.push(DiscoveredPeer::from_info(&service_info));
+            }
+            mdns_sd::ServiceEvent::SearchStopped(_) => {
+                debug!("Search stopped");
+            }
+            mdns_sd::ServiceEvent::ServiceFound(s, t) => {
+                debug!("Found service: {s}{t}")
+            }
+            _ => (),
+        }
+    }
+    let _ = MDNS.stop_browse(service);
+    new_hosts
+}
+
+#[cfg(test)]
+pub mod mdns_tests {
+    use mdns_sd::DaemonStatus;
+
+    use super::*;
+    #[test]
+    fn test_get_local_ip() {
+        let ip = get_local_ip();
+        assert!(ip.is_some(), "No valid IP address found");
+        assert!(
+            !ip.unwrap().is_loopback(),
+            "get_local_ip should not return a loopback address"
+        );
+    }
+    #[test]
+    fn test_start_service() {
+        let advertisement = Advertisement {
+            service: "_eye-spy-test._tcp.local.".to_string(),
+            instance: Some("test".to_string()),
+            ..Default::default()
+        };
+        let mut registration =
+            start_service(&advertisement, 60102, Capabilities::default()).unwrap();
+        registration.set_in_call(true).unwrap();
+        assert!(MDNS.status().is_ok_and(
+            |v| v.recv_timeout(Duration::from_secs(1)).unwrap() == DaemonStatus::Running
+        ));
+        let invalid = Advertisement {
+            service: "eye-spy".to_string(),
+            ..Default::default()
+        };
+        assert!(start_service(&invalid, 60102, Capabilities::default()).is_err());
+    }
+    #[test]
+    fn test_find_hosts() {
+        find_all_hosts(DEFAULT_SERVICE_NAME, None);
+    }
+    #[test]
+    fn test_profiles() {
+        let path = std::env::temp_dir().join(format!("mdns-profiles-{}", std::process::id()));
+        assert!(load_profiles(&path).unwrap().is_empty());
+        std::fs::write(
+            &path,
+            r#"{"work": {}, "home": {"service": "_eye-spy-home._tcp.local.", "port": 60300},
+                "lab": {"service": "_eye-spy-home._tcp.local.", "instance": "lab"}}"#,
+        )
+        .unwrap();
+        let profiles = load_profiles(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(profiles["work"], Advertisement::default());
+        assert_eq!(profiles["home"].port, Some(60300));
+        let profiles: Vec<_> = profiles.into_values().collect();
+        // In the order of the names
+        assert_eq!(
+            services(&profiles),
+            ["_eye-spy-home._tcp.local.", DEFAULT_SERVICE_NAME]
+        );
+        assert_eq!(services(&[]), [DEFAULT_SERVICE_NAME]);
+    }
+    #[test]
+    fn test_txt_record() {
+        let ip: IpAddr = "192.168.1.12".parse().unwrap();
+        let txt = TxtRecord {
+            proto_ver: Some(PROTOCOL_VERSION),
+            scp_port: Some(60300),
+            name: Some("kitchen".to_string()),
+            caps: Capabilities {
+                screen: false,
+                srtp: true,
+            },
+            in_call: true,
+        };
+        let info = |properties: &[(&str, String)]| {
+            ServiceInfo::new(
+                DEFAULT_SERVICE_NAME,
+                "peer",
+                "192.168.1.12.local.",
+                ip,
+                60201,
+                properties,
+            )
+            .unwrap()
+        };
+        let peer = DiscoveredPeer::from_info(&info(&txt.properties()));
+        assert_eq!(peer.txt, txt);
+        assert_eq!(peer.addrs, [SocketAddr::new(ip, 60300)]);
+        assert_eq!(peer.name(), "kitchen");
+
+        // A client predating the schema, with a capability from the future
+        let old = [
+            ("in_call", "false".to_string()),
+            ("video_port", "7000".to_string()),
+            ("caps", "hologram, screen".to_string()),
+            ("proto_ver", "next".to_string()),
+        ];
+        let peer = DiscoveredPeer::from_info(&info(&old));
+        assert_eq!(
+            peer.txt,
+            TxtRecord {
+                caps: Capabilities {
+                    screen: true,
+                    srtp: false,
+                },
+                ..Default::default()
+            }
+        );
+        assert_eq!(peer.addrs, [SocketAddr::new(ip, 60201)]);
+        assert_eq!(peer.name(), "192.168.1.12.local.");
+    }
+    #[test]
+    fn test_measure_rtt() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let rtt = measure_rtt(addr, Duration::from_secs(1)).unwrap();
+        assert!(rtt < Duration::from_secs(1));
+        drop(listener);
+        assert_eq!(measure_rtt(addr, Duration::from_millis(200)), None);
+    }
+    #[test]
+    fn test_merge_peer() {
+        let peer = |ip: [u8; 4], port| DiscoveredPeer {
+            host_name: "desk.local.".to_string(),
+            addrs: vec![SocketAddr::from((ip, port))],
+            txt: TxtRecord::default(),
+        };
+        let mut peers = vec![peer([192, 168, 1, 12], 60102)];
+        merge_peer(&mut peers, peer([10, 0, 0, 12], 60102));
+        merge_peer(&mut peers, peer([192, 168, 1, 12], 60102));
+        // Another instance on the same machine
+        merge_peer(&mut peers, peer([192, 168, 1, 12], 60103));
+        assert_eq!(peers.len(), 2);
+        assert_eq!(
+            peers[0].addrs,
+            [
+                SocketAddr::from(([10, 0, 0, 12], 60102)),
+                SocketAddr::from(([192, 168, 1, 12], 60102))
+            ]
+        );
+    }
+}
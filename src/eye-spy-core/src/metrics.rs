@@ -0,0 +1,278 @@
+//! Optional HTTP endpoint exposing the stream stats in the Prometheus text format,
+//! for the nodes running without anyone watching them.
+//! Deliberately tiny: it answers `GET /metrics` and nothing else, one connection at a time.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tracing::{debug, info, info_span};
+
+use crate::h264_stream::join_with_timeout;
+use crate::profile::{PipelineProfile, LATENCY_BUCKETS_US};
+use crate::stats::{SharedIncomingStats, SharedOutgoingStats};
+
+/// How long a scraper may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the server checks if it should stop, while nobody connects
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+/// Longest request head read, anything longer is cut off
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Where the exported values come from
+/// * `active_sessions` - calls going on at the moment
+#[derive(Debug, Clone)]
+pub struct MetricsSources {
+    pub outgoing: SharedOutgoingStats,
+    pub incoming: SharedIncomingStats,
+    pub active_sessions: Arc<AtomicU64>,
+}
+
+/// The sources in the Prometheus text exposition format
+pub fn render(sources: &MetricsSources) -> String {
+    let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+    let (outgoing, incoming) = (&sources.outgoing, &sources.incoming);
+    let metrics = [
+        (
+            "eyespy_frames_encoded_total",
+            "counter",
+            "Frames captured and encoded.",
+            load(&outgoing.frames_encoded),
+        ),
+        (
+            "eyespy_packets_sent_total",
+            "counter",
+            "Packets of the outgoing stream, all destinations.",
+            load(&outgoing.packets_sent),
+        ),
+        (
+            "eyespy_bytes_sent_total",
+            "counter",
+            "Bytes of the outgoing stream, all destinations.",
+            load(&outgoing.bytes_sent),
+        ),
+        (
+            "eyespy_outgoing_bitrate_bits",
+            "gauge",
+            "Bits per second of the outgoing stream, over the last second.",
+            outgoing.bitrate(),
+        ),
+        (
+            "eyespy_packets_received_total",
+            "counter",
+            "Packets of the incoming stream.",
+            load(&incoming.packets_received),
+        ),
+        (
+            "eyespy_packets_lost_total",
+            "counter",
+            "Packets of the incoming stream that never arrived.",
+            load(&incoming.packets_lost),
+        ),
+        (
+            "eyespy_frames_decoded_total",
+            "counter",
+            "Frames of the incoming stream decoded.",
+            load(&incoming.frames_decoded),
+        ),
+        (
+            "eyespy_decode_errors_total",
+            "counter",
+            "NAL units of the incoming stream the decoder refused.",
+            load(&incoming.decode_errors),
+        ),
+        (
+            "eyespy_active_sessions",
+            "gauge",
+            "Calls going on.",
+            load(&sources.active_sessions),
+        ),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+        );
+    }
+    // The latencies of the pipeline stages, as cumulative histograms in seconds
+    let profile = PipelineProfile::take(outgoing, incoming);
+    for (stage, latency) in profile.stages() {
+        let name = format!("eyespy_{stage}_seconds");
+        let help = stage.replace('_', " ");
+        let _ = writeln!(
+            out,
+            "# HELP {name} Latency of the pipeline from {help}.\n# TYPE {name} histogram"
+        );
+        let mut count = 0;
+        for (bound, bucket) in LATENCY_BUCKETS_US.iter().zip(&latency.buckets) {
+            count += bucket;
+            let le = *bound as f64 / 1e6;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}");
+        }
+        let sum = latency.sum_us as f64 / 1e6;
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{le=\"+Inf\"}} {}\n{name}_sum {sum}\n{name}_count {}",
+            latency.count, latency.count
+        );
+    }
+    out
+}
+
+/// The endpoint, serving until it's shut down
+pub struct MetricsServer {
+    /// None after the thread was shut down
+    t_handle: Option<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+    /// Address the endpoint listens on
+    pub address: SocketAddr,
+}
+
+impl MetricsServer {
+    /// Listen on `addr`. Use port 0 to let the OS pick one
+    /// # Errors
+    /// Returns an error if the address cannot be bound or the thread spawned
+    pub fn start(addr: SocketAddr, sources: MetricsSources) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = Arc::clone(&stop);
+        let t_handle = thread::Builder::new()
+            .name("metrics".into())
+            .spawn(move || {
+                let _span = info_span!("metrics", addr = %address).entered();
+                info!("Serving the metrics at http://{address}/metrics");
+                while !stop_clone.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, peer)) => {
+                            if let Err(e) = respond(stream, &sources) {
+                                debug!("Cannot answer {peer}: {e}");
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            thread::sleep(ACCEPT_INTERVAL);
+                        }
+                        Err(e) => debug!("Cannot accept a connection: {e}"),
+                    }
+                }
+            })?;
+        Ok(Self {
+            t_handle: Some(t_handle),
+            stop,
+            address,
+        })
+    }
+    /// Stop serving and wait for the thread to exit
+    /// # Errors
+    /// Returns an error if the thread panicked or didn't exit in time
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.stop()
+    }
+    fn stop(&mut self) -> anyhow::Result<()> {
+        let Some(t_handle) = self.t_handle.take() else {
+            return Ok(());
+        };
+        self.stop.store(true, Ordering::SeqCst);
+        join_with_timeout(t_handle, REQUEST_TIMEOUT + ACCEPT_INTERVAL * 2)
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Read the request and answer it, closing the connection
+fn respond(mut stream: TcpStream, sources: &MetricsSources) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut chunk = [0; 512];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        match stream.read(&mut chunk)? {
+            0 => break,
+            size => request.extend_from_slice(&chunk[..size]),
+        }
+    }
+    let request_line = request.split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", render(sources)),
+        _ => (
+            "404 Not Found",
+            String::from("Only /metrics is served here\n"),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use super::{render, MetricsServer, MetricsSources};
+
+    fn sources() -> MetricsSources {
+        MetricsSources {
+            outgoing: Default::default(),
+            incoming: Default::default(),
+            active_sessions: Default::default(),
+        }
+    }
+    fn get(server: &MetricsServer, path: &str) -> String {
+        let mut stream = TcpStream::connect(server.address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+    #[test]
+    fn test_render() {
+        let sources = sources();
+        sources.incoming.packets_lost.store(3, Ordering::Relaxed);
+        sources.active_sessions.store(1, Ordering::Relaxed);
+        sources
+            .outgoing
+            .capture_to_encode
+            .record(Duration::from_millis(3));
+        let text = render(&sources);
+        assert!(text
+            .contains("# TYPE eyespy_packets_lost_total counter\neyespy_packets_lost_total 3\n"));
+        assert!(text.contains("\neyespy_active_sessions 1\n"));
+        assert!(text.contains("# TYPE eyespy_capture_to_encode_seconds histogram\n"));
+        assert!(text.contains("\neyespy_capture_to_encode_seconds_bucket{le=\"0.002\"} 0\n"));
+        assert!(text.contains("\neyespy_capture_to_encode_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("\neyespy_capture_to_encode_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("\neyespy_capture_to_encode_seconds_sum 0.003\n"));
+        assert!(text.contains("\neyespy_decode_to_display_seconds_count 0\n"));
+    }
+    #[test]
+    fn test_metrics_endpoint() {
+        let sources = sources();
+        sources.outgoing.frames_encoded.store(42, Ordering::Relaxed);
+        let server =
+            MetricsServer::start(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), sources).unwrap();
+
+        let response = get(&server, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\neyespy_frames_encoded_total 42\n"));
+        assert!(get(&server, "/").starts_with("HTTP/1.1 404"));
+        server.shutdown().unwrap();
+    }
+}
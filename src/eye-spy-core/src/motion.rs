@@ -0,0 +1,179 @@
+//! Motion detection on the camera frames, for cameras left watching a place.
+//! Frames are compared by the average luma of their blocks, which evens out the noise of the sensor.
+//! The outgoing stream reports when the motion starts and stops,
+//! and can send the frames only while there's motion, see `MotionMode`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Side of the blocks the frames are compared by
+const BLOCK: usize = 16;
+
+/// What the outgoing stream does with the motion
+/// * `Report` - reports when it starts and stops
+/// * `Gate` - also sends the frames only while there's motion
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MotionMode {
+    #[default]
+    Off,
+    Report,
+    Gate,
+}
+
+/// Tells whether there's motion by differencing the luma of the frames
+/// * `threshold` - change of the average luma of a block that counts as motion
+/// * `min_area` - share of the blocks that have to change, 0 to 1
+/// * `hold` - still frames in a row before the motion is over, so it doesn't flicker
+#[derive(Debug, Clone)]
+pub struct MotionDetector {
+    pub threshold: u8,
+    pub min_area: f32,
+    pub hold: u32,
+    /// Average luma of the blocks of the previous frame
+    previous: Vec<u8>,
+    still_frames: u32,
+    moving: bool,
+}
+
+impl Default for MotionDetector {
+    fn default() -> Self {
+        Self {
+            threshold: 12,
+            min_area: 0.02,
+            hold: 50,
+            previous: Vec::new(),
+            still_frames: 0,
+            moving: false,
+        }
+    }
+}
+
+impl MotionDetector {
+    pub fn is_moving(&self) -> bool {
+        self.moving
+    }
+    /// Compare the YUYV frame with the previous one.
+    /// Returns whether there's motion now
+    pub fn update(&mut self, frame: &[u8], width: usize, height: usize) -> bool {
+        let blocks = Self::block_luma(frame, width, height);
+        if blocks.len() == self.previous.len() && !blocks.is_empty() {
+            let changed = blocks
+                .iter()
+                .zip(&self.previous)
+                .filter(|(now, before)| now.abs_diff(**before) > self.threshold)
+                .count();
+            if changed as f32 >= self.min_area * blocks.len() as f32 {
+                self.moving = true;
+                self.still_frames = 0;
+            } else {
+                self.still_frames += 1;
+                if self.still_frames >= self.hold {
+                    self.moving = false;
+                }
+            }
+        }
+        self.previous = blocks;
+        self.moving
+    }
+    fn block_luma(frame: &[u8], width: usize, height: usize) -> Vec<u8> {
+        if frame.len() < width * height * 2 {
+            return Vec::new();
+        }
+        let (columns, rows) = (width / BLOCK, height / BLOCK);
+        let mut sums = vec![0u32; columns * rows];
+        for y in 0..rows * BLOCK {
+            for x in 0..columns * BLOCK {
+                sums[y / BLOCK * columns + x / BLOCK] += u32::from(frame[(y * width + x) * 2]);
+            }
+        }
+        let area = (BLOCK * BLOCK) as u32;
+        sums.into_iter().map(|sum| (sum / area) as u8).collect()
+    }
+}
+
+/// The detector shared by the stream thread and its controls
+#[derive(Debug, Default)]
+pub(crate) struct MotionState {
+    /// None while the detection is off
+    detector: Mutex<Option<MotionDetector>>,
+    gate: AtomicBool,
+}
+
+impl MotionState {
+    /// A detector that already runs keeps what it has seen
+    pub(crate) fn set_mode(&self, mode: MotionMode) {
+        if let Ok(mut detector) = self.detector.lock() {
+            match mode {
+                MotionMode::Off => *detector = None,
+                MotionMode::Report | MotionMode::Gate => {
+                    detector.get_or_insert_with(MotionDetector::default);
+                }
+            }
+        }
+        self.gate.store(mode == MotionMode::Gate, Ordering::Relaxed);
+    }
+    pub(crate) fn is_gated(&self) -> bool {
+        self.gate.load(Ordering::Relaxed)
+    }
+    /// Run the detector on the frame, if it's on.
+    /// Returns whether the motion has just started
+    pub(crate) fn update(&self, frame: &[u8], width: usize, height: usize) -> bool {
+        let Ok(mut detector) = self.detector.lock() else {
+            return false;
+        };
+        detector.as_mut().is_some_and(|detector| {
+            let was_moving = detector.is_moving();
+            detector.update(frame, width, height) && !was_moving
+        })
+    }
+    /// Whether there's motion, None while the detection is off
+    pub(crate) fn moving(&self) -> Option<bool> {
+        let detector = self.detector.lock().ok()?;
+        detector.as_ref().map(MotionDetector::is_moving)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MotionDetector, MotionMode, MotionState};
+
+    #[test]
+    fn test_motion_detector() {
+        let (width, height) = (64, 48);
+        let still = vec![80; width * height * 2];
+        let mut moved = still.clone();
+        // A bright object in the top left block
+        for y in 0..16 {
+            for x in 0..16 {
+                moved[(y * width + x) * 2] = 200;
+            }
+        }
+        let mut detector = MotionDetector {
+            hold: 2,
+            ..Default::default()
+        };
+        assert!(!detector.update(&still, width, height));
+        assert!(!detector.update(&still, width, height));
+        assert!(detector.update(&moved, width, height));
+        // Held for a while after the last change
+        assert!(detector.update(&moved, width, height));
+        assert!(!detector.update(&moved, width, height));
+
+        // Sensor noise doesn't count
+        let mut noisy = moved.clone();
+        noisy.iter_mut().step_by(2).for_each(|luma| *luma += 3);
+        assert!(!detector.update(&noisy, width, height));
+
+        let state = MotionState::default();
+        assert_eq!(state.moving(), None);
+        state.set_mode(MotionMode::Gate);
+        assert!(state.is_gated());
+        assert!(!state.update(&still, width, height));
+        assert!(state.update(&moved, width, height));
+        assert!(!state.update(&moved, width, height));
+        assert_eq!(state.moving(), Some(true));
+        state.set_mode(MotionMode::Off);
+        assert!(!state.is_gated());
+        assert_eq!(state.moving(), None);
+    }
+}
@@ -0,0 +1,417 @@
+//! A minimal MP4 writer for a single H.264 video track, just enough for the players to open it.
+//! The samples go into one `mdat` as they come, the index of them is written into `moov`
+//! once the file is finished. A file that isn't finished has no index and doesn't play.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use openh264::nal_units;
+
+/// Ticks per second of the track, the usual one for video
+const TIMESCALE: u32 = 90_000;
+/// Ticks per second of the movie header
+const MOVIE_TIMESCALE: u32 = 1000;
+/// Duration of the last sample, there's no next one to tell it
+const LAST_SAMPLE_TICKS: u32 = TIMESCALE / 20;
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+/// Size of `ftyp` and the header of `mdat`, where the samples start
+const MDAT_DATA_OFFSET: u64 = 32 + 8;
+
+/// MP4 file of an H.264 stream, written a frame at a time
+/// * `sps`, `pps` - the first parameter sets of the stream, they go into the sample description
+/// * `sizes` - of every sample written so far
+/// * `times` - of every sample in `TIMESCALE` ticks since the start
+/// * `sync` - numbers of the keyframe samples, starting at 1
+pub(crate) struct Mp4Writer {
+    file: BufWriter<File>,
+    width: u16,
+    height: u16,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    sizes: Vec<u32>,
+    times: Vec<u64>,
+    sync: Vec<u32>,
+    mdat_size: u64,
+}
+
+impl Mp4Writer {
+    /// # Errors
+    /// Returns an error if the file cannot be created
+    pub(crate) fn create(path: &Path, width: u16, height: u16) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut header = Vec::new();
+        write_box(&mut header, b"ftyp", |b| {
+            b.extend_from_slice(b"isom");
+            push_u32(b, 0x200);
+            for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+                b.extend_from_slice(brand);
+            }
+        });
+        // The size is set once the file is finished
+        push_u32(&mut header, 0);
+        header.extend_from_slice(b"mdat");
+        debug_assert_eq!(header.len() as u64, MDAT_DATA_OFFSET);
+        file.write_all(&header)?;
+        Ok(Self {
+            file,
+            width,
+            height,
+            sps: None,
+            pps: None,
+            sizes: Vec::new(),
+            times: Vec::new(),
+            sync: Vec::new(),
+            mdat_size: 8,
+        })
+    }
+    /// Write a frame encoded into NAL units with start codes, as the encoder gives them.
+    /// The parameter sets are kept for the sample description, not written as a part of the frame
+    /// * `time` - since the start of the recording
+    /// # Errors
+    /// Returns an error if the frame cannot be written
+    pub(crate) fn write_frame(&mut self, annex_b: &[u8], time: Duration) -> io::Result<()> {
+        let mut sample = Vec::with_capacity(annex_b.len());
+        let mut keyframe = false;
+        for unit in nal_units(annex_b).map(strip_start_code) {
+            let Some(header) = unit.first() else {
+                continue;
+            };
+            match header & 0x1F {
+                NAL_SPS => {
+                    self.sps.get_or_insert_with(|| unit.to_vec());
+                }
+                NAL_PPS => {
+                    self.pps.get_or_insert_with(|| unit.to_vec());
+                }
+                kind => {
+                    keyframe |= kind == NAL_IDR;
+                    push_u32(&mut sample, unit.len() as u32);
+                    sample.extend_from_slice(unit);
+                }
+            }
+        }
+        if sample.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&sample)?;
+        self.mdat_size += sample.len() as u64;
+        self.sizes.push(sample.len() as u32);
+        self.times
+            .push(time.as_micros() as u64 * u64::from(TIMESCALE) / 1_000_000);
+        if keyframe {
+            self.sync.push(self.sizes.len() as u32);
+        }
+        Ok(())
+    }
+    /// Write the index of the samples, the file plays from then on
+    /// # Errors
+    /// Returns an error if the file cannot be written, has no parameter sets
+    /// or grew over the 4 GiB a plain `mdat` can hold
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        let (Some(sps), Some(pps)) = (self.sps.take(), self.pps.take()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no parameter sets were encoded",
+            ));
+        };
+        let mdat_size = u32::try_from(self.mdat_size).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "the recording is too large")
+        })?;
+        let moov = self.moov(&sps, &pps);
+        self.file.write_all(&moov)?;
+        self.file.seek(SeekFrom::Start(MDAT_DATA_OFFSET - 8))?;
+        self.file.write_all(&mdat_size.to_be_bytes())?;
+        self.file.flush()
+    }
+
+    /// Duration of every sample in ticks, the last one is a guess
+    fn durations(&self) -> Vec<u32> {
+        let mut durations: Vec<u32> = self
+            .times
+            .windows(2)
+            .map(|pair| pair[1].saturating_sub(pair[0]).max(1) as u32)
+            .collect();
+        if !self.times.is_empty() {
+            durations.push(durations.last().copied().unwrap_or(LAST_SAMPLE_TICKS));
+        }
+        durations
+    }
+    fn moov(&self, sps: &[u8], pps: &[u8]) -> Vec<u8> {
+        let durations = self.durations();
+        let duration: u64 = durations.iter().copied().map(u64::from).sum();
+        let movie_duration = duration * u64::from(MOVIE_TIMESCALE) / u64::from(TIMESCALE);
+        let (width, height) = (u32::from(self.width), u32::from(self.height));
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", |b| {
+            write_full_box(b, b"mvhd", 0, |b| {
+                push_u32(b, 0);
+                push_u32(b, 0);
+                push_u32(b, MOVIE_TIMESCALE);
+                push_u32(b, movie_duration as u32);
+                push_u32(b, 0x0001_0000);
+                b.extend_from_slice(&0x0100u16.to_be_bytes());
+                b.extend_from_slice(&[0; 10]);
+                push_matrix(b);
+                b.extend_from_slice(&[0; 24]);
+                push_u32(b, 2);
+            });
+            write_box(b, b"trak", |b| {
+                // Enabled and in the movie
+                write_full_box(b, b"tkhd", 3, |b| {
+                    push_u32(b, 0);
+                    push_u32(b, 0);
+                    push_u32(b, 1);
+                    push_u32(b, 0);
+                    push_u32(b, movie_duration as u32);
+                    b.extend_from_slice(&[0; 16]);
+                    push_matrix(b);
+                    push_u32(b, width << 16);
+                    push_u32(b, height << 16);
+                });
+                write_box(b, b"mdia", |b| {
+                    write_full_box(b, b"mdhd", 0, |b| {
+                        push_u32(b, 0);
+                        push_u32(b, 0);
+                        push_u32(b, TIMESCALE);
+                        push_u32(b, duration as u32);
+                        // "und" packed into 5 bits a letter
+                        b.extend_from_slice(&0x55C4u16.to_be_bytes());
+                        b.extend_from_slice(&[0; 2]);
+                    });
+                    write_full_box(b, b"hdlr", 0, |b| {
+                        push_u32(b, 0);
+                        b.extend_from_slice(b"vide");
+                        b.extend_from_slice(&[0; 12]);
+                        b.extend_from_slice(b"VideoHandler\0");
+                    });
+                    write_box(b, b"minf", |b| {
+                        write_full_box(b, b"vmhd", 1, |b| b.extend_from_slice(&[0; 8]));
+                        write_box(b, b"dinf", |b| {
+                            write_full_box(b, b"dref", 0, |b| {
+                                push_u32(b, 1);
+                                // The samples are in this very file
+                                write_full_box(b, b"url ", 1, |_| {});
+                            });
+                        });
+                        write_box(b, b"stbl", |b| self.stbl(b, &durations, sps, pps));
+                    });
+                });
+            });
+        });
+        moov
+    }
+    fn stbl(&self, b: &mut Vec<u8>, durations: &[u32], sps: &[u8], pps: &[u8]) {
+        write_full_box(b, b"stsd", 0, |b| {
+            push_u32(b, 1);
+            write_box(b, b"avc1", |b| {
+                b.extend_from_slice(&[0; 6]);
+                // Data reference index
+                b.extend_from_slice(&1u16.to_be_bytes());
+                b.extend_from_slice(&[0; 16]);
+                b.extend_from_slice(&self.width.to_be_bytes());
+                b.extend_from_slice(&self.height.to_be_bytes());
+                // 72 dpi both ways
+                push_u32(b, 0x0048_0000);
+                push_u32(b, 0x0048_0000);
+                push_u32(b, 0);
+                // Frames in a sample
+                b.extend_from_slice(&1u16.to_be_bytes());
+                b.extend_from_slice(&[0; 32]);
+                b.extend_from_slice(&0x0018u16.to_be_bytes());
+                b.extend_from_slice(&(-1i16).to_be_bytes());
+                write_box(b, b"avcC", |b| {
+                    // Version, then the profile, compatibility and level as the SPS has them
+                    b.push(1);
+                    b.extend(sps.iter().skip(1).chain([0; 3].iter()).take(3));
+                    // 4 byte lengths of the units
+                    b.push(0xFF);
+                    // A single SPS and PPS
+                    b.push(0xE1);
+                    b.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+                    b.extend_from_slice(sps);
+                    b.push(1);
+                    b.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+                    b.extend_from_slice(pps);
+                });
+            });
+        });
+        write_full_box(b, b"stts", 0, |b| {
+            let mut runs: Vec<(u32, u32)> = Vec::new();
+            for &duration in durations {
+                match runs.last_mut() {
+                    Some((count, last)) if *last == duration => *count += 1,
+                    _ => runs.push((1, duration)),
+                }
+            }
+            push_u32(b, runs.len() as u32);
+            for (count, duration) in runs {
+                push_u32(b, count);
+                push_u32(b, duration);
+            }
+        });
+        write_full_box(b, b"stss", 0, |b| {
+            push_u32(b, self.sync.len() as u32);
+            for &sample in &self.sync {
+                push_u32(b, sample);
+            }
+        });
+        // Every sample in a single chunk, right at the start of `mdat`
+        write_full_box(b, b"stsc", 0, |b| {
+            push_u32(b, 1);
+            push_u32(b, 1);
+            push_u32(b, self.sizes.len() as u32);
+            push_u32(b, 1);
+        });
+        write_full_box(b, b"stsz", 0, |b| {
+            push_u32(b, 0);
+            push_u32(b, self.sizes.len() as u32);
+            for &size in &self.sizes {
+                push_u32(b, size);
+            }
+        });
+        write_full_box(b, b"stco", 0, |b| {
+            push_u32(b, 1);
+            push_u32(b, MDAT_DATA_OFFSET as u32);
+        });
+    }
+}
+
+/// The unit without the 3 or 4 byte start code in front of it
+fn strip_start_code(unit: &[u8]) -> &[u8] {
+    let start = unit
+        .iter()
+        .position(|&byte| byte != 0)
+        .unwrap_or(unit.len());
+    match unit.get(start) {
+        Some(1) => &unit[start + 1..],
+        _ => unit,
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// The identity transformation of the video
+fn push_matrix(buf: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        push_u32(buf, value);
+    }
+}
+
+/// A box of `kind` with the content written by `content`, its size in front
+fn write_box(buf: &mut Vec<u8>, kind: &[u8; 4], content: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    push_u32(buf, 0);
+    buf.extend_from_slice(kind);
+    content(buf);
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// A box with version 0 and `flags` before its content
+fn write_full_box(
+    buf: &mut Vec<u8>,
+    kind: &[u8; 4],
+    flags: u32,
+    content: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(buf, kind, |b| {
+        push_u32(b, flags & 0x00FF_FFFF);
+        content(b);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{strip_start_code, Mp4Writer, MDAT_DATA_OFFSET};
+
+    /// Content of the first box of `kind` under `buf`, looking into the boxes on `path`
+    fn find_box<'a>(mut buf: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+        let (kind, rest) = path.split_first()?;
+        while buf.len() >= 8 {
+            let size = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+            if &buf[4..8] == *kind {
+                let content = &buf[8..size];
+                return if rest.is_empty() {
+                    Some(content)
+                } else {
+                    find_box(content, rest)
+                };
+            }
+            buf = &buf[size..];
+        }
+        None
+    }
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_strip_start_code() {
+        assert_eq!(strip_start_code(&[0, 0, 0, 1, 0x65, 1]), &[0x65, 1]);
+        assert_eq!(strip_start_code(&[0, 0, 1, 0x41]), &[0x41]);
+        assert_eq!(strip_start_code(&[0x41, 2]), &[0x41, 2]);
+    }
+    #[test]
+    fn test_mp4_layout() {
+        let path = std::env::temp_dir().join(format!("eye-spy-mp4-{}.mp4", std::process::id()));
+        let mut mp4 = Mp4Writer::create(&path, 1280, 480).unwrap();
+        let keyframe = [
+            &[0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1F, 0xAA][..],
+            &[0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80],
+            &[0, 0, 0, 1, 0x65, 1, 2, 3, 4],
+        ]
+        .concat();
+        mp4.write_frame(&keyframe, Duration::ZERO).unwrap();
+        mp4.write_frame(&[0, 0, 0, 1, 0x41, 5, 6], Duration::from_millis(50))
+            .unwrap();
+        // Nothing but parameter sets, not a sample
+        mp4.write_frame(&[0, 0, 0, 1, 0x68, 0xCE], Duration::from_millis(60))
+            .unwrap();
+        mp4.write_frame(&[0, 0, 0, 1, 0x41, 7], Duration::from_millis(100))
+            .unwrap();
+        mp4.finish().unwrap();
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&file[4..12], b"ftypisom");
+        let mdat_size = u32_at(&file, MDAT_DATA_OFFSET as usize - 8) as usize;
+        // Samples of 4 + 5, 4 + 3 and 4 + 2 bytes
+        assert_eq!(mdat_size, 8 + 9 + 7 + 6);
+        assert_eq!(
+            &file[MDAT_DATA_OFFSET as usize..][..9],
+            &[0, 0, 0, 5, 0x65, 1, 2, 3, 4]
+        );
+        let moov = &file[MDAT_DATA_OFFSET as usize - 8 + mdat_size..];
+        let stbl: [&[u8; 4]; 5] = [b"moov", b"trak", b"mdia", b"minf", b"stbl"];
+        let table = |kind: &[u8; 4]| {
+            let path: Vec<&[u8; 4]> = stbl.iter().copied().chain([kind]).collect();
+            find_box(moov, &path).unwrap()
+        };
+        let stsz = table(b"stsz");
+        assert_eq!(u32_at(stsz, 8), 3);
+        assert_eq!(
+            [u32_at(stsz, 12), u32_at(stsz, 16), u32_at(stsz, 20)],
+            [9, 7, 6]
+        );
+        let stss = table(b"stss");
+        assert_eq!((u32_at(stss, 4), u32_at(stss, 8)), (1, 1));
+        // 50 ms each, the last one like the one before
+        let stts = table(b"stts");
+        assert_eq!(
+            (u32_at(stts, 4), u32_at(stts, 8), u32_at(stts, 12)),
+            (1, 3, 4500)
+        );
+        assert_eq!(u32_at(table(b"stco"), 8), MDAT_DATA_OFFSET as u32);
+        let avcc = &table(b"stsd")[8 + 8 + 78..];
+        assert_eq!(&avcc[4..8], b"avcC");
+        assert_eq!(&avcc[8..12], &[1, 0x42, 0xC0, 0x1F]);
+    }
+}
@@ -0,0 +1,217 @@
+//! Path MTU probing, so the stream is sent in packets as large as the path to the peer allows.
+//! Probes of the packet sizes are sent over UDP with fragmentation off, the peer acknowledges
+//! the ones that came through over SCP, see `ControlMessage::ProbeAck`. The sizes are binary searched
+//! between `DEFAULT_PACKET_DATA_SIZE`, which fits any path, and `MAX_PACKET_DATA_SIZE`.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::nal::{DEFAULT_PACKET_DATA_SIZE, MAX_PACKET_OVERHEAD};
+
+/// Starts every probe. Stream packets start with a NAL unit, the end of one or the header
+const PROBE_MAGIC: &[u8] = b"MTUPROBE";
+/// A probe not acknowledged by then didn't come through
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+/// Every size is probed this many times, so a single lost datagram doesn't count
+const PROBE_COPIES: usize = 2;
+
+/// Whether the packet is a probe, not a part of the stream
+pub(crate) fn is_probe(packet: &[u8]) -> bool {
+    packet.starts_with(PROBE_MAGIC)
+}
+
+/// A probe as long as a stream packet with `data_size` bytes of data, in either format
+fn probe_packet(data_size: usize) -> Vec<u8> {
+    let mut packet = vec![0; data_size + MAX_PACKET_OVERHEAD];
+    packet[..PROBE_MAGIC.len()].copy_from_slice(PROBE_MAGIC);
+    packet
+}
+
+/// Data size of the stream packets the probe stands for
+pub(crate) fn probe_data_size(probe: &[u8]) -> usize {
+    probe.len().saturating_sub(MAX_PACKET_OVERHEAD)
+}
+
+/// Binary search of the largest packet data size that comes through
+/// * `low` - the largest size known to come through
+/// * `high` - the largest size that may come through
+/// * `trying` - size of the probe in flight and when it was sent
+#[derive(Debug)]
+struct PacketSizeSearch {
+    low: usize,
+    high: usize,
+    trying: Option<(usize, Instant)>,
+}
+
+impl PacketSizeSearch {
+    fn new(low: usize, high: usize) -> Self {
+        Self {
+            low,
+            high: high.max(low),
+            trying: None,
+        }
+    }
+    /// The size to probe next, None while a probe is in flight or once the search is over
+    fn next_probe(&mut self, now: Instant) -> Option<usize> {
+        if let Some((size, sent)) = self.trying {
+            if now.duration_since(sent) < PROBE_TIMEOUT {
+                return None;
+            }
+            self.high = size - 1;
+            self.trying = None;
+        }
+        if self.low >= self.high {
+            return None;
+        }
+        let size = (self.low + self.high).div_ceil(2);
+        self.trying = Some((size, now));
+        Some(size)
+    }
+    fn on_ack(&mut self, size: usize) {
+        self.low = self.low.max(size.min(self.high));
+        if self.trying.is_some_and(|(trying, _)| trying <= size) {
+            self.trying = None;
+        }
+    }
+    /// The size found, None while searching
+    fn result(&self) -> Option<usize> {
+        (self.low >= self.high && self.trying.is_none()).then_some(self.low)
+    }
+}
+
+/// Probes the path to the peer of a call
+pub(crate) struct MtuProbe {
+    search: PacketSizeSearch,
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl MtuProbe {
+    /// Probe the path to the incoming stream of the peer at `target`, up to `max` bytes of packet data
+    /// # Errors
+    /// Returns an error if the socket of the probes cannot be set up
+    pub(crate) fn start(target: SocketAddr, max: usize) -> io::Result<Self> {
+        let any = match target.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0))?;
+        socket.set_nonblocking(true)?;
+        forbid_fragmentation(&socket)?;
+        Ok(Self {
+            search: PacketSizeSearch::new(DEFAULT_PACKET_DATA_SIZE, max),
+            socket,
+            target,
+        })
+    }
+    /// Send the next probe when it's time. Returns the packet data size once it's found
+    pub(crate) fn poll(&mut self) -> Option<usize> {
+        if let Some(size) = self.search.next_probe(Instant::now()) {
+            let probe = probe_packet(size);
+            for _ in 0..PROBE_COPIES {
+                // Too large for the first hop already, that's as good as lost
+                let _ = self.socket.send_to(&probe, self.target);
+            }
+        }
+        self.search.result()
+    }
+    /// The peer received a probe of `size` bytes of packet data
+    pub(crate) fn on_ack(&mut self, size: usize) {
+        self.search.on_ack(size);
+    }
+}
+
+/// Set the Don't Fragment bit, so probes larger than the path are dropped instead of fragmented
+#[cfg(target_os = "linux")]
+fn forbid_fragmentation(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    use nix::libc;
+
+    let (level, name, value) = if socket.local_addr()?.is_ipv4() {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    };
+    // SAFETY: the option is a c_int read from a valid pointer, on a socket that's open
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            std::ptr::addr_of!(value).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Elsewhere the probes may be fragmented, they only tell that the peer can take the packets
+#[cfg(not(target_os = "linux"))]
+fn forbid_fragmentation(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        is_probe, probe_data_size, probe_packet, MtuProbe, PacketSizeSearch, PROBE_TIMEOUT,
+    };
+
+    #[test]
+    fn test_packet_size_search() {
+        let path = 1200;
+        let mut search = PacketSizeSearch::new(504, 1464);
+        let mut now = Instant::now();
+        let mut probes = 0;
+        while search.result().is_none() {
+            if let Some(size) = search.next_probe(now) {
+                assert!(search.next_probe(now).is_none(), "a probe is in flight");
+                probes += 1;
+                if size <= path {
+                    search.on_ack(size);
+                }
+            }
+            now += PROBE_TIMEOUT + Duration::from_millis(1);
+        }
+        assert_eq!(search.result(), Some(path));
+        assert!(probes <= 10);
+
+        let probe = probe_packet(path);
+        assert!(is_probe(&probe));
+        assert_eq!(probe_data_size(&probe), path);
+    }
+    #[test]
+    fn test_probe_over_loopback() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut probe = MtuProbe::start(peer.local_addr().unwrap(), 1464).unwrap();
+        let mut buf = [0; 2048];
+        let found = (0..50).find_map(|_| {
+            let found = probe.poll();
+            while let Ok(size) = peer.recv(&mut buf) {
+                assert!(is_probe(&buf[..size]));
+                probe.on_ack(probe_data_size(&buf[..size]));
+            }
+            found
+        });
+        // Loopback takes anything
+        assert_eq!(found, Some(1464));
+    }
+}
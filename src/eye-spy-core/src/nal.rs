@@ -0,0 +1,959 @@
+//! The packets the video stream is sent in. `PacketBuffer` splits the NAL units of the outgoing
+//! stream into packets small enough for UDP, `NalBuilder` puts them back together on the other side.
+//! Both streams go through this module only, so the format is defined in a single place.
+//!
+//! The packets come straight from the network, so anyone may send anything. The builder
+//! only hands out units it got every packet of, in order, and drops the rest, telling why
+//! with a `PacketError`. No packet makes it panic or write past its buffer.
+//! Every packet is used once at most: one received again, i.e. an old keyframe re-injected
+//! by someone on the path, is dropped, see `ReplayWindow`.
+//! It's fuzzed on its own, see `fuzz/fuzz_targets/nal_builder.rs`.
+//!
+//! The packets come in one of the formats of `PacketFormat`, negotiated for each session so
+//! peers that predate the header still get the legacy one. Both are put back together
+//! the same way, only taken apart differently, see `Fragment`.
+
+use scp_client::client::PacketFormat;
+
+/// Packet identifier. Starts with 1
+type PacketIdentifier = u32;
+/// Sequence number of a packet in the stream, unlike the identifier it goes on across the NAL units.
+/// Wraps around
+type PacketSequence = u16;
+// and frame ends with 11 one's, followed by the capture timestamp (u64 LE, see `av_sync::timestamp_now`)
+const FRAME_END: &[u8] = b"11111111111";
+/// Length of the capture timestamp sent after FRAME_END
+const TIMESTAMP_LEN: usize = 8;
+/// Length of the session ID stamped into every packet, see `SessionConfig::session_id`
+const SESSION_ID_LEN: usize = 4;
+/// Length of the sequence number stamped into every packet after the session ID
+const SEQUENCE_LEN: usize = 2;
+/// Length of the session ID, the sequence number and the identifier after the data of every packet
+pub(crate) const PACKET_META_LEN: usize = SESSION_ID_LEN + SEQUENCE_LEN + 4;
+/// Length of the `PacketHeader` without the timestamp
+pub const HEADER_LEN: usize = 2 + SESSION_ID_LEN + SEQUENCE_LEN + 4;
+/// Set on the last fragment of a unit
+const FLAG_LAST_FRAGMENT: u8 = 0x01;
+/// The capture timestamp follows the header
+const FLAG_TIMESTAMP: u8 = 0x02;
+/// Most a packet carries besides its data in either format, the header with the timestamp
+pub(crate) const MAX_PACKET_OVERHEAD: usize = HEADER_LEN + TIMESTAMP_LEN;
+/// The size of packet's raw frame data EXCLUDING meta, fits any path.
+/// Larger packets are sent once the path to the peer is probed, see `crate::mtu`
+pub const DEFAULT_PACKET_DATA_SIZE: usize = 504;
+/// Packet data filling an Ethernet frame: 1500 bytes less the IPv4 and UDP headers and the meta
+pub const MAX_PACKET_DATA_SIZE: usize = 1472 - MAX_PACKET_OVERHEAD;
+/// Packets behind the newest one remembered to tell the duplicates, older ones are taken as replays
+const REPLAY_WINDOW: u64 = 64;
+/// Packets in a row it takes to move the window further ahead than it reaches at once.
+/// Without SRTP nothing tells a forged packet apart, and a single one far ahead would
+/// otherwise push every genuine packet behind the window
+const REPLAY_JUMP_PACKETS: u8 = 3;
+/// Largest NAL unit the builder puts together unless set, larger ones are dropped.
+/// Keyframes of high resolutions take hundreds of KB, see `NalBuilder::set_max_unit_size`
+pub const DEFAULT_MAX_NAL_UNIT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Splits a NAL unit into packets of the legacy format, ready to be sent over UDP.
+/// Each packet is the data followed by the session ID, the sequence number (u16 LE) and its identifier
+/// (u32 LE, starting with 1), the last one is FRAME_END with the capture timestamp, the session ID and the sequence number.
+/// The sequence numbers start with 0, the units of a stream are packetized by a single `PacketBuffer`
+pub fn packetize(unit: &[u8], capture_ts: u64, session_id: u32) -> Vec<Vec<u8>> {
+    let mut buffer = PacketBuffer::default();
+    buffer.push_unit(unit, capture_ts, session_id);
+    buffer.packets().map(<[u8]>::to_vec).collect()
+}
+
+/// Packets of a frame, stored back to back in a single buffer.
+/// The buffer is reused for every frame, so packetizing doesn't allocate once it has grown enough
+#[derive(Debug)]
+pub struct PacketBuffer {
+    data: Vec<u8>,
+    /// End of each packet in `data`
+    ends: Vec<usize>,
+    /// Data of a packet, `DEFAULT_PACKET_DATA_SIZE` unless set
+    packet_size: usize,
+    /// Sequence number of the next packet, kept when the buffer is cleared
+    sequence: PacketSequence,
+    /// `PacketFormat::Legacy` unless set
+    format: PacketFormat,
+}
+
+impl Default for PacketBuffer {
+    fn default() -> Self {
+        Self {
+            data: Vec::new(),
+            ends: Vec::new(),
+            packet_size: DEFAULT_PACKET_DATA_SIZE,
+            sequence: 0,
+            format: PacketFormat::Legacy,
+        }
+    }
+}
+
+impl PacketBuffer {
+    /// Split the units pushed from now on into packets of `format`, see `SessionConfig::codecs`
+    pub fn set_format(&mut self, format: PacketFormat) {
+        self.format = format;
+    }
+    /// Split the units pushed from now on into packets of `size` bytes of data,
+    /// clamped to `MAX_PACKET_DATA_SIZE`
+    pub fn set_packet_size(&mut self, size: usize) {
+        self.packet_size = size.clamp(1, MAX_PACKET_DATA_SIZE);
+    }
+    /// Forget the packets, keeping the memory
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.ends.clear();
+    }
+    /// Append the packets of `unit`, see `packetize` and `PacketHeader`
+    pub fn push_unit(&mut self, unit: &[u8], capture_ts: u64, session_id: u32) {
+        if self.format == PacketFormat::Header {
+            self.push_fragments(unit, capture_ts, session_id);
+            return;
+        }
+        let session_as_bytes = session_id.to_le_bytes();
+        for (num, packet) in unit.chunks(self.packet_size).enumerate() {
+            self.data.extend_from_slice(packet);
+            self.data.extend_from_slice(&session_as_bytes);
+            self.push_sequence();
+            self.data.extend_from_slice(&(num as u32 + 1).to_le_bytes());
+            self.ends.push(self.data.len());
+        }
+        self.data.extend_from_slice(FRAME_END);
+        self.data.extend_from_slice(&capture_ts.to_le_bytes());
+        self.data.extend_from_slice(&session_as_bytes);
+        self.push_sequence();
+        self.ends.push(self.data.len());
+    }
+    /// Nothing is sent for an empty unit, the header has no packet ending the unit without data
+    fn push_fragments(&mut self, unit: &[u8], capture_ts: u64, session_id: u32) {
+        let count = unit.chunks(self.packet_size).len();
+        for (num, data) in unit.chunks(self.packet_size).enumerate() {
+            let last = num + 1 == count;
+            let header = PacketHeader {
+                session_id,
+                sequence: self.sequence,
+                // Wraps around past 65536 packets, such a unit is dropped by the builder.
+                // Far past the size limit at the usual packet sizes
+                fragment: num as u16,
+                last,
+                timestamp: last.then_some(capture_ts),
+                payload_len: data.len() as u16,
+            };
+            self.sequence = self.sequence.wrapping_add(1);
+            header.encode(&mut self.data);
+            self.data.extend_from_slice(data);
+            self.ends.push(self.data.len());
+        }
+    }
+    fn push_sequence(&mut self) {
+        self.data.extend_from_slice(&self.sequence.to_le_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+    pub fn packets(&self) -> impl Iterator<Item = &[u8]> {
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(&self.ends)
+            .map(|(start, end)| &self.data[start..*end])
+    }
+}
+
+/// Header ahead of the data of every packet in `PacketFormat::Header`, all LE: the version (u8),
+/// flags (u8), session ID (u32), sequence number (u16), fragment index (u16) and payload length (u16),
+/// followed by the capture timestamp (u64) when flagged. Flags unknown to a version are ignored,
+/// so new ones can be added without a new version
+/// * `fragment` - index of the packet within its unit, from 0
+/// * `last` - the packet ends its unit
+/// * `timestamp` - capture timestamp of the unit, sent along with its last fragment
+/// * `payload_len` - bytes of data after the header, any bytes past them are ignored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub session_id: u32,
+    pub sequence: u16,
+    pub fragment: u16,
+    pub last: bool,
+    pub timestamp: Option<u64>,
+    pub payload_len: u16,
+}
+
+impl PacketHeader {
+    /// Append the header to `out`, the payload goes right after it
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.last {
+            flags |= FLAG_LAST_FRAGMENT;
+        }
+        if self.timestamp.is_some() {
+            flags |= FLAG_TIMESTAMP;
+        }
+        out.push(PacketFormat::Header.version());
+        out.push(flags);
+        out.extend_from_slice(&self.session_id.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&self.fragment.to_le_bytes());
+        out.extend_from_slice(&self.payload_len.to_le_bytes());
+        if let Some(ts) = self.timestamp {
+            out.extend_from_slice(&ts.to_le_bytes());
+        }
+    }
+    /// The header of the packet and the payload after it
+    /// # Errors
+    /// Returns `PacketError::UnsupportedVersion` for a packet of another version,
+    /// `PacketError::Truncated` if it's shorter than its header or its payload
+    pub fn decode(packet: &[u8]) -> Result<(Self, &[u8]), PacketError> {
+        let Some((fixed, rest)) = packet.split_first_chunk::<HEADER_LEN>() else {
+            return Err(PacketError::Truncated);
+        };
+        if fixed[0] != PacketFormat::Header.version() {
+            return Err(PacketError::UnsupportedVersion(fixed[0]));
+        }
+        let flags = fixed[1];
+        let u16_at = |at: usize| u16::from_le_bytes([fixed[at], fixed[at + 1]]);
+        let session_id = u32::from_le_bytes(fixed[2..6].try_into().unwrap());
+        let (timestamp, payload) = match flags & FLAG_TIMESTAMP {
+            0 => (None, rest),
+            _ => {
+                let (ts, payload) = rest
+                    .split_first_chunk::<TIMESTAMP_LEN>()
+                    .ok_or(PacketError::Truncated)?;
+                (Some(u64::from_le_bytes(*ts)), payload)
+            }
+        };
+        let payload_len = u16_at(10);
+        let payload = payload
+            .get(..usize::from(payload_len))
+            .ok_or(PacketError::Truncated)?;
+        let header = Self {
+            session_id,
+            sequence: u16_at(6),
+            fragment: u16_at(8),
+            last: flags & FLAG_LAST_FRAGMENT != 0,
+            timestamp,
+            payload_len,
+        };
+        Ok((header, payload))
+    }
+    /// Bytes the header takes, the timestamp included
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.timestamp.map_or(0, |_| TIMESTAMP_LEN)
+    }
+}
+
+/// Whether the packet ends a unit, as far as it tells without being checked
+pub(crate) fn ends_unit(packet: &[u8], format: PacketFormat) -> bool {
+    match format {
+        PacketFormat::Legacy => packet.starts_with(FRAME_END),
+        PacketFormat::Header => PacketHeader::decode(packet).is_ok_and(|(header, _)| header.last),
+    }
+}
+
+/// A NAL unit put back together, borrowed from the builder until the next packet
+/// * `timestamp` - capture timestamp the peer sent along with the end of the unit, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NalUnit<'a> {
+    pub data: &'a [u8],
+    pub timestamp: Option<u64>,
+}
+
+/// Why a packet wasn't used. The builder goes on with the next one either way
+/// * `Truncated` - too short for the metadata of a packet
+/// * `InvalidIdentifier` - identifier 0, the packets of a unit are numbered from 1
+/// * `OtherSession` - packet of another session, i.e. of the previous call
+/// * `Duplicate` - received lately already
+/// * `Replayed` - older than the packets remembered, so possibly received already
+/// * `TooFarAhead` - further ahead than the window reaches, taken once enough packets follow it.
+///   See `ReplayWindow`
+/// * `Missing` - that many packets before it were lost, the unit is dropped
+/// * `Incomplete` - the unit it belongs to was dropped already
+/// * `Overflow` - the unit is larger than the builder takes, it's dropped.
+///   See `NalBuilder::set_max_unit_size`
+/// * `UnsupportedVersion` - header of another version than the one negotiated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    Truncated,
+    InvalidIdentifier,
+    OtherSession,
+    Duplicate,
+    Replayed,
+    TooFarAhead,
+    Missing(u32),
+    Incomplete,
+    Overflow,
+    UnsupportedVersion(u8),
+}
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "packet shorter than its metadata"),
+            Self::InvalidIdentifier => write!(f, "packet identifier 0"),
+            Self::OtherSession => write!(f, "packet of another session"),
+            Self::Duplicate => write!(f, "duplicated packet"),
+            Self::Replayed => write!(f, "packet older than the replay window"),
+            Self::TooFarAhead => write!(f, "packet too far ahead of the replay window"),
+            Self::Missing(count) => write!(f, "{count} packets before it were lost"),
+            Self::Incomplete => write!(f, "packet of a dropped NAL unit"),
+            Self::Overflow => write!(f, "NAL unit larger than the size limit"),
+            Self::UnsupportedVersion(version) => write!(f, "packet header of version {version}"),
+        }
+    }
+}
+impl std::error::Error for PacketError {}
+
+/// NAL unit builder for a H.264 stream over UDP.
+/// The NAL units cannot be safely sent over UDP without splitting them into smaller packets.
+/// This object uses the underlying decoder only when the whole NAL unit can be re-created.
+/// TODO: UDP PACKETS MIGHT COME UNORDERED, SAVE THE LAST N OF THEM AND LOOK IF CAN RECOVER
+pub struct NalBuilder {
+    finished: bool,
+    failed: bool,
+    /// Data of the unit being built. Grows with the units up to `max_unit_size`,
+    /// and is kept for the next ones
+    unit: Vec<u8>,
+    /// Units growing past that are dropped, `DEFAULT_MAX_NAL_UNIT_SIZE` unless set
+    max_unit_size: usize,
+    /// Identifier of the last packet. If the packet is lost, the NAL unit build is failed
+    last_packet: PacketIdentifier,
+    /// Sequence number of the last packet, the packets at the end of a unit
+    /// or between two units are lost if the next one doesn't follow it
+    last_sequence: PacketSequence,
+    /// Capture timestamp of the NAL unit, sent by the peer along with the end of the unit
+    timestamp: Option<u64>,
+    /// Packets of other sessions are discarded. 0 accepts packets of any session
+    session_id: u32,
+    /// Packets missing from the units since the builder was created
+    packets_lost: u64,
+    /// Sequence numbers of the packets received lately
+    replay: ReplayWindow,
+    /// `PacketFormat::Legacy` unless set
+    format: PacketFormat,
+}
+impl Default for NalBuilder {
+    fn default() -> Self {
+        Self {
+            finished: false,
+            failed: false,
+            unit: Vec::new(),
+            max_unit_size: DEFAULT_MAX_NAL_UNIT_SIZE,
+            last_packet: 0,
+            last_sequence: 0,
+            timestamp: None,
+            session_id: 0,
+            packets_lost: 0,
+            replay: ReplayWindow::default(),
+            format: PacketFormat::Legacy,
+        }
+    }
+}
+impl NalBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a packet of the stream. Returns the unit it ends, if every packet of it came.
+    /// Packets of a stale session and duplicated packets are dropped without touching the unit being built
+    /// # Errors
+    /// Returns why the packet wasn't used, see `PacketError`
+    pub fn push_packet(&mut self, packet: &[u8]) -> Result<Option<NalUnit<'_>>, PacketError> {
+        self.add_data(packet)?;
+        Ok(self.finished.then(|| NalUnit {
+            data: &self.unit,
+            timestamp: self.timestamp,
+        }))
+    }
+    /// Packets missing from the units so far, judging by the gaps in the identifiers
+    /// and the sequence numbers
+    pub fn packets_lost(&self) -> u64 {
+        self.packets_lost
+    }
+    /// Accept only the packets of given session from now on. The unit being built is dropped
+    pub fn set_session_id(&mut self, session_id: u32) {
+        self.session_id = session_id;
+        self.replay = ReplayWindow::default();
+        self.reset();
+    }
+    /// Drop the units larger than `size` bytes from now on, the one being built included.
+    /// The memory of the builder is bound by it, whatever the peer sends
+    pub fn set_max_unit_size(&mut self, size: usize) {
+        self.max_unit_size = size;
+    }
+    /// Take the packets in `format` from now on, see `SessionConfig::codecs`.
+    /// The unit being built is dropped
+    pub fn set_format(&mut self, format: PacketFormat) {
+        self.format = format;
+        self.reset();
+    }
+    fn is_active_session(&self, session_id: u32) -> bool {
+        self.session_id == 0 || self.session_id == session_id
+    }
+    /// Whether the packet was received already, remembering it if not
+    fn check_packet(
+        &mut self,
+        session_id: u32,
+        sequence: PacketSequence,
+    ) -> Result<(), PacketError> {
+        if !self.is_active_session(session_id) {
+            return Err(PacketError::OtherSession);
+        }
+        self.replay.check(session_id, sequence)
+    }
+    /// Fails the unit if the packet doesn't come right after the last one
+    fn follows_last(&mut self, sequence: PacketSequence) -> Result<(), PacketError> {
+        // The sequence numbers wrap around
+        let missing = sequence.wrapping_sub(self.last_sequence).wrapping_sub(1);
+        self.lose(missing.into())
+    }
+    /// Fails the unit if any packets were lost before the current one
+    fn lose(&mut self, missing: u32) -> Result<(), PacketError> {
+        if missing == 0 {
+            return Ok(());
+        }
+        self.packets_lost = self.packets_lost.saturating_add(missing.into());
+        self.failed = true;
+        Err(PacketError::Missing(missing))
+    }
+
+    fn reset(&mut self) {
+        self.finished = false;
+        self.failed = false;
+        self.last_packet = 0;
+        self.unit.clear();
+        self.timestamp = None;
+    }
+    /// Add data from the buffer. The more, the better
+    /// A finished unit is only available until the next packet is added
+    fn add_data(&mut self, buf: &[u8]) -> Result<(), PacketError> {
+        if self.finished {
+            self.reset();
+        }
+        let fragment = match self.format {
+            PacketFormat::Legacy => Fragment::decode_legacy(buf)?,
+            PacketFormat::Header => Fragment::decode(buf)?,
+        };
+        self.check_packet(fragment.session_id, fragment.sequence)?;
+        let added = match fragment.ident {
+            Some(ident) => self.add_fragment(fragment.data, fragment.sequence, ident),
+            None if self.failed || self.unit.is_empty() => Err(PacketError::Incomplete),
+            // The last packets of the unit may be lost, which only the sequence number tells
+            None => self.follows_last(fragment.sequence),
+        };
+        // Ends the unit even if it failed, so the next one starts clean
+        if fragment.last {
+            self.timestamp = fragment.timestamp;
+            self.finished = true;
+        }
+        added
+    }
+    /// Copy the data of a packet into the unit, if every packet before it came
+    fn add_fragment(
+        &mut self,
+        data: &[u8],
+        sequence: PacketSequence,
+        ident: PacketIdentifier,
+    ) -> Result<(), PacketError> {
+        if ident <= self.last_packet {
+            self.reset();
+        }
+        if self.failed {
+            return Err(PacketError::Incomplete);
+        }
+        let missing = ident
+            .checked_sub(1)
+            .and_then(|previous| previous.checked_sub(self.last_packet))
+            .ok_or(PacketError::InvalidIdentifier)?;
+        self.lose(missing)?;
+        // The identifiers go on, but the packet may be of the next unit
+        if self.last_packet > 0 {
+            self.follows_last(sequence)?;
+        }
+        self.last_packet = ident;
+        self.last_sequence = sequence;
+        if self.unit.len() + data.len() > self.max_unit_size {
+            self.failed = true;
+            return Err(PacketError::Overflow);
+        }
+        self.unit.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A packet taken apart, whichever format it came in
+/// * `ident` - number of the packet within its unit, from 1. None for the legacy `FRAME_END`
+/// * `last` - the packet ends its unit
+/// * `timestamp` - capture timestamp of the unit, sent along with its end
+struct Fragment<'a> {
+    data: &'a [u8],
+    session_id: u32,
+    sequence: PacketSequence,
+    ident: Option<PacketIdentifier>,
+    last: bool,
+    timestamp: Option<u64>,
+}
+impl<'a> Fragment<'a> {
+    /// A packet of `PacketFormat::Header`
+    fn decode(packet: &'a [u8]) -> Result<Self, PacketError> {
+        let (header, data) = PacketHeader::decode(packet)?;
+        Ok(Self {
+            data,
+            session_id: header.session_id,
+            sequence: header.sequence,
+            ident: Some(PacketIdentifier::from(header.fragment) + 1),
+            last: header.last,
+            timestamp: header.timestamp,
+        })
+    }
+    /// A packet of `PacketFormat::Legacy`, see `packetize`
+    fn decode_legacy(packet: &'a [u8]) -> Result<Self, PacketError> {
+        const END_LEN: usize = FRAME_END.len() + TIMESTAMP_LEN + SESSION_ID_LEN + SEQUENCE_LEN;
+        if packet.starts_with(FRAME_END) && packet.len() == END_LEN {
+            let (ts, meta) = packet[FRAME_END.len()..].split_at(TIMESTAMP_LEN);
+            let (session, sequence) = meta.split_at(SESSION_ID_LEN);
+            return Ok(Self {
+                data: &[],
+                session_id: u32::from_le_bytes(session.try_into().unwrap()),
+                sequence: PacketSequence::from_le_bytes(sequence.try_into().unwrap()),
+                ident: None,
+                last: true,
+                timestamp: ts.try_into().ok().map(u64::from_le_bytes),
+            });
+        }
+        if packet.len() <= PACKET_META_LEN {
+            return Err(PacketError::Truncated);
+        }
+        let (data, meta) = packet.split_at(packet.len() - PACKET_META_LEN);
+        let (session_slice, rest) = meta.split_at(SESSION_ID_LEN);
+        let (sequence_slice, ident_slice) = rest.split_at(SEQUENCE_LEN);
+        let ident = u32::from_le_bytes(ident_slice.try_into().unwrap());
+        if ident == 0 {
+            return Err(PacketError::InvalidIdentifier);
+        }
+        Ok(Self {
+            data,
+            session_id: u32::from_le_bytes(session_slice.try_into().unwrap()),
+            sequence: PacketSequence::from_le_bytes(sequence_slice.try_into().unwrap()),
+            ident: Some(ident),
+            last: false,
+            timestamp: None,
+        })
+    }
+}
+
+/// Sliding window over the sequence numbers of a session, as in RFC 4303.
+/// The sequence numbers are extended past their 16 bits, so the window slides on when they wrap.
+/// It only jumps further than `REPLAY_WINDOW` ahead after `REPLAY_JUMP_PACKETS` in a row
+/// * `newest` - session and extended sequence number of the newest packet, None before the first
+/// * `received` - packets up to `REPLAY_WINDOW` behind the newest one, a bit each
+/// * `jump` - extended sequence number of the last packet too far ahead, and the packets in a row
+///   up to it
+#[derive(Clone, Copy, Debug, Default)]
+struct ReplayWindow {
+    newest: Option<(u32, u64)>,
+    received: u64,
+    jump: Option<(u64, u8)>,
+}
+impl ReplayWindow {
+    /// Remembers the packet, unless it was received already or is too old to tell
+    fn check(&mut self, session_id: u32, sequence: PacketSequence) -> Result<(), PacketError> {
+        let Some((_, newest)) = self.newest.filter(|(session, _)| *session == session_id) else {
+            // The sequence numbers start over with every session.
+            // Kept off 0, so the packets of the first round behind it don't underflow
+            self.newest = Some((session_id, u64::from(sequence) + (1 << 16)));
+            self.received = 1;
+            self.jump = None;
+            return Ok(());
+        };
+        // The closest one to the newest, ahead or behind
+        let distance = sequence.wrapping_sub(newest as PacketSequence) as i16;
+        let extended = newest.wrapping_add_signed(distance.into());
+        if extended > newest {
+            let ahead = extended - newest;
+            if ahead >= REPLAY_WINDOW {
+                let in_row = match self.jump {
+                    Some((last, count)) if extended == last + 1 => count + 1,
+                    _ => 1,
+                };
+                if in_row < REPLAY_JUMP_PACKETS {
+                    self.jump = Some((extended, in_row));
+                    return Err(PacketError::TooFarAhead);
+                }
+            }
+            self.jump = None;
+            self.received = if ahead < REPLAY_WINDOW {
+                (self.received << ahead) | 1
+            } else {
+                1
+            };
+            self.newest = Some((session_id, extended));
+            return Ok(());
+        }
+        let behind = newest - extended;
+        if behind >= REPLAY_WINDOW {
+            return Err(PacketError::Replayed);
+        }
+        if self.received & (1 << behind) != 0 {
+            return Err(PacketError::Duplicate);
+        }
+        self.received |= 1 << behind;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::quickcheck;
+    use scp_client::client::PacketFormat;
+
+    use super::{
+        ends_unit, packetize, NalBuilder, PacketBuffer, PacketError, PacketHeader,
+        DEFAULT_PACKET_DATA_SIZE, HEADER_LEN, PACKET_META_LEN, REPLAY_WINDOW, SESSION_ID_LEN,
+    };
+
+    const SESSION: u32 = 7;
+    const FORMATS: [PacketFormat; 2] = [PacketFormat::Legacy, PacketFormat::Header];
+
+    /// Up to 20 units of the generated ones, none empty as nothing is sent for those
+    fn units(generated: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        generated
+            .into_iter()
+            .filter(|unit| !unit.is_empty())
+            .take(20)
+            .collect()
+    }
+    /// Packets of the units with up to 16 bytes of data each, numbered on like in a stream.
+    /// The capture timestamp of each unit is its index
+    fn stream(units: &[Vec<u8>], size: u8) -> Vec<Vec<u8>> {
+        stream_in(PacketFormat::Legacy, units, size)
+    }
+    fn stream_in(format: PacketFormat, units: &[Vec<u8>], size: u8) -> Vec<Vec<u8>> {
+        let mut buffer = PacketBuffer::default();
+        buffer.set_format(format);
+        buffer.set_packet_size(usize::from(size % 16) + 1);
+        for (i, unit) in units.iter().enumerate() {
+            buffer.push_unit(unit, i as u64, SESSION);
+        }
+        buffer.packets().map(<[u8]>::to_vec).collect()
+    }
+    /// Every packet of `at` arrives again up to 8 packets later, close enough to be told apart
+    fn duplicate(packets: &mut Vec<Vec<u8>>, at: &[usize]) {
+        for &i in at.iter().take(10) {
+            if packets.is_empty() {
+                return;
+            }
+            let copy = packets[i % packets.len()].clone();
+            let to = (i % packets.len() + 1 + i % 8).min(packets.len());
+            packets.insert(to, copy);
+        }
+    }
+    fn builder_for(format: PacketFormat) -> NalBuilder {
+        let mut builder = NalBuilder::new();
+        builder.set_format(format);
+        builder.set_session_id(SESSION);
+        builder
+    }
+    /// The units rebuilt out of the packets, with their timestamps
+    fn rebuild(builder: &mut NalBuilder, packets: &[Vec<u8>]) -> Vec<(Vec<u8>, Option<u64>)> {
+        let mut rebuilt = Vec::new();
+        for packet in packets {
+            if let Ok(Some(unit)) = builder.push_packet(packet) {
+                rebuilt.push((unit.data.to_vec(), unit.timestamp));
+            }
+        }
+        rebuilt
+    }
+
+    #[test]
+    fn test_in_order_stream() {
+        fn prop(generated: Vec<Vec<u8>>, size: u8, duplicates: Vec<usize>) -> bool {
+            let units = units(generated);
+            let expected: Vec<_> = units
+                .iter()
+                .enumerate()
+                .map(|(i, unit)| (unit.clone(), Some(i as u64)))
+                .collect();
+            FORMATS.into_iter().all(|format| {
+                let mut packets = stream_in(format, &units, size);
+                duplicate(&mut packets, &duplicates);
+                let mut builder = builder_for(format);
+                rebuild(&mut builder, &packets) == expected && builder.packets_lost() == 0
+            })
+        }
+        quickcheck(prop as fn(Vec<Vec<u8>>, u8, Vec<usize>) -> bool);
+    }
+    #[test]
+    fn test_impaired_stream() {
+        /// Whatever is lost, reordered or duplicated, a unit is rebuilt whole or not at all
+        fn prop(
+            generated: Vec<Vec<u8>>,
+            size: u8,
+            lost: Vec<bool>,
+            swaps: Vec<(usize, u8)>,
+            duplicates: Vec<usize>,
+        ) -> bool {
+            let units = units(generated);
+            FORMATS.into_iter().all(|format| {
+                let mut packets: Vec<_> = stream_in(format, &units, size)
+                    .into_iter()
+                    .zip(lost.iter().copied().chain(std::iter::repeat(false)))
+                    .filter_map(|(packet, lost)| (!lost).then_some(packet))
+                    .collect();
+                for &(i, distance) in &swaps {
+                    if packets.is_empty() {
+                        break;
+                    }
+                    let i = i % packets.len();
+                    let j = (i + usize::from(distance % 8)).min(packets.len() - 1);
+                    packets.swap(i, j);
+                }
+                duplicate(&mut packets, &duplicates);
+                let mut builder = builder_for(format);
+                rebuild(&mut builder, &packets)
+                    .into_iter()
+                    .all(|(data, ts)| {
+                        ts.and_then(|ts| units.get(ts as usize))
+                            .is_some_and(|unit| *unit == data)
+                    })
+            })
+        }
+        quickcheck(prop as fn(Vec<Vec<u8>>, u8, Vec<bool>, Vec<(usize, u8)>, Vec<usize>) -> bool);
+    }
+    #[test]
+    fn test_lost_tail() {
+        // Three packets of data and the end each
+        let units = [vec![1; 40], vec![2; 40]];
+        let packets = stream(&units, 15);
+        let mut builder = NalBuilder::new();
+        let mut lost_tail = packets.clone();
+        lost_tail.remove(2);
+        assert_eq!(
+            rebuild(&mut builder, &lost_tail),
+            [(units[1].clone(), Some(1))]
+        );
+        assert_eq!(builder.packets_lost(), 1);
+        // The identifiers of the second unit go on where the first one stopped
+        let mut mixed = packets;
+        mixed.drain(2..5);
+        mixed.remove(2);
+        let mut builder = NalBuilder::new();
+        assert!(rebuild(&mut builder, &mixed).is_empty());
+    }
+    #[test]
+    fn test_malformed_packets() {
+        let mut builder = NalBuilder::new();
+        assert_eq!(builder.push_packet(&[]), Err(PacketError::Truncated));
+        assert_eq!(
+            builder.push_packet(&[1; PACKET_META_LEN]),
+            Err(PacketError::Truncated)
+        );
+        // Identifier 0 would underflow the count of the missing packets
+        let mut zero = packetize(&[5; 10], 0, 0).remove(0);
+        let len = zero.len();
+        zero[len - 4..].fill(0);
+        assert_eq!(
+            builder.push_packet(&zero),
+            Err(PacketError::InvalidIdentifier)
+        );
+        let mut last = packetize(&[5; 10], 0, 0).remove(0);
+        last[len - 4..].fill(0xFF);
+        assert_eq!(
+            builder.push_packet(&last),
+            Err(PacketError::Missing(u32::MAX - 1))
+        );
+
+        // More data than fits a unit, the next one is still rebuilt
+        const LIMIT: usize = 65535;
+        let units = [vec![3; LIMIT + 1], vec![4; 100]];
+        let mut packets = stream(&units, u8::MAX);
+        let next = packets.split_off(packets.len() - 8);
+        let mut builder = NalBuilder::new();
+        builder.set_max_unit_size(LIMIT);
+        let errors: Vec<_> = packets
+            .iter()
+            .filter_map(|packet| builder.push_packet(packet).err())
+            .collect();
+        assert_eq!(errors.first(), Some(&PacketError::Overflow));
+        assert_eq!(errors.last(), Some(&PacketError::Incomplete));
+        assert!(errors[1..].iter().all(|e| *e == PacketError::Incomplete));
+        assert_eq!(rebuild(&mut builder, &next), [(units[1].clone(), Some(1))]);
+    }
+    #[test]
+    fn test_packet_header() {
+        let header = PacketHeader {
+            session_id: SESSION,
+            sequence: 0xFFFE,
+            fragment: 3,
+            last: true,
+            timestamp: Some(42),
+            payload_len: 5,
+        };
+        let mut packet = Vec::new();
+        header.encode(&mut packet);
+        assert_eq!(packet.len(), header.encoded_len());
+        assert_eq!(
+            packet[..HEADER_LEN],
+            [2, 0b11, 7, 0, 0, 0, 0xFE, 0xFF, 3, 0, 5, 0]
+        );
+        packet.extend_from_slice(&[1, 2, 3, 4, 5]);
+        assert_eq!(
+            PacketHeader::decode(&packet),
+            Ok((header, &[1, 2, 3, 4, 5][..]))
+        );
+        // Trailing bytes and unknown flags are left for the versions to come
+        packet.push(6);
+        packet[1] |= 0x80;
+        assert_eq!(
+            PacketHeader::decode(&packet),
+            Ok((header, &[1, 2, 3, 4, 5][..]))
+        );
+
+        let middle = PacketHeader {
+            last: false,
+            timestamp: None,
+            ..header
+        };
+        let mut packet = Vec::new();
+        middle.encode(&mut packet);
+        assert_eq!(packet.len(), HEADER_LEN);
+        assert_eq!(PacketHeader::decode(&packet), Err(PacketError::Truncated));
+        packet.extend_from_slice(&[0; 5]);
+        assert_eq!(PacketHeader::decode(&packet).unwrap().0, middle);
+        assert_eq!(
+            PacketHeader::decode(&packet[..HEADER_LEN - 1]),
+            Err(PacketError::Truncated)
+        );
+        packet[0] = 3;
+        assert_eq!(
+            PacketHeader::decode(&packet),
+            Err(PacketError::UnsupportedVersion(3))
+        );
+    }
+    #[test]
+    fn test_header_stream() {
+        // Three fragments each, the timestamp along with the last one
+        let units = [vec![1; 40], vec![2; 40]];
+        let packets = stream_in(PacketFormat::Header, &units, 15);
+        assert_eq!(packets.len(), 6);
+        let ends: Vec<_> = packets
+            .iter()
+            .map(|packet| ends_unit(packet, PacketFormat::Header))
+            .collect();
+        assert_eq!(ends, [false, false, true, false, false, true]);
+        let (last, _) = PacketHeader::decode(&packets[5]).unwrap();
+        assert_eq!(
+            (last.fragment, last.sequence, last.timestamp),
+            (2, 5, Some(1))
+        );
+
+        // The unit missing its last fragment is dropped, the next one still rebuilt
+        let mut lost_tail = packets.clone();
+        lost_tail.remove(2);
+        let mut builder = builder_for(PacketFormat::Header);
+        assert_eq!(
+            rebuild(&mut builder, &lost_tail),
+            [(units[1].clone(), Some(1))]
+        );
+        let mut lost_middle = packets.clone();
+        lost_middle.remove(1);
+        let mut builder = builder_for(PacketFormat::Header);
+        assert_eq!(
+            rebuild(&mut builder, &lost_middle),
+            [(units[1].clone(), Some(1))]
+        );
+        assert_eq!(builder.packets_lost(), 1);
+
+        // Either format is dropped by a builder expecting the other one
+        let mut builder = builder_for(PacketFormat::Legacy);
+        assert!(rebuild(&mut builder, &packets).is_empty());
+        let mut builder = builder_for(PacketFormat::Header);
+        assert!(rebuild(&mut builder, &stream(&units, 15)).is_empty());
+        assert_eq!(
+            builder.push_packet(&[1; PACKET_META_LEN + 5]),
+            Err(PacketError::UnsupportedVersion(1))
+        );
+    }
+    #[test]
+    fn test_large_units() {
+        // Keyframes of 200 KB between the frames, in packets of the default size
+        let keyframe =
+            |seed: u8| -> Vec<u8> { (0..200 * 1024).map(|b: u32| (b as u8) ^ seed).collect() };
+        let units = [keyframe(1), vec![2; 900], keyframe(3), vec![4; 900]];
+        for format in FORMATS {
+            let mut buffer = PacketBuffer::default();
+            buffer.set_format(format);
+            for (i, unit) in units.iter().enumerate() {
+                buffer.push_unit(unit, i as u64, SESSION);
+            }
+            let packets: Vec<Vec<u8>> = buffer.packets().map(<[u8]>::to_vec).collect();
+            assert!(packets.len() > 2 * 200 * 1024 / DEFAULT_PACKET_DATA_SIZE);
+            let expected: Vec<_> = units
+                .iter()
+                .enumerate()
+                .map(|(i, unit)| (unit.clone(), Some(i as u64)))
+                .collect();
+            let mut builder = builder_for(format);
+            assert_eq!(rebuild(&mut builder, &packets), expected);
+
+            // Past the limit only the keyframes are dropped, the rest goes on
+            let mut builder = builder_for(format);
+            builder.set_max_unit_size(100 * 1024);
+            assert_eq!(
+                rebuild(&mut builder, &packets),
+                [expected[1].clone(), expected[3].clone()]
+            );
+            assert_eq!(builder.packets_lost(), 0);
+        }
+    }
+    #[test]
+    fn test_replayed_packets() {
+        // A keyframe, then enough units to push it out of the window
+        let units: Vec<Vec<u8>> = (0..=REPLAY_WINDOW as u8).map(|i| vec![i; 20]).collect();
+        let packets = stream(&units, 15);
+        let mut builder = NalBuilder::new();
+        builder.set_session_id(SESSION);
+        assert_eq!(rebuild(&mut builder, &packets).len(), units.len());
+        for packet in &packets[..packets.len() - REPLAY_WINDOW as usize] {
+            assert_eq!(builder.push_packet(packet), Err(PacketError::Replayed));
+        }
+        let recent = &packets[packets.len() - 2];
+        assert_eq!(builder.push_packet(recent), Err(PacketError::Duplicate));
+
+        // Another session starts its sequence numbers over
+        let mut builder = NalBuilder::new();
+        assert_eq!(rebuild(&mut builder, &packets).len(), units.len());
+        let mut buffer = PacketBuffer::default();
+        buffer.push_unit(&[9; 10], 0, SESSION + 1);
+        let next: Vec<Vec<u8>> = buffer.packets().map(<[u8]>::to_vec).collect();
+        assert_eq!(rebuild(&mut builder, &next), [(vec![9; 10], Some(0))]);
+    }
+    #[test]
+    fn test_forged_packets_ahead() {
+        let units: Vec<Vec<u8>> = (0..100).map(|i| vec![i; 20]).collect();
+        let packets = stream(&units, 15);
+        let per_unit = packets.len() / units.len();
+        // A data packet numbered far ahead of the stream, as anyone may send without SRTP
+        let mut forged = packets[0].clone();
+        let at = forged.len() - PACKET_META_LEN + SESSION_ID_LEN;
+        forged[at..at + 2].copy_from_slice(&30_000u16.to_le_bytes());
+
+        let mut builder = NalBuilder::new();
+        builder.set_session_id(SESSION);
+        assert_eq!(rebuild(&mut builder, &packets[..per_unit]).len(), 1);
+        assert_eq!(builder.push_packet(&forged), Err(PacketError::TooFarAhead));
+        // The genuine packets go on, none of them is taken as replayed
+        assert_eq!(
+            rebuild(&mut builder, &packets[per_unit..]).len(),
+            units.len() - 1
+        );
+
+        // A gap of the genuine stream is jumped over once the packets after it follow each other
+        let mut builder = NalBuilder::new();
+        builder.set_session_id(SESSION);
+        let gap = REPLAY_WINDOW as usize + per_unit;
+        let mut after_gap = packets[..per_unit].to_vec();
+        after_gap.extend_from_slice(&packets[per_unit + gap..]);
+        let rebuilt = rebuild(&mut builder, &after_gap);
+        let expected: Vec<_> = units[units.len() - 50..]
+            .iter()
+            .enumerate()
+            .map(|(i, unit)| (unit.clone(), Some((units.len() - 50 + i) as u64)))
+            .collect();
+        assert!(rebuilt.ends_with(&expected));
+    }
+}
@@ -0,0 +1,188 @@
+//! Pacing of the outgoing stream. A keyframe is dozens of packets, and sent back to back
+//! they overflow the queues of consumer routers, so the packets of exactly the frames the peer
+//! can't do without get lost. The `Pacer` is a token bucket: a frame within `BURST_BYTES`
+//! goes at once, the rest of it is spread at a rate following the bitrate budget of the call,
+//! but never past the frame interval, so the stream doesn't fall behind the camera.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::udp_batch::send_all;
+
+/// Bytes sent at once before the pacing kicks in, about the size of a usual inter frame
+const BURST_BYTES: f64 = 12. * 1024.;
+/// The packets are paced this many times faster than the bitrate budget,
+/// the frames between the keyframes have to catch up with them
+const PACING_FACTOR: f64 = 2.5;
+/// Packets due within that long are sent in a single batch, sleeping is coarser anyway
+const SEND_SLACK: Duration = Duration::from_millis(1);
+
+/// Token bucket spreading the packets of a frame
+/// * `rate` - bytes a second, None sends every packet at once
+/// * `tokens` - bytes that may still go at once, as of `refilled`
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    rate: Option<f64>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            tokens: BURST_BYTES,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+impl Pacer {
+    /// Pace for a bitrate budget of `kbps`, or send at once for None
+    pub(crate) fn set_rate(&mut self, kbps: Option<u32>) {
+        self.rate = kbps
+            .filter(|&kbps| kbps > 0)
+            .map(|kbps| f64::from(kbps) * 1000. / 8. * PACING_FACTOR);
+    }
+    /// When each packet of a frame of `sizes` is due, the last one within `window` from `now`
+    pub(crate) fn schedule(
+        &mut self,
+        sizes: &[usize],
+        window: Duration,
+        now: Instant,
+    ) -> Vec<Instant> {
+        let Some(rate) = self.rate else {
+            return vec![now; sizes.len()];
+        };
+        let mut at = now.max(self.refilled);
+        self.tokens =
+            (self.tokens + at.duration_since(self.refilled).as_secs_f64() * rate).min(BURST_BYTES);
+        // Faster if the frame would take longer than the next one takes to come
+        let total: usize = sizes.iter().sum();
+        let debt = total as f64 - self.tokens;
+        let rate = match window.as_secs_f64() {
+            window if window > 0. && debt > 0. => rate.max(debt / window),
+            _ => rate,
+        };
+        let times = sizes
+            .iter()
+            .map(|&size| {
+                let size = size as f64;
+                if self.tokens < size {
+                    at += Duration::from_secs_f64((size - self.tokens) / rate);
+                    self.tokens = size;
+                }
+                self.tokens -= size;
+                at
+            })
+            .collect();
+        self.refilled = at;
+        times
+    }
+}
+
+/// Send all `packets` to `addr`, each once its time in `times` comes, see `Pacer::schedule`.
+/// Sleeps until the last one is sent
+/// # Errors
+/// Returns the first error of `send_all`, the rest of the packets isn't sent then
+pub(crate) fn send_paced(
+    socket: &UdpSocket,
+    packets: &[&[u8]],
+    times: &[Instant],
+    addr: SocketAddr,
+) -> io::Result<()> {
+    let mut next = 0;
+    while next < packets.len() {
+        let now = Instant::now();
+        if times[next] > now {
+            std::thread::sleep(times[next] - now);
+        }
+        let due = Instant::now() + SEND_SLACK;
+        let count = times[next..packets.len()]
+            .iter()
+            .take_while(|&&at| at <= due)
+            .count()
+            .max(1);
+        send_all(socket, &packets[next..next + count], addr)?;
+        next += count;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use super::{send_paced, Pacer, BURST_BYTES, PACING_FACTOR, SEND_SLACK};
+
+    const WINDOW: Duration = Duration::from_millis(33);
+
+    #[test]
+    fn test_unpaced() {
+        let mut pacer = Pacer::default();
+        let now = Instant::now();
+        assert_eq!(pacer.schedule(&[1200; 100], WINDOW, now), vec![now; 100]);
+        pacer.set_rate(Some(0));
+        assert_eq!(pacer.schedule(&[1200; 100], WINDOW, now), vec![now; 100]);
+    }
+    #[test]
+    fn test_small_frames_at_once() {
+        let mut pacer = Pacer::default();
+        pacer.set_rate(Some(1000));
+        let now = Instant::now();
+        assert_eq!(pacer.schedule(&[1200; 8], WINDOW, now), vec![now; 8]);
+        // Refilled by the next frame
+        let next = now + WINDOW;
+        assert_eq!(pacer.schedule(&[1200; 8], WINDOW, next), vec![next; 8]);
+    }
+    #[test]
+    fn test_keyframe_spread() {
+        let mut pacer = Pacer::default();
+        pacer.set_rate(Some(1000));
+        let now = Instant::now();
+        let times = pacer.schedule(&[1200; 100], WINDOW, now);
+        let at_once = times.iter().filter(|&&at| at == now).count();
+        assert_eq!(at_once, BURST_BYTES as usize / 1200);
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+        // Within the window, with a little room for the rounding
+        let last = times.last().unwrap().duration_since(now);
+        assert!(last > WINDOW * 9 / 10 && last <= WINDOW + Duration::from_millis(1));
+        // The next frame waits for the tokens
+        let next = pacer.schedule(&[1200], WINDOW, now + Duration::from_millis(1));
+        assert!(next[0] > *times.last().unwrap());
+    }
+    #[test]
+    fn test_rate() {
+        let mut pacer = Pacer::default();
+        // Fast enough for the frame to fit the window as it is
+        pacer.set_rate(Some(20_000));
+        let rate = 20_000. * 1000. / 8. * PACING_FACTOR;
+        let now = Instant::now();
+        let times = pacer.schedule(&[1000; 40], WINDOW, now);
+        let gap = times[30].duration_since(times[20]).as_secs_f64();
+        assert!((gap - 10_000. / rate).abs() < 1e-6);
+    }
+    #[test]
+    fn test_send_paced() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let packets: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 100]).collect();
+        let slices: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+        let start = Instant::now();
+        let times: Vec<Instant> = (0..20).map(|i| start + Duration::from_millis(i)).collect();
+        send_paced(&sender, &slices, &times, receiver.local_addr().unwrap()).unwrap();
+        // The last one may go a little early along with the one before
+        assert!(start.elapsed() >= Duration::from_millis(19) - SEND_SLACK);
+
+        let mut buf = [0; 1024];
+        for packet in &packets {
+            let size = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..size], packet.as_slice());
+        }
+    }
+}
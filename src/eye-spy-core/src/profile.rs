@@ -0,0 +1,241 @@
+//! Where the time of the video pipeline goes, to tell when a change makes it slower.
+//! The streams time the stages of every frame into `LatencyHistogram`s kept with their counters,
+//! see `crate::stats`, and `ThreadCpuSampler` tells how busy each thread of the process is.
+//! `PipelineProfile` puts both together, for the debug overlay or a bug report.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::stats::{IncomingStats, OutgoingStats};
+
+/// Upper bounds of the latency buckets, in microseconds. The last bucket takes the rest
+pub const LATENCY_BUCKETS_US: [u64; 10] = [
+    500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 200_000, 500_000,
+];
+/// Clock ticks the kernel counts the CPU time of the threads in. Fixed at 100 for user space
+const USER_HZ: u64 = 100;
+
+/// Latencies of a pipeline stage, counted into `LATENCY_BUCKETS_US`.
+/// Recorded from the stream threads, read from anywhere
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    sum_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+    /// What was recorded so far
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        LatencySnapshot {
+            count: counts.iter().sum(),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+            buckets: counts,
+        }
+    }
+}
+
+/// A `LatencyHistogram` as it was when it was taken
+/// * `buckets` - samples in each of `LATENCY_BUCKETS_US` and then the rest, not cumulative
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub sum_us: u64,
+    pub buckets: Vec<u64>,
+}
+
+impl LatencySnapshot {
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_micros(self.sum_us / self.count))
+    }
+    /// Upper bound of the bucket the `quantile` (0 to 1) of the samples falls into.
+    /// None without samples, or if it falls into the last bucket which has no bound
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets
+            .iter()
+            .zip(LATENCY_BUCKETS_US)
+            .find(|(count, _)| {
+                seen += **count;
+                seen >= rank
+            })
+            .map(|(_, bound)| Duration::from_micros(bound))
+    }
+}
+
+/// CPU time of a thread of the process since the previous sample
+/// * `usage` - share of a single core, 1.0 is a core fully busy
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ThreadCpu {
+    pub tid: u32,
+    pub name: String,
+    pub usage: f64,
+}
+
+/// Samples the CPU time of the threads of the process from `/proc`, so only on Linux
+#[derive(Debug, Default)]
+pub struct ThreadCpuSampler {
+    /// When the previous sample was taken and the CPU time of every thread then
+    previous: Option<(Instant, HashMap<u32, Duration>)>,
+}
+
+impl ThreadCpuSampler {
+    /// How busy every thread was since the previous sample, the busiest first.
+    /// The first sample has nothing to compare to, so it's empty
+    /// # Errors
+    /// Returns an error if the threads cannot be listed, i.e. not on Linux
+    pub fn sample(&mut self) -> io::Result<Vec<ThreadCpu>> {
+        let now = Instant::now();
+        let mut times = HashMap::new();
+        let mut names = HashMap::new();
+        for entry in std::fs::read_dir("/proc/self/task")? {
+            let entry = entry?;
+            let Some(tid) = entry.file_name().to_str().and_then(|tid| tid.parse().ok()) else {
+                continue;
+            };
+            // The thread may have exited since it was listed
+            let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+                continue;
+            };
+            let Some((name, time)) = parse_stat(&stat) else {
+                continue;
+            };
+            times.insert(tid, time);
+            names.insert(tid, name);
+        }
+        let mut threads = Vec::new();
+        if let Some((then, ref previous)) = self.previous {
+            let elapsed = now.duration_since(then).as_secs_f64();
+            for (tid, time) in &times {
+                let before = previous.get(tid).copied().unwrap_or_default();
+                threads.push(ThreadCpu {
+                    tid: *tid,
+                    name: names.remove(tid).unwrap_or_default(),
+                    usage: time.saturating_sub(before).as_secs_f64() / elapsed.max(f64::EPSILON),
+                });
+            }
+        }
+        threads.sort_by(|a, b| b.usage.total_cmp(&a.usage));
+        self.previous = Some((now, times));
+        Ok(threads)
+    }
+}
+
+/// Name and CPU time, user and system, of a thread from its `/proc/<pid>/task/<tid>/stat`
+fn parse_stat(stat: &str) -> Option<(String, Duration)> {
+    // The name is in parentheses and may contain spaces or parentheses itself
+    let name = &stat[stat.find('(')? + 1..stat.rfind(')')?];
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    // utime and stime are the 14th and 15th fields, the state the 3rd
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    let ticks = utime + stime;
+    Some((
+        name.to_string(),
+        Duration::from_millis(ticks * 1000 / USER_HZ),
+    ))
+}
+
+/// Latency of every stage of the pipeline, and how busy the threads are
+/// * `capture_to_encode` - a camera frame captured until it's encoded, filters included
+/// * `encode_to_send` - an encoded frame until it's sent to every destination
+/// * `receive_to_decode` - a NAL unit leaving the playout queue until it's decoded
+/// * `decode_to_display` - a decoded frame until the frontend shows it
+/// * `threads` - empty unless sampled, see `ThreadCpuSampler`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PipelineProfile {
+    pub capture_to_encode: LatencySnapshot,
+    pub encode_to_send: LatencySnapshot,
+    pub receive_to_decode: LatencySnapshot,
+    pub decode_to_display: LatencySnapshot,
+    pub threads: Vec<ThreadCpu>,
+}
+
+impl PipelineProfile {
+    /// The latencies recorded by the streams so far
+    pub fn take(outgoing: &OutgoingStats, incoming: &IncomingStats) -> Self {
+        Self {
+            capture_to_encode: outgoing.capture_to_encode.snapshot(),
+            encode_to_send: outgoing.encode_to_send.snapshot(),
+            receive_to_decode: incoming.receive_to_decode.snapshot(),
+            decode_to_display: incoming.decode_to_display.snapshot(),
+            threads: Vec::new(),
+        }
+    }
+    /// Name and latencies of every stage, in the order a frame goes through them
+    pub fn stages(&self) -> [(&'static str, &LatencySnapshot); 4] {
+        [
+            ("capture_to_encode", &self.capture_to_encode),
+            ("encode_to_send", &self.encode_to_send),
+            ("receive_to_decode", &self.receive_to_decode),
+            ("decode_to_display", &self.decode_to_display),
+        ]
+    }
+    /// The profile as pretty printed JSON, for a bug report
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_stat, LatencyHistogram, ThreadCpuSampler};
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.snapshot().quantile(0.5), None);
+        for ms in [1, 3, 3, 4, 15, 700] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 6);
+        assert_eq!(snapshot.buckets[1], 1);
+        assert_eq!(snapshot.buckets[3], 3);
+        assert_eq!(*snapshot.buckets.last().unwrap(), 1);
+        assert_eq!(snapshot.mean(), Some(Duration::from_micros(121_000)));
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(snapshot.quantile(0.8), Some(Duration::from_millis(20)));
+        // Slower than the last bound
+        assert_eq!(snapshot.quantile(1.), None);
+    }
+    #[test]
+    fn test_thread_cpu() {
+        let stat = "4242 (incoming (dec)) S 1 1 1 0 -1 4194368 0 0 0 0 250 50 0 0 20 0 1 0";
+        assert_eq!(
+            parse_stat(stat),
+            Some(("incoming (dec)".to_string(), Duration::from_secs(3)))
+        );
+        assert_eq!(parse_stat("4242 (broken"), None);
+
+        let mut sampler = ThreadCpuSampler::default();
+        if let Ok(first) = sampler.sample() {
+            assert!(first.is_empty());
+            let threads = sampler.sample().unwrap();
+            assert!(threads.iter().all(|thread| thread.usage >= 0.));
+        }
+    }
+}
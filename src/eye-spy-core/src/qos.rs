@@ -0,0 +1,186 @@
+//! Quality of service of the stream sockets. Home routers with QoS put the packets marked
+//! with a DSCP class ahead of the bulk traffic, so the video doesn't queue behind a download.
+//! The video is marked `Dscp::Af41` by default, the class meant for video calls.
+//! The socket buffers can be grown too, for the bursts of the keyframes, though the system
+//! caps them, i.e. at `net.core.wmem_max` and `net.core.rmem_max` on Linux.
+//! The settings the sockets end up with are logged, see `SocketSettings`.
+
+use std::fmt;
+use std::io;
+use std::net::UdpSocket;
+
+use socket2::SockRef;
+use tracing::{info, warn};
+
+/// DSCP class the stream packets are marked with
+/// * `Ef` - expedited forwarding, meant for voice. Some routers only prioritize that one
+/// * `Af41` - assured forwarding class 4, low drop precedence, meant for video calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dscp {
+    Ef,
+    Af41,
+}
+
+impl Dscp {
+    /// Code point, the upper 6 bits of the traffic class
+    pub fn code_point(self) -> u8 {
+        match self {
+            Dscp::Ef => 46,
+            Dscp::Af41 => 34,
+        }
+    }
+    /// The class by its name, as in `Display`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ef" => Some(Dscp::Ef),
+            "af41" => Some(Dscp::Af41),
+            _ => None,
+        }
+    }
+    /// Value of the IP TOS byte or IPv6 traffic class, the ECN bits left clear
+    fn traffic_class(self) -> u32 {
+        u32::from(self.code_point()) << 2
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dscp::Ef => write!(f, "EF"),
+            Dscp::Af41 => write!(f, "AF41"),
+        }
+    }
+}
+
+/// What the stream sockets are set up with
+/// * `send_buffer`, `recv_buffer` - bytes of the socket buffers, None leaves the system default
+/// * `dscp` - class the packets are marked with, None leaves them unmarked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketTuning {
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    pub dscp: Option<Dscp>,
+}
+
+impl Default for SocketTuning {
+    fn default() -> Self {
+        Self {
+            send_buffer: None,
+            recv_buffer: None,
+            dscp: Some(Dscp::Af41),
+        }
+    }
+}
+
+/// What a socket ended up with, the system may cap or round what was asked for
+/// * `send_buffer`, `recv_buffer` - bytes, as the system reports them. Linux reports twice
+///   the size asked for, the other half is kept for its bookkeeping
+/// * `dscp` - code point the packets are marked with, None if it can't be told
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketSettings {
+    pub send_buffer: usize,
+    pub recv_buffer: usize,
+    pub dscp: Option<u8>,
+}
+
+/// Set `socket` up with `tuning` and log what it ended up with.
+/// Options the system refuses are warned about, the rest is still set
+/// # Errors
+/// Returns an error if the settings of the socket cannot be read back
+pub fn tune_socket(socket: &UdpSocket, tuning: &SocketTuning) -> io::Result<SocketSettings> {
+    let sock = SockRef::from(socket);
+    let addr = socket.local_addr()?;
+    if let Some(size) = tuning.send_buffer {
+        if let Err(e) = sock.set_send_buffer_size(size) {
+            warn!("Cannot set the send buffer of {addr} to {size} bytes: {e}");
+        }
+    }
+    if let Some(size) = tuning.recv_buffer {
+        if let Err(e) = sock.set_recv_buffer_size(size) {
+            warn!("Cannot set the receive buffer of {addr} to {size} bytes: {e}");
+        }
+    }
+    if let Some(dscp) = tuning.dscp {
+        if let Err(e) = set_traffic_class(&sock, addr.is_ipv4(), dscp.traffic_class()) {
+            warn!("Cannot mark the packets of {addr} with {dscp}: {e}");
+        }
+    }
+    let settings = SocketSettings {
+        send_buffer: sock.send_buffer_size()?,
+        recv_buffer: sock.recv_buffer_size()?,
+        dscp: traffic_class(&sock, addr.is_ipv4())
+            .ok()
+            .map(|class| (class >> 2) as u8),
+    };
+    let capped = |asked: Option<usize>, got: usize| asked.is_some_and(|asked| got < asked);
+    if capped(tuning.send_buffer, settings.send_buffer)
+        || capped(tuning.recv_buffer, settings.recv_buffer)
+    {
+        warn!("The system caps the buffers of {addr}, i.e. with net.core.wmem_max and rmem_max");
+    }
+    let dscp = settings
+        .dscp
+        .map_or_else(|| "unknown".to_string(), |dscp| dscp.to_string());
+    info!(
+        "Socket {addr}: send buffer {} bytes, receive buffer {} bytes, DSCP {dscp}",
+        settings.send_buffer, settings.recv_buffer
+    );
+    Ok(settings)
+}
+
+fn set_traffic_class(sock: &SockRef, ipv4: bool, class: u32) -> io::Result<()> {
+    if ipv4 {
+        return sock.set_tos(class);
+    }
+    #[cfg(target_os = "linux")]
+    return sock.set_tclass_v6(class);
+    #[cfg(not(target_os = "linux"))]
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+fn traffic_class(sock: &SockRef, ipv4: bool) -> io::Result<u32> {
+    if ipv4 {
+        return sock.tos();
+    }
+    #[cfg(target_os = "linux")]
+    return sock.tclass_v6();
+    #[cfg(not(target_os = "linux"))]
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::{tune_socket, Dscp, SocketTuning};
+
+    #[test]
+    fn test_dscp() {
+        assert_eq!(Dscp::Ef.traffic_class(), 0xb8);
+        assert_eq!(Dscp::Af41.traffic_class(), 0x88);
+        assert_eq!(Dscp::from_name("AF41"), Some(Dscp::Af41));
+        assert_eq!(Dscp::from_name(&Dscp::Ef.to_string()), Some(Dscp::Ef));
+        assert_eq!(Dscp::from_name("cs1"), None);
+    }
+    #[test]
+    fn test_tune_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tuning = SocketTuning {
+            send_buffer: Some(64 * 1024),
+            recv_buffer: Some(64 * 1024),
+            dscp: Some(Dscp::Ef),
+        };
+        let settings = tune_socket(&socket, &tuning).unwrap();
+        // Within the limits of any system, so not capped
+        assert!(settings.send_buffer >= 64 * 1024);
+        assert!(settings.recv_buffer >= 64 * 1024);
+        assert_eq!(settings.dscp, Some(46));
+
+        let untouched = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tuning = SocketTuning {
+            dscp: None,
+            ..Default::default()
+        };
+        assert_eq!(tune_socket(&untouched, &tuning).unwrap().dscp, Some(0));
+    }
+}
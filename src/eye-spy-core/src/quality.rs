@@ -0,0 +1,126 @@
+//! Quality of the outgoing video the user picks. `Auto` leaves it to the call, which picks
+//! the camera mode for `CallConfig::bitrate_budget` and switches the layer the peer gets
+//! when it asks for a lower resolution, see `ControlMessage::LowResolution`.
+//! The other qualities are presets of a resolution, a frame rate and a bitrate. Picking one
+//! switches the camera to the best mode within it, tells the peer the new mode
+//! and stops the call from adapting the video until `Auto` is picked again.
+
+use scp_client::client::VideoMode;
+
+use crate::camera::pick_mode;
+
+/// Quality of the outgoing video
+/// * `Auto` - adapted to the peer during the call
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VideoQuality {
+    #[default]
+    Auto,
+    High,
+    Medium,
+    Low,
+}
+
+/// Limits of a quality
+/// * `bitrate` - kbit/s the camera mode is picked for, see `crate::camera::pick_mode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QualityPreset {
+    pub width: u16,
+    pub height: u16,
+    pub fps: u16,
+    pub bitrate: u32,
+}
+
+impl VideoQuality {
+    /// Every quality, in the order the user cycles through them
+    pub const ALL: [Self; 4] = [Self::Auto, Self::High, Self::Medium, Self::Low];
+
+    /// The limits of the quality, None for `Auto` which has none of its own
+    pub fn preset(self) -> Option<QualityPreset> {
+        let (width, height, fps, bitrate) = match self {
+            Self::Auto => return None,
+            Self::High => (1280, 720, 30, 3000),
+            Self::Medium => (640, 480, 24, 1000),
+            Self::Low => (320, 240, 15, 300),
+        };
+        Some(QualityPreset {
+            width,
+            height,
+            fps,
+            bitrate,
+        })
+    }
+    /// The quality after this one in `ALL`, wrapping around
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|quality| *quality == self);
+        Self::ALL[index.map_or(0, |index| (index + 1) % Self::ALL.len())]
+    }
+}
+
+impl std::fmt::Display for VideoQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::High => write!(f, "high"),
+            Self::Medium => write!(f, "medium"),
+            Self::Low => write!(f, "low"),
+        }
+    }
+}
+
+impl QualityPreset {
+    /// The best of `modes` within the preset, slowed down to its frame rate.
+    /// The cheapest one if none fits it, None only if there are no modes
+    pub fn pick_mode(&self, modes: &[VideoMode]) -> Option<VideoMode> {
+        let capped: Vec<VideoMode> = modes
+            .iter()
+            .map(|mode| VideoMode {
+                fps: mode.fps.min(self.fps),
+                ..*mode
+            })
+            .collect();
+        let fitting: Vec<VideoMode> = capped
+            .iter()
+            .filter(|mode| mode.width <= self.width && mode.height <= self.height)
+            .copied()
+            .collect();
+        pick_mode(&fitting, self.bitrate).or_else(|| pick_mode(&capped, self.bitrate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scp_client::client::VideoMode;
+
+    use crate::camera::estimated_bitrate;
+
+    use super::VideoQuality;
+
+    fn mode(width: u16, height: u16, fps: u16) -> VideoMode {
+        VideoMode { width, height, fps }
+    }
+
+    #[test]
+    fn test_quality_presets() {
+        let modes = [mode(640, 480, 30), mode(320, 240, 30), mode(160, 120, 30)];
+        let pick = |quality: VideoQuality| quality.preset().unwrap().pick_mode(&modes);
+        assert_eq!(pick(VideoQuality::High), Some(mode(640, 480, 30)));
+        assert_eq!(pick(VideoQuality::Medium), Some(mode(640, 480, 24)));
+        assert_eq!(pick(VideoQuality::Low), Some(mode(320, 240, 15)));
+        // Each preset fits a mode of its own size
+        for quality in &VideoQuality::ALL[1..] {
+            let preset = quality.preset().unwrap();
+            let own = [mode(preset.width, preset.height, 30)];
+            let picked = preset.pick_mode(&own).unwrap();
+            assert!(estimated_bitrate(&picked) <= preset.bitrate, "{quality}");
+        }
+        // Nothing small enough
+        let large = [mode(640, 480, 30)];
+        let low = VideoQuality::Low.preset().unwrap();
+        assert_eq!(low.pick_mode(&large), Some(mode(640, 480, 15)));
+        assert_eq!(low.pick_mode(&[]), None);
+
+        assert!(VideoQuality::Auto.preset().is_none());
+        assert_eq!(VideoQuality::Low.next(), VideoQuality::Auto);
+        assert_eq!(VideoQuality::Auto.next(), VideoQuality::High);
+    }
+}
@@ -0,0 +1,314 @@
+//! Recording of the calls into MP4 files, both directions of the video in one picture.
+//! The peer's video and the one sent to it are composited side by side or picture-in-picture,
+//! see `RecordingLayout`, sampled at `RECORDING_FPS` and encoded on a thread of their own.
+//! The recordings have no audio track. Mixing the microphone with the peer's audio waits on
+//! the calls capturing and sending audio at all, there's only the negotiation of it so far.
+//! The sent video is only there when it comes from a camera, a replayed file leaves its place black.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use openh264::encoder::Encoder;
+use openh264::formats::YUVSlices;
+use tracing::{info_span, warn};
+
+use crate::av_sync::timestamp_now;
+use crate::h264_stream::{StreamSource, HEIGHT, WIDTH};
+use crate::mp4::Mp4Writer;
+
+/// Frames a second in the recordings, whatever rate the streams have
+pub const RECORDING_FPS: u32 = 20;
+/// The sent video is scaled down this much in `RecordingLayout::PictureInPicture`
+const PIP_SCALE: usize = 3;
+/// Space between the sent video and the corner of the picture
+const PIP_MARGIN: usize = 16;
+/// The frame buffers are built on the stack of the thread touching them first,
+/// which may be the recorder. They don't fit the default one in debug builds
+const RECORDER_STACK_SIZE: usize = 8 << 20;
+
+lazy_static! {
+    /// The last frame sent by the camera, YUYV of `WIDTH` x `HEIGHT` as the peer gets it.
+    /// Only kept while a call is recorded or still images are sent, None otherwise,
+    /// see `keep_local_frames`
+    pub(crate) static ref LOCAL_FRAME_BUFFER: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+}
+/// Recorders and senders of still images taking the frames of `LOCAL_FRAME_BUFFER`
+static LOCAL_FRAME_USERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Keep the frames sent by the camera in `LOCAL_FRAME_BUFFER` for one more user (true),
+/// or stop for one of them (false). They are dropped once nobody takes them
+pub(crate) fn keep_local_frames(keep: bool) {
+    // Counted under the lock, so the buffer follows the users
+    let Ok(mut local) = LOCAL_FRAME_BUFFER.lock() else {
+        return;
+    };
+    let users = if keep {
+        LOCAL_FRAME_USERS.fetch_add(1, Ordering::Relaxed) + 1
+    } else {
+        LOCAL_FRAME_USERS.fetch_sub(1, Ordering::Relaxed) - 1
+    };
+    match users {
+        0 => *local = None,
+        _ => {
+            local.get_or_insert_with(Vec::new);
+        }
+    }
+}
+
+/// How both directions of the video are put into one picture
+/// * `SideBySide` - the peer's video on the left, the sent one on the right
+/// * `PictureInPicture` - the peer's video with the sent one small in the bottom right corner
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingLayout {
+    #[default]
+    SideBySide,
+    PictureInPicture,
+}
+
+impl RecordingLayout {
+    /// Width and height of the recorded picture
+    pub fn size(self) -> (usize, usize) {
+        match self {
+            Self::SideBySide => (WIDTH * 2, HEIGHT),
+            Self::PictureInPicture => (WIDTH, HEIGHT),
+        }
+    }
+}
+
+/// Where and how the calls are recorded
+/// * `dir` - every call is recorded into a file of its own there, named after the time it started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub layout: RecordingLayout,
+}
+
+/// A picture with a full resolution Y, U and V plane each, subsampled once it's encoded
+struct Canvas {
+    width: usize,
+    height: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl Canvas {
+    fn black(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            y: vec![16; width * height],
+            u: vec![128; width * height],
+            v: vec![128; width * height],
+        }
+    }
+    /// Both directions of the video in `layout`
+    /// * `remote` - RGBA8 frame of the peer of `WIDTH` x `HEIGHT`, see `h264_stream::RGB_FRAME_BUFFER`
+    /// * `local` - YUYV frame sent to the peer, if there is one
+    fn compose(layout: RecordingLayout, remote: &[u8], local: Option<&[u8]>) -> Self {
+        let (width, height) = layout.size();
+        let mut canvas = Self::black(width, height);
+        canvas.draw(0, 0, 1, |x, y| rgba_to_yuv(&remote[(y * WIDTH + x) * 4..]));
+        let Some(local) = local else {
+            return canvas;
+        };
+        let yuyv = |x: usize, y: usize| {
+            let macropixel = (y * WIDTH + (x & !1)) * 2;
+            (
+                local[(y * WIDTH + x) * 2],
+                local[macropixel + 1],
+                local[macropixel + 3],
+            )
+        };
+        match layout {
+            RecordingLayout::SideBySide => canvas.draw(WIDTH, 0, 1, yuyv),
+            RecordingLayout::PictureInPicture => canvas.draw(
+                WIDTH - WIDTH / PIP_SCALE - PIP_MARGIN,
+                HEIGHT - HEIGHT / PIP_SCALE - PIP_MARGIN,
+                PIP_SCALE,
+                yuyv,
+            ),
+        }
+        canvas
+    }
+    /// Draw a `WIDTH` x `HEIGHT` frame at `left`, `top`, scaled down `scale` times
+    /// * `pixel` - Y, U and V of the frame at x, y
+    fn draw(
+        &mut self,
+        left: usize,
+        top: usize,
+        scale: usize,
+        pixel: impl Fn(usize, usize) -> (u8, u8, u8),
+    ) {
+        for row in 0..HEIGHT / scale {
+            for column in 0..WIDTH / scale {
+                let at = (top + row) * self.width + left + column;
+                (self.y[at], self.u[at], self.v[at]) = pixel(column * scale, row * scale);
+            }
+        }
+    }
+    /// The planes of I420, the chroma of every 2x2 block averaged
+    fn to_i420(&self) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let (half_width, half_height) = (self.width / 2, self.height / 2);
+        let mut u = Vec::with_capacity(half_width * half_height);
+        let mut v = Vec::with_capacity(half_width * half_height);
+        for row in 0..half_height {
+            for column in 0..half_width {
+                let at = row * 2 * self.width + column * 2;
+                let block = [at, at + 1, at + self.width, at + self.width + 1];
+                let average = |plane: &[u8]| {
+                    (block.iter().map(|&i| u32::from(plane[i])).sum::<u32>() / 4) as u8
+                };
+                u.push(average(&self.u));
+                v.push(average(&self.v));
+            }
+        }
+        (self.y.clone(), u, v)
+    }
+}
+
+/// BT.601 Y, U and V of the first pixel of `rgba`, in the studio range like the camera gives them
+pub(crate) fn rgba_to_yuv(rgba: &[u8]) -> (u8, u8, u8) {
+    let (r, g, b) = (i32::from(rgba[0]), i32::from(rgba[1]), i32::from(rgba[2]));
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
+
+/// Records the ongoing call until it's finished
+pub(crate) struct CallRecorder {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl CallRecorder {
+    /// Start recording into a new file in `config.dir`
+    /// # Errors
+    /// Returns an error if the file cannot be created or the thread spawned
+    pub(crate) fn start(config: &RecordingConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        let path = config.dir.join(format!("call-{}.mp4", timestamp_now()));
+        let (width, height) = config.layout.size();
+        let mp4 = Mp4Writer::create(&path, width as u16, height as u16)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = Arc::clone(&stop);
+        let layout = config.layout;
+        let thread = std::thread::Builder::new()
+            .name("call_recorder".into())
+            .stack_size(RECORDER_STACK_SIZE)
+            .spawn(move || {
+                let _span = info_span!("call_recorder", ?layout).entered();
+                record(mp4, layout, &stop_clone)
+            })?;
+        keep_local_frames(true);
+        Ok(Self { path, stop, thread })
+    }
+    /// Stop recording and finish the file
+    /// # Errors
+    /// Returns an error if the recording cannot be finished, the file may not play then
+    pub(crate) fn finish(self) -> anyhow::Result<PathBuf> {
+        keep_local_frames(false);
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.join() {
+            Ok(result) => result
+                .map(|()| self.path)
+                .map_err(|e| anyhow::anyhow!("Cannot finish the recording: {e}")),
+            Err(_) => anyhow::bail!("The recorder panicked"),
+        }
+    }
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Body of the recorder thread: composite, encode and write a frame every tick until stopped
+fn record(mut mp4: Mp4Writer, layout: RecordingLayout, stop: &AtomicBool) -> io::Result<()> {
+    let mut encoder = Encoder::new().map_err(|e| io::Error::other(e.to_string()))?;
+    let (width, height) = layout.size();
+    let interval = Duration::from_secs(1) / RECORDING_FPS;
+    let start = Instant::now();
+    let mut next_frame = start;
+    let mut warned = false;
+    while !stop.load(Ordering::Relaxed) {
+        let local = LOCAL_FRAME_BUFFER
+            .lock()
+            .ok()
+            .and_then(|local| local.clone())
+            .filter(|local| local.len() == WIDTH * HEIGHT * 2);
+        let canvas = {
+            let remote = StreamSource::Camera.frame_buffer().lock().unwrap();
+            // The peer may send another size
+            Canvas::compose(layout, &remote.scaled(WIDTH, HEIGHT), local.as_deref())
+        };
+        let (y, u, v) = canvas.to_i420();
+        let slices = YUVSlices::new((&y, &u, &v), (width, height), (width, width / 2, width / 2));
+        let mut encoded = Vec::new();
+        match encoder
+            .encode(&slices)
+            .and_then(|bitstream| bitstream.write(&mut encoded))
+        {
+            Ok(()) => mp4.write_frame(&encoded, start.elapsed())?,
+            // Every frame would fail the same way
+            Err(e) if !warned => {
+                warn!("Cannot encode the recording: {e}");
+                warned = true;
+            }
+            Err(_) => {}
+        }
+        next_frame += interval;
+        std::thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+    }
+    mp4.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rgba_to_yuv, Canvas, RecordingLayout, PIP_MARGIN, PIP_SCALE};
+    use crate::h264_stream::{HEIGHT, WIDTH};
+
+    #[test]
+    fn test_rgba_to_yuv() {
+        assert_eq!(rgba_to_yuv(&[0, 0, 0, 255]), (16, 128, 128));
+        assert_eq!(rgba_to_yuv(&[255, 255, 255, 255]), (235, 128, 128));
+        let (_, u, v) = rgba_to_yuv(&[255, 0, 0, 255]);
+        assert!(u < 128 && v > 200);
+    }
+    #[test]
+    fn test_compose_layouts() {
+        let remote = [255u8, 255, 255, 255].repeat(WIDTH * HEIGHT);
+        // Y of 200, U of 100 and V of 150 everywhere
+        let local = [200u8, 100, 200, 150].repeat(WIDTH * HEIGHT / 2);
+
+        let side_by_side = Canvas::compose(RecordingLayout::SideBySide, &remote, Some(&local));
+        assert_eq!(
+            (side_by_side.width, side_by_side.height),
+            (WIDTH * 2, HEIGHT)
+        );
+        let (y, u, v) = side_by_side.to_i420();
+        assert_eq!(y.len(), WIDTH * 2 * HEIGHT);
+        assert_eq!((u.len(), v.len()), (WIDTH * HEIGHT / 2, WIDTH * HEIGHT / 2));
+        assert_eq!((y[0], u[0], v[0]), (235, 128, 128));
+        let last = y.len() - 1;
+        assert_eq!((y[last], u[u.len() - 1], v[v.len() - 1]), (200, 100, 150));
+
+        let pip = Canvas::compose(RecordingLayout::PictureInPicture, &remote, Some(&local));
+        assert_eq!((pip.width, pip.height), (WIDTH, HEIGHT));
+        let corner = (HEIGHT - PIP_MARGIN - 1) * WIDTH + WIDTH - PIP_MARGIN - 1;
+        assert_eq!(pip.y[corner], 200);
+        let outside = (HEIGHT - PIP_MARGIN / 2) * WIDTH + WIDTH - PIP_MARGIN / 2;
+        assert_eq!(pip.y[outside], 235);
+        let above = (HEIGHT - HEIGHT / PIP_SCALE - PIP_MARGIN - 1) * WIDTH + WIDTH - PIP_MARGIN - 1;
+        assert_eq!(pip.y[above], 235);
+
+        // Without the sent video its place stays black
+        let blank = Canvas::compose(RecordingLayout::SideBySide, &remote, None);
+        assert_eq!(blank.y[WIDTH * 2 - 1], 16);
+    }
+}
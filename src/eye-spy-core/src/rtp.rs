@@ -0,0 +1,229 @@
+//! Republishing a stream as RTP, for the players and recorders outside the app, i.e. VLC, OBS or an NVR.
+//! The NAL units go out as in RFC 6184, packetization mode 1: a unit a packet,
+//! or FU-A fragments of it when it doesn't fit one. Either the received stream or the one sent
+//! to the peer is republished, see `RtpConfig`.
+//! There's no RTSP server or RTCP, the players are pointed at the stream with the SDP of `RtpOutput::sdp`,
+//! i.e. saved as `stream.sdp` and opened in VLC or as an OBS media source.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::h264_stream::UnitSink;
+
+/// Dynamic payload type the stream is sent with, as the SDP tells
+pub const RTP_PAYLOAD_TYPE: u8 = 96;
+/// Payload of a packet, leaves room for the headers and tunnels within an Ethernet frame
+pub const RTP_PAYLOAD_SIZE: usize = 1200;
+/// Ticks a second of the timestamps, the same for every video stream
+const CLOCK_RATE: u64 = 90_000;
+const RTP_VERSION: u8 = 2;
+const HEADER_LEN: usize = 12;
+/// NAL unit type of a FU-A fragment
+const FU_A: u8 = 28;
+
+/// What to republish and where
+/// * `addr` - where the packets are sent, i.e. a multicast group or the machine of the player
+/// * `local` - republish the video sent to the peer, the received one otherwise
+/// * `sdp_path` - where to save the SDP describing the stream, if anywhere
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpConfig {
+    pub addr: SocketAddr,
+    pub local: bool,
+    pub sdp_path: Option<PathBuf>,
+}
+
+/// Splits the NAL units into RTP packets of a single stream
+#[derive(Debug)]
+pub struct RtpPacketizer {
+    ssrc: u32,
+    sequence: u16,
+    payload_size: usize,
+}
+
+impl RtpPacketizer {
+    /// Packets of the stream `ssrc`, carrying `payload_size` bytes at most
+    pub fn new(ssrc: u32, payload_size: usize) -> Self {
+        Self {
+            ssrc,
+            sequence: 0,
+            // The FU header takes 2 bytes of every fragment
+            payload_size: payload_size.max(3),
+        }
+    }
+    /// Packets of a NAL unit, the start code is left out
+    /// * `capture_ts` - microseconds the frame was captured at, see `av_sync::timestamp_now`
+    pub fn packetize(&mut self, unit: &[u8], capture_ts: u64) -> Vec<Vec<u8>> {
+        let unit = strip_start_code(unit);
+        let Some((&header, body)) = unit.split_first() else {
+            return Vec::new();
+        };
+        let timestamp = (capture_ts.wrapping_mul(CLOCK_RATE) / 1_000_000) as u32;
+        // The decoder can show the frame once its picture is in, a frame is a slice from openh264
+        let is_picture = (1..=5).contains(&(header & 0x1f));
+        if unit.len() <= self.payload_size {
+            return vec![self.packet(timestamp, is_picture, &[unit])];
+        }
+        let indicator = (header & 0xe0) | FU_A;
+        let mut chunks = body.chunks(self.payload_size - 2).peekable();
+        let mut packets = Vec::new();
+        let mut first = true;
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            let fu_header = (u8::from(first) << 7) | (u8::from(last) << 6) | (header & 0x1f);
+            packets.push(self.packet(
+                timestamp,
+                last && is_picture,
+                &[&[indicator, fu_header], chunk],
+            ));
+            first = false;
+        }
+        packets
+    }
+    fn packet(&mut self, timestamp: u32, marker: bool, payload: &[&[u8]]) -> Vec<u8> {
+        let mut packet =
+            Vec::with_capacity(HEADER_LEN + payload.iter().map(|p| p.len()).sum::<usize>());
+        packet.push(RTP_VERSION << 6);
+        packet.push((u8::from(marker) << 7) | RTP_PAYLOAD_TYPE);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        for part in payload {
+            packet.extend_from_slice(part);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        packet
+    }
+}
+
+/// The NAL unit without the 3 or 4 byte start code it may begin with
+fn strip_start_code(unit: &[u8]) -> &[u8] {
+    unit.strip_prefix(&[0, 0, 0, 1])
+        .or_else(|| unit.strip_prefix(&[0, 0, 1]))
+        .unwrap_or(unit)
+}
+
+/// Sends the NAL units of a stream to `addr` as RTP, see `UnitSink`
+pub struct RtpOutput {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    packetizer: RtpPacketizer,
+    /// Whether a send failed since the last one that didn't, so it's logged once
+    failing: bool,
+}
+
+impl RtpOutput {
+    /// Send to `addr` from a port picked by the OS
+    /// # Errors
+    /// Returns an error if the socket cannot be bound
+    pub fn new(addr: SocketAddr) -> io::Result<Self> {
+        let bind: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        Ok(Self {
+            socket,
+            addr,
+            // Random, as RFC 3550 asks, so the players tell a restarted app apart
+            packetizer: RtpPacketizer::new(Uuid::new_v4().as_u128() as u32, RTP_PAYLOAD_SIZE),
+            failing: false,
+        })
+    }
+    /// The session description the players open the stream with
+    pub fn sdp(&self) -> String {
+        let family = if self.addr.is_ipv4() { "IP4" } else { "IP6" };
+        format!(
+            "v=0\r\n\
+             o=- 0 0 IN {family} {ip}\r\n\
+             s=eye-spy\r\n\
+             c=IN {family} {ip}\r\n\
+             t=0 0\r\n\
+             m=video {port} RTP/AVP {RTP_PAYLOAD_TYPE}\r\n\
+             a=rtpmap:{RTP_PAYLOAD_TYPE} H264/{CLOCK_RATE}\r\n\
+             a=fmtp:{RTP_PAYLOAD_TYPE} packetization-mode=1\r\n",
+            ip = self.addr.ip(),
+            port = self.addr.port(),
+        )
+    }
+}
+
+impl UnitSink for RtpOutput {
+    fn unit(&mut self, unit: &[u8], capture_ts: u64) {
+        for packet in self.packetizer.packetize(unit, capture_ts) {
+            match self.socket.send_to(&packet, self.addr) {
+                Ok(_) => self.failing = false,
+                Err(e) if !self.failing => {
+                    warn!("Cannot republish the stream to {}: {e}", self.addr);
+                    self.failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RtpPacketizer, FU_A, HEADER_LEN, RTP_PAYLOAD_TYPE};
+
+    #[test]
+    fn test_single_unit_packet() {
+        let mut packetizer = RtpPacketizer::new(0xdead_beef, 1200);
+        // SPS, then a slice of an IDR picture
+        let sps = packetizer.packetize(&[0, 0, 0, 1, 0x67, 1, 2, 3], 1_000_000);
+        assert_eq!(sps.len(), 1);
+        assert_eq!(
+            sps[0],
+            [
+                0x80,
+                RTP_PAYLOAD_TYPE,
+                0,
+                0,
+                0,
+                1,
+                0x5f,
+                0x90,
+                0xde,
+                0xad,
+                0xbe,
+                0xef,
+                0x67,
+                1,
+                2,
+                3
+            ]
+        );
+        let idr = packetizer.packetize(&[0, 0, 1, 0x65, 9], 1_000_000);
+        // Marked as the end of the picture, next in the sequence
+        assert_eq!(idr[0][1], 0x80 | RTP_PAYLOAD_TYPE);
+        assert_eq!(&idr[0][2..4], &[0, 1]);
+        assert_eq!(&idr[0][HEADER_LEN..], &[0x65, 9]);
+        assert!(packetizer.packetize(&[0, 0, 1], 0).is_empty());
+    }
+    #[test]
+    fn test_fragmented_unit() {
+        let mut packetizer = RtpPacketizer::new(1, 10);
+        let body: Vec<u8> = (0..20).collect();
+        let unit = [&[0, 0, 0, 1, 0x65][..], &body].concat();
+        let packets = packetizer.packetize(&unit, 0);
+        assert_eq!(packets.len(), 3);
+        let mut rebuilt = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            let (indicator, fu_header) = (packet[HEADER_LEN], packet[HEADER_LEN + 1]);
+            assert_eq!(indicator, 0x60 | FU_A);
+            assert_eq!(fu_header & 0x1f, 5);
+            assert_eq!(fu_header & 0x80 != 0, i == 0);
+            assert_eq!(fu_header & 0x40 != 0, i == 2);
+            // Only the last fragment ends the picture
+            assert_eq!(packet[1] & 0x80 != 0, i == 2);
+            assert!(packet.len() <= HEADER_LEN + 10);
+            rebuilt.extend_from_slice(&packet[HEADER_LEN + 2..]);
+        }
+        assert_eq!(rebuilt, body);
+    }
+}
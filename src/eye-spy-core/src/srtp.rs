@@ -0,0 +1,295 @@
+//! SRTP protection of the stream packets, as in RFC 3711, so nobody on the LAN can watch
+//! the calls or inject and replay packets into them.
+//! Every packet of the streams goes out as the payload of an RTP packet: the payload is encrypted
+//! with AES-128 in counter mode, and the packet is authenticated with a HMAC-SHA1 tag of 80 bits
+//! over itself and the rollover counter of its index. The packets received twice, or too late
+//! to be told apart from a replay, are dropped.
+//!
+//! Protected packet: <RTP HEADER(96bits)><ENCRYPTED PACKET><HMAC-SHA1(80bits)>
+//!
+//! The keys come out of the SCP key exchange, see `scp_client::client::MediaKeys`.
+//! Each direction has its own, and each stream of a direction its own SSRC, so a packet
+//! of a stream is never taken as one of another. MTU probes go out as they are.
+
+use aes::Aes128;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use scp_client::client::{MediaKey, MEDIA_KEY_LEN, MEDIA_SALT_LEN};
+use sha1::Sha1;
+use uuid::Uuid;
+
+use crate::h264_stream::StreamSource;
+
+/// Bytes the protection adds to every packet, taken off the data of the stream packets
+pub const SRTP_OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+const HEADER_LEN: usize = 12;
+const TAG_LEN: usize = 10;
+const AUTH_KEY_LEN: usize = 20;
+const RTP_VERSION: u8 = 2;
+/// Dynamic payload type, the stream packets aren't a standard payload
+const PAYLOAD_TYPE: u8 = 96;
+/// Ticks a second of the RTP timestamps, the same for every video stream
+const CLOCK_RATE: u64 = 90_000;
+/// Older packets than these behind the newest one are taken as replays
+const REPLAY_WINDOW: u64 = 64;
+
+/// Labels of the session keys derived from the master key, see RFC 3711 section 4.3
+const LABEL_ENCRYPTION: u8 = 0;
+const LABEL_AUTH: u8 = 1;
+const LABEL_SALT: u8 = 2;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+/// SSRC of the packets of a stream
+fn ssrc(source: StreamSource) -> u32 {
+    match source {
+        StreamSource::Camera => 1,
+        StreamSource::Screen => 2,
+    }
+}
+
+/// Protects the packets of a stream sent, or opens the packets of one received
+/// * `index` - of the last packet sent, or of the newest one received. None before the first
+/// * `window` - packets received up to `REPLAY_WINDOW` behind the newest one, a bit each
+pub struct SrtpContext {
+    ssrc: u32,
+    encryption_key: [u8; MEDIA_KEY_LEN],
+    auth_key: [u8; AUTH_KEY_LEN],
+    salt: [u8; MEDIA_SALT_LEN],
+    index: Option<u64>,
+    window: u64,
+}
+
+impl std::fmt::Debug for SrtpContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the keys
+        f.debug_struct("SrtpContext")
+            .field("ssrc", &self.ssrc)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SrtpContext {
+    /// Context of the stream of `source`, protected with `master`.
+    /// The same master key protects every stream of a direction
+    pub fn new(master: &MediaKey, source: StreamSource) -> Self {
+        let mut encryption_key = [0; MEDIA_KEY_LEN];
+        let mut auth_key = [0; AUTH_KEY_LEN];
+        let mut salt = [0; MEDIA_SALT_LEN];
+        derive(master, LABEL_ENCRYPTION, &mut encryption_key);
+        derive(master, LABEL_AUTH, &mut auth_key);
+        derive(master, LABEL_SALT, &mut salt);
+        Self {
+            ssrc: ssrc(source),
+            encryption_key,
+            auth_key,
+            salt,
+            index: None,
+            window: 0,
+        }
+    }
+    /// The packet as the payload of the next SRTP packet
+    /// * `capture_ts` - microseconds the frame was captured at, see `av_sync::timestamp_now`
+    pub fn protect(&mut self, packet: &[u8], capture_ts: u64) -> Vec<u8> {
+        let index = match self.index {
+            Some(index) => index + 1,
+            // Random as RFC 3550 asks, below 2^15 so the receiver's rollover counter stays right
+            None => u64::from(Uuid::new_v4().as_u128() as u16 & 0x7fff),
+        };
+        self.index = Some(index);
+        let timestamp = (capture_ts.wrapping_mul(CLOCK_RATE) / 1_000_000) as u32;
+        let mut protected = Vec::with_capacity(packet.len() + SRTP_OVERHEAD);
+        protected.push(RTP_VERSION << 6);
+        protected.push(PAYLOAD_TYPE);
+        protected.extend_from_slice(&(index as u16).to_be_bytes());
+        protected.extend_from_slice(&timestamp.to_be_bytes());
+        protected.extend_from_slice(&self.ssrc.to_be_bytes());
+        protected.extend_from_slice(packet);
+        self.cipher(index)
+            .apply_keystream(&mut protected[HEADER_LEN..]);
+        let tag = self.mac(&protected, index).finalize().into_bytes();
+        protected.extend_from_slice(&tag[..TAG_LEN]);
+        protected
+    }
+    /// Opens a SRTP packet in place, the stream packet is moved to the start of `packet`.
+    /// Returns its length, None if it's not of this stream, was tampered with or replayed
+    pub fn unprotect(&mut self, packet: &mut [u8]) -> Option<usize> {
+        if packet.len() < SRTP_OVERHEAD
+            || packet[0] != RTP_VERSION << 6
+            || packet[1] & 0x7f != PAYLOAD_TYPE
+            || packet[8..HEADER_LEN] != self.ssrc.to_be_bytes()
+        {
+            return None;
+        }
+        let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+        let index = self.estimate_index(sequence)?;
+        if self.is_replayed(index) {
+            return None;
+        }
+        let (authenticated, tag) = packet.split_at_mut(packet.len() - TAG_LEN);
+        // Compared in constant time
+        self.mac(authenticated, index)
+            .verify_truncated_left(tag)
+            .ok()?;
+        self.accept(index);
+        self.cipher(index)
+            .apply_keystream(&mut authenticated[HEADER_LEN..]);
+        let len = authenticated.len() - HEADER_LEN;
+        packet.copy_within(HEADER_LEN..HEADER_LEN + len, 0);
+        Some(len)
+    }
+    /// Index of a received packet out of its sequence number, see RFC 3711 appendix A.
+    /// None if it's from before the rollover counter started
+    fn estimate_index(&self, sequence: u16) -> Option<u64> {
+        let Some(newest) = self.index else {
+            return Some(u64::from(sequence));
+        };
+        let (roc, last) = (newest >> 16, newest as u16);
+        let roc = if last < 0x8000 {
+            if sequence > last && sequence - last > 0x8000 {
+                roc.checked_sub(1)?
+            } else {
+                roc
+            }
+        } else if last - 0x8000 > sequence {
+            roc + 1
+        } else {
+            roc
+        };
+        Some((roc << 16) | u64::from(sequence))
+    }
+    fn is_replayed(&self, index: u64) -> bool {
+        match self.index {
+            Some(newest) if index <= newest => {
+                let behind = newest - index;
+                behind >= REPLAY_WINDOW || self.window & (1 << behind) != 0
+            }
+            _ => false,
+        }
+    }
+    /// Mark an authenticated packet as received
+    fn accept(&mut self, index: u64) {
+        match self.index {
+            Some(newest) if index <= newest => self.window |= 1 << (newest - index),
+            Some(newest) => {
+                let ahead = index - newest;
+                self.window = if ahead < REPLAY_WINDOW {
+                    (self.window << ahead) | 1
+                } else {
+                    1
+                };
+                self.index = Some(index);
+            }
+            None => {
+                self.window = 1;
+                self.index = Some(index);
+            }
+        }
+    }
+    /// Keystream of the packet at `index`: the salt, the SSRC and the index make up the IV
+    fn cipher(&self, index: u64) -> Aes128Ctr {
+        let mut iv = [0; 16];
+        iv[..MEDIA_SALT_LEN].copy_from_slice(&self.salt);
+        for (iv, ssrc) in iv[4..8].iter_mut().zip(self.ssrc.to_be_bytes()) {
+            *iv ^= ssrc;
+        }
+        for (iv, index) in iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]) {
+            *iv ^= index;
+        }
+        Aes128Ctr::new(&self.encryption_key.into(), &iv.into())
+    }
+    /// The tag covers the rollover counter too, which isn't in the packet
+    fn mac(&self, packet: &[u8], index: u64) -> Hmac<Sha1> {
+        // Any key length goes for HMAC
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.auth_key).unwrap();
+        mac.update(packet);
+        mac.update(&((index >> 16) as u32).to_be_bytes());
+        mac
+    }
+}
+
+/// A session key out of the master key, with the key derivation rate of 0
+fn derive(master: &MediaKey, label: u8, key: &mut [u8]) {
+    let mut iv = [0; 16];
+    iv[..MEDIA_SALT_LEN].copy_from_slice(&master.salt);
+    iv[7] ^= label;
+    key.fill(0);
+    Aes128Ctr::new(&master.key.into(), &iv.into()).apply_keystream(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use scp_client::client::MediaKey;
+
+    use super::{SrtpContext, HEADER_LEN, REPLAY_WINDOW, SRTP_OVERHEAD};
+    use crate::h264_stream::StreamSource;
+
+    const KEY: MediaKey = MediaKey {
+        key: [7; 16],
+        salt: [9; 14],
+    };
+
+    #[test]
+    fn test_protected_packets() {
+        let mut sender = SrtpContext::new(&KEY, StreamSource::Camera);
+        let mut receiver = SrtpContext::new(&KEY, StreamSource::Camera);
+        let data = b"a packet of the stream".to_vec();
+        let protected = sender.protect(&data, 1_000_000);
+        assert_eq!(protected.len(), data.len() + SRTP_OVERHEAD);
+        assert!(!protected.windows(data.len()).any(|window| window == data));
+
+        let mut buf = protected.clone();
+        let len = receiver.unprotect(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &data);
+        // Replayed
+        let mut buf = protected.clone();
+        assert_eq!(receiver.unprotect(&mut buf), None);
+        // Tampered with
+        let mut tampered = sender.protect(&data, 1_000_000);
+        tampered[HEADER_LEN] ^= 1;
+        assert_eq!(receiver.unprotect(&mut tampered), None);
+        // Another stream, another direction
+        let mut screen = SrtpContext::new(&KEY, StreamSource::Screen);
+        let mut buf = sender.protect(&data, 0);
+        assert_eq!(screen.unprotect(&mut buf), None);
+        let mut other = SrtpContext::new(
+            &MediaKey {
+                key: [8; 16],
+                ..KEY
+            },
+            StreamSource::Camera,
+        );
+        let mut buf = sender.protect(&data, 0);
+        assert_eq!(other.unprotect(&mut buf), None);
+    }
+    #[test]
+    fn test_reordered_and_rolled_over() {
+        let mut sender = SrtpContext::new(&KEY, StreamSource::Camera);
+        let mut receiver = SrtpContext::new(&KEY, StreamSource::Camera);
+        // Past the 16 bits of the sequence numbers
+        let mut packets: Vec<Vec<u8>> = (0..70_000u32)
+            .map(|i| sender.protect(&i.to_le_bytes(), 0))
+            .collect();
+        let last = packets.len() - 1;
+        packets.swap(last - 1, last);
+        for (i, packet) in packets.iter_mut().enumerate().skip(last - 3 * 65_536 / 4) {
+            let expected = if i == last - 1 {
+                last
+            } else if i == last {
+                last - 1
+            } else {
+                i
+            };
+            let len = receiver.unprotect(packet).unwrap();
+            assert_eq!(&packet[..len], &(expected as u32).to_le_bytes());
+        }
+        // Too old to be told apart from a replay
+        let mut old = sender.protect(b"", 0);
+        for _ in 0..REPLAY_WINDOW {
+            let mut newer = sender.protect(b"", 0);
+            receiver.unprotect(&mut newer).unwrap();
+        }
+        assert_eq!(receiver.unprotect(&mut old), None);
+    }
+}
@@ -0,0 +1,120 @@
+//! Peers listed in a file rather than found, i.e. a list an admin hands out to a lab of machines
+//! that mDNS doesn't reach across. The list is a JSON array, i.e.
+//! `[{"name": "bench-3", "addr": "10.0.0.13:60102"}]`, exported from the peers found
+//! by one machine and imported by the others. The peers listed are shown next to the ones found,
+//! unless they're found too, see `StaticPeer::is_found`.
+
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mdns::{DiscoveredPeer, TxtRecord};
+
+/// A peer of the list
+/// * `name` - the name it's shown with, its address if None
+/// * `addr` - where its ScpClient listens
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticPeer {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub addr: SocketAddr,
+}
+
+impl StaticPeer {
+    /// Whether one of the peers found is at its address
+    pub fn is_found(&self, found: &[DiscoveredPeer]) -> bool {
+        found.iter().any(|peer| peer.addrs.contains(&self.addr))
+    }
+    /// The peer as if it was found, it advertised nothing but its name and SCP port
+    pub fn to_discovered(&self) -> DiscoveredPeer {
+        DiscoveredPeer {
+            host_name: self.addr.ip().to_string(),
+            addrs: vec![self.addr],
+            txt: TxtRecord {
+                scp_port: Some(self.addr.port()),
+                name: self.name.clone(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Loads the list stored at `path`. Empty if the file doesn't exist
+/// # Errors
+/// Returns an error if the file exists but cannot be read or parsed
+pub fn load(path: &Path) -> anyhow::Result<Vec<StaticPeer>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// # Errors
+/// Returns an error if the list cannot be written to `path`
+pub fn save(path: &Path, peers: &[StaticPeer]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(peers)?)?;
+    Ok(())
+}
+
+/// Adds the peers of `imported` to `peers`. The ones at an address already listed replace it
+pub fn merge(peers: &mut Vec<StaticPeer>, imported: Vec<StaticPeer>) {
+    for peer in imported {
+        match peers.iter_mut().find(|listed| listed.addr == peer.addr) {
+            Some(listed) => *listed = peer,
+            None => peers.push(peer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, merge, save, StaticPeer};
+
+    fn peer(name: &str, addr: &str) -> StaticPeer {
+        StaticPeer {
+            name: Some(name.to_string()),
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_static_peers() {
+        let path = std::env::temp_dir().join(format!("static_peers_{}.json", std::process::id()));
+        assert!(load(&path).unwrap().is_empty());
+
+        let mut peers = vec![peer("bench-3", "10.0.0.13:60102")];
+        merge(
+            &mut peers,
+            vec![
+                peer("bench-4", "10.0.0.14:60102"),
+                peer("bench-3b", "10.0.0.13:60102"),
+            ],
+        );
+        assert_eq!(
+            peers,
+            [
+                peer("bench-3b", "10.0.0.13:60102"),
+                peer("bench-4", "10.0.0.14:60102")
+            ]
+        );
+        save(&path, &peers).unwrap();
+        assert_eq!(load(&path).unwrap(), peers);
+
+        std::fs::write(&path, r#"[{"addr": "10.0.0.15:60102"}]"#).unwrap();
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded[0].name, None);
+        let discovered = loaded[0].to_discovered();
+        assert_eq!(discovered.name(), "10.0.0.15");
+        assert!(loaded[0].is_found(&[discovered]));
+        assert!(!peers[0].is_found(&[loaded[0].to_discovered()]));
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
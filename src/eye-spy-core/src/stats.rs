@@ -0,0 +1,368 @@
+//! Counters of the streams. The stream threads update them,
+//! anyone holding the `Arc` reads them, i.e. `crate::metrics`.
+//! The counters go on across the calls, `CallSummary` tells what they counted in a single one.
+//! The latencies of the stages of the streams are kept here too, see `crate::profile`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::profile::LatencyHistogram;
+
+/// How long the bitrate is measured over
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+/// How long the packet loss is measured over, see `LossMonitor`
+const LOSS_WINDOW: Duration = Duration::from_secs(1);
+/// Share of the packets lost in a window for the link to be taken as weak
+const WEAK_LINK_LOSS: f64 = 0.05;
+/// Windows in a row it takes for the link to be taken as weak, or as good again
+const WEAK_LINK_WINDOWS: u32 = 3;
+
+pub type SharedOutgoingStats = Arc<OutgoingStats>;
+pub type SharedIncomingStats = Arc<IncomingStats>;
+
+/// Counters of the outgoing stream, summed over all destinations
+/// * `frames_encoded` - frames captured and encoded
+/// * `packets_sent` - packets handed to the socket
+/// * `bytes_sent` - size of those packets
+/// * `capture_to_encode`, `encode_to_send` - latencies of the stages, see `PipelineProfile`
+#[derive(Debug, Default)]
+pub struct OutgoingStats {
+    pub frames_encoded: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub capture_to_encode: LatencyHistogram,
+    pub encode_to_send: LatencyHistogram,
+    bitrate: Mutex<BitrateWindow>,
+}
+
+/// Bytes counted since `start`, the bitrate of the window before
+/// and the highest bitrate of a window since the peak was taken
+#[derive(Debug, Default)]
+struct BitrateWindow {
+    start: Option<Instant>,
+    bytes: u64,
+    bitrate: u64,
+    peak: u64,
+}
+
+impl BitrateWindow {
+    fn record(&mut self, bytes: u64) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.bytes += bytes;
+        let elapsed = start.elapsed();
+        if elapsed >= BITRATE_WINDOW {
+            self.bitrate = (self.bytes as f64 * 8. / elapsed.as_secs_f64()) as u64;
+            self.peak = self.peak.max(self.bitrate);
+            self.start = Some(Instant::now());
+            self.bytes = 0;
+        }
+    }
+    fn bitrate(&self) -> u64 {
+        match self.start {
+            Some(start) if start.elapsed() < 2 * BITRATE_WINDOW => self.bitrate,
+            _ => 0,
+        }
+    }
+}
+
+impl OutgoingStats {
+    /// Count the packets sent to one destination
+    pub fn record_sent(&self, packets: &[&[u8]]) {
+        let bytes: u64 = packets.iter().map(|p| p.len() as u64).sum();
+        self.packets_sent
+            .fetch_add(packets.len() as u64, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.bitrate.lock().unwrap().record(bytes);
+    }
+    /// Bits per second sent lately, 0 once nothing was sent for a while
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate.lock().unwrap().bitrate()
+    }
+    /// The highest bitrate sent since the last time it was taken
+    pub fn take_peak_bitrate(&self) -> u64 {
+        std::mem::take(&mut self.bitrate.lock().unwrap().peak)
+    }
+}
+
+/// Counters of the incoming stream
+/// * `packets_received` - packets of the accepted peer
+/// * `bytes_received` - size of those packets
+/// * `packets_lost` - packets that never arrived, judging by the gaps in their identifiers
+/// * `frames_decoded` - frames decoded and ready to be shown
+/// * `decode_errors` - NAL units the decoder refused
+/// * `decoder_resets` - times the decoder was created again after refusing too many units
+/// * `receive_to_decode`, `decode_to_display` - latencies of the stages, see `PipelineProfile`.
+///   The frontend records the latter, the frames are shown there
+/// * `frame_gaps` - time between the NAL units coming in whole, long ones are the link stalling
+#[derive(Debug, Default)]
+pub struct IncomingStats {
+    pub packets_received: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub packets_lost: AtomicU64,
+    pub frames_decoded: AtomicU64,
+    pub decode_errors: AtomicU64,
+    pub decoder_resets: AtomicU64,
+    pub receive_to_decode: LatencyHistogram,
+    pub decode_to_display: LatencyHistogram,
+    pub frame_gaps: LatencyHistogram,
+    bitrate: Mutex<BitrateWindow>,
+}
+
+impl IncomingStats {
+    /// Count a packet of the accepted peer
+    pub fn record_received(&self, packet: &[u8]) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        self.bitrate.lock().unwrap().record(packet.len() as u64);
+    }
+    /// Bits per second received lately, 0 once nothing came for a while
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate.lock().unwrap().bitrate()
+    }
+    /// The highest bitrate received since the last time it was taken
+    pub fn take_peak_bitrate(&self) -> u64 {
+        std::mem::take(&mut self.bitrate.lock().unwrap().peak)
+    }
+}
+
+/// Tells when the packet loss of the incoming stream stays high, so the link is weak.
+/// A single lossy window doesn't make it weak, nor does a single clean one make it good again
+#[derive(Debug, Default)]
+pub struct LossMonitor {
+    /// When the window started, with the counters then
+    window: Option<(Instant, u64, u64)>,
+    /// Windows in a row that disagreed with `weak`
+    streak: u32,
+    weak: bool,
+}
+
+impl LossMonitor {
+    /// Measure the loss of the incoming stream from now on, forgetting what was measured
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+    /// Take a look at the counters of the stream, once a window is over.
+    /// Returns whether the link is weak if it changed
+    pub fn sample(&mut self, stats: &IncomingStats) -> Option<bool> {
+        let received = stats.packets_received.load(Ordering::Relaxed);
+        let lost = stats.packets_lost.load(Ordering::Relaxed);
+        let (start, received_before, lost_before) = *self
+            .window
+            .get_or_insert_with(|| (Instant::now(), received, lost));
+        if start.elapsed() < LOSS_WINDOW {
+            return None;
+        }
+        self.window = Some((Instant::now(), received, lost));
+        self.record(
+            received.saturating_sub(received_before),
+            lost.saturating_sub(lost_before),
+        )
+    }
+    /// Count a window in which this many packets came and got lost.
+    /// Returns whether the link is weak if it changed
+    fn record(&mut self, received: u64, lost: u64) -> Option<bool> {
+        // Nothing was sent, which tells nothing about the link
+        if received + lost == 0 {
+            return None;
+        }
+        let lossy = lost as f64 / (received + lost) as f64 >= WEAK_LINK_LOSS;
+        if lossy == self.weak {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        if self.streak < WEAK_LINK_WINDOWS {
+            return None;
+        }
+        self.streak = 0;
+        self.weak = lossy;
+        Some(lossy)
+    }
+}
+
+/// The counters of both streams when a call started, see `CallSummary`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StatsSnapshot {
+    start: Instant,
+    bytes_sent: u64,
+    packets_sent: u64,
+    frames_encoded: u64,
+    bytes_received: u64,
+    packets_received: u64,
+    packets_lost: u64,
+    frames_decoded: u64,
+    decoder_resets: u64,
+}
+
+impl StatsSnapshot {
+    /// Take the counters as they are now. The peak bitrates start over
+    pub(crate) fn take(outgoing: &OutgoingStats, incoming: &IncomingStats) -> Self {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        outgoing.take_peak_bitrate();
+        incoming.take_peak_bitrate();
+        Self {
+            start: Instant::now(),
+            bytes_sent: load(&outgoing.bytes_sent),
+            packets_sent: load(&outgoing.packets_sent),
+            frames_encoded: load(&outgoing.frames_encoded),
+            bytes_received: load(&incoming.bytes_received),
+            packets_received: load(&incoming.packets_received),
+            packets_lost: load(&incoming.packets_lost),
+            frames_decoded: load(&incoming.frames_decoded),
+            decoder_resets: load(&incoming.decoder_resets),
+        }
+    }
+}
+
+/// What the video stream of one direction did during a call
+/// * `frames` - frames encoded when sending, decoded when receiving
+/// * `average_bitrate`, `peak_bitrate` - bits per second, the peak of a `BITRATE_WINDOW`
+/// * `packets_lost` - only known for the received stream
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StreamSummary {
+    pub bytes: u64,
+    pub packets: u64,
+    pub frames: u64,
+    pub average_bitrate: u64,
+    pub peak_bitrate: u64,
+    pub packets_lost: Option<u64>,
+}
+
+/// Statistics of a call that ended, shown to the user and exported for the bug reports.
+/// Only the video of the peer's camera is counted, not the shared screen
+/// * `resolution_changes` - times either stream was asked for low or full resolution,
+///   see `ControlMessage::LowResolution` and `ControlMessage::LowBandwidth`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CallSummary {
+    pub duration_secs: f64,
+    pub sent: StreamSummary,
+    pub received: StreamSummary,
+    pub decoder_resets: u64,
+    pub resolution_changes: u64,
+}
+
+impl CallSummary {
+    /// What the counters counted since `start`
+    pub(crate) fn since(
+        start: &StatsSnapshot,
+        outgoing: &OutgoingStats,
+        incoming: &IncomingStats,
+        resolution_changes: u64,
+    ) -> Self {
+        let since = |counter: &AtomicU64, before: u64| {
+            counter.load(Ordering::Relaxed).saturating_sub(before)
+        };
+        let duration = start.start.elapsed().as_secs_f64();
+        let average = |bytes: u64| {
+            if duration > 0. {
+                (bytes as f64 * 8. / duration) as u64
+            } else {
+                0
+            }
+        };
+        let bytes_sent = since(&outgoing.bytes_sent, start.bytes_sent);
+        let bytes_received = since(&incoming.bytes_received, start.bytes_received);
+        Self {
+            duration_secs: duration,
+            sent: StreamSummary {
+                bytes: bytes_sent,
+                packets: since(&outgoing.packets_sent, start.packets_sent),
+                frames: since(&outgoing.frames_encoded, start.frames_encoded),
+                average_bitrate: average(bytes_sent),
+                peak_bitrate: outgoing.take_peak_bitrate(),
+                packets_lost: None,
+            },
+            received: StreamSummary {
+                bytes: bytes_received,
+                packets: since(&incoming.packets_received, start.packets_received),
+                frames: since(&incoming.frames_decoded, start.frames_decoded),
+                average_bitrate: average(bytes_received),
+                peak_bitrate: incoming.take_peak_bitrate(),
+                packets_lost: Some(since(&incoming.packets_lost, start.packets_lost)),
+            },
+            decoder_resets: since(&incoming.decoder_resets, start.decoder_resets),
+            resolution_changes,
+        }
+    }
+    /// The summary as pretty printed JSON, for a bug report
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{CallSummary, IncomingStats, LossMonitor, OutgoingStats, StatsSnapshot};
+
+    #[test]
+    fn test_sent_packets_counted() {
+        let stats = OutgoingStats::default();
+        stats.record_sent(&[&[0; 100], &[0; 20]]);
+        stats.record_sent(&[&[0; 5]]);
+        assert_eq!(stats.packets_sent.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.bytes_sent.load(Ordering::Relaxed), 125);
+        // Not a whole window yet
+        assert_eq!(stats.bitrate(), 0);
+    }
+    #[test]
+    fn test_call_summary() {
+        let (outgoing, incoming) = (OutgoingStats::default(), IncomingStats::default());
+        // Counted before the call, not a part of it
+        outgoing.record_sent(&[&[0; 1000]]);
+        incoming.record_received(&[0; 500]);
+        incoming.packets_lost.store(2, Ordering::Relaxed);
+        let start = StatsSnapshot::take(&outgoing, &incoming);
+
+        outgoing.record_sent(&[&[0; 100], &[0; 20]]);
+        outgoing.frames_encoded.fetch_add(1, Ordering::Relaxed);
+        incoming.record_received(&[0; 50]);
+        incoming.packets_lost.store(5, Ordering::Relaxed);
+        incoming.decoder_resets.fetch_add(1, Ordering::Relaxed);
+        let summary = CallSummary::since(&start, &outgoing, &incoming, 2);
+        assert_eq!(
+            (
+                summary.sent.bytes,
+                summary.sent.packets,
+                summary.sent.frames
+            ),
+            (120, 2, 1)
+        );
+        assert_eq!((summary.received.bytes, summary.received.packets), (50, 1));
+        assert_eq!(summary.received.packets_lost, Some(3));
+        assert_eq!(summary.sent.packets_lost, None);
+        assert_eq!((summary.decoder_resets, summary.resolution_changes), (1, 2));
+
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+        assert_eq!(json["sent"]["bytes"], 120);
+        assert_eq!(json["received"]["packets_lost"], 3);
+    }
+    #[test]
+    fn test_loss_monitor() {
+        let mut monitor = LossMonitor::default();
+        // A burst of loss doesn't make it weak
+        assert_eq!(monitor.record(90, 10), None);
+        assert_eq!(monitor.record(100, 0), None);
+        assert_eq!(monitor.record(90, 10), None);
+        assert_eq!(monitor.record(94, 6), None);
+        // Windows without packets don't count either way
+        assert_eq!(monitor.record(0, 0), None);
+        assert_eq!(monitor.record(80, 20), Some(true));
+        assert!(monitor.is_weak());
+        assert_eq!(monitor.record(80, 20), None);
+
+        assert_eq!(monitor.record(99, 1), None);
+        assert_eq!(monitor.record(100, 0), None);
+        assert_eq!(monitor.record(100, 0), Some(false));
+        monitor.reset();
+        assert!(!monitor.is_weak());
+    }
+}
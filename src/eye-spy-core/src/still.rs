@@ -0,0 +1,229 @@
+//! Still images sent over SCP in place of the video, while the video doesn't get through.
+//! Once the video of the peer stays slower than `StillFallback::threshold` while losing
+//! packets, or doesn't come at all, the peer is asked for `ControlMessage::StillImages`.
+//! It sends a JPEG of its camera every `StillFallback::interval` over the SCP connection,
+//! which is TCP and gets through where the UDP stream starves, until it's asked to stop
+//! once the video comes back above the threshold, see `scp_client::scp::StillImage`.
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use scp_client::scp::StillImage;
+use tracing::warn;
+
+use crate::h264_stream::{HEIGHT, WIDTH};
+use crate::jpeg::{self, JpegPicture};
+use crate::recording::{keep_local_frames, LOCAL_FRAME_BUFFER};
+use crate::stats::IncomingStats;
+
+/// kbit/s of the video of the peer below which it's asked for still images, unless set
+pub const DEFAULT_STILL_THRESHOLD: u32 = 32;
+/// How often the still images are sent, unless set
+pub const DEFAULT_STILL_INTERVAL: Duration = Duration::from_secs(5);
+/// Quality of the JPEGs, enough to tell what's going on
+const STILL_QUALITY: u8 = 50;
+/// How long the video of the peer is measured over, see `ThroughputMonitor`
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+/// Windows in a row it takes for the video to be taken as starved, or as back
+const STARVED_WINDOWS: u32 = 3;
+
+/// When the still images stand in for the video
+/// * `threshold` - kbit/s of the video of the peer below which it's asked for still images,
+///   0 never to ask. The peers asking are sent them anyway
+/// * `interval` - how often the still images are sent to the peers asking for them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StillFallback {
+    pub threshold: u32,
+    pub interval: Duration,
+}
+
+impl Default for StillFallback {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_STILL_THRESHOLD,
+            interval: DEFAULT_STILL_INTERVAL,
+        }
+    }
+}
+
+/// Tells when the video of the peer starves below the threshold, and when it's back above it.
+/// A window starves when it's below the threshold while packets get lost, or nothing comes.
+/// A peer sending nothing on purpose, i.e. gated by motion, is asked for the still images too,
+/// they only cost a JPEG every so often
+#[derive(Debug, Default)]
+pub(crate) struct ThroughputMonitor {
+    /// When the window started, with the bytes received, the packets received and lost then
+    window: Option<(Instant, u64, u64, u64)>,
+    /// Windows in a row that disagreed with `starved`
+    streak: u32,
+    starved: bool,
+}
+
+impl ThroughputMonitor {
+    /// Measure the video of the peer from now on, forgetting what was measured
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+    pub fn is_starved(&self) -> bool {
+        self.starved
+    }
+    /// Take a look at the counters of the stream, once a window is over.
+    /// Returns whether the video starves if it changed
+    pub fn sample(&mut self, stats: &IncomingStats, threshold: u32) -> Option<bool> {
+        let bytes = stats.bytes_received.load(Ordering::Relaxed);
+        let received = stats.packets_received.load(Ordering::Relaxed);
+        let lost = stats.packets_lost.load(Ordering::Relaxed);
+        let (start, bytes_before, received_before, lost_before) = *self
+            .window
+            .get_or_insert_with(|| (Instant::now(), bytes, received, lost));
+        let elapsed = start.elapsed();
+        if elapsed < THROUGHPUT_WINDOW {
+            return None;
+        }
+        self.window = Some((Instant::now(), bytes, received, lost));
+        let bytes = bytes.saturating_sub(bytes_before);
+        let kbps = (bytes as f64 * 8. / 1000. / elapsed.as_secs_f64()) as u64;
+        self.record(
+            kbps,
+            received.saturating_sub(received_before),
+            lost.saturating_sub(lost_before),
+            threshold,
+        )
+    }
+    /// Count a window in which the video came at `kbps`, in this many packets and lost ones.
+    /// Returns whether the video starves if it changed
+    fn record(&mut self, kbps: u64, received: u64, lost: u64, threshold: u32) -> Option<bool> {
+        let starving = kbps < u64::from(threshold) && (lost > 0 || received == 0);
+        if starving == self.starved {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        if self.streak < STARVED_WINDOWS {
+            return None;
+        }
+        self.streak = 0;
+        self.starved = starving;
+        Some(starving)
+    }
+}
+
+/// Sends a still image every `interval` while the peer asks for them.
+/// The frames of the camera are kept meanwhile, see `keep_local_frames`
+#[derive(Debug)]
+pub(crate) struct StillSender {
+    interval: Duration,
+    /// When the last still image was sent, None if none was since the peer asked
+    sent: Option<Instant>,
+    active: bool,
+}
+
+impl StillSender {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            sent: None,
+            active: false,
+        }
+    }
+    /// Start (true) or stop (false) sending, as the peer asks
+    pub fn set_active(&mut self, active: bool) {
+        if active == self.active {
+            return;
+        }
+        self.active = active;
+        self.sent = None;
+        keep_local_frames(active);
+    }
+    /// The still image to send now, if it's time and the camera sent a frame since the start
+    pub fn poll(&mut self, now: Instant) -> Option<StillImage> {
+        if !self.active
+            || self
+                .sent
+                .is_some_and(|sent| now.duration_since(sent) < self.interval)
+        {
+            return None;
+        }
+        let frame = LOCAL_FRAME_BUFFER
+            .lock()
+            .ok()?
+            .clone()
+            .filter(|frame| frame.len() == WIDTH * HEIGHT * 2)?;
+        self.sent = Some(now);
+        match jpeg::encode_yuyv(&frame, (WIDTH, HEIGHT), STILL_QUALITY) {
+            Ok(jpeg) => Some(StillImage(jpeg)),
+            Err(e) => {
+                warn!("Cannot encode a still image: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for StillSender {
+    fn drop(&mut self) {
+        self.set_active(false);
+    }
+}
+
+/// The picture of a still image of the peer
+/// # Errors
+/// Returns an error if it isn't a JPEG the peers send
+pub(crate) fn decode(still: &StillImage) -> anyhow::Result<JpegPicture> {
+    jpeg::decode(&still.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use scp_client::scp::StillImage;
+
+    use super::{decode, StillSender, ThroughputMonitor};
+    use crate::codec::DecodedPicture;
+    use crate::jpeg;
+
+    #[test]
+    fn test_throughput_monitor() {
+        let mut monitor = ThroughputMonitor::default();
+        // Little video without loss is only a quiet peer
+        for _ in 0..5 {
+            assert_eq!(monitor.record(8, 20, 0, 32), None);
+        }
+        // A few starved windows don't make it starve
+        assert_eq!(monitor.record(10, 12, 4, 32), None);
+        assert_eq!(monitor.record(0, 0, 0, 32), None);
+        assert_eq!(monitor.record(400, 90, 0, 32), None);
+        assert_eq!(monitor.record(10, 12, 4, 32), None);
+        assert_eq!(monitor.record(0, 0, 0, 32), None);
+        assert_eq!(monitor.record(3, 2, 30, 32), Some(true));
+        assert!(monitor.is_starved());
+        assert_eq!(monitor.record(0, 0, 0, 32), None);
+
+        // Back once it's above the threshold for a while, lossy or not
+        assert_eq!(monitor.record(200, 40, 3, 32), None);
+        assert_eq!(monitor.record(300, 60, 0, 32), None);
+        assert_eq!(monitor.record(300, 60, 0, 32), Some(false));
+        // A threshold of 0 never starves
+        for _ in 0..5 {
+            assert_eq!(monitor.record(0, 0, 0, 0), None);
+        }
+        monitor.reset();
+        assert!(!monitor.is_starved());
+    }
+    #[test]
+    fn test_still_sender() {
+        let mut sender = StillSender::new(Duration::from_secs(5));
+        let now = Instant::now();
+        assert!(sender.poll(now).is_none());
+        // Without the camera there's nothing to send
+        sender.set_active(true);
+        assert!(sender.poll(now).is_none());
+        sender.set_active(false);
+        assert!(sender.poll(now).is_none());
+
+        let still = StillImage(jpeg::encode_yuyv(&[128; 64 * 48 * 2], (64, 48), 50).unwrap());
+        assert_eq!(decode(&still).unwrap().dimensions(), (64, 48));
+        assert!(decode(&StillImage(vec![0xFF, 0xD8])).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Sending many datagrams to one address at once.
+//! On Linux a whole batch goes out with a single `sendmmsg` call,
+//! elsewhere the datagrams are sent one by one.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Most datagrams handed to the kernel in a single call
+#[cfg(target_os = "linux")]
+const BATCH_LEN: usize = 64;
+
+/// Send all `packets` to `addr`.
+/// Packets the socket has no room for are dropped, like with `send_to` on a non-blocking socket
+/// # Errors
+/// Returns the first error other than a full socket buffer, the rest of the packets isn't sent then
+#[cfg(target_os = "linux")]
+pub fn send_all(socket: &UdpSocket, packets: &[&[u8]], addr: SocketAddr) -> io::Result<()> {
+    use std::io::IoSlice;
+    use std::os::fd::AsRawFd;
+
+    use nix::errno::Errno;
+    use nix::sys::socket::{sendmmsg, ControlMessage, MsgFlags, MultiHeaders, SockaddrStorage};
+
+    let destination = Some(SockaddrStorage::from(addr));
+    for batch in packets.chunks(BATCH_LEN) {
+        let slices: Vec<[IoSlice; 1]> = batch.iter().map(|p| [IoSlice::new(p)]).collect();
+        let addrs = vec![destination; batch.len()];
+        let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(batch.len(), None);
+        let mut next = 0;
+        while next < batch.len() {
+            let sent = sendmmsg(
+                socket.as_raw_fd(),
+                &mut headers,
+                &slices[next..],
+                &addrs[next..],
+                [] as [ControlMessage; 0],
+                MsgFlags::empty(),
+            );
+            match sent {
+                // Less than the whole batch is sent when the buffer fills up midway
+                Ok(sent) => next += sent.count().max(1),
+                // The socket buffer is full, this packet is dropped
+                Err(Errno::EAGAIN) => next += 1,
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send all `packets` to `addr`.
+/// Packets the socket has no room for are dropped, like with `send_to` on a non-blocking socket
+/// # Errors
+/// Returns the first error other than a full socket buffer, the rest of the packets isn't sent then
+#[cfg(not(target_os = "linux"))]
+pub fn send_all(socket: &UdpSocket, packets: &[&[u8]], addr: SocketAddr) -> io::Result<()> {
+    for packet in packets {
+        match socket.send_to(packet, addr) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use super::send_all;
+
+    #[test]
+    fn test_send_all_in_order() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // More than a single batch, with packets of different sizes
+        let packets: Vec<Vec<u8>> = (0..150u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let slices: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+        send_all(&sender, &slices, receiver.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0; 1024];
+        for packet in &packets {
+            let size = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..size], packet.as_slice());
+        }
+    }
+}
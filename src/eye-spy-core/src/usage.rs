@@ -0,0 +1,257 @@
+//! How much data the calls use, for the users on metered connections.
+//! `DataUsage` adds up the bytes all the streams sent and received, for the ongoing call,
+//! the current month and since it was first counted, and keeps the totals in a file.
+//! The months are the calendar months in UTC.
+//! With a `MonthlyCap`, `CallManager` tells once the month's usage nears it,
+//! see `CallEvent::DataCapNear`, and can cut the video down until the month is over.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Percent of the cap the month's usage nears it at
+pub const CAP_WARNING_PERCENT: u64 = 90;
+/// How often the totals are saved while they're counted, so a crash loses little
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bytes sent and received
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.sent.saturating_add(self.received)
+    }
+    fn add(&mut self, sent: u64, received: u64) {
+        self.sent = self.sent.saturating_add(sent);
+        self.received = self.received.saturating_add(received);
+    }
+}
+
+/// Data the calls may use in a month, i.e. on a metered connection
+/// * `bytes` - sent and received together
+/// * `downgrade` - once the month nears the cap, `VideoQuality::Auto` sends the cheapest video
+///   and asks the peers for theirs. Otherwise the user is only told
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MonthlyCap {
+    pub bytes: u64,
+    pub downgrade: bool,
+}
+
+impl MonthlyCap {
+    /// Whether `usage` is over `CAP_WARNING_PERCENT` of the cap
+    pub fn is_near(&self, usage: Usage) -> bool {
+        u128::from(usage.total()) * 100 >= u128::from(self.bytes) * u128::from(CAP_WARNING_PERCENT)
+    }
+}
+
+/// What's saved to the file
+/// * `month` - year and month `this_month` was counted in, i.e. 202410
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Totals {
+    month: u32,
+    this_month: Usage,
+    all_time: Usage,
+}
+
+/// Year and month of the day `secs` after the UNIX epoch, i.e. 202410
+fn month_of(secs: u64) -> u32 {
+    // Days to the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = secs / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year * 100 + month) as u32
+}
+
+fn current_month() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    month_of(secs)
+}
+
+/// Data the calls used. Saved as JSON every `SAVE_INTERVAL` while counted when loaded from a file
+#[derive(Debug)]
+pub struct DataUsage {
+    path: Option<PathBuf>,
+    totals: Totals,
+    /// Usage of the ongoing call, or of the last one
+    call: Usage,
+    /// When the first bytes not saved yet were counted, None if there are none
+    saved: Option<Instant>,
+}
+
+impl Default for DataUsage {
+    fn default() -> Self {
+        Self {
+            path: None,
+            totals: Totals {
+                month: current_month(),
+                ..Default::default()
+            },
+            call: Usage::default(),
+            saved: None,
+        }
+    }
+}
+
+impl DataUsage {
+    /// Usage that isn't saved anywhere
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+    /// Loads the totals stored at `path`. The file is created once something is counted
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read or parsed
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let totals = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Totals {
+                month: current_month(),
+                ..Default::default()
+            },
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            totals,
+            ..Default::default()
+        })
+    }
+    /// Count the bytes into the ongoing call, the month and the total
+    pub fn record(&mut self, sent: u64, received: u64) {
+        if sent == 0 && received == 0 {
+            return;
+        }
+        let month = current_month();
+        if self.totals.month != month {
+            self.totals.month = month;
+            self.totals.this_month = Usage::default();
+        }
+        self.call.add(sent, received);
+        self.totals.this_month.add(sent, received);
+        self.totals.all_time.add(sent, received);
+        match self.saved {
+            Some(saved) if saved.elapsed() < SAVE_INTERVAL => {}
+            Some(_) => self.save(),
+            None => self.saved = Some(Instant::now()),
+        }
+    }
+    /// Count the ongoing call from zero
+    pub fn start_call(&mut self) {
+        self.call = Usage::default();
+    }
+    /// Usage of the ongoing call, or of the last one if there's none
+    pub fn call(&self) -> Usage {
+        self.call
+    }
+    /// Usage in the current month
+    pub fn this_month(&self) -> Usage {
+        if self.totals.month == current_month() {
+            self.totals.this_month
+        } else {
+            Usage::default()
+        }
+    }
+    /// Usage since it was first counted
+    pub fn all_time(&self) -> Usage {
+        self.totals.all_time
+    }
+    /// Save the totals now, if anything was counted since they were last saved
+    pub fn save(&mut self) {
+        if self.saved.take().is_none() {
+            return;
+        }
+        if let Err(e) = self.try_save() {
+            warn!("Cannot save the data usage: {e}");
+        }
+    }
+    fn try_save(&self) -> anyhow::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(&self.totals)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_month, month_of, DataUsage, MonthlyCap, Usage};
+
+    #[test]
+    fn test_month_of() {
+        assert_eq!(month_of(0), 197001);
+        // 2024-02-29 23:59:59 and the second after
+        assert_eq!(month_of(1_709_251_199), 202402);
+        assert_eq!(month_of(1_709_251_200), 202403);
+        // 2099-12-31 12:00:00
+        assert_eq!(month_of(4_102_401_600), 209912);
+    }
+    #[test]
+    fn test_usage_persists() {
+        let path = std::env::temp_dir().join(format!("eye-spy-usage-{}", std::process::id()));
+        let mut usage = DataUsage::load(&path).unwrap();
+        usage.record(100, 50);
+        usage.start_call();
+        usage.record(10, 0);
+        assert_eq!(
+            usage.call(),
+            Usage {
+                sent: 10,
+                received: 0
+            }
+        );
+        assert_eq!(usage.this_month().total(), 160);
+        usage.save();
+
+        let mut loaded = DataUsage::load(&path).unwrap();
+        assert_eq!(
+            loaded.all_time(),
+            Usage {
+                sent: 110,
+                received: 50
+            }
+        );
+        assert_eq!(loaded.this_month(), loaded.all_time());
+        assert_eq!(loaded.call(), Usage::default());
+        // A new month starts from zero
+        loaded.totals.month = current_month() - 1;
+        assert_eq!(loaded.this_month(), Usage::default());
+        loaded.record(1, 1);
+        assert_eq!(loaded.this_month().total(), 2);
+        assert_eq!(loaded.all_time().total(), 162);
+        std::fs::remove_file(&path).unwrap();
+
+        let cap = MonthlyCap {
+            bytes: 1000,
+            downgrade: false,
+        };
+        assert!(!cap.is_near(Usage {
+            sent: 899,
+            received: 0
+        }));
+        assert!(cap.is_near(Usage {
+            sent: 450,
+            received: 450
+        }));
+    }
+}
@@ -0,0 +1,125 @@
+//! The peer's video as a webcam of this machine, for the other apps, i.e. Zoom or OBS.
+//! The decoded frames are written into a v4l2loopback device, see `VirtualCamera`,
+//! which the other apps open like any camera. The device is made by the kernel module:
+//! `modprobe v4l2loopback exclusive_caps=1 card_label="eye-spy"`
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tracing::warn;
+use v4l::video::Output;
+use v4l::{Device, Format};
+
+use crate::h264_stream::incoming::FrameSink;
+use crate::h264_stream::{RgbaFrame, FOURCC};
+use crate::recording::rgba_to_yuv;
+
+/// Driver the v4l2loopback devices report
+const LOOPBACK_DRIVER: &str = "v4l2 loopback";
+
+/// The first v4l2loopback device, if the module is loaded
+pub fn find_loopback_device() -> Option<PathBuf> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir("/dev")
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("video"))
+        })
+        .collect();
+    paths.sort();
+    paths.into_iter().find(|path| {
+        Device::with_path(path)
+            .and_then(|device| device.query_caps())
+            .is_ok_and(|caps| caps.driver == LOOPBACK_DRIVER)
+    })
+}
+
+/// Writes the decoded frames into a v4l2loopback device as YUYV, see `FrameSink`.
+/// The format follows the size of the frames the peer sends
+/// * `size` - width and height the device was last set to
+/// * `failing` - whether the last write failed, so it's logged once
+pub struct VirtualCamera {
+    device: Device,
+    path: PathBuf,
+    size: Option<(usize, usize)>,
+    yuyv: Vec<u8>,
+    failing: bool,
+}
+
+impl VirtualCamera {
+    /// # Errors
+    /// Returns an error if the device cannot be opened
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let device = Device::with_path(path)
+            .with_context(|| format!("Cannot open the virtual camera {}", path.display()))?;
+        Ok(Self {
+            device,
+            path: path.to_path_buf(),
+            size: None,
+            yuyv: Vec::new(),
+            failing: false,
+        })
+    }
+    fn write(&mut self, rgba: &RgbaFrame) -> std::io::Result<()> {
+        let size = (rgba.width, rgba.height);
+        if self.size != Some(size) {
+            let format = Format::new(rgba.width as u32, rgba.height as u32, FOURCC);
+            self.device.set_format(&format)?;
+            self.size = Some(size);
+        }
+        rgba_to_yuyv(&rgba.data, &mut self.yuyv);
+        self.device.write_all(&self.yuyv)
+    }
+}
+
+impl FrameSink for VirtualCamera {
+    fn frame(&mut self, rgba: &RgbaFrame, _sequence: u64) {
+        match self.write(rgba) {
+            Ok(()) => self.failing = false,
+            Err(e) if !self.failing => {
+                warn!(
+                    "Cannot write into the virtual camera {}: {e}",
+                    self.path.display()
+                );
+                self.failing = true;
+                // The format is set again with the next frame
+                self.size = None;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// RGBA pixels into `yuyv`, the two pixels of a pair sharing the average of their U and V
+fn rgba_to_yuyv(rgba: &[u8], yuyv: &mut Vec<u8>) {
+    yuyv.clear();
+    for pair in rgba.chunks_exact(8) {
+        let (y1, u1, v1) = rgba_to_yuv(&pair[..4]);
+        let (y2, u2, v2) = rgba_to_yuv(&pair[4..]);
+        let average = |a: u8, b: u8| ((u16::from(a) + u16::from(b)) / 2) as u8;
+        yuyv.extend_from_slice(&[y1, average(u1, u2), y2, average(v1, v2)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rgba_to_yuyv;
+
+    #[test]
+    fn test_rgba_to_yuyv() {
+        let mut yuyv = vec![1, 2, 3];
+        // A black and a white pixel, then two red ones
+        let rgba = [
+            0, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 255, 255, 0, 0, 255,
+        ];
+        rgba_to_yuyv(&rgba, &mut yuyv);
+        assert_eq!(yuyv.len(), 8);
+        assert_eq!(&yuyv[..4], &[16, 128, 235, 128]);
+        assert_eq!(yuyv[4], yuyv[6]);
+        assert!(yuyv[5] < 128 && yuyv[7] > 200);
+    }
+}
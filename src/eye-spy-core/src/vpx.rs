@@ -0,0 +1,461 @@
+//! The VP8 codec of the system through libvpx, loaded as it's opened, see `crate::codec`.
+//! The ABI versions the library is initialized with change between its releases, so they're
+//! looked up within `ABI_VERSIONS` until the library takes one. The encoder configuration is
+//! filled in by the library, only its start is laid out here, up to the target bitrate,
+//! and the codec contexts are kept opaque.
+//! A VP8 frame goes out as a single unit, see `crate::codec::units`.
+
+use std::ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void, CStr};
+use std::ops::RangeInclusive;
+use std::ptr;
+
+use anyhow::{anyhow, bail};
+use libloading::Library;
+
+use crate::camera::DEFAULT_BITRATE_BUDGET;
+use crate::codec::{
+    write_i420_rgba8, CodecBackend, DecodedPicture, VideoDecoder, VideoEncoder, VideoEncoding,
+    YuvFrame,
+};
+
+/// Names the library goes by, the unversioned one is only there along with the headers
+const LIBRARIES: [&str; 6] = [
+    "libvpx.so",
+    "libvpx.so.9",
+    "libvpx.so.8",
+    "libvpx.so.7",
+    "libvpx.so.6",
+    "libvpx.so.5",
+];
+/// ABI versions `vpx_codec_enc_init_ver` and `vpx_codec_dec_init_ver` are tried with
+const ABI_VERSIONS: RangeInclusive<c_int> = 1..=64;
+/// 8 byte words reserved for `vpx_codec_ctx_t`, it takes 7
+const CONTEXT_WORDS: usize = 16;
+const VPX_CODEC_OK: c_int = 0;
+const VPX_CODEC_ABI_MISMATCH: c_int = 3;
+const VPX_IMG_FMT_I420: c_int = 0x102;
+const VPX_CODEC_CX_FRAME_PKT: c_int = 0;
+const VPX_EFLAG_FORCE_KF: c_long = 1;
+const VPX_DL_REALTIME: c_ulong = 1;
+const VPX_CBR: c_int = 1;
+const VPX_ERROR_RESILIENT_DEFAULT: c_uint = 1;
+/// Frame rate the rate control is set up for, the stream paces the frames itself
+const FPS: c_int = 30;
+
+#[repr(C)]
+struct Rational {
+    num: c_int,
+    den: c_int,
+}
+
+#[repr(C)]
+struct FixedBuf {
+    buf: *mut c_void,
+    size: usize,
+}
+
+/// The start of `vpx_codec_enc_cfg_t`, the rest is reserved for the library
+#[repr(C)]
+struct EncoderConfig {
+    usage: c_uint,
+    threads: c_uint,
+    profile: c_uint,
+    width: c_uint,
+    height: c_uint,
+    bit_depth: c_uint,
+    input_bit_depth: c_uint,
+    timebase: Rational,
+    error_resilient: c_uint,
+    pass: c_int,
+    lag_in_frames: c_uint,
+    dropframe_thresh: c_uint,
+    resize_allowed: c_uint,
+    scaled_width: c_uint,
+    scaled_height: c_uint,
+    resize_up_thresh: c_uint,
+    resize_down_thresh: c_uint,
+    end_usage: c_int,
+    twopass_stats_in: FixedBuf,
+    firstpass_mb_stats_in: FixedBuf,
+    target_bitrate: c_uint,
+    rest: [u8; 4096],
+}
+
+/// `vpx_image_t`
+#[repr(C)]
+struct Image {
+    fmt: c_int,
+    cs: c_int,
+    range: c_int,
+    w: c_uint,
+    h: c_uint,
+    bit_depth: c_uint,
+    d_w: c_uint,
+    d_h: c_uint,
+    r_w: c_uint,
+    r_h: c_uint,
+    x_chroma_shift: c_uint,
+    y_chroma_shift: c_uint,
+    planes: [*mut u8; 4],
+    stride: [c_int; 4],
+    bps: c_int,
+    user_priv: *mut c_void,
+    img_data: *mut u8,
+    img_data_owner: c_int,
+    self_allocd: c_int,
+    fb_priv: *mut c_void,
+}
+
+/// The start of `vpx_codec_cx_pkt_t`, as far as the frames go
+#[repr(C)]
+struct Packet {
+    kind: c_int,
+    buf: *const u8,
+    size: usize,
+}
+
+type Interface = unsafe extern "C" fn() -> *const c_void;
+type EncConfigDefault = unsafe extern "C" fn(*const c_void, *mut EncoderConfig, c_uint) -> c_int;
+type EncInit =
+    unsafe extern "C" fn(*mut c_void, *const c_void, *const EncoderConfig, c_long, c_int) -> c_int;
+type Encode =
+    unsafe extern "C" fn(*mut c_void, *const Image, i64, c_ulong, c_long, c_ulong) -> c_int;
+type GetCxData = unsafe extern "C" fn(*mut c_void, *mut *const c_void) -> *const Packet;
+type DecInit =
+    unsafe extern "C" fn(*mut c_void, *const c_void, *const c_void, c_long, c_int) -> c_int;
+type Decode = unsafe extern "C" fn(*mut c_void, *const u8, c_uint, *mut c_void, c_long) -> c_int;
+type GetFrame = unsafe extern "C" fn(*mut c_void, *mut *const c_void) -> *const Image;
+type Destroy = unsafe extern "C" fn(*mut c_void) -> c_int;
+type Error = unsafe extern "C" fn(*const c_void) -> *const c_char;
+
+/// The functions of the library, valid as long as it's loaded
+struct Api {
+    vp8_cx: Interface,
+    vp8_dx: Interface,
+    enc_config_default: EncConfigDefault,
+    enc_init: EncInit,
+    encode: Encode,
+    get_cx_data: GetCxData,
+    dec_init: DecInit,
+    decode: Decode,
+    get_frame: GetFrame,
+    destroy: Destroy,
+    error: Error,
+    _library: Library,
+}
+
+impl Api {
+    fn load() -> anyhow::Result<Self> {
+        // SAFETY: libvpx runs no code as it's loaded
+        let library = LIBRARIES
+            .iter()
+            .find_map(|name| unsafe { Library::new(name) }.ok())
+            .ok_or_else(|| anyhow!("libvpx isn't installed"))?;
+        // SAFETY: the types are the ones of vpx_encoder.h, vpx_decoder.h and vp8cx.h
+        unsafe {
+            Ok(Self {
+                vp8_cx: *library.get(b"vpx_codec_vp8_cx\0")?,
+                vp8_dx: *library.get(b"vpx_codec_vp8_dx\0")?,
+                enc_config_default: *library.get(b"vpx_codec_enc_config_default\0")?,
+                enc_init: *library.get(b"vpx_codec_enc_init_ver\0")?,
+                encode: *library.get(b"vpx_codec_encode\0")?,
+                get_cx_data: *library.get(b"vpx_codec_get_cx_data\0")?,
+                dec_init: *library.get(b"vpx_codec_dec_init_ver\0")?,
+                decode: *library.get(b"vpx_codec_decode\0")?,
+                get_frame: *library.get(b"vpx_codec_get_frame\0")?,
+                destroy: *library.get(b"vpx_codec_destroy\0")?,
+                error: *library.get(b"vpx_codec_error\0")?,
+                _library: library,
+            })
+        }
+    }
+    /// Initialize `context` with `init`, trying the ABI versions until the library takes one
+    /// # Errors
+    /// Returns an error if it takes none of them, or fails otherwise
+    fn init(
+        &self,
+        context: &mut [u64; CONTEXT_WORDS],
+        init: impl Fn(*mut c_void, c_int) -> c_int,
+    ) -> anyhow::Result<()> {
+        let context = context.as_mut_ptr().cast::<c_void>();
+        for version in ABI_VERSIONS {
+            match init(context, version) {
+                VPX_CODEC_OK => return Ok(()),
+                VPX_CODEC_ABI_MISMATCH => continue,
+                status => bail!("{}, status {status}", self.last_error(context)),
+            }
+        }
+        bail!("The ABI of libvpx isn't one of {ABI_VERSIONS:?}")
+    }
+    /// What the last call on `context` failed with
+    fn last_error(&self, context: *const c_void) -> String {
+        // SAFETY: the message is a static string of the library
+        unsafe {
+            let message = (self.error)(context);
+            if message.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(message).to_string_lossy().into_owned()
+            }
+        }
+    }
+}
+
+/// Whether libvpx is there to encode and decode VP8
+pub(crate) fn available() -> bool {
+    Api::load().is_ok()
+}
+
+/// A VP8 encoder, for frames of a single size, tuned for real time and lossy networks
+/// * `config` - referred to by the library for as long as the encoder is open
+/// * `force_intra` - the next frame is made a keyframe
+pub(crate) struct VpxEncoder {
+    api: Api,
+    context: Box<[u64; CONTEXT_WORDS]>,
+    _config: Box<EncoderConfig>,
+    width: usize,
+    height: usize,
+    pts: i64,
+    force_intra: bool,
+}
+
+impl VpxEncoder {
+    /// # Errors
+    /// Returns an error if the library isn't there, or it doesn't take the configuration
+    pub(crate) fn open(width: usize, height: usize) -> anyhow::Result<Self> {
+        let api = Api::load()?;
+        // SAFETY: the configuration is filled in by the library, within the bytes reserved.
+        // The encoder is only initialized if it takes it, and destroyed as it's dropped
+        unsafe {
+            let interface = (api.vp8_cx)();
+            let mut config: Box<EncoderConfig> = Box::new(std::mem::zeroed());
+            if (api.enc_config_default)(interface, &mut *config, 0) != VPX_CODEC_OK {
+                bail!("libvpx has no default VP8 encoder configuration");
+            }
+            config.width = c_uint::try_from(width)?;
+            config.height = c_uint::try_from(height)?;
+            config.timebase = Rational { num: 1, den: FPS };
+            config.target_bitrate = DEFAULT_BITRATE_BUDGET;
+            config.end_usage = VPX_CBR;
+            // Every frame comes out as it's encoded, and the lost packets don't break the rest
+            config.lag_in_frames = 0;
+            config.error_resilient = VPX_ERROR_RESILIENT_DEFAULT;
+            let mut context = Box::new([0u64; CONTEXT_WORDS]);
+            api.init(&mut context, |context, version| {
+                (api.enc_init)(context, interface, &*config, 0, version)
+            })
+            .map_err(|e| anyhow!("Cannot open the VP8 encoder for {width}x{height}: {e}"))?;
+            Ok(Self {
+                api,
+                context,
+                _config: config,
+                width,
+                height,
+                pts: 0,
+                force_intra: false,
+            })
+        }
+    }
+    fn context(&mut self) -> *mut c_void {
+        self.context.as_mut_ptr().cast()
+    }
+}
+
+impl VideoEncoder for VpxEncoder {
+    fn encode(&mut self, frame: &YuvFrame) -> anyhow::Result<Vec<u8>> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            bail!(
+                "The VP8 encoder takes {}x{}, not {}x{}",
+                self.width,
+                self.height,
+                frame.width,
+                frame.height
+            );
+        }
+        if !frame.is_complete() {
+            bail!("The planes of the frame are short of its size");
+        }
+        let stride = |stride: usize| c_int::try_from(stride).unwrap_or(c_int::MAX);
+        let (width, height) = (self.width as c_uint, self.height as c_uint);
+        let image = Image {
+            fmt: VPX_IMG_FMT_I420,
+            cs: 0,
+            range: 0,
+            w: width,
+            h: height,
+            bit_depth: 8,
+            d_w: width,
+            d_h: height,
+            r_w: width,
+            r_h: height,
+            x_chroma_shift: 1,
+            y_chroma_shift: 1,
+            planes: [
+                frame.y.as_ptr().cast_mut(),
+                frame.u.as_ptr().cast_mut(),
+                frame.v.as_ptr().cast_mut(),
+                ptr::null_mut(),
+            ],
+            stride: [
+                stride(frame.strides.0),
+                stride(frame.strides.1),
+                stride(frame.strides.2),
+                0,
+            ],
+            bps: 12,
+            user_priv: ptr::null_mut(),
+            img_data: ptr::null_mut(),
+            img_data_owner: 0,
+            self_allocd: 0,
+            fb_priv: ptr::null_mut(),
+        };
+        let flags = if std::mem::take(&mut self.force_intra) {
+            VPX_EFLAG_FORCE_KF
+        } else {
+            0
+        };
+        let context = self.context();
+        // SAFETY: the planes are only read, and copied by the library before it returns.
+        // The packets are valid until the next frame is encoded
+        unsafe {
+            let status = (self.api.encode)(context, &image, self.pts, 1, flags, VPX_DL_REALTIME);
+            if status != VPX_CODEC_OK {
+                bail!(
+                    "libvpx failed to encode the frame: {}",
+                    self.api.last_error(context)
+                );
+            }
+            self.pts += 1;
+            let mut frame = Vec::new();
+            let mut iter: *const c_void = ptr::null();
+            loop {
+                let packet = (self.api.get_cx_data)(context, &mut iter);
+                let Some(packet) = packet.as_ref() else {
+                    break;
+                };
+                if packet.kind == VPX_CODEC_CX_FRAME_PKT && !packet.buf.is_null() {
+                    frame.extend_from_slice(std::slice::from_raw_parts(packet.buf, packet.size));
+                }
+            }
+            Ok(frame)
+        }
+    }
+    fn force_intra_frame(&mut self) {
+        self.force_intra = true;
+    }
+    fn backend(&self) -> CodecBackend {
+        CodecBackend::Libvpx
+    }
+    fn encoding(&self) -> VideoEncoding {
+        VideoEncoding::Vp8
+    }
+}
+
+impl Drop for VpxEncoder {
+    fn drop(&mut self) {
+        let context = self.context();
+        // SAFETY: initialized by this library, and destroyed only here
+        unsafe { (self.api.destroy)(context) };
+    }
+}
+
+/// A VP8 decoder, the frames are taken whole
+pub(crate) struct VpxDecoder {
+    api: Api,
+    context: Box<[u64; CONTEXT_WORDS]>,
+}
+
+impl VpxDecoder {
+    /// # Errors
+    /// Returns an error if the library isn't there, or cannot create the decoder
+    pub(crate) fn open() -> anyhow::Result<Self> {
+        let api = Api::load()?;
+        let mut context = Box::new([0u64; CONTEXT_WORDS]);
+        // SAFETY: the decoder takes the default configuration, and is destroyed as it's dropped
+        unsafe {
+            let interface = (api.vp8_dx)();
+            api.init(&mut context, |context, version| {
+                (api.dec_init)(context, interface, ptr::null(), 0, version)
+            })
+            .map_err(|e| anyhow!("Cannot open the VP8 decoder: {e}"))?;
+        }
+        Ok(Self { api, context })
+    }
+    fn context(&mut self) -> *mut c_void {
+        self.context.as_mut_ptr().cast()
+    }
+}
+
+/// A picture of the decoder, valid until the next frame is decoded
+struct VpxPicture<'a>(&'a Image);
+
+impl DecodedPicture for VpxPicture<'_> {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.0.d_w as usize, self.0.d_h as usize)
+    }
+    fn write_rgba8(&self, target: &mut [u8]) {
+        let (width, height) = self.dimensions();
+        let plane = |index: usize, rows: usize| {
+            let stride = self.0.stride[index].max(0) as usize;
+            // SAFETY: the library holds `rows` rows of the plane, `stride` bytes apart
+            unsafe { std::slice::from_raw_parts(self.0.planes[index], stride * rows) }
+        };
+        let chroma_rows = height.div_ceil(2);
+        write_i420_rgba8(
+            (
+                plane(0, height),
+                plane(1, chroma_rows),
+                plane(2, chroma_rows),
+            ),
+            (
+                self.0.stride[0].max(0) as usize,
+                self.0.stride[1].max(0) as usize,
+                self.0.stride[2].max(0) as usize,
+            ),
+            (width, height),
+            target,
+        );
+    }
+}
+
+impl VideoDecoder for VpxDecoder {
+    fn decode(&mut self, unit: &[u8]) -> anyhow::Result<Option<Box<dyn DecodedPicture + '_>>> {
+        let context = self.context();
+        // SAFETY: the frame is only read, the picture is borrowed along with the decoder
+        unsafe {
+            let status = (self.api.decode)(
+                context,
+                unit.as_ptr(),
+                c_uint::try_from(unit.len())?,
+                ptr::null_mut(),
+                0,
+            );
+            if status != VPX_CODEC_OK {
+                bail!(self.api.last_error(context));
+            }
+            let mut iter: *const c_void = ptr::null();
+            let mut last = None;
+            while let Some(image) = (self.api.get_frame)(context, &mut iter).as_ref() {
+                if image.fmt != VPX_IMG_FMT_I420 {
+                    bail!("libvpx decoded the frame as {:#x}, not I420", image.fmt);
+                }
+                last = Some(image);
+            }
+            Ok(last.map(|image| Box::new(VpxPicture(image)) as Box<dyn DecodedPicture>))
+        }
+    }
+    fn backend(&self) -> CodecBackend {
+        CodecBackend::Libvpx
+    }
+    fn encoding(&self) -> VideoEncoding {
+        VideoEncoding::Vp8
+    }
+}
+
+impl Drop for VpxDecoder {
+    fn drop(&mut self) {
+        let context = self.context();
+        // SAFETY: initialized by this library, and destroyed only here
+        unsafe { (self.api.destroy)(context) };
+    }
+}
@@ -0,0 +1,262 @@
+//! The x264 encoder of the system, loaded as it's opened, see `crate::codec`.
+//! x264 has no stable ABI: `x264_encoder_open` is named after the build of the library,
+//! so it's looked up for each of `BUILDS`, and the parameters are only set by their names with
+//! `x264_param_parse`, leaving their layout to the library. Only the start of the pictures
+//! is laid out here, up to the planes, which stayed the same over the builds.
+//! The stream is kept to the baseline profile, the only one every OpenH264 decoder takes.
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ops::RangeInclusive;
+use std::ptr;
+
+use anyhow::{anyhow, bail};
+use libloading::Library;
+
+use crate::codec::{CodecBackend, VideoEncoder, YuvFrame};
+
+/// Names the library goes by, the unversioned one is only there along with the headers
+const LIBRARIES: [&str; 4] = [
+    "libx264.so",
+    "libx264.so.164",
+    "libx264.so.163",
+    "libx264.so.155",
+];
+/// Builds `x264_encoder_open` is looked up for, the newest first
+const BUILDS: RangeInclusive<u32> = 148..=170;
+/// 8 byte words reserved for `x264_param_t`, far more than any build takes
+const PARAM_WORDS: usize = 1024;
+/// Frame rate the rate control is set up for, the stream paces the frames itself
+const FPS: &CStr = c"30";
+const X264_CSP_I420: c_int = 0x0002;
+const X264_TYPE_AUTO: c_int = 0;
+const X264_TYPE_IDR: c_int = 1;
+
+/// `x264_image_t`
+#[repr(C)]
+struct Image {
+    csp: c_int,
+    planes: c_int,
+    stride: [c_int; 4],
+    plane: [*mut u8; 4],
+}
+
+/// The start of `x264_picture_t`, the rest is reserved for the library
+#[repr(C)]
+struct Picture {
+    kind: c_int,
+    qp_plus_1: c_int,
+    pic_struct: c_int,
+    keyframe: c_int,
+    pts: i64,
+    dts: i64,
+    param: *mut c_void,
+    img: Image,
+    rest: [u8; 1024],
+}
+
+/// `x264_nal_t`, read as the library returns them
+#[repr(C)]
+struct Nal {
+    ref_idc: c_int,
+    kind: c_int,
+    long_start_code: c_int,
+    first_mb: c_int,
+    last_mb: c_int,
+    payload_len: c_int,
+    payload: *mut u8,
+    padding: c_int,
+}
+
+type ParamDefaultPreset = unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int;
+type ParamParse = unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int;
+type ParamApplyProfile = unsafe extern "C" fn(*mut c_void, *const c_char) -> c_int;
+type EncoderOpen = unsafe extern "C" fn(*mut c_void) -> *mut c_void;
+type EncoderEncode = unsafe extern "C" fn(
+    *mut c_void,
+    *mut *mut Nal,
+    *mut c_int,
+    *mut Picture,
+    *mut Picture,
+) -> c_int;
+type EncoderClose = unsafe extern "C" fn(*mut c_void);
+type PictureInit = unsafe extern "C" fn(*mut Picture);
+
+/// The functions of the library, valid as long as it's loaded
+struct Api {
+    param_default_preset: ParamDefaultPreset,
+    param_parse: ParamParse,
+    param_apply_profile: ParamApplyProfile,
+    encoder_open: EncoderOpen,
+    encoder_encode: EncoderEncode,
+    encoder_close: EncoderClose,
+    picture_init: PictureInit,
+    _library: Library,
+}
+
+impl Api {
+    fn load() -> anyhow::Result<Self> {
+        // SAFETY: x264 runs no code as it's loaded
+        let library = LIBRARIES
+            .iter()
+            .find_map(|name| unsafe { Library::new(name) }.ok())
+            .ok_or_else(|| anyhow!("libx264 isn't installed"))?;
+        // SAFETY: the types are the ones of x264.h
+        unsafe {
+            let encoder_open = BUILDS
+                .rev()
+                .find_map(|build| {
+                    let name = format!("x264_encoder_open_{build}\0");
+                    library.get::<EncoderOpen>(name.as_bytes()).ok().map(|f| *f)
+                })
+                .ok_or_else(|| anyhow!("The build of libx264 isn't one of {BUILDS:?}"))?;
+            Ok(Self {
+                param_default_preset: *library.get(b"x264_param_default_preset\0")?,
+                param_parse: *library.get(b"x264_param_parse\0")?,
+                param_apply_profile: *library.get(b"x264_param_apply_profile\0")?,
+                encoder_open,
+                encoder_encode: *library.get(b"x264_encoder_encode\0")?,
+                encoder_close: *library.get(b"x264_encoder_close\0")?,
+                picture_init: *library.get(b"x264_picture_init\0")?,
+                _library: library,
+            })
+        }
+    }
+}
+
+/// An x264 encoder, for frames of a single size
+/// * `force_intra` - the next frame is made a keyframe
+pub(crate) struct X264Encoder {
+    api: Api,
+    encoder: *mut c_void,
+    width: usize,
+    height: usize,
+    pts: i64,
+    force_intra: bool,
+}
+
+impl X264Encoder {
+    /// Tuned for low latency, every frame comes out as it's encoded
+    /// # Errors
+    /// Returns an error if the library isn't there, or it doesn't take the parameters
+    pub(crate) fn open(width: usize, height: usize) -> anyhow::Result<Self> {
+        let api = Api::load()?;
+        let mut words = vec![0u64; PARAM_WORDS];
+        let param = words.as_mut_ptr().cast::<c_void>();
+        let size = |value: usize| std::ffi::CString::new(value.to_string()).unwrap();
+        let (width_value, height_value) = (size(width), size(height));
+        let settings: [(&CStr, &CStr); 4] = [
+            (c"width", &width_value),
+            (c"height", &height_value),
+            (c"fps", FPS),
+            // The peers joining midway wait for a keyframe, they need the headers with it
+            (c"repeat-headers", c"1"),
+        ];
+        // SAFETY: the parameters are only touched by the library, within the words reserved
+        let encoder = unsafe {
+            if (api.param_default_preset)(param, c"veryfast".as_ptr(), c"zerolatency".as_ptr()) != 0
+            {
+                bail!("libx264 doesn't take the veryfast preset");
+            }
+            for (name, value) in settings {
+                if (api.param_parse)(param, name.as_ptr(), value.as_ptr()) != 0 {
+                    bail!("libx264 doesn't take {name:?} of {value:?}");
+                }
+            }
+            if (api.param_apply_profile)(param, c"baseline".as_ptr()) != 0 {
+                bail!("libx264 doesn't take the baseline profile");
+            }
+            (api.encoder_open)(param)
+        };
+        if encoder.is_null() {
+            bail!("Cannot open the x264 encoder for {width}x{height}");
+        }
+        Ok(Self {
+            api,
+            encoder,
+            width,
+            height,
+            pts: 0,
+            force_intra: false,
+        })
+    }
+}
+
+impl VideoEncoder for X264Encoder {
+    fn encode(&mut self, frame: &YuvFrame) -> anyhow::Result<Vec<u8>> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            bail!(
+                "The x264 encoder takes {}x{}, not {}x{}",
+                self.width,
+                self.height,
+                frame.width,
+                frame.height
+            );
+        }
+        if !frame.is_complete() {
+            bail!("The planes of the frame are short of its size");
+        }
+        let stride = |stride: usize| c_int::try_from(stride).unwrap_or(c_int::MAX);
+        // SAFETY: the planes are only read, and copied by the library before it returns
+        unsafe {
+            let mut picture: Picture = std::mem::zeroed();
+            (self.api.picture_init)(&mut picture);
+            picture.kind = if std::mem::take(&mut self.force_intra) {
+                X264_TYPE_IDR
+            } else {
+                X264_TYPE_AUTO
+            };
+            picture.pts = self.pts;
+            picture.img = Image {
+                csp: X264_CSP_I420,
+                planes: 3,
+                stride: [
+                    stride(frame.strides.0),
+                    stride(frame.strides.1),
+                    stride(frame.strides.2),
+                    0,
+                ],
+                plane: [
+                    frame.y.as_ptr().cast_mut(),
+                    frame.u.as_ptr().cast_mut(),
+                    frame.v.as_ptr().cast_mut(),
+                    ptr::null_mut(),
+                ],
+            };
+            let mut encoded: Picture = std::mem::zeroed();
+            let mut nals: *mut Nal = ptr::null_mut();
+            let mut count: c_int = 0;
+            let size = (self.api.encoder_encode)(
+                self.encoder,
+                &mut nals,
+                &mut count,
+                &mut picture,
+                &mut encoded,
+            );
+            if size < 0 {
+                bail!("x264 failed to encode the frame");
+            }
+            self.pts += 1;
+            let mut bitstream = Vec::with_capacity(size as usize);
+            if !nals.is_null() {
+                for nal in std::slice::from_raw_parts(nals, count.max(0) as usize) {
+                    let len = nal.payload_len.max(0) as usize;
+                    bitstream.extend_from_slice(std::slice::from_raw_parts(nal.payload, len));
+                }
+            }
+            Ok(bitstream)
+        }
+    }
+    fn force_intra_frame(&mut self) {
+        self.force_intra = true;
+    }
+    fn backend(&self) -> CodecBackend {
+        CodecBackend::X264
+    }
+}
+
+impl Drop for X264Encoder {
+    fn drop(&mut self) {
+        // SAFETY: opened by this library, and closed only here
+        unsafe { (self.api.encoder_close)(self.encoder) };
+    }
+}
@@ -0,0 +1,374 @@
+//! Files sent to the peer during a call, and the ones it sends.
+//! A file dropped on the window while connected is offered to the peer. The files the peer
+//! offers are asked about first, and saved into `CallConfig::downloads_dir` once taken.
+//! The transfers themselves run in `scp_client::file_transfer`, this keeps track of them for the UI.
+
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use eye_spy_core::CallManager;
+use scp_client::file_transfer::TransferEvent;
+
+use crate::connection_state_bevy::{FileTransferEvent, ScpConnectionState};
+use crate::locale::{tr, Language};
+use crate::plugin::CallManagerBevy;
+use crate::settings::format_bytes;
+use crate::toast::ToastEvent;
+
+pub struct FileTransferPlugin;
+
+impl Plugin for FileTransferPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FileTransfers>();
+        app.add_systems(
+            Update,
+            (
+                on_file_transfer_events.run_if(on_event::<FileTransferEvent>()),
+                on_file_drag_and_drop.run_if(on_event::<FileDragAndDrop>()),
+                check_file_offer_buttons,
+                update_file_transfer_ui.run_if(
+                    resource_changed::<FileTransfers>.or_else(resource_changed::<Language>),
+                ),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// A file the peer offers, waiting for the user to save or decline it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOffer {
+    pub id: u32,
+    pub name: String,
+    pub size: u64,
+}
+
+/// A file on its way
+/// * `done` - bytes that went through so far, out of `size`
+/// * `sending` - it's sent to the peer, rather than received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub id: u32,
+    pub name: String,
+    pub done: u64,
+    pub size: u64,
+    pub sending: bool,
+}
+
+impl Transfer {
+    pub fn percent(&self) -> u64 {
+        self.done
+            .saturating_mul(100)
+            .checked_div(self.size)
+            .unwrap_or(100)
+            .min(100)
+    }
+}
+
+/// The files offered by the peer, the oldest first, and the files on their way either side
+#[derive(Resource, Debug, Default)]
+pub struct FileTransfers {
+    pub offers: Vec<FileOffer>,
+    pub transfers: Vec<Transfer>,
+}
+
+impl FileTransfers {
+    /// Offer the file at `path` to the peer of the current call
+    pub fn send(&mut self, call: &CallManager, path: PathBuf) {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let size = path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        let Some(id) = call.send_file(path) else {
+            return;
+        };
+        self.transfers.push(Transfer {
+            id,
+            name,
+            done: 0,
+            size,
+            sending: true,
+        });
+    }
+    /// The offer is taken, the file is on its way from now on
+    pub fn accept(&mut self, id: u32) {
+        let Some(offer) = self.take_offer(id) else {
+            return;
+        };
+        self.transfers.push(Transfer {
+            id,
+            name: offer.name,
+            done: 0,
+            size: offer.size,
+            sending: false,
+        });
+    }
+    pub fn decline(&mut self, id: u32) {
+        self.take_offer(id);
+    }
+    fn take_offer(&mut self, id: u32) -> Option<FileOffer> {
+        let index = self.offers.iter().position(|offer| offer.id == id)?;
+        Some(self.offers.remove(index))
+    }
+    /// Forget the file, whether offered or on its way. Returns its transfer, or the offer as one
+    fn take(&mut self, id: u32) -> Option<Transfer> {
+        if let Some(index) = self.transfers.iter().position(|t| t.id == id) {
+            return Some(self.transfers.remove(index));
+        }
+        self.take_offer(id).map(|offer| Transfer {
+            id,
+            name: offer.name,
+            done: 0,
+            size: offer.size,
+            sending: false,
+        })
+    }
+    /// Keep track of what happened to a file. Returns what to tell the user, if anything.
+    /// The files not known here, i.e. the ones already given up on, are ignored
+    pub fn on_event(&mut self, event: &TransferEvent) -> Option<ToastEvent> {
+        match event {
+            TransferEvent::Offered { id, name, size } => {
+                self.offers.push(FileOffer {
+                    id: *id,
+                    name: name.clone(),
+                    size: *size,
+                });
+                None
+            }
+            TransferEvent::Progress { id, done, size } => {
+                let transfer = self.transfers.iter_mut().find(|t| t.id == *id)?;
+                transfer.done = *done;
+                transfer.size = *size;
+                None
+            }
+            TransferEvent::Completed { id, path } => {
+                let transfer = self.take(*id)?;
+                Some(ToastEvent::info(if transfer.sending {
+                    tr!("file_sent", name = transfer.name)
+                } else {
+                    tr!("file_saved", path = path.display())
+                }))
+            }
+            TransferEvent::Declined { id } => {
+                let transfer = self.take(*id)?;
+                Some(ToastEvent::warning(tr!(
+                    "file_declined",
+                    name = transfer.name
+                )))
+            }
+            TransferEvent::Cancelled { id } => {
+                let transfer = self.take(*id)?;
+                Some(ToastEvent::warning(tr!(
+                    "file_cancelled",
+                    name = transfer.name
+                )))
+            }
+            TransferEvent::Failed { id, error } => {
+                let transfer = self.take(*id)?;
+                Some(ToastEvent::error(tr!(
+                    "file_failed",
+                    name = transfer.name,
+                    error = error
+                )))
+            }
+        }
+    }
+}
+
+/// Marker for the prompt asking about the oldest file the peer offers
+#[derive(Component)]
+pub struct FileOfferPrompt;
+
+/// Marker for the text telling which file the peer offers
+#[derive(Component)]
+pub struct FileOfferText;
+
+#[derive(Component)]
+pub struct SaveFileButton;
+
+#[derive(Component)]
+pub struct DeclineFileButton;
+
+/// Marker for the panel listing the files on their way, hidden while there are none
+#[derive(Component)]
+pub struct FileTransfersPanel;
+
+#[derive(Component)]
+pub struct FileTransfersText;
+
+/// Marker for the overlay shown while a file is dragged over the window during a call
+#[derive(Component)]
+pub struct FileDropTarget;
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn on_file_transfer_events(
+    mut events: EventReader<FileTransferEvent>,
+    mut transfers: ResMut<FileTransfers>,
+    mut toasts: EventWriter<ToastEvent>,
+) {
+    for event in events.read() {
+        if let Some(toast) = transfers.on_event(event) {
+            toasts.send(toast);
+        }
+    }
+}
+/// Offers the files dropped during a call, the drop target is only shown then
+fn on_file_drag_and_drop(
+    mut events: EventReader<FileDragAndDrop>,
+    call: Res<CallManagerBevy>,
+    scp_state: Res<State<ScpConnectionState>>,
+    mut transfers: ResMut<FileTransfers>,
+    mut targets: Query<&mut Style, With<FileDropTarget>>,
+) {
+    let connected = *scp_state.get() == ScpConnectionState::Connected;
+    for event in events.read() {
+        let hovered = match event {
+            FileDragAndDrop::HoveredFile { .. } => connected,
+            FileDragAndDrop::HoveredFileCanceled { .. } => false,
+            FileDragAndDrop::DroppedFile { path_buf, .. } => {
+                if connected {
+                    transfers.send(&call.0, path_buf.clone());
+                }
+                false
+            }
+        };
+        for mut style in &mut targets {
+            style.display = if hovered {
+                Display::Flex
+            } else {
+                Display::None
+            };
+        }
+    }
+}
+#[allow(clippy::type_complexity)]
+fn check_file_offer_buttons(
+    save: Query<&Interaction, (Changed<Interaction>, With<SaveFileButton>)>,
+    decline: Query<&Interaction, (Changed<Interaction>, With<DeclineFileButton>)>,
+    call: Res<CallManagerBevy>,
+    mut transfers: ResMut<FileTransfers>,
+) {
+    let pressed = |interaction: &Interaction| *interaction == Interaction::Pressed;
+    let Some(id) = transfers.offers.first().map(|offer| offer.id) else {
+        return;
+    };
+    if save.iter().any(pressed) {
+        call.0.accept_file(id);
+        transfers.accept(id);
+    } else if decline.iter().any(pressed) {
+        call.0.decline_file(id);
+        transfers.decline(id);
+    }
+}
+#[allow(clippy::type_complexity)]
+fn update_file_transfer_ui(
+    transfers: Res<FileTransfers>,
+    mut prompt: Query<&mut Style, (With<FileOfferPrompt>, Without<FileTransfersPanel>)>,
+    mut prompt_text: Query<&mut Text, (With<FileOfferText>, Without<FileTransfersText>)>,
+    mut panel: Query<&mut Style, (With<FileTransfersPanel>, Without<FileOfferPrompt>)>,
+    mut panel_text: Query<&mut Text, (With<FileTransfersText>, Without<FileOfferText>)>,
+) {
+    let display = |shown: bool| if shown { Display::Flex } else { Display::None };
+    for mut style in &mut prompt {
+        style.display = display(!transfers.offers.is_empty());
+    }
+    if let Some(offer) = transfers.offers.first() {
+        let message = tr!(
+            "file_offered",
+            name = offer.name,
+            size = format_bytes(offer.size)
+        );
+        for mut text in &mut prompt_text {
+            text.sections[0].value.clone_from(&message);
+        }
+    }
+    for mut style in &mut panel {
+        style.display = display(!transfers.transfers.is_empty());
+    }
+    let lines: Vec<_> = transfers
+        .transfers
+        .iter()
+        .map(|transfer| {
+            let key = if transfer.sending {
+                "file_sending"
+            } else {
+                "file_receiving"
+            };
+            tr!(key, name = transfer.name, percent = transfer.percent())
+        })
+        .collect();
+    let lines = lines.join("\n");
+    for mut text in &mut panel_text {
+        text.sections[0].value.clone_from(&lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use scp_client::file_transfer::TransferEvent;
+
+    use super::{FileTransfers, Transfer};
+    use crate::toast::Severity;
+
+    #[test]
+    fn test_file_transfers() {
+        let mut transfers = FileTransfers::default();
+        let offered = |id| TransferEvent::Offered {
+            id,
+            name: format!("{id}.txt"),
+            size: 200,
+        };
+        assert!(transfers.on_event(&offered(1)).is_none());
+        assert!(transfers.on_event(&offered(2)).is_none());
+        assert_eq!(transfers.offers.len(), 2);
+
+        transfers.accept(1);
+        transfers.decline(2);
+        assert!(transfers.offers.is_empty());
+        let progress = TransferEvent::Progress {
+            id: 1,
+            done: 50,
+            size: 200,
+        };
+        assert!(transfers.on_event(&progress).is_none());
+        assert_eq!(transfers.transfers[0].percent(), 25);
+
+        let completed = TransferEvent::Completed {
+            id: 1,
+            path: PathBuf::from("1.txt"),
+        };
+        let toast = transfers.on_event(&completed).unwrap();
+        assert_eq!(toast.severity, Severity::Info);
+        assert!(toast.text.contains("1.txt"));
+        assert!(transfers.transfers.is_empty());
+        // Already forgotten
+        assert!(transfers.on_event(&completed).is_none());
+
+        // An offer given up on by the peer goes away too
+        transfers.on_event(&offered(3));
+        let toast = transfers
+            .on_event(&TransferEvent::Cancelled { id: 3 })
+            .unwrap();
+        assert_eq!(toast.severity, Severity::Warning);
+        assert!(transfers.offers.is_empty());
+    }
+    #[test]
+    fn test_transfer_percent() {
+        let transfer = |done, size| Transfer {
+            id: 0,
+            name: String::new(),
+            done,
+            size,
+            sending: true,
+        };
+        assert_eq!(transfer(0, 0).percent(), 100);
+        assert_eq!(transfer(1, 3).percent(), 33);
+        assert_eq!(transfer(5, 3).percent(), 100);
+    }
+}
@@ -0,0 +1,211 @@
+//! The app without the window, running the subcommands of `cli::Command`.
+//! Drives the same `CallManager` the window does. The logs go to stderr,
+//! what the user asked for to stdout. A call goes on until either side hangs up,
+//! pressing Enter hangs up here.
+
+use std::io::BufRead;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use eye_spy_core::mdns;
+use eye_spy_core::static_peers::{self, StaticPeer};
+use eye_spy_core::{AutoAnswer, CallConfig, CallEvent, CallManager};
+use eye_spy_core::{RecordingConfig, RecordingLayout};
+use scp_client::file_transfer::TransferEvent;
+use scp_client::probe::probe;
+use tracing::{info, warn};
+
+use crate::cli::Command;
+use crate::daemon::Daemon;
+use crate::ui_logic::{describe_path, find_hosts, FoundHost, PROBE_TIMEOUT};
+
+/// How often the calls are polled, like a frame of the window would
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Run `command` until it's done
+/// # Errors
+/// Returns an error if the calls cannot be started, or the call couldn't be placed
+pub fn run(command: Command, config: CallConfig) -> anyhow::Result<()> {
+    match command {
+        Command::List => {
+            list(&config);
+            Ok(())
+        }
+        Command::Call { addr } => call(config, addr, None),
+        Command::Answer => answer(config, None),
+        Command::Daemon => answer(config, Some(Daemon::from_env())),
+        Command::Record { addr, out } => call(config, addr, Some(&out)),
+        Command::ExportPeers { out } => export_peers(&config, &out),
+    }
+}
+
+/// Print the hosts found over mDNS under the services of `config`, every address of each
+/// with the round trip time to it, the fastest first
+fn list(config: &CallConfig) {
+    let hosts = find_config_hosts(config);
+    if hosts.is_empty() {
+        println!("No hosts found");
+    }
+    for host in hosts {
+        let label = host.label();
+        if host.paths.is_empty() {
+            println!("-\t{label}");
+        }
+        for path in &host.paths {
+            println!("{}\t{}", path.addr, describe_path(&label, path.rtt));
+        }
+    }
+}
+
+/// Save the hosts found and the static peers into `out`, each at its fastest address,
+/// so other machines can import them, see `eye_spy_core::static_peers`
+fn export_peers(config: &CallConfig, out: &Path) -> anyhow::Result<()> {
+    let peers: Vec<StaticPeer> = find_config_hosts(config)
+        .into_iter()
+        .filter_map(|host| {
+            Some(StaticPeer {
+                name: Some(host.peer.name().to_string()),
+                addr: host.paths.first()?.addr,
+            })
+        })
+        .collect();
+    static_peers::save(out, &peers)
+        .with_context(|| format!("Cannot export the peers to {}", out.display()))?;
+    println!("Exported {} peers to {}", peers.len(), out.display());
+    Ok(())
+}
+
+fn find_config_hosts(config: &CallConfig) -> Vec<FoundHost> {
+    find_hosts(
+        &mdns::services(&config.advertisements),
+        config.beacon_port,
+        &config.static_peers,
+    )
+}
+
+/// Call the peer at `addr` until either side hangs up, recording the call into `out` if given
+fn call(config: CallConfig, addr: SocketAddr, out: Option<&Path>) -> anyhow::Result<()> {
+    let mut calls = CallManager::new(config)?;
+    if let Some(out) = out {
+        // Recorded next to the file, then renamed to it once it's finished
+        let dir = match out.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        calls.set_recording(Some(RecordingConfig {
+            dir,
+            layout: RecordingLayout::PictureInPicture,
+        }));
+    }
+    match probe(addr, PROBE_TIMEOUT) {
+        Ok(capabilities) => calls.call_probed(addr, &capabilities),
+        Err(e) => {
+            info!("{addr} didn't answer the probe: {e}");
+            calls.call(addr);
+        }
+    }
+    let hang_up = hang_up_on_enter();
+    let mut established = false;
+    let result = 'call: loop {
+        if established && hang_up.load(Ordering::Relaxed) {
+            calls.hang_up();
+            break Ok(());
+        }
+        for event in drive(&mut calls) {
+            match event {
+                CallEvent::Established(_) => {
+                    println!("Connected to {addr}, press Enter to hang up");
+                    established = true;
+                }
+                CallEvent::Failed(e) => break 'call Err(anyhow::anyhow!("Call failed: {e}")),
+                CallEvent::Ended => {
+                    println!("{addr} hung up");
+                    break 'call Ok(());
+                }
+                _ => {}
+            }
+        }
+    };
+    let recorded = calls.last_recording().map(Path::to_path_buf);
+    calls.shutdown()?;
+    if let (Some(out), Some(recorded)) = (out, recorded) {
+        std::fs::rename(recorded, out)
+            .with_context(|| format!("Cannot move the recording to {}", out.display()))?;
+        println!("Recorded the call into {}", out.display());
+    }
+    result
+}
+
+/// Answer the calls by itself until the process is stopped, as a service if `daemon` is given
+fn answer(config: CallConfig, mut daemon: Option<Daemon>) -> anyhow::Result<()> {
+    if config.auto_answer == AutoAnswer::Never {
+        bail!("Nobody would be answered, pass --auto or --auto-answer=<ip or fingerprint>,...");
+    }
+    let mut calls = CallManager::new(config)?;
+    let status = format!("Answering the calls at {}", calls.invite());
+    println!("{status}");
+    if let Some(ref daemon) = daemon {
+        daemon.ready(&status);
+    }
+    loop {
+        for event in drive(&mut calls) {
+            if let Some(ref mut daemon) = daemon {
+                daemon.on_event(&event);
+            }
+            match event {
+                CallEvent::Established(config) => println!("Connected to {}", config.ip),
+                #[cfg(feature = "webrtc")]
+                CallEvent::BrowserJoined(addr) => println!("{addr} joined from a browser"),
+                CallEvent::Ended => println!("The call ended"),
+                _ => {}
+            }
+        }
+        if let Some(ref mut daemon) = daemon {
+            daemon.tick(&calls)?;
+        }
+    }
+}
+
+/// Poll the calls once, the events the subcommands may act on returned.
+/// There's nobody to ask, so the calls not answered by themselves, and the files offered, are turned down.
+/// The texts sent for the clipboard are left alone
+fn drive(calls: &mut CallManager) -> Vec<CallEvent> {
+    std::thread::sleep(POLL_INTERVAL);
+    let mut events = Vec::new();
+    for event in calls.poll() {
+        match event {
+            CallEvent::Incoming(ip) => {
+                info!("Turning down the call of {ip}");
+                calls.refuse();
+            }
+            CallEvent::Ringing => println!("Ringing"),
+            CallEvent::File(TransferEvent::Offered { id, name, .. }) => {
+                info!("Turning down {name} offered by the peer");
+                calls.decline_file(id);
+            }
+            CallEvent::Clipboard(_) => info!("Ignoring the clipboard sent by the peer"),
+            CallEvent::DataCapNear(usage) => {
+                warn!("The calls used {} bytes this month", usage.total());
+            }
+            event => events.push(event),
+        }
+    }
+    events
+}
+
+/// Set once a line is read from stdin. Nothing is set if stdin is closed, i.e. run in the background
+fn hang_up_on_enter() -> Arc<AtomicBool> {
+    let hang_up = Arc::new(AtomicBool::new(false));
+    let hang_up_clone = Arc::clone(&hang_up);
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        if matches!(std::io::stdin().lock().read_line(&mut line), Ok(read) if read > 0) {
+            hang_up_clone.store(true, Ordering::Relaxed);
+        }
+    });
+    hang_up
+}
@@ -0,0 +1,448 @@
+//! Keyboard shortcuts of the call: mute the microphone, turn the camera off and on, hang up,
+//! switch the privacy mode and mute the peer's notifications.
+//! Set in a file of their own, one `<action> <keys>` a line, i.e. `hang_up Ctrl+H`,
+//! the actions missing from it keep their defaults, see `Hotkeys`.
+//! They work while the window is focused, system-wide ones would need a hook into the desktop.
+//! The shortcuts go through the same states as the buttons, see `connection_state_bevy`.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use eye_spy_core::h264_stream::outgoing::StreamControls;
+use scp_client::scp::ControlMessage;
+
+use crate::connection_state_bevy::{
+    IncomingVideoStreamState, OutgoingVideoStreamState, SendControlEvent,
+};
+use crate::plugin::CallManagerBevy;
+use crate::ui::TextInputFocus;
+
+pub struct HotkeysPlugin;
+
+impl Plugin for HotkeysPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Hotkeys>();
+        app.init_resource::<MicrophoneMuted>();
+        app.init_resource::<CameraOff>();
+        app.init_resource::<PrivacyMode>();
+        app.init_resource::<PeerNotificationsMuted>();
+        app.add_event::<HotkeyEvent>();
+        app.add_systems(
+            OnEnter(OutgoingVideoStreamState::Off),
+            (reset_camera_off, reset_peer_notifications),
+        );
+        app.add_systems(
+            Update,
+            (
+                read_hotkeys,
+                (
+                    toggle_microphone,
+                    toggle_camera,
+                    toggle_privacy,
+                    toggle_peer_notifications,
+                    hang_up,
+                )
+                    .run_if(on_event::<HotkeyEvent>()),
+                apply_privacy_mode.run_if(resource_changed::<PrivacyMode>),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// What a shortcut does
+/// * `MuteMicrophone` - mute or unmute the microphone. There's no audio stream yet,
+///   so only `MicrophoneMuted` is kept
+/// * `ToggleCamera` - pause the video sent to the peer, or send it again
+/// * `HangUp` - end the call, like the Disconnect button
+/// * `PrivacyMode` - close the camera, or open it again, see `PrivacyMode`
+/// * `MutePeerNotifications` - ask the peer to hold its notifications back, or show them again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HotkeyAction {
+    MuteMicrophone,
+    ToggleCamera,
+    HangUp,
+    PrivacyMode,
+    MutePeerNotifications,
+}
+
+impl FromStr for HotkeyAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mute_microphone" => Ok(Self::MuteMicrophone),
+            "toggle_camera" => Ok(Self::ToggleCamera),
+            "hang_up" => Ok(Self::HangUp),
+            "privacy_mode" => Ok(Self::PrivacyMode),
+            "mute_peer_notifications" => Ok(Self::MutePeerNotifications),
+            _ => Err(format!("Unknown action: {s}")),
+        }
+    }
+}
+
+/// A key with the modifiers held along, i.e. `Ctrl+Shift+M`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hotkey {
+    pub key: KeyCode,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl Hotkey {
+    const fn ctrl(key: KeyCode) -> Self {
+        Self {
+            key,
+            ctrl: true,
+            shift: false,
+            alt: false,
+        }
+    }
+    /// The key was just pressed with exactly these modifiers held
+    fn just_pressed(&self, keys: &ButtonInput<KeyCode>) -> bool {
+        let held = |left, right| keys.any_pressed([left, right]);
+        keys.just_pressed(self.key)
+            && held(KeyCode::ControlLeft, KeyCode::ControlRight) == self.ctrl
+            && held(KeyCode::ShiftLeft, KeyCode::ShiftRight) == self.shift
+            && held(KeyCode::AltLeft, KeyCode::AltRight) == self.alt
+    }
+}
+
+impl FromStr for Hotkey {
+    type Err = String;
+    /// Modifiers and a key joined with `+`, case-insensitive
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut ctrl, mut shift, mut alt) = (false, false, false);
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let key = parts.pop().unwrap_or_default();
+        for modifier in parts {
+            match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => ctrl = true,
+                "shift" => shift = true,
+                "alt" => alt = true,
+                _ => return Err(format!("Unknown modifier: {modifier}")),
+            }
+        }
+        let key = parse_key(key).ok_or_else(|| format!("Unknown key: {key}"))?;
+        Ok(Self {
+            key,
+            ctrl,
+            shift,
+            alt,
+        })
+    }
+}
+
+/// Letters, digits, function keys and a few named ones
+fn parse_key(name: &str) -> Option<KeyCode> {
+    let key = match name.to_ascii_uppercase().as_str() {
+        "A" => KeyCode::KeyA,
+        "B" => KeyCode::KeyB,
+        "C" => KeyCode::KeyC,
+        "D" => KeyCode::KeyD,
+        "E" => KeyCode::KeyE,
+        "F" => KeyCode::KeyF,
+        "G" => KeyCode::KeyG,
+        "H" => KeyCode::KeyH,
+        "I" => KeyCode::KeyI,
+        "J" => KeyCode::KeyJ,
+        "K" => KeyCode::KeyK,
+        "L" => KeyCode::KeyL,
+        "M" => KeyCode::KeyM,
+        "N" => KeyCode::KeyN,
+        "O" => KeyCode::KeyO,
+        "P" => KeyCode::KeyP,
+        "Q" => KeyCode::KeyQ,
+        "R" => KeyCode::KeyR,
+        "S" => KeyCode::KeyS,
+        "T" => KeyCode::KeyT,
+        "U" => KeyCode::KeyU,
+        "V" => KeyCode::KeyV,
+        "W" => KeyCode::KeyW,
+        "X" => KeyCode::KeyX,
+        "Y" => KeyCode::KeyY,
+        "Z" => KeyCode::KeyZ,
+        "0" => KeyCode::Digit0,
+        "1" => KeyCode::Digit1,
+        "2" => KeyCode::Digit2,
+        "3" => KeyCode::Digit3,
+        "4" => KeyCode::Digit4,
+        "5" => KeyCode::Digit5,
+        "6" => KeyCode::Digit6,
+        "7" => KeyCode::Digit7,
+        "8" => KeyCode::Digit8,
+        "9" => KeyCode::Digit9,
+        "F1" => KeyCode::F1,
+        "F2" => KeyCode::F2,
+        "F3" => KeyCode::F3,
+        "F4" => KeyCode::F4,
+        "F5" => KeyCode::F5,
+        "F6" => KeyCode::F6,
+        "F7" => KeyCode::F7,
+        "F8" => KeyCode::F8,
+        "F9" => KeyCode::F9,
+        "F10" => KeyCode::F10,
+        "F11" => KeyCode::F11,
+        "F12" => KeyCode::F12,
+        "SPACE" => KeyCode::Space,
+        "ESCAPE" => KeyCode::Escape,
+        "BACKSPACE" => KeyCode::Backspace,
+        "DELETE" => KeyCode::Delete,
+        "INSERT" => KeyCode::Insert,
+        "HOME" => KeyCode::Home,
+        "END" => KeyCode::End,
+        "PAUSE" => KeyCode::Pause,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// The shortcut of every action. Ctrl+D mutes the microphone, Ctrl+E toggles the camera,
+/// Ctrl+H hangs up, Ctrl+P switches the privacy mode and Ctrl+N mutes the peer's notifications
+/// unless set otherwise
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct Hotkeys(Vec<(HotkeyAction, Hotkey)>);
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self(vec![
+            (HotkeyAction::MuteMicrophone, Hotkey::ctrl(KeyCode::KeyD)),
+            (HotkeyAction::ToggleCamera, Hotkey::ctrl(KeyCode::KeyE)),
+            (HotkeyAction::HangUp, Hotkey::ctrl(KeyCode::KeyH)),
+            (HotkeyAction::PrivacyMode, Hotkey::ctrl(KeyCode::KeyP)),
+            (
+                HotkeyAction::MutePeerNotifications,
+                Hotkey::ctrl(KeyCode::KeyN),
+            ),
+        ])
+    }
+}
+
+impl Hotkeys {
+    /// Loads the shortcuts set at `path` over the defaults, a missing file keeps all of them.
+    /// Lines that cannot be parsed are skipped
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+    fn parse(text: &str) -> Self {
+        let mut hotkeys = Self::default();
+        for (action, hotkey) in text.lines().filter_map(Self::parse_line) {
+            for entry in hotkeys.0.iter_mut().filter(|(a, _)| *a == action) {
+                entry.1 = hotkey;
+            }
+        }
+        hotkeys
+    }
+    fn parse_line(line: &str) -> Option<(HotkeyAction, Hotkey)> {
+        let (action, hotkey) = line.trim().split_once(char::is_whitespace)?;
+        Some((action.parse().ok()?, hotkey.trim().parse().ok()?))
+    }
+}
+
+/// A shortcut was pressed
+#[derive(Event)]
+pub struct HotkeyEvent(pub HotkeyAction);
+
+/// Whether the microphone is muted, kept across the calls
+#[derive(Resource, Debug, Default)]
+pub struct MicrophoneMuted(pub bool);
+
+/// Whether the camera was turned off in the ongoing call, it's on again in the next one
+#[derive(Resource, Debug, Default)]
+pub struct CameraOff(pub bool);
+
+/// Whether the camera is kept closed, so its light is off, instead of only pausing the video.
+/// Kept across the calls, the peer gets no video meanwhile
+#[derive(Resource, Debug, Default)]
+pub struct PrivacyMode(pub bool);
+
+/// Whether the peer of the ongoing call was asked to mute its notifications,
+/// see `ControlMessage::MuteNotifications`. Lifted in the next call
+#[derive(Resource, Debug, Default)]
+pub struct PeerNotificationsMuted(pub bool);
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn read_hotkeys(
+    keys: Res<ButtonInput<KeyCode>>,
+    hotkeys: Res<Hotkeys>,
+    typing: Res<TextInputFocus>,
+    mut writer: EventWriter<HotkeyEvent>,
+) {
+    // Typing an invite isn't a shortcut
+    if typing.is_typing() {
+        return;
+    }
+    for (action, hotkey) in &hotkeys.0 {
+        if hotkey.just_pressed(&keys) {
+            writer.send(HotkeyEvent(*action));
+        }
+    }
+}
+fn toggle_microphone(mut events: EventReader<HotkeyEvent>, mut muted: ResMut<MicrophoneMuted>) {
+    for _ in events
+        .read()
+        .filter(|event| event.0 == HotkeyAction::MuteMicrophone)
+    {
+        muted.0 = !muted.0;
+        info!("Microphone {}", if muted.0 { "muted" } else { "unmuted" });
+    }
+}
+/// Only the video of an ongoing call can be paused
+fn toggle_camera(
+    mut events: EventReader<HotkeyEvent>,
+    stream_out_state: Res<State<OutgoingVideoStreamState>>,
+    mut call: ResMut<CallManagerBevy>,
+    mut camera_off: ResMut<CameraOff>,
+) {
+    for _ in events
+        .read()
+        .filter(|event| event.0 == HotkeyAction::ToggleCamera)
+    {
+        if *stream_out_state.get() != OutgoingVideoStreamState::On {
+            continue;
+        }
+        camera_off.0 = !camera_off.0;
+        if camera_off.0 {
+            call.0.outgoing_mut().pause();
+            info!("Camera off");
+        } else {
+            call.0.outgoing_mut().unpause();
+            info!("Camera on");
+        }
+    }
+}
+/// Out of calls too, the camera isn't opened for the next one then
+fn toggle_privacy(mut events: EventReader<HotkeyEvent>, mut privacy: ResMut<PrivacyMode>) {
+    for _ in events
+        .read()
+        .filter(|event| event.0 == HotkeyAction::PrivacyMode)
+    {
+        privacy.0 = !privacy.0;
+    }
+}
+/// Only the peer of an ongoing call can be asked
+fn toggle_peer_notifications(
+    mut events: EventReader<HotkeyEvent>,
+    stream_out_state: Res<State<OutgoingVideoStreamState>>,
+    mut muted: ResMut<PeerNotificationsMuted>,
+    mut writer: EventWriter<SendControlEvent>,
+) {
+    for _ in events
+        .read()
+        .filter(|event| event.0 == HotkeyAction::MutePeerNotifications)
+    {
+        if *stream_out_state.get() != OutgoingVideoStreamState::On {
+            continue;
+        }
+        muted.0 = !muted.0;
+        writer.send(SendControlEvent(ControlMessage::MuteNotifications(muted.0)));
+        info!(
+            "Peer's notifications {}",
+            if muted.0 { "muted" } else { "unmuted" }
+        );
+    }
+}
+fn apply_privacy_mode(privacy: Res<PrivacyMode>, call: Res<CallManagerBevy>) {
+    call.0.outgoing().set_privacy(privacy.0);
+    if privacy.is_added() {
+        return;
+    }
+    info!("Privacy mode {}", if privacy.0 { "on" } else { "off" });
+}
+fn hang_up(
+    mut events: EventReader<HotkeyEvent>,
+    mut stream_in_state: ResMut<NextState<IncomingVideoStreamState>>,
+    mut stream_out_state: ResMut<NextState<OutgoingVideoStreamState>>,
+) {
+    if events.read().any(|event| event.0 == HotkeyAction::HangUp) {
+        stream_in_state.set(IncomingVideoStreamState::Off);
+        stream_out_state.set(OutgoingVideoStreamState::Off);
+    }
+}
+fn reset_camera_off(mut camera_off: ResMut<CameraOff>) {
+    camera_off.0 = false;
+}
+fn reset_peer_notifications(mut muted: ResMut<PeerNotificationsMuted>) {
+    muted.0 = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::KeyCode;
+
+    use super::{Hotkey, HotkeyAction, Hotkeys};
+
+    #[test]
+    fn test_parse_hotkeys() {
+        assert_eq!(
+            "ctrl+shift+m".parse(),
+            Ok(Hotkey {
+                key: KeyCode::KeyM,
+                ctrl: true,
+                shift: true,
+                alt: false,
+            })
+        );
+        assert_eq!(
+            "F5".parse::<Hotkey>()
+                .map(|hotkey| (hotkey.key, hotkey.ctrl)),
+            Ok((KeyCode::F5, false))
+        );
+        assert!("Super+M".parse::<Hotkey>().is_err());
+        assert!("Ctrl+".parse::<Hotkey>().is_err());
+
+        let hotkeys = Hotkeys::parse(
+            "hang_up Alt+F4\nunmute_everyone Ctrl+U\ntoggle_camera\nprivacy_mode Ctrl+Shift+P\n\
+             mute_peer_notifications F9\n",
+        );
+        let defaults = Hotkeys::default();
+        assert_eq!(hotkeys.0[0], defaults.0[0]);
+        assert_eq!(hotkeys.0[1], defaults.0[1]);
+        assert_eq!(
+            hotkeys.0[2],
+            (
+                HotkeyAction::HangUp,
+                Hotkey {
+                    key: KeyCode::F4,
+                    ctrl: false,
+                    shift: false,
+                    alt: true,
+                }
+            )
+        );
+        assert_eq!(
+            hotkeys.0[3],
+            (
+                HotkeyAction::PrivacyMode,
+                Hotkey {
+                    key: KeyCode::KeyP,
+                    ctrl: true,
+                    shift: true,
+                    alt: false,
+                }
+            )
+        );
+        assert_eq!(
+            hotkeys.0[4],
+            (
+                HotkeyAction::MutePeerNotifications,
+                Hotkey {
+                    key: KeyCode::F9,
+                    ctrl: false,
+                    shift: false,
+                    alt: false,
+                }
+            )
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Control of the app from scripts or desktop widgets, over a Unix socket of the instance.
+//! Every line sent is a JSON command, i.e. `{"command": "hang_up"}`, answered with a line of JSON,
+//! `{"ok": true}` or `{"ok": false, "error": "..."}`. `status` also answers the state of the call.
+//! The commands go through the same paths as the buttons and the hotkeys, see `hotkeys`,
+//! so `CallManager` starts and stops the streams with the calls as usual.
+//!
+//! `echo '{"command": "status"}' | socat - UNIX-CONNECT:$HOME/.config/eye-spy/control.sock`
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::connection_state_bevy::{OutgoingVideoStreamState, ScpConnectionState};
+use crate::hotkeys::{CameraOff, HotkeyAction, HotkeyEvent, MicrophoneMuted};
+use crate::plugin::CallManagerBevy;
+
+/// How long a connection waits for the app to carry a command out, i.e. while it's frozen
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Listens on `socket` for the commands. Nothing listens if it cannot be bound
+pub struct IpcPlugin {
+    pub socket: PathBuf,
+}
+
+impl Plugin for IpcPlugin {
+    fn build(&self, app: &mut App) {
+        match IpcServer::bind(&self.socket) {
+            Ok(server) => {
+                info!("Taking commands at {}", self.socket.display());
+                app.insert_resource(server);
+                app.add_systems(Update, handle_ipc_requests);
+            }
+            Err(e) => warn!("Cannot take commands at {}: {e}", self.socket.display()),
+        }
+    }
+}
+
+/// What a script can ask for
+/// * `Status` - the state of the call, see `Status`
+/// * `Answer`, `Refuse` - the call ringing
+/// * `HangUp`, `MuteMicrophone`, `ToggleCamera` - like their hotkeys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum IpcCommand {
+    Status,
+    Answer,
+    Refuse,
+    HangUp,
+    MuteMicrophone,
+    ToggleCamera,
+}
+
+/// State of the call answered to `IpcCommand::Status`
+/// * `state` - `off`, `connecting`, `ringing` or `connected`, `incoming` while a peer calls
+/// * `peer` - IP of the peer of the ongoing call, or of the one calling
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Status {
+    pub state: &'static str,
+    pub peer: Option<IpAddr>,
+    pub microphone_muted: bool,
+    pub camera_off: bool,
+}
+
+/// Answer to a command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Reply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+            status: None,
+        }
+    }
+    fn error(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.into()),
+            status: None,
+        }
+    }
+}
+
+/// A command waiting for the app to carry it out
+struct Request {
+    command: IpcCommand,
+    reply: Sender<Reply>,
+}
+
+/// The commands of every connection to the socket, removed once the app exits
+#[derive(Resource)]
+struct IpcServer {
+    path: PathBuf,
+    requests: Mutex<Receiver<Request>>,
+}
+
+impl IpcServer {
+    /// Listen at `path`, readable by the owner only, on a thread of its own.
+    /// A socket left there by an instance that crashed is replaced
+    /// # Errors
+    /// Returns an error if another app listens there, or the socket cannot be bound
+    fn bind(path: &Path) -> std::io::Result<Self> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another app takes them",
+            ));
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        let (sender, receiver) = channel();
+        std::thread::Builder::new()
+            .name("ipc_listener".into())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let sender = sender.clone();
+                    std::thread::spawn(move || serve(stream, &sender));
+                }
+            })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            requests: Mutex::new(receiver),
+        })
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Answer the commands of a connection, a line each, until it's closed
+fn serve(stream: UnixStream, requests: &Sender<Request>) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str(&line) {
+            Ok(command) => {
+                let (reply, replied) = channel();
+                if requests.send(Request { command, reply }).is_err() {
+                    return;
+                }
+                replied
+                    .recv_timeout(REPLY_TIMEOUT)
+                    .unwrap_or_else(|_| Reply::error("The app didn't answer in time"))
+            }
+            Err(e) => Reply::error(format!("Invalid command: {e}")),
+        };
+        let Ok(reply) = serde_json::to_string(&reply) else {
+            return;
+        };
+        if writeln!(writer, "{reply}").is_err() {
+            return;
+        }
+    }
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn handle_ipc_requests(
+    server: Res<IpcServer>,
+    mut call: ResMut<CallManagerBevy>,
+    scp_state: Res<State<ScpConnectionState>>,
+    stream_out_state: Res<State<OutgoingVideoStreamState>>,
+    muted: Res<MicrophoneMuted>,
+    camera_off: Res<CameraOff>,
+    mut hotkeys: EventWriter<HotkeyEvent>,
+) {
+    let requests = server.requests.lock().unwrap();
+    for Request { command, reply } in requests.try_iter() {
+        let in_call = *stream_out_state.get() == OutgoingVideoStreamState::On;
+        let result = match command {
+            IpcCommand::Status => {
+                let ringing = call.0.ringing().map(|(ip, _)| *ip);
+                let state = if ringing.is_some() {
+                    "incoming"
+                } else {
+                    match scp_state.get() {
+                        ScpConnectionState::Off => "off",
+                        ScpConnectionState::Connecting => "connecting",
+                        ScpConnectionState::Ringing => "ringing",
+                        ScpConnectionState::Connected => "connected",
+                    }
+                };
+                Reply {
+                    status: Some(Status {
+                        state,
+                        peer: ringing.or_else(|| call.0.session().map(|session| session.ip)),
+                        microphone_muted: muted.0,
+                        camera_off: camera_off.0,
+                    }),
+                    ..Reply::ok()
+                }
+            }
+            IpcCommand::Answer | IpcCommand::Refuse if call.0.ringing().is_none() => {
+                Reply::error("Nobody is calling")
+            }
+            IpcCommand::Answer => {
+                call.0.accept();
+                Reply::ok()
+            }
+            IpcCommand::Refuse => {
+                call.0.refuse();
+                Reply::ok()
+            }
+            IpcCommand::HangUp | IpcCommand::ToggleCamera if !in_call => {
+                Reply::error("There's no call")
+            }
+            IpcCommand::HangUp => {
+                hotkeys.send(HotkeyEvent(HotkeyAction::HangUp));
+                Reply::ok()
+            }
+            IpcCommand::ToggleCamera => {
+                hotkeys.send(HotkeyEvent(HotkeyAction::ToggleCamera));
+                Reply::ok()
+            }
+            IpcCommand::MuteMicrophone => {
+                hotkeys.send(HotkeyEvent(HotkeyAction::MuteMicrophone));
+                Reply::ok()
+            }
+        };
+        // The script may have given up waiting
+        let _ = reply.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    use super::{IpcCommand, IpcServer, Reply, Status};
+
+    #[test]
+    fn test_ipc_commands() {
+        let path = std::env::temp_dir().join(format!("eye-spy-ipc-{}", std::process::id()));
+        let server = IpcServer::bind(&path).unwrap();
+        // Another instance cannot take the commands
+        assert!(IpcServer::bind(&path).is_err());
+        let answer = std::thread::spawn(move || {
+            let request = server.requests.lock().unwrap().recv().unwrap();
+            assert_eq!(request.command, IpcCommand::Status);
+            let status = Status {
+                state: "off",
+                peer: None,
+                microphone_muted: true,
+                camera_off: false,
+            };
+            request
+                .reply
+                .send(Reply {
+                    status: Some(status),
+                    ..Reply::ok()
+                })
+                .unwrap();
+            server
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        writeln!(stream, r#"{{"command": "status"}}"#).unwrap();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"ok":true,"state":"off","peer":null,"microphone_muted":true,"camera_off":false}"#
+        );
+        writeln!(stream, r#"{{"command": "dance"}}"#).unwrap();
+        let reply = lines.next().unwrap().unwrap();
+        assert!(reply.starts_with(r#"{"ok":false,"error":"Invalid command"#));
+
+        // The socket is gone with the app
+        drop(answer.join().unwrap());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,203 @@
+//! Translations of the UI. Every language has a catalog in `assets/locales/<code>.toml`,
+//! a flat table of keys to text, built into the app and parsed when it's picked.
+//! `tr!` gives the text of a key in the language picked, see `Language`, falling back
+//! to English, then to the key itself. `{name}` in the text is replaced by the argument of that name.
+//! The texts spawned with `UiSpawner::spawn_translated_text` follow the language as it's switched.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{OnceLock, RwLock};
+
+use bevy::prelude::*;
+
+/// The catalog of the language picked, None for English
+static ACTIVE: RwLock<Option<Catalog>> = RwLock::new(None);
+
+pub struct LocalePlugin {
+    pub language: Language,
+}
+
+impl Plugin for LocalePlugin {
+    fn build(&self, app: &mut App) {
+        // The UI is spawned in the language right away
+        set_language(self.language);
+        app.insert_resource(self.language);
+        app.add_systems(
+            PreUpdate,
+            retranslate_texts.run_if(resource_changed::<Language>),
+        );
+    }
+}
+
+/// Language the UI is shown in, picked in the settings
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    English,
+    Polish,
+}
+
+impl Language {
+    pub const ALL: [Language; 2] = [Language::English, Language::Polish];
+
+    /// The code of the catalog, i.e. "pl"
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Polish => "pl",
+        }
+    }
+    /// The name of the language in itself
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::Polish => "Polski",
+        }
+    }
+    /// The language of a locale, i.e. "pl_PL.UTF-8". None if there's no catalog for it
+    pub fn from_locale(locale: &str) -> Option<Self> {
+        let code = locale.split(['_', '-', '.']).next()?;
+        Self::ALL
+            .into_iter()
+            .find(|language| language.code().eq_ignore_ascii_case(code))
+    }
+    /// The language of the system, see `LC_ALL`, `LC_MESSAGES` and `LANG`. English if unknown
+    pub fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .into_iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|locale| !locale.is_empty())
+            .and_then(|locale| Self::from_locale(&locale))
+            .unwrap_or_default()
+    }
+    /// The next one, for a button cycling through them
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|language| *language == self);
+        Self::ALL[i.map_or(0, |i| (i + 1) % Self::ALL.len())]
+    }
+    fn source(self) -> &'static str {
+        match self {
+            Language::English => include_str!("../assets/locales/en.toml"),
+            Language::Polish => include_str!("../assets/locales/pl.toml"),
+        }
+    }
+}
+
+/// The texts of a language by their keys
+#[derive(Debug, Default)]
+pub struct Catalog(HashMap<String, String>);
+
+impl Catalog {
+    /// # Errors
+    /// Returns an error if `source` isn't a flat TOML table of strings
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        Ok(Self(toml::from_str(source)?))
+    }
+    /// The text of `key` with the arguments put in, None if the catalog lacks it
+    pub fn get(&self, key: &str, args: &[(&str, &dyn Display)]) -> Option<String> {
+        let mut text = self.0.get(key)?.clone();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{name}}}"), &value.to_string());
+        }
+        Some(text)
+    }
+}
+
+fn english() -> &'static Catalog {
+    static ENGLISH: OnceLock<Catalog> = OnceLock::new();
+    ENGLISH.get_or_init(|| {
+        Catalog::parse(Language::English.source()).expect("The English catalog is valid")
+    })
+}
+
+/// Show the UI in `language` from now on, the texts spawned before are left as they are
+pub fn set_language(language: Language) {
+    let catalog = match language {
+        Language::English => None,
+        _ => match Catalog::parse(language.source()) {
+            Ok(catalog) => Some(catalog),
+            Err(e) => {
+                warn!(
+                    "Cannot load the {} catalog, using English: {e}",
+                    language.code()
+                );
+                None
+            }
+        },
+    };
+    *ACTIVE.write().unwrap() = catalog;
+}
+
+/// The text of `key` in the language picked, see `tr!`
+pub fn translate(key: &str, args: &[(&str, &dyn Display)]) -> String {
+    let active = ACTIVE.read().unwrap();
+    active
+        .as_ref()
+        .and_then(|catalog| catalog.get(key, args))
+        .or_else(|| english().get(key, args))
+        .unwrap_or_else(|| key.to_string())
+}
+
+/// The text of a key in the language picked, i.e. `tr!("calling", ip = ip)`
+macro_rules! tr {
+    ($key:expr) => {
+        $crate::locale::translate($key, &[])
+    };
+    ($key:expr, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::locale::translate(
+            $key,
+            &[$((stringify!($name), &$value as &dyn std::fmt::Display)),+],
+        )
+    };
+}
+pub(crate) use tr;
+
+/// A text showing the key in the language picked, translated again once it's switched
+#[derive(Component)]
+pub struct Translated(pub &'static str);
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn retranslate_texts(language: Res<Language>, mut texts: Query<(&Translated, &mut Text)>) {
+    set_language(*language);
+    for (translated, mut text) in &mut texts {
+        text.sections[0].value = tr!(translated.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{english, Catalog, Language};
+
+    #[test]
+    fn test_catalogs() {
+        let english = english();
+        assert_eq!(
+            english.get("calling", &[("ip", &"10.0.0.2")]).unwrap(),
+            "10.0.0.2 is calling"
+        );
+        assert_eq!(english.get("no such key", &[]), None);
+        // Every language has every text
+        for language in Language::ALL {
+            let catalog = Catalog::parse(language.source()).unwrap();
+            for key in english.0.keys() {
+                assert!(
+                    catalog.0.contains_key(key),
+                    "{} lacks {key}",
+                    language.code()
+                );
+            }
+        }
+        assert!(Catalog::parse("key = 1").is_err());
+    }
+    #[test]
+    fn test_languages() {
+        assert_eq!(Language::from_locale("pl_PL.UTF-8"), Some(Language::Polish));
+        assert_eq!(Language::from_locale("en"), Some(Language::English));
+        assert_eq!(Language::from_locale("C"), None);
+        assert_eq!(Language::English.next(), Language::Polish);
+        assert_eq!(Language::Polish.next(), Language::English);
+    }
+}
@@ -1,93 +1,210 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
-use bevy::color::palettes::css::WHITE;
+use bevy::log::{tracing_subscriber, Level, LogPlugin};
 use bevy::prelude::*;
-use bevy::render::render_asset::RenderAssetUsages;
-use bevy::render::render_resource::{Extent3d, TextureFormat};
+use bevy::window::ExitCondition;
 use bevy::winit::WinitSettings;
+mod call_summary;
+mod cli;
 mod connection_state_bevy;
-mod h264_stream;
-mod mdns;
+mod daemon;
+mod debug_console;
+mod file_transfer;
+mod headless;
+mod hotkeys;
+#[cfg(unix)]
+mod ipc;
+mod locale;
+mod plugin;
+mod pop_out;
+mod screen_share;
+mod settings;
+mod shared_clipboard;
+mod stream_view;
+mod toast;
 mod ui;
 mod ui_logic;
+mod video_tile;
+mod window_activity;
 
-use bevy_tweening::TweeningPlugin;
-use connection_state_bevy::{ConnectionStatePlugin, IncomingVideoStreamState};
-use h264_stream::incoming::{init_incoming_h264_stream, IncomingStreamControls};
-use h264_stream::outgoing::{init_h264_video_stream, StreamControls};
-use h264_stream::{HEIGHT, RGB_FRAME_BUFFER, VIDEO_STREAM_PORT, WIDTH};
-use scp_client::client::ScpClientBuilder;
-use ui::UIElementsPlugin;
+use eye_spy_core::camera::{CameraProfiles, DEFAULT_BITRATE_BUDGET};
+use eye_spy_core::h264_stream::outgoing::VideoBackend;
+use eye_spy_core::h264_stream::DEFAULT_KEYFRAME_INTERVAL;
+use eye_spy_core::mdns;
+use eye_spy_core::nal::DEFAULT_MAX_NAL_UNIT_SIZE;
+use eye_spy_core::static_peers;
+use eye_spy_core::{CallConfig, DataUsage, RecordingLayout, VideoEncoding};
+use hotkeys::Hotkeys;
+use locale::Language;
+use plugin::{Config, EyeSpyPlugin, FrameUpload};
+use scp_client::client::DEFAULT_RING_TIMEOUT;
+use scp_client::identity::{Identity, KnownPeers};
+use scp_client::peer_list::PeerList;
+use settings::CallRecording;
+use stream_view::StreamOrientations;
 
-pub const STREAM_IMAGE_HANDLE: Handle<Image> = Handle::weak_from_u128(0b00100011010001000101010101101110000011001011010011001111110010000000110000100010001101111111001000011010010010010011001111111101);
-
-// The following are bevy ECS wrappers for objects relating to streams and scp
-#[derive(Resource)]
-pub struct OutgoingVideoStreamControls<T: StreamControls>(pub T);
-
-#[derive(Resource)]
-pub struct IncomingVideoStreamControls<T: IncomingStreamControls>(pub T);
-
-#[derive(Resource)]
-pub struct ScpClientBevy(pub scp_client::client::ScpClient);
-
-//////////////////
+fn main() {
+    let args = cli::Args::parse();
 
-fn spawn_camera(mut commands: Commands, mut clear_color: ResMut<ClearColor>) {
-    commands.spawn((Camera2dBundle::default(), IsDefaultUiCamera));
-    clear_color.0 = WHITE.into();
-}
-fn update_incoming_stream_image(mut images: ResMut<Assets<Image>>) {
-    let buf = RGB_FRAME_BUFFER.lock().unwrap();
-    let buf = buf.as_slice();
-    if buf.is_empty() {
+    let backend = args
+        .replay
+        .clone()
+        .map_or(VideoBackend::Camera, VideoBackend::File);
+    // Logging isn't set up yet, so report straight to stderr
+    let config_dir = args.config_dir();
+    let identity = Identity::load_or_create(&config_dir.join("identity.key")).unwrap_or_else(|e| {
+        eprintln!("Cannot load the identity, using a temporary one: {e}");
+        Identity::generate()
+    });
+    let known_peers = KnownPeers::load(&config_dir.join("known_peers.json")).unwrap_or_else(|e| {
+        eprintln!("Cannot load the known peers, they won't be remembered: {e}");
+        KnownPeers::in_memory()
+    });
+    let peer_list = PeerList::load(&config_dir.join("peer_list.json")).unwrap_or_else(|e| {
+        eprintln!("Cannot load the peer list, anyone can call: {e}");
+        PeerList::in_memory()
+    });
+    let orientations = StreamOrientations::load(&config_dir.join("stream_orientations"))
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot load the stream orientations, they won't be remembered: {e}");
+            StreamOrientations::in_memory()
+        });
+    let camera_profiles = CameraProfiles::load(&config_dir.join("camera_profiles.json"))
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot load the camera profiles, they won't be saved: {e}");
+            CameraProfiles::in_memory()
+        });
+    let data_usage = DataUsage::load(&config_dir.join("data_usage.json")).unwrap_or_else(|e| {
+        eprintln!("Cannot load the data usage, counting from zero: {e}");
+        DataUsage::in_memory()
+    });
+    let mdns_profiles =
+        mdns::load_profiles(&config_dir.join("mdns_profiles.json")).unwrap_or_else(|e| {
+            eprintln!("Cannot load the mDNS profiles, advertising the default one: {e}");
+            Default::default()
+        });
+    let static_peers_path = config_dir.join("static_peers.json");
+    let mut static_peers = static_peers::load(&static_peers_path).unwrap_or_else(|e| {
+        eprintln!("Cannot load the static peers, none are listed: {e}");
+        Vec::new()
+    });
+    if let Some(ref path) = args.import_peers {
+        match static_peers::load(path) {
+            Ok(imported) => {
+                static_peers::merge(&mut static_peers, imported);
+                if let Err(e) = static_peers::save(&static_peers_path, &static_peers) {
+                    eprintln!("Cannot save the imported peers, they're listed this time only: {e}");
+                }
+            }
+            Err(e) => eprintln!("Cannot import the peers of {}: {e}", path.display()),
+        }
+    }
+    let call = CallConfig {
+        video: backend,
+        dump_dir: args.dump.clone(),
+        identity,
+        known_peers,
+        peer_list,
+        advertise: true,
+        instance: args.instance.clone(),
+        advertisements: args.advertisements(mdns_profiles),
+        beacon_port: args.beacon_port,
+        static_peers,
+        metrics_addr: args.metrics,
+        auto_answer: args.auto_answer.clone().unwrap_or_default(),
+        bitrate_budget: args.max_bitrate.unwrap_or(DEFAULT_BITRATE_BUDGET),
+        packet_size: args.packet_size,
+        keyframe_interval: args.keyframe_interval.unwrap_or(DEFAULT_KEYFRAME_INTERVAL),
+        max_unit_size: args
+            .max_nal_size
+            .map_or(DEFAULT_MAX_NAL_UNIT_SIZE, |kilobytes| {
+                kilobytes.saturating_mul(1024)
+            }),
+        socket_tuning: args.socket_tuning(),
+        encoder: args.encoder,
+        decoder: args.decoder,
+        video_encoding: args.video_codec.unwrap_or(VideoEncoding::H264),
+        ring_timeout: args.ring_timeout.unwrap_or(DEFAULT_RING_TIMEOUT),
+        resume_path: Some(config_dir.join("resume_ticket.json")),
+        screen: args.share_screen.clone().map(VideoBackend::File),
+        data_usage,
+        monthly_cap: args.monthly_cap(),
+        rtp: args.rtp(config_dir.join("stream.sdp")),
+        #[cfg(feature = "webrtc")]
+        browser_addr: args.webrtc,
+        still_fallback: args.still_fallback(),
+        downloads_dir: Some(
+            args.downloads
+                .clone()
+                .unwrap_or_else(|| config_dir.join("downloads")),
+        ),
+        ..Default::default()
+    };
+    if let Some(command) = args.command.clone() {
+        let logs = tracing_subscriber::fmt()
+            .with_max_level(args.log_level.unwrap_or(Level::INFO))
+            .with_writer(std::io::stderr);
+        if daemon::logs_to_journal() {
+            logs.with_ansi(false)
+                .event_format(daemon::JournalFormat::default())
+                .init();
+        } else {
+            logs.init();
+        }
+        if let Err(e) = headless::run(command, call) {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
         return;
     }
-    let format = TextureFormat::Rgba8UnormSrgb;
 
-    let image = Image::new_fill(
-        Extent3d {
-            width: WIDTH as u32,
-            height: HEIGHT as u32,
-            depth_or_array_layers: 1,
+    let hotkeys = Hotkeys::load(&config_dir.join("hotkeys")).unwrap_or_else(|e| {
+        eprintln!("Cannot load the hotkeys, using the default ones: {e}");
+        Hotkeys::default()
+    });
+    let config = Config {
+        call,
+        loopback: args.loopback,
+        dial: args.call().cloned(),
+        orientations,
+        camera_profiles,
+        watermark_label: args.watermark.clone(),
+        motion: args.motion.unwrap_or_default(),
+        recording: CallRecording {
+            dir: args
+                .record
+                .clone()
+                .unwrap_or_else(|| config_dir.join("recordings")),
+            layout: args.record.is_some().then_some(RecordingLayout::SideBySide),
         },
-        bevy::render::render_resource::TextureDimension::D2,
-        buf,
-        format,
-        RenderAssetUsages::all(),
-    );
-    images.insert(STREAM_IMAGE_HANDLE.id(), image);
-}
-
-fn main() {
-    mdns::start_service();
-
-    let addr_out = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
-    let outgoing_controls = init_h264_video_stream(addr_out).unwrap();
-    let incoming_controls = init_incoming_h264_stream().unwrap();
-    let scp_client = ScpClientBuilder::builder()
-        .audio_port(7001)
-        .video_port(VIDEO_STREAM_PORT)
-        .port_scp(60102)
-        .build();
+        summary_dir: config_dir.join("call-summaries"),
+        profile_dir: config_dir.join("profiles"),
+        hotkeys,
+        control_socket: Some(config_dir.join("control.sock")),
+        language: Language::from_env(),
+        frame_upload: FrameUpload {
+            interval: args
+                .upload_fps
+                .map_or(Duration::ZERO, |fps| Duration::from_secs(1) / fps),
+            budget: args.upload_budget.unwrap_or(0).saturating_mul(1_000_000),
+        },
+    };
 
     App::new()
-        .insert_resource(OutgoingVideoStreamControls(outgoing_controls))
-        .insert_resource(IncomingVideoStreamControls(incoming_controls))
-        .insert_resource(ScpClientBevy(scp_client))
-        .add_plugins(DefaultPlugins)
-        .add_plugins(ConnectionStatePlugin)
-        .add_plugins(TweeningPlugin)
-        .add_plugins(ui_logic::UILogicPlugin)
-        .add_plugins(UIElementsPlugin)
-        .insert_resource(Time::<Fixed>::from_seconds(0.050))
-        .insert_resource(WinitSettings::game())
-        .add_systems(Startup, spawn_camera)
-        .add_systems(
-            FixedUpdate,
-            update_incoming_stream_image.run_if(in_state(IncomingVideoStreamState::On)),
+        .add_plugins(
+            DefaultPlugins
+                .set(LogPlugin {
+                    level: args.log_level.unwrap_or(Level::INFO),
+                    custom_layer: debug_console::console_layer,
+                    ..Default::default()
+                })
+                // The panels popped out go with the app
+                .set(WindowPlugin {
+                    exit_condition: ExitCondition::OnPrimaryClosed,
+                    ..Default::default()
+                }),
         )
+        .add_plugins(EyeSpyPlugin::new(config))
+        .insert_resource(WinitSettings::game())
         .run();
-
-    // Create a texture to store RGB data
 }
@@ -0,0 +1,292 @@
+//! Simulated lossy network for tests.
+//! `Impairment` decides what happens to each datagram (loss, duplication, reordering, jitter)
+//! and `UdpProxy` applies it to real UDP traffic between two in-process endpoints.
+//! Everything is driven by a seeded generator, so a failing test can be reproduced.
+//! # Examples
+//! ```
+//! use std::net::UdpSocket;
+//! use std::time::Duration;
+//! use netsim::{NetSimConfig, UdpProxy};
+//!
+//! let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+//! let config = NetSimConfig {
+//!     loss: 0.05,
+//!     ..Default::default()
+//! };
+//! let proxy = UdpProxy::start(receiver.local_addr().unwrap(), config).unwrap();
+//!
+//! let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+//! sender.send_to(b"hello", proxy.addr()).unwrap();
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the proxy checks for packets to release when nothing arrives
+const PROXY_TICK: Duration = Duration::from_millis(1);
+
+/// Configuration of the simulated network. Probabilities are in range 0.0..=1.0
+/// * `loss` - chance that a packet is dropped
+/// * `duplicate` - chance that a packet is delivered twice
+/// * `reorder` - chance that a packet is held back and delivered after the next one
+/// * `jitter` - every packet is delayed by a random time up to this value
+/// * `seed` - seed of the generator deciding the fate of the packets
+#[derive(Clone, Copy, Debug)]
+pub struct NetSimConfig {
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub jitter: Duration,
+    pub seed: u64,
+}
+
+impl Default for NetSimConfig {
+    fn default() -> Self {
+        Self {
+            loss: 0.,
+            duplicate: 0.,
+            reorder: 0.,
+            jitter: Duration::ZERO,
+            seed: 0x5EED,
+        }
+    }
+}
+
+/// Small xorshift generator. Good enough for tests and keeps the crate dependency free
+#[derive(Clone, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck on 0
+        Self(seed.max(1))
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    /// Random number in range 0.0..1.0
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+}
+
+/// Decides the fate of each packet passing through the simulated network.
+/// It's pure, so it can be used without sockets for deterministic tests.
+#[derive(Clone, Debug)]
+pub struct Impairment {
+    config: NetSimConfig,
+    rng: Rng,
+    /// Packet held back to be sent after the next one
+    held_back: Option<Vec<u8>>,
+}
+
+impl Impairment {
+    pub fn new(config: NetSimConfig) -> Self {
+        Self {
+            rng: Rng::new(config.seed),
+            config,
+            held_back: None,
+        }
+    }
+
+    /// Pass a packet through the network.
+    /// Returns the packets that leave the network now along with their extra delay.
+    pub fn process(&mut self, packet: &[u8]) -> Vec<(Vec<u8>, Duration)> {
+        let mut out = Vec::new();
+        if self.rng.chance(self.config.loss) {
+            return out;
+        }
+        let copies = if self.rng.chance(self.config.duplicate) {
+            2
+        } else {
+            1
+        };
+        if self.held_back.is_none() && self.rng.chance(self.config.reorder) {
+            self.held_back = Some(packet.to_vec());
+            return out;
+        }
+        for _ in 0..copies {
+            let delay = self.jitter();
+            out.push((packet.to_vec(), delay));
+        }
+        if let Some(held) = self.held_back.take() {
+            let delay = self.jitter();
+            out.push((held, delay));
+        }
+        out
+    }
+
+    /// Pass the whole sequence through the network, ignoring the jitter.
+    /// Returns the packets in the order they'd arrive.
+    pub fn process_all<'a>(&mut self, packets: impl IntoIterator<Item = &'a [u8]>) -> Vec<Vec<u8>> {
+        let mut out: Vec<Vec<u8>> = packets
+            .into_iter()
+            .flat_map(|packet| self.process(packet))
+            .map(|(packet, _)| packet)
+            .collect();
+        // Whatever is still held back arrives last
+        out.extend(self.held_back.take());
+        out
+    }
+
+    fn jitter(&mut self) -> Duration {
+        self.config.jitter.mul_f64(self.rng.next_f64())
+    }
+}
+
+/// UDP proxy applying `Impairment` to all datagrams it receives, forwarding them to the target.
+/// The proxy thread stops when the proxy is dropped.
+pub struct UdpProxy {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    t_handle: Option<JoinHandle<()>>,
+}
+
+impl UdpProxy {
+    /// Starts the proxy on a loopback port picked by the OS. Send to `UdpProxy::addr()` to reach `target`.
+    /// # Errors
+    /// Returns an error if the proxy socket cannot be created
+    pub fn start(target: SocketAddr, config: NetSimConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(target.ip(), 0))?;
+        socket.set_read_timeout(Some(PROXY_TICK))?;
+        let addr = socket.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = Arc::clone(&running);
+
+        let t_handle = std::thread::spawn(move || {
+            let mut impairment = Impairment::new(config);
+            let mut buf = [0u8; 65536];
+            // Packets waiting for their jitter to pass, with their release time
+            let mut pending: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+
+            while running_clone.load(Ordering::Relaxed) {
+                if let Ok(size) = socket.recv(&mut buf) {
+                    let now = Instant::now();
+                    for (packet, delay) in impairment.process(&buf[..size]) {
+                        pending.push_back((now + delay, packet));
+                    }
+                }
+                let now = Instant::now();
+                // Jitter may reorder packets on its own, just like a real network would
+                pending
+                    .make_contiguous()
+                    .sort_by_key(|(release, _)| *release);
+                while pending.front().is_some_and(|(release, _)| *release <= now) {
+                    if let Some((_, packet)) = pending.pop_front() {
+                        let _ = socket.send_to(&packet, target);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            running,
+            t_handle: Some(t_handle),
+        })
+    }
+
+    /// Address of the proxy. Packets sent here are forwarded to the target
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for UdpProxy {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.t_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use super::{Impairment, NetSimConfig, UdpProxy};
+
+    fn packets(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i]).collect()
+    }
+
+    #[test]
+    fn test_perfect_network() {
+        let input = packets(100);
+        let out =
+            Impairment::new(NetSimConfig::default()).process_all(input.iter().map(Vec::as_slice));
+        assert_eq!(out, input);
+    }
+    #[test]
+    fn test_loss() {
+        let config = NetSimConfig {
+            loss: 0.5,
+            ..Default::default()
+        };
+        let input = packets(200);
+        let out = Impairment::new(config).process_all(input.iter().map(Vec::as_slice));
+        assert!(
+            out.len() > 50 && out.len() < 150,
+            "lost {}",
+            200 - out.len()
+        );
+    }
+    #[test]
+    fn test_duplicate_and_reorder() {
+        let config = NetSimConfig {
+            duplicate: 0.2,
+            reorder: 0.2,
+            ..Default::default()
+        };
+        let input = packets(200);
+        let out = Impairment::new(config).process_all(input.iter().map(Vec::as_slice));
+        assert!(out.len() > input.len());
+        assert_ne!(out[..], input[..]);
+        // Nothing gets lost
+        assert!(input.iter().all(|p| out.contains(p)));
+    }
+    #[test]
+    fn test_same_seed_same_fate() {
+        let config = NetSimConfig {
+            loss: 0.1,
+            duplicate: 0.1,
+            reorder: 0.1,
+            ..Default::default()
+        };
+        let input = packets(100);
+        let a = Impairment::new(config).process_all(input.iter().map(Vec::as_slice));
+        let b = Impairment::new(config).process_all(input.iter().map(Vec::as_slice));
+        assert_eq!(a, b);
+    }
+    #[test]
+    fn test_proxy_forwards() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let config = NetSimConfig {
+            jitter: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let proxy = UdpProxy::start(receiver.local_addr().unwrap(), config).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"hello", proxy.addr()).unwrap();
+
+        let mut buf = [0u8; 16];
+        let size = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"hello");
+    }
+}
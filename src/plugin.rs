@@ -0,0 +1,383 @@
+//! The whole video chat as a single bevy plugin, so it can be embedded into another app
+//! with one `add_plugins` call. App-wide settings, i.e. logging or the window, are left to the app.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bevy::color::palettes::css::WHITE;
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use bevy_tweening::TweeningPlugin;
+use eye_spy_core::camera::CameraProfiles;
+use eye_spy_core::h264_stream::{RgbaFrame, RGB_FRAME_BUFFER};
+use eye_spy_core::{CallConfig, CallManager, Invite, MotionMode};
+
+use crate::call_summary::{CallSummaryExport, CallSummaryPlugin};
+use crate::connection_state_bevy::{
+    ConnectionStatePlugin, DialEvent, IncomingVideoStreamState, LoopbackCallEvent,
+};
+use crate::debug_console::{DebugConsolePlugin, ProfileExport};
+use crate::file_transfer::FileTransferPlugin;
+use crate::hotkeys::{Hotkeys, HotkeysPlugin};
+#[cfg(unix)]
+use crate::ipc::IpcPlugin;
+use crate::locale::{Language, LocalePlugin};
+use crate::pop_out::PopOutPlugin;
+use crate::screen_share::ScreenSharePlugin;
+use crate::settings::{
+    CallRecording, CameraSettings, MotionDetection, SettingsPlugin, VideoFilters,
+};
+use crate::shared_clipboard::SharedClipboardPlugin;
+use crate::stream_view::{
+    IncomingFrameSize, SharpenMaterial, SharpenMaterialHandle, StreamOrientations, StreamScaling,
+    StreamViewPlugin,
+};
+use crate::ui::UIElementsPlugin;
+use crate::ui_logic::UILogicPlugin;
+use crate::video_tile::VideoTilePlugin;
+use crate::window_activity::{window_visible, WindowActivityPlugin};
+
+pub const STREAM_IMAGE_HANDLE: Handle<Image> = Handle::weak_from_u128(0b00100011010001000101010101101110000011001011010011001111110010000000110000100010001101111111001000011010010010010011001111111101);
+/// The screen shared by the peer, see `screen_share`
+pub const SCREEN_IMAGE_HANDLE: Handle<Image> = Handle::weak_from_u128(0b01110010100100011100001011010110100010011110110000101001101101011101000111010010011100100010110101100110001010111001000001100111);
+
+/// Bevy ECS wrapper for the calls, streams and scp included
+#[derive(Resource)]
+pub struct CallManagerBevy(pub CallManager);
+
+/// A frame taking longer than that to render means the GPU doesn't keep up,
+/// the uploads of the incoming frames are put off then, see `FrameUpload`
+const SATURATED_FRAME_TIME: Duration = Duration::from_millis(100);
+/// Put off for no longer than that, so the stream still moves on a GPU that never keeps up
+const MAX_UPLOAD_STALL: Duration = Duration::from_millis(500);
+
+/// How the incoming frames are uploaded to the GPU, to keep the UI responsive on weak ones.
+/// The frames decoded in between are skipped, the latest one goes up next.
+/// Bevy uploads the whole texture whenever the image changes, so a frame isn't split across
+/// the uploads, the ones that don't fit are put off instead
+/// * `interval` - between two uploads at least, zero to upload every frame as it's decoded
+/// * `budget` - bytes uploaded a second at most, 0 for no limit.
+///   A second's worth may go up at once, or a single frame if it's larger
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameUpload {
+    pub interval: Duration,
+    pub budget: u64,
+}
+
+/// Decides which frames go up, see `FrameUpload`
+/// * `credit` - bytes that may be uploaded now, refilled with the budget as the time goes
+/// * `refilled_at`, `uploaded_at` - since the app started
+#[derive(Debug, Default)]
+struct UploadPacer {
+    credit: f64,
+    refilled_at: Duration,
+    uploaded_at: Option<Duration>,
+}
+
+impl UploadPacer {
+    /// Whether a frame of `bytes` is uploaded `now`, when the last frame took `frame_time` to render
+    fn allow(
+        &mut self,
+        upload: &FrameUpload,
+        now: Duration,
+        frame_time: Duration,
+        bytes: usize,
+    ) -> bool {
+        let bytes = bytes as f64;
+        let budget = upload.budget as f64;
+        let refill = budget * now.saturating_sub(self.refilled_at).as_secs_f64();
+        self.credit = (self.credit + refill).min(budget.max(bytes));
+        self.refilled_at = now;
+        let since_upload = self.uploaded_at.map(|at| now.saturating_sub(at));
+        let saturated = frame_time > SATURATED_FRAME_TIME
+            && since_upload.is_some_and(|since| since < MAX_UPLOAD_STALL);
+        if saturated
+            || since_upload.is_some_and(|since| since < upload.interval)
+            || upload.budget > 0 && self.credit < bytes
+        {
+            return false;
+        }
+        if upload.budget > 0 {
+            self.credit -= bytes;
+        }
+        self.uploaded_at = Some(now);
+        true
+    }
+}
+
+/// What the plugin is set up with
+/// * `call` - passed on to `CallManager`
+/// * `loopback` - call yourself right after the start
+/// * `dial` - accept this invite right after the start
+/// * `orientations` - how the stream of each peer was turned before
+/// * `camera_profiles` - set on the cameras they were saved for
+/// * `watermark_label` - custom text the watermark can show
+/// * `motion` - what the outgoing stream does with the motion in front of the camera
+/// * `recording` - whether and where the calls are recorded
+/// * `summary_dir` - where the summaries of the calls are exported to
+/// * `profile_dir` - where the debug console exports the pipeline profile to
+/// * `hotkeys` - keyboard shortcuts of the call
+/// * `control_socket` - where scripts can control the app, see `ipc`. None not to take commands
+/// * `language` - the UI is shown in, can be switched in the settings
+/// * `frame_upload` - how the incoming frames are uploaded to the GPU
+#[derive(Default)]
+pub struct Config {
+    pub call: CallConfig,
+    pub loopback: bool,
+    pub dial: Option<Invite>,
+    pub orientations: StreamOrientations,
+    pub camera_profiles: CameraProfiles,
+    pub watermark_label: Option<String>,
+    pub motion: MotionMode,
+    pub recording: CallRecording,
+    pub summary_dir: PathBuf,
+    pub profile_dir: PathBuf,
+    pub hotkeys: Hotkeys,
+    pub control_socket: Option<PathBuf>,
+    pub language: Language,
+    pub frame_upload: FrameUpload,
+}
+
+/// Registers all the states, events and systems of the video chat
+/// and starts the calls with the given `Config`
+pub struct EyeSpyPlugin {
+    /// Taken when the plugin is built, `CallManager` owns it then
+    config: Mutex<Option<Config>>,
+}
+
+impl EyeSpyPlugin {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config: Mutex::new(Some(config)),
+        }
+    }
+}
+
+impl Plugin for EyeSpyPlugin {
+    fn build(&self, app: &mut App) {
+        let config = self
+            .config
+            .lock()
+            .unwrap()
+            .take()
+            .expect("EyeSpyPlugin is built only once");
+        // All sockets use ports picked by the OS, so multiple instances can run on one machine
+        let calls = CallManager::new(config.call).expect("Cannot start the streams");
+
+        app.insert_resource(CallManagerBevy(calls));
+        app.insert_resource(config.orientations);
+        app.insert_resource(CameraSettings::new(config.camera_profiles));
+        app.insert_resource(VideoFilters {
+            label: config.watermark_label,
+            ..Default::default()
+        });
+        app.insert_resource(MotionDetection(config.motion));
+        app.insert_resource(config.recording);
+        app.insert_resource(CallSummaryExport(config.summary_dir));
+        app.insert_resource(ProfileExport(config.profile_dir));
+        app.insert_resource(config.hotkeys);
+        app.insert_resource(config.frame_upload);
+        // The app may animate its own UI already
+        if !app.is_plugin_added::<TweeningPlugin>() {
+            app.add_plugins(TweeningPlugin);
+        }
+        app.add_plugins((
+            LocalePlugin {
+                language: config.language,
+            },
+            ConnectionStatePlugin,
+            CallSummaryPlugin,
+            UILogicPlugin,
+            UIElementsPlugin,
+            DebugConsolePlugin,
+            HotkeysPlugin,
+            SettingsPlugin,
+            StreamViewPlugin,
+            VideoTilePlugin,
+            ScreenSharePlugin,
+            FileTransferPlugin,
+            SharedClipboardPlugin,
+            WindowActivityPlugin,
+            PopOutPlugin,
+        ));
+        app.add_systems(Startup, spawn_camera);
+        app.add_systems(
+            Update,
+            update_incoming_stream_image.run_if(
+                in_state(IncomingVideoStreamState::On)
+                    .and_then(window_visible)
+                    .and_then(incoming_frame_arrived.or_else(resource_changed::<StreamScaling>)),
+            ),
+        );
+        #[cfg(unix)]
+        if let Some(socket) = config.control_socket {
+            app.add_plugins(IpcPlugin { socket });
+        }
+        if config.loopback {
+            app.add_systems(PostStartup, |mut writer: EventWriter<LoopbackCallEvent>| {
+                writer.send(LoopbackCallEvent);
+            });
+        }
+        if let Some(invite) = config.dial {
+            app.add_systems(PostStartup, move |mut writer: EventWriter<DialEvent>| {
+                writer.send(DialEvent(invite.clone()));
+            });
+        }
+    }
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn spawn_camera(mut commands: Commands, mut clear_color: ResMut<ClearColor>) {
+    commands.spawn((Camera2dBundle::default(), IsDefaultUiCamera));
+    clear_color.0 = WHITE.into();
+}
+/// Run condition: the incoming stream decoded a frame since it was last shown
+fn incoming_frame_arrived(call: Res<CallManagerBevy>) -> bool {
+    call.0.incoming().has_new_frame()
+}
+/// Runs as the frames arrive, or when they're sampled differently.
+/// A frame put off by `FrameUpload` stays new, so it's tried again the next frame
+#[allow(clippy::too_many_arguments)]
+fn update_incoming_stream_image(
+    call: Res<CallManagerBevy>,
+    mut images: ResMut<Assets<Image>>,
+    time: Res<Time>,
+    upload: Res<FrameUpload>,
+    mut pacer: Local<UploadPacer>,
+    scaling: Res<StreamScaling>,
+    sharpen: Option<Res<SharpenMaterialHandle>>,
+    mut materials: ResMut<Assets<SharpenMaterial>>,
+    mut frame_size: ResMut<IncomingFrameSize>,
+) {
+    let buf = RGB_FRAME_BUFFER.lock().unwrap();
+    if !scaling.is_changed() && !pacer.allow(&upload, time.elapsed(), time.delta(), buf.data.len())
+    {
+        return;
+    }
+    if call.0.incoming().take_new_frame() {
+        if let Some(age) = call.0.incoming().last_frame_age() {
+            call.0.incoming().stats().decode_to_display.record(age);
+        }
+    }
+    if call.0.incoming().last_frame_sequence().is_none() {
+        return;
+    }
+    if buf.data.is_empty() {
+        return;
+    }
+    write_frame_image(&mut images, &STREAM_IMAGE_HANDLE, &buf, scaling.sampler());
+    frame_size.set_if_neq(IncomingFrameSize(UVec2::new(
+        buf.width as u32,
+        buf.height as u32,
+    )));
+    // The material keeps the texture it was prepared with until it's changed
+    if let (StreamScaling::Sharpen, Some(sharpen)) = (*scaling, sharpen) {
+        if let Some(material) = materials.get_mut(&sharpen.0) {
+            material.set_texture_size(buf.width, buf.height);
+        }
+    }
+}
+/// Copy a decoded frame into the image of `handle`. The image is kept, so the texture isn't
+/// allocated again, unless there's none yet or the frame changed size
+pub fn write_frame_image(
+    images: &mut Assets<Image>,
+    handle: &Handle<Image>,
+    frame: &RgbaFrame,
+    sampler: ImageSampler,
+) {
+    match images.get_mut(handle) {
+        Some(image)
+            if image.width() as usize == frame.width
+                && image.height() as usize == frame.height
+                && image.data.len() == frame.data.len() =>
+        {
+            image.data.copy_from_slice(&frame.data);
+            image.sampler = sampler;
+        }
+        _ => {
+            images.insert(handle.id(), frame_image(frame, sampler));
+        }
+    }
+}
+/// Image of a decoded frame, of the size it was decoded in
+fn frame_image(frame: &RgbaFrame, sampler: ImageSampler) -> Image {
+    let format = TextureFormat::Rgba8UnormSrgb;
+
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: frame.width as u32,
+            height: frame.height as u32,
+            depth_or_array_layers: 1,
+        },
+        bevy::render::render_resource::TextureDimension::D2,
+        &frame.data,
+        format,
+        RenderAssetUsages::all(),
+    );
+    image.sampler = sampler;
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{FrameUpload, UploadPacer, MAX_UPLOAD_STALL};
+
+    const MS: Duration = Duration::from_millis(1);
+    const FRAME: usize = 1000;
+
+    #[test]
+    fn test_upload_interval() {
+        let upload = FrameUpload {
+            interval: 50 * MS,
+            budget: 0,
+        };
+        let mut pacer = UploadPacer::default();
+        assert!(pacer.allow(&upload, 1000 * MS, 16 * MS, FRAME));
+        assert!(!pacer.allow(&upload, 1016 * MS, 16 * MS, FRAME));
+        assert!(pacer.allow(&upload, 1050 * MS, 16 * MS, FRAME));
+        // Every frame without an interval
+        let mut pacer = UploadPacer::default();
+        let upload = FrameUpload::default();
+        assert!(pacer.allow(&upload, 1000 * MS, 16 * MS, FRAME));
+        assert!(pacer.allow(&upload, 1000 * MS, 16 * MS, FRAME));
+    }
+
+    #[test]
+    fn test_upload_budget() {
+        // Two frames a second
+        let upload = FrameUpload {
+            interval: Duration::ZERO,
+            budget: 2 * FRAME as u64,
+        };
+        let mut pacer = UploadPacer::default();
+        assert!(pacer.allow(&upload, 1000 * MS, 16 * MS, FRAME));
+        assert!(pacer.allow(&upload, 1016 * MS, 16 * MS, FRAME));
+        assert!(!pacer.allow(&upload, 1032 * MS, 16 * MS, FRAME));
+        assert!(pacer.allow(&upload, 1532 * MS, 16 * MS, FRAME));
+        // A frame over the whole budget still goes up, every other second then
+        let mut pacer = UploadPacer::default();
+        assert!(!pacer.allow(&upload, 1000 * MS, 16 * MS, 4 * FRAME));
+        assert!(pacer.allow(&upload, 2000 * MS, 16 * MS, 4 * FRAME));
+        assert!(!pacer.allow(&upload, 3000 * MS, 16 * MS, 4 * FRAME));
+        assert!(pacer.allow(&upload, 4000 * MS, 16 * MS, 4 * FRAME));
+    }
+
+    #[test]
+    fn test_upload_saturated() {
+        let upload = FrameUpload::default();
+        let mut pacer = UploadPacer::default();
+        assert!(pacer.allow(&upload, 1000 * MS, 200 * MS, FRAME));
+        assert!(!pacer.allow(&upload, 1200 * MS, 200 * MS, FRAME));
+        // Not for too long
+        assert!(pacer.allow(&upload, 1000 * MS + MAX_UPLOAD_STALL, 200 * MS, FRAME));
+        assert!(pacer.allow(&upload, 1600 * MS, 16 * MS, FRAME));
+    }
+}
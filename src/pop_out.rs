@@ -0,0 +1,226 @@
+//! Panels detached into windows of their own, i.e. the settings next to the call.
+//! A panel with `PopOut` is moved into a new window with its `PopOutButton`, drawn there
+//! by a camera of its own, see `TargetCamera`. Pressing the button again, or closing the window,
+//! docks the panel back as it was.
+//! The keyboard navigation stays within the focused window, see `WindowFocus`.
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::render::camera::RenderTarget;
+use bevy::window::{PrimaryWindow, WindowClosed, WindowRef, WindowResolution};
+
+use crate::locale::{tr, Language};
+
+/// Size the windows open with
+const POP_OUT_WIDTH: f32 = 640.;
+const POP_OUT_HEIGHT: f32 = 720.;
+
+pub struct PopOutPlugin;
+
+impl Plugin for PopOutPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                check_pop_out_buttons,
+                dock_closed_panels,
+                update_pop_out_buttons,
+            )
+                .chain(),
+        );
+    }
+}
+
+/// A panel that can be detached into a window of its own
+/// * `title` - of the window
+/// * `window` - the window it's in, None while docked
+/// * `camera` - the camera drawing the window
+/// * `docked` - the style it had docked, given back once the window is closed
+#[derive(Component, Debug)]
+pub struct PopOut {
+    pub title: &'static str,
+    window: Option<Entity>,
+    camera: Option<Entity>,
+    docked: Option<Style>,
+}
+
+impl PopOut {
+    pub fn new(title: &'static str) -> Self {
+        Self {
+            title,
+            window: None,
+            camera: None,
+            docked: None,
+        }
+    }
+    pub fn is_detached(&self) -> bool {
+        self.window.is_some()
+    }
+}
+
+/// Detaches the panel it holds, or docks it back
+#[derive(Component)]
+pub struct PopOutButton(pub Entity);
+
+/// Tells which window the UI nodes are in, to route the keyboard to the focused one
+#[derive(SystemParam)]
+pub struct WindowFocus<'w, 's> {
+    parents: Query<'w, 's, &'static Parent>,
+    panels: Query<'w, 's, &'static PopOut>,
+    windows: Query<'w, 's, (Entity, &'static Window), Without<PrimaryWindow>>,
+}
+
+impl WindowFocus<'_, '_> {
+    /// The node `entity` is in the window with the keyboard focus
+    pub fn is_focused(&self, entity: Entity) -> bool {
+        self.window_of(entity) == self.focused_window()
+    }
+    /// The window of the detached panel `entity` is in, None for the primary window
+    fn window_of(&self, mut entity: Entity) -> Option<Entity> {
+        loop {
+            if let Ok(panel) = self.panels.get(entity) {
+                return panel.window;
+            }
+            entity = self.parents.get(entity).ok()?.get();
+        }
+    }
+    /// The window with the keyboard focus, None for the primary window
+    fn focused_window(&self) -> Option<Entity> {
+        self.windows
+            .iter()
+            .find(|(_, window)| window.focused)
+            .map(|(entity, _)| entity)
+    }
+}
+
+/// The docked style made to fill a window, laid out the same inside
+fn detached(docked: &Style) -> Style {
+    Style {
+        display: Display::Flex,
+        position_type: PositionType::Absolute,
+        top: Val::Px(0.),
+        left: Val::Px(0.),
+        width: Val::Percent(100.),
+        height: Val::Percent(100.),
+        overflow: Overflow::clip_y(),
+        ..docked.clone()
+    }
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn check_pop_out_buttons(
+    query: Query<(&Interaction, &PopOutButton), Changed<Interaction>>,
+    mut panels: Query<(&mut PopOut, &mut Style)>,
+    mut commands: Commands,
+) {
+    for (interaction, button) in &query {
+        if interaction != &Interaction::Pressed {
+            continue;
+        }
+        let Ok((mut panel, mut style)) = panels.get_mut(button.0) else {
+            continue;
+        };
+        // Docked once the window is gone, see `dock_closed_panels`
+        if let Some(window) = panel.window {
+            commands.entity(window).despawn();
+            continue;
+        }
+        let window = commands
+            .spawn(Window {
+                title: panel.title.to_string(),
+                resolution: WindowResolution::new(POP_OUT_WIDTH, POP_OUT_HEIGHT),
+                ..Default::default()
+            })
+            .id();
+        let camera = commands
+            .spawn(Camera2dBundle {
+                camera: Camera {
+                    target: RenderTarget::Window(WindowRef::Entity(window)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .id();
+        commands.entity(button.0).insert(TargetCamera(camera));
+        info!("Popped out {} into a window", panel.title);
+        panel.docked = Some(style.clone());
+        *style = detached(&style);
+        panel.window = Some(window);
+        panel.camera = Some(camera);
+    }
+}
+fn dock_closed_panels(
+    mut closed: EventReader<WindowClosed>,
+    mut panels: Query<(Entity, &mut PopOut, &mut Style)>,
+    mut commands: Commands,
+) {
+    for event in closed.read() {
+        for (entity, mut panel, mut style) in &mut panels {
+            if panel.window != Some(event.window) {
+                continue;
+            }
+            commands.entity(entity).remove::<TargetCamera>();
+            if let Some(camera) = panel.camera.take() {
+                commands.entity(camera).despawn();
+            }
+            if let Some(docked) = panel.docked.take() {
+                *style = docked;
+            }
+            panel.window = None;
+            info!("Docked {} back", panel.title);
+        }
+    }
+}
+fn update_pop_out_buttons(
+    buttons: Query<(&PopOutButton, &Children)>,
+    panels: Query<Ref<PopOut>>,
+    language: Res<Language>,
+    mut texts: Query<&mut Text>,
+) {
+    for (button, children) in &buttons {
+        let Ok(panel) = panels.get(button.0) else {
+            continue;
+        };
+        if !panel.is_changed() && !language.is_changed() {
+            continue;
+        }
+        let label = if panel.is_detached() {
+            tr!("dock")
+        } else {
+            tr!("pop_out")
+        };
+        let mut texts = texts.iter_many_mut(children);
+        while let Some(mut text) = texts.fetch_next() {
+            text.sections[0].value = label.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::detached;
+
+    #[test]
+    fn test_detached_style() {
+        let docked = Style {
+            display: Display::None,
+            top: Val::Percent(10.),
+            left: Val::Percent(30.),
+            width: Val::Percent(40.),
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(10.),
+            ..Default::default()
+        };
+        let style = detached(&docked);
+        assert_eq!(style.display, Display::Flex);
+        assert_eq!((style.top, style.left), (Val::Px(0.), Val::Px(0.)));
+        assert_eq!(style.width, Val::Percent(100.));
+        assert_eq!(style.flex_direction, FlexDirection::Column);
+        assert_eq!(style.row_gap, Val::Px(10.));
+    }
+}
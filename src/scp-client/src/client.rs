@@ -24,14 +24,32 @@
 //! client.end_connection();
 //!
 //! ```
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::hash::{BuildHasher, Hasher};
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
 use std::sync::Weak;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 use std::time::Duration;
 
+use crate::file_transfer::TransferEvent;
+use crate::identity::{Identity, KnownPeers, PeerIdentity, PUBLIC_KEY_LEN};
+use crate::link_local::with_port;
+use crate::peer_list::{PeerList, SharedPeerList};
+use crate::resume::ResumeTicket;
+use crate::scp::{ClipboardText, ControlMessage, StillImage};
 use crate::scp_listener::ScpListener;
 
+/// Highest audio bitrate taken unless set, in kbit/s. Plenty for speech in Opus
+pub const DEFAULT_AUDIO_BITRATE: u32 = 64;
+/// How long a call rings unless set, before it ends as `ScpConnectionError::NotAnswered`
+pub const DEFAULT_RING_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long `request_chat` waits for the peer to ring
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Events used by the client to signify what happens inside the thread with the socket
 #[derive(Debug, Clone)]
 pub enum ConnectionEvent {
@@ -39,8 +57,13 @@ pub enum ConnectionEvent {
     ConnectionEstablished(SessionConfig),
     /// Connection failed - refused, busy or other
     ConnectionFailed(ScpConnectionError),
-    /// Peer attempts to make a connection and waiting for confirmation
-    ConnectionIncoming(IpAddr),
+    /// Peer attempts to make a connection and waiting for confirmation.
+    /// Carries the identity the peer proved to hold, if it shared one
+    ConnectionIncoming(IpAddr, Option<PeerIdentity>),
+    /// The peer got the request and asks its user to accept it.
+    /// `ConnectionEstablished` follows once accepted, `ConnectionFailed` otherwise.
+    /// Carries the address of the peer and the identity it proved to hold, if it shared one
+    Ringing(IpAddr, Option<PeerIdentity>),
     /// Connection ended for whatever reason. Sockets should be cleaned up
     ConnectionEnd,
 }
@@ -49,50 +72,367 @@ pub enum ConnectionEvent {
 pub enum ConnectionAction {
     /// Attempt to make a connection with the provided settings
     AttemptConnection(ConnectionSetings),
+    /// Come back to the session of the ticket, see `crate::resume`
+    Resume(Box<ResumeTicket>),
+    /// Hand the established session over to the device listening at the address
+    Transfer(SocketAddr),
+    /// Give up on the requested session before it's established
+    Cancel,
     /// Refuse incoming connection, or do nothing if no incoming connections
     RefuseConnection,
     /// Accept incoming connection, or do nothing if no incoming connections
     AcceptConnection,
-    /// Set password required for the connection, making an encryption key with it
-    SetPassword(String),
-    /// Remove the password for the socket connection, switching to automatic key generation
-    UnsetPassword,
     EndConnection,
+    /// Send a request to the peer of the established session
+    SendControl(ControlMessage),
+    /// Send a still image to the peer of the established session, see `ControlMessage::StillImages`
+    SendStillImage(StillImage),
+    /// Send the text to the peer of the established session, for its clipboard
+    SendClipboard(ClipboardText),
+    /// Offer the file at the path to the peer of the established session, under the ID
+    SendFile(u32, PathBuf),
+    /// Take the file the peer offered, see `crate::file_transfer`
+    AcceptFile(u32),
+    /// Refuse the file the peer offered
+    DeclineFile(u32),
+    /// Give up on the file sent or received
+    CancelFile(u32),
+    /// Change the audio channels shared with the peers, the next session is negotiated with them
+    SetAudioChannels(u8),
+    /// Remember the key of the peer at the address, see `KnownPeers::trust`
+    TrustPeer(IpAddr, [u8; PUBLIC_KEY_LEN]),
     Terminate,
 }
 /// Configuration for an established chat session
 /// These are "suggestions" only and the responsibility to use all of them correctly
 /// falls on the external implementation.
 /// * `ip` - IpAddr of the connection
-/// * `port_video` - UDP port to send video stream to
-/// * `port_audio` - UDP port to send audio stream to
-/// * `video_encoding` - !UNUSED! method of video encoding used
-/// * `audio_encoding` - !UNUSED! method of audio encoding used
-/// * `encryption_key` - encryption key used to encrypt all and any packets sent
-/// * `encryption_method` - !UNUSED! - encryption method used
+/// * `endpoints` - where the peer takes the streams
+/// * `codecs` - encodings the streams are sent in
+/// * `encryption` - how the messages of the session are protected
+/// * `video_mode` - the mode the peer sends its video in, None if it isn't known up front
+/// * `session_id` - ID to stamp into every stream packet of the session, so packets of a previous
+///   call can be told apart. 0 if the peer didn't negotiate one
+/// * `peer_identity` - the key the peer proved to hold, None if it didn't share one
+/// * `resume` - what's needed to resume the session after a restart, to be saved.
+///   None if the session isn't protected, see `crate::resume`
+/// * `media_keys` - keys to protect the streams with as SRTP. None if the session isn't protected
+///   or a side doesn't take protected streams, see `Preferences::srtp`
 #[derive(Clone, Debug)]
 pub struct SessionConfig {
-    pub encryption_key: Option<String>,
-    pub encrytpion_method: Option<bool>,
     pub ip: IpAddr,
-    pub(crate) stream_config: Preferences,
+    pub endpoints: StreamEndpoints,
+    pub codecs: NegotiatedCodecs,
+    pub encryption: EncryptionMethod,
+    pub video_mode: Option<VideoMode>,
+    pub session_id: u32,
+    pub peer_identity: Option<PeerIdentity>,
+    pub resume: Option<Box<ResumeTicket>>,
+    pub media_keys: Option<Box<MediaKeys>>,
+}
+
+pub const MEDIA_KEY_LEN: usize = 16;
+pub const MEDIA_SALT_LEN: usize = 14;
+
+/// SRTP master key and salt of the streams of one direction, see RFC 3711
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MediaKey {
+    pub key: [u8; MEDIA_KEY_LEN],
+    pub salt: [u8; MEDIA_SALT_LEN],
+}
+
+impl Debug for MediaKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the keys
+        f.debug_struct("MediaKey").finish_non_exhaustive()
+    }
+}
+
+/// Keys of the streams of a session, each direction has its own
+/// * `send` - protects the streams sent to the peer
+/// * `receive` - opens the streams of the peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MediaKeys {
+    pub send: MediaKey,
+    pub receive: MediaKey,
+}
+
+/// Addresses the peer takes the streams on
+/// * `screen` - None if the peer cannot show a shared screen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamEndpoints {
+    pub video: SocketAddr,
+    pub audio: SocketAddr,
+    pub screen: Option<SocketAddr>,
+}
+
+impl StreamEndpoints {
+    /// Endpoints of the peer at `peer`, with the ports of its preferences.
+    /// The scope of a link-local `peer` is kept, see `crate::link_local`
+    pub(crate) fn of_peer(peer: SocketAddr, preferences: &Preferences) -> Self {
+        Self {
+            video: with_port(peer, preferences.port_in_video),
+            audio: with_port(peer, preferences.port_in_audio),
+            screen: preferences.port_in_screen.map(|port| with_port(peer, port)),
+        }
+    }
+}
+
+/// Encodings of the streams sent to the peer.
+/// The video is sent in the first encoding the peer asked for that this client takes too
+/// * `audio` - None if the peers have no audio encoding in common, the call goes without audio
+/// * `packet_format` - how the video streams are split into packets, both ways
+/// * `peer_encoder`, `peer_decoder` - backends the peer encodes its video and decodes ours with,
+///   None for peers that predate them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NegotiatedCodecs {
+    pub video: VideoEncoding,
+    pub audio: Option<AudioCodec>,
+    pub packet_format: PacketFormat,
+    pub peer_encoder: Option<CodecBackend>,
+    pub peer_decoder: Option<CodecBackend>,
+}
+
+impl NegotiatedCodecs {
+    /// Both peers come to the same codecs, whichever of them asks
+    pub(crate) fn negotiate(own: &Preferences, peer: &Preferences) -> Self {
+        Self {
+            video: VideoEncoding::negotiate(own, peer),
+            audio: AudioCodec::negotiate(own, peer),
+            packet_format: PacketFormat::negotiate(own, peer),
+            peer_encoder: peer.encoder,
+            peer_decoder: peer.decoder,
+        }
+    }
+}
+
+/// Layouts of the video stream packets, see `eye_spy_core::nal`
+/// * `Legacy` - the data followed by the session ID, the sequence number and the identifier,
+///   every unit ended by a marker packet. Taken by every peer
+/// * `Header` - a versioned header ahead of the data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PacketFormat {
+    #[default]
+    Legacy = 1,
+    Header = 2,
+}
+
+impl PacketFormat {
+    /// The newest format this client takes
+    pub const NEWEST: Self = Self::Header;
+
+    /// Version the format is told by, in the preferences and the header itself
+    pub fn version(self) -> u8 {
+        self as u8
+    }
+    /// None if the version is unknown, i.e. newer than this client
+    pub fn from_version(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(Self::Legacy),
+            2 => Some(Self::Header),
+            _ => None,
+        }
+    }
+    /// The newest format both peers take, the legacy one with peers that predate the others
+    fn negotiate(own: &Preferences, peer: &Preferences) -> Self {
+        let version = own.packet_version.min(peer.packet_version);
+        Self::from_version(version).unwrap_or_default()
+    }
+}
+
+/// Audio both peers support, in the settings both can take
+/// * `bitrate` - kbit/s, the lower of the two peers. Only Opus goes by it, PCM has a fixed rate
+/// * `channels` - the fewer of the two peers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioCodec {
+    pub encoding: AudioEncoding,
+    pub bitrate: u32,
+    pub channels: u8,
+}
+
+impl AudioCodec {
+    /// The first encoding of `AudioEncoding::ALL` that both peers support
+    fn negotiate(own: &Preferences, peer: &Preferences) -> Option<Self> {
+        let encoding = AudioEncoding::ALL.into_iter().find(|encoding| {
+            own.audio_encodings.contains(encoding) && peer.audio_encodings.contains(encoding)
+        })?;
+        Some(Self {
+            encoding,
+            bitrate: own.audio_bitrate.min(peer.audio_bitrate),
+            channels: own.audio_channels.min(peer.audio_channels).max(1),
+        })
+    }
+}
+
+impl std::fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.encoding {
+            AudioEncoding::Opus48k => write!(f, "Opus at {} kbit/s", self.bitrate)?,
+            AudioEncoding::PcmS16 => write!(f, "16 bit PCM")?,
+        }
+        write!(f, ", {} channel(s)", self.channels)
+    }
+}
+
+/// How a session is protected
+/// * `None` - messages are sent in the clear, nothing stops a forged one,
+///   and the streams go as they are
+/// * `SessionHmac` - messages carry a HMAC with the key agreed on for the session,
+///   see `crate::integrity`. The streams are protected as SRTP with keys derived from it
+///   when both sides set `Preferences::srtp`, see `SessionConfig::media_keys`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionMethod {
+    None,
+    SessionHmac,
+}
+
+/// Random, non-zero session ID. 0 is left for peers that don't negotiate one
+pub fn new_session_id() -> u32 {
+    loop {
+        // RandomState is seeded randomly for each instance, no need for a rand dependency
+        let id = RandomState::new().build_hasher().finish() as u32;
+        if id != 0 {
+            return id;
+        }
+    }
 }
 
 /// Available video encoding formats
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+/// * `H264` - taken by every peer
+/// * `Vp8` - for the builds without OpenH264's licensing, see `eye_spy_core::codec`
+/// * `Av1` - for the constrained uplinks, it takes the fewest bits for the same picture
+/// * `Unknown` - one of a newer peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum VideoEncoding {
     H264,
+    Vp8,
+    Av1,
+    #[serde(other)]
+    Unknown,
+}
+
+impl VideoEncoding {
+    /// The encoding by its name, as in `Display`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('.', "").as_str() {
+            "h264" => Some(Self::H264),
+            "vp8" => Some(Self::Vp8),
+            "av1" => Some(Self::Av1),
+            _ => None,
+        }
+    }
+    /// Encodings a client takes, only `video_encoding` from peers that predate the list
+    pub(crate) fn taken_by(preferences: &Preferences) -> &[Self] {
+        if preferences.video_encodings.is_empty() {
+            std::slice::from_ref(&preferences.video_encoding)
+        } else {
+            &preferences.video_encodings
+        }
+    }
+    /// The first encoding the peer takes that this client takes too, H.264 if there's none
+    fn negotiate(own: &Preferences, peer: &Preferences) -> Self {
+        let own = Self::taken_by(own);
+        Self::taken_by(peer)
+            .iter()
+            .copied()
+            .find(|encoding| *encoding != Self::Unknown && own.contains(encoding))
+            .unwrap_or(Self::H264)
+    }
+}
+
+impl std::fmt::Display for VideoEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::H264 => write!(f, "H.264"),
+            Self::Vp8 => write!(f, "VP8"),
+            Self::Av1 => write!(f, "AV1"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+/// Resolution and frame rate of a video stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoMode {
+    pub width: u16,
+    pub height: u16,
+    pub fps: u16,
+}
+
+impl std::fmt::Display for VideoMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{} at {} fps", self.width, self.height, self.fps)
+    }
 }
 /// Available audio encoding formats
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+/// * `Opus48k` - Opus sampled at 48 kHz
+/// * `PcmS16` - uncompressed signed 16 bit samples at 48 kHz, for peers without Opus
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AudioEncoding {
-    NoIdea,
+    Opus48k,
+    PcmS16,
+}
+
+impl AudioEncoding {
+    /// Every encoding, the preferred one first
+    pub const ALL: [Self; 2] = [Self::Opus48k, Self::PcmS16];
+}
+
+/// Implementations of the video encoding, all of them H.264 the peers can decode either way,
+/// but `Libvpx`, `Rav1e` and `Dav1d`. Told to the peer, so the call shows what both sides run,
+/// see `eye_spy_core::codec`
+/// * `OpenH264` - the software codec built in everywhere
+/// * `X264` - the x264 software encoder of the system, encoding only
+/// * `Libvpx` - the VP8 codec of the system, for `VideoEncoding::Vp8`
+/// * `Rav1e`, `Dav1d` - the AV1 encoder and decoder of the system, for `VideoEncoding::Av1`
+/// * `Passthrough` - the video is encoded already, i.e. a recorded file, and sent as it is
+/// * `Unknown` - one of a newer peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecBackend {
+    #[default]
+    OpenH264,
+    X264,
+    Libvpx,
+    Rav1e,
+    Dav1d,
+    Passthrough,
+    #[serde(other)]
+    Unknown,
+}
+
+impl CodecBackend {
+    /// The backend by its name, as in the preferences
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "openh264" => Some(Self::OpenH264),
+            "x264" => Some(Self::X264),
+            "libvpx" => Some(Self::Libvpx),
+            "rav1e" => Some(Self::Rav1e),
+            "dav1d" => Some(Self::Dav1d),
+            "passthrough" => Some(Self::Passthrough),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CodecBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OpenH264 => write!(f, "OpenH264"),
+            Self::X264 => write!(f, "x264"),
+            Self::Libvpx => write!(f, "libvpx"),
+            Self::Rav1e => write!(f, "rav1e"),
+            Self::Dav1d => write!(f, "dav1d"),
+            Self::Passthrough => write!(f, "pass-through"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
 }
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Clone, Copy, Debug, Error)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
 /// Errors that may arise when establishing a session fails.
 /// Some of the errors may require only small changes to provided config
 pub enum ScpConnectionError {
@@ -106,28 +446,129 @@ pub enum ScpConnectionError {
     PasswordRequired,
     #[error("ScpClient is already connected somewhere")]
     AlreadyConnected,
+    #[error("Nobody answered the call in time")]
+    NotAnswered,
+    #[error("The call was cancelled")]
+    Cancelled,
+}
+
+/// Errors returned by the public API of the crate
+#[derive(Debug, Error)]
+pub enum ScpError {
+    #[error("Port {port} is given to both the {first} and the {second}")]
+    PortConflict {
+        port: u16,
+        first: &'static str,
+        second: &'static str,
+    },
+    #[error("Cannot bind the listener to {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+    #[error("Cannot set up the listener: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Cannot establish the session: {0}")]
+    Connection(#[from] ScpConnectionError),
+    #[error("Peer sent an invalid reply: {0}")]
+    InvalidReply(String),
+    #[error("ScpListener stopped, the client cannot be used anymore")]
+    ListenerStopped,
+}
+
+impl<T> From<PoisonError<T>> for ScpError {
+    fn from(_: PoisonError<T>) -> Self {
+        // Only the listener thread panicking while holding the lock poisons it
+        Self::ListenerStopped
+    }
 }
 
 /// Preferences that ScpClient takes when etablishing a connection
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Preferences {
+    /// Kept to H.264, the only one the peers that predate `video_encodings` can read
     pub video_encoding: VideoEncoding,
-    pub audio_encoding: AudioEncoding,
+    /// Video encodings this client takes and sends, the preferred one first.
+    /// None from peers that predate them
+    #[serde(default)]
+    pub video_encodings: Vec<VideoEncoding>,
+    /// Audio encodings this client takes, none from peers that predate them
+    #[serde(default)]
+    pub audio_encodings: Vec<AudioEncoding>,
+    /// Highest audio bitrate this client takes, in kbit/s
+    #[serde(default)]
+    pub audio_bitrate: u32,
+    #[serde(default)]
+    pub audio_channels: u8,
     pub port_in_video: u16,
     pub port_in_audio: u16,
     pub port_scp: u16,
+    /// The mode the video is sent in, None if it isn't known up front
+    #[serde(default)]
+    pub video_mode: Option<VideoMode>,
+    /// Port the shared screen is taken on, None if it cannot be shown
+    #[serde(default)]
+    pub port_in_screen: Option<u16>,
+    /// Takes the streams protected as SRTP, false from peers that predate it
+    #[serde(default)]
+    pub srtp: bool,
+    /// Newest packet format this client takes, see `PacketFormat::version`.
+    /// 0 from peers that predate it
+    #[serde(default)]
+    pub packet_version: u8,
+    /// Backends the video is encoded and decoded with, see `CodecBackend`.
+    /// None from peers that predate them
+    #[serde(default)]
+    pub encoder: Option<CodecBackend>,
+    #[serde(default)]
+    pub decoder: Option<CodecBackend>,
 }
 
 impl Default for Preferences {
     fn default() -> Self {
         Self {
             video_encoding: VideoEncoding::H264,
-            audio_encoding: AudioEncoding::NoIdea,
+            video_encodings: vec![VideoEncoding::H264],
+            audio_encodings: AudioEncoding::ALL.to_vec(),
+            audio_bitrate: DEFAULT_AUDIO_BITRATE,
+            audio_channels: 1,
             port_in_audio: 7001,
             port_in_video: 7000,
             port_scp: 60201,
+            video_mode: None,
+            port_in_screen: None,
+            srtp: true,
+            packet_version: PacketFormat::NEWEST.version(),
+            encoder: Some(CodecBackend::OpenH264),
+            decoder: Some(CodecBackend::OpenH264),
+        }
+    }
+}
+
+impl Preferences {
+    /// Fails when a port is given to more than one thing. Ports set to 0 are picked by the OS
+    fn validate_ports(&self) -> Result<(), ScpError> {
+        let ports = [
+            ("video stream", Some(self.port_in_video)),
+            ("audio stream", Some(self.port_in_audio)),
+            ("screen stream", self.port_in_screen),
+            ("SCP listener", Some(self.port_scp)),
+        ];
+        let ports: Vec<_> = ports
+            .into_iter()
+            .filter_map(|(name, port)| port.filter(|port| *port != 0).map(|port| (name, port)))
+            .collect();
+        for (i, (first, port)) in ports.iter().enumerate() {
+            if let Some((second, _)) = ports[i + 1..].iter().find(|(_, other)| other == port) {
+                return Err(ScpError::PortConflict {
+                    port: *port,
+                    first,
+                    second,
+                });
+            }
         }
+        Ok(())
     }
 }
 
@@ -138,8 +579,17 @@ pub struct ConnectionSetings {
     pub password: Option<String>,
 }
 
-pub type ActionConnector = Arc<(Mutex<Option<ConnectionAction>>, Condvar)>;
+/// Actions in the order they were taken, the listener carries out all of them on every loop
+pub type ActionConnector = Arc<(Mutex<VecDeque<ConnectionAction>>, Condvar)>;
 pub type EventConnector = Arc<(Mutex<Option<ConnectionEvent>>, Condvar)>;
+/// Requests, still images, files and clipboard texts received from the peer,
+/// kept apart from the events
+type PeerMessages = (
+    Receiver<ControlMessage>,
+    Receiver<StillImage>,
+    Receiver<TransferEvent>,
+    Receiver<ClipboardText>,
+);
 // What does the user want:
 // 1. Try to connect with some settings
 // 2. Wait patiently for some result (sync or async)
@@ -149,29 +599,66 @@ pub type EventConnector = Arc<(Mutex<Option<ConnectionEvent>>, Condvar)>;
 
 pub struct ScpClient {
     preferences: Preferences,
+    identity: Identity,
     tx: ActionConnector,
     rx: EventConnector,
+    /// Requests from the peer. Kept apart from the events, so they don't overwrite each other
+    controls: Mutex<Receiver<ControlMessage>>,
+    /// Still images from the peer, see `ControlMessage::StillImages`
+    stills: Mutex<Receiver<StillImage>>,
+    /// What happened to the files sent and received, see `crate::file_transfer`
+    transfers: Mutex<Receiver<TransferEvent>>,
+    /// Texts the peer sent for the clipboard
+    clipboard: Mutex<Receiver<ClipboardText>>,
+    /// Shared with the listener thread, which enforces it
+    peer_list: SharedPeerList,
     sock_addr: SocketAddr,
+    /// How long the requests ring, see `ScpClientBuilder::ring_timeout`
+    ring_timeout: Duration,
 }
 
 impl ScpClient {
-    /// # Panics
-    /// Panics when a listener cannot be created on the given TCP port.
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        Self::with_preferences(Preferences::default())
+    /// Fails when a listener cannot be created on the given TCP port.
+    pub fn new() -> Result<Self, ScpError> {
+        ScpClientBuilder::builder().try_build()
     }
-    /// # Panics
-    /// Panics when a listener cannot be created on the given TCP port.
-    fn with_preferences(preferences: Preferences) -> Self {
-        let (tx, rx, sock_addr) = Self::spawn_handler_thread(preferences);
+    /// Fails when a listener cannot be created on the given TCP port.
+    fn with_preferences(
+        mut preferences: Preferences,
+        video_modes: Vec<VideoMode>,
+        ring_timeout: Duration,
+        identity: Identity,
+        known_peers: KnownPeers,
+        peer_list: PeerList,
+        downloads: Option<PathBuf>,
+    ) -> Result<Self, ScpError> {
+        let peer_list = Arc::new(Mutex::new(peer_list));
+        let (tx, rx, (controls, stills, transfers, clipboard), sock_addr) =
+            Self::spawn_handler_thread(
+                preferences.clone(),
+                video_modes,
+                ring_timeout,
+                identity.clone(),
+                known_peers,
+                Arc::clone(&peer_list),
+                downloads,
+            )?;
+        // The port might have been picked by the OS
+        preferences.port_scp = sock_addr.port();
 
-        Self {
+        Ok(Self {
             preferences,
+            identity,
             tx,
             rx,
+            controls: Mutex::new(controls),
+            stills: Mutex::new(stills),
+            transfers: Mutex::new(transfers),
+            clipboard: Mutex::new(clipboard),
+            peer_list,
             sock_addr,
-        }
+            ring_timeout,
+        })
     }
     /// Spawns the event loop with TCP socket, reading the messages and responding to external events.
     /// Model of communication:
@@ -183,53 +670,134 @@ impl ScpClient {
     /// More importantly, it gives "async-ish" felling
     fn spawn_handler_thread(
         preferences: Preferences,
-    ) -> (ActionConnector, EventConnector, SocketAddr) {
-        let action: ActionConnector = Arc::new((Mutex::new(None), Condvar::new()));
+        video_modes: Vec<VideoMode>,
+        ring_timeout: Duration,
+        identity: Identity,
+        known_peers: KnownPeers,
+        peer_list: SharedPeerList,
+        downloads: Option<PathBuf>,
+    ) -> Result<(ActionConnector, EventConnector, PeerMessages, SocketAddr), ScpError> {
+        let action: ActionConnector = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
         let event: EventConnector = Arc::new((Mutex::new(None), Condvar::new()));
+        let (controls_tx, controls_rx) = mpsc::channel();
+        let (stills_tx, stills_rx) = mpsc::channel();
+        let (transfers_tx, transfers_rx) = mpsc::channel();
+        let (clipboard_tx, clipboard_rx) = mpsc::channel();
 
         let rx = Arc::clone(&action);
         let tx = Arc::clone(&event);
 
-        let mut listener = ScpListener::new(rx, tx, preferences);
-        let sock_addr = listener.tcp_listener.local_addr().unwrap();
-        std::thread::spawn(move || 'outer: loop {
-            match listener.handle_event_loop() {
-                Ok(()) => continue,
-                Err(e) => {
-                    println!("{e}");
+        let mut listener = ScpListener::new(
+            rx,
+            tx,
+            controls_tx,
+            preferences,
+            identity,
+            known_peers,
+            peer_list,
+        )?;
+        listener.video_modes = video_modes;
+        listener.ring_timeout = ring_timeout;
+        listener.stills = Some(stills_tx);
+        listener.transfers.downloads = downloads;
+        listener.transfer_events = Some(transfers_tx);
+        listener.clipboard = Some(clipboard_tx);
+        let sock_addr = listener.tcp_listener.local_addr()?;
+        std::thread::spawn(move || {
+            let _span = tracing::info_span!("scp_listener", addr = %sock_addr).entered();
+            'outer: loop {
+                match listener.handle_event_loop() {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        tracing::info!("ScpListener stopped: {e}");
 
-                    break 'outer;
+                        break 'outer;
+                    }
                 }
             }
         });
 
-        (action, event, sock_addr)
+        Ok((
+            action,
+            event,
+            (controls_rx, stills_rx, transfers_rx, clipboard_rx),
+            sock_addr,
+        ))
     }
 
-    pub fn request_chat(
-        &self,
-        destination: SocketAddr,
-    ) -> Result<SessionConfig, ScpConnectionError> {
-        *self.tx.0.lock().unwrap() = Some(ConnectionAction::AttemptConnection(ConnectionSetings {
-            destination,
-            password: None,
-        }));
+    /// Blocking function. Requests a session and waits for the peer to accept it,
+    /// for as long as the call rings at most
+    pub fn request_chat(&self, destination: SocketAddr) -> Result<SessionConfig, ScpError> {
+        // Only the outcome of this request counts
+        self.poll_event();
+        self.tx
+            .0
+            .lock()?
+            .push_back(ConnectionAction::AttemptConnection(ConnectionSetings {
+                destination,
+                password: None,
+            }));
         self.tx.1.notify_all();
 
         let (lock, cvar) = &*self.rx;
-        let msg = cvar
-            .wait_timeout_while(lock.lock().unwrap(), Duration::from_secs(5), |msg| {
-                msg.is_none()
-            })
-            .unwrap()
-            .0;
-
-        match &*msg {
-            Some(ConnectionEvent::ConnectionEstablished(s)) => Ok(s.clone()),
-            Some(ConnectionEvent::ConnectionFailed(err)) => Err(*err),
-            _ => Err(ScpConnectionError::NotResponding),
+        let mut timeout = REQUEST_TIMEOUT;
+        loop {
+            let mut msg = cvar
+                .wait_timeout_while(lock.lock()?, timeout, |msg| msg.is_none())?
+                .0;
+            match &*msg {
+                Some(ConnectionEvent::ConnectionEstablished(s)) => return Ok(s.clone()),
+                Some(ConnectionEvent::ConnectionFailed(err)) => return Err((*err).into()),
+                // The listener ends the call once it rang for too long, the margin is for the peer
+                Some(ConnectionEvent::Ringing(..)) => {
+                    msg.take();
+                    timeout = self.ring_timeout + REQUEST_TIMEOUT;
+                }
+                _ => return Err(ScpConnectionError::NotResponding.into()),
+            }
         }
     }
+
+    /// Request a session without waiting for it, the outcome comes with `poll_event`
+    pub fn attempt_connection(&self, destination: SocketAddr) {
+        self.act(ConnectionAction::AttemptConnection(ConnectionSetings {
+            destination,
+            password: None,
+        }));
+    }
+    /// Resume the session of the ticket, i.e. after the app restarted, without dialing again.
+    /// The outcome comes with `poll_event`, `ConnectionEstablished` with the same config as before
+    pub fn resume(&self, ticket: ResumeTicket) {
+        self.act(ConnectionAction::Resume(Box::new(ticket)));
+    }
+    /// Hand the established session over to another device of the user, listening at `destination`.
+    /// The device resumes the session with the peer, which ends it here with `ConnectionEnd`
+    /// once its streams go to the device. Both devices have to know each other's key,
+    /// see `KnownPeers`. Nothing happens if the device cannot take it over
+    pub fn transfer(&self, destination: SocketAddr) {
+        self.act(ConnectionAction::Transfer(destination));
+    }
+    /// Give up on the session requested with `attempt_connection` while the peer is yet to accept it.
+    /// The peer is told and `ConnectionFailed(Cancelled)` follows. Nothing happens once it's established
+    pub fn cancel(&self) {
+        self.act(ConnectionAction::Cancel);
+    }
+    /// Accept the requested session without waiting for it, the outcome comes with `poll_event`
+    pub fn accept(&self) {
+        self.act(ConnectionAction::AcceptConnection);
+    }
+    /// Refuse the requested session
+    pub fn refuse_incoming_connection(&self) {
+        self.act(ConnectionAction::RefuseConnection);
+    }
+    /// Take the latest event, if any. Events not taken in time are replaced by the next one
+    pub fn poll_event(&self) -> Option<ConnectionEvent> {
+        self.rx.0.lock().ok()?.take()
+    }
+    fn act(&self, action: ConnectionAction) {
+        self.tx.0.lock().unwrap().push_back(action);
+        self.tx.1.notify_all();
+    }
     /// Read the events in a blocking way
     pub fn events(&self) -> impl Iterator<Item = ConnectionEvent> {
         EventIterator {
@@ -237,29 +805,121 @@ impl ScpClient {
         }
     }
     /// Blocking function. If no incoming connections, it will wait until there's one available.
-    pub fn accept_incoming_connection(&mut self) -> Result<SessionConfig, ScpConnectionError> {
+    pub fn accept_incoming_connection(&mut self) -> Result<SessionConfig, ScpError> {
         const TIMEOUT: Duration = std::time::Duration::from_secs(3);
-        *self.tx.0.lock().unwrap() = Some(ConnectionAction::AcceptConnection);
+        // i.e. `ConnectionIncoming` of the session being accepted
+        self.poll_event();
+        self.tx
+            .0
+            .lock()?
+            .push_back(ConnectionAction::AcceptConnection);
         self.tx.1.notify_all();
         let (lock, cvar) = &*self.rx;
-        let _ = cvar.wait_timeout_while(lock.lock().unwrap(), TIMEOUT, |event| event.is_none());
-        let val = &*lock.lock().unwrap();
+        let val = cvar
+            .wait_timeout_while(lock.lock()?, TIMEOUT, |event| event.is_none())?
+            .0;
 
-        match val {
+        match &*val {
             Some(ConnectionEvent::ConnectionEstablished(s)) => Ok(s.clone()),
-            Some(ConnectionEvent::ConnectionFailed(e)) => Err(*e),
-            _ => Err(ScpConnectionError::NotResponding),
+            Some(ConnectionEvent::ConnectionFailed(e)) => Err((*e).into()),
+            _ => Err(ScpConnectionError::NotResponding.into()),
         }
     }
     pub fn end_connection(&mut self) {
-        *self.tx.0.lock().unwrap() = Some(ConnectionAction::EndConnection);
+        self.act(ConnectionAction::EndConnection);
+    }
+    /// Send a request to the peer. Dropped if there's no established session
+    pub fn send_control(&self, control: ControlMessage) {
+        self.act(ConnectionAction::SendControl(control));
+    }
+    /// Send a still image to the peer. Dropped if there's no established session
+    pub fn send_still_image(&self, still: StillImage) {
+        self.act(ConnectionAction::SendStillImage(still));
+    }
+    /// The latest still image received from the peer since the last call, the older ones
+    /// are of no use anymore
+    pub fn poll_still_image(&self) -> Option<StillImage> {
+        self.stills.lock().ok()?.try_iter().last()
+    }
+    /// Send the text to the peer, for its user to copy. Dropped if there's no established session
+    pub fn send_clipboard(&self, text: ClipboardText) {
+        self.act(ConnectionAction::SendClipboard(text));
+    }
+    /// The latest text the peer sent for the clipboard since the last call,
+    /// it replaces the ones before
+    pub fn poll_clipboard(&self) -> Option<ClipboardText> {
+        self.clipboard.lock().ok()?.try_iter().last()
+    }
+    /// Offer the file at `path` to the peer of the established session.
+    /// Returns the ID of the transfer, what happens to it comes with `poll_transfers`
+    pub fn send_file(&self, path: PathBuf) -> u32 {
+        let id = new_session_id();
+        self.act(ConnectionAction::SendFile(id, path));
+        id
+    }
+    /// Take the file the peer offered with `TransferEvent::Offered`, into the downloads directory
+    pub fn accept_file(&self, id: u32) {
+        self.act(ConnectionAction::AcceptFile(id));
+    }
+    /// Refuse the file the peer offered with `TransferEvent::Offered`
+    pub fn decline_file(&self, id: u32) {
+        self.act(ConnectionAction::DeclineFile(id));
+    }
+    /// Give up on the file sent or received, the peer is told
+    pub fn cancel_file(&self, id: u32) {
+        self.act(ConnectionAction::CancelFile(id));
+    }
+    /// What happened to the files sent and received since the last call
+    pub fn poll_transfers(&self) -> Vec<TransferEvent> {
+        match self.transfers.lock() {
+            Ok(transfers) => transfers.try_iter().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+    /// Audio channels, 1 or 2, shared with the peers from the next session on.
+    /// The ongoing session keeps the ones negotiated for it
+    pub fn set_audio_channels(&mut self, channels: u8) {
+        self.preferences.audio_channels = channels;
+        self.act(ConnectionAction::SetAudioChannels(channels));
+    }
+    /// Requests received from the peer since the last call
+    pub fn poll_controls(&self) -> Vec<ControlMessage> {
+        match self.controls.lock() {
+            Ok(controls) => controls.try_iter().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+    /// Address the ScpClient listens on. Use it to advertise the client, i.e. over mDNS
+    pub fn local_addr(&self) -> SocketAddr {
+        self.sock_addr
+    }
+    /// Preferences shared with peers. Ports set to 0 are replaced with the ones picked by the OS
+    pub fn preferences(&self) -> &Preferences {
+        &self.preferences
+    }
+    /// Peers allowed or denied to request sessions, changes apply to the next request
+    pub fn peer_list(&self) -> MutexGuard<'_, PeerList> {
+        self.peer_list.lock().unwrap()
+    }
+    /// Identity shared with peers, see `crate::identity`
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+    /// Remember `public_key` as the key of the peer at `ip`, once the user compared
+    /// its fingerprint out of band. A changed key is reported until then, see `PeerTrust::Changed`
+    pub fn trust_peer(&self, ip: IpAddr, public_key: [u8; PUBLIC_KEY_LEN]) {
+        self.act(ConnectionAction::TrustPeer(ip, public_key));
     }
 }
 impl Drop for ScpClient {
     fn drop(&mut self) {
         // if poisoned then thread already panicked and doesn't exist
         if !self.tx.0.is_poisoned() {
-            *self.tx.0.lock().unwrap() = Some(ConnectionAction::Terminate);
+            self.tx
+                .0
+                .lock()
+                .unwrap()
+                .push_back(ConnectionAction::Terminate);
         }
         let _ = self;
     }
@@ -296,21 +956,69 @@ impl Iterator for EventIterator {
     }
 }
 /// Convinient builder for ScpClient with preferences
+/// A throwaway identity, in-memory known peers and an empty peer list are used unless set.
+/// The files the peers offer are declined unless the downloads directory is set
 pub struct ScpClientBuilder {
     preferences: Preferences,
+    /// Answered to probes, see `crate::probe`
+    video_modes: Vec<VideoMode>,
+    ring_timeout: Duration,
+    identity: Option<Identity>,
+    known_peers: Option<KnownPeers>,
+    peer_list: Option<PeerList>,
+    downloads: Option<PathBuf>,
 }
 
 impl ScpClientBuilder {
     pub fn builder() -> Self {
         Self {
             preferences: Preferences::default(),
+            video_modes: Vec::new(),
+            ring_timeout: DEFAULT_RING_TIMEOUT,
+            identity: None,
+            known_peers: None,
+            peer_list: None,
+            downloads: None,
         }
     }
 
     /// # Panics
-    /// May panic when the settings cannot be used: i.e. TCP port unavailable
+    /// Panics when the settings cannot be used, see `try_build`.
+    /// Kept for compatibility, prefer `try_build`
     pub fn build(self) -> ScpClient {
-        ScpClient::with_preferences(self.preferences)
+        self.try_build()
+            .unwrap_or_else(|e| panic!("Cannot build ScpClient: {e}"))
+    }
+    /// Fails when the settings cannot be used: i.e. the same port given twice or TCP port unavailable
+    pub fn try_build(self) -> Result<ScpClient, ScpError> {
+        self.preferences.validate_ports()?;
+        ScpClient::with_preferences(
+            self.preferences,
+            self.video_modes,
+            self.ring_timeout,
+            self.identity.unwrap_or_else(Identity::generate),
+            self.known_peers.unwrap_or_else(KnownPeers::in_memory),
+            self.peer_list.unwrap_or_else(PeerList::in_memory),
+            self.downloads,
+        )
+    }
+    pub fn identity(self, identity: Identity) -> Self {
+        Self {
+            identity: Some(identity),
+            ..self
+        }
+    }
+    pub fn known_peers(self, known_peers: KnownPeers) -> Self {
+        Self {
+            known_peers: Some(known_peers),
+            ..self
+        }
+    }
+    pub fn peer_list(self, peer_list: PeerList) -> Self {
+        Self {
+            peer_list: Some(peer_list),
+            ..self
+        }
     }
     pub fn video_port(self, port: u16) -> Self {
         Self {
@@ -318,6 +1026,16 @@ impl ScpClientBuilder {
                 port_in_video: port,
                 ..self.preferences
             },
+            ..self
+        }
+    }
+    pub fn screen_port(self, port: Option<u16>) -> Self {
+        Self {
+            preferences: Preferences {
+                port_in_screen: port,
+                ..self.preferences
+            },
+            ..self
         }
     }
     pub fn audio_port(self, port: u16) -> Self {
@@ -326,6 +1044,7 @@ impl ScpClientBuilder {
                 port_in_audio: port,
                 ..self.preferences
             },
+            ..self
         }
     }
     pub fn video_encoding(self, encoding: VideoEncoding) -> Self {
@@ -334,14 +1053,80 @@ impl ScpClientBuilder {
                 video_encoding: encoding,
                 ..self.preferences
             },
+            ..self
+        }
+    }
+    /// Video encodings this client takes and sends, the preferred one first
+    pub fn video_encodings(self, encodings: Vec<VideoEncoding>) -> Self {
+        Self {
+            preferences: Preferences {
+                video_encodings: encodings,
+                ..self.preferences
+            },
+            ..self
         }
     }
-    pub fn audio_encoding(self, encoding: AudioEncoding) -> Self {
+    /// Audio encodings this client takes, none leaves the calls without audio
+    pub fn audio_encodings(self, encodings: Vec<AudioEncoding>) -> Self {
         Self {
             preferences: Preferences {
-                audio_encoding: encoding,
+                audio_encodings: encodings,
                 ..self.preferences
             },
+            ..self
+        }
+    }
+    /// Highest audio bitrate in kbit/s and the channels this client takes
+    pub fn audio_quality(self, bitrate: u32, channels: u8) -> Self {
+        Self {
+            preferences: Preferences {
+                audio_bitrate: bitrate,
+                audio_channels: channels,
+                ..self.preferences
+            },
+            ..self
+        }
+    }
+    pub fn video_mode(self, mode: Option<VideoMode>) -> Self {
+        Self {
+            preferences: Preferences {
+                video_mode: mode,
+                ..self.preferences
+            },
+            ..self
+        }
+    }
+    /// Backends the video is encoded and decoded with, told to the peers
+    pub fn codec_backends(self, encoder: CodecBackend, decoder: CodecBackend) -> Self {
+        Self {
+            preferences: Preferences {
+                encoder: Some(encoder),
+                decoder: Some(decoder),
+                ..self.preferences
+            },
+            ..self
+        }
+    }
+    /// Modes the camera captures in, told to the peers probing this client
+    pub fn video_modes(self, modes: Vec<VideoMode>) -> Self {
+        Self {
+            video_modes: modes,
+            ..self
+        }
+    }
+    /// How long an incoming request rings before it's refused as not answered
+    pub fn ring_timeout(self, timeout: Duration) -> Self {
+        Self {
+            ring_timeout: timeout,
+            ..self
+        }
+    }
+    /// Where the files the peers send are received into, see `crate::file_transfer`.
+    /// None declines them
+    pub fn downloads_dir(self, dir: Option<PathBuf>) -> Self {
+        Self {
+            downloads: dir,
+            ..self
         }
     }
     pub fn port_scp(self, port: u16) -> Self {
@@ -350,15 +1135,26 @@ impl ScpClientBuilder {
                 port_scp: port,
                 ..self.preferences
             },
+            ..self
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
     use std::time::Duration;
 
-    use super::{ConnectionEvent, ScpClient, ScpClientBuilder};
+    use super::{
+        AudioCodec, AudioEncoding, CodecBackend, ConnectionEvent, EncryptionMethod,
+        NegotiatedCodecs, PacketFormat, Preferences, ScpClient, ScpClientBuilder,
+        ScpConnectionError, ScpError, SessionConfig, VideoEncoding, DEFAULT_AUDIO_BITRATE,
+    };
+    use crate::file_transfer::TransferEvent;
+    use crate::identity::{Identity, KnownPeers, PeerTrust};
+    use crate::misc;
+    use crate::peer_list::PeerEntry;
+    use crate::scp::{ClipboardText, ControlMessage, ScpCommand, ScpMessage, StillImage};
     fn prepare_two_clients() -> (ScpClient, ScpClient) {
         let client = ScpClientBuilder::builder()
             .audio_port(7001)
@@ -370,17 +1166,695 @@ mod tests {
             .build();
         (client, client2)
     }
+    /// Request a session from `client2` and accept it, the request blocks until then
+    fn connect(
+        client1: &ScpClient,
+        client2: &mut ScpClient,
+    ) -> (
+        Result<SessionConfig, ScpError>,
+        Result<SessionConfig, ScpError>,
+    ) {
+        let addr = client2.sock_addr;
+        std::thread::scope(|s| {
+            let request = s.spawn(|| client1.request_chat(addr));
+            std::thread::sleep(Duration::from_millis(300));
+            let accepted = client2.accept_incoming_connection();
+            (request.join().unwrap(), accepted)
+        })
+    }
     #[test]
     fn test_accept() {
-        let (client1, mut client2) = prepare_two_clients();
+        let (mut client1, mut client2) = prepare_two_clients();
+        client1.set_audio_channels(2);
+        client2.set_audio_channels(2);
 
         let addr = client2.sock_addr;
         std::thread::sleep(Duration::from_millis(100));
-        let config = client1.request_chat(addr);
-        std::thread::sleep(Duration::from_millis(300));
-        let config2 = client2.accept_incoming_connection();
+        let (config, config2) = connect(&client1, &mut client2);
         assert!(config.is_ok());
         assert!(config2.is_ok());
+        let (config, config2) = (config.unwrap(), config2.unwrap());
+        assert_ne!(config.session_id, 0);
+        assert_eq!(config.session_id, config2.session_id);
+        let peer = config.peer_identity.unwrap();
+        assert_eq!(peer.public_key, client2.identity().public_key());
+        assert_eq!(peer.trust, PeerTrust::New);
+        let peer2 = config2.peer_identity.unwrap();
+        assert_eq!(peer2.public_key, client1.identity().public_key());
+        assert_eq!(config.encryption, EncryptionMethod::SessionHmac);
+        assert_eq!(config.codecs.video, VideoEncoding::H264);
+        assert_eq!(config.codecs.audio, config2.codecs.audio);
+        assert_eq!(config.codecs.packet_format, PacketFormat::NEWEST);
+        assert_eq!(
+            config
+                .codecs
+                .audio
+                .map(|audio| (audio.encoding, audio.channels)),
+            Some((AudioEncoding::Opus48k, 2))
+        );
+        assert_eq!(config.endpoints.video.ip(), addr.ip());
+        assert_eq!(config.endpoints.audio.port(), 7001);
+        assert_eq!(config.endpoints.screen, None);
+    }
+    #[test]
+    fn test_audio_negotiation() {
+        let own = Preferences::default();
+        let peer = Preferences {
+            audio_encodings: vec![AudioEncoding::PcmS16],
+            audio_bitrate: 256,
+            audio_channels: 2,
+            ..Default::default()
+        };
+        let expected = Some(AudioCodec {
+            encoding: AudioEncoding::PcmS16,
+            bitrate: DEFAULT_AUDIO_BITRATE,
+            channels: 1,
+        });
+        assert_eq!(NegotiatedCodecs::negotiate(&own, &peer).audio, expected);
+        assert_eq!(NegotiatedCodecs::negotiate(&peer, &own).audio, expected);
+
+        // Peers predating the audio encodings share none
+        let old: Preferences = serde_json::from_str(
+            r#"{"video_encoding":"H264","audio_encoding":"NoIdea","port_in_video":7000,
+                "port_in_audio":7001,"port_scp":60201}"#,
+        )
+        .unwrap();
+        assert_eq!(NegotiatedCodecs::negotiate(&own, &old).audio, None);
+    }
+    #[test]
+    fn test_packet_format_negotiation() {
+        let own = Preferences::default();
+        let negotiate = |peer: &Preferences| {
+            let format = NegotiatedCodecs::negotiate(&own, peer).packet_format;
+            assert_eq!(
+                format,
+                NegotiatedCodecs::negotiate(peer, &own).packet_format
+            );
+            format
+        };
+        assert_eq!(negotiate(&own), PacketFormat::NEWEST);
+        // A newer peer comes down to ours, one that predates the formats to the legacy one
+        let newer = Preferences {
+            packet_version: u8::MAX,
+            ..Default::default()
+        };
+        assert_eq!(negotiate(&newer), PacketFormat::NEWEST);
+        let old: Preferences = serde_json::from_str(
+            r#"{"video_encoding":"H264","port_in_video":7000,"port_in_audio":7001,"port_scp":60201}"#,
+        )
+        .unwrap();
+        assert_eq!(negotiate(&old), PacketFormat::Legacy);
+        for format in [PacketFormat::Legacy, PacketFormat::Header] {
+            assert_eq!(PacketFormat::from_version(format.version()), Some(format));
+        }
+    }
+    #[test]
+    fn test_codec_backends() {
+        let own = Preferences::default();
+        let peer = Preferences {
+            encoder: Some(CodecBackend::X264),
+            decoder: Some(CodecBackend::Dav1d),
+            ..Default::default()
+        };
+        let codecs = NegotiatedCodecs::negotiate(&own, &peer);
+        assert_eq!(codecs.peer_encoder, Some(CodecBackend::X264));
+        assert_eq!(codecs.peer_decoder, Some(CodecBackend::Dav1d));
+        // Unknown to peers that predate them, and the ones of newer peers to this client
+        let old: Preferences = serde_json::from_str(
+            r#"{"video_encoding":"H264","port_in_video":7000,"port_in_audio":7001,"port_scp":60201}"#,
+        )
+        .unwrap();
+        assert_eq!(NegotiatedCodecs::negotiate(&own, &old).peer_encoder, None);
+        let newer: Preferences = serde_json::from_str(
+            r#"{"video_encoding":"H264","port_in_video":7000,"port_in_audio":7001,"port_scp":60201,
+                "encoder":"av1hw","decoder":"openh264"}"#,
+        )
+        .unwrap();
+        assert_eq!(newer.encoder, Some(CodecBackend::Unknown));
+        assert_eq!(newer.decoder, Some(CodecBackend::OpenH264));
+        for backend in [
+            CodecBackend::OpenH264,
+            CodecBackend::X264,
+            CodecBackend::Libvpx,
+            CodecBackend::Rav1e,
+            CodecBackend::Dav1d,
+            CodecBackend::Passthrough,
+        ] {
+            let name = serde_json::to_string(&backend).unwrap();
+            assert_eq!(
+                CodecBackend::from_name(name.trim_matches('"')),
+                Some(backend)
+            );
+        }
+        assert_eq!(CodecBackend::from_name("X264"), Some(CodecBackend::X264));
+        assert_eq!(CodecBackend::from_name("vaapi"), None);
+        assert_eq!(CodecBackend::from_name("unknown"), None);
+    }
+    #[test]
+    fn test_video_encoding() {
+        let h264 = Preferences::default();
+        let vp8 = Preferences {
+            video_encodings: vec![VideoEncoding::Vp8, VideoEncoding::H264],
+            ..Default::default()
+        };
+        // Sent in the one the peer prefers, as long as this client takes it too
+        assert_eq!(
+            NegotiatedCodecs::negotiate(&vp8, &vp8).video,
+            VideoEncoding::Vp8
+        );
+        assert_eq!(
+            NegotiatedCodecs::negotiate(&h264, &vp8).video,
+            VideoEncoding::H264
+        );
+        assert_eq!(
+            NegotiatedCodecs::negotiate(&vp8, &h264).video,
+            VideoEncoding::H264
+        );
+        let old: Preferences = serde_json::from_str(
+            r#"{"video_encoding":"H264","port_in_video":7000,"port_in_audio":7001,"port_scp":60201}"#,
+        )
+        .unwrap();
+        assert!(old.video_encodings.is_empty());
+        assert_eq!(
+            NegotiatedCodecs::negotiate(&vp8, &old).video,
+            VideoEncoding::H264
+        );
+        let newer: Preferences = serde_json::from_str(
+            r#"{"video_encoding":"H264","video_encodings":["H266","Vp8"],"port_in_video":7000,
+                "port_in_audio":7001,"port_scp":60201}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            newer.video_encodings,
+            [VideoEncoding::Unknown, VideoEncoding::Vp8]
+        );
+        assert_eq!(
+            NegotiatedCodecs::negotiate(&vp8, &newer).video,
+            VideoEncoding::Vp8
+        );
+        assert_eq!(VideoEncoding::from_name("H.264"), Some(VideoEncoding::H264));
+        assert_eq!(
+            VideoEncoding::from_name(&VideoEncoding::Vp8.to_string()),
+            Some(VideoEncoding::Vp8)
+        );
+        assert_eq!(VideoEncoding::from_name("AV1"), Some(VideoEncoding::Av1));
+        assert_eq!(VideoEncoding::from_name("h266"), None);
+        // The AV1 peers fall back to H.264 with the ones without it
+        let av1 = Preferences {
+            video_encodings: vec![VideoEncoding::Av1, VideoEncoding::H264],
+            ..Default::default()
+        };
+        assert_eq!(
+            NegotiatedCodecs::negotiate(&av1, &av1).video,
+            VideoEncoding::Av1
+        );
+        assert_eq!(
+            NegotiatedCodecs::negotiate(&vp8, &av1).video,
+            VideoEncoding::H264
+        );
+    }
+    #[test]
+    fn test_port_taken() {
+        let client = ScpClientBuilder::builder().port_scp(0).build();
+        let taken = client.local_addr().port();
+        let result = ScpClientBuilder::builder().port_scp(taken).try_build();
+        assert!(matches!(result, Err(ScpError::Bind { addr, .. }) if addr.port() == taken));
+    }
+    #[test]
+    fn test_port_conflicts() {
+        let result = ScpClientBuilder::builder()
+            .video_port(7100)
+            .audio_port(7100)
+            .port_scp(0)
+            .try_build();
+        assert!(matches!(
+            result,
+            Err(ScpError::PortConflict {
+                port: 7100,
+                first: "video stream",
+                second: "audio stream"
+            })
+        ));
+        let result = ScpClientBuilder::builder()
+            .screen_port(Some(7001))
+            .port_scp(0)
+            .try_build();
+        assert!(matches!(
+            result,
+            Err(ScpError::PortConflict { port: 7001, .. })
+        ));
+        // Picked by the OS, they cannot clash
+        assert!(ScpClientBuilder::builder()
+            .video_port(0)
+            .screen_port(Some(0))
+            .port_scp(0)
+            .try_build()
+            .is_ok());
+    }
+    #[test]
+    fn test_control_messages() {
+        let (client1, mut client2) = prepare_two_clients();
+        // No session yet, the request goes nowhere
+        client1.send_control(ControlMessage::RequestKeyframe);
+        std::thread::sleep(Duration::from_millis(100));
+
+        let (config, config2) = connect(&client1, &mut client2);
+        config.unwrap();
+        config2.unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        client1.send_control(ControlMessage::LowResolution(true));
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            client2.poll_controls(),
+            [ControlMessage::LowResolution(true)]
+        );
+        assert!(client1.poll_controls().is_empty());
+
+        // Back to back, within a loop of the listener, none of them is lost
+        let controls = [
+            ControlMessage::ProbeAck(1200),
+            ControlMessage::BandwidthReport {
+                packets: 20,
+                span_us: 4000,
+            },
+            ControlMessage::LowBandwidth(true),
+            ControlMessage::RequestKeyframe,
+        ];
+        for control in controls {
+            client1.send_control(control);
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(client2.poll_controls(), controls);
+
+        // Only the latest still image is of use
+        client1.send_still_image(StillImage(vec![1]));
+        std::thread::sleep(Duration::from_millis(300));
+        client1.send_still_image(StillImage(vec![2]));
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(client2.poll_still_image(), Some(StillImage(vec![2])));
+        assert!(client2.poll_controls().is_empty());
+        assert_eq!(client2.poll_still_image(), None);
+
+        let link = ClipboardText("https://example.org/notes\nsecond line".to_string());
+        client2.send_clipboard(link.clone());
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(client1.poll_clipboard(), Some(link));
+        assert!(client1.poll_controls().is_empty());
+        assert_eq!(client2.poll_clipboard(), None);
+    }
+    /// The events of the transfers until one matches, for a few seconds at most
+    fn wait_for_transfer(
+        client: &ScpClient,
+        matches: impl Fn(&TransferEvent) -> bool,
+    ) -> Option<TransferEvent> {
+        for _ in 0..50 {
+            if let Some(event) = client.poll_transfers().into_iter().find(&matches) {
+                return Some(event);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        None
+    }
+    #[test]
+    fn test_file_transfer() {
+        let dir = std::env::temp_dir().join(format!("scp-file-transfer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("notes.txt");
+        let contents = "a line\n".repeat(10_000);
+        std::fs::write(&source, &contents).unwrap();
+        let client1 = ScpClientBuilder::builder()
+            .audio_port(7001)
+            .port_scp(0)
+            .build();
+        let mut client2 = ScpClientBuilder::builder()
+            .audio_port(7001)
+            .port_scp(0)
+            .downloads_dir(Some(dir.join("downloads")))
+            .build();
+        // No session to send it in
+        let id = client1.send_file(source.clone());
+        assert!(wait_for_transfer(
+            &client1,
+            |e| matches!(e, TransferEvent::Failed { id: i, .. } if *i == id)
+        )
+        .is_some());
+
+        let (config, config2) = connect(&client1, &mut client2);
+        config.unwrap();
+        config2.unwrap();
+        let id = client1.send_file(source.clone());
+        let offered = wait_for_transfer(&client2, |e| matches!(e, TransferEvent::Offered { .. }));
+        assert_eq!(
+            offered,
+            Some(TransferEvent::Offered {
+                id,
+                name: "notes.txt".into(),
+                size: contents.len() as u64,
+            })
+        );
+        client2.accept_file(id);
+        let received =
+            wait_for_transfer(&client2, |e| matches!(e, TransferEvent::Completed { .. }));
+        let path = dir.join("downloads").join("notes.txt");
+        assert_eq!(
+            received,
+            Some(TransferEvent::Completed {
+                id,
+                path: path.clone()
+            })
+        );
+        assert_eq!(std::fs::read_to_string(path).unwrap(), contents);
+        let sent = wait_for_transfer(&client1, |e| matches!(e, TransferEvent::Completed { .. }));
+        assert_eq!(
+            sent,
+            Some(TransferEvent::Completed {
+                id,
+                path: source.clone()
+            })
+        );
+
+        let id = client1.send_file(source);
+        wait_for_transfer(&client2, |e| matches!(e, TransferEvent::Offered { .. })).unwrap();
+        client2.decline_file(id);
+        let declined = wait_for_transfer(&client1, |e| matches!(e, TransferEvent::Declined { .. }));
+        assert_eq!(declined, Some(TransferEvent::Declined { id }));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_non_blocking_session() {
+        let (client1, client2) = prepare_two_clients();
+        client1.attempt_connection(client2.local_addr());
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            client2.poll_event(),
+            Some(ConnectionEvent::ConnectionIncoming(_, Some(_)))
+        ));
+        // The requesting side rings until the peer accepts
+        assert!(matches!(
+            client1.poll_event(),
+            Some(ConnectionEvent::Ringing(..))
+        ));
+
+        client2.accept();
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            client2.poll_event(),
+            Some(ConnectionEvent::ConnectionEstablished(_))
+        ));
+        assert!(matches!(
+            client1.poll_event(),
+            Some(ConnectionEvent::ConnectionEstablished(_))
+        ));
+        // Taken already
+        assert!(client2.poll_event().is_none());
+    }
+    #[test]
+    fn test_simultaneous_requests() {
+        let (client1, client2) = prepare_two_clients();
+        client1.attempt_connection(client2.local_addr());
+        client2.attempt_connection(client1.local_addr());
+        std::thread::sleep(Duration::from_millis(500));
+        // One of the requests is answered by itself, nobody has to accept it
+        let (
+            Some(ConnectionEvent::ConnectionEstablished(config)),
+            Some(ConnectionEvent::ConnectionEstablished(config2)),
+        ) = (client1.poll_event(), client2.poll_event())
+        else {
+            panic!("Both requests should collapse into one session");
+        };
+        assert_eq!(config.session_id, config2.session_id);
+    }
+    #[test]
+    fn test_resume_session() {
+        let (client1, mut client2) = prepare_two_clients();
+        let (config, _) = connect(&client1, &mut client2);
+        let ticket = *config.unwrap().resume.unwrap();
+        client2.poll_event();
+
+        // client1 crashed and came back as another client with the same identity,
+        // the peer didn't notice
+        let restarted = ScpClientBuilder::builder()
+            .identity(client1.identity().clone())
+            .port_scp(0)
+            .build();
+        restarted.resume(ticket.clone());
+        std::thread::sleep(Duration::from_millis(300));
+        let Some(ConnectionEvent::ConnectionEstablished(resumed)) = restarted.poll_event() else {
+            panic!("The session should be resumed");
+        };
+        assert_eq!(resumed.session_id, ticket.session_id);
+        assert_eq!(
+            resumed.endpoints.video.port(),
+            client2.preferences().port_in_video
+        );
+        assert!(resumed.peer_identity.is_some());
+        // The peer goes on with the session, over the new key
+        assert!(client2.poll_event().is_none());
+        client2.send_control(ControlMessage::RequestKeyframe);
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(restarted.poll_controls(), [ControlMessage::RequestKeyframe]);
+
+        // Ended for good, there's nothing to come back to
+        client2.end_connection();
+        std::thread::sleep(Duration::from_millis(300));
+        let again = ScpClientBuilder::builder()
+            .identity(client1.identity().clone())
+            .port_scp(0)
+            .build();
+        again.resume(ticket);
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            again.poll_event(),
+            Some(ConnectionEvent::ConnectionFailed(
+                ScpConnectionError::Refused
+            ))
+        ));
+    }
+    #[test]
+    fn test_transfer() {
+        // Both devices of the user know each other's key, all of the clients share an address here
+        let ip = misc::get_local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let (identity1, device_identity) = (Identity::generate(), Identity::generate());
+        let known_peers = |identity: &Identity| {
+            let mut known = KnownPeers::in_memory();
+            known.trust(ip, &identity.public_key());
+            known
+        };
+        let client1 = ScpClientBuilder::builder()
+            .identity(identity1.clone())
+            .known_peers(known_peers(&device_identity))
+            .audio_port(7001)
+            .port_scp(0)
+            .build();
+        let mut client2 = ScpClientBuilder::builder()
+            .audio_port(7001)
+            .port_scp(0)
+            .build();
+        let device = ScpClientBuilder::builder()
+            .identity(device_identity)
+            .known_peers(known_peers(&identity1))
+            .video_port(7200)
+            .port_scp(0)
+            .build();
+        // An unknown device isn't handed anything
+        let stranger = ScpClientBuilder::builder().port_scp(0).build();
+        // Nothing to hand over yet
+        client1.transfer(device.local_addr());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(device.poll_event().is_none());
+
+        let (config, _) = connect(&client1, &mut client2);
+        let session_id = config.unwrap().session_id;
+        client1.poll_event();
+        client2.poll_event();
+        client1.transfer(stranger.local_addr());
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(stranger.poll_event().is_none());
+        assert!(client2.poll_event().is_none());
+        client1.transfer(device.local_addr());
+        std::thread::sleep(Duration::from_millis(500));
+        let Some(ConnectionEvent::ConnectionEstablished(taken)) = device.poll_event() else {
+            panic!("The device should take the session over");
+        };
+        assert_eq!(taken.session_id, session_id);
+        assert_eq!(
+            taken.endpoints.video.port(),
+            client2.preferences().port_in_video
+        );
+        // The peer sends the streams to the device from now on, and ends the session with the old one
+        let Some(ConnectionEvent::ConnectionEstablished(retargeted)) = client2.poll_event() else {
+            panic!("The peer should establish the session with the device");
+        };
+        assert_eq!(retargeted.session_id, session_id);
+        assert_eq!(retargeted.endpoints.video.port(), 7200);
+        assert!(matches!(
+            client1.poll_event(),
+            Some(ConnectionEvent::ConnectionEnd)
+        ));
+        client2.send_control(ControlMessage::RequestKeyframe);
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(device.poll_controls(), [ControlMessage::RequestKeyframe]);
+        assert!(client1.poll_controls().is_empty());
+
+        // The old device has nothing left to hand over
+        client1.transfer(device.local_addr());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(device.poll_event().is_none());
+    }
+    #[test]
+    fn test_ring_timeout() {
+        let client1 = ScpClientBuilder::builder().port_scp(0).build();
+        let client2 = ScpClientBuilder::builder()
+            .port_scp(0)
+            .ring_timeout(Duration::from_millis(300))
+            .build();
+        // Nobody answers, both sides give up
+        assert!(matches!(
+            client1.request_chat(client2.local_addr()),
+            Err(ScpError::Connection(ScpConnectionError::NotAnswered))
+        ));
+        assert!(matches!(
+            client2.poll_event(),
+            Some(ConnectionEvent::ConnectionFailed(
+                ScpConnectionError::NotAnswered
+            ))
+        ));
+
+        // Free again for the next call
+        client1.attempt_connection(client2.local_addr());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(
+            client2.poll_event(),
+            Some(ConnectionEvent::ConnectionIncoming(..))
+        ));
+    }
+    #[test]
+    fn test_trust_changed_key() {
+        // client2 remembers another key for the address, as if client1 was impersonated before
+        let ip = misc::get_local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut known = KnownPeers::in_memory();
+        known.trust(ip, &Identity::generate().public_key());
+        let client1 = ScpClientBuilder::builder().port_scp(0).build();
+        let client2 = ScpClientBuilder::builder()
+            .known_peers(known)
+            .port_scp(0)
+            .build();
+        let key = client1.identity().public_key();
+        let incoming_trust = || {
+            client1.attempt_connection(client2.local_addr());
+            std::thread::sleep(Duration::from_millis(300));
+            let Some(ConnectionEvent::ConnectionIncoming(_, Some(identity))) = client2.poll_event()
+            else {
+                panic!("The peer should share its identity");
+            };
+            assert_eq!(identity.public_key, key);
+            // The caller sees the identity of the peer while it rings
+            let Some(ConnectionEvent::Ringing(_, Some(peer))) = client1.poll_event() else {
+                panic!("The caller should see the identity of the peer");
+            };
+            assert_eq!(peer.public_key, client2.identity().public_key());
+            client2.refuse_incoming_connection();
+            std::thread::sleep(Duration::from_millis(300));
+            client1.poll_event();
+            identity.trust
+        };
+        assert_eq!(incoming_trust(), PeerTrust::Changed);
+        // Reported until the user trusts it, after comparing the fingerprints
+        assert_eq!(incoming_trust(), PeerTrust::Changed);
+        client2.trust_peer(ip, key);
+        assert_eq!(incoming_trust(), PeerTrust::Known);
+    }
+    #[test]
+    fn test_cancel() {
+        let client1 = ScpClientBuilder::builder().port_scp(0).build();
+        let client2 = ScpClientBuilder::builder().port_scp(0).build();
+        client1.attempt_connection(client2.local_addr());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(
+            client1.poll_event(),
+            Some(ConnectionEvent::Ringing(..))
+        ));
+        assert!(matches!(
+            client2.poll_event(),
+            Some(ConnectionEvent::ConnectionIncoming(..))
+        ));
+        // The peer stops ringing too
+        client1.cancel();
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(
+            client1.poll_event(),
+            Some(ConnectionEvent::ConnectionFailed(
+                ScpConnectionError::Cancelled
+            ))
+        ));
+        assert!(matches!(
+            client2.poll_event(),
+            Some(ConnectionEvent::ConnectionEnd)
+        ));
+        // Too late to accept it
+        client2.accept();
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(client1.poll_event().is_none());
+    }
+    #[test]
+    fn test_denied_peers_refused() {
+        let (client1, client2) = prepare_two_clients();
+        // Denied by the IP, the connection is dropped right away
+        client2
+            .peer_list()
+            .deny(PeerEntry::Ip(client1.local_addr().ip()));
+        client1.attempt_connection(client2.local_addr());
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            client1.poll_event(),
+            Some(ConnectionEvent::ConnectionFailed(_))
+        ));
+        assert!(client2.poll_event().is_none());
+
+        // Denied by the identity, refused once it's proven
+        client2
+            .peer_list()
+            .remove(&PeerEntry::Ip(client1.local_addr().ip()));
+        let fingerprint = client1.identity().fingerprint();
+        client2.peer_list().deny(PeerEntry::identity(&fingerprint));
+        client1.attempt_connection(client2.local_addr());
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            client1.poll_event(),
+            Some(ConnectionEvent::ConnectionFailed(_))
+        ));
+        assert!(client2.poll_event().is_none());
+    }
+    #[test]
+    fn test_unsupported_commands() {
+        use std::io::Write;
+
+        let (client1, mut client2) = prepare_two_clients();
+        // Any host may send them, before there's a key to open them with
+        let mut stream = std::net::TcpStream::connect(client2.local_addr()).unwrap();
+        for command in [
+            ScpCommand::OwnKeyRequired,
+            ScpCommand::ReqGenerateKey,
+            ScpCommand::AckGenerateKey,
+        ] {
+            stream
+                .write_all(&ScpMessage::new(command, b"").as_bytes())
+                .unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(client2.poll_event().is_none());
+        // The listener is still there
+        let (config, config2) = connect(&client1, &mut client2);
+        config.unwrap();
+        config2.unwrap();
+    }
+    #[test]
+    fn test_ephemeral_scp_port() {
+        let (client1, client2) = prepare_two_clients();
+        assert_ne!(client1.preferences().port_scp, 0);
+        assert_eq!(client1.preferences().port_scp, client1.local_addr().port());
+        assert_ne!(client1.local_addr(), client2.local_addr());
     }
     #[test]
     fn test_event_iterator() {
@@ -394,9 +1868,9 @@ mod tests {
                 }
             }
         });
-        client1.request_chat(client2.sock_addr).unwrap();
-        std::thread::sleep(Duration::from_millis(100));
-        client2.accept_incoming_connection().unwrap();
+        let (config, config2) = connect(&client1, &mut client2);
+        config.unwrap();
+        config2.unwrap();
         std::thread::sleep(Duration::from_millis(1000));
 
         client2.end_connection();
@@ -415,9 +1889,9 @@ mod tests {
                 }
             }
         });
-        client1.request_chat(client2.sock_addr).unwrap();
-        std::thread::sleep(Duration::from_millis(100));
-        client2.accept_incoming_connection().unwrap();
+        let (config, config2) = connect(&client1, &mut client2);
+        config.unwrap();
+        config2.unwrap();
         std::thread::sleep(Duration::from_millis(100));
         client2.end_connection();
         // No events for 10 secs
@@ -0,0 +1,186 @@
+//! A TCP connection to a peer, carrying any number of SCP messages in both directions.
+//! A session keeps a single one open, instead of connecting anew for every message.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::link_local::resolve_scope;
+use crate::scp::{ScpMessage, MAX_MESSAGE_LEN};
+
+/// Bytes read from the stream in one call at most, so a flood doesn't hold up the listener
+const READ_LIMIT: usize = 256 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct ScpConnection {
+    stream: TcpStream,
+    /// Remote address of the stream. Not the peer's listener when the peer connected to us
+    pub addr: SocketAddr,
+    /// Received bytes not making up a whole message yet
+    buf: Vec<u8>,
+    /// Bytes of `buf` already looked through for the end of a message
+    scanned: usize,
+    closed: bool,
+}
+
+impl ScpConnection {
+    /// Connect to the peer at `addr`, on the interface of a link-local one, see `crate::link_local`
+    pub fn connect(addr: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let addr = resolve_scope(addr)?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        Self::new(stream, timeout)
+    }
+    /// Wrap an accepted stream. Writes give up after `timeout`
+    pub fn new(stream: TcpStream, timeout: Duration) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Self {
+            addr: stream.peer_addr()?,
+            stream,
+            buf: Vec::new(),
+            scanned: 0,
+            closed: false,
+        })
+    }
+    /// Fails for messages over `MAX_MESSAGE_LEN`, the peer would drop the connection
+    pub fn send(&mut self, msg: &ScpMessage) -> io::Result<()> {
+        let bytes = msg.as_bytes();
+        if bytes.len() > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes is over the largest message", bytes.len()),
+            ));
+        }
+        // Reads never block, but a message is written either whole or not at all
+        self.stream.set_nonblocking(false)?;
+        let written = self.stream.write_all(&bytes);
+        self.stream.set_nonblocking(true)?;
+        written
+    }
+    /// Messages received since the last call, out of `READ_LIMIT` bytes at most, the rest
+    /// is left for the next one. Messages that cannot be parsed are dropped.
+    /// The connection is closed once more than `MAX_MESSAGE_LEN` bytes don't make up a message
+    pub fn receive(&mut self) -> Vec<ScpMessage> {
+        let mut chunk = [0; 4096];
+        let mut read = 0;
+        while !self.closed && read < READ_LIMIT {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.closed = true,
+                Ok(size) => {
+                    self.buf.extend_from_slice(&chunk[..size]);
+                    read += size;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => self.closed = true,
+            }
+        }
+        let mut messages = Vec::new();
+        while let Some(len) = ScpMessage::frame_len_from(&self.buf, self.scanned) {
+            match ScpMessage::deserialize(&self.buf[..len]) {
+                Ok(msg) => messages.push(msg),
+                Err(e) => warn!("Invalid message from {}: {e}", self.addr),
+            }
+            self.buf.drain(..len);
+            self.scanned = 0;
+        }
+        self.scanned = ScpMessage::scanned_len(self.buf.len());
+        if self.buf.len() > MAX_MESSAGE_LEN {
+            warn!(
+                "{} sent {} bytes that aren't a message, closing the connection",
+                self.addr,
+                self.buf.len()
+            );
+            self.buf = Vec::new();
+            self.close();
+        }
+        messages
+    }
+    pub fn close(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.closed = true;
+    }
+    /// Closed by either side
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use std::io::Write;
+
+    use super::{ScpConnection, READ_LIMIT};
+    use crate::scp::{ScpCommand, ScpMessage, MAX_MESSAGE_LEN};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_messages_over_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = ScpConnection::connect(listener.local_addr().unwrap(), TIMEOUT).unwrap();
+        let mut server = ScpConnection::new(listener.accept().unwrap().0, TIMEOUT).unwrap();
+
+        client
+            .send(&ScpMessage::new(ScpCommand::Start, &[1, 2]))
+            .unwrap();
+        client
+            .send(&ScpMessage::new(ScpCommand::Ready, b""))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let commands: Vec<_> = server.receive().iter().map(|m| m.command).collect();
+        assert_eq!(commands, [ScpCommand::Start, ScpCommand::Ready]);
+
+        // Replies go back over the same connection
+        server.send(&ScpMessage::new(ScpCommand::End, b"")).unwrap();
+        server.close();
+        std::thread::sleep(Duration::from_millis(50));
+        let commands: Vec<_> = client.receive().iter().map(|m| m.command).collect();
+        assert_eq!(commands, [ScpCommand::End]);
+        assert!(client.is_closed());
+    }
+    #[test]
+    fn test_message_size_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = ScpConnection::connect(listener.local_addr().unwrap(), TIMEOUT).unwrap();
+        let mut server = ScpConnection::new(listener.accept().unwrap().0, TIMEOUT).unwrap();
+
+        let too_large = ScpMessage::new(ScpCommand::SimpleMessage, &vec![0; MAX_MESSAGE_LEN]);
+        assert!(client.send(&too_large).is_err());
+        // A message split over the calls still comes through
+        let msg = ScpMessage::new(ScpCommand::SimpleMessage, &vec![7; 3 * READ_LIMIT / 2]);
+        client.send(&msg).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..20 {
+            std::thread::sleep(Duration::from_millis(10));
+            received.extend(server.receive());
+        }
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].body, msg.body);
+
+        // Bytes that never end a message close the connection, a bounded amount at a time
+        let mut stream = client.stream.try_clone().unwrap();
+        stream.set_nonblocking(false).unwrap();
+        let flood = std::thread::spawn(move || {
+            let junk = vec![b'x'; 64 * 1024];
+            while stream.write_all(&junk).is_ok() {}
+        });
+        for _ in 0..200 {
+            if server.is_closed() {
+                break;
+            }
+            assert!(server.receive().is_empty());
+            assert!(server.buf.len() <= MAX_MESSAGE_LEN);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(server.is_closed());
+        drop(server);
+        flood.join().unwrap();
+    }
+}
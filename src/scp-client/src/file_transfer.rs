@@ -0,0 +1,757 @@
+//! Files sent to the peer during a session, over the SCP connection of the session.
+//! The sender offers a file with its name and size, and the peer's user accepts or declines it.
+//! Once accepted, the file goes in `CHUNK_SIZE` chunks, a few every turn of the event loop,
+//! so the other messages of the session still get through.
+//!
+//! The receiver writes the file into `<name>.<size>.part` in the downloads directory and renames
+//! it once all of it came, confirming it to the sender. Accepting names the offset to start from,
+//! so a file offered again, i.e. after the session was lost, picks up where its part left off.
+//! A chunk coming past a gap asks for the rest again from where it went wrong.
+//!
+//! Goes in the body of `ScpCommand::SimpleMessage`, so the peers that don't know it drop it.
+//! Body: <SUBTYPE(8bits)><KIND(8bits)><ID(32bits LE)><...>.
+//! `Offer` carries <SIZE(64bits LE)><NAME(UTF-8)>, `Accept` <OFFSET(64bits LE)>,
+//! `Chunk` <OFFSET(64bits LE)><DATA>. The data has its newlines escaped, so it never ends
+//! the message early, see `ScpMessage::frame_len`. `Decline` and `Cancel` carry nothing
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::scp::{escape, unescape};
+
+/// Bytes of a file sent in one message
+pub const CHUNK_SIZE: usize = 16 * 1024;
+/// Chunks sent every turn of the event loop at most, of all the files
+const CHUNKS_PER_TURN: usize = 4;
+/// How often the progress of a transfer is told at most
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// Bytes of the longest file name taken
+const MAX_NAME_LEN: usize = 255;
+
+/// What goes on with a file between the peers, see the module docs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FileMessage {
+    /// The sender offers the file
+    Offer {
+        id: u32,
+        size: u64,
+        name: String,
+    },
+    /// The receiver takes the file from `offset` on, or confirms it once `offset` is the size
+    Accept {
+        id: u32,
+        offset: u64,
+    },
+    /// The receiver doesn't want the file
+    Decline {
+        id: u32,
+    },
+    Chunk {
+        id: u32,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Either side gives up on the file
+    Cancel {
+        id: u32,
+    },
+}
+
+impl FileMessage {
+    const SUBTYPE: u8 = 9;
+    const OFFER: u8 = 0;
+    const ACCEPT: u8 = 1;
+    const DECLINE: u8 = 2;
+    const CHUNK: u8 = 3;
+    const CANCEL: u8 = 4;
+
+    pub fn id(&self) -> u32 {
+        match *self {
+            FileMessage::Offer { id, .. }
+            | FileMessage::Accept { id, .. }
+            | FileMessage::Decline { id }
+            | FileMessage::Chunk { id, .. }
+            | FileMessage::Cancel { id } => id,
+        }
+    }
+    pub fn to_body(&self) -> Vec<u8> {
+        let header = |kind: u8| [&[Self::SUBTYPE, kind][..], &self.id().to_le_bytes()].concat();
+        match self {
+            FileMessage::Offer { size, name, .. } => [
+                header(Self::OFFER),
+                size.to_le_bytes().to_vec(),
+                name.as_bytes().to_vec(),
+            ]
+            .concat(),
+            FileMessage::Accept { offset, .. } => {
+                [header(Self::ACCEPT), offset.to_le_bytes().to_vec()].concat()
+            }
+            FileMessage::Decline { .. } => header(Self::DECLINE),
+            FileMessage::Chunk { offset, data, .. } => [
+                header(Self::CHUNK),
+                offset.to_le_bytes().to_vec(),
+                escape(data),
+            ]
+            .concat(),
+            FileMessage::Cancel { .. } => header(Self::CANCEL),
+        }
+    }
+    /// None if the body is another message, or it's cut short
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        let ([subtype, kind], rest) = body.split_first_chunk::<2>()?;
+        if *subtype != Self::SUBTYPE {
+            return None;
+        }
+        let (id, argument) = rest.split_first_chunk::<4>()?;
+        let id = u32::from_le_bytes(*id);
+        let number = || argument.first_chunk::<8>().map(|n| u64::from_le_bytes(*n));
+        match *kind {
+            Self::OFFER => Some(FileMessage::Offer {
+                id,
+                size: number()?,
+                name: String::from_utf8(argument[8..].to_vec()).ok()?,
+            }),
+            Self::ACCEPT => Some(FileMessage::Accept {
+                id,
+                offset: number()?,
+            }),
+            Self::DECLINE => Some(FileMessage::Decline { id }),
+            Self::CHUNK => Some(FileMessage::Chunk {
+                id,
+                offset: number()?,
+                data: unescape(&argument[8..])?,
+            }),
+            Self::CANCEL => Some(FileMessage::Cancel { id }),
+            _ => None,
+        }
+    }
+}
+
+/// What happened to the files sent and received, see `ScpClient::poll_transfers`.
+/// The IDs of the files sent are the ones `ScpClient::send_file` returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    /// The peer offers a file, to accept or decline with its ID
+    Offered { id: u32, name: String, size: u64 },
+    /// `done` bytes of the file went through so far, out of `size`
+    Progress { id: u32, done: u64, size: u64 },
+    /// The whole file went through. Saved at `path` when received, read from there when sent
+    Completed { id: u32, path: PathBuf },
+    /// The peer declined the file offered
+    Declined { id: u32 },
+    /// Given up on by either side, or the session ended. What came of a received file is kept,
+    /// and picked up from when it's offered again
+    Cancelled { id: u32 },
+    /// The file cannot be read or written
+    Failed { id: u32, error: String },
+}
+
+/// A file offered to the peer
+/// * `offset` - where the next chunk starts, None until the peer accepts the file
+#[derive(Debug)]
+struct Outgoing {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    offset: Option<u64>,
+    reported: Instant,
+}
+
+/// A file the peer offers
+/// * `file` - the part written into, None until the user accepts the file
+/// * `rewound` - the rest was asked for again after a gap, the chunks until then are dropped
+#[derive(Debug)]
+struct Incoming {
+    name: String,
+    size: u64,
+    file: Option<File>,
+    received: u64,
+    rewound: bool,
+    reported: Instant,
+}
+
+/// The files sent and received in the session. Returns the messages to send to the peer,
+/// and keeps what happened for `take_events`
+#[derive(Debug, Default)]
+pub(crate) struct FileTransfers {
+    /// Where the files are received into. The files offered are declined without one
+    pub downloads: Option<PathBuf>,
+    outgoing: HashMap<u32, Outgoing>,
+    incoming: HashMap<u32, Incoming>,
+    events: Vec<TransferEvent>,
+}
+
+impl FileTransfers {
+    /// What happened since the last call
+    pub fn take_events(&mut self) -> Vec<TransferEvent> {
+        std::mem::take(&mut self.events)
+    }
+    /// The file is offered to the peer, or sent
+    pub fn is_sending(&self, id: u32) -> bool {
+        self.outgoing.contains_key(&id)
+    }
+    /// Offer the file at `path` to the peer
+    pub fn offer(&mut self, id: u32, path: &Path) -> Option<FileMessage> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(sanitize_name);
+        let opened = File::open(path).and_then(|file| {
+            let metadata = file.metadata()?;
+            match metadata.is_file() {
+                true => Ok((file, metadata.len())),
+                false => Err(io::Error::other("Not a file")),
+            }
+        });
+        let (file, size, name) = match (opened, name) {
+            (Ok((file, size)), Some(name)) => (file, size, name),
+            (Err(e), _) => return self.fail(id, &e),
+            (Ok(_), None) => return self.fail(id, &io::Error::other("The name cannot be sent")),
+        };
+        info!("Offering {name} ({size} bytes) to the peer");
+        self.outgoing.insert(
+            id,
+            Outgoing {
+                path: path.to_path_buf(),
+                file,
+                size,
+                offset: None,
+                reported: Instant::now(),
+            },
+        );
+        Some(FileMessage::Offer { id, size, name })
+    }
+    /// Take the file the peer offers, from where its part left off if there's one
+    pub fn accept(&mut self, id: u32) -> Option<FileMessage> {
+        let (Some(incoming), Some(downloads)) = (self.incoming.get_mut(&id), &self.downloads)
+        else {
+            warn!("No file {id} offered to accept");
+            return None;
+        };
+        if incoming.file.is_some() {
+            return None;
+        }
+        let part = part_path(downloads, &incoming.name, incoming.size);
+        let opened = fs::create_dir_all(downloads).and_then(|()| {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&part)?;
+            let mut received = file.metadata()?.len();
+            // Not a part of this file after all
+            if received > incoming.size {
+                file.set_len(0)?;
+                received = 0;
+            }
+            file.seek(SeekFrom::Start(received))?;
+            Ok((file, received))
+        });
+        match opened {
+            // i.e. an empty file, or one whose part came whole before the session was lost
+            Ok((file, received)) if received == incoming.size => {
+                incoming.file = Some(file);
+                self.complete(id)
+            }
+            Ok((file, received)) => {
+                info!("Receiving {} from byte {received} on", incoming.name);
+                incoming.file = Some(file);
+                incoming.received = received;
+                Some(FileMessage::Accept {
+                    id,
+                    offset: received,
+                })
+            }
+            Err(e) => self.fail(id, &e),
+        }
+    }
+    /// Refuse the file the peer offers
+    pub fn decline(&mut self, id: u32) -> Option<FileMessage> {
+        self.incoming
+            .remove(&id)
+            .map(|_| FileMessage::Decline { id })
+    }
+    /// Give up on the file, sent or received
+    pub fn cancel(&mut self, id: u32) -> Option<FileMessage> {
+        let known = self.outgoing.remove(&id).is_some() || self.incoming.remove(&id).is_some();
+        if !known {
+            return None;
+        }
+        self.events.push(TransferEvent::Cancelled { id });
+        Some(FileMessage::Cancel { id })
+    }
+    /// Give up on the file that cannot be read, written or sent. Returns the message telling
+    /// the peer, if it knows the file
+    pub fn fail(&mut self, id: u32, error: &io::Error) -> Option<FileMessage> {
+        warn!("File transfer {id} failed: {error}");
+        self.events.push(TransferEvent::Failed {
+            id,
+            error: error.to_string(),
+        });
+        let known = self.outgoing.remove(&id).is_some() || self.incoming.remove(&id).is_some();
+        known.then_some(FileMessage::Cancel { id })
+    }
+    /// Forget every file as the session is over, the parts are kept
+    pub fn abort(&mut self) {
+        let ids = self.outgoing.drain().map(|(id, _)| id);
+        let ids: Vec<_> = ids.chain(self.incoming.drain().map(|(id, _)| id)).collect();
+        self.events
+            .extend(ids.into_iter().map(|id| TransferEvent::Cancelled { id }));
+    }
+    /// Take what the peer sent. Returns the answer, if any
+    pub fn on_message(&mut self, msg: FileMessage) -> Option<FileMessage> {
+        match msg {
+            FileMessage::Offer { id, size, name } => self.on_offer(id, size, &name),
+            FileMessage::Accept { id, offset } => self.on_accept(id, offset),
+            FileMessage::Decline { id } => {
+                self.outgoing.remove(&id)?;
+                info!("The peer declined file {id}");
+                self.events.push(TransferEvent::Declined { id });
+                None
+            }
+            FileMessage::Chunk { id, offset, data } => self.on_chunk(id, offset, &data),
+            FileMessage::Cancel { id } => {
+                let known =
+                    self.outgoing.remove(&id).is_some() || self.incoming.remove(&id).is_some();
+                if known {
+                    info!("The peer gave up on file {id}");
+                    self.events.push(TransferEvent::Cancelled { id });
+                }
+                None
+            }
+        }
+    }
+    fn on_offer(&mut self, id: u32, size: u64, name: &str) -> Option<FileMessage> {
+        let taken = self.outgoing.contains_key(&id) || self.incoming.contains_key(&id);
+        let Some(name) = sanitize_name(name).filter(|_| self.downloads.is_some() && !taken) else {
+            warn!("Declined file {id} of the peer, there's nowhere to put it");
+            return Some(FileMessage::Decline { id });
+        };
+        info!("The peer offers {name} ({size} bytes)");
+        self.events.push(TransferEvent::Offered {
+            id,
+            name: name.clone(),
+            size,
+        });
+        self.incoming.insert(
+            id,
+            Incoming {
+                name,
+                size,
+                file: None,
+                received: 0,
+                rewound: false,
+                reported: Instant::now(),
+            },
+        );
+        None
+    }
+    fn on_accept(&mut self, id: u32, offset: u64) -> Option<FileMessage> {
+        let outgoing = self.outgoing.get_mut(&id)?;
+        if offset == outgoing.size {
+            let outgoing = self.outgoing.remove(&id)?;
+            info!("Sent {}", outgoing.path.display());
+            self.events.push(TransferEvent::Completed {
+                id,
+                path: outgoing.path,
+            });
+            return None;
+        }
+        if offset > outgoing.size {
+            return self.fail(id, &io::Error::other("The peer took more than the file"));
+        }
+        if let Err(e) = outgoing.file.seek(SeekFrom::Start(offset)) {
+            return self.fail(id, &e);
+        }
+        outgoing.offset = Some(offset);
+        None
+    }
+    fn on_chunk(&mut self, id: u32, offset: u64, data: &[u8]) -> Option<FileMessage> {
+        let incoming = self.incoming.get_mut(&id)?;
+        let file = incoming.file.as_mut()?;
+        if offset != incoming.received {
+            // Asked once, the chunks still on the way are dropped until the rest comes again
+            if offset < incoming.received || incoming.rewound {
+                return None;
+            }
+            incoming.rewound = true;
+            return Some(FileMessage::Accept {
+                id,
+                offset: incoming.received,
+            });
+        }
+        incoming.rewound = false;
+        let received = offset + data.len() as u64;
+        if received > incoming.size {
+            return self.fail(id, &io::Error::other("The peer sent more than the file"));
+        }
+        if let Err(e) = file.write_all(data) {
+            return self.fail(id, &e);
+        }
+        incoming.received = received;
+        if received < incoming.size {
+            report(
+                &mut self.events,
+                id,
+                received,
+                incoming.size,
+                &mut incoming.reported,
+            );
+            return None;
+        }
+        self.complete(id)
+    }
+    /// Keep the file that came whole and confirm it to the peer
+    fn complete(&mut self, id: u32) -> Option<FileMessage> {
+        let incoming = self.incoming.remove(&id)?;
+        match self.finish(&incoming) {
+            Ok(path) => {
+                info!("Received {}", path.display());
+                self.events.push(TransferEvent::Completed { id, path });
+                Some(FileMessage::Accept {
+                    id,
+                    offset: incoming.size,
+                })
+            }
+            Err(e) => {
+                warn!("File transfer {id} failed: {e}");
+                self.events.push(TransferEvent::Failed {
+                    id,
+                    error: e.to_string(),
+                });
+                Some(FileMessage::Cancel { id })
+            }
+        }
+    }
+    /// Move the whole part to where it's kept, next to the files of the same name
+    fn finish(&self, incoming: &Incoming) -> io::Result<PathBuf> {
+        let downloads = self.downloads.as_deref().ok_or(io::ErrorKind::NotFound)?;
+        if let Some(ref file) = incoming.file {
+            file.sync_all()?;
+        }
+        let path = unused_path(downloads, &incoming.name);
+        fs::rename(part_path(downloads, &incoming.name, incoming.size), &path)?;
+        Ok(path)
+    }
+    /// The chunks to send this turn, of the files the peer accepted
+    pub fn next_chunks(&mut self) -> Vec<FileMessage> {
+        let mut chunks = Vec::new();
+        let mut failed = Vec::new();
+        for (&id, outgoing) in &mut self.outgoing {
+            let Some(ref mut offset) = outgoing.offset else {
+                continue;
+            };
+            let sent = chunks.len();
+            while chunks.len() < CHUNKS_PER_TURN && *offset < outgoing.size {
+                let len = (outgoing.size - *offset).min(CHUNK_SIZE as u64) as usize;
+                let mut data = vec![0; len];
+                if let Err(e) = outgoing.file.read_exact(&mut data) {
+                    failed.push((id, e));
+                    break;
+                }
+                chunks.push(FileMessage::Chunk {
+                    id,
+                    offset: *offset,
+                    data,
+                });
+                *offset += len as u64;
+            }
+            if chunks.len() == sent {
+                continue;
+            }
+            report(
+                &mut self.events,
+                id,
+                *offset,
+                outgoing.size,
+                &mut outgoing.reported,
+            );
+        }
+        for (id, e) in failed {
+            chunks.retain(|chunk| chunk.id() != id);
+            chunks.extend(self.fail(id, &e));
+        }
+        chunks
+    }
+}
+
+/// Tell the progress of a transfer, unless it was told a moment ago
+fn report(events: &mut Vec<TransferEvent>, id: u32, done: u64, size: u64, reported: &mut Instant) {
+    if reported.elapsed() < PROGRESS_INTERVAL {
+        return;
+    }
+    *reported = Instant::now();
+    events.push(TransferEvent::Progress { id, done, size });
+}
+
+/// The name of a file, safe to put into the downloads directory.
+/// Only the last component is kept, without control characters. None if nothing usable is left
+fn sanitize_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    match name {
+        "" | "." | ".." => None,
+        name if name.len() > MAX_NAME_LEN => None,
+        name => Some(name.to_string()),
+    }
+}
+
+/// Where the file is written into until all of it comes
+fn part_path(downloads: &Path, name: &str, size: u64) -> PathBuf {
+    downloads.join(format!("{name}.{size}.part"))
+}
+
+/// `name` in `downloads`, or `name (1)`, `name (2)`... if there's a file of that name already
+fn unused_path(downloads: &Path, name: &str) -> PathBuf {
+    let path = downloads.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name.extension().map(|e| e.to_string_lossy());
+    let mut n = 1;
+    loop {
+        let path = downloads.join(match extension {
+            Some(ref extension) => format!("{stem} ({n}).{extension}"),
+            None => format!("{stem} ({n})"),
+        });
+        if !path.exists() {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{
+        sanitize_name, unused_path, FileMessage, FileTransfers, TransferEvent, CHUNK_SIZE,
+    };
+    use crate::scp::{ControlMessage, ScpCommand, ScpMessage, StillImage};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("scp-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+    /// A file with newlines and escapes all over it, over a few chunks
+    fn contents() -> Vec<u8> {
+        (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect()
+    }
+    /// Pass the messages between the sides until neither has anything to send
+    fn run(sender: &mut FileTransfers, receiver: &mut FileTransfers, first: Option<FileMessage>) {
+        let mut to_receiver: Vec<_> = first.into_iter().collect();
+        for _ in 0..100 {
+            to_receiver.extend(sender.next_chunks());
+            if to_receiver.is_empty() {
+                return;
+            }
+            let replies: Vec<_> = to_receiver
+                .drain(..)
+                .filter_map(|msg| receiver.on_message(msg))
+                .collect();
+            for reply in replies {
+                to_receiver.extend(sender.on_message(reply));
+            }
+        }
+        panic!("The transfer didn't settle");
+    }
+    fn completed(events: &[TransferEvent]) -> Option<PathBuf> {
+        events.iter().find_map(|event| match event {
+            TransferEvent::Completed { path, .. } => Some(path.clone()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_file_message_roundtrip() {
+        let data = vec![b'\n', 0x1B, b'1', b'\n', 0, 0xFF];
+        for msg in [
+            FileMessage::Offer {
+                id: 7,
+                size: 1 << 40,
+                name: "notes.txt".into(),
+            },
+            FileMessage::Accept { id: 7, offset: 42 },
+            FileMessage::Decline { id: 7 },
+            FileMessage::Chunk {
+                id: 7,
+                offset: 16384,
+                data: data.clone(),
+            },
+            FileMessage::Cancel { id: 7 },
+        ] {
+            let bytes = ScpMessage::new(ScpCommand::SimpleMessage, &msg.to_body()).as_bytes();
+            // The newlines of the data don't end the message early
+            assert_eq!(ScpMessage::frame_len(&bytes), Some(bytes.len()));
+            let body = ScpMessage::deserialize(&bytes).unwrap().body;
+            assert_eq!(FileMessage::from_body(&body), Some(msg));
+            // Not mistaken for the other messages
+            assert_eq!(ControlMessage::from_body(&body), None);
+            assert_eq!(StillImage::from_body(&body), None);
+        }
+        assert_eq!(FileMessage::from_body(&[9, 0, 7, 0, 0, 0, 1]), None);
+        assert_eq!(
+            FileMessage::from_body(&[9, 3, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1B]),
+            None
+        );
+        assert_eq!(FileMessage::from_body(&[9, 5, 7, 0, 0, 0]), None);
+        assert_eq!(FileMessage::from_body(&[8, 0xFF, 0xD8]), None);
+    }
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("notes.txt"), Some("notes.txt".into()));
+        assert_eq!(
+            sanitize_name("../../.ssh/id_ed25519"),
+            Some("id_ed25519".into())
+        );
+        assert_eq!(sanitize_name("C:\\Users\\a\\b.png"), Some("b.png".into()));
+        assert_eq!(
+            sanitize_name("evil\n1234564321\n.txt"),
+            Some("evil1234564321.txt".into())
+        );
+        assert_eq!(sanitize_name(".."), None);
+        assert_eq!(sanitize_name("dir/"), None);
+        assert_eq!(sanitize_name(&"a".repeat(300)), None);
+    }
+    #[test]
+    fn test_unused_path() {
+        let dir = temp_dir("unused-path");
+        assert_eq!(unused_path(&dir, "a.txt"), dir.join("a.txt"));
+        fs::write(dir.join("a.txt"), b"").unwrap();
+        fs::write(dir.join("a (1).txt"), b"").unwrap();
+        assert_eq!(unused_path(&dir, "a.txt"), dir.join("a (2).txt"));
+        fs::write(dir.join("README"), b"").unwrap();
+        assert_eq!(unused_path(&dir, "README"), dir.join("README (1)"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_transfer() {
+        let dir = temp_dir("transfer");
+        let source = dir.join("source.bin");
+        fs::write(&source, contents()).unwrap();
+        let mut sender = FileTransfers::default();
+        let mut receiver = FileTransfers {
+            downloads: Some(dir.join("downloads")),
+            ..Default::default()
+        };
+
+        let offer = sender.offer(1, &source).unwrap();
+        assert_eq!(receiver.on_message(offer), None);
+        assert_eq!(
+            receiver.take_events(),
+            [TransferEvent::Offered {
+                id: 1,
+                name: "source.bin".into(),
+                size: contents().len() as u64,
+            }]
+        );
+        // Nothing goes until it's accepted
+        assert!(sender.next_chunks().is_empty());
+        let accept = receiver.accept(1);
+        run(&mut sender, &mut receiver, None);
+        assert!(sender.take_events().is_empty());
+        let accept = sender.on_message(accept.unwrap());
+        run(&mut sender, &mut receiver, accept);
+
+        let path = completed(&receiver.take_events()).unwrap();
+        assert_eq!(path, dir.join("downloads").join("source.bin"));
+        assert_eq!(fs::read(&path).unwrap(), contents());
+        assert_eq!(completed(&sender.take_events()), Some(source.clone()));
+
+        // The same name again goes next to it
+        let offer = sender.offer(2, &source).unwrap();
+        receiver.on_message(offer);
+        let accept = sender.on_message(receiver.accept(2).unwrap());
+        run(&mut sender, &mut receiver, accept);
+        let path = completed(&receiver.take_events()).unwrap();
+        assert_eq!(path, dir.join("downloads").join("source (1).bin"));
+        assert_eq!(completed(&sender.take_events()), Some(source.clone()));
+
+        // Declined
+        let offer = sender.offer(3, &source).unwrap();
+        receiver.on_message(offer);
+        let decline = receiver.decline(3).unwrap();
+        assert_eq!(sender.on_message(decline), None);
+        assert_eq!(sender.take_events(), [TransferEvent::Declined { id: 3 }]);
+        // Without the downloads directory, or a file
+        let offer = sender.offer(4, &source).unwrap();
+        let decline = FileTransfers::default().on_message(offer).unwrap();
+        assert_eq!(decline, FileMessage::Decline { id: 4 });
+        assert_eq!(sender.offer(5, &dir), None);
+        assert!(matches!(
+            sender.take_events()[..],
+            [TransferEvent::Failed { id: 5, .. }]
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_resume() {
+        let dir = temp_dir("resume");
+        let source = dir.join("source.bin");
+        fs::write(&source, contents()).unwrap();
+        let mut sender = FileTransfers::default();
+        let mut receiver = FileTransfers {
+            downloads: Some(dir.join("downloads")),
+            ..Default::default()
+        };
+        let offer = sender.offer(1, &source).unwrap();
+        receiver.on_message(offer);
+        sender.on_message(receiver.accept(1).unwrap());
+        // A chunk gets through, then the session is lost
+        let mut chunks = sender.next_chunks().into_iter();
+        assert_eq!(receiver.on_message(chunks.next().unwrap()), None);
+        sender.abort();
+        receiver.abort();
+        assert!(receiver
+            .take_events()
+            .contains(&TransferEvent::Cancelled { id: 1 }));
+
+        // Offered again, it picks up after that chunk
+        let offer = sender.offer(2, &source).unwrap();
+        receiver.on_message(offer);
+        let accept = receiver.accept(2).unwrap();
+        assert_eq!(
+            accept,
+            FileMessage::Accept {
+                id: 2,
+                offset: CHUNK_SIZE as u64
+            }
+        );
+        assert_eq!(sender.on_message(accept), None);
+        // A chunk goes missing, the rest is asked for again once
+        let mut sent = sender.next_chunks().into_iter();
+        sent.next();
+        let past_gap = sent.next().unwrap();
+        let rewind = receiver.on_message(past_gap.clone());
+        assert_eq!(
+            rewind,
+            Some(FileMessage::Accept {
+                id: 2,
+                offset: CHUNK_SIZE as u64
+            })
+        );
+        assert_eq!(receiver.on_message(past_gap), None);
+        let rewind = sender.on_message(rewind.unwrap());
+        run(&mut sender, &mut receiver, rewind);
+        let path = completed(&receiver.take_events()).unwrap();
+        assert_eq!(fs::read(path).unwrap(), contents());
+        assert!(!dir
+            .join("downloads")
+            .join(format!("source.bin.{}.part", contents().len()))
+            .exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,382 @@
+//! Identities of the peers, so a man in the middle on the LAN can be detected.
+//! Every install has its own Ed25519 keypair. The public keys are exchanged during the handshake
+//! and the users can compare the short fingerprints out of band.
+//! Keys of the peers are remembered on first use (TOFU), a peer showing up with a different key
+//! later is reported.
+//! Data meant for a single known peer is encrypted to the X25519 form of its key and signed,
+//! see `Identity::seal`.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use aes::Aes128;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::integrity::EPHEMERAL_KEY_LEN;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+/// Prefix of the signed data, so the signatures mean nothing outside of a session
+const SIGNATURE_CONTEXT: &[u8] = b"eye-spy session ";
+/// Prefix of the signed and hashed data of sealed bodies, see `Identity::seal`
+const SEAL_CONTEXT: &[u8] = b"eye-spy sealed ";
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+/// Keypair of this install
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the secret key
+        f.debug_struct("Identity")
+            .field("fingerprint", &self.fingerprint())
+            .finish()
+    }
+}
+
+impl Identity {
+    /// A new random identity, forgotten once dropped
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+    /// Loads the identity stored at `path`, creating a new one if there's none yet
+    /// # Errors
+    /// Returns an error if the file cannot be read or written, or isn't an identity
+    pub fn load_or_create(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                let secret: [u8; 32] = bytes.try_into().map_err(|_| {
+                    anyhow::Error::msg(format!("Invalid identity file {}", path.display()))
+                })?;
+                Ok(Self {
+                    key: SigningKey::from_bytes(&secret),
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options.open(path)?.write_all(&identity.key.to_bytes())?;
+                Ok(identity)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.key.verifying_key().to_bytes()
+    }
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+    /// Body of `ScpCommand::KeyShare`:
+    /// <PUBLIC_KEY(32 bytes)><EPHEMERAL_KEY(32 bytes)><SIGNATURE(64 bytes)>
+    /// The signature of the session ID proves the key is held by the peer,
+    /// and the ephemeral key of the session (see `crate::integrity`) comes from it
+    pub(crate) fn key_share(
+        &self,
+        session_id: u32,
+        ephemeral: &[u8; EPHEMERAL_KEY_LEN],
+    ) -> Vec<u8> {
+        let signature = self.key.sign(&signed_data(session_id, ephemeral));
+        [&self.public_key()[..], ephemeral, &signature.to_bytes()].concat()
+    }
+    /// Signature of `data`, prefixed with a context of its own by the caller
+    pub(crate) fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.key.sign(data).to_bytes()
+    }
+    /// `data` only the holder of `recipient` can read, signed by this identity:
+    /// <PUBLIC_KEY(32 bytes)><EPHEMERAL_KEY(32 bytes)><SIGNATURE(64 bytes)><ENCRYPTED_DATA>
+    /// None if `recipient` isn't a valid key
+    pub(crate) fn seal(&self, recipient: &[u8; PUBLIC_KEY_LEN], data: &[u8]) -> Option<Vec<u8>> {
+        let recipient_x25519 = VerifyingKey::from_bytes(recipient).ok()?.to_montgomery();
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&PublicKey::from(recipient_x25519.to_bytes()));
+        if !shared.was_contributory() {
+            return None;
+        }
+        let mut encrypted = data.to_vec();
+        seal_cipher(shared.as_bytes(), &ephemeral).apply_keystream(&mut encrypted);
+        // Bound to the recipient, so it cannot be passed on to another one
+        let signature = self.sign(&[SEAL_CONTEXT, recipient, &ephemeral, &encrypted].concat());
+        Some([&self.public_key()[..], &ephemeral, &signature, &encrypted].concat())
+    }
+    /// The key of the sender and the data of a body `seal`ed for this identity,
+    /// if the sender's signature holds
+    pub(crate) fn open(&self, sealed: &[u8]) -> Option<([u8; PUBLIC_KEY_LEN], Vec<u8>)> {
+        let (sender, body) = sealed.split_first_chunk::<PUBLIC_KEY_LEN>()?;
+        let (ephemeral, body) = body.split_first_chunk::<EPHEMERAL_KEY_LEN>()?;
+        let (signature, encrypted) = body.split_first_chunk::<SIGNATURE_LEN>()?;
+        let signed = [SEAL_CONTEXT, &self.public_key(), ephemeral, encrypted].concat();
+        if !verify(sender, &signed, signature) {
+            return None;
+        }
+        // The X25519 form of our key, the sender encrypted to it
+        let secret = StaticSecret::from(self.key.to_scalar_bytes());
+        let shared = secret.diffie_hellman(&PublicKey::from(*ephemeral));
+        if !shared.was_contributory() {
+            return None;
+        }
+        let mut data = encrypted.to_vec();
+        seal_cipher(shared.as_bytes(), ephemeral).apply_keystream(&mut data);
+        Some((*sender, data))
+    }
+}
+
+/// Cipher of a sealed body. The ephemeral key is new for every body, so is the cipher key
+fn seal_cipher(shared: &[u8; 32], ephemeral: &[u8; EPHEMERAL_KEY_LEN]) -> Aes128Ctr {
+    let hash = Sha256::new()
+        .chain_update(SEAL_CONTEXT)
+        .chain_update(shared)
+        .chain_update(ephemeral)
+        .finalize();
+    let mut key = [0; 16];
+    key.copy_from_slice(&hash[..16]);
+    Aes128Ctr::new(&key.into(), &[0; 16].into())
+}
+
+/// Whether `signature` of `data` was made by the holder of `public_key`
+pub(crate) fn verify(public_key: &[u8; PUBLIC_KEY_LEN], data: &[u8], signature: &[u8]) -> bool {
+    let (Ok(key), Ok(signature)) = (
+        VerifyingKey::from_bytes(public_key),
+        Signature::from_slice(signature),
+    ) else {
+        return false;
+    };
+    key.verify(data, &signature).is_ok()
+}
+
+fn signed_data(session_id: u32, ephemeral: &[u8; EPHEMERAL_KEY_LEN]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, &session_id.to_le_bytes(), ephemeral].concat()
+}
+
+/// The public key and the ephemeral key of a `Identity::key_share` body,
+/// if its signature for the session holds
+pub(crate) fn verify_key_share(
+    body: &[u8],
+    session_id: u32,
+) -> Option<([u8; PUBLIC_KEY_LEN], [u8; EPHEMERAL_KEY_LEN])> {
+    let (public_key, body) = body.split_first_chunk::<PUBLIC_KEY_LEN>()?;
+    let (ephemeral, signature) = body.split_first_chunk::<EPHEMERAL_KEY_LEN>()?;
+    let signature = Signature::from_slice(signature).ok()?;
+    VerifyingKey::from_bytes(public_key)
+        .ok()?
+        .verify(&signed_data(session_id, ephemeral), &signature)
+        .ok()?;
+    Some((*public_key, *ephemeral))
+}
+
+/// Short form of a public key to compare out of band, e.g. `3F2A 91C0 77DE 0B14`
+pub fn fingerprint(public_key: &[u8; PUBLIC_KEY_LEN]) -> String {
+    let hash = Sha256::digest(public_key);
+    hash[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A fingerprint as typed in by the user, without spaces and in upper case
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// How far the key of a peer can be trusted
+/// * `New` - the peer wasn't seen before, its key is remembered from now on
+/// * `Known` - the same key as the last time
+/// * `Changed` - a different key than the last time, possibly a man in the middle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerTrust {
+    New,
+    Known,
+    Changed,
+}
+
+/// Identity of the peer of a session
+/// * `public_key` - the key the peer proved to hold
+/// * `trust` - how it compares to the key remembered for the peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+    pub trust: PeerTrust,
+}
+
+impl PeerIdentity {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+}
+
+/// Keys of the peers seen so far, by their address.
+/// Saved as JSON after every change when loaded from a file
+#[derive(Debug, Default)]
+pub struct KnownPeers {
+    path: Option<PathBuf>,
+    keys: HashMap<IpAddr, String>,
+}
+
+impl KnownPeers {
+    /// Known peers that aren't saved anywhere
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+    /// Loads the peers stored at `path`. The file is created once there's a peer to remember
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read or parsed
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let keys = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            keys,
+        })
+    }
+    /// Compare the key of a peer with the remembered one. New peers are remembered,
+    /// a changed key is not replaced until it's trusted explicitly
+    pub fn check(&mut self, ip: IpAddr, public_key: &[u8; PUBLIC_KEY_LEN]) -> PeerTrust {
+        let key = to_hex(public_key);
+        match self.keys.get(&ip) {
+            Some(known) if *known == key => PeerTrust::Known,
+            Some(_) => PeerTrust::Changed,
+            None => {
+                self.trust(ip, public_key);
+                PeerTrust::New
+            }
+        }
+    }
+    /// The key remembered for the peer at `ip`, None if it wasn't seen before
+    pub fn key_of(&self, ip: IpAddr) -> Option<[u8; PUBLIC_KEY_LEN]> {
+        let hex = self.keys.get(&ip)?;
+        let mut key = [0; PUBLIC_KEY_LEN];
+        if hex.len() != PUBLIC_KEY_LEN * 2 {
+            return None;
+        }
+        for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(key)
+    }
+    /// Remember the key of a peer, i.e. after its fingerprint was verified out of band
+    pub fn trust(&mut self, ip: IpAddr, public_key: &[u8; PUBLIC_KEY_LEN]) {
+        self.keys.insert(ip, to_hex(public_key));
+        if let Err(e) = self.save() {
+            warn!("Cannot save the known peers: {e}");
+        }
+    }
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(&self.keys)?)?;
+        Ok(())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{verify_key_share, Identity, KnownPeers, PeerTrust};
+
+    #[test]
+    fn test_key_share() {
+        let identity = Identity::generate();
+        let share = identity.key_share(42, &[7; 32]);
+        assert_eq!(
+            verify_key_share(&share, 42),
+            Some((identity.public_key(), [7; 32]))
+        );
+        // Replayed in another session
+        assert_eq!(verify_key_share(&share, 43), None);
+        assert_eq!(verify_key_share(&share[..40], 42), None);
+        // Another ephemeral key slipped in
+        let mut swapped = share.clone();
+        swapped[40] ^= 1;
+        assert_eq!(verify_key_share(&swapped, 42), None);
+    }
+    #[test]
+    fn test_sealed() {
+        let (sender, recipient, other) = (
+            Identity::generate(),
+            Identity::generate(),
+            Identity::generate(),
+        );
+        let sealed = sender.seal(&recipient.public_key(), b"ticket").unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"ticket"));
+        assert_eq!(
+            recipient.open(&sealed),
+            Some((sender.public_key(), b"ticket".to_vec()))
+        );
+        // Meant for another identity, tampered or signed by someone else
+        assert_eq!(other.open(&sealed), None);
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(recipient.open(&tampered), None);
+        let mut forged = sealed.clone();
+        forged[..32].copy_from_slice(&other.public_key());
+        assert_eq!(recipient.open(&forged), None);
+        assert_eq!(recipient.open(&sealed[..100]), None);
+    }
+    #[test]
+    fn test_known_peers() {
+        let path = std::env::temp_dir().join(format!("scp-known-peers-{}", std::process::id()));
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 7));
+        let (peer, impostor) = (Identity::generate(), Identity::generate());
+        {
+            let mut known = KnownPeers::load(&path).unwrap();
+            assert_eq!(known.check(ip, &peer.public_key()), PeerTrust::New);
+            assert_eq!(known.check(ip, &peer.public_key()), PeerTrust::Known);
+            assert_eq!(known.key_of(ip), Some(peer.public_key()));
+            assert_eq!(known.key_of(IpAddr::V4(Ipv4Addr::LOCALHOST)), None);
+        }
+        let mut known = KnownPeers::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(known.check(ip, &peer.public_key()), PeerTrust::Known);
+        assert_eq!(known.check(ip, &impostor.public_key()), PeerTrust::Changed);
+        // Not replaced until trusted
+        assert_eq!(known.check(ip, &impostor.public_key()), PeerTrust::Changed);
+    }
+    #[test]
+    fn test_identity_persists() {
+        let path = std::env::temp_dir().join(format!("scp-identity-{}", std::process::id()));
+        let created = Identity::load_or_create(&path).unwrap();
+        let loaded = Identity::load_or_create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+        assert_eq!(created.fingerprint().len(), "3F2A 91C0 77DE 0B14".len());
+    }
+}
@@ -0,0 +1,278 @@
+//! Integrity of the SCP messages of a session, so nobody on the LAN can inject an `End`
+//! or rogue preferences into an ongoing negotiation.
+//! Both sides contribute an ephemeral X25519 key, signed with their identity in `KeyShare`.
+//! The requesting side sends its key along with `Start`, so the session key exists
+//! on both sides before any preferences are shared.
+//! Every message but `Start` and `KeyShare` carries then a nonce and a HMAC of itself.
+//!
+//! Sealed body: <BODY><NONCE(64bits)><HMAC-SHA256(256bits)>
+//!
+//! A resume token comes out of the same exchange, so a side that restarted can prove
+//! it took part in the session, see `crate::resume`. A resumed session gets a new key
+//! out of the token and the salt of the `Resume` messages.
+//!
+//! The SRTP keys of the streams come out of the session key's secret as well, see `MediaKeys`.
+
+use std::fmt::Debug;
+
+use hmac::{Hmac, Mac};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, ReusableSecret};
+
+use crate::client::{MediaKey, MediaKeys, MEDIA_KEY_LEN, MEDIA_SALT_LEN};
+use crate::scp::ScpCommand;
+
+pub const EPHEMERAL_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = std::mem::size_of::<u64>();
+const TAG_LEN: usize = 32;
+/// Prefix of the hashed data, so the keys mean nothing outside of a session
+const KEY_CONTEXT: &[u8] = b"eye-spy session key ";
+pub const RESUME_TOKEN_LEN: usize = 32;
+pub const RESUME_SALT_LEN: usize = 32;
+
+/// Ephemeral key of this side of a session, forgotten with the session
+pub(crate) struct KeyExchange {
+    secret: ReusableSecret,
+    public: PublicKey,
+}
+
+impl Debug for KeyExchange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyExchange").finish_non_exhaustive()
+    }
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = ReusableSecret::random_from_rng(OsRng);
+        Self {
+            public: PublicKey::from(&secret),
+            secret,
+        }
+    }
+    pub fn public_key(&self) -> [u8; EPHEMERAL_KEY_LEN] {
+        self.public.to_bytes()
+    }
+    /// The key of the session with the peer. None if the peer's key is a low order point,
+    /// i.e. picked so the key is known in advance
+    /// * `requester` - this side requested the session
+    pub fn session_key(
+        &self,
+        peer_key: &[u8; EPHEMERAL_KEY_LEN],
+        session_id: u32,
+        requester: bool,
+    ) -> Option<SessionKey> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_key));
+        if !shared.was_contributory() {
+            return None;
+        }
+        let resume_token = derive(b"resume", shared.as_bytes(), session_id);
+        Some(SessionKey::derived(
+            shared.as_bytes(),
+            session_id,
+            requester,
+            resume_token,
+        ))
+    }
+}
+
+fn derive(label: &[u8], secret: &[u8], session_id: u32) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(KEY_CONTEXT)
+        .chain_update(label)
+        .chain_update(secret)
+        .chain_update(session_id.to_le_bytes())
+        .finalize()
+        .into()
+}
+
+/// Seals the messages sent in a session and opens the received ones
+/// * `sent` - nonce of the last message sent
+/// * `received` - nonce of the last message received, older ones are replays
+/// * `resume_token` - secret both sides share to resume the session, see `crate::resume`
+/// * `requester` - this side requested the session
+pub(crate) struct SessionKey {
+    seal_key: [u8; 32],
+    open_key: [u8; 32],
+    media_keys: MediaKeys,
+    sent: u64,
+    received: u64,
+    resume_token: [u8; RESUME_TOKEN_LEN],
+    requester: bool,
+}
+
+impl Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the keys
+        f.debug_struct("SessionKey")
+            .field("sent", &self.sent)
+            .field("received", &self.received)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionKey {
+    fn derived(
+        secret: &[u8],
+        session_id: u32,
+        requester: bool,
+        resume_token: [u8; RESUME_TOKEN_LEN],
+    ) -> Self {
+        // Each direction has its own key, so our own messages cannot be reflected back at us
+        let requester_key = derive(b"requester", secret, session_id);
+        let responder_key = derive(b"responder", secret, session_id);
+        let (seal_key, open_key) = if requester {
+            (requester_key, responder_key)
+        } else {
+            (responder_key, requester_key)
+        };
+        let requester_media = media_key(&derive(b"media requester", secret, session_id));
+        let responder_media = media_key(&derive(b"media responder", secret, session_id));
+        let media_keys = if requester {
+            MediaKeys {
+                send: requester_media,
+                receive: responder_media,
+            }
+        } else {
+            MediaKeys {
+                send: responder_media,
+                receive: requester_media,
+            }
+        };
+        Self {
+            seal_key,
+            open_key,
+            media_keys,
+            sent: 0,
+            received: 0,
+            resume_token,
+            requester,
+        }
+    }
+    /// The key of a resumed session. The salt is new for every resume,
+    /// so the messages of a previous one cannot be replayed
+    pub fn resumed(
+        resume_token: &[u8; RESUME_TOKEN_LEN],
+        salt: &[u8; RESUME_SALT_LEN],
+        session_id: u32,
+        requester: bool,
+    ) -> Self {
+        let secret = [&resume_token[..], salt].concat();
+        Self::derived(&secret, session_id, requester, *resume_token)
+    }
+    pub fn resume_token(&self) -> [u8; RESUME_TOKEN_LEN] {
+        self.resume_token
+    }
+    pub fn requester(&self) -> bool {
+        self.requester
+    }
+    pub fn media_keys(&self) -> MediaKeys {
+        self.media_keys
+    }
+    /// The body with the next nonce and the HMAC appended
+    pub fn seal(&mut self, command: ScpCommand, body: &[u8]) -> Vec<u8> {
+        self.sent += 1;
+        let nonce = self.sent.to_le_bytes();
+        let tag = mac(&self.seal_key, command, &nonce, body).finalize();
+        [body, &nonce, &tag.into_bytes()].concat()
+    }
+    /// The body without the nonce and the HMAC. None if it was tampered with or replayed
+    pub fn open(&mut self, command: ScpCommand, sealed: &[u8]) -> Option<Vec<u8>> {
+        let (rest, tag) = sealed.split_last_chunk::<TAG_LEN>()?;
+        let (body, nonce) = rest.split_last_chunk::<NONCE_LEN>()?;
+        // Compared in constant time
+        mac(&self.open_key, command, nonce, body)
+            .verify_slice(tag)
+            .ok()?;
+        let nonce = u64::from_le_bytes(*nonce);
+        if nonce <= self.received {
+            return None;
+        }
+        self.received = nonce;
+        Some(body.to_vec())
+    }
+}
+
+/// The SRTP master key and salt out of the first 30 bytes of a derived one
+fn media_key(derived: &[u8; 32]) -> MediaKey {
+    let mut key = MediaKey {
+        key: [0; MEDIA_KEY_LEN],
+        salt: [0; MEDIA_SALT_LEN],
+    };
+    key.key.copy_from_slice(&derived[..MEDIA_KEY_LEN]);
+    key.salt
+        .copy_from_slice(&derived[MEDIA_KEY_LEN..MEDIA_KEY_LEN + MEDIA_SALT_LEN]);
+    key
+}
+
+fn mac(key: &[u8; 32], command: ScpCommand, nonce: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    // Any key length goes for HMAC
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(&(command as u16).to_le_bytes());
+    mac.update(nonce);
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyExchange, SessionKey};
+    use crate::scp::ScpCommand;
+
+    #[test]
+    fn test_sealed_messages() {
+        let (requester, responder) = (KeyExchange::new(), KeyExchange::new());
+        let mut sender = requester
+            .session_key(&responder.public_key(), 7, true)
+            .unwrap();
+        let mut receiver = responder
+            .session_key(&requester.public_key(), 7, false)
+            .unwrap();
+
+        let ready = sender.seal(ScpCommand::Ready, b"");
+        let end = sender.seal(ScpCommand::End, b"bye");
+        assert_eq!(receiver.open(ScpCommand::Ready, &ready), Some(vec![]));
+        // Replayed
+        assert_eq!(receiver.open(ScpCommand::Ready, &ready), None);
+        // Another command, tampered body, reflected back at the sender
+        assert_eq!(receiver.open(ScpCommand::Ready, &end), None);
+        let mut tampered = end.clone();
+        tampered[0] ^= 1;
+        assert_eq!(receiver.open(ScpCommand::End, &tampered), None);
+        assert_eq!(sender.open(ScpCommand::End, &end), None);
+        assert_eq!(receiver.open(ScpCommand::End, &end), Some(b"bye".to_vec()));
+
+        // The streams each side sends are opened by the other
+        let (sent, received) = (sender.media_keys(), receiver.media_keys());
+        assert_eq!(sent.send, received.receive);
+        assert_eq!(sent.receive, received.send);
+        assert_ne!(sent.send, sent.receive);
+    }
+    #[test]
+    fn test_resumed_key() {
+        let (requester, responder) = (KeyExchange::new(), KeyExchange::new());
+        let token = requester
+            .session_key(&responder.public_key(), 7, true)
+            .unwrap()
+            .resume_token();
+        let other = responder
+            .session_key(&requester.public_key(), 7, false)
+            .unwrap();
+        assert_eq!(token, other.resume_token());
+
+        let mut sender = SessionKey::resumed(&token, &[1; 32], 7, true);
+        let mut receiver = SessionKey::resumed(&token, &[1; 32], 7, false);
+        let ready = sender.seal(ScpCommand::Ready, b"");
+        assert_eq!(receiver.open(ScpCommand::Ready, &ready), Some(vec![]));
+        // Messages of a previous resume cannot be replayed
+        let mut resumed_again = SessionKey::resumed(&token, &[2; 32], 7, false);
+        assert_eq!(resumed_again.open(ScpCommand::Ready, &ready), None);
+        assert_eq!(resumed_again.resume_token(), token);
+    }
+    #[test]
+    fn test_low_order_key() {
+        let key = KeyExchange::new();
+        assert!(key.session_key(&[0; 32], 7, true).is_none());
+    }
+}
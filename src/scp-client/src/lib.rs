@@ -1,4 +1,13 @@
 pub mod client;
+mod connection;
+pub mod file_transfer;
+pub mod identity;
+mod integrity;
+pub mod link_local;
 mod misc;
+pub mod peer_list;
+pub mod probe;
+pub mod resume;
 pub mod scp;
 pub mod scp_listener;
+mod session;
@@ -0,0 +1,187 @@
+//! Calls over link-local addresses, between two laptops on a cable, an ad-hoc network or
+//! the hotspot of one of them, where no router hands out addresses.
+//! The IPv4 ones (169.254.0.0/16) work like any other address. The IPv6 ones (fe80::/10) are
+//! on every interface at once, so they only tell where the peer is along with the interface,
+//! the scope ID, i.e. `[fe80::1%wlan0]:7000`. The scope is kept with the address of the peer
+//! from the dial on, and picked for the peers dialed without one, see `resolve_scope`.
+
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener};
+
+use if_addrs::{get_if_addrs, IfAddr};
+use tracing::{info, warn};
+
+/// Whether `ip` is only valid on the link it's on
+pub fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => is_link_local_v6(&ip),
+    }
+}
+
+fn is_link_local_v6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Parses a socket address, the scope of an IPv6 one also given by the name of the interface,
+/// i.e. `[fe80::1%wlan0]:7000` as well as `[fe80::1%3]:7000`
+pub fn parse_addr(text: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = text.parse() {
+        return Some(addr);
+    }
+    let (ip, rest) = text.strip_prefix('[')?.split_once('%')?;
+    let (interface, port) = rest.split_once("]:")?;
+    let scope = interface_index(interface)?;
+    Some(SocketAddr::V6(SocketAddrV6::new(
+        ip.parse().ok()?,
+        port.parse().ok()?,
+        0,
+        scope,
+    )))
+}
+
+/// Index of the network interface called `name`, None if there's none
+pub fn interface_index(name: &str) -> Option<u32> {
+    get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.name == name)
+        .and_then(|interface| interface.index)
+}
+
+/// `addr` with its scope, for the IPv6 link-local addresses given without one.
+/// It's the interface with a link-local address of its own, as long as there's a single one
+/// # Errors
+/// Returns `InvalidInput` if there's no such interface, or several of them to choose from
+pub fn resolve_scope(addr: SocketAddr) -> io::Result<SocketAddr> {
+    let SocketAddr::V6(mut v6) = addr else {
+        return Ok(addr);
+    };
+    if v6.scope_id() != 0 || !is_link_local_v6(v6.ip()) {
+        return Ok(addr);
+    }
+    let mut scopes = link_local_interfaces()?.into_iter().map(|(_, scope)| scope);
+    match (scopes.next(), scopes.next()) {
+        (Some(scope), None) => {
+            v6.set_scope_id(scope);
+            Ok(SocketAddr::V6(v6))
+        }
+        (None, _) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No interface to reach the link-local address {addr} on"),
+        )),
+        (Some(_), Some(_)) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Give the interface of the link-local address {addr}, i.e. [fe80::1%wlan0]"),
+        )),
+    }
+}
+
+/// `addr` with another port, keeping the scope of an IPv6 one
+pub(crate) fn with_port(mut addr: SocketAddr, port: u16) -> SocketAddr {
+    addr.set_port(port);
+    addr
+}
+
+/// IPv6 link-local addresses of the interfaces, and the scopes they're on
+fn link_local_interfaces() -> io::Result<Vec<(Ipv6Addr, u32)>> {
+    Ok(get_if_addrs()?
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .filter_map(|interface| match interface.addr {
+            IfAddr::V6(ref v6) if is_link_local_v6(&v6.ip) => Some((v6.ip, interface.index?)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Listeners on the IPv6 link-local address of every interface having one, at `port`,
+/// so the peers on a link without a router can call in over IPv6 too.
+/// The ones that cannot be bound are left out
+pub(crate) fn listeners(port: u16) -> Vec<TcpListener> {
+    let interfaces = link_local_interfaces().unwrap_or_else(|e| {
+        warn!("Cannot list the network interfaces: {e}");
+        Vec::new()
+    });
+    interfaces
+        .into_iter()
+        .filter_map(|(ip, scope)| {
+            let addr = SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope));
+            let listener = TcpListener::bind(addr)
+                .and_then(|listener| listener.set_nonblocking(true).map(|_| listener));
+            match listener {
+                Ok(listener) => {
+                    info!("Listening on the link-local address {addr} too");
+                    Some(listener)
+                }
+                Err(e) => {
+                    warn!("Cannot listen on the link-local address {addr}: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use if_addrs::get_if_addrs;
+
+    use super::{interface_index, is_link_local, parse_addr, resolve_scope, with_port};
+
+    #[test]
+    fn test_is_link_local() {
+        let link_local = |ip: &str| is_link_local(ip.parse::<IpAddr>().unwrap());
+        assert!(link_local("169.254.10.3"));
+        assert!(link_local("fe80::1"));
+        assert!(link_local("febf::1"));
+        assert!(!link_local("192.168.1.12"));
+        assert!(!link_local("fec0::1"));
+        assert!(!link_local("::1"));
+    }
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(
+            parse_addr("169.254.10.3:7000"),
+            Some("169.254.10.3:7000".parse().unwrap())
+        );
+        let Some(SocketAddr::V6(numeric)) = parse_addr("[fe80::1%3]:7000") else {
+            panic!("Numeric scopes are parsed as they are");
+        };
+        assert_eq!(numeric.scope_id(), 3);
+        // Any interface does, it's only looked up by name
+        let interface = get_if_addrs().unwrap().into_iter().next().unwrap();
+        let Some(SocketAddr::V6(named)) = parse_addr(&format!("[fe80::1%{}]:7000", interface.name))
+        else {
+            panic!("The scope is looked up by the name of the interface");
+        };
+        assert_eq!(Some(named.scope_id()), interface.index);
+        assert_eq!(named.port(), 7000);
+        assert_eq!(parse_addr("[fe80::1%no-such-interface]:7000"), None);
+        assert_eq!(interface_index("no-such-interface"), None);
+        assert_eq!(parse_addr("fe80::1%3"), None);
+    }
+    #[test]
+    fn test_resolve_scope() {
+        for addr in [
+            "169.254.10.3:7000",
+            "[fe80::1%3]:7000",
+            "[2001:db8::1]:7000",
+        ] {
+            let addr = addr.parse().unwrap();
+            assert_eq!(resolve_scope(addr).unwrap(), addr);
+        }
+        // Picked if there's a single interface to pick, an error otherwise
+        let addr: SocketAddr = "[fe80::1]:7000".parse().unwrap();
+        if let Ok(SocketAddr::V6(resolved)) = resolve_scope(addr) {
+            assert_ne!(resolved.scope_id(), 0);
+        }
+    }
+    #[test]
+    fn test_with_port() {
+        let addr = "[fe80::1%3]:7000".parse().unwrap();
+        assert_eq!(with_port(addr, 7100), "[fe80::1%3]:7100".parse().unwrap());
+    }
+}
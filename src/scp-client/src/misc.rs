@@ -1,8 +1,9 @@
 use get_if_addrs::get_if_addrs;
 use std::net::IpAddr;
 
+/// First IPv4 address that isn't a loopback, None if there's none or the interfaces cannot be listed
 pub fn get_local_ip() -> Option<IpAddr> {
-    let interfaces = get_if_addrs().expect("Failed to get network interfaces");
+    let interfaces = get_if_addrs().ok()?;
 
     for iface in interfaces {
         if !iface.is_loopback() {
@@ -0,0 +1,247 @@
+//! Peers allowed or denied to request sessions, by their IP or identity.
+//! Denied IPs are refused before any handshake work, denied identities as soon as
+//! the peer proves it holds one. Once anything is allowed, only the allowed peers get through.
+//! Saved as JSON after every change when loaded from a file.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::identity::{normalize_fingerprint, PeerIdentity};
+
+pub type SharedPeerList = Arc<Mutex<PeerList>>;
+
+/// A peer on the list
+/// * `Ip` - any peer connecting from the address
+/// * `Identity` - the peer holding the identity with this fingerprint, see `normalize_fingerprint`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerEntry {
+    Ip(IpAddr),
+    Identity(String),
+}
+
+impl PeerEntry {
+    /// Entry of the identity with the fingerprint, spaces and case don't matter
+    pub fn identity(fingerprint: &str) -> Self {
+        Self::Identity(normalize_fingerprint(fingerprint))
+    }
+    /// The entry of the identity if the peer proved one, of the IP otherwise
+    pub fn of_peer(ip: IpAddr, identity: Option<&PeerIdentity>) -> Self {
+        identity.map_or(Self::Ip(ip), |identity| {
+            Self::identity(&identity.fingerprint())
+        })
+    }
+    fn matches(&self, ip: IpAddr, fingerprint: Option<&str>) -> bool {
+        match self {
+            Self::Ip(entry) => *entry == ip,
+            Self::Identity(entry) => fingerprint == Some(entry.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for PeerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{ip}"),
+            Self::Identity(fingerprint) => write!(f, "{fingerprint}"),
+        }
+    }
+}
+
+impl FromStr for PeerEntry {
+    type Err = anyhow::Error;
+
+    /// An IP, or a fingerprint like `3F2A 91C0 77DE 0B14`
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        if let Ok(ip) = text.trim().parse() {
+            return Ok(Self::Ip(ip));
+        }
+        let fingerprint = normalize_fingerprint(text);
+        if fingerprint.len() != 16 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Neither an IP nor a fingerprint: {text}");
+        }
+        Ok(Self::Identity(fingerprint))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entries {
+    allowed: Vec<PeerEntry>,
+    denied: Vec<PeerEntry>,
+}
+
+/// The allowed and the denied peers
+#[derive(Debug, Default)]
+pub struct PeerList {
+    path: Option<PathBuf>,
+    entries: Entries,
+}
+
+impl PeerList {
+    /// A list that isn't saved anywhere
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+    /// Loads the list stored at `path`. The file is created once there's an entry to remember
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read or parsed
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let entries = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Entries::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            entries,
+        })
+    }
+    pub fn allowed(&self) -> &[PeerEntry] {
+        &self.entries.allowed
+    }
+    pub fn denied(&self) -> &[PeerEntry] {
+        &self.entries.denied
+    }
+    /// Allow the peer, taking it off the denied ones
+    pub fn allow(&mut self, entry: PeerEntry) {
+        self.entries.denied.retain(|e| *e != entry);
+        if !self.entries.allowed.contains(&entry) {
+            self.entries.allowed.push(entry);
+        }
+        self.save();
+    }
+    /// Deny the peer, taking it off the allowed ones
+    pub fn deny(&mut self, entry: PeerEntry) {
+        self.entries.allowed.retain(|e| *e != entry);
+        if !self.entries.denied.contains(&entry) {
+            self.entries.denied.push(entry);
+        }
+        self.save();
+    }
+    /// Take the peer off the list
+    pub fn remove(&mut self, entry: &PeerEntry) {
+        self.entries.allowed.retain(|e| e != entry);
+        self.entries.denied.retain(|e| e != entry);
+        self.save();
+    }
+    /// Whether the peer connecting from `ip` may start a handshake.
+    /// Peers allowed by their identity cannot be told apart yet, so they get through
+    pub fn admits_ip(&self, ip: IpAddr) -> bool {
+        let Entries { allowed, denied } = &self.entries;
+        if denied.iter().any(|e| e.matches(ip, None)) {
+            return false;
+        }
+        allowed.is_empty()
+            || allowed
+                .iter()
+                .any(|e| matches!(e, PeerEntry::Identity(_)) || e.matches(ip, None))
+    }
+    /// Whether the peer connecting from `ip` without an identity to prove, i.e. a browser,
+    /// may have a session. Only the entries by IP can allow it
+    pub fn admits_anonymous(&self, ip: IpAddr) -> bool {
+        let Entries { allowed, denied } = &self.entries;
+        !denied.iter().any(|e| e.matches(ip, None))
+            && (allowed.is_empty() || allowed.iter().any(|e| e.matches(ip, None)))
+    }
+    /// Whether the peer connecting from `ip` with the proven `identity` may have a session
+    pub fn admits(&self, ip: IpAddr, identity: &PeerIdentity) -> bool {
+        let fingerprint = normalize_fingerprint(&identity.fingerprint());
+        let fingerprint = Some(fingerprint.as_str());
+        let Entries { allowed, denied } = &self.entries;
+        !denied.iter().any(|e| e.matches(ip, fingerprint))
+            && (allowed.is_empty() || allowed.iter().any(|e| e.matches(ip, fingerprint)))
+    }
+    fn save(&self) {
+        if let Err(e) = self.try_save() {
+            warn!("Cannot save the peer list: {e}");
+        }
+    }
+    fn try_save(&self) -> anyhow::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(&self.entries)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{PeerEntry, PeerList};
+    use crate::identity::{Identity, PeerIdentity, PeerTrust};
+
+    fn peer_identity(identity: &Identity) -> PeerIdentity {
+        PeerIdentity {
+            public_key: identity.public_key(),
+            trust: PeerTrust::Known,
+        }
+    }
+    #[test]
+    fn test_denied_peers() {
+        let (ip, other) = (
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)),
+        );
+        let (blocked, friend) = (Identity::generate(), Identity::generate());
+        let mut list = PeerList::in_memory();
+        list.deny(PeerEntry::Ip(ip));
+        list.deny(PeerEntry::identity(&blocked.fingerprint()));
+
+        assert!(!list.admits_ip(ip));
+        assert!(!list.admits_anonymous(ip));
+        assert!(list.admits_anonymous(other));
+        // Only known once the handshake gets to the identities
+        assert!(list.admits_ip(other));
+        assert!(!list.admits(other, &peer_identity(&blocked)));
+        assert!(list.admits(other, &peer_identity(&friend)));
+
+        list.remove(&PeerEntry::Ip(ip));
+        assert!(list.admits_ip(ip));
+    }
+    #[test]
+    fn test_allowed_peers_only() {
+        let (ip, other) = (
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)),
+        );
+        let (friend, stranger) = (Identity::generate(), Identity::generate());
+        let mut list = PeerList::in_memory();
+        list.allow(PeerEntry::Ip(ip));
+        assert!(list.admits_ip(ip));
+        assert!(!list.admits_ip(other));
+
+        list.allow(PeerEntry::identity(&friend.fingerprint()));
+        assert!(list.admits_ip(other));
+        assert!(list.admits_anonymous(ip));
+        assert!(!list.admits_anonymous(other));
+        assert!(list.admits(other, &peer_identity(&friend)));
+        assert!(!list.admits(other, &peer_identity(&stranger)));
+        assert!(list.admits(ip, &peer_identity(&stranger)));
+        // Denying takes it off the allowed ones
+        list.deny(PeerEntry::Ip(ip));
+        assert!(!list.admits(ip, &peer_identity(&stranger)));
+        assert_eq!(list.allowed().len(), 1);
+    }
+    #[test]
+    fn test_peer_list_persists() {
+        let path = std::env::temp_dir().join(format!("scp-peer-list-{}", std::process::id()));
+        let entry: PeerEntry = "3f2a 91c0 77de 0b14".parse().unwrap();
+        assert_eq!(entry, PeerEntry::Identity("3F2A91C077DE0B14".into()));
+        PeerList::load(&path).unwrap().deny(entry.clone());
+        let loaded = PeerList::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.denied(), [entry]);
+        assert!("not a peer".parse::<PeerEntry>().is_err());
+    }
+}
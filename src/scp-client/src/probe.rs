@@ -0,0 +1,132 @@
+//! Pre-flight query of a peer's capabilities, without requesting a session.
+//! The client sends `Probe` over a connection of its own, the listener answers with
+//! `ProbeReply` carrying `PeerCapabilities` as JSON, busy or not, and closes the connection.
+//! Peers denied by the peer list get `End` instead, like any request of theirs.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::{AudioEncoding, ScpConnectionError, ScpError, VideoEncoding, VideoMode};
+use crate::connection::ScpConnection;
+use crate::scp::{ScpCommand, ScpMessage, PROTOCOL_VERSION};
+
+/// How often the connection is checked for the reply
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a peer supports, answered to `Probe`
+/// * `protocol_version` - see `crate::scp::PROTOCOL_VERSION`
+/// * `video_modes` - modes the peer's camera captures in, empty if it has none or isn't known
+/// * `busy` - the peer is in a session or negotiating one, a request would be refused
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCapabilities {
+    pub protocol_version: u16,
+    pub video_encodings: Vec<VideoEncoding>,
+    pub audio_encodings: Vec<AudioEncoding>,
+    #[serde(default)]
+    pub video_modes: Vec<VideoMode>,
+    pub busy: bool,
+}
+
+impl PeerCapabilities {
+    /// The peer speaks the same protocol and takes the video this client sends
+    pub fn compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+            && self.video_encodings.contains(&VideoEncoding::H264)
+    }
+    /// The largest, then the fastest, mode of the peer
+    pub fn best_mode(&self) -> Option<VideoMode> {
+        self.video_modes
+            .iter()
+            .max_by_key(|mode| (mode.width as u32 * mode.height as u32, mode.fps))
+            .copied()
+    }
+}
+
+/// Ask the peer listening at `destination` what it supports. Blocks for `timeout` at most
+/// # Errors
+/// * `ScpError::Io` - the peer cannot be reached
+/// * `ScpError::Connection` - the peer didn't answer in time, or refused to
+/// * `ScpError::InvalidReply` - the answer cannot be read, i.e. sent by an incompatible peer
+pub fn probe(destination: SocketAddr, timeout: Duration) -> Result<PeerCapabilities, ScpError> {
+    let deadline = Instant::now() + timeout;
+    let mut connection = ScpConnection::connect(destination, timeout)?;
+    connection.send(&ScpMessage::new(ScpCommand::Probe, b""))?;
+    let reply = loop {
+        if let Some(reply) = connection.receive().into_iter().next() {
+            break reply;
+        }
+        if connection.is_closed() || Instant::now() >= deadline {
+            connection.close();
+            return Err(ScpConnectionError::NotResponding.into());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    connection.close();
+    match reply.command {
+        ScpCommand::ProbeReply => {
+            serde_json::from_slice(&reply.body).map_err(|e| ScpError::InvalidReply(e.to_string()))
+        }
+        ScpCommand::End => Err(ScpConnectionError::Refused.into()),
+        command => Err(ScpError::InvalidReply(format!(
+            "expected a probe reply, got {command:?}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{probe, PeerCapabilities};
+    use crate::client::{ScpClientBuilder, ScpConnectionError, ScpError, VideoMode};
+    use crate::peer_list::PeerEntry;
+    use crate::scp::PROTOCOL_VERSION;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_probe() {
+        let mode = VideoMode {
+            width: 640,
+            height: 480,
+            fps: 30,
+        };
+        let client = ScpClientBuilder::builder().port_scp(0).build();
+        let peer = ScpClientBuilder::builder()
+            .port_scp(0)
+            .video_modes(vec![mode])
+            .build();
+        let capabilities = probe(peer.local_addr(), TIMEOUT).unwrap();
+        assert_eq!(capabilities.protocol_version, PROTOCOL_VERSION);
+        assert!(capabilities.compatible());
+        assert_eq!(capabilities.best_mode(), Some(mode));
+        assert!(!capabilities.busy);
+
+        // Probing doesn't start anything, but a session in the works makes the peer busy
+        assert!(peer.poll_event().is_none());
+        client.attempt_connection(peer.local_addr());
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(probe(peer.local_addr(), TIMEOUT).unwrap().busy);
+
+        peer.peer_list()
+            .deny(PeerEntry::Ip(client.local_addr().ip()));
+        assert!(matches!(
+            probe(peer.local_addr(), TIMEOUT),
+            Err(ScpError::Connection(ScpConnectionError::Refused))
+        ));
+    }
+    #[test]
+    fn test_incompatible_capabilities() {
+        let capabilities = PeerCapabilities {
+            protocol_version: PROTOCOL_VERSION + 1,
+            video_encodings: Vec::new(),
+            audio_encodings: Vec::new(),
+            video_modes: Vec::new(),
+            busy: false,
+        };
+        assert!(!capabilities.compatible());
+        assert_eq!(capabilities.best_mode(), None);
+    }
+}
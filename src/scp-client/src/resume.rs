@@ -0,0 +1,352 @@
+//! Resuming a session after the app restarted, i.e. crashed, without dialing the peer again.
+//! Once a session is established, `SessionConfig::resume` holds a `ResumeTicket` for it,
+//! for the frontend to save. After a restart within `RESUME_WINDOW`, `ScpClient::resume`
+//! sends the peer `Resume` with a proof of the token both sides got out of the key exchange,
+//! signed with the identity it had in the session, see `crate::identity`. The address it comes
+//! from doesn't matter. The peer answers with its own `Resume` if it still has the session, and both go on
+//! with the ports and codecs negotiated before, under a new key, see `crate::integrity`.
+//! A session ended by either side cannot be resumed.
+//!
+//! The ticket also hands a session over to another device of the user, see `ScpClient::transfer`.
+//! The device in the session sends it in `Transfer`, sealed to the identity of the other one,
+//! along with its signature of that identity, see `ResumeTicket::handover`. Both devices have
+//! to know each other's keys, see `crate::identity::KnownPeers`. The other one resumes the session
+//! with it, signing with its own identity and sending the handover and its own preferences along.
+//! The peer takes the session over to the new device, retargeting its streams,
+//! and ends it with the old one.
+//!
+//! Body of `Resume`: <PORT_SCP(16bits)><SESSION_ID(32bits)><SALT(32 bytes)><PROOF(32 bytes)>
+//! <PUBLIC_KEY(32 bytes)><SIGNATURE(64 bytes)>
+//! <HANDOVER(64 bytes)><PREFERENCES(JSON)>, when taking the session over
+
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::client::Preferences;
+use crate::identity::{self, Identity, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use crate::integrity::{RESUME_SALT_LEN, RESUME_TOKEN_LEN};
+
+/// How long after this side was last running a session it can still be resumed
+pub const RESUME_WINDOW: Duration = Duration::from_secs(30);
+/// Prefix of the hashed data, so the proofs mean nothing outside of a resume
+const PROOF_CONTEXT: &[u8] = b"eye-spy resume ";
+const PROOF_LEN: usize = 32;
+/// Prefix of the signed proofs, see `ResumeTicket::resume_body`
+const SIGNATURE_CONTEXT: &[u8] = b"eye-spy resumed by ";
+/// Prefix of the signed identities the session is handed over to, see `ResumeTicket::handover`
+const HANDOVER_CONTEXT: &[u8] = b"eye-spy handover ";
+
+/// Port, salt, key and preferences of the peer's valid `Resume`, see `ResumeTicket::verify`
+type VerifiedResume<'a> = (u16, [u8; RESUME_SALT_LEN], [u8; PUBLIC_KEY_LEN], &'a [u8]);
+
+/// What's needed to resume a session
+/// * `peer` - address of the peer's listener
+/// * `requester` - this side requested the session
+/// * `preferences` - our preferences in the session, with the ports the peer sends the streams to
+/// * `peer_preferences` - the preferences the peer shared
+/// * `peer_key` - the key the peer proved to hold, None if it didn't share one
+/// * `alive_at` - seconds since the UNIX epoch this side was last known running the session
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResumeTicket {
+    pub peer: SocketAddr,
+    pub session_id: u32,
+    pub requester: bool,
+    pub preferences: Preferences,
+    pub peer_preferences: Preferences,
+    pub peer_key: Option<[u8; PUBLIC_KEY_LEN]>,
+    token: [u8; RESUME_TOKEN_LEN],
+    alive_at: u64,
+}
+
+impl Debug for ResumeTicket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the token
+        f.debug_struct("ResumeTicket")
+            .field("peer", &self.peer)
+            .field("session_id", &self.session_id)
+            .field("alive_at", &self.alive_at)
+            .finish_non_exhaustive()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl ResumeTicket {
+    pub(crate) fn new(
+        peer: SocketAddr,
+        session_id: u32,
+        requester: bool,
+        preferences: Preferences,
+        peer_preferences: Preferences,
+        peer_key: Option<[u8; PUBLIC_KEY_LEN]>,
+        token: [u8; RESUME_TOKEN_LEN],
+    ) -> Self {
+        Self {
+            peer,
+            session_id,
+            requester,
+            preferences,
+            peer_preferences,
+            peer_key,
+            token,
+            alive_at: now(),
+        }
+    }
+    /// Mark the session as running on this side now, see `is_fresh`
+    pub fn refresh(&mut self) {
+        self.alive_at = now();
+    }
+    /// The session ran on this side within `RESUME_WINDOW`
+    pub fn is_fresh(&self) -> bool {
+        now().saturating_sub(self.alive_at) <= RESUME_WINDOW.as_secs()
+    }
+    pub(crate) fn token(&self) -> &[u8; RESUME_TOKEN_LEN] {
+        &self.token
+    }
+    /// Body of `Resume`, proving this side holds the token of the session, signed by `identity`.
+    /// When taking the session over, `takeover` holds the handover of the device
+    /// that handed it over and our preferences
+    pub(crate) fn resume_body(
+        &self,
+        identity: &Identity,
+        port_scp: u16,
+        salt: &[u8; RESUME_SALT_LEN],
+        takeover: Option<(&[u8; SIGNATURE_LEN], &[u8])>,
+    ) -> Vec<u8> {
+        let (handover, preferences) = takeover.unzip();
+        let preferences = preferences.unwrap_or_default();
+        let proof = self.proof(self.requester, port_scp, salt, preferences);
+        let signature = identity.sign(&[SIGNATURE_CONTEXT, &proof].concat());
+        [
+            &port_scp.to_le_bytes()[..],
+            &self.session_id.to_le_bytes(),
+            salt,
+            &proof,
+            &identity.public_key(),
+            &signature,
+            handover.map_or(&[][..], |handover| &handover[..]),
+            preferences,
+        ]
+        .concat()
+    }
+    /// The port, the salt, the key and the preferences of the peer's `Resume`, if it proves
+    /// the peer holds the token and is the one of the session, or the device it was handed
+    /// over to. The preferences are empty unless the device takes over
+    pub(crate) fn verify<'a>(&self, body: &'a [u8]) -> Option<VerifiedResume<'a>> {
+        let (port, body) = body.split_first_chunk::<2>()?;
+        let (session_id, body) = body.split_first_chunk::<4>()?;
+        let (salt, body) = body.split_first_chunk::<RESUME_SALT_LEN>()?;
+        let (proof, body) = body.split_first_chunk::<PROOF_LEN>()?;
+        let (public_key, body) = body.split_first_chunk::<PUBLIC_KEY_LEN>()?;
+        let (signature, body) = body.split_first_chunk::<SIGNATURE_LEN>()?;
+        let (handover, preferences) = match body {
+            [] => (None, body),
+            body => {
+                let (handover, preferences) = body.split_first_chunk::<SIGNATURE_LEN>()?;
+                (Some(handover), preferences)
+            }
+        };
+        let port = u16::from_le_bytes(*port);
+        if u32::from_le_bytes(*session_id) != self.session_id {
+            return None;
+        }
+        // The peer proves the other role, so our own proof cannot be reflected back at us
+        let expected = self.proof(!self.requester, port, salt, preferences);
+        // Compared in constant time
+        let differs = expected
+            .iter()
+            .zip(proof)
+            .fold(0, |differs, (a, b)| differs | (a ^ b));
+        if differs != 0
+            || !identity::verify(public_key, &[SIGNATURE_CONTEXT, proof].concat(), signature)
+        {
+            return None;
+        }
+        // The token alone doesn't do, it's the identity of the session or the one it handed over to
+        let peer_key = self.peer_key?;
+        let known = match handover {
+            None => *public_key == peer_key,
+            Some(handover) => {
+                identity::verify(&peer_key, &self.handover_data(public_key), handover)
+            }
+        };
+        known.then_some((port, *salt, *public_key, preferences))
+    }
+    /// Our signature of the identity of the device we hand the session over to,
+    /// it shows it to the peer when taking the session over
+    pub(crate) fn handover(
+        &self,
+        identity: &Identity,
+        successor: &[u8; PUBLIC_KEY_LEN],
+    ) -> [u8; SIGNATURE_LEN] {
+        identity.sign(&self.handover_data(successor))
+    }
+    fn handover_data(&self, successor: &[u8; PUBLIC_KEY_LEN]) -> Vec<u8> {
+        [HANDOVER_CONTEXT, &self.session_id.to_le_bytes(), successor].concat()
+    }
+    fn proof(
+        &self,
+        requester: bool,
+        port_scp: u16,
+        salt: &[u8; RESUME_SALT_LEN],
+        preferences: &[u8],
+    ) -> [u8; PROOF_LEN] {
+        let role: &[u8] = if requester {
+            b"requester"
+        } else {
+            b"responder"
+        };
+        Sha256::new()
+            .chain_update(PROOF_CONTEXT)
+            .chain_update(role)
+            .chain_update(self.token)
+            .chain_update(self.session_id.to_le_bytes())
+            .chain_update(port_scp.to_le_bytes())
+            .chain_update(salt)
+            .chain_update(preferences)
+            .finalize()
+            .into()
+    }
+    /// Saves the ticket to `path`, readable by the owner only as it holds the token
+    /// # Errors
+    /// Returns an error if the file cannot be written
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)?
+            .write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+    /// The ticket saved at `path`, None if there's none
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read or parsed
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Forget the ticket saved at `path`, once its session is over
+    /// # Errors
+    /// Returns an error if the file exists but cannot be removed
+    pub fn remove(path: &Path) -> std::io::Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::ResumeTicket;
+    use crate::client::Preferences;
+    use crate::identity::Identity;
+
+    /// The tickets of both sides of a session, each with the key of the other one
+    fn tickets(requester: &Identity, responder: &Identity) -> (ResumeTicket, ResumeTicket) {
+        let ticket = |requester, peer: &Identity| {
+            ResumeTicket::new(
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 60102),
+                7,
+                requester,
+                Preferences::default(),
+                Preferences::default(),
+                Some(peer.public_key()),
+                [3; 32],
+            )
+        };
+        (ticket(true, responder), ticket(false, requester))
+    }
+
+    #[test]
+    fn test_resume_proof() {
+        let (requester_id, responder_id) = (Identity::generate(), Identity::generate());
+        let (requester, responder) = tickets(&requester_id, &responder_id);
+        let key = requester_id.public_key();
+        let body = requester.resume_body(&requester_id, 60103, &[9; 32], None);
+        assert_eq!(
+            responder.verify(&body),
+            Some((60103, [9; 32], key, &b""[..]))
+        );
+        // Reflected back, or for another session
+        assert_eq!(requester.verify(&body), None);
+        let mut other = responder.clone();
+        other.session_id = 8;
+        assert_eq!(other.verify(&body), None);
+        // Tampered port, truncated signature
+        let mut tampered = body.clone();
+        tampered[0] ^= 1;
+        assert_eq!(responder.verify(&tampered), None);
+        assert_eq!(responder.verify(&body[..body.len() - 1]), None);
+        // The token alone doesn't do, it has to be the identity of the session
+        let impostor = Identity::generate();
+        let body = requester.resume_body(&impostor, 60103, &[9; 32], None);
+        assert_eq!(responder.verify(&body), None);
+        let mut unknown = responder.clone();
+        unknown.peer_key = None;
+        let body = requester.resume_body(&requester_id, 60103, &[9; 32], None);
+        assert_eq!(unknown.verify(&body), None);
+    }
+    #[test]
+    fn test_resume_handed_over() {
+        let (requester_id, responder_id) = (Identity::generate(), Identity::generate());
+        let (requester, responder) = tickets(&requester_id, &responder_id);
+        let device = Identity::generate();
+        let handover = requester.handover(&requester_id, &device.public_key());
+        // Taking over, the preferences are proven along
+        let body = requester.resume_body(&device, 60103, &[9; 32], Some((&handover, b"{}")));
+        assert_eq!(
+            responder.verify(&body),
+            Some((60103, [9; 32], device.public_key(), &b"{}"[..]))
+        );
+        let mut tampered = body.clone();
+        *tampered.last_mut().unwrap() = b']';
+        assert_eq!(responder.verify(&tampered), None);
+        // Handed over to another device, or by someone else than the peer
+        let impostor = Identity::generate();
+        let body = requester.resume_body(&impostor, 60103, &[9; 32], Some((&handover, b"{}")));
+        assert_eq!(responder.verify(&body), None);
+        let forged = requester.handover(&impostor, &impostor.public_key());
+        let body = requester.resume_body(&impostor, 60103, &[9; 32], Some((&forged, b"{}")));
+        assert_eq!(responder.verify(&body), None);
+    }
+    #[test]
+    fn test_ticket_persists() {
+        let path = std::env::temp_dir().join(format!("eye-spy-resume-{}", std::process::id()));
+        assert!(ResumeTicket::load(&path).unwrap().is_none());
+        let (ticket, _) = tickets(&Identity::generate(), &Identity::generate());
+        assert!(ticket.is_fresh());
+        ticket.save(&path).unwrap();
+        let loaded = ResumeTicket::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.token(), ticket.token());
+        assert_eq!(loaded.session_id, 7);
+
+        let mut stale = loaded;
+        stale.alive_at -= 60;
+        assert!(!stale.is_fresh());
+        ResumeTicket::remove(&path).unwrap();
+        ResumeTicket::remove(&path).unwrap();
+        assert!(ResumeTicket::load(&path).unwrap().is_none());
+    }
+}
@@ -3,6 +3,17 @@
 
 use std::fmt::Display;
 
+use crate::client::VideoMode;
+
+/// Version of the protocol, answered to `ScpCommand::Probe`.
+/// Bumped whenever peers of different versions cannot have a session anymore
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Bytes of the largest message taken, sealed and framed. Room for a `StillImage`
+/// of the whole camera frame, and for the escaped file chunks and `ClipboardText`s,
+/// which can double in size at worst
+pub const MAX_MESSAGE_LEN: usize = 512 * 1024;
+
 const SCP_HEADER: &[u8] = b"12345654321\n";
 const SCP_END: &[u8] = b"1234564321\n";
 
@@ -42,6 +53,26 @@ impl ScpMessage {
         .cloned()
         .collect()
     }
+    /// Length of the first complete message in `raw`, None if it's not all there yet.
+    /// Lets many messages share a single stream
+    pub fn frame_len(raw: &[u8]) -> Option<usize> {
+        Self::frame_len_from(raw, 0)
+    }
+    /// Like `frame_len`, the end only looked for from `from` on, i.e. past the bytes already
+    /// looked through, see `scanned_len`
+    pub fn frame_len_from(raw: &[u8], from: usize) -> Option<usize> {
+        const BODY_START: usize = SCP_HEADER.len() + std::mem::size_of::<ScpCommand>();
+        let start = from.max(BODY_START);
+        let body = raw.get(start..)?;
+        body.windows(SCP_END.len() + 1)
+            .position(|end| end[0] == b'\n' && &end[1..] == SCP_END)
+            .map(|body_len| start + body_len + 1 + SCP_END.len())
+    }
+    /// Where to look for the end from once more bytes come, after `frame_len_from` found
+    /// none in `len` bytes. The end may have started in the last ones
+    pub fn scanned_len(len: usize) -> usize {
+        len.saturating_sub(SCP_END.len())
+    }
     pub fn deserialize(raw: &[u8]) -> Result<ScpMessage, SCPParseError> {
         const H_LEN: usize = SCP_HEADER.len();
         const C_LEN: usize = std::mem::size_of::<ScpCommand>();
@@ -56,11 +87,7 @@ impl ScpMessage {
             .split_first_chunk::<C_LEN>()
             .ok_or(SCPParseError::MissingCommand)?;
 
-        // Shouldn't panic: already checked for SCP_END
-        let command;
-        unsafe {
-            command = std::mem::transmute::<[u8; C_LEN], ScpCommand>(*command_raw);
-        }
+        let command = ScpCommand::try_from(u16::from_le_bytes(*command_raw))?;
         let (body_raw, end) = raw.split_at(raw.len() - H_LEN);
         // End must contains newline and SCP_END
         if &end[1..] != SCP_END {
@@ -91,6 +118,17 @@ pub enum ScpCommand {
     SimpleMessage,
 
     End,
+    /// Ask what the peer supports without requesting a session, see `crate::probe`
+    Probe,
+    /// Capabilities of the peer, the answer to `Probe`
+    ProbeReply,
+    /// The callee's user accepted the ringing call, sent before `Ready`
+    Accept,
+    /// Come back to a session after a restart, see `crate::resume`
+    Resume,
+    /// Hand the session over to another device of the user, with the ticket to resume it
+    /// sealed to the device's identity, see `crate::resume`
+    Transfer,
 }
 
 impl ScpCommand {
@@ -105,10 +143,229 @@ impl ScpCommand {
             ScpCommand::PreferencesShare => true,
             ScpCommand::Ready => false,
             ScpCommand::End => false,
+            ScpCommand::Probe => false,
+            ScpCommand::ProbeReply => true,
+            ScpCommand::Accept => false,
+            ScpCommand::Resume => true,
+            ScpCommand::Transfer => true,
+        }
+    }
+}
+
+impl TryFrom<u16> for ScpCommand {
+    type Error = SCPParseError;
+
+    /// `SCPParseError::UnknownCommand` for the values no command has, i.e. of newer peers
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        // In the order of the discriminants
+        const COMMANDS: [ScpCommand; 14] = [
+            ScpCommand::Start,
+            ScpCommand::OwnKeyRequired,
+            ScpCommand::ReqGenerateKey,
+            ScpCommand::AckGenerateKey,
+            ScpCommand::KeyShare,
+            ScpCommand::PreferencesShare,
+            ScpCommand::Ready,
+            ScpCommand::SimpleMessage,
+            ScpCommand::End,
+            ScpCommand::Probe,
+            ScpCommand::ProbeReply,
+            ScpCommand::Accept,
+            ScpCommand::Resume,
+            ScpCommand::Transfer,
+        ];
+        COMMANDS
+            .get(usize::from(value))
+            .copied()
+            .ok_or(SCPParseError::UnknownCommand(value))
+    }
+}
+
+/// Requests sent to the peer during a session, in the body of `ScpCommand::SimpleMessage`.
+/// Body: <SUBTYPE(8bits)><ARGUMENT(8bits, 16bits LE for `ProbeAck`), if any>.
+/// `VideoMode` carries <WIDTH(16bits LE)><HEIGHT(16bits LE)><FPS(16bits LE)>,
+/// `BandwidthReport` <PACKETS(16bits LE)><SPAN_US(32bits LE)>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Send a keyframe, i.e. the stream cannot be decoded after losing packets
+    RequestKeyframe,
+    /// Hold the notifications back (true) or show them again (false)
+    MuteNotifications(bool),
+    /// Send the reduced stream (true) or the full one (false)
+    LowResolution(bool),
+    /// A probe of the path MTU with this much packet data came through
+    ProbeAck(u16),
+    /// The video is sent in this mode from now on, i.e. the camera was switched mid-call
+    VideoMode(VideoMode),
+    /// The sender is on a weak link, send the cheapest video (true) or the usual one again (false)
+    LowBandwidth(bool),
+    /// This many packets of a bandwidth probe came through, the first to the last `span_us`
+    /// microseconds apart
+    BandwidthReport { packets: u16, span_us: u32 },
+    /// The video doesn't get through, send a `StillImage` every so often (true) or stop (false)
+    StillImages(bool),
+}
+
+impl ControlMessage {
+    const REQUEST_KEYFRAME: u8 = 0;
+    const MUTE_NOTIFICATIONS: u8 = 1;
+    const LOW_RESOLUTION: u8 = 2;
+    const PROBE_ACK: u8 = 3;
+    const VIDEO_MODE: u8 = 4;
+    const LOW_BANDWIDTH: u8 = 5;
+    const BANDWIDTH_REPORT: u8 = 6;
+    const STILL_IMAGES: u8 = 7;
+
+    pub fn to_body(self) -> Vec<u8> {
+        match self {
+            ControlMessage::RequestKeyframe => vec![Self::REQUEST_KEYFRAME],
+            ControlMessage::MuteNotifications(mute) => vec![Self::MUTE_NOTIFICATIONS, mute as u8],
+            ControlMessage::LowResolution(low) => vec![Self::LOW_RESOLUTION, low as u8],
+            ControlMessage::LowBandwidth(low) => vec![Self::LOW_BANDWIDTH, low as u8],
+            ControlMessage::StillImages(stills) => vec![Self::STILL_IMAGES, stills as u8],
+            ControlMessage::ProbeAck(size) => {
+                let [low, high] = size.to_le_bytes();
+                vec![Self::PROBE_ACK, low, high]
+            }
+            ControlMessage::VideoMode(mode) => [
+                &[Self::VIDEO_MODE][..],
+                &mode.width.to_le_bytes(),
+                &mode.height.to_le_bytes(),
+                &mode.fps.to_le_bytes(),
+            ]
+            .concat(),
+            ControlMessage::BandwidthReport { packets, span_us } => [
+                &[Self::BANDWIDTH_REPORT][..],
+                &packets.to_le_bytes(),
+                &span_us.to_le_bytes(),
+            ]
+            .concat(),
+        }
+    }
+    /// None if the subtype is unknown, i.e. sent by a newer peer, or the argument is missing
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        let (subtype, argument) = body.split_first()?;
+        let flag = || argument.first().map(|a| *a != 0);
+        match *subtype {
+            Self::REQUEST_KEYFRAME => Some(ControlMessage::RequestKeyframe),
+            Self::MUTE_NOTIFICATIONS => flag().map(ControlMessage::MuteNotifications),
+            Self::LOW_RESOLUTION => flag().map(ControlMessage::LowResolution),
+            Self::LOW_BANDWIDTH => flag().map(ControlMessage::LowBandwidth),
+            Self::STILL_IMAGES => flag().map(ControlMessage::StillImages),
+            Self::PROBE_ACK => {
+                let size = argument.first_chunk::<2>()?;
+                Some(ControlMessage::ProbeAck(u16::from_le_bytes(*size)))
+            }
+            Self::VIDEO_MODE => {
+                let field = |i: usize| {
+                    argument
+                        .get(i * 2..i * 2 + 2)
+                        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                };
+                Some(ControlMessage::VideoMode(VideoMode {
+                    width: field(0)?,
+                    height: field(1)?,
+                    fps: field(2)?,
+                }))
+            }
+            Self::BANDWIDTH_REPORT => {
+                let packets = argument.first_chunk::<2>()?;
+                let span_us = argument.get(2..)?.first_chunk::<4>()?;
+                Some(ControlMessage::BandwidthReport {
+                    packets: u16::from_le_bytes(*packets),
+                    span_us: u32::from_le_bytes(*span_us),
+                })
+            }
+            _ => None,
         }
     }
 }
 
+/// A JPEG of the camera, sent in place of the video while the peer asks for
+/// `ControlMessage::StillImages`. Goes in the body of `ScpCommand::SimpleMessage` too,
+/// so the peers that don't know it drop it like any unknown request.
+/// Body: <SUBTYPE(8bits)><JPEG>
+#[derive(Clone, PartialEq, Eq)]
+pub struct StillImage(pub Vec<u8>);
+
+impl StillImage {
+    const SUBTYPE: u8 = 8;
+
+    pub fn to_body(&self) -> Vec<u8> {
+        [&[Self::SUBTYPE][..], &self.0].concat()
+    }
+    /// None if the body is a `ControlMessage`, or the image is missing
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        match body.split_first() {
+            Some((&Self::SUBTYPE, jpeg)) if !jpeg.is_empty() => Some(Self(jpeg.to_vec())),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for StillImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StillImage({} bytes)", self.0.len())
+    }
+}
+
+/// Text copied on one side, i.e. a link, for the peer's user to put into their clipboard.
+/// Goes in the body of `ScpCommand::SimpleMessage` too, like `StillImage`.
+/// Body: <SUBTYPE(8bits)><TEXT(UTF-8)>, the text has its newlines escaped, see `escape`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClipboardText(pub String);
+
+impl ClipboardText {
+    const SUBTYPE: u8 = 10;
+    /// Bytes of the longest text sent, the larger things go as files
+    pub const MAX_LEN: usize = 64 * 1024;
+
+    pub fn to_body(&self) -> Vec<u8> {
+        [&[Self::SUBTYPE][..], &escape(self.0.as_bytes())].concat()
+    }
+    /// None if the body is something else, or the text is empty, too long or not UTF-8
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        let (&Self::SUBTYPE, escaped) = body.split_first()? else {
+            return None;
+        };
+        let text = String::from_utf8(unescape(escaped)?).ok()?;
+        (!text.is_empty() && text.len() <= Self::MAX_LEN).then_some(Self(text))
+    }
+}
+
+/// Escapes the newlines of the data, and itself
+const ESCAPE: u8 = 0x1B;
+
+/// The data with its newlines escaped, so it cannot end an `ScpMessage` early
+pub(crate) fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len() + data.len() / 64);
+    for &byte in data {
+        match byte {
+            b'\n' => escaped.extend([ESCAPE, b'n']),
+            ESCAPE => escaped.extend([ESCAPE, ESCAPE]),
+            byte => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+/// None if an escape is cut short or unknown
+pub(crate) fn unescape(escaped: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.iter();
+    while let Some(&byte) = bytes.next() {
+        data.push(match byte {
+            ESCAPE => match *bytes.next()? {
+                b'n' => b'\n',
+                ESCAPE => ESCAPE,
+                _ => return None,
+            },
+            byte => byte,
+        });
+    }
+    Some(data)
+}
+
 #[allow(unused)]
 #[derive(Debug, PartialEq)]
 pub enum SCPParseError {
@@ -116,6 +373,8 @@ pub enum SCPParseError {
     MissingBody,
     MissingCommand,
     MissingEnd,
+    /// A command of this value doesn't exist, i.e. sent by a newer peer
+    UnknownCommand(u16),
 }
 impl Display for SCPParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -131,7 +390,8 @@ impl Display for SCPParseError {
                 "No ending: SCP message should end with {}",
                 String::from_utf8_lossy(SCP_END)
             )),
-            SCPParseError::MissingCommand => f.write_str("Missing command: SCP message requires a command (1 byte + newline) after the header.")
+            SCPParseError::MissingCommand => f.write_str("Missing command: SCP message requires a command (1 byte + newline) after the header."),
+            SCPParseError::UnknownCommand(command) => write!(f, "Unknown command: {command}"),
         }
     }
 }
@@ -141,7 +401,8 @@ impl std::error::Error for SCPParseError {}
 #[cfg(test)]
 mod tests_scp {
 
-    use crate::scp::{SCPParseError, ScpMessage};
+    use crate::client::VideoMode;
+    use crate::scp::{ClipboardText, ControlMessage, SCPParseError, ScpMessage, StillImage};
 
     use super::{ScpCommand, SCP_END, SCP_HEADER};
 
@@ -185,4 +446,96 @@ mod tests_scp {
         assert!(msg.is_err());
         assert!(msg.is_err_and(|e| e == SCPParseError::MissingBody))
     }
+    #[test]
+    fn test_unknown_command() {
+        for value in 0..14 {
+            let command = ScpCommand::try_from(value).unwrap();
+            assert_eq!(command as u16, value);
+        }
+        for value in [14, 0x0100, u16::MAX] {
+            let raw = [SCP_HEADER, &value.to_le_bytes(), b"Hello\n", SCP_END].concat();
+            assert_eq!(
+                ScpMessage::deserialize(&raw).unwrap_err(),
+                SCPParseError::UnknownCommand(value)
+            );
+        }
+    }
+    #[test]
+    fn test_frame_len() {
+        let first = ScpMessage::new(ScpCommand::Ready, b"").as_bytes();
+        let second = get_correct_message();
+        let stream = [&first[..], &second].concat();
+        assert_eq!(ScpMessage::frame_len(&stream), Some(first.len()));
+        assert_eq!(
+            ScpMessage::frame_len(&stream[first.len()..]),
+            Some(second.len())
+        );
+        assert_eq!(ScpMessage::frame_len(&second[..second.len() - 1]), None);
+        // Picking up after the bytes looked through, the end split between them
+        for cut in 0..second.len() {
+            let from = ScpMessage::scanned_len(cut);
+            assert_eq!(
+                ScpMessage::frame_len_from(&second, from),
+                Some(second.len())
+            );
+        }
+    }
+    #[test]
+    fn test_control_message_roundtrip() {
+        for control in [
+            ControlMessage::RequestKeyframe,
+            ControlMessage::MuteNotifications(true),
+            ControlMessage::LowResolution(false),
+            ControlMessage::ProbeAck(1464),
+            ControlMessage::LowBandwidth(true),
+            ControlMessage::VideoMode(VideoMode {
+                width: 320,
+                height: 240,
+                fps: 15,
+            }),
+            ControlMessage::BandwidthReport {
+                packets: 48,
+                span_us: 12_500,
+            },
+            ControlMessage::StillImages(true),
+        ] {
+            let bytes = ScpMessage::new(ScpCommand::SimpleMessage, &control.to_body()).as_bytes();
+            let msg = ScpMessage::deserialize(&bytes).unwrap();
+            assert_eq!(ControlMessage::from_body(&msg.body), Some(control));
+        }
+        assert_eq!(ControlMessage::from_body(&[0xFF]), None);
+        assert_eq!(ControlMessage::from_body(&[2]), None);
+        assert_eq!(ControlMessage::from_body(&[5]), None);
+        assert_eq!(ControlMessage::from_body(&[3, 0xB8]), None);
+        assert_eq!(ControlMessage::from_body(&[4, 0x40, 0x01, 0xF0]), None);
+        assert_eq!(ControlMessage::from_body(&[6, 0x30, 0, 0xD4, 0x30]), None);
+        assert_eq!(ControlMessage::from_body(&[7]), None);
+    }
+    #[test]
+    fn test_still_image() {
+        let still = StillImage(vec![0xFF, 0xD8, 0xFF, 0xD9]);
+        let bytes = ScpMessage::new(ScpCommand::SimpleMessage, &still.to_body()).as_bytes();
+        let msg = ScpMessage::deserialize(&bytes).unwrap();
+        assert_eq!(StillImage::from_body(&msg.body), Some(still));
+        // Neither is mistaken for the other
+        assert_eq!(ControlMessage::from_body(&msg.body), None);
+        let control = ControlMessage::StillImages(false).to_body();
+        assert_eq!(StillImage::from_body(&control), None);
+        assert_eq!(StillImage::from_body(&[8]), None);
+    }
+    #[test]
+    fn test_clipboard_text() {
+        // The newlines don't end the message early
+        let text = ClipboardText("https://example.org\n1234564321\n\u{1B}zażółć".to_string());
+        let bytes = ScpMessage::new(ScpCommand::SimpleMessage, &text.to_body()).as_bytes();
+        let msg = ScpMessage::deserialize(&bytes).unwrap();
+        assert_eq!(ClipboardText::from_body(&msg.body), Some(text));
+        assert_eq!(ControlMessage::from_body(&msg.body), None);
+        assert_eq!(StillImage::from_body(&msg.body), None);
+
+        assert_eq!(ClipboardText::from_body(&[10]), None);
+        assert_eq!(ClipboardText::from_body(&[10, 0xFF, 0xFE]), None);
+        let long = ClipboardText("a".repeat(ClipboardText::MAX_LEN + 1));
+        assert_eq!(ClipboardText::from_body(&long.to_body()), None);
+    }
 }
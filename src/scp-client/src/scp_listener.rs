@@ -2,121 +2,278 @@
 //! It manages internal state, listens to ConnectionAction events it has to respond to
 //! and emits ConnectionEvent when something happens.
 
-use std::io::{Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
 
+use rand_core::{OsRng, RngCore};
 use serde::Deserialize;
 use serde_json::Deserializer;
+use tracing::{info, info_span, warn, Span};
 
 use crate::client::{
-    ActionConnector, ConnectionAction, ConnectionEvent, ConnectionSetings, EventConnector,
-    Preferences, SessionConfig,
+    new_session_id, ActionConnector, ConnectionAction, ConnectionEvent, EncryptionMethod,
+    EventConnector, NegotiatedCodecs, Preferences, ScpError, SessionConfig, StreamEndpoints,
+    VideoEncoding, VideoMode, DEFAULT_RING_TIMEOUT,
 };
+use crate::connection::ScpConnection;
+use crate::file_transfer::{FileMessage, FileTransfers, TransferEvent};
+use crate::identity::{
+    self, Identity, KnownPeers, PeerIdentity, PeerTrust, PUBLIC_KEY_LEN, SIGNATURE_LEN,
+};
+use crate::integrity::{KeyExchange, SessionKey, EPHEMERAL_KEY_LEN, RESUME_SALT_LEN};
+use crate::link_local::{self, with_port};
 use crate::misc::{self};
-use crate::scp::{ScpCommand, ScpMessage};
+use crate::peer_list::SharedPeerList;
+use crate::probe::PeerCapabilities;
+use crate::resume::ResumeTicket;
+use crate::scp::{
+    ClipboardText, ControlMessage, ScpCommand, ScpMessage, StillImage, PROTOCOL_VERSION,
+};
+use crate::session::{self, ConnectionState, Effect, Input};
 const TCP_TIMEOUT: Duration = Duration::from_secs(1);
 const EVENT_LOOP_MIN_TIME: Duration = Duration::from_millis(30);
-/// The current state of the connection.
-/// In an ideal world, it should go from top to bottom
-#[derive(PartialEq, Debug, Clone, Copy)]
-enum ConnectionState {
-    /// Not connecting anywhere
-    Free,
-    /// Performing a handshake - initialized from either side
-    Handshake,
-    /// Awaiting the confirmation - either waiting for user to accept or peer to do so
-    ConfigShared,
-    Awaiting,
-    /// Connection fully established
-    Connected,
+/// Open connections kept at most, the oldest ones but the one of the session are closed first
+const MAX_CONNECTIONS: usize = 16;
+/// Body of the `End` giving up on a session nobody answered
+const NO_ANSWER: &[u8] = b"NoAnswer";
+/// Body of the `End` of a session handed over to another device
+const TRANSFERRED: &[u8] = b"Transferred";
+
+/// A session about to begin, see `Effect::Begin`
+/// * `peer` - address of the peer's listener
+/// * `session_id` - ID picked by the side requesting the session
+/// * `connection` - remote address of the connection the peer requested the session over
+/// * `peer_key` - ephemeral key the peer requested the session with
+#[derive(Debug)]
+struct PendingSession {
+    peer: SocketAddr,
+    session_id: u32,
+    connection: Option<SocketAddr>,
+    peer_key: Option<[u8; EPHEMERAL_KEY_LEN]>,
 }
-/// An overly complex state machine that manages the connection with Scp protocol
+
+/// The peer's valid `Resume`, see `Effect::Restore`
+/// * `connection` - remote address of the connection it came over
+/// * `port` - port of the peer's listener
+/// * `salt` - salt of the `Resume` messages of the resumed session
+/// * `public_key` - identity the peer signed it with
+/// * `preferences` - sent by the devices taking the session over only
+#[derive(Debug)]
+struct PendingResume {
+    connection: SocketAddr,
+    port: u16,
+    salt: [u8; RESUME_SALT_LEN],
+    public_key: [u8; PUBLIC_KEY_LEN],
+    preferences: Option<Preferences>,
+}
+
+/// Manages the connection with Scp protocol, following the state machine in `crate::session`
 #[derive(Debug)]
 pub struct ScpListener {
     action: ActionConnector,
     event: EventConnector,
+    /// Requests received from the peer
+    controls: Sender<ControlMessage>,
+    /// Still images received from the peer, dropped if None
+    pub(crate) stills: Option<Sender<StillImage>>,
+    /// Texts the peer sent for the clipboard, dropped if None
+    pub(crate) clipboard: Option<Sender<ClipboardText>>,
+    /// Files sent and received in the session, see `crate::file_transfer`
+    pub(crate) transfers: FileTransfers,
+    /// What happened to the files, dropped if None
+    pub(crate) transfer_events: Option<Sender<TransferEvent>>,
     communicating_with: Option<SocketAddr>,
     got_preferences: Option<Preferences>,
+    pending_session: Option<PendingSession>,
+    /// Preferences just received from the peer, see `Effect::StorePreferences`
+    pending_preferences: Option<Preferences>,
+    /// ID of the current session, picked by the side requesting it. 0 if none
+    session_id: u32,
+    identity: Identity,
+    known_peers: KnownPeers,
+    /// Peers allowed or denied to request sessions
+    peer_list: SharedPeerList,
+    /// Identity the peer of the current session proved to hold
+    peer_identity: Option<PeerIdentity>,
     state: ConnectionState,
     preferences: Preferences,
     pub tcp_listener: TcpListener,
-    buf: Vec<u8>,
+    /// Listeners on the IPv6 link-local addresses, see `crate::link_local`
+    link_local_listeners: Vec<TcpListener>,
+    /// Connections with the peers, accepted or opened, kept open for more messages
+    connections: Vec<ScpConnection>,
+    /// Remote address of the connection the messages of the session go over
+    session_connection: Option<SocketAddr>,
+    /// Our ephemeral key for the session, see `crate::integrity`
+    key_exchange: Option<KeyExchange>,
+    /// Ephemeral key the peer requested the session with, to be signed in its `KeyShare`
+    peer_key: Option<[u8; EPHEMERAL_KEY_LEN]>,
+    /// Seals and opens every message but `Start` and `KeyShare` once both keys are known
+    session_key: Option<SessionKey>,
+    /// Span of the current session with a peer. Disabled when not communicating with anyone
+    session_span: Span,
+    /// Modes the camera captures in, answered to probes
+    pub(crate) video_modes: Vec<VideoMode>,
+    /// How long a session rings before it's given up on, see `Input::RingTimeout`
+    pub(crate) ring_timeout: Duration,
+    /// When the current session started waiting for an answer, if it does
+    ringing_since: Option<Instant>,
+    /// The session to resume, or the one the peer may come back to, see `crate::resume`
+    resumable: Option<ResumeTicket>,
+    pending_resume: Option<PendingResume>,
+    /// Salt of the `Resume` messages of the current session, if it was resumed
+    resume_salt: Option<[u8; RESUME_SALT_LEN]>,
+    /// The session to resume was handed over by another device, which signed our identity,
+    /// see `ResumeTicket::handover`. It goes along with our preferences
+    handover: Option<[u8; SIGNATURE_LEN]>,
 }
 impl ScpListener {
     pub fn new(
         action: ActionConnector,
         event: EventConnector,
+        controls: Sender<ControlMessage>,
         mut preferences: Preferences,
-    ) -> Self {
-        let addr = misc::get_local_ip()
-            .or_else(|| {
-                log::warn!("No local address found for ScpClient. Using Loopback address.");
-                Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
-            })
-            .unwrap();
+        identity: Identity,
+        known_peers: KnownPeers,
+        peer_list: SharedPeerList,
+    ) -> Result<Self, ScpError> {
+        let addr = misc::get_local_ip().unwrap_or_else(|| {
+            warn!("No local address found for ScpClient. Using Loopback address.");
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        });
         let sock_addr = SocketAddr::new(addr, preferences.port_scp);
-        let listener = TcpListener::bind(sock_addr)
-            .unwrap_or_else(|e| panic!("Cannot bind the listener to {sock_addr}.\n{e}"));
+        let listener = TcpListener::bind(sock_addr).map_err(|source| ScpError::Bind {
+            addr: sock_addr,
+            source,
+        })?;
 
         // The OS might have given us a different port when the preferences are set to 0
-        preferences.port_scp = listener.local_addr().unwrap().port();
+        preferences.port_scp = listener.local_addr()?.port();
 
-        listener.set_nonblocking(true).unwrap();
-        Self {
+        listener.set_nonblocking(true)?;
+        let link_local_listeners = link_local::listeners(preferences.port_scp);
+        Ok(Self {
             action,
             event,
+            controls,
+            stills: None,
+            clipboard: None,
+            transfers: FileTransfers::default(),
+            transfer_events: None,
             preferences,
             communicating_with: None,
             got_preferences: None,
+            pending_session: None,
+            pending_preferences: None,
+            session_id: 0,
+            identity,
+            known_peers,
+            peer_list,
+            peer_identity: None,
             state: ConnectionState::Free,
             tcp_listener: listener,
-            buf: Vec::with_capacity(1024),
-        }
+            link_local_listeners,
+            connections: Vec::new(),
+            session_connection: None,
+            key_exchange: None,
+            peer_key: None,
+            session_key: None,
+            session_span: Span::none(),
+            video_modes: Vec::new(),
+            ring_timeout: DEFAULT_RING_TIMEOUT,
+            ringing_since: None,
+            resumable: None,
+            pending_resume: None,
+            resume_salt: None,
+            handover: None,
+        })
     }
     pub fn handle_event_loop(&mut self) -> anyhow::Result<()> {
         // Check the action that need to be taken first
         // The function call shouldn't be expensive as there's a potential connection waiting next
         let start = Instant::now();
+        let span = self.session_span.clone();
+        let _guard = span.enter();
         self.handle_action()?;
+        if self
+            .ringing_since
+            .is_some_and(|since| since.elapsed() >= self.ring_timeout)
+        {
+            self.handle(Input::RingTimeout);
+        }
 
         // Handle any incoming connection
         self.handle_connection()?;
-        self.buf.clear();
+        self.poll_transfers();
         let diff = Instant::now().duration_since(start);
         if diff < EVENT_LOOP_MIN_TIME {
             std::thread::sleep(EVENT_LOOP_MIN_TIME - diff);
         }
         Ok(())
     }
-    /// Handle the action from ConnectionAction.
-    /// If the action
+    /// Handle the actions from ConnectionAction, all of the queued ones in order
     fn handle_action(&mut self) -> anyhow::Result<()> {
-        let mut action = self.action.0.lock().unwrap();
-        if action.is_none() {
-            return Ok(());
-        }
-        // unfortunate clone thanks to the borrow checker
-        let cloned = action.as_ref().unwrap().clone();
-        action.take();
-        drop(action);
-
-        match cloned {
+        let actions = std::mem::take(&mut *self.action.0.lock().unwrap());
+        for action in actions {
+            self.handle_one_action(action)?;
+        }
+        Ok(())
+    }
+    fn handle_one_action(&mut self, action: ConnectionAction) -> anyhow::Result<()> {
+        match action {
             ConnectionAction::AttemptConnection(settings) => {
-                self.on_attempt_connection_action(&settings)
+                self.pending_session = Some(PendingSession {
+                    peer: settings.destination,
+                    session_id: new_session_id(),
+                    connection: None,
+                    peer_key: None,
+                });
+                self.handle(Input::Attempt);
+            }
+            ConnectionAction::Resume(ticket) => {
+                self.resumable = Some(*ticket);
+                self.handle(Input::Resume);
+            }
+            ConnectionAction::Transfer(destination) => self.transfer(destination),
+            ConnectionAction::Cancel => self.handle(Input::Cancel),
+            ConnectionAction::RefuseConnection => self.handle(Input::Refuse),
+            ConnectionAction::AcceptConnection => self.handle(Input::Accept),
+            ConnectionAction::EndConnection => self.handle(Input::End),
+            ConnectionAction::SendControl(control) => self.send_control(control),
+            ConnectionAction::SendStillImage(still) => self.send_still_image(still),
+            ConnectionAction::SendClipboard(text) => self.send_clipboard(text),
+            ConnectionAction::SendFile(id, path) => self.send_file(id, &path),
+            ConnectionAction::AcceptFile(id) => {
+                let reply = self.transfers.accept(id);
+                self.reply_file(reply);
+            }
+            ConnectionAction::DeclineFile(id) => {
+                let reply = self.transfers.decline(id);
+                self.reply_file(reply);
+            }
+            ConnectionAction::CancelFile(id) => {
+                let reply = self.transfers.cancel(id);
+                self.reply_file(reply);
             }
-            ConnectionAction::RefuseConnection => self.end_connection(),
-            ConnectionAction::AcceptConnection => {
-                if self.communicating_with.is_some() {
-                    self.share_config();
-                    self.finalize_connection();
+            ConnectionAction::SetAudioChannels(channels) => {
+                self.preferences.audio_channels = channels;
+            }
+            ConnectionAction::TrustPeer(ip, public_key) => {
+                self.known_peers.trust(ip, &public_key);
+                // The ongoing session's peer, if it's the one
+                if let Some(identity) = self.peer_identity.as_mut().filter(|identity| {
+                    identity.public_key == public_key
+                        && self.communicating_with.is_some_and(|peer| peer.ip() == ip)
+                }) {
+                    identity.trust = PeerTrust::Known;
                 }
+                info!("Trusting the key of {ip} from now on");
             }
-            ConnectionAction::SetPassword(_) => todo!(),
-            ConnectionAction::UnsetPassword => todo!(),
-            ConnectionAction::EndConnection => self.end_connection(),
             ConnectionAction::Terminate => {
-                self.end_connection();
+                self.handle(Input::End);
                 *self.event.0.lock().unwrap() = None;
 
                 self.event.1.notify_one();
@@ -126,146 +283,909 @@ impl ScpListener {
 
         Ok(())
     }
-    /// Handle the incoming connection. If none are present, skip
+    /// Accept the incoming connections and handle the messages received over any connection
     /// If returns error, pass it down to the event loop handler
     fn handle_connection(&mut self) -> anyhow::Result<()> {
-        if let Ok((mut stream, addr_in)) = self.tcp_listener.accept() {
-            // If we are in the middle of smthng and the connection comes from somewhere else:
+        let accepted: Vec<_> = std::iter::once(&self.tcp_listener)
+            .chain(&self.link_local_listeners)
+            .flat_map(|listener| std::iter::from_fn(|| listener.accept().ok()))
+            .collect();
+        for (stream, addr_in) in accepted {
+            let admitted = self.peer_list.lock().unwrap().admits_ip(addr_in.ip());
+            match ScpConnection::new(stream, TCP_TIMEOUT) {
+                // Told off without reading a thing, so the peer doesn't wait for an answer
+                Ok(mut connection) if !admitted => {
+                    info!("Refused the connection of the denied peer {addr_in}");
+                    let _ = connection.send(&ScpMessage::new(ScpCommand::End, b"Denied"));
+                    connection.close();
+                }
+                Ok(connection) => self.add_connection(connection),
+                Err(e) => warn!("Cannot set up the connection from {addr_in}: {e}"),
+            }
+        }
+        let mut received = Vec::new();
+        for connection in &mut self.connections {
+            let addr_in = connection.addr;
+            received.extend(connection.receive().into_iter().map(|msg| (msg, addr_in)));
+        }
+        self.connections.retain(|c| !c.is_closed());
+
+        for (msg, addr_in) in received {
+            // Answered whoever asks and whatever goes on, it doesn't touch the session
+            if msg.command == ScpCommand::Probe {
+                self.on_probe(addr_in);
+                continue;
+            }
+            // If we are in the middle of smthng and the message comes from somewhere else.
+            // Unless it's the device the peer handed the session over to, see `on_resume`
             if self.state != ConnectionState::Free
+                && msg.command != ScpCommand::Resume
                 && self
                     .communicating_with
                     .is_some_and(|sa| sa.ip() != addr_in.ip())
             {
-                let _ =
-                    stream.write(&ScpMessage::new(ScpCommand::End, b"I'my busy my man").as_bytes());
+                if let Some(connection) = self.connections.iter_mut().find(|c| c.addr == addr_in) {
+                    let _ = connection.send(&ScpMessage::new(ScpCommand::End, b"I'my busy my man"));
+                    connection.close();
+                }
+                continue;
+            }
+            self.handle_scp_message(msg, addr_in);
+        }
+        Ok(())
+    }
+    fn add_connection(&mut self, connection: ScpConnection) {
+        if self.connections.len() >= MAX_CONNECTIONS {
+            // Anyone may open connections, they mustn't push out the one of the session
+            let session_connection = self.session_connection;
+            if let Some(oldest) = self
+                .connections
+                .iter()
+                .position(|c| Some(c.addr) != session_connection)
+            {
+                self.connections.remove(oldest).close();
+            }
+        }
+        self.connections.push(connection);
+    }
+    /// Close the connection of the session, the next message opens a new one
+    fn close_session_connection(&mut self) {
+        if let Some(addr) = self.session_connection.take() {
+            for connection in self.connections.iter_mut().filter(|c| c.addr == addr) {
+                connection.close();
+            }
+        }
+    }
 
-                return Ok(());
+    /// Run the input through the state machine and carry out the effects.
+    /// A failed effect feeds its own input, i.e. `Input::PeerUnreachable`
+    fn handle(&mut self, input: Input) {
+        let mut next_input = Some(input);
+        while let Some(input) = next_input.take() {
+            let (state, effects) = session::on(self.state, input);
+            if state != self.state {
+                info!("Session state {:?} -> {state:?} on {input:?}", self.state);
+                let ringing = matches!(
+                    state,
+                    ConnectionState::Ringing
+                        | ConnectionState::Awaiting
+                        | ConnectionState::Resuming
+                );
+                self.ringing_since = ringing.then(Instant::now);
             }
-            if let Ok(size) = stream.read_to_end(&mut self.buf) {
-                if size == 0 {
-                    return Ok(());
+            self.state = state;
+            for effect in effects {
+                if let Err(e) = self.apply(effect) {
+                    warn!("Cannot carry out {effect:?}: {e}");
+                    next_input = Some(Input::PeerUnreachable);
+                    break;
+                }
+            }
+        }
+        // Data of inputs the state machine ignored
+        self.pending_session = None;
+        self.pending_preferences = None;
+        self.pending_resume = None;
+    }
+    fn apply(&mut self, effect: Effect) -> io::Result<()> {
+        match effect {
+            Effect::Begin => {
+                let pending = self.pending_session.take();
+                let connection = pending.as_ref().and_then(|p| p.connection);
+                if self.session_connection != connection {
+                    self.close_session_connection();
+                }
+                self.reset();
+                // A new session, the previous one cannot be come back to
+                self.resumable = None;
+                self.handover = None;
+                if let Some(PendingSession {
+                    peer,
+                    session_id,
+                    peer_key,
+                    ..
+                }) = pending
+                {
+                    self.communicating_with = Some(peer);
+                    self.session_id = session_id;
+                    self.session_connection = connection;
+                    self.peer_key = peer_key;
+                    self.session_span = info_span!("scp_session", peer = %peer);
+                }
+            }
+            Effect::SendStart => {
+                // Start body: <PORT_SCP(16bits)><SESSION_ID(32bits)><EPHEMERAL_KEY(32 bytes)>
+                let key_exchange = self.key_exchange.insert(KeyExchange::new());
+                let body = [
+                    &self.preferences.port_scp.to_le_bytes()[..],
+                    &self.session_id.to_le_bytes(),
+                    &key_exchange.public_key(),
+                ]
+                .concat();
+                self.send(ScpCommand::Start, &body)?;
+                info!(parent: &self.session_span, "Requested a session");
+            }
+            Effect::ShareConfig => {
+                let key_exchange = self.key_exchange.get_or_insert_with(KeyExchange::new);
+                let key_share = self
+                    .identity
+                    .key_share(self.session_id, &key_exchange.public_key());
+                // Responding to a session, the peer's key is known since `Start`
+                if let (Some(peer_key), None) = (self.peer_key, &self.session_key) {
+                    let session_key = key_exchange
+                        .session_key(&peer_key, self.session_id, false)
+                        .ok_or(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Invalid ephemeral key of the peer",
+                        ))?;
+                    // The key share itself goes unsealed, the peer needs it to get the key
+                    self.send(ScpCommand::KeyShare, &key_share)?;
+                    self.session_key = Some(session_key);
+                } else {
+                    // The identity goes first, so it's known by the time the session is established
+                    self.send(ScpCommand::KeyShare, &key_share)?;
+                }
+                let preferences = serde_json::to_vec(&self.preferences)?;
+                self.send(ScpCommand::PreferencesShare, &preferences)?;
+            }
+            Effect::SendReady => self.send(ScpCommand::Ready, b"")?,
+            Effect::SendAccept => self.send(ScpCommand::Accept, b"")?,
+            Effect::Restore => self.restore()?,
+            Effect::SendResume => {
+                let (Some(ticket), Some(salt)) = (&self.resumable, self.resume_salt) else {
+                    return Err(io::ErrorKind::NotFound.into());
+                };
+                // Taking the session over, the peer sends the streams to our ports from now on
+                let preferences = match self.handover {
+                    Some(_) => serde_json::to_vec(&self.preferences)?,
+                    None => Vec::new(),
+                };
+                let takeover = self
+                    .handover
+                    .as_ref()
+                    .map(|handover| (handover, &preferences[..]));
+                let body =
+                    ticket.resume_body(&self.identity, self.preferences.port_scp, &salt, takeover);
+                self.send(ScpCommand::Resume, &body)?;
+            }
+            // The session is over either way
+            Effect::SendEnd => {
+                self.resumable = None;
+                let _ = self.send(ScpCommand::End, b"");
+            }
+            Effect::SendNoAnswer => {
+                let _ = self.send(ScpCommand::End, NO_ANSWER);
+            }
+            // The old device may be gone already, the session goes on either way
+            Effect::SendTransferred => {
+                info!("Session taken over by another device of the peer");
+                let _ = self.send(ScpCommand::End, TRANSFERRED);
+            }
+            Effect::StorePreferences => self.got_preferences = self.pending_preferences.take(),
+            Effect::NotifyIncoming => {
+                if let Some(peer) = self.communicating_with {
+                    info!("Waiting for the user to accept the session");
+                    self.notify(ConnectionEvent::ConnectionIncoming(
+                        peer.ip(),
+                        self.peer_identity,
+                    ));
+                }
+            }
+            Effect::NotifyRinging => {
+                info!("Waiting for the peer to accept the session");
+                if let Some(peer) = self.communicating_with {
+                    self.notify(ConnectionEvent::Ringing(peer.ip(), self.peer_identity));
+                }
+            }
+            Effect::Establish => self.finalize_connection(),
+            Effect::NotifyEnd => {
+                info!("Session ended by the peer");
+                self.notify(ConnectionEvent::ConnectionEnd);
+            }
+            Effect::Fail(e) => {
+                info!("Session failed: {e}");
+                self.notify(ConnectionEvent::ConnectionFailed(e));
+            }
+            Effect::Reset => {
+                self.close_session_connection();
+                self.reset();
+                self.handover = None;
+                // Lost, the peer may come back to it for a while
+                if let Some(ref mut ticket) = self.resumable {
+                    ticket.refresh();
                 }
-
-                let msg = ScpMessage::deserialize(&self.buf[..size]).unwrap();
-                let _ = stream.flush();
-                self.handle_scp_message(msg, addr_in);
             }
         }
         Ok(())
     }
-
-    // Following parts are just handlers for each action, should be inlined really
-    fn on_attempt_connection_action(&mut self, settings: &ConnectionSetings) {
-        if self.state == ConnectionState::Connected {
-            *self.event.0.lock().unwrap() = Some(ConnectionEvent::ConnectionFailed(
-                crate::client::ScpConnectionError::AlreadyConnected,
-            ));
-            self.event.1.notify_one();
-            return;
+    /// Send a message to the peer of the session, over the connection of the session.
+    /// The connection is opened on the first message, and opened again if it was lost
+    fn send(&mut self, command: ScpCommand, body: &[u8]) -> io::Result<()> {
+        let msg = match self.session_key {
+            Some(ref mut key) if !matches!(command, ScpCommand::KeyShare | ScpCommand::Resume) => {
+                ScpMessage::new(command, &key.seal(command, body))
+            }
+            _ => ScpMessage::new(command, body),
+        };
+        let session_connection = self.session_connection;
+        let connection = self
+            .connections
+            .iter_mut()
+            .find(|c| Some(c.addr) == session_connection && !c.is_closed());
+        if let Some(connection) = connection {
+            match connection.send(&msg) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("Lost the connection to the peer, reconnecting: {e}");
+                    connection.close();
+                }
+            }
+        }
+        let peer = self.communicating_with.ok_or(io::ErrorKind::NotConnected)?;
+        let mut connection = ScpConnection::connect(peer, TCP_TIMEOUT)?;
+        connection.send(&msg)?;
+        self.session_connection = Some(connection.addr);
+        self.add_connection(connection);
+        Ok(())
+    }
+    fn notify(&self, event: ConnectionEvent) {
+        *self.event.0.lock().unwrap() = Some(event);
+        self.event.1.notify_one();
+    }
+    fn reset(&mut self) {
+        self.transfers.abort();
+        self.communicating_with = None;
+        self.got_preferences = None;
+        self.session_id = 0;
+        self.peer_identity = None;
+        self.key_exchange = None;
+        self.peer_key = None;
+        self.session_key = None;
+        self.resume_salt = None;
+        self.session_span = Span::none();
+    }
+    /// Bring back the session of `resumable`, over the connection of the peer's `Resume` if any
+    fn restore(&mut self) -> io::Result<()> {
+        let ticket = self.resumable.clone().ok_or(io::ErrorKind::NotFound)?;
+        let pending = self.pending_resume.take();
+        let connection = pending.as_ref().map(|pending| pending.connection);
+        if self.session_connection != connection {
+            self.close_session_connection();
         }
-        let mut stream = TcpStream::connect_timeout(&settings.destination, TCP_TIMEOUT).unwrap();
-        stream
-            .write_all(
-                &ScpMessage::new(ScpCommand::Start, &self.preferences.port_scp.to_le_bytes())
-                    .as_bytes(),
-            )
-            .unwrap();
-        self.communicating_with = Some(settings.destination);
-        self.state = ConnectionState::Handshake;
+        self.reset();
+        let (peer, salt, peer_key, preferences) = match pending {
+            // The peer may listen on another port after a restart, or be another device taking over
+            Some(pending) => (
+                with_port(pending.connection, pending.port),
+                pending.salt,
+                Some(pending.public_key),
+                pending.preferences,
+            ),
+            None => {
+                let mut salt = [0; RESUME_SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                (ticket.peer, salt, ticket.peer_key, None)
+            }
+        };
+        self.communicating_with = Some(peer);
+        self.session_id = ticket.session_id;
+        self.session_connection = connection;
+        self.got_preferences = Some(preferences.unwrap_or_else(|| ticket.peer_preferences.clone()));
+        self.peer_identity = peer_key.map(|public_key| PeerIdentity {
+            public_key,
+            trust: self.known_peers.check(peer.ip(), &public_key),
+        });
+        self.session_key = Some(SessionKey::resumed(
+            ticket.token(),
+            &salt,
+            ticket.session_id,
+            ticket.requester,
+        ));
+        self.resume_salt = Some(salt);
+        self.session_span = info_span!("scp_session", peer = %peer);
+        Ok(())
     }
+
     fn handle_scp_message(&mut self, msg: ScpMessage, addr_in: SocketAddr) {
+        // A peer that doesn't know the session to resume has no key to seal its refusal with
+        let unsealed = matches!(
+            msg.command,
+            ScpCommand::Start | ScpCommand::KeyShare | ScpCommand::Resume | ScpCommand::Transfer
+        ) || (msg.command == ScpCommand::End
+            && self.state == ConnectionState::Resuming);
+        let msg = match self.session_key {
+            Some(ref mut key) if !unsealed => match key.open(msg.command, &msg.body) {
+                Some(body) => ScpMessage {
+                    command: msg.command,
+                    body,
+                },
+                None => {
+                    warn!(
+                        parent: &self.session_span,
+                        "Dropping a tampered or replayed {:?} from the peer", msg.command
+                    );
+                    return;
+                }
+            },
+            _ => msg,
+        };
+        // Like `Start` without an ephemeral key, a handshake without the peer's verified key share
+        // would set up a session nobody can tell was tampered with, see `on_key_share`
+        let handshake = matches!(
+            msg.command,
+            ScpCommand::PreferencesShare | ScpCommand::Ready | ScpCommand::Accept
+        );
+        if handshake && self.session_key.is_none() {
+            warn!(
+                parent: &self.session_span,
+                "Peer sent {:?} without sharing its key, ending the session", msg.command
+            );
+            self.handle(Input::PeerInvalid);
+            return;
+        }
         match msg.command {
-            ScpCommand::Start => self.init_connection(msg, addr_in),
-            ScpCommand::OwnKeyRequired => todo!(),
-            ScpCommand::ReqGenerateKey => todo!(),
-            ScpCommand::AckGenerateKey => todo!(),
-            ScpCommand::KeyShare => todo!(),
+            ScpCommand::Start => self.on_start(msg, addr_in),
+            ScpCommand::OwnKeyRequired
+            | ScpCommand::ReqGenerateKey
+            | ScpCommand::AckGenerateKey => {
+                warn!(
+                    "The peer sent {:?}, which isn't supported, dropping it",
+                    msg.command
+                );
+                self.handle(Input::PeerUnsupported);
+            }
+            ScpCommand::KeyShare => self.on_key_share(msg),
             ScpCommand::PreferencesShare => self.on_preferences_share(msg),
-            ScpCommand::Ready => self.finalize_connection(),
-            ScpCommand::SimpleMessage => todo!(),
+            ScpCommand::Ready => self.handle(Input::PeerReady),
+            ScpCommand::SimpleMessage => self.on_simple_message(msg),
+            ScpCommand::End if msg.body == NO_ANSWER => self.handle(Input::PeerNoAnswer),
             ScpCommand::End => {
-                self.notify_end_connection();
+                if msg.body == TRANSFERRED {
+                    info!("The peer took the session over to another device");
+                }
+                // Ended for good, there's nothing to come back to
+                if self
+                    .resumable
+                    .as_ref()
+                    .is_some_and(|ticket| ticket.peer.ip() == addr_in.ip())
+                {
+                    self.resumable = None;
+                }
+                self.handle(Input::PeerEnd);
             }
+            ScpCommand::Resume => self.on_resume(msg, addr_in),
+            ScpCommand::Transfer => self.on_transfer(msg, addr_in),
+            ScpCommand::Accept => self.handle(Input::PeerAccept),
+            ScpCommand::Probe => self.on_probe(addr_in),
+            ScpCommand::ProbeReply => warn!("Got a probe reply nobody asked for, ignoring it"),
         }
     }
-    fn end_connection(&mut self) {
-        if let Some(sock_addr) = self.communicating_with {
-            if let Ok(mut stream) = TcpStream::connect(sock_addr) {
-                let _ = stream.set_nonblocking(true);
-                let _ = stream.write(&ScpMessage::new(ScpCommand::End, b"").as_bytes());
+    /// The peer came back to the session after a restart, or answers our coming back,
+    /// or another device of the peer takes the session over.
+    /// Told off if there's no such session anymore, or it isn't signed by the identity
+    /// of the session or the one it was handed over to, wherever it comes from
+    fn on_resume(&mut self, msg: ScpMessage, addr_in: SocketAddr) {
+        let resuming = self.state == ConnectionState::Resuming;
+        let verified = self
+            .resumable
+            .as_ref()
+            .filter(|ticket| {
+                resuming || self.state == ConnectionState::Connected || ticket.is_fresh()
+            })
+            .and_then(|ticket| {
+                let (port, salt, public_key, preferences) = ticket.verify(&msg.body)?;
+                // Only a device taking the session over sends its preferences
+                let preferences = match preferences {
+                    [] => None,
+                    preferences => Some(serde_json::from_slice(preferences).ok()?),
+                };
+                Some(PendingResume {
+                    connection: addr_in,
+                    port,
+                    salt,
+                    public_key,
+                    preferences,
+                })
+            })
+            // The answer echoes our salt, an older one is replayed
+            .filter(|pending| !resuming || Some(pending.salt) == self.resume_salt);
+        let Some(pending) = verified else {
+            warn!("Peer {addr_in} asked to resume a session there's none of");
+            if let Some(connection) = self.connections.iter_mut().find(|c| c.addr == addr_in) {
+                let _ = connection.send(&ScpMessage::new(ScpCommand::End, b"Unknown session"));
+                connection.close();
             }
+            return;
+        };
+        let input = match pending.preferences {
+            Some(_) => {
+                info!("Device {addr_in} takes the session over");
+                Input::PeerTakeOver
+            }
+            None => {
+                info!("Peer {addr_in} resumes the session");
+                Input::PeerResume
+            }
+        };
+        self.pending_resume = Some(pending);
+        self.handle(input);
+    }
+    /// Hand the established session over to the device listening at `destination`.
+    /// It goes on here until the peer takes it over.
+    /// Transfer body: <SEALED(<HANDOVER(64 bytes)><TICKET(JSON)>)>, see `Identity::seal`
+    fn transfer(&mut self, destination: SocketAddr) {
+        let Some(mut ticket) = self
+            .resumable
+            .clone()
+            .filter(|_| self.state == ConnectionState::Connected)
+        else {
+            warn!("No session to hand over to {destination}");
+            return;
+        };
+        // The token goes to the device only, which has to be known already
+        let Some(device_key) = self.known_peers.key_of(destination.ip()) else {
+            warn!("Device {destination} isn't known, not handing the session over to it");
+            return;
+        };
+        ticket.refresh();
+        let handover = ticket.handover(&self.identity, &device_key);
+        let sent = serde_json::to_vec(&ticket)
+            .map_err(io::Error::from)
+            .and_then(|ticket| {
+                let payload = [&handover[..], &ticket].concat();
+                self.identity
+                    .seal(&device_key, &payload)
+                    .ok_or(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid key of the device",
+                    ))
+            })
+            .and_then(|body| {
+                let mut connection = ScpConnection::connect(destination, TCP_TIMEOUT)?;
+                connection.send(&ScpMessage::new(ScpCommand::Transfer, &body))?;
+                connection.close();
+                Ok(())
+            });
+        match sent {
+            Ok(()) => info!("Handed the session over to {destination}"),
+            Err(e) => warn!("Cannot hand the session over to {destination}: {e}"),
         }
     }
-    fn notify_end_connection(&mut self) {
-        *self.event.0.lock().unwrap() = Some(ConnectionEvent::ConnectionEnd);
-        self.event.1.notify_one();
-        self.communicating_with = None;
-        self.got_preferences = None;
+    /// Another device of the user hands us its session, resumed with our own preferences.
+    /// Taken only from a device known to us, sealed to our identity
+    fn on_transfer(&mut self, msg: ScpMessage, addr_in: SocketAddr) {
+        let opened = self
+            .identity
+            .open(&msg.body)
+            .filter(|(sender, _)| self.known_peers.key_of(addr_in.ip()) == Some(*sender));
+        let Some((_, payload)) = opened else {
+            warn!("Device {addr_in} isn't known or didn't seal its session to us, ignoring it");
+            if let Some(connection) = self.connections.iter_mut().find(|c| c.addr == addr_in) {
+                let _ = connection.send(&ScpMessage::new(ScpCommand::End, b"Unknown device"));
+                connection.close();
+            }
+            return;
+        };
+        let handover = payload.first_chunk::<SIGNATURE_LEN>().copied();
+        let ticket = payload
+            .get(SIGNATURE_LEN..)
+            .and_then(|ticket| {
+                serde_json::from_slice::<ResumeTicket>(ticket)
+                    .inspect_err(|e| warn!("Device {addr_in} handed over an invalid ticket: {e}"))
+                    .ok()
+            })
+            .filter(|ticket| ticket.is_fresh() && self.state == ConnectionState::Free);
+        let (Some(handover), Some(mut ticket)) = (handover, ticket) else {
+            warn!("Cannot take over the session handed over by {addr_in}");
+            if let Some(connection) = self.connections.iter_mut().find(|c| c.addr == addr_in) {
+                let _ = connection.send(&ScpMessage::new(ScpCommand::End, b"Cannot take over"));
+                connection.close();
+            }
+            return;
+        };
+        info!(
+            "Device {addr_in} handed over its session with {}",
+            ticket.peer
+        );
+        ticket.preferences = self.preferences.clone();
+        self.resumable = Some(ticket);
+        self.handover = Some(handover);
+        self.handle(Input::Resume);
     }
-    /// Called when a connection comes from the peer first
-    fn init_connection(&mut self, msg: ScpMessage, addr_in: SocketAddr) {
-        if self.state != ConnectionState::Free {
-            self.end_connection();
+    /// Tell the peer what this client supports and close the connection it asked over
+    fn on_probe(&mut self, addr_in: SocketAddr) {
+        let capabilities = PeerCapabilities {
+            protocol_version: PROTOCOL_VERSION,
+            video_encodings: VideoEncoding::taken_by(&self.preferences).to_vec(),
+            audio_encodings: self.preferences.audio_encodings.clone(),
+            video_modes: self.video_modes.clone(),
+            busy: self.state != ConnectionState::Free,
+        };
+        let Some(connection) = self.connections.iter_mut().find(|c| c.addr == addr_in) else {
+            return;
+        };
+        let body = match serde_json::to_vec(&capabilities) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Cannot answer the probe of {addr_in}: {e}");
+                return;
+            }
+        };
+        if let Err(e) = connection.send(&ScpMessage::new(ScpCommand::ProbeReply, &body)) {
+            warn!("Cannot answer the probe of {addr_in}: {e}");
+        }
+        connection.close();
+        info!("Answered the probe of {addr_in}");
+    }
+    fn send_control(&mut self, control: ControlMessage) {
+        if self.state != ConnectionState::Connected {
+            warn!("No session to send {control:?} in");
+            return;
+        }
+        if let Err(e) = self.send(ScpCommand::SimpleMessage, &control.to_body()) {
+            warn!("Cannot send {control:?}: {e}");
+        }
+    }
+    fn send_still_image(&mut self, still: StillImage) {
+        if self.state != ConnectionState::Connected {
+            return;
+        }
+        if let Err(e) = self.send(ScpCommand::SimpleMessage, &still.to_body()) {
+            warn!("Cannot send a still image: {e}");
+        }
+    }
+    fn send_clipboard(&mut self, text: ClipboardText) {
+        if self.state != ConnectionState::Connected {
+            return;
         }
-        if msg.body.len() >= 2 {
-            let slice = &msg.body[0..2];
-            if let Ok(port) = slice.try_into().map(u16::from_le_bytes) {
-                self.communicating_with = Some(SocketAddr::new(addr_in.ip(), port));
-                self.share_config();
-                self.state = ConnectionState::ConfigShared;
+        if let Err(e) = self.send(ScpCommand::SimpleMessage, &text.to_body()) {
+            warn!("Cannot send the clipboard: {e}");
+        }
+    }
+    fn send_file(&mut self, id: u32, path: &Path) {
+        if self.state != ConnectionState::Connected {
+            let error = io::Error::new(io::ErrorKind::NotConnected, "No session to send it in");
+            self.transfers.fail(id, &error);
+            return;
+        }
+        let offer = self.transfers.offer(id, path);
+        self.reply_file(offer);
+    }
+    /// Send the message about a file, if there's one. The file is given up on if it cannot be sent
+    fn reply_file(&mut self, msg: Option<FileMessage>) {
+        let Some(msg) = msg.filter(|_| self.state == ConnectionState::Connected) else {
+            return;
+        };
+        if let Err(e) = self.send(ScpCommand::SimpleMessage, &msg.to_body()) {
+            if let Some(cancel) = self.transfers.fail(msg.id(), &e) {
+                let _ = self.send(ScpCommand::SimpleMessage, &cancel.to_body());
             }
         }
     }
-
-    fn on_preferences_share(&mut self, msg: ScpMessage) {
-        // Get the shared preferences
-        // If we have shared, we can see ourselves as connected
-        // Why serde and json right now from all places? I was lazy
-        let mut deser = Deserializer::from_slice(&msg.body);
-        let preferences = Preferences::deserialize(&mut deser);
-        if let Ok(p) = preferences {
-            self.got_preferences = Some(p);
-            match self.state {
-                ConnectionState::Handshake => self.share_config(),
-                ConnectionState::ConfigShared => {
-                    let _ = TcpStream::connect(self.communicating_with.unwrap())
-                        .unwrap()
-                        .write(&ScpMessage::new(ScpCommand::Ready, b"").as_bytes());
-                    self.state = ConnectionState::Awaiting;
+    /// Send the next chunks of the files the peer accepted, and tell what happened to the files
+    fn poll_transfers(&mut self) {
+        if self.state == ConnectionState::Connected {
+            for msg in self.transfers.next_chunks() {
+                // The file was given up on, a chunk before couldn't be sent
+                if matches!(msg, FileMessage::Chunk { id, .. } if !self.transfers.is_sending(id)) {
+                    continue;
                 }
-                ConnectionState::Awaiting => self.finalize_connection(),
-                _ => (),
+                self.reply_file(Some(msg));
+            }
+        }
+        for event in self.transfers.take_events() {
+            if let Some(ref events) = self.transfer_events {
+                let _ = events.send(event);
+            }
+        }
+    }
+    fn on_simple_message(&mut self, msg: ScpMessage) {
+        if self.state != ConnectionState::Connected {
+            warn!("Got a message outside of a session, ignoring it");
+            return;
+        }
+        if let Some(file) = FileMessage::from_body(&msg.body) {
+            let reply = self.transfers.on_message(file);
+            self.reply_file(reply);
+            return;
+        }
+        if let Some(still) = StillImage::from_body(&msg.body) {
+            if let Some(ref stills) = self.stills {
+                let _ = stills.send(still);
+            }
+            return;
+        }
+        if let Some(text) = ClipboardText::from_body(&msg.body) {
+            info!("The peer sent {} bytes for the clipboard", text.0.len());
+            if let Some(ref clipboard) = self.clipboard {
+                let _ = clipboard.send(text);
+            }
+            return;
+        }
+        match ControlMessage::from_body(&msg.body) {
+            Some(control) => {
+                info!("Peer requested {control:?}");
+                // Nobody listens only when the client is gone, the listener is about to stop then
+                let _ = self.controls.send(control);
             }
+            None => warn!("Unknown control message from the peer: {:?}", msg.body),
+        }
+    }
+    /// Called when a connection comes from the peer first
+    fn on_start(&mut self, msg: ScpMessage, addr_in: SocketAddr) {
+        // Cannot be told apart from a forged one, so it doesn't end a verified session
+        if self.session_key.is_some() {
+            warn!(parent: &self.session_span, "Peer requested a session in the middle of one, ignoring it");
+            return;
+        }
+        let Some(port) = msg.body.first_chunk().map(|port| u16::from_le_bytes(*port)) else {
+            warn!("Peer requested a session without its port, ignoring it");
+            return;
+        };
+        let peer = with_port(addr_in, port);
+        // Older peers don't send the session ID
+        let session_id = msg
+            .body
+            .get(2..6)
+            .and_then(|id| id.try_into().ok())
+            .map_or(0, u32::from_le_bytes);
+        let Some(peer_key) = msg.body.get(6..).and_then(|key| key.try_into().ok()) else {
+            warn!(%peer, "Peer requested a session without its ephemeral key, ignoring it");
+            return;
+        };
+        info!(%peer, "Peer requested a session");
+        self.pending_session = Some(PendingSession {
+            peer,
+            session_id,
+            connection: Some(addr_in),
+            peer_key: Some(peer_key),
+        });
+        let requesting = matches!(
+            self.state,
+            ConnectionState::Handshake | ConnectionState::AwaitingReady | ConnectionState::Ringing
+        );
+        if !requesting || self.communicating_with != Some(peer) {
+            self.handle(Input::PeerStart);
+            return;
+        }
+        // Both sides requested a session at once, the higher session ID answers the other request.
+        // The ports tell the sides apart in the unlikely case of the same ID
+        let own = (self.session_id, self.preferences.port_scp);
+        if own > (session_id, port) {
+            info!(%peer, "Requested a session from each other, answering the peer's request");
+            self.handle(Input::PeerStartWon);
         } else {
-            self.end_connection();
+            info!(%peer, "Requested a session from each other, the peer answers ours");
+            self.handle(Input::PeerStartLost);
         }
     }
 
-    /// Share the if addr_in is present
-    /// Change the state to ConfigShared
-    fn share_config(&mut self) {
-        // share your config
-        if let Some(addr_in) = self.communicating_with {
-            let t = serde_json::to_vec(&self.preferences);
-            if t.is_err() {
-                self.end_connection();
-            }
-            let _ = TcpStream::connect(addr_in)
+    fn on_key_share(&mut self, msg: ScpMessage) {
+        let Some(peer) = self.communicating_with else {
+            return;
+        };
+        if self.peer_identity.is_some() {
+            warn!("Peer shared its identity again, ignoring it");
+            return;
+        }
+        let Some((public_key, peer_key)) = identity::verify_key_share(&msg.body, self.session_id)
+        else {
+            warn!("Peer shared an invalid identity, ending the session");
+            self.handle(Input::PeerInvalid);
+            return;
+        };
+        let mut responding = false;
+        match (self.peer_key, &self.key_exchange) {
+            // Responding, the key of `Start` has to be the signed one
+            (Some(known), _) if known != peer_key => {
+                warn!("Peer signed another ephemeral key than it started with, ending the session");
+                self.handle(Input::PeerInvalid);
+                return;
+            }
+            (Some(_), _) => responding = true,
+            // Requesting, the session key exists from now on
+            (None, Some(key_exchange)) => {
+                match key_exchange.session_key(&peer_key, self.session_id, true) {
+                    Some(session_key) => self.session_key = Some(session_key),
+                    None => {
+                        warn!("Peer shared an invalid ephemeral key, ending the session");
+                        self.handle(Input::PeerInvalid);
+                        return;
+                    }
+                }
+                self.peer_key = Some(peer_key);
+            }
+            (None, None) => return,
+        }
+        let trust = self.known_peers.check(peer.ip(), &public_key);
+        let peer_identity = PeerIdentity { public_key, trust };
+        let fingerprint = peer_identity.fingerprint();
+        // Sessions requested by the user go on, whoever the peer is
+        if responding
+            && !self
+                .peer_list
+                .lock()
                 .unwrap()
-                .write(&ScpMessage::new(ScpCommand::PreferencesShare, &t.unwrap()).as_bytes());
-            self.state = ConnectionState::ConfigShared;
+                .admits(peer.ip(), &peer_identity)
+        {
+            info!(fingerprint, "Refused the session of the denied peer");
+            self.handle(Input::Refuse);
+            return;
+        }
+        match trust {
+            PeerTrust::Changed => warn!(
+                fingerprint,
+                "Peer identity changed since the last session, it might be a man in the middle"
+            ),
+            _ => info!(fingerprint, ?trust, "Peer identity verified"),
         }
+        self.peer_identity = Some(peer_identity);
     }
-    /// Function to call when we're ready to receive data from a peer
+
+    fn on_preferences_share(&mut self, msg: ScpMessage) {
+        // Why serde and json right now from all places? I was lazy
+        let mut deser = Deserializer::from_slice(&msg.body);
+        match Preferences::deserialize(&mut deser) {
+            Ok(preferences) => {
+                self.pending_preferences = Some(preferences);
+                self.handle(Input::PeerPreferences);
+            }
+            Err(_) => {
+                warn!("Peer shared invalid preferences, ending the session");
+                self.handle(Input::PeerInvalid);
+            }
+        }
+    }
+
+    /// Function to call when we're ready to receive data from a peer.
+    /// The state machine only gets here with the peer and its preferences known
     fn finalize_connection(&mut self) {
-        *self.event.0.lock().unwrap() =
-        Some(ConnectionEvent::ConnectionEstablished(SessionConfig {
-            encryption_key: None,
-            encrytpion_method: None,
-            ip: self.communicating_with.expect("Invalid finalize connection call. Expected to have a peer communicating with, got None.").ip(),
-            stream_config: self.got_preferences.expect("Cannot finalize connection with no preferences"),
+        let (Some(peer), Some(stream_config)) =
+            (self.communicating_with, self.got_preferences.clone())
+        else {
+            warn!("Cannot establish a session without the peer and its preferences");
+            return;
+        };
+        let encryption = match self.session_key {
+            Some(_) => EncryptionMethod::SessionHmac,
+            None => EncryptionMethod::None,
+        };
+        let resume = self.session_key.as_ref().map(|key| {
+            Box::new(ResumeTicket::new(
+                peer,
+                self.session_id,
+                key.requester(),
+                self.preferences.clone(),
+                stream_config.clone(),
+                self.peer_identity.map(|identity| identity.public_key),
+                key.resume_token(),
+            ))
+        });
+        self.resumable = resume.as_deref().cloned();
+        self.handover = None;
+        // Both sides have to take protected streams, older peers send and take them as they are
+        let media_keys = self
+            .session_key
+            .as_ref()
+            .filter(|_| self.preferences.srtp && stream_config.srtp)
+            .map(|key| Box::new(key.media_keys()));
+        self.notify(ConnectionEvent::ConnectionEstablished(SessionConfig {
+            ip: peer.ip(),
+            endpoints: StreamEndpoints::of_peer(peer, &stream_config),
+            codecs: NegotiatedCodecs::negotiate(&self.preferences, &stream_config),
+            encryption,
+            video_mode: stream_config.video_mode,
+            session_id: self.session_id,
+            peer_identity: self.peer_identity,
+            resume,
+            media_keys,
         }));
-        self.event.1.notify_one();
-        self.state = ConnectionState::Connected;
+        info!("Session established");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+    use super::{PendingSession, ScpListener, MAX_CONNECTIONS, TCP_TIMEOUT};
+    use crate::client::Preferences;
+    use crate::connection::ScpConnection;
+    use crate::identity::{Identity, KnownPeers};
+    use crate::scp::{ScpCommand, ScpMessage};
+    use crate::session::{ConnectionState, Input};
+
+    fn listener() -> ScpListener {
+        ScpListener::new(
+            Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            Arc::new((Mutex::new(None), Condvar::new())),
+            mpsc::channel().0,
+            Preferences {
+                port_scp: 0,
+                ..Default::default()
+            },
+            Identity::generate(),
+            KnownPeers::in_memory(),
+            Default::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_session_connection_kept() {
+        let mut listener = listener();
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        // Accepted, so each is told apart by the port of the other side
+        let connect = || {
+            let _peer = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            ScpConnection::new(server.accept().unwrap().0, TCP_TIMEOUT).unwrap()
+        };
+        let session = connect();
+        let session_addr = session.addr;
+        listener.session_connection = Some(session_addr);
+        listener.add_connection(session);
+        // Anyone opening more of them pushes out their own, the oldest but the session's
+        let first = connect();
+        let first_addr = first.addr;
+        listener.add_connection(first);
+        for _ in 0..MAX_CONNECTIONS {
+            listener.add_connection(connect());
+        }
+        assert_eq!(listener.connections.len(), MAX_CONNECTIONS);
+        assert_eq!(listener.connections[0].addr, session_addr);
+        assert!(listener.connections.iter().all(|c| c.addr != first_addr));
+
+        // With no session, the oldest goes
+        listener.session_connection = None;
+        listener.add_connection(connect());
+        assert!(listener.connections.iter().all(|c| c.addr != session_addr));
+    }
+    #[test]
+    fn test_answer_without_key_share() {
+        let mut listener = listener();
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        listener.pending_session = Some(PendingSession {
+            peer: peer_addr,
+            session_id: 7,
+            connection: None,
+            peer_key: None,
+        });
+        listener.handle(Input::Attempt);
+        assert_eq!(listener.state, ConnectionState::Handshake);
+        // Its key share dropped on the way, the rest of the answer goes unsealed
+        let preferences = serde_json::to_vec(&Preferences::default()).unwrap();
+        for (command, body) in [
+            (ScpCommand::PreferencesShare, &preferences[..]),
+            (ScpCommand::Ready, b""),
+            (ScpCommand::Accept, b""),
+        ] {
+            listener.handle_scp_message(ScpMessage::new(command, body), peer_addr);
+        }
+        assert_eq!(listener.state, ConnectionState::Free);
+        assert!(listener.session_key.is_none());
+        assert!(listener.got_preferences.is_none());
     }
 }
@@ -0,0 +1,494 @@
+//! The state machine of a SCP session, kept pure so every transition can be tested.
+//! `ScpListener` turns actions and messages into `Input`s, feeds them to `on`
+//! and carries out the returned `Effect`s, in order.
+//!
+//! The requesting side goes `Free -> Handshake -> AwaitingReady -> Ringing -> Connected`,
+//! the responding side `Free -> ConfigShared -> Awaiting -> Connected`.
+//! The responding side sends `Ready` once it asks the user, and `Accept` once the user accepts.
+//! A call not answered within the ring timeout of either side ends on both as `NotAnswered`.
+//! The requesting side may give up on it until it's accepted, see `Input::Cancel`.
+//!
+//! Peers requesting a session from each other at once both get a `Start` while in `Handshake`.
+//! The side with the higher session ID answers the peer's request in `Answering`,
+//! accepting it right away as its user asked for the call, the other one goes on requesting.
+//!
+//! A side that restarted goes `Free -> Resuming -> Connected` with the session it was in,
+//! the peer straight to `Connected`, see `crate::resume`.
+//! A device the session is handed over to goes the same way, the peer takes the session over
+//! and establishes it again with the new device, ending it with the old one.
+//! Anything coming out of order leaves the state as it is.
+
+use crate::client::ScpConnectionError;
+
+/// The current state of the connection
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum ConnectionState {
+    /// Not connecting anywhere
+    Free,
+    /// Requested a session, waiting for the peer to share its config
+    Handshake,
+    /// Shared the config after the peer requested a session, waiting for the peer's config
+    ConfigShared,
+    /// Configs exchanged, waiting for the peer to be ready
+    AwaitingReady,
+    /// The peer asks its user, waiting for the session to be accepted
+    Ringing,
+    /// Configs exchanged, waiting for the user to accept the session
+    Awaiting,
+    /// Shared the config for the peer's request that won over ours, to accept it once configured
+    Answering,
+    /// Asked the peer to resume the session we were in before a restart
+    Resuming,
+    /// Connection fully established
+    Connected,
+}
+
+/// What happened, either from the user or the peer
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum Input {
+    /// The user requested a session with a peer
+    Attempt,
+    /// The user resumes the session of a ticket
+    Resume,
+    /// The user accepted the requested session
+    Accept,
+    /// The user refused the requested session
+    Refuse,
+    /// The user ended the session
+    End,
+    /// The user gave up on the session it requested, or resumes, before it's established
+    Cancel,
+    /// The peer requested a session
+    PeerStart,
+    /// The peer requested a session while we request one from it, and its request goes on
+    PeerStartWon,
+    /// The peer requested a session while we request one from it, and ours goes on
+    PeerStartLost,
+    /// The peer shared valid preferences
+    PeerPreferences,
+    /// The peer is ready for the session and asks its user to accept it
+    PeerReady,
+    /// The user of the peer accepted the session
+    PeerAccept,
+    /// The peer proved it was in the session to resume
+    PeerResume,
+    /// Another device of the peer proved it was handed the session to resume
+    PeerTakeOver,
+    /// The peer gave up on the session nobody answered
+    PeerNoAnswer,
+    /// Nobody answered the session, or the resume, within the ring timeout
+    RingTimeout,
+    /// The peer ended the session, or refused it
+    PeerEnd,
+    /// The peer sent something invalid, i.e. preferences or identity
+    PeerInvalid,
+    /// The peer sent a command this side doesn't take, i.e. the key generation ones
+    PeerUnsupported,
+    /// The peer cannot be reached
+    PeerUnreachable,
+}
+
+/// What `ScpListener` has to do after a transition
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum Effect {
+    /// Start a new session with the pending peer, forgetting the previous one
+    Begin,
+    /// Bring back the session to resume, under a new key
+    Restore,
+    SendStart,
+    /// Share the identity and the preferences with the peer
+    ShareConfig,
+    SendReady,
+    SendAccept,
+    SendResume,
+    SendEnd,
+    /// End the session with the device it was handed over from
+    SendTransferred,
+    /// End the session nobody answered
+    SendNoAnswer,
+    /// Keep the pending preferences of the peer
+    StorePreferences,
+    /// Tell the user the peer requests a session, to accept or refuse it
+    NotifyIncoming,
+    /// Tell the user the peer asks its user to accept the session
+    NotifyRinging,
+    /// Tell the user the session is established
+    Establish,
+    /// Tell the user the peer ended the session
+    NotifyEnd,
+    /// Tell the user the session cannot be established
+    Fail(ScpConnectionError),
+    /// Forget the session
+    Reset,
+}
+
+/// The transition table. Returns the next state and the effects to carry out, in order
+pub(crate) fn on(state: ConnectionState, input: Input) -> (ConnectionState, Vec<Effect>) {
+    use ConnectionState::*;
+    use Effect::*;
+    match (state, input) {
+        (Connected, Input::Attempt) => {
+            (Connected, vec![Fail(ScpConnectionError::AlreadyConnected)])
+        }
+        (Free, Input::Attempt) => (Handshake, vec![Begin, SendStart]),
+        // A session that's not established yet is abandoned for the new one
+        (_, Input::Attempt) => (Handshake, vec![SendEnd, Begin, SendStart]),
+
+        (Free, Input::Resume) => (Resuming, vec![Restore, SendResume]),
+        (Resuming, Input::PeerResume) => (Connected, vec![Establish]),
+        // The peer restarted, we may not have noticed it was gone
+        (Free, Input::PeerResume) => (Connected, vec![Restore, SendResume, Establish]),
+        (Connected, Input::PeerResume) => (Connected, vec![Restore, SendResume]),
+        // The streams go to the new device from now on
+        (Free, Input::PeerTakeOver) => (Connected, vec![Restore, SendResume, Establish]),
+        (Connected, Input::PeerTakeOver) => (
+            Connected,
+            vec![SendTransferred, Restore, SendResume, Establish],
+        ),
+        (Resuming, Input::RingTimeout) => {
+            (Free, vec![Fail(ScpConnectionError::NotResponding), Reset])
+        }
+
+        // Messages from other peers are refused while busy, so this is the same peer starting over
+        (Connected, Input::PeerStart) => (ConfigShared, vec![NotifyEnd, Begin, ShareConfig]),
+        (_, Input::PeerStart) => (ConfigShared, vec![Begin, ShareConfig]),
+        (Handshake | AwaitingReady | Ringing, Input::PeerStartWon) => {
+            (Answering, vec![Begin, ShareConfig])
+        }
+        // The peer answers our request instead
+        (Handshake | AwaitingReady | Ringing, Input::PeerStartLost) => (state, vec![]),
+
+        (Handshake, Input::PeerPreferences) => (AwaitingReady, vec![StorePreferences, ShareConfig]),
+        (ConfigShared, Input::PeerPreferences) => {
+            (Awaiting, vec![StorePreferences, SendReady, NotifyIncoming])
+        }
+        (Answering, Input::PeerPreferences) => (
+            Connected,
+            vec![StorePreferences, SendReady, SendAccept, Establish],
+        ),
+        (AwaitingReady, Input::PeerReady) => (Ringing, vec![NotifyRinging]),
+        (Ringing, Input::PeerAccept) => (Connected, vec![Establish]),
+        (Awaiting, Input::Accept) => (Connected, vec![SendAccept, Establish]),
+        (Ringing | Awaiting, Input::RingTimeout) => (
+            Free,
+            vec![SendNoAnswer, Fail(ScpConnectionError::NotAnswered), Reset],
+        ),
+        (Ringing | Awaiting, Input::PeerNoAnswer) => {
+            (Free, vec![Fail(ScpConnectionError::NotAnswered), Reset])
+        }
+
+        (Handshake | AwaitingReady | Ringing | Answering | Resuming, Input::Cancel) => (
+            Free,
+            vec![SendEnd, Fail(ScpConnectionError::Cancelled), Reset],
+        ),
+
+        (
+            Free,
+            Input::Refuse
+            | Input::End
+            | Input::PeerEnd
+            | Input::PeerInvalid
+            | Input::PeerUnsupported,
+        ) => (Free, vec![]),
+        (_, Input::Refuse | Input::End | Input::PeerInvalid) => (Free, vec![SendEnd, Reset]),
+        // Like the peer ending it, the peer is told too
+        (Handshake | AwaitingReady | Ringing | Answering | Resuming, Input::PeerUnsupported) => (
+            Free,
+            vec![SendEnd, Fail(ScpConnectionError::Refused), Reset],
+        ),
+        (_, Input::PeerUnsupported) => (Free, vec![SendEnd, NotifyEnd, Reset]),
+        (Handshake | AwaitingReady | Ringing | Answering | Resuming, Input::PeerEnd) => {
+            (Free, vec![Fail(ScpConnectionError::Refused), Reset])
+        }
+        (_, Input::PeerEnd) => (Free, vec![NotifyEnd, Reset]),
+        (Free, Input::PeerUnreachable) => (Free, vec![]),
+        (Handshake | AwaitingReady | Ringing | Answering | Resuming, Input::PeerUnreachable) => {
+            (Free, vec![Fail(ScpConnectionError::NotResponding), Reset])
+        }
+        (_, Input::PeerUnreachable) => (Free, vec![NotifyEnd, Reset]),
+
+        // Out of order or repeated, i.e. accepting when nobody asked
+        (state, _) => (state, vec![]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    use super::{on, ConnectionState, Effect, Input};
+    use crate::client::ScpConnectionError;
+
+    const INPUTS: [Input; 20] = [
+        Input::Attempt,
+        Input::Resume,
+        Input::Accept,
+        Input::Refuse,
+        Input::End,
+        Input::Cancel,
+        Input::PeerStart,
+        Input::PeerStartWon,
+        Input::PeerStartLost,
+        Input::PeerPreferences,
+        Input::PeerReady,
+        Input::PeerAccept,
+        Input::PeerResume,
+        Input::PeerTakeOver,
+        Input::PeerNoAnswer,
+        Input::RingTimeout,
+        Input::PeerEnd,
+        Input::PeerInvalid,
+        Input::PeerUnsupported,
+        Input::PeerUnreachable,
+    ];
+
+    impl Arbitrary for Input {
+        fn arbitrary(g: &mut Gen) -> Self {
+            *g.choose(&INPUTS).unwrap()
+        }
+    }
+
+    fn run(inputs: &[Input]) -> (ConnectionState, Vec<Effect>) {
+        inputs.iter().fold(
+            (ConnectionState::Free, Vec::new()),
+            |(state, mut all), input| {
+                let (state, effects) = on(state, *input);
+                all.extend(effects);
+                (state, all)
+            },
+        )
+    }
+
+    #[test]
+    fn test_requesting_side() {
+        let (state, effects) = run(&[Input::Attempt, Input::PeerPreferences, Input::PeerReady]);
+        assert_eq!(state, ConnectionState::Ringing);
+        assert_eq!(effects.last(), Some(&Effect::NotifyRinging));
+        let (state, effects) = on(state, Input::PeerAccept);
+        assert_eq!(state, ConnectionState::Connected);
+        assert_eq!(effects, [Effect::Establish]);
+    }
+    #[test]
+    fn test_responding_side() {
+        let (state, effects) = run(&[Input::PeerStart, Input::PeerPreferences, Input::Accept]);
+        assert_eq!(state, ConnectionState::Connected);
+        assert_eq!(
+            effects[effects.len() - 2..],
+            [Effect::SendAccept, Effect::Establish]
+        );
+    }
+    #[test]
+    fn test_ring_timeout() {
+        let not_answered = Effect::Fail(ScpConnectionError::NotAnswered);
+        // Either side may give up first, the other one is told
+        let caller = [Input::Attempt, Input::PeerPreferences, Input::PeerReady];
+        let callee = [Input::PeerStart, Input::PeerPreferences];
+        for ringing in [&caller[..], &callee[..]] {
+            let (state, effects) = run(&[ringing, &[Input::RingTimeout]].concat());
+            assert_eq!(state, ConnectionState::Free);
+            assert_eq!(
+                effects[effects.len() - 3..],
+                [Effect::SendNoAnswer, not_answered, Effect::Reset]
+            );
+            let (state, effects) = run(&[ringing, &[Input::PeerNoAnswer]].concat());
+            assert_eq!(state, ConnectionState::Free);
+            assert_eq!(effects[effects.len() - 2..], [not_answered, Effect::Reset]);
+        }
+        // Too late once the session is established
+        let (state, _) = run(&[&caller[..], &[Input::PeerAccept, Input::RingTimeout]].concat());
+        assert_eq!(state, ConnectionState::Connected);
+    }
+    #[test]
+    fn test_cancel() {
+        let cancelled = Effect::Fail(ScpConnectionError::Cancelled);
+        let (state, effects) = run(&[Input::Attempt, Input::Cancel]);
+        assert_eq!(state, ConnectionState::Free);
+        assert_eq!(effects[2..], [Effect::SendEnd, cancelled, Effect::Reset]);
+        let (state, effects) = run(&[
+            Input::Attempt,
+            Input::PeerPreferences,
+            Input::PeerReady,
+            Input::Cancel,
+        ]);
+        assert_eq!(state, ConnectionState::Free);
+        assert!(effects.contains(&cancelled));
+        // Not the user's request to give up on, nor one that's established
+        let (state, effects) = run(&[Input::PeerStart, Input::PeerPreferences, Input::Cancel]);
+        assert_eq!(state, ConnectionState::Awaiting);
+        assert!(!effects.contains(&cancelled));
+        let (state, _) = run(&[
+            Input::Attempt,
+            Input::PeerPreferences,
+            Input::PeerReady,
+            Input::PeerAccept,
+            Input::Cancel,
+        ]);
+        assert_eq!(state, ConnectionState::Connected);
+    }
+    #[test]
+    fn test_glare() {
+        // The peer answers our request
+        let (state, effects) = run(&[Input::Attempt, Input::PeerStartLost]);
+        assert_eq!(state, ConnectionState::Handshake);
+        assert_eq!(effects, [Effect::Begin, Effect::SendStart]);
+        // We answer the peer's, without asking the user who called it too
+        let (state, effects) = run(&[Input::Attempt, Input::PeerStartWon, Input::PeerPreferences]);
+        assert_eq!(state, ConnectionState::Connected);
+        assert_eq!(
+            effects[effects.len() - 4..],
+            [
+                Effect::StorePreferences,
+                Effect::SendReady,
+                Effect::SendAccept,
+                Effect::Establish
+            ]
+        );
+        assert!(!effects.contains(&Effect::NotifyIncoming));
+    }
+    #[test]
+    fn test_resume() {
+        let (state, effects) = run(&[Input::Resume, Input::PeerResume]);
+        assert_eq!(state, ConnectionState::Connected);
+        assert_eq!(
+            effects,
+            [Effect::Restore, Effect::SendResume, Effect::Establish]
+        );
+        // Still in the session, it goes on without being established again
+        let (state, effects) = run(&[Input::PeerStart, Input::PeerPreferences, Input::Accept]);
+        assert_eq!(state, ConnectionState::Connected);
+        let (state, resumed) = on(state, Input::PeerResume);
+        assert_eq!(state, ConnectionState::Connected);
+        assert_eq!(resumed, [Effect::Restore, Effect::SendResume]);
+        assert!(effects.contains(&Effect::Establish));
+        // The peer doesn't answer
+        let (state, effects) = run(&[Input::Resume, Input::RingTimeout]);
+        assert_eq!(state, ConnectionState::Free);
+        assert_eq!(effects.last(), Some(&Effect::Reset));
+    }
+    #[test]
+    fn test_take_over() {
+        // The new device resumes like a restarted one
+        let (state, effects) = run(&[Input::Resume, Input::PeerResume]);
+        assert_eq!(state, ConnectionState::Connected);
+        assert!(effects.contains(&Effect::Establish));
+        // The peer establishes the session again, after ending it with the old device
+        let (state, _) = run(&[Input::PeerStart, Input::PeerPreferences, Input::Accept]);
+        let (state, effects) = on(state, Input::PeerTakeOver);
+        assert_eq!(state, ConnectionState::Connected);
+        assert_eq!(
+            effects,
+            [
+                Effect::SendTransferred,
+                Effect::Restore,
+                Effect::SendResume,
+                Effect::Establish
+            ]
+        );
+        // Nothing to end when the old device was gone already
+        let (state, effects) = run(&[Input::PeerTakeOver]);
+        assert_eq!(state, ConnectionState::Connected);
+        assert!(!effects.contains(&Effect::SendTransferred));
+        // Handing over a session that's not established yet goes nowhere
+        let (state, effects) = run(&[Input::Attempt, Input::PeerTakeOver]);
+        assert_eq!(state, ConnectionState::Handshake);
+        assert_eq!(effects, [Effect::Begin, Effect::SendStart]);
+    }
+    #[test]
+    fn test_out_of_order_messages() {
+        // Ready before the preferences, accepting before anyone asked
+        let (state, effects) = run(&[Input::PeerReady, Input::Accept, Input::PeerPreferences]);
+        assert_eq!(state, ConnectionState::Free);
+        assert!(effects.is_empty());
+        let (state, _) = run(&[Input::PeerStart, Input::Accept]);
+        assert_eq!(state, ConnectionState::ConfigShared);
+    }
+    #[test]
+    fn test_unsupported_commands() {
+        // Dropped while idle
+        let (state, effects) = run(&[Input::PeerUnsupported]);
+        assert_eq!(state, ConnectionState::Free);
+        assert!(effects.is_empty());
+        // The session goes, whether established or not
+        let (state, effects) = run(&[Input::Attempt, Input::PeerUnsupported]);
+        assert_eq!(state, ConnectionState::Free);
+        assert_eq!(
+            effects[effects.len() - 3..],
+            [
+                Effect::SendEnd,
+                Effect::Fail(ScpConnectionError::Refused),
+                Effect::Reset
+            ]
+        );
+        let (state, effects) = run(&[
+            Input::Attempt,
+            Input::PeerPreferences,
+            Input::PeerReady,
+            Input::PeerAccept,
+            Input::PeerUnsupported,
+        ]);
+        assert_eq!(state, ConnectionState::Free);
+        assert_eq!(
+            effects[effects.len() - 3..],
+            [Effect::SendEnd, Effect::NotifyEnd, Effect::Reset]
+        );
+    }
+    #[test]
+    fn test_session_invariants() {
+        fn prop(inputs: Vec<Input>) -> bool {
+            let mut state = ConnectionState::Free;
+            // What's known about the current session
+            let mut has_peer = false;
+            let mut has_preferences = false;
+            for input in inputs {
+                let (next, effects) = on(state, input);
+                for effect in effects {
+                    match effect {
+                        Effect::Begin => {
+                            has_peer = true;
+                            has_preferences = false;
+                        }
+                        Effect::Reset => {
+                            has_peer = false;
+                            has_preferences = false;
+                        }
+                        Effect::StorePreferences => has_preferences = true,
+                        Effect::Restore => {
+                            has_peer = true;
+                            has_preferences = true;
+                        }
+                        // The session can only be established with everything about the peer known,
+                        // and once unless it's taken over
+                        Effect::Establish
+                            if !has_peer
+                                || !has_preferences
+                                || (state == ConnectionState::Connected
+                                    && input != Input::PeerTakeOver) =>
+                        {
+                            return false
+                        }
+                        Effect::SendStart
+                        | Effect::ShareConfig
+                        | Effect::SendReady
+                        | Effect::SendAccept
+                        | Effect::SendResume
+                            if !has_peer =>
+                        {
+                            return false
+                        }
+                        _ => {}
+                    }
+                }
+                // Connected only through Establish, Free only with the session forgotten
+                if next == ConnectionState::Connected && !has_preferences {
+                    return false;
+                }
+                if next == ConnectionState::Free && has_peer {
+                    return false;
+                }
+                state = next;
+            }
+            true
+        }
+        quickcheck(prop as fn(Vec<Input>) -> bool);
+    }
+}
@@ -0,0 +1,153 @@
+//! The screen the peer shares, shown in a second panel over the UI.
+//! The panel shows up once the frames of the shared screen come and goes away when they stop.
+//! It's resized by dragging its corner, the picture keeps the aspect of the stream.
+
+use bevy::input::mouse::MouseMotion;
+use bevy::prelude::*;
+use eye_spy_core::h264_stream::StreamSource;
+
+use crate::connection_state_bevy::IncomingVideoStreamState;
+use crate::plugin::{write_frame_image, CallManagerBevy, SCREEN_IMAGE_HANDLE};
+use crate::stream_view::{RemoteStreamStatus, StreamScaling};
+use crate::window_activity::window_visible;
+
+/// Width of the shared screen when the panel first shows up
+pub const SCREEN_SHARE_WIDTH: f32 = 320.;
+/// The panel is resized between these widths of the screen
+const MIN_WIDTH: f32 = 160.;
+const MAX_WIDTH: f32 = 1280.;
+
+pub struct ScreenSharePlugin;
+
+impl Plugin for ScreenSharePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ScreenShareStatus>();
+        app.add_systems(OnEnter(IncomingVideoStreamState::Off), hide_screen_share);
+        app.add_systems(
+            Update,
+            (
+                update_screen_share_status,
+                show_screen_share.run_if(resource_changed::<ScreenShareStatus>),
+            )
+                .chain()
+                .run_if(in_state(IncomingVideoStreamState::On)),
+        );
+        app.add_systems(Update, resize_screen_share);
+        app.add_systems(
+            Update,
+            update_screen_share_image.run_if(
+                in_state(IncomingVideoStreamState::On)
+                    .and_then(window_visible)
+                    .and_then(screen_shared)
+                    .and_then(screen_frame_arrived.or_else(resource_changed::<StreamScaling>)),
+            ),
+        );
+    }
+}
+
+/// Whether the frames of the shared screen keep coming, like `RemoteStreamStatus` of the camera.
+/// The panel is only shown while it's live
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenShareStatus(pub RemoteStreamStatus);
+
+/// Holds the shared screen, hidden while the peer doesn't share it
+#[derive(Component)]
+pub struct ScreenSharePanel;
+
+/// The shared screen itself, the panel grows with it
+#[derive(Component)]
+pub struct ScreenShareImage;
+
+/// Corner of the panel, dragged to resize it
+#[derive(Component)]
+pub struct ScreenShareResizeHandle;
+
+/// Width of the shared screen after the corner is dragged by `dx`
+fn resized_width(width: f32, dx: f32) -> f32 {
+    (width + dx).clamp(MIN_WIDTH, MAX_WIDTH)
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn screen_shared(status: Res<ScreenShareStatus>) -> bool {
+    status.0 == RemoteStreamStatus::Live
+}
+/// Run condition: the shared screen decoded a frame since it was last shown
+fn screen_frame_arrived(call: Res<CallManagerBevy>) -> bool {
+    call.0.screen().has_new_frame()
+}
+fn update_screen_share_status(call: Res<CallManagerBevy>, mut status: ResMut<ScreenShareStatus>) {
+    // A frozen screen stays up, only a screen that stopped coming is hidden
+    let current = ScreenShareStatus(RemoteStreamStatus::from_frame_age(
+        call.0.screen().last_frame_age(),
+        false,
+    ));
+    if status.set_if_neq(current) {
+        info!("Shared screen: {:?}", current.0);
+    }
+}
+fn show_screen_share(
+    status: Res<ScreenShareStatus>,
+    mut panels: Query<&mut Style, With<ScreenSharePanel>>,
+) {
+    for mut style in &mut panels {
+        style.display = if status.0 == RemoteStreamStatus::Live {
+            Display::Flex
+        } else {
+            Display::None
+        };
+    }
+}
+fn hide_screen_share(
+    mut status: ResMut<ScreenShareStatus>,
+    mut panels: Query<&mut Style, With<ScreenSharePanel>>,
+) {
+    *status = ScreenShareStatus::default();
+    for mut style in &mut panels {
+        style.display = Display::None;
+    }
+}
+/// The handle stays pressed while the mouse button is held, even once the cursor leaves it
+fn resize_screen_share(
+    handles: Query<&Interaction, With<ScreenShareResizeHandle>>,
+    mut motion: EventReader<MouseMotion>,
+    ui_scale: Res<UiScale>,
+    mut images: Query<&mut Style, With<ScreenShareImage>>,
+) {
+    let dx: f32 = motion.read().map(|motion| motion.delta.x).sum();
+    if dx == 0. || !handles.iter().any(|i| *i == Interaction::Pressed) {
+        return;
+    }
+    for mut style in &mut images {
+        if let Val::Px(width) = style.width {
+            style.width = Val::Px(resized_width(width, dx / ui_scale.0));
+        }
+    }
+}
+/// Runs as the frames arrive, or when they're sampled differently
+fn update_screen_share_image(
+    call: Res<CallManagerBevy>,
+    mut images: ResMut<Assets<Image>>,
+    scaling: Res<StreamScaling>,
+) {
+    call.0.screen().take_new_frame();
+    let buf = StreamSource::Screen.frame_buffer().lock().unwrap();
+    write_frame_image(&mut images, &SCREEN_IMAGE_HANDLE, &buf, scaling.sampler());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resized_width, MAX_WIDTH, MIN_WIDTH, SCREEN_SHARE_WIDTH};
+
+    #[test]
+    fn test_resized_width() {
+        assert_eq!(
+            resized_width(SCREEN_SHARE_WIDTH, 40.),
+            SCREEN_SHARE_WIDTH + 40.
+        );
+        assert_eq!(resized_width(SCREEN_SHARE_WIDTH, -1000.), MIN_WIDTH);
+        assert_eq!(resized_width(MAX_WIDTH, 1.), MAX_WIDTH);
+    }
+}
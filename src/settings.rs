@@ -0,0 +1,1332 @@
+//! Settings panel, opened with the Settings button.
+//! Sets the `Theme`: the UI scale with a slider, dragged or moved with the arrow keys
+//! once picked with Tab, and the high contrast mode.
+//! Picks how the incoming stream is scaled, see `StreamScaling`.
+//! Sets the picture controls of the camera, which can be saved as its profile,
+//! the mode it captures in, mid-call too, and the filters of the outgoing video, see `VideoFilters`, and the motion detection.
+//! Turns the recording of the calls on and off, see `CallRecording`, and sets the audio to mono or stereo.
+//! Turns the peer's video as a webcam for the other apps on and off, see `VirtualCameraOutput`.
+//! Shows the data the calls used, against the monthly cap if there's one, see `eye_spy_core::usage`.
+//! Applies the quality picked with the button under the stream, see `CallQuality`,
+//! and asks the peer for its cheapest video with the one next to it.
+//! The profile is set again whenever the camera is opened.
+//! Lists the peers allowed or denied to call, each can be taken off the list.
+//! Picks the language of the UI, see `Language`, and the name put into the invites.
+//! Can be popped out into a window of its own, see `crate::pop_out`.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::ui::RelativeCursorPosition;
+use eye_spy_core::camera::{
+    CameraControl, CameraControls, CameraProfile, CameraProfiles, ControlRange,
+};
+use eye_spy_core::filter::{BackgroundBlur, Watermark};
+use eye_spy_core::h264_stream::outgoing::OutgoingStreamEvent;
+use eye_spy_core::usage::Usage;
+use eye_spy_core::virtual_camera::find_loopback_device;
+use eye_spy_core::{
+    AudioChannels, DataUsage, FrameFilter, MonthlyCap, MotionMode, RecordingConfig,
+    RecordingLayout, VideoQuality, VirtualCamera,
+};
+use scp_client::client::VideoMode;
+use scp_client::peer_list::PeerEntry;
+
+use crate::connection_state_bevy::{MotionEvent, OutgoingStreamHealthEvent};
+use crate::locale::{tr, Language};
+use crate::plugin::CallManagerBevy;
+use crate::pop_out::{PopOut, PopOutButton};
+use crate::stream_view::StreamScaling;
+use crate::toast::ToastEvent;
+use crate::ui::{TextInput, TextInputFocus, TextInputSubmitted, Theme, UiSpawner};
+use crate::ui_logic::buttons::{LowBandwidthButton, SettingsButton, VideoQualityButton};
+use crate::ui_logic::{KeyboardFocus, Slider};
+
+const SETTINGS_FONT_SIZE: f32 = 24.;
+/// Of the name put into the invites, which are shared in chats
+const MAX_NAME_CHARS: usize = 32;
+/// The scale is rounded to it, so it doesn't jitter while dragging
+const SCALE_STEP: f32 = 0.05;
+/// How often the data usage is shown again, it changes with every packet
+const USAGE_REFRESH: Duration = Duration::from_secs(1);
+
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<PeerListChangedEvent>();
+        app.init_resource::<VideoFilters>();
+        app.init_resource::<MotionDetection>();
+        app.init_resource::<CallRecording>();
+        app.init_resource::<CallAudio>();
+        app.init_resource::<CallQuality>();
+        app.insert_resource(VirtualCameraOutput {
+            device: find_loopback_device(),
+            on: false,
+        });
+        app.add_systems(Startup, open_camera_controls);
+        app.add_systems(PostStartup, spawn_settings_panel);
+        app.add_systems(Update, notify_recording);
+        app.add_systems(
+            Update,
+            (
+                toggle_settings_panel,
+                (
+                    drag_scale_slider,
+                    step_scale_slider,
+                    check_high_contrast_button,
+                    check_stream_scaling_button,
+                    check_recording_button,
+                    check_audio_channels_button,
+                    check_virtual_camera_button,
+                    check_language_button,
+                    check_display_name_field,
+                    check_video_quality_button,
+                    check_low_bandwidth_button,
+                ),
+                update_low_bandwidth_button,
+                show_data_usage,
+                update_theme_controls.run_if(resource_changed::<Theme>),
+                update_stream_scaling_button.run_if(resource_changed::<StreamScaling>),
+                apply_call_recording.run_if(resource_changed::<CallRecording>),
+                apply_call_audio.run_if(resource_changed::<CallAudio>),
+                apply_virtual_camera.run_if(resource_changed::<VirtualCameraOutput>),
+                show_language.run_if(resource_changed::<Language>),
+                apply_call_quality.run_if(resource_changed::<CallQuality>),
+                reopen_camera_controls.run_if(on_event::<OutgoingStreamHealthEvent>()),
+                (
+                    drag_camera_sliders,
+                    step_camera_sliders,
+                    check_camera_profile_buttons,
+                    check_background_blur_button,
+                    check_watermark_button,
+                    check_motion_button,
+                    check_camera_mode_button,
+                ),
+                update_camera_controls.run_if(resource_changed::<CameraSettings>),
+                apply_video_filters.run_if(resource_changed::<VideoFilters>),
+                apply_motion_detection.run_if(resource_changed::<MotionDetection>),
+                show_motion.run_if(on_event::<MotionEvent>()),
+                show_camera_mode.run_if(on_event::<OutgoingStreamHealthEvent>()),
+                check_remove_peer_buttons,
+                update_peer_list.run_if(on_event::<PeerListChangedEvent>()),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// The camera the outgoing stream captures from and the profiles of all the cameras
+/// * `controls` - None if the video doesn't come from a camera, or it cannot be opened
+/// * `values` - of the controls, as last read or set
+#[derive(Resource)]
+pub struct CameraSettings {
+    pub controls: Option<CameraControls>,
+    pub values: CameraProfile,
+    pub profiles: CameraProfiles,
+}
+
+impl CameraSettings {
+    /// The camera is opened at the start, see `open_camera_controls`
+    pub fn new(profiles: CameraProfiles) -> Self {
+        Self {
+            controls: None,
+            values: CameraProfile::new(),
+            profiles,
+        }
+    }
+    fn range(&self, control: CameraControl) -> Option<ControlRange> {
+        self.controls.as_ref()?.range(control)
+    }
+    /// Sets the control unless it has the value already
+    fn set(&mut self, control: CameraControl, value: i64) {
+        let Some(ref controls) = self.controls else {
+            return;
+        };
+        if self.values.get(&control) == Some(&value) {
+            return;
+        }
+        match controls.set(control, value) {
+            Ok(()) => {
+                self.values.insert(control, value);
+            }
+            Err(e) => warn!("{e}"),
+        }
+    }
+    fn read_values(&mut self) {
+        self.values = self
+            .controls
+            .as_ref()
+            .map(CameraControls::profile)
+            .unwrap_or_default();
+    }
+    fn apply_profile(&mut self) {
+        if let Some(ref controls) = self.controls {
+            if let Some(profile) = self.profiles.get(controls.name()) {
+                info!("Setting the saved profile of {}", controls.name());
+                controls.apply(profile);
+            }
+        }
+        self.read_values();
+    }
+}
+
+/// Filters applied to the camera frames before they are sent
+/// * `background_blur` - blur everything but the person, see `BackgroundBlur`
+/// * `watermark` - what's written into the corner, see `Watermark`
+/// * `label` - custom text of the watermark, given on the command line
+#[derive(Resource, Debug, Default)]
+pub struct VideoFilters {
+    pub background_blur: bool,
+    pub watermark: WatermarkContent,
+    pub label: Option<String>,
+}
+
+/// What the watermark of the outgoing video shows
+/// * `Name` - the instance name, i.e. where the camera is
+/// * `Label` - the custom label
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkContent {
+    #[default]
+    Off,
+    Name,
+    Time,
+    NameAndTime,
+    Label,
+    LabelAndTime,
+}
+
+impl WatermarkContent {
+    /// The next one, for a button cycling through them.
+    /// The label ones are skipped without a label
+    pub fn next(self, has_label: bool) -> Self {
+        match self {
+            Self::Off => Self::Name,
+            Self::Name => Self::Time,
+            Self::Time => Self::NameAndTime,
+            Self::NameAndTime if has_label => Self::Label,
+            Self::Label => Self::LabelAndTime,
+            Self::NameAndTime | Self::LabelAndTime => Self::Off,
+        }
+    }
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Name => "name",
+            Self::Time => "time",
+            Self::NameAndTime => "name and time",
+            Self::Label => "label",
+            Self::LabelAndTime => "label and time",
+        }
+    }
+}
+
+/// What the outgoing stream does with the motion in front of the camera, see `MotionMode`
+#[derive(Resource, Debug, Default)]
+pub struct MotionDetection(pub MotionMode);
+
+impl MotionDetection {
+    /// The next mode, for a button cycling through them
+    fn next(&self) -> Self {
+        Self(match self.0 {
+            MotionMode::Off => MotionMode::Report,
+            MotionMode::Report => MotionMode::Gate,
+            MotionMode::Gate => MotionMode::Off,
+        })
+    }
+}
+
+/// Whether and how the calls are recorded, see `eye_spy_core::recording`
+/// * `dir` - the recordings go there
+/// * `layout` - None not to record the calls
+#[derive(Resource, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CallRecording {
+    pub dir: PathBuf,
+    pub layout: Option<RecordingLayout>,
+}
+
+impl CallRecording {
+    /// The next layout, for a button cycling through them
+    fn next(&self) -> Self {
+        let layout = match self.layout {
+            None => Some(RecordingLayout::SideBySide),
+            Some(RecordingLayout::SideBySide) => Some(RecordingLayout::PictureInPicture),
+            Some(RecordingLayout::PictureInPicture) => None,
+        };
+        Self {
+            dir: self.dir.clone(),
+            layout,
+        }
+    }
+    fn config(&self) -> Option<RecordingConfig> {
+        self.layout.map(|layout| RecordingConfig {
+            dir: self.dir.clone(),
+            layout,
+        })
+    }
+}
+
+/// Channels the audio of the calls is captured and played in. Calls with a peer taking fewer
+/// go with those, see `eye_spy_core::audio`
+#[derive(Resource, Debug, Default)]
+pub struct CallAudio(pub AudioChannels);
+
+impl CallAudio {
+    /// The other layout, for a button toggling between them
+    fn next(&self) -> Self {
+        Self(match self.0 {
+            AudioChannels::Mono => AudioChannels::Stereo,
+            AudioChannels::Stereo => AudioChannels::Mono,
+        })
+    }
+}
+
+/// Quality of the outgoing video. A preset stops the call from lowering the quality
+/// for a peer that can't keep up, see `eye_spy_core::quality`
+#[derive(Resource, Debug, Default)]
+pub struct CallQuality(pub VideoQuality);
+
+/// Whether the peer's video is written into a v4l2loopback device too,
+/// see `eye_spy_core::virtual_camera`
+/// * `device` - the one found at the start, None if the module isn't loaded
+#[derive(Resource, Debug, Default)]
+pub struct VirtualCameraOutput {
+    pub device: Option<PathBuf>,
+    pub on: bool,
+}
+
+/// The peer list was changed, so the panel shows it again
+#[derive(Event)]
+pub struct PeerListChangedEvent;
+
+#[derive(Component)]
+struct SettingsPanel;
+
+/// The track of the scale slider
+#[derive(Component)]
+struct ScaleSlider;
+
+#[derive(Component)]
+struct ScaleSliderHandle;
+
+#[derive(Component)]
+struct ScaleText;
+
+#[derive(Component)]
+struct HighContrastButton;
+
+#[derive(Component)]
+struct StreamScalingButton;
+
+#[derive(Component)]
+struct RecordingButton;
+
+#[derive(Component)]
+struct AudioChannelsButton;
+
+#[derive(Component)]
+struct VirtualCameraButton;
+
+#[derive(Component)]
+struct LanguageButton;
+
+/// The name put into the invites, set with Enter
+#[derive(Component)]
+struct DisplayNameField;
+
+#[derive(Component)]
+struct DataUsageText;
+
+#[derive(Component)]
+struct CameraSlider(CameraControl);
+
+#[derive(Component)]
+struct CameraSliderHandle(CameraControl);
+
+#[derive(Component)]
+struct CameraControlText(CameraControl);
+
+/// Saves the values of the camera as its profile
+#[derive(Component)]
+struct SaveCameraProfileButton;
+
+/// Sets the camera back to the defaults of the driver
+#[derive(Component)]
+struct ResetCameraButton;
+
+#[derive(Component)]
+struct BackgroundBlurButton;
+
+#[derive(Component)]
+struct WatermarkButton;
+
+#[derive(Component)]
+struct MotionButton;
+
+/// Switches the camera to its next mode, labeled with the one it captures in
+#[derive(Component)]
+struct CameraModeButton;
+
+/// Tells whether there's motion now
+#[derive(Component)]
+struct MotionText;
+
+/// Holds a row for every peer on the list
+#[derive(Component)]
+struct PeerListContainer;
+
+/// Takes the peer off the list
+#[derive(Component)]
+struct RemovePeerButton(PeerEntry);
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+/// Opens the controls of the camera and sets its profile, if it has one
+fn open_camera_controls(call: Res<CallManagerBevy>, mut camera: ResMut<CameraSettings>) {
+    match call.0.outgoing().camera_controls() {
+        Ok(controls) => camera.controls = Some(controls),
+        Err(e) => info!("No camera controls: {e}"),
+    }
+    camera.apply_profile();
+}
+
+fn spawn_settings_panel(
+    mut spawner: UiSpawner,
+    call: Res<CallManagerBevy>,
+    camera: Res<CameraSettings>,
+    mut changed: EventWriter<PeerListChangedEvent>,
+) {
+    // Labeled by whether it's popped out, see `crate::pop_out`
+    let pop_out = spawner
+        .spawn_pretty_button_with_text(&tr!("pop_out"), SETTINGS_FONT_SIZE)
+        .id();
+    let scale_text = spawner
+        .spawn_pretty_text("", SETTINGS_FONT_SIZE)
+        .insert(ScaleText)
+        .id();
+    let handle = spawner
+        .commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Px(10.),
+                    height: Val::Percent(100.),
+                    ..Default::default()
+                },
+                background_color: BackgroundColor(spawner.theme.text()),
+                ..Default::default()
+            },
+            ScaleSliderHandle,
+        ))
+        .id();
+    let mut slider = spawner.spawn_pretty_button();
+    slider
+        .insert((ScaleSlider, Slider, RelativeCursorPosition::default()))
+        .add_child(handle);
+    let slider = slider.id();
+    let mut high_contrast = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+    high_contrast.insert(HighContrastButton);
+    let high_contrast = high_contrast.id();
+    let mut stream_scaling = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+    stream_scaling.insert(StreamScalingButton);
+    let stream_scaling = stream_scaling.id();
+    let mut recording = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+    recording.insert(RecordingButton);
+    let recording = recording.id();
+    let mut audio = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+    audio.insert(AudioChannelsButton);
+    let audio = audio.id();
+    let mut virtual_camera = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+    virtual_camera.insert(VirtualCameraButton);
+    let virtual_camera = virtual_camera.id();
+    let mut language = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+    language.insert(LanguageButton);
+    let language = language.id();
+    let mut name = TextInput::new("display_name_placeholder").with_max_chars(MAX_NAME_CHARS);
+    name.set_text(call.0.name().unwrap_or_default());
+    let name = spawner
+        .spawn_text_input(name, SETTINGS_FONT_SIZE)
+        .insert(DisplayNameField)
+        .id();
+    let usage = spawner
+        .spawn_pretty_text("", SETTINGS_FONT_SIZE)
+        .insert(DataUsageText)
+        .id();
+    let mode = call.0.outgoing().video_mode();
+    let camera = spawn_camera_controls(&mut spawner, &camera, mode, call.0.camera_modes());
+    let title = spawner
+        .spawn_translated_text("peer_list", SETTINGS_FONT_SIZE)
+        .id();
+    let list = spawner
+        .commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    display: Display::Flex,
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(5.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            PeerListContainer,
+        ))
+        .id();
+    let panel = spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Percent(10.),
+                left: Val::Percent(30.),
+                width: Val::Percent(40.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            5,
+        )
+        .insert((SettingsPanel, PopOut::new("eye-spy settings")))
+        .push_children(&[
+            pop_out,
+            scale_text,
+            slider,
+            high_contrast,
+            stream_scaling,
+            recording,
+            audio,
+            virtual_camera,
+            language,
+            name,
+            usage,
+            camera,
+            title,
+            list,
+        ])
+        .id();
+    spawner.commands.entity(pop_out).insert(PopOutButton(panel));
+    changed.send(PeerListChangedEvent);
+}
+
+/// A slider for every control the camera has, with the profile buttons under them.
+/// The mode can only be switched if the camera has more than one
+fn spawn_camera_controls(
+    spawner: &mut UiSpawner,
+    camera: &CameraSettings,
+    mode: Option<VideoMode>,
+    modes: &[VideoMode],
+) -> Entity {
+    let mut children = Vec::new();
+    let title = match camera.controls {
+        Some(ref controls) => format!("Camera: {}", controls.name()),
+        None => "No camera to set up".to_string(),
+    };
+    children.push(spawner.spawn_pretty_text(&title, SETTINGS_FONT_SIZE).id());
+    if let Some(mode) = mode {
+        let mode = format!("Sending {mode}");
+        if modes.len() > 1 {
+            let mut button = spawner.spawn_pretty_button_with_text(&mode, SETTINGS_FONT_SIZE);
+            button.insert(CameraModeButton);
+            children.push(button.id());
+        } else {
+            children.push(spawner.spawn_pretty_text(&mode, SETTINGS_FONT_SIZE).id());
+        }
+    }
+    for control in CameraControl::ALL {
+        if camera.range(control).is_none() {
+            continue;
+        }
+        let text = spawner
+            .spawn_pretty_text("", SETTINGS_FONT_SIZE)
+            .insert(CameraControlText(control))
+            .id();
+        let handle = spawner
+            .commands
+            .spawn((
+                NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        width: Val::Px(10.),
+                        height: Val::Percent(100.),
+                        ..Default::default()
+                    },
+                    background_color: BackgroundColor(spawner.theme.text()),
+                    ..Default::default()
+                },
+                CameraSliderHandle(control),
+            ))
+            .id();
+        let mut slider = spawner.spawn_pretty_button();
+        slider
+            .insert((
+                CameraSlider(control),
+                Slider,
+                RelativeCursorPosition::default(),
+            ))
+            .add_child(handle);
+        children.extend([text, slider.id()]);
+    }
+    if camera.controls.is_some() {
+        let mut blur = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+        blur.insert(BackgroundBlurButton);
+        children.push(blur.id());
+        let mut watermark = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+        watermark.insert(WatermarkButton);
+        children.push(watermark.id());
+        let mut motion = spawner.spawn_pretty_button_with_text("", SETTINGS_FONT_SIZE);
+        motion.insert(MotionButton);
+        let motion = motion.id();
+        let motion_text = spawner
+            .spawn_pretty_text("", SETTINGS_FONT_SIZE)
+            .insert(MotionText)
+            .id();
+        children.extend([motion, motion_text]);
+        let mut save = spawner.spawn_translated_button("save_camera_profile", SETTINGS_FONT_SIZE);
+        save.insert(SaveCameraProfileButton);
+        let save = save.id();
+        let mut reset = spawner.spawn_translated_button("reset_camera", SETTINGS_FONT_SIZE);
+        reset.insert(ResetCameraButton);
+        children.extend([save, reset.id()]);
+    }
+    spawner
+        .commands
+        .spawn(NodeBundle {
+            style: Style {
+                display: Display::Flex,
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(5.),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .push_children(&children)
+        .id()
+}
+
+/// A panel popped out stays open in its window until it's closed
+fn toggle_settings_panel(
+    query: Query<&Interaction, (Changed<Interaction>, With<SettingsButton>)>,
+    mut panel: Query<(&mut Style, &PopOut), With<SettingsPanel>>,
+) {
+    for interaction in &query {
+        if interaction != &Interaction::Pressed {
+            continue;
+        }
+        for (mut style, pop_out) in &mut panel {
+            if pop_out.is_detached() {
+                continue;
+            }
+            style.display = match style.display {
+                Display::None => Display::Flex,
+                _ => Display::None,
+            };
+        }
+    }
+}
+
+fn drag_scale_slider(
+    query: Query<(&Interaction, &RelativeCursorPosition), With<ScaleSlider>>,
+    mut theme: ResMut<Theme>,
+) {
+    for (interaction, cursor) in &query {
+        let (Interaction::Pressed, Some(position)) = (interaction, cursor.normalized) else {
+            continue;
+        };
+        let fraction = position.x.clamp(0., 1.);
+        let scale = Theme::MIN_SCALE + fraction * (Theme::MAX_SCALE - Theme::MIN_SCALE);
+        set_scale(&mut theme, scale);
+    }
+}
+fn step_scale_slider(
+    keys: Res<ButtonInput<KeyCode>>,
+    focus: Res<KeyboardFocus>,
+    slider: Query<(), With<ScaleSlider>>,
+    mut theme: ResMut<Theme>,
+) {
+    if !focus.0.is_some_and(|focused| slider.contains(focused)) {
+        return;
+    }
+    let scale = theme.scale;
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        set_scale(&mut theme, scale - 2. * SCALE_STEP);
+    } else if keys.just_pressed(KeyCode::ArrowRight) {
+        set_scale(&mut theme, scale + 2. * SCALE_STEP);
+    }
+}
+fn check_high_contrast_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<HighContrastButton>)>,
+    mut theme: ResMut<Theme>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            theme.high_contrast = !theme.high_contrast;
+        }
+    }
+}
+#[allow(clippy::type_complexity)]
+fn update_theme_controls(
+    theme: Res<Theme>,
+    mut handle: Query<(&mut Style, &mut BackgroundColor), With<ScaleSliderHandle>>,
+    mut scale_text: Query<&mut Text, With<ScaleText>>,
+    high_contrast: Query<&Children, With<HighContrastButton>>,
+    mut texts: Query<&mut Text, Without<ScaleText>>,
+) {
+    let fraction = (theme.scale - Theme::MIN_SCALE) / (Theme::MAX_SCALE - Theme::MIN_SCALE);
+    for (mut style, mut color) in &mut handle {
+        style.left = Val::Percent(fraction * 100.);
+        color.0 = theme.text();
+    }
+    for mut text in &mut scale_text {
+        text.sections[0].value = format!("UI scale: {:.2}x", theme.scale);
+    }
+    let label = if theme.high_contrast {
+        "High contrast: on"
+    } else {
+        "High contrast: off"
+    };
+    for children in &high_contrast {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.to_string();
+        }
+    }
+}
+fn check_stream_scaling_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<StreamScalingButton>)>,
+    mut scaling: ResMut<StreamScaling>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            *scaling = scaling.next();
+        }
+    }
+}
+fn update_stream_scaling_button(
+    scaling: Res<StreamScaling>,
+    button: Query<&Children, With<StreamScalingButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    let label = match *scaling {
+        StreamScaling::Nearest => "Video scaling: nearest",
+        StreamScaling::Bilinear => "Video scaling: bilinear",
+        StreamScaling::Sharpen => "Video scaling: sharpened",
+    };
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.to_string();
+        }
+    }
+}
+fn check_recording_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<RecordingButton>)>,
+    mut recording: ResMut<CallRecording>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            *recording = recording.next();
+        }
+    }
+}
+/// The ongoing call is only recorded if the recording was on when it started
+fn apply_call_recording(
+    recording: Res<CallRecording>,
+    mut call: ResMut<CallManagerBevy>,
+    button: Query<&Children, With<RecordingButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    call.0.set_recording(recording.config());
+    let label = match recording.layout {
+        None => "Record calls: off",
+        Some(RecordingLayout::SideBySide) => "Record calls: side by side",
+        Some(RecordingLayout::PictureInPicture) => "Record calls: picture-in-picture",
+    };
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.to_string();
+        }
+    }
+}
+/// Tells when a call starts and stops being recorded, and where it went
+fn notify_recording(
+    call: Res<CallManagerBevy>,
+    mut recording: Local<bool>,
+    mut toasts: EventWriter<ToastEvent>,
+) {
+    if call.0.is_recording() == *recording {
+        return;
+    }
+    *recording = call.0.is_recording();
+    let toast = match (*recording, call.0.last_recording()) {
+        (true, _) => ToastEvent::info(tr!("recording_started")),
+        (false, Some(path)) => ToastEvent::info(tr!("recording_saved", path = path.display())),
+        (false, None) => ToastEvent::error(tr!("recording_failed")),
+    };
+    toasts.send(toast);
+}
+fn check_audio_channels_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<AudioChannelsButton>)>,
+    mut audio: ResMut<CallAudio>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            *audio = audio.next();
+        }
+    }
+}
+/// The ongoing call keeps the channels it was negotiated with
+fn apply_call_audio(
+    audio: Res<CallAudio>,
+    mut call: ResMut<CallManagerBevy>,
+    button: Query<&Children, With<AudioChannelsButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    call.0.set_audio_channels(audio.0);
+    let label = format!("Audio: {}", audio.0);
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.clone();
+        }
+    }
+}
+fn check_language_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<LanguageButton>)>,
+    mut language: ResMut<Language>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            *language = language.next();
+        }
+    }
+}
+fn check_display_name_field(
+    mut submitted: EventReader<TextInputSubmitted>,
+    fields: Query<&TextInput, With<DisplayNameField>>,
+    mut focus: ResMut<TextInputFocus>,
+    mut call: ResMut<CallManagerBevy>,
+) {
+    for TextInputSubmitted(entity) in submitted.read() {
+        let Ok(field) = fields.get(*entity) else {
+            continue;
+        };
+        let name = field.text().trim();
+        info!("Name in the invites: {name:?}");
+        call.0
+            .set_name((!name.is_empty()).then(|| name.to_string()));
+        focus.0 = None;
+    }
+}
+fn show_language(
+    language: Res<Language>,
+    button: Query<&Children, With<LanguageButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    let label = tr!("language", language = language.name());
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.clone();
+        }
+    }
+}
+fn check_virtual_camera_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<VirtualCameraButton>)>,
+    mut output: ResMut<VirtualCameraOutput>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed && output.device.is_some() {
+            output.on = !output.on;
+        }
+    }
+}
+/// The device is opened again every time it's turned on, another app may have taken it meanwhile
+fn apply_virtual_camera(
+    output: Res<VirtualCameraOutput>,
+    call: Res<CallManagerBevy>,
+    button: Query<&Children, With<VirtualCameraButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    let label = match (&output.device, output.on) {
+        (None, _) => "Virtual camera: no v4l2loopback device".to_string(),
+        (Some(path), false) => {
+            call.0.set_frame_sink(None);
+            format!("Virtual camera: off ({})", path.display())
+        }
+        (Some(path), true) => match VirtualCamera::open(path) {
+            Ok(camera) => {
+                call.0.set_frame_sink(Some(Box::new(camera)));
+                format!("Virtual camera: on ({})", path.display())
+            }
+            Err(e) => {
+                warn!("{e:#}");
+                format!("Virtual camera: cannot open {}", path.display())
+            }
+        },
+    };
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value.clone_from(&label);
+        }
+    }
+}
+fn check_video_quality_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<VideoQualityButton>)>,
+    mut quality: ResMut<CallQuality>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            quality.0 = quality.0.next();
+        }
+    }
+}
+/// Mid-call too, the peer is told about the new camera mode
+fn apply_call_quality(
+    quality: Res<CallQuality>,
+    mut call: ResMut<CallManagerBevy>,
+    button: Query<&Children, With<VideoQualityButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    call.0.set_quality(quality.0);
+    let label = format!("Quality: {}", quality.0);
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.clone();
+        }
+    }
+}
+fn check_low_bandwidth_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<LowBandwidthButton>)>,
+    mut call: ResMut<CallManagerBevy>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            let low = !call.0.low_bandwidth_requested();
+            call.0.request_low_bandwidth(low);
+        }
+    }
+}
+/// The peer is also asked by `CallManager` itself when many packets get lost
+fn update_low_bandwidth_button(
+    call: Res<CallManagerBevy>,
+    mut shown: Local<Option<bool>>,
+    button: Query<&Children, With<LowBandwidthButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    let low = call.0.low_bandwidth_requested();
+    if *shown == Some(low) {
+        return;
+    }
+    *shown = Some(low);
+    let label = if low {
+        "Weak link: asked for less"
+    } else {
+        "Weak link? Ask for less"
+    };
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.to_string();
+        }
+    }
+}
+/// Counted by `CallManager` as the streams go
+fn show_data_usage(
+    call: Res<CallManagerBevy>,
+    mut shown_at: Local<Option<Instant>>,
+    mut texts: Query<&mut Text, With<DataUsageText>>,
+) {
+    if shown_at.is_some_and(|at| at.elapsed() < USAGE_REFRESH) {
+        return;
+    }
+    *shown_at = Some(Instant::now());
+    let description = describe_usage(call.0.data_usage(), call.0.monthly_cap());
+    for mut text in &mut texts {
+        if text.sections[0].value != description {
+            text.sections[0].value.clone_from(&description);
+        }
+    }
+}
+/// A camera plugged in again lost what was set, unless the driver stayed loaded
+fn reopen_camera_controls(
+    mut events: EventReader<OutgoingStreamHealthEvent>,
+    call: Res<CallManagerBevy>,
+    mut camera: ResMut<CameraSettings>,
+) {
+    if !events
+        .read()
+        .any(|event| event.0 == OutgoingStreamEvent::DeviceRecovered)
+    {
+        return;
+    }
+    match call.0.outgoing().camera_controls() {
+        Ok(controls) => camera.controls = Some(controls),
+        Err(e) => warn!("Cannot re-open the camera controls: {e}"),
+    }
+    camera.apply_profile();
+}
+fn drag_camera_sliders(
+    query: Query<(&Interaction, &RelativeCursorPosition, &CameraSlider)>,
+    mut camera: ResMut<CameraSettings>,
+) {
+    for (interaction, cursor, CameraSlider(control)) in &query {
+        let (Interaction::Pressed, Some(position)) = (interaction, cursor.normalized) else {
+            continue;
+        };
+        let Some(range) = camera.range(*control) else {
+            continue;
+        };
+        camera.set(*control, range.at(position.x));
+    }
+}
+fn step_camera_sliders(
+    keys: Res<ButtonInput<KeyCode>>,
+    focus: Res<KeyboardFocus>,
+    sliders: Query<&CameraSlider>,
+    mut camera: ResMut<CameraSettings>,
+) {
+    let Some(&CameraSlider(control)) = focus.0.and_then(|focused| sliders.get(focused).ok()) else {
+        return;
+    };
+    let (Some(range), Some(&value)) = (camera.range(control), camera.values.get(&control)) else {
+        return;
+    };
+    // About 20 presses from one end to the other
+    let step = ((range.max - range.min) / 20 / range.step).max(1) * range.step;
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        camera.set(control, value - step);
+    } else if keys.just_pressed(KeyCode::ArrowRight) {
+        camera.set(control, value + step);
+    }
+}
+#[allow(clippy::type_complexity)]
+fn check_camera_profile_buttons(
+    save: Query<&Interaction, (Changed<Interaction>, With<SaveCameraProfileButton>)>,
+    reset: Query<&Interaction, (Changed<Interaction>, With<ResetCameraButton>)>,
+    mut camera: ResMut<CameraSettings>,
+) {
+    let pressed = |interaction: &Interaction| interaction == &Interaction::Pressed;
+    if save.iter().any(pressed) {
+        let camera = &mut *camera;
+        if let Some(ref controls) = camera.controls {
+            info!("Saving the profile of {}", controls.name());
+            camera.profiles.save(controls.name(), controls.profile());
+        }
+    }
+    if reset.iter().any(pressed) {
+        if let Some(ref controls) = camera.controls {
+            controls.reset();
+        }
+        camera.read_values();
+    }
+}
+fn check_background_blur_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<BackgroundBlurButton>)>,
+    mut filters: ResMut<VideoFilters>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            filters.background_blur = !filters.background_blur;
+        }
+    }
+}
+fn check_watermark_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<WatermarkButton>)>,
+    mut filters: ResMut<VideoFilters>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            filters.watermark = filters.watermark.next(filters.label.is_some());
+        }
+    }
+}
+/// Sets the filters on the outgoing stream and shows them on the buttons.
+/// The watermark goes last, so it isn't blurred
+#[allow(clippy::type_complexity)]
+fn apply_video_filters(
+    filters: Res<VideoFilters>,
+    call: Res<CallManagerBevy>,
+    blur_button: Query<&Children, With<BackgroundBlurButton>>,
+    watermark_button: Query<&Children, With<WatermarkButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    let mut set: Vec<Box<dyn FrameFilter>> = Vec::new();
+    if filters.background_blur {
+        set.push(Box::new(BackgroundBlur::default()));
+    }
+    let name = || Some(call.0.invite().name.unwrap_or_else(|| "eye-spy".into()));
+    let (label, time) = match filters.watermark {
+        WatermarkContent::Off => (None, false),
+        WatermarkContent::Name => (name(), false),
+        WatermarkContent::Time => (None, true),
+        WatermarkContent::NameAndTime => (name(), true),
+        WatermarkContent::Label => (filters.label.clone(), false),
+        WatermarkContent::LabelAndTime => (filters.label.clone(), true),
+    };
+    if label.is_some() || time {
+        set.push(Box::new(Watermark {
+            label,
+            time,
+            ..Default::default()
+        }));
+    }
+    call.0.outgoing().set_filters(set);
+    let blur = if filters.background_blur {
+        "Background blur: on"
+    } else {
+        "Background blur: off"
+    };
+    let watermark = format!("Watermark: {}", filters.watermark.describe());
+    for (children, label) in blur_button.iter().map(|children| (children, blur)).chain(
+        watermark_button
+            .iter()
+            .map(|children| (children, watermark.as_str())),
+    ) {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.to_string();
+        }
+    }
+}
+fn check_motion_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<MotionButton>)>,
+    mut motion: ResMut<MotionDetection>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            *motion = motion.next();
+        }
+    }
+}
+/// The button is relabeled once the camera captures in the new mode, see `show_camera_mode`
+fn check_camera_mode_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<CameraModeButton>)>,
+    mut call: ResMut<CallManagerBevy>,
+) {
+    if !query
+        .iter()
+        .any(|interaction| interaction == &Interaction::Pressed)
+    {
+        return;
+    }
+    let Some(mode) = next_mode(call.0.camera_modes(), call.0.outgoing().video_mode()) else {
+        return;
+    };
+    if let Err(e) = call.0.set_video_mode(mode) {
+        warn!("Cannot switch the camera to {mode}: {e}");
+    }
+}
+fn show_camera_mode(
+    mut events: EventReader<OutgoingStreamHealthEvent>,
+    button: Query<&Children, With<CameraModeButton>>,
+    mut texts: Query<&mut Text>,
+) {
+    let Some(mode) = events.read().fold(None, |mode, event| match event.0 {
+        OutgoingStreamEvent::Reconfigured(mode) => Some(mode),
+        _ => mode,
+    }) else {
+        return;
+    };
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = format!("Sending {mode}");
+        }
+    }
+}
+fn apply_motion_detection(
+    motion: Res<MotionDetection>,
+    call: Res<CallManagerBevy>,
+    button: Query<&Children, With<MotionButton>>,
+    mut texts: Query<&mut Text>,
+    motion_text: Query<Entity, With<MotionText>>,
+) {
+    call.0.outgoing().set_motion_mode(motion.0);
+    let label = match motion.0 {
+        MotionMode::Off => "Motion detection: off",
+        MotionMode::Report => "Motion detection: on",
+        MotionMode::Gate => "Motion detection: send only on motion",
+    };
+    for children in &button {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = label.to_string();
+        }
+    }
+    // Nothing is known until the next frame
+    let mut iter = texts.iter_many_mut(&motion_text);
+    while let Some(mut text) = iter.fetch_next() {
+        text.sections[0].value.clear();
+    }
+}
+fn show_motion(
+    mut events: EventReader<MotionEvent>,
+    mut query: Query<&mut Text, With<MotionText>>,
+) {
+    let Some(MotionEvent(moving)) = events.read().last() else {
+        return;
+    };
+    let message = if *moving { "Motion now" } else { "No motion" };
+    for mut text in &mut query {
+        text.sections[0].value = message.to_string();
+    }
+}
+#[allow(clippy::type_complexity)]
+fn update_camera_controls(
+    camera: Res<CameraSettings>,
+    mut handles: Query<(&CameraSliderHandle, &mut Style)>,
+    mut texts: Query<(&CameraControlText, &mut Text)>,
+) {
+    for (CameraSliderHandle(control), mut style) in &mut handles {
+        let (Some(range), Some(&value)) = (camera.range(*control), camera.values.get(control))
+        else {
+            continue;
+        };
+        style.left = Val::Percent(range.fraction(value) * 100.);
+    }
+    for (CameraControlText(control), mut text) in &mut texts {
+        text.sections[0].value = match camera.values.get(control) {
+            Some(value) => format!("{control}: {value}"),
+            None => format!("{control}: ?"),
+        };
+    }
+}
+fn check_remove_peer_buttons(
+    query: Query<(&Interaction, &RemovePeerButton), Changed<Interaction>>,
+    call: Res<CallManagerBevy>,
+    mut changed: EventWriter<PeerListChangedEvent>,
+) {
+    for (interaction, RemovePeerButton(entry)) in &query {
+        if interaction != &Interaction::Pressed {
+            continue;
+        }
+        info!("Taking {entry} off the peer list");
+        call.0.scp().peer_list().remove(entry);
+        changed.send(PeerListChangedEvent);
+    }
+}
+
+fn update_peer_list(
+    call: Res<CallManagerBevy>,
+    container: Query<Entity, With<PeerListContainer>>,
+    mut spawner: UiSpawner,
+) {
+    let Ok(container) = container.get_single() else {
+        return;
+    };
+    let rows: Vec<_> = {
+        let peer_list = call.0.scp().peer_list();
+        let allowed = peer_list.allowed().iter().map(|e| ("Allowed", e.clone()));
+        let denied = peer_list.denied().iter().map(|e| ("Denied", e.clone()));
+        allowed.chain(denied).collect()
+    };
+    let mut children = Vec::with_capacity(rows.len().max(1));
+    if rows.is_empty() {
+        let empty = spawner.spawn_translated_text("anyone_can_call", SETTINGS_FONT_SIZE);
+        children.push(empty.id());
+    }
+    for (kind, entry) in rows {
+        let text = spawner
+            .spawn_pretty_text(&format!("{kind}: {entry}"), SETTINGS_FONT_SIZE)
+            .id();
+        let mut remove = spawner.spawn_translated_button("remove", SETTINGS_FONT_SIZE);
+        remove.insert(RemovePeerButton(entry));
+        let remove = remove.id();
+        let row = spawner
+            .commands
+            .spawn(NodeBundle {
+                style: Style {
+                    display: Display::Flex,
+                    justify_content: JustifyContent::SpaceBetween,
+                    align_items: AlignItems::Center,
+                    column_gap: Val::Px(10.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .push_children(&[text, remove])
+            .id();
+        children.push(row);
+    }
+    let mut container = spawner.commands.entity(container);
+    container.despawn_descendants();
+    container.push_children(&children);
+}
+
+/// Only changes the theme when the rounded scale differs, the whole UI is restyled then
+/// The mode after `current` in `modes`, the first one after the last
+/// i.e. 850 kB, 12.3 MB or 1.20 GB
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=999_999 => format!("{} kB", bytes / 1000),
+        1_000_000..=999_999_999 => format!("{:.1} MB", bytes as f64 / 1e6),
+        _ => format!("{:.2} GB", bytes as f64 / 1e9),
+    }
+}
+fn format_usage(usage: Usage) -> String {
+    format!(
+        "{} ({} sent)",
+        format_bytes(usage.total()),
+        format_bytes(usage.sent)
+    )
+}
+/// The usage as lines of text for the panel
+fn describe_usage(usage: &DataUsage, cap: Option<MonthlyCap>) -> String {
+    let month = usage.this_month();
+    let mut month_line = format!("Data this month: {}", format_usage(month));
+    if let Some(cap) = cap {
+        month_line.push_str(&format!(" of {}", format_bytes(cap.bytes)));
+        if cap.is_near(month) {
+            month_line.push_str(", nearly used up");
+        }
+    }
+    [
+        format!("Data this call: {}", format_usage(usage.call())),
+        month_line,
+        format!("Data in total: {}", format_usage(usage.all_time())),
+    ]
+    .join("\n")
+}
+fn next_mode(modes: &[VideoMode], current: Option<VideoMode>) -> Option<VideoMode> {
+    let next = current
+        .and_then(|current| modes.iter().position(|mode| *mode == current))
+        .map_or(0, |at| (at + 1) % modes.len());
+    modes.get(next).copied()
+}
+fn set_scale(theme: &mut ResMut<Theme>, scale: f32) {
+    let scale =
+        ((scale / SCALE_STEP).round() * SCALE_STEP).clamp(Theme::MIN_SCALE, Theme::MAX_SCALE);
+    if (theme.scale - scale).abs() > f32::EPSILON {
+        theme.scale = scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eye_spy_core::{DataUsage, MonthlyCap};
+
+    use super::{describe_usage, format_bytes};
+
+    #[test]
+    fn test_describe_usage() {
+        assert_eq!(format_bytes(850_000), "850 kB");
+        assert_eq!(format_bytes(12_345_678), "12.3 MB");
+        assert_eq!(format_bytes(1_200_000_000), "1.20 GB");
+        let mut usage = DataUsage::in_memory();
+        usage.record(4_000_000, 1_000_000);
+        let cap = MonthlyCap {
+            bytes: 5_000_000,
+            downgrade: false,
+        };
+        let text = describe_usage(&usage, Some(cap));
+        assert!(text.starts_with("Data this call: 5.0 MB (4.0 MB sent)\n"));
+        assert!(text.contains("Data this month: 5.0 MB (4.0 MB sent) of 5.0 MB, nearly used up"));
+        assert!(!describe_usage(&usage, None).contains(" of "));
+    }
+}
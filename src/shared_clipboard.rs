@@ -0,0 +1,154 @@
+//! Text shared through the clipboards of both sides during a call, i.e. a link.
+//! The text copied is sent to the peer with a button. The text the peer sends is shown
+//! first, and only copied into the clipboard once the user says so, as it'd overwrite it.
+
+use bevy::prelude::*;
+use scp_client::scp::ClipboardText;
+
+use crate::connection_state_bevy::ClipboardTextEvent;
+use crate::locale::{tr, Language};
+use crate::plugin::CallManagerBevy;
+use crate::toast::ToastEvent;
+use crate::ui_logic::buttons::SendClipboardButton;
+use crate::ui_logic::AppClipboard;
+
+/// Characters of the text the peer sent shown in the prompt, the rest is cut off
+const PREVIEW_LEN: usize = 200;
+
+pub struct SharedClipboardPlugin;
+
+impl Plugin for SharedClipboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReceivedClipboard>();
+        app.add_systems(
+            Update,
+            (
+                check_send_clipboard_button,
+                on_clipboard_text.run_if(on_event::<ClipboardTextEvent>()),
+                check_clipboard_prompt_buttons,
+                update_clipboard_prompt.run_if(
+                    resource_changed::<ReceivedClipboard>.or_else(resource_changed::<Language>),
+                ),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// The latest text the peer sent, until it's copied or ignored
+#[derive(Resource, Debug, Default)]
+pub struct ReceivedClipboard(pub Option<String>);
+
+/// Marker for the prompt asking whether to copy the text the peer sent
+#[derive(Component)]
+pub struct ClipboardPrompt;
+
+/// Marker for the text of the prompt, showing what the peer sent
+#[derive(Component)]
+pub struct ClipboardPromptText;
+
+#[derive(Component)]
+pub struct CopyClipboardButton;
+
+#[derive(Component)]
+pub struct IgnoreClipboardButton;
+
+/// The start of `text` shown in the prompt, cut off after `PREVIEW_LEN` characters
+fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn check_send_clipboard_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<SendClipboardButton>)>,
+    call: Res<CallManagerBevy>,
+    mut clipboard: NonSendMut<AppClipboard>,
+    mut toasts: EventWriter<ToastEvent>,
+) {
+    for interaction in &query {
+        if interaction != &Interaction::Pressed {
+            continue;
+        }
+        let text = clipboard.text().unwrap_or_default();
+        let toast = if text.is_empty() {
+            ToastEvent::warning(tr!("clipboard_empty"))
+        } else if text.len() > ClipboardText::MAX_LEN {
+            ToastEvent::warning(tr!("clipboard_too_long"))
+        } else if call.0.send_clipboard(text) {
+            ToastEvent::info(tr!("clipboard_sent"))
+        } else {
+            continue;
+        };
+        toasts.send(toast);
+    }
+}
+/// A newer text replaces the one not answered yet
+fn on_clipboard_text(
+    mut events: EventReader<ClipboardTextEvent>,
+    mut received: ResMut<ReceivedClipboard>,
+) {
+    if let Some(event) = events.read().last() {
+        received.0 = Some(event.0.clone());
+    }
+}
+#[allow(clippy::type_complexity)]
+fn check_clipboard_prompt_buttons(
+    copy: Query<&Interaction, (Changed<Interaction>, With<CopyClipboardButton>)>,
+    ignore: Query<&Interaction, (Changed<Interaction>, With<IgnoreClipboardButton>)>,
+    mut received: ResMut<ReceivedClipboard>,
+    mut clipboard: NonSendMut<AppClipboard>,
+    mut toasts: EventWriter<ToastEvent>,
+) {
+    let pressed = |interaction: &Interaction| *interaction == Interaction::Pressed;
+    if received.0.is_none() {
+        return;
+    }
+    if copy.iter().any(pressed) {
+        if let Some(text) = received.0.take() {
+            if clipboard.set_text(text) {
+                toasts.send(ToastEvent::info(tr!("clipboard_copied")));
+            }
+        }
+    } else if ignore.iter().any(pressed) {
+        received.0 = None;
+    }
+}
+fn update_clipboard_prompt(
+    received: Res<ReceivedClipboard>,
+    mut prompt: Query<&mut Style, With<ClipboardPrompt>>,
+    mut texts: Query<&mut Text, With<ClipboardPromptText>>,
+) {
+    for mut style in &mut prompt {
+        style.display = if received.0.is_some() {
+            Display::Flex
+        } else {
+            Display::None
+        };
+    }
+    let Some(ref text) = received.0 else {
+        return;
+    };
+    let message = tr!("clipboard_received", text = preview(text));
+    for mut text in &mut texts {
+        text.sections[0].value.clone_from(&message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{preview, PREVIEW_LEN};
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview("https://example.org"), "https://example.org");
+        let long = "ż".repeat(PREVIEW_LEN + 1);
+        assert_eq!(preview(&long), format!("{}...", "ż".repeat(PREVIEW_LEN)));
+        assert_eq!(preview(&long[..PREVIEW_LEN * 2]), "ż".repeat(PREVIEW_LEN));
+    }
+}
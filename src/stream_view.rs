@@ -0,0 +1,623 @@
+//! How the incoming stream is scaled up to the stream window.
+//! The peer sends 640x480 at most, which looks blocky or blurry on a large window,
+//! so the sampling can be picked in the settings, up to a sharpening shader.
+//! Some cameras deliver flipped or rotated images, so the stream can be turned
+//! and mirrored during a call. That's remembered for the peer, see `StreamOrientations`.
+//! When the frames stop coming, the last one is dimmed and labeled, see `RemoteStreamStatus`.
+//! Packets still coming without frames decoding out of them are shown as an unstable connection.
+//! The stream window takes the aspect ratio of the mode the peer captures in, which may change
+//! mid-call, see `RemoteVideoMode`, or else of the frames it sends, see `IncomingFrameSize`.
+
+use std::f32::consts::FRAC_PI_2;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::render::render_resource::{AsBindGroup, ShaderRef};
+use bevy::render::texture::ImageSampler;
+use eye_spy_core::h264_stream::{HEIGHT, WIDTH};
+use scp_client::client::VideoMode;
+use scp_client::peer_list::PeerEntry;
+use scp_client::scp::ControlMessage;
+
+use crate::connection_state_bevy::{
+    ConnectionEvent, ControlMessageEvent, IncomingVideoStreamState, LoopbackCallEvent,
+};
+use crate::plugin::{CallManagerBevy, STREAM_IMAGE_HANDLE};
+use crate::ui::UiContainers;
+use crate::ui_logic::buttons::{MirrorStreamButton, RotateStreamButton};
+
+const SHARPEN_SHADER_PATH: &str = "shaders/sharpen.wgsl";
+/// How much the sharpening shader adds to the edges
+const SHARPEN_STRENGTH: f32 = 0.8;
+/// No new frame for this long and the stream counts as paused
+const PAUSED_AFTER: Duration = Duration::from_millis(1500);
+/// How often a dot is added to the label of a frozen stream, so it's seen to be waiting
+const SPINNER_STEP: Duration = Duration::from_millis(400);
+
+pub struct StreamViewPlugin;
+
+impl Plugin for StreamViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(UiMaterialPlugin::<SharpenMaterial>::default());
+        app.init_resource::<StreamScaling>();
+        app.init_resource::<StreamOrientation>();
+        app.init_resource::<RemoteStreamStatus>();
+        app.init_resource::<RemoteVideoMode>();
+        app.init_resource::<IncomingFrameSize>();
+        app.add_systems(
+            Update,
+            apply_stream_scaling.run_if(
+                resource_changed::<StreamScaling>.and_then(resource_exists::<UiContainers>),
+            ),
+        );
+        app.add_systems(
+            OnEnter(IncomingVideoStreamState::On),
+            (show_orientation_controls, reset_remote_stream_status),
+        );
+        app.add_systems(
+            OnEnter(IncomingVideoStreamState::Off),
+            (
+                hide_orientation_controls,
+                hide_stream_status,
+                reset_remote_video_mode,
+            ),
+        );
+        app.add_systems(
+            Update,
+            (
+                update_remote_stream_status,
+                show_stream_status.run_if(resource_changed::<RemoteStreamStatus>),
+                spin_frozen_status.run_if(resource_equals(RemoteStreamStatus::Frozen)),
+            )
+                .chain()
+                .run_if(in_state(IncomingVideoStreamState::On)),
+        );
+        app.add_systems(
+            Update,
+            (
+                on_connection.run_if(on_event::<ConnectionEvent>()),
+                on_loopback_call.run_if(on_event::<LoopbackCallEvent>()),
+                on_remote_video_mode.run_if(on_event::<ControlMessageEvent>()),
+                resize_stream_window.run_if(
+                    resource_changed::<RemoteVideoMode>
+                        .or_else(resource_changed::<IncomingFrameSize>)
+                        .and_then(resource_exists::<UiContainers>),
+                ),
+                check_orientation_buttons,
+                apply_stream_orientation.run_if(
+                    resource_changed::<StreamOrientation>.and_then(resource_exists::<UiContainers>),
+                ),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// Mode the peer's camera captures in, None if it's not known
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteVideoMode(pub Option<VideoMode>);
+
+/// Size of the frames the peer sends, as last decoded
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncomingFrameSize(pub UVec2);
+
+impl Default for IncomingFrameSize {
+    fn default() -> Self {
+        Self(UVec2::new(WIDTH as u32, HEIGHT as u32))
+    }
+}
+
+/// Size of the stream window, `WIDTH` wide with the aspect ratio of the peer's mode.
+/// The frames may be scaled from it, so their own size only counts if the mode isn't known
+fn stream_window_size(mode: Option<VideoMode>, frame: UVec2) -> Vec2 {
+    let size = match mode {
+        Some(mode) => UVec2::new(mode.width.into(), mode.height.into()),
+        None => frame,
+    };
+    if size.x == 0 || size.y == 0 {
+        return Vec2::new(WIDTH as f32, HEIGHT as f32);
+    }
+    Vec2::new(WIDTH as f32, WIDTH as f32 * size.y as f32 / size.x as f32)
+}
+
+/// How the incoming stream is sampled when it's scaled
+/// * `Nearest` - sharp, blocky pixels
+/// * `Bilinear` - smooth, but blurry
+/// * `Sharpen` - bilinear, sharpened by `SHARPEN_SHADER_PATH`
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StreamScaling {
+    Nearest,
+    #[default]
+    Bilinear,
+    Sharpen,
+}
+
+impl StreamScaling {
+    /// The next one, for a button cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Nearest => Self::Bilinear,
+            Self::Bilinear => Self::Sharpen,
+            Self::Sharpen => Self::Nearest,
+        }
+    }
+    /// Sampler of the stream image
+    pub fn sampler(self) -> ImageSampler {
+        match self {
+            Self::Nearest => ImageSampler::nearest(),
+            Self::Bilinear | Self::Sharpen => ImageSampler::linear(),
+        }
+    }
+}
+
+/// Draws the stream image sharpened
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+pub struct SharpenMaterial {
+    /// x - strength, yz - size of a texel of the stream
+    #[uniform(0)]
+    params: Vec4,
+    #[texture(1)]
+    #[sampler(2)]
+    texture: Handle<Image>,
+}
+
+impl SharpenMaterial {
+    /// The stream image is `width` x `height` from now on
+    pub fn set_texture_size(&mut self, width: usize, height: usize) {
+        self.params.y = 1. / width as f32;
+        self.params.z = 1. / height as f32;
+    }
+}
+
+impl UiMaterial for SharpenMaterial {
+    fn fragment_shader() -> ShaderRef {
+        SHARPEN_SHADER_PATH.into()
+    }
+}
+
+/// The material the stream window uses while sharpening.
+/// The stream image is replaced every frame, so it has to be touched every frame too
+#[derive(Resource)]
+pub struct SharpenMaterialHandle(pub Handle<SharpenMaterial>);
+
+/// How the stream is turned on the screen
+/// * `quarter_turns` - clockwise, 0 to 3
+/// * `mirrored` - flipped horizontally, before turning
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Orientation {
+    pub quarter_turns: u8,
+    pub mirrored: bool,
+}
+
+impl Orientation {
+    /// Turned by another 90 degrees
+    pub fn rotated(self) -> Self {
+        Self {
+            quarter_turns: (self.quarter_turns + 1) % 4,
+            ..self
+        }
+    }
+    pub fn transform(self) -> Transform {
+        // UI coordinates grow downwards, so a positive angle turns clockwise
+        let mut transform =
+            Transform::from_rotation(Quat::from_rotation_z(FRAC_PI_2 * self.quarter_turns as f32));
+        if self.mirrored {
+            transform.scale.x = -1.;
+        }
+        transform
+    }
+}
+
+/// The orientation picked for each peer, so their stream is shown right in the next call too.
+/// Saved after every change when loaded from a file, one `<peer> <quarter turns> <mirrored>` a line
+#[derive(Resource, Debug, Default)]
+pub struct StreamOrientations {
+    path: Option<PathBuf>,
+    entries: Vec<(PeerEntry, Orientation)>,
+}
+
+impl StreamOrientations {
+    /// Orientations that aren't saved anywhere
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+    /// Loads the orientations stored at `path`, the file is created once one is picked.
+    /// Lines that cannot be parsed are skipped
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let entries = text.lines().filter_map(Self::parse_line).collect();
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            entries,
+        })
+    }
+    fn parse_line(line: &str) -> Option<(PeerEntry, Orientation)> {
+        let mut fields = line.split_whitespace();
+        let peer = fields.next()?.parse().ok()?;
+        let quarter_turns = fields.next()?.parse::<u8>().ok()? % 4;
+        let mirrored = fields.next()?.parse().ok()?;
+        Some((
+            peer,
+            Orientation {
+                quarter_turns,
+                mirrored,
+            },
+        ))
+    }
+    pub fn get(&self, peer: &PeerEntry) -> Orientation {
+        self.entries
+            .iter()
+            .find_map(|(entry, orientation)| (entry == peer).then_some(*orientation))
+            .unwrap_or_default()
+    }
+    /// Remember the orientation of the peer, the default one is forgotten
+    pub fn set(&mut self, peer: PeerEntry, orientation: Orientation) {
+        self.entries.retain(|(entry, _)| *entry != peer);
+        if orientation != Orientation::default() {
+            self.entries.push((peer, orientation));
+        }
+        if let Err(e) = self.save() {
+            warn!("Cannot save the stream orientations: {e}");
+        }
+    }
+    fn save(&self) -> std::io::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text: String = self
+            .entries
+            .iter()
+            .map(|(peer, o)| format!("{peer} {} {}\n", o.quarter_turns, o.mirrored))
+            .collect();
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+/// Orientation of the stream in the current call
+/// * `peer` - who sends the stream, None outside of calls
+#[derive(Resource, Debug, Default)]
+pub struct StreamOrientation {
+    pub peer: Option<PeerEntry>,
+    pub orientation: Orientation,
+}
+
+/// Holds the buttons turning the stream, shown during calls
+#[derive(Component)]
+pub struct StreamOrientationControls;
+
+/// Whether the frames of the peer keep coming during a call
+/// * `Waiting` - nothing was decoded yet
+/// * `Paused` - the frames stopped, the peer paused its camera or the connection is lost.
+///   The last frame stays on the screen, dimmed
+/// * `Frozen` - the packets keep coming but no frame decodes out of them, the connection
+///   is unstable. `CallManager` asks the peer for keyframes until it recovers
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteStreamStatus {
+    #[default]
+    Waiting,
+    Live,
+    Paused,
+    Frozen,
+}
+
+impl RemoteStreamStatus {
+    /// Status of a stream whose last frame was decoded `age` ago, None if none was yet.
+    /// See `H264IncomingStreamControls::is_frozen` for `frozen`
+    pub fn from_frame_age(age: Option<Duration>, frozen: bool) -> Self {
+        match age {
+            None => Self::Waiting,
+            Some(_) if frozen => Self::Frozen,
+            Some(age) if age < PAUSED_AFTER => Self::Live,
+            Some(_) => Self::Paused,
+        }
+    }
+    /// Shown over the stream, nothing while it's live
+    fn label(self) -> Option<&'static str> {
+        match self {
+            Self::Waiting => Some("Waiting for video"),
+            Self::Live => None,
+            Self::Paused => Some("Paused"),
+            Self::Frozen => Some("Connection unstable"),
+        }
+    }
+}
+
+/// Dims the stream window while the stream isn't live
+#[derive(Component)]
+pub struct StreamStatusOverlay;
+
+/// Tells why the stream isn't live, kept upright however the stream is turned
+#[derive(Component)]
+pub struct StreamStatusText;
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+/// Swaps the plain image of the stream window for the sharpening material and back
+fn apply_stream_scaling(
+    mut commands: Commands,
+    scaling: Res<StreamScaling>,
+    containers: Res<UiContainers>,
+    mut materials: ResMut<Assets<SharpenMaterial>>,
+    material: Option<Res<SharpenMaterialHandle>>,
+) {
+    if *scaling != StreamScaling::Sharpen {
+        commands
+            .entity(containers.stream_window)
+            .remove::<Handle<SharpenMaterial>>()
+            .insert(UiImage::new(STREAM_IMAGE_HANDLE).with_flip_x());
+        return;
+    }
+    let handle = match material {
+        Some(material) => material.0.clone(),
+        None => {
+            let handle = materials.add(SharpenMaterial {
+                params: Vec4::new(SHARPEN_STRENGTH, 1. / WIDTH as f32, 1. / HEIGHT as f32, 0.),
+                texture: STREAM_IMAGE_HANDLE,
+            });
+            commands.insert_resource(SharpenMaterialHandle(handle.clone()));
+            handle
+        }
+    };
+    commands
+        .entity(containers.stream_window)
+        .remove::<UiImage>()
+        .insert(handle);
+}
+
+fn show_orientation_controls(mut query: Query<&mut Style, With<StreamOrientationControls>>) {
+    for mut style in &mut query {
+        style.display = Display::Flex;
+    }
+}
+/// The placeholder shown outside of calls isn't turned
+fn hide_orientation_controls(
+    mut query: Query<&mut Style, With<StreamOrientationControls>>,
+    mut current: ResMut<StreamOrientation>,
+) {
+    for mut style in &mut query {
+        style.display = Display::None;
+    }
+    *current = StreamOrientation::default();
+}
+/// Whatever the last call ended with, the next one starts without frames
+fn reset_remote_stream_status(mut status: ResMut<RemoteStreamStatus>) {
+    *status = RemoteStreamStatus::Waiting;
+}
+fn hide_stream_status(mut query: Query<&mut Style, With<StreamStatusOverlay>>) {
+    for mut style in &mut query {
+        style.display = Display::None;
+    }
+}
+fn update_remote_stream_status(call: Res<CallManagerBevy>, mut status: ResMut<RemoteStreamStatus>) {
+    let incoming = call.0.incoming();
+    let current =
+        RemoteStreamStatus::from_frame_age(incoming.last_frame_age(), incoming.is_frozen());
+    if status.set_if_neq(current) {
+        info!("Incoming stream: {current:?}");
+    }
+}
+fn show_stream_status(
+    status: Res<RemoteStreamStatus>,
+    mut overlays: Query<&mut Style, With<StreamStatusOverlay>>,
+    mut texts: Query<&mut Text, With<StreamStatusText>>,
+) {
+    let label = status.label();
+    for mut style in &mut overlays {
+        style.display = if label.is_some() {
+            Display::Flex
+        } else {
+            Display::None
+        };
+    }
+    for mut text in &mut texts {
+        text.sections[0].value = label.unwrap_or_default().to_string();
+    }
+}
+/// Dots after the label of a frozen stream, one more every `SPINNER_STEP`
+fn spin_frozen_status(time: Res<Time>, mut texts: Query<&mut Text, With<StreamStatusText>>) {
+    let label = RemoteStreamStatus::Frozen.label().unwrap_or_default();
+    let dots = (time.elapsed().as_millis() / SPINNER_STEP.as_millis()) % 4;
+    for mut text in &mut texts {
+        text.sections[0].value = format!("{label}{}", ".".repeat(dots as usize));
+    }
+}
+fn on_connection(
+    mut events: EventReader<ConnectionEvent>,
+    orientations: Res<StreamOrientations>,
+    mut current: ResMut<StreamOrientation>,
+    mut remote_mode: ResMut<RemoteVideoMode>,
+) {
+    for ConnectionEvent(config) in events.read() {
+        let peer = PeerEntry::of_peer(config.ip, config.peer_identity.as_ref());
+        current.orientation = orientations.get(&peer);
+        current.peer = Some(peer);
+        remote_mode.set_if_neq(RemoteVideoMode(config.video_mode));
+    }
+}
+/// The peer switched its camera to another mode mid-call
+fn on_remote_video_mode(
+    mut events: EventReader<ControlMessageEvent>,
+    mut remote_mode: ResMut<RemoteVideoMode>,
+) {
+    for event in events.read() {
+        if let ControlMessage::VideoMode(mode) = event.0 {
+            info!("The peer captures {mode}");
+            remote_mode.set_if_neq(RemoteVideoMode(Some(mode)));
+        }
+    }
+}
+fn reset_remote_video_mode(mut remote_mode: ResMut<RemoteVideoMode>) {
+    remote_mode.set_if_neq(RemoteVideoMode::default());
+}
+fn resize_stream_window(
+    remote_mode: Res<RemoteVideoMode>,
+    frame_size: Res<IncomingFrameSize>,
+    containers: Res<UiContainers>,
+    mut query: Query<&mut Style>,
+) {
+    let size = stream_window_size(remote_mode.0, frame_size.0);
+    // The tiles share it, see `video_tile`
+    if let Ok(mut style) = query.get_mut(containers.video_grid) {
+        style.width = Val::Px(size.x);
+        style.height = Val::Px(size.y);
+    }
+}
+/// Your own camera is remembered as the loopback address
+fn on_loopback_call(orientations: Res<StreamOrientations>, mut current: ResMut<StreamOrientation>) {
+    let peer = PeerEntry::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    current.orientation = orientations.get(&peer);
+    current.peer = Some(peer);
+}
+#[allow(clippy::type_complexity)]
+fn check_orientation_buttons(
+    rotate: Query<&Interaction, (Changed<Interaction>, With<RotateStreamButton>)>,
+    mirror: Query<&Interaction, (Changed<Interaction>, With<MirrorStreamButton>)>,
+    mut orientations: ResMut<StreamOrientations>,
+    mut current: ResMut<StreamOrientation>,
+) {
+    let pressed = |interaction: &Interaction| interaction == &Interaction::Pressed;
+    let mut orientation = current.orientation;
+    if rotate.iter().any(pressed) {
+        orientation = orientation.rotated();
+    }
+    if mirror.iter().any(pressed) {
+        orientation.mirrored = !orientation.mirrored;
+    }
+    if orientation == current.orientation {
+        return;
+    }
+    current.orientation = orientation;
+    if let Some(ref peer) = current.peer {
+        orientations.set(peer.clone(), orientation);
+    }
+}
+fn apply_stream_orientation(
+    current: Res<StreamOrientation>,
+    containers: Res<UiContainers>,
+    mut query: Query<&mut Transform, Without<StreamStatusText>>,
+    mut texts: Query<&mut Transform, With<StreamStatusText>>,
+) {
+    let oriented = current.orientation.transform();
+    if let Ok(mut transform) = query.get_mut(containers.stream_window) {
+        // The layout keeps the translation up to date
+        transform.rotation = oriented.rotation;
+        transform.scale = oriented.scale;
+    }
+    // Undo the turn for the text. Mirroring turns the other way, so it's undone by turning along
+    for mut transform in &mut texts {
+        transform.rotation = if current.orientation.mirrored {
+            oriented.rotation
+        } else {
+            oriented.rotation.inverse()
+        };
+        transform.scale = oriented.scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scp_client::peer_list::PeerEntry;
+
+    use std::time::Duration;
+
+    use bevy::math::{UVec2, Vec2};
+    use eye_spy_core::h264_stream::{HEIGHT, WIDTH};
+    use scp_client::client::VideoMode;
+
+    use super::{
+        stream_window_size, Orientation, RemoteStreamStatus, StreamOrientations, PAUSED_AFTER,
+    };
+
+    #[test]
+    fn test_stream_orientations_persist() {
+        let path =
+            std::env::temp_dir().join(format!("eye-spy-orientations-{}", std::process::id()));
+        let peer: PeerEntry = "10.0.0.2".parse().unwrap();
+        let turned = Orientation {
+            quarter_turns: 3,
+            mirrored: true,
+        };
+        let mut orientations = StreamOrientations::load(&path).unwrap();
+        orientations.set(peer.clone(), turned);
+        orientations.set(
+            "3f2a 91c0 77de 0b14".parse().unwrap(),
+            Orientation::default().rotated(),
+        );
+
+        let mut loaded = StreamOrientations::load(&path).unwrap();
+        assert_eq!(loaded.get(&peer), turned);
+        assert_eq!(
+            loaded.get(&"10.0.0.3".parse().unwrap()),
+            Orientation::default()
+        );
+        // Turned all the way back, nothing to remember
+        loaded.set(peer.clone(), Orientation::default());
+        let loaded = StreamOrientations::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.entries.len(), 1);
+        assert_eq!(
+            Orientation::default()
+                .rotated()
+                .rotated()
+                .rotated()
+                .rotated(),
+            Orientation::default()
+        );
+    }
+    #[test]
+    fn test_remote_stream_status() {
+        assert_eq!(
+            RemoteStreamStatus::from_frame_age(None, false),
+            RemoteStreamStatus::Waiting
+        );
+        assert_eq!(
+            RemoteStreamStatus::from_frame_age(Some(Duration::from_millis(40)), false),
+            RemoteStreamStatus::Live
+        );
+        assert_eq!(
+            RemoteStreamStatus::from_frame_age(Some(PAUSED_AFTER), false),
+            RemoteStreamStatus::Paused
+        );
+        // The packets keep coming
+        assert_eq!(
+            RemoteStreamStatus::from_frame_age(Some(PAUSED_AFTER), true),
+            RemoteStreamStatus::Frozen
+        );
+    }
+    #[test]
+    fn test_stream_window_size() {
+        let default = Vec2::new(WIDTH as f32, HEIGHT as f32);
+        let frame = UVec2::new(WIDTH as u32, HEIGHT as u32);
+        assert_eq!(stream_window_size(None, frame), default);
+        let wide = VideoMode {
+            width: 1280,
+            height: 720,
+            fps: 30,
+        };
+        let wide_size = Vec2::new(WIDTH as f32, WIDTH as f32 * 9. / 16.);
+        assert_eq!(stream_window_size(Some(wide), frame), wide_size);
+        // A peer sending frames of its own size
+        assert_eq!(stream_window_size(None, UVec2::new(1280, 720)), wide_size);
+        let broken = VideoMode {
+            width: 0,
+            height: 0,
+            fps: 0,
+        };
+        assert_eq!(stream_window_size(Some(broken), frame), default);
+        assert_eq!(stream_window_size(None, UVec2::ZERO), default);
+    }
+}
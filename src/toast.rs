@@ -0,0 +1,265 @@
+//! Short messages popping up in the corner, i.e. the peer that joined or a failed call.
+//! Send a `ToastEvent` to show one. They're queued and shown a few at a time,
+//! each fading out after `TOAST_DURATION`. The border tells the severity, see `Severity`.
+//! The peer may ask to hold the info ones back during a call, see `ToastsMuted`.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_tweening::lens::{TextColorLens, UiBackgroundColorLens};
+use bevy_tweening::{Animator, EaseFunction, Tween, TweenCompleted};
+
+use crate::ui::{Theme, UiSpawner};
+
+/// How long a toast is shown before fading out
+const TOAST_DURATION: Duration = Duration::from_secs(4);
+const TOAST_FADE: Duration = Duration::from_millis(500);
+/// Shown at once, the rest waits in the queue
+const MAX_SHOWN: usize = 3;
+/// The oldest ones are dropped beyond that, i.e. while the window is minimized
+const MAX_QUEUED: usize = 16;
+const TOAST_FONT_SIZE: f32 = 24.;
+/// `TweenCompleted::user_data` of the fade out
+const TOAST_FADED: u64 = 0x7057;
+
+pub struct ToastPlugin;
+
+impl Plugin for ToastPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ToastEvent>();
+        app.init_resource::<ToastQueue>();
+        app.init_resource::<ToastsMuted>();
+        app.add_systems(PostStartup, spawn_toast_container);
+        app.add_systems(
+            Update,
+            (
+                queue_toasts,
+                show_toasts,
+                fade_out_toasts,
+                remove_faded_toasts,
+            )
+                .chain(),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    /// Border of the toast
+    fn color(self, theme: &Theme) -> Color {
+        match self {
+            Severity::Info => theme.border(),
+            Severity::Warning => Color::srgb(0.9, 0.6, 0.),
+            Severity::Error => Color::srgb(0.8, 0.1, 0.1),
+        }
+    }
+}
+
+/// Show `text` in a toast
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct ToastEvent {
+    pub severity: Severity,
+    pub text: String,
+}
+
+impl ToastEvent {
+    pub fn info(text: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Info,
+            text: text.into(),
+        }
+    }
+    pub fn warning(text: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            text: text.into(),
+        }
+    }
+    pub fn error(text: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            text: text.into(),
+        }
+    }
+}
+
+/// Whether the peer asked to mute the notifications, see `ControlMessage::MuteNotifications`.
+/// Only the info toasts are held back then, warnings and errors still show.
+/// Lifted as the call ends
+#[derive(Resource, Debug, Default)]
+pub struct ToastsMuted(pub bool);
+
+/// The toasts waiting for a free spot, oldest first
+#[derive(Resource, Debug, Default)]
+struct ToastQueue(VecDeque<ToastEvent>);
+
+impl ToastQueue {
+    /// The same text waiting already isn't repeated
+    fn push(&mut self, toast: ToastEvent) {
+        if self.0.contains(&toast) {
+            return;
+        }
+        if self.0.len() == MAX_QUEUED {
+            self.0.pop_front();
+        }
+        self.0.push_back(toast);
+    }
+}
+
+/// Holds the toasts shown, newest at the bottom
+#[derive(Component)]
+struct ToastContainer;
+
+/// A toast shown, fading out once `timer` runs out
+#[derive(Component)]
+struct Toast {
+    timer: Timer,
+    text: Entity,
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn spawn_toast_container(mut commands: Commands) {
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(20.),
+                right: Val::Px(20.),
+                max_width: Val::Percent(40.),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::End,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            // Over the panels too
+            z_index: ZIndex::Global(20),
+            ..Default::default()
+        },
+        ToastContainer,
+    ));
+}
+fn queue_toasts(
+    mut events: EventReader<ToastEvent>,
+    mut queue: ResMut<ToastQueue>,
+    muted: Res<ToastsMuted>,
+) {
+    for event in events.read() {
+        if muted.0 && event.severity == Severity::Info {
+            continue;
+        }
+        queue.push(event.clone());
+    }
+}
+fn show_toasts(
+    mut queue: ResMut<ToastQueue>,
+    container: Query<(Entity, Option<&Children>), With<ToastContainer>>,
+    mut spawner: UiSpawner,
+) {
+    let Ok((container, shown)) = container.get_single() else {
+        return;
+    };
+    let shown = shown.map_or(0, |children| children.len());
+    for _ in shown..MAX_SHOWN {
+        let Some(toast) = queue.0.pop_front() else {
+            return;
+        };
+        let text = spawner.spawn_pretty_text(&toast.text, TOAST_FONT_SIZE).id();
+        let border = toast.severity.color(&spawner.theme);
+        let background = spawner.theme.background();
+        let node = spawner
+            .commands
+            .spawn((
+                NodeBundle {
+                    style: Style {
+                        padding: UiRect::all(Val::Px(10.)),
+                        border: UiRect::all(Val::Px(3.)),
+                        ..Default::default()
+                    },
+                    border_color: BorderColor(border),
+                    background_color: BackgroundColor(background),
+                    ..Default::default()
+                },
+                Toast {
+                    timer: Timer::new(TOAST_DURATION, TimerMode::Once),
+                    text,
+                },
+            ))
+            .add_child(text)
+            .id();
+        spawner.commands.entity(container).add_child(node);
+    }
+}
+fn fade_out_toasts(
+    time: Res<Time>,
+    theme: Res<Theme>,
+    mut toasts: Query<(Entity, &mut Toast)>,
+    mut commands: Commands,
+) {
+    for (entity, mut toast) in &mut toasts {
+        if !toast.timer.tick(time.delta()).just_finished() {
+            continue;
+        }
+        let background = theme.background();
+        let fade = Tween::new(
+            EaseFunction::QuadraticIn,
+            TOAST_FADE,
+            UiBackgroundColorLens {
+                start: background,
+                end: background.with_alpha(0.),
+            },
+        )
+        .with_completed_event(TOAST_FADED);
+        commands.entity(entity).insert(Animator::new(fade));
+        let text = theme.text();
+        let fade = Tween::new(
+            EaseFunction::QuadraticIn,
+            TOAST_FADE,
+            TextColorLens {
+                start: text,
+                end: text.with_alpha(0.),
+                section: 0,
+            },
+        );
+        commands.entity(toast.text).insert(Animator::new(fade));
+    }
+}
+fn remove_faded_toasts(
+    mut completed: EventReader<TweenCompleted>,
+    toasts: Query<(), With<Toast>>,
+    mut commands: Commands,
+) {
+    for event in completed.read() {
+        if event.user_data == TOAST_FADED && toasts.contains(event.entity) {
+            commands.entity(event.entity).despawn_recursive();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ToastEvent, ToastQueue, MAX_QUEUED};
+
+    #[test]
+    fn test_toast_queue() {
+        let mut queue = ToastQueue::default();
+        queue.push(ToastEvent::info("joined"));
+        queue.push(ToastEvent::info("joined"));
+        queue.push(ToastEvent::error("joined"));
+        assert_eq!(queue.0.len(), 2);
+        for i in 0..MAX_QUEUED {
+            queue.push(ToastEvent::info(i.to_string()));
+        }
+        assert_eq!(queue.0.len(), MAX_QUEUED);
+        assert_eq!(queue.0.front(), Some(&ToastEvent::info("0")));
+    }
+}
@@ -1,23 +1,61 @@
-//! Module for UI layout and styling. No logic here.
+//! Module for UI layout and styling, and the widgets it's built of.
+//! `TextInput` is a field the keyboard types into, as Bevy has none.
 use std::time::Duration;
 
 use bevy::ecs::system::{EntityCommands, SystemParam};
+use bevy::input::keyboard::{Key, KeyboardInput};
+use bevy::input::ButtonState;
 use bevy::prelude::*;
-use bevy_tweening::lens::UiBackgroundColorLens;
-use bevy_tweening::{Animator, EaseFunction, Tween};
+use bevy_tweening::lens::{TransformRotateZLens, UiBackgroundColorLens};
+use bevy_tweening::{Animator, EaseFunction, RepeatCount, Tween};
 
-use crate::ui_logic::buttons::{DisconnectButton, FindHostsButton};
-use crate::STREAM_IMAGE_HANDLE;
+use crate::file_transfer::{
+    DeclineFileButton, FileDropTarget, FileOfferPrompt, FileOfferText, FileTransfersPanel,
+    FileTransfersText, SaveFileButton,
+};
+use crate::locale::{tr, Language, Translated};
+use crate::plugin::{SCREEN_IMAGE_HANDLE, STREAM_IMAGE_HANDLE};
+use crate::screen_share::{
+    ScreenShareImage, ScreenSharePanel, ScreenShareResizeHandle, SCREEN_SHARE_WIDTH,
+};
+use crate::shared_clipboard::{
+    ClipboardPrompt, ClipboardPromptText, CopyClipboardButton, IgnoreClipboardButton,
+};
+use crate::stream_view::{StreamOrientationControls, StreamStatusOverlay, StreamStatusText};
+use crate::toast::ToastPlugin;
+use crate::ui_logic::buttons::{
+    AcceptConnectionButton, BlockCallerButton, CancelCallButton, CopyInviteButton, DialButton,
+    DialField, DisconnectButton, FindHostsButton, LoopbackButton, LowBandwidthButton,
+    MirrorStreamButton, PrivacyButton, RejectConnectionButton, RotateStreamButton,
+    SendClipboardButton, SettingsButton, TrustPeerButton, VideoQualityButton,
+};
+use crate::ui_logic::{
+    AppClipboard, CameraInUseIndicator, FingerprintText, IncomingCallPrompt, IncomingCallText,
+    InviteText, OutgoingCallPrompt, OutgoingCallText, PeerTrustText, StreamErrorText,
+};
+use crate::video_tile::{spawn_video_tile, VideoGrid};
 
 #[allow(unused)]
 pub mod color_palette {
-    use bevy::color::palettes::tailwind::VIOLET_200;
+    use bevy::color::palettes::tailwind::{VIOLET_200, VIOLET_600};
     use bevy::color::Alpha;
     use bevy::prelude::Color;
 
     pub const WHITE: Color = Color::srgb(1., 1., 1.);
     pub const DARK: Color = Color::srgba(0.1, 0.1, 0.1, 0.4);
     pub const BLACK: Color = Color::srgba(0., 0., 0., 1.);
+    pub const FOCUS: Color = Color::Srgba(VIOLET_600);
+    /// Laid over the stream while it's not live
+    pub const DIM: Color = Color::srgba(0., 0., 0., 0.6);
+    /// High contrast
+    pub const HC_HOVER: Color = Color::srgb(0.3, 0.3, 0.);
+    pub const HC_FOCUS: Color = Color::srgb(1., 1., 0.);
+    /// The dot telling the camera is in use
+    pub const RECORDING: Color = Color::srgb(0.9, 0.1, 0.1);
+    /// The dot telling the camera is kept closed, see `crate::hotkeys::PrivacyMode`
+    pub const CLOSED: Color = Color::srgb(0.5, 0.5, 0.5);
+    /// The text telling the key of the peer changed, see `crate::ui_logic::PeerTrustText`
+    pub const WARNING: Color = Color::srgb(0.9, 0.6, 0.);
 }
 
 pub const FONT_PATH: &str = "pixelplay.ttf";
@@ -26,9 +64,80 @@ pub struct UIElementsPlugin;
 
 impl Plugin for UIElementsPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<Theme>();
+        app.add_plugins(ToastPlugin);
         app.add_systems(Startup, load_fonts);
         app.add_systems(PostStartup, init_ui);
         app.add_systems(PostUpdate, pretty_button_behavior);
+        app.add_systems(Update, apply_theme.run_if(resource_changed::<Theme>));
+        app.init_resource::<TextInputFocus>();
+        app.add_event::<TextInputSubmitted>();
+        app.add_systems(
+            Update,
+            (
+                focus_text_inputs,
+                type_into_text_input.run_if(|focus: Res<TextInputFocus>| focus.0.is_some()),
+                update_text_inputs,
+            )
+                .chain(),
+        );
+    }
+}
+
+/// How the UI looks, changing it restyles everything spawned by `UiSpawner`
+/// * `scale` - multiplies all sizes, fonts included, see `UiScale`
+/// * `high_contrast` - white on black, with a yellow keyboard focus
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub scale: f32,
+    pub high_contrast: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            scale: 1.,
+            high_contrast: false,
+        }
+    }
+}
+
+impl Theme {
+    pub const MIN_SCALE: f32 = 0.5;
+    pub const MAX_SCALE: f32 = 2.5;
+
+    pub fn text(&self) -> Color {
+        if self.high_contrast {
+            color_palette::WHITE
+        } else {
+            color_palette::BLACK
+        }
+    }
+    pub fn background(&self) -> Color {
+        if self.high_contrast {
+            color_palette::BLACK
+        } else {
+            color_palette::WHITE
+        }
+    }
+    /// Background of the hovered buttons
+    pub fn hover(&self) -> Color {
+        if self.high_contrast {
+            color_palette::HC_HOVER
+        } else {
+            color_palette::DARK
+        }
+    }
+    pub fn border(&self) -> Color {
+        self.text()
+    }
+    /// Border of the button picked with the keyboard
+    pub fn focus(&self) -> Color {
+        if self.high_contrast {
+            color_palette::HC_FOCUS
+        } else {
+            color_palette::FOCUS
+        }
     }
 }
 
@@ -39,13 +148,22 @@ pub struct UiContainers {
     pub root: Entity,
     /// Left side bar with found hosts
     pub host_bar: Entity,
-    /// Contains incoming stream window, eg. use when setting image to placeholder
+    /// Contains incoming stream window, eg. use when setting image to placeholder.
+    /// It's in the tile of the peer, turned and scaled with the stream
     pub stream_window: Entity,
+    /// Tile of the peer of the call, see `video_tile`
+    pub video_tile: Entity,
+    /// Holds the tiles of all peers, sized after the stream
+    pub video_grid: Entity,
 }
 /// Marker component for styling behavior
 #[derive(Component)]
 pub struct PrettyNode;
 
+/// Marker for the boxes drawn over the rest of the UI, i.e. the settings
+#[derive(Component)]
+pub struct PrettyPanel;
+
 /// Resource containing systems for spawning and returning Entity ID of a UI Node
 #[derive(Resource)]
 pub struct UiElementSpawnerResources {
@@ -70,22 +188,52 @@ fn load_fonts(mut commands: Commands, asset_server: Res<AssetServer>) {
 pub struct UiSpawner<'w, 's> {
     pub commands: Commands<'w, 's>,
     pub ui_elements: Res<'w, UiElementSpawnerResources>,
+    pub theme: Res<'w, Theme>,
 }
 /// Spawns a button with consistent styling and returns its Entity ID
 impl UiSpawner<'_, '_> {
     pub fn spawn_pretty_button(&mut self) -> EntityCommands {
-        self.commands.spawn((get_pretty_button(), PrettyNode))
+        self.commands
+            .spawn((get_pretty_button(&self.theme), PrettyNode))
     }
     pub fn spawn_pretty_button_with_text(&mut self, text: &str, font_size: f32) -> EntityCommands {
         let t = self
             .spawn_pretty_text(text, font_size)
             .insert(PrettyNode)
             .id();
-        let mut cmds = self.commands.spawn((get_pretty_button(), PrettyNode));
+        let mut cmds = self
+            .commands
+            .spawn((get_pretty_button(&self.theme), PrettyNode));
         cmds.add_child(t);
         cmds
     }
 
+    /// A button labeled with the text of `key` in the language picked, see `crate::locale`
+    pub fn spawn_translated_button(
+        &mut self,
+        key: &'static str,
+        font_size: f32,
+    ) -> EntityCommands<'_> {
+        let t = self
+            .spawn_translated_text(key, font_size)
+            .insert(PrettyNode)
+            .id();
+        let mut cmds = self
+            .commands
+            .spawn((get_pretty_button(&self.theme), PrettyNode));
+        cmds.add_child(t);
+        cmds
+    }
+    /// The text of `key` in the language picked, translated again once it's switched
+    pub fn spawn_translated_text(
+        &mut self,
+        key: &'static str,
+        font_size: f32,
+    ) -> EntityCommands<'_> {
+        let mut text = self.spawn_pretty_text(&tr!(key), font_size);
+        text.insert(Translated(key));
+        text
+    }
     pub fn spawn_pretty_text(&mut self, text: &str, font_size: f32) -> EntityCommands {
         self.commands.spawn((
             TextBundle::from_section(
@@ -93,16 +241,191 @@ impl UiSpawner<'_, '_> {
                 TextStyle {
                     font_size,
                     font: self.ui_elements.font.clone(),
-                    color: color_palette::BLACK,
+                    color: self.theme.text(),
                 },
             ),
             PrettyNode,
         ))
     }
+    /// A field to type `input` into, see `TextInput`
+    pub fn spawn_text_input(&mut self, input: TextInput, font_size: f32) -> EntityCommands<'_> {
+        let text = self
+            .spawn_pretty_text(&input.display(false), font_size)
+            .id();
+        let mut cmds = self
+            .commands
+            .spawn((get_pretty_button(&self.theme), PrettyNode, input));
+        cmds.add_child(text);
+        cmds
+    }
+    /// A box drawn over the rest of the UI, positioned by `style`
+    pub fn spawn_pretty_panel(&mut self, style: Style, z_index: i32) -> EntityCommands<'_> {
+        self.commands.spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    padding: UiRect::all(Val::Px(10.)),
+                    border: UiRect::all(Val::Px(2.)),
+                    ..style
+                },
+                border_color: BorderColor(self.theme.border()),
+                background_color: BackgroundColor(self.theme.background()),
+                z_index: ZIndex::Global(z_index),
+                ..Default::default()
+            },
+            PrettyPanel,
+        ))
+    }
+}
+
+/// Which characters a `TextInput` takes, the rest is dropped, pasted ones included
+/// * `Any` - all but control characters
+/// * `NoSpaces` - i.e. an address or an invite
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputFilter {
+    #[default]
+    Any,
+    NoSpaces,
+}
+
+impl InputFilter {
+    pub fn accepts(self, c: char) -> bool {
+        match self {
+            Self::Any => !c.is_control(),
+            Self::NoSpaces => !c.is_control() && !c.is_whitespace(),
+        }
+    }
+}
+
+/// A field the keyboard types into once it's clicked, until Enter or Escape, see `TextInputFocus`.
+/// Enter sends `TextInputSubmitted`
+/// * `text` - typed so far
+/// * `cursor` - byte index into `text` the next character goes to
+/// * `placeholder` - key of the text shown while it's empty and not typed into, see `crate::locale`
+/// * `filter` - which characters it takes
+/// * `secret` - shown as `*`, i.e. a password
+/// * `max_chars` - longer text is cut, None for no limit
+#[derive(Component, Debug, Default, Clone, PartialEq)]
+pub struct TextInput {
+    text: String,
+    cursor: usize,
+    pub placeholder: &'static str,
+    pub filter: InputFilter,
+    pub secret: bool,
+    pub max_chars: Option<usize>,
+}
+
+impl TextInput {
+    pub fn new(placeholder: &'static str) -> Self {
+        Self {
+            placeholder,
+            ..Default::default()
+        }
+    }
+    pub fn with_filter(self, filter: InputFilter) -> Self {
+        Self { filter, ..self }
+    }
+    pub fn with_max_chars(self, max_chars: usize) -> Self {
+        Self {
+            max_chars: Some(max_chars),
+            ..self
+        }
+    }
+    /// For the password entry, which waits for `ScpListener` to take passwords
+    #[allow(dead_code)]
+    pub fn secret(self) -> Self {
+        Self {
+            secret: true,
+            ..self
+        }
+    }
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    /// Replace the text, the cursor goes to its end
+    pub fn set_text(&mut self, text: &str) {
+        self.text.clear();
+        self.cursor = 0;
+        self.insert(text);
+    }
+    pub fn clear(&mut self) {
+        self.set_text("");
+    }
+    /// Type `text` at the cursor, as much of it as the filter and the limit let in
+    pub fn insert(&mut self, text: &str) {
+        for c in text.chars().filter(|c| self.filter.accepts(*c)) {
+            if self
+                .max_chars
+                .is_some_and(|max| self.text.chars().count() >= max)
+            {
+                break;
+            }
+            self.text.insert(self.cursor, c);
+            self.cursor += c.len_utf8();
+        }
+    }
+    /// Remove the character before the cursor
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+    /// Remove the character after the cursor
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+    pub fn move_left(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+    pub fn move_right(&mut self) {
+        if let Some(c) = self.text[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+    /// What the field shows, with a cursor while it's typed into
+    fn display(&self, focused: bool) -> String {
+        if self.text.is_empty() && !focused {
+            return tr!(self.placeholder);
+        }
+        let (before, after) = self.text.split_at(self.cursor);
+        let hide = |text: &str| match self.secret {
+            true => "*".repeat(text.chars().count()),
+            false => text.to_string(),
+        };
+        match focused {
+            true => format!("{}|{}", hide(before), hide(after)),
+            false => hide(&self.text),
+        }
+    }
+}
+
+/// The `TextInput` the keyboard types into, None when it goes to the buttons and the hotkeys
+#[derive(Resource, Debug, Default)]
+pub struct TextInputFocus(pub Option<Entity>);
+
+impl TextInputFocus {
+    pub fn is_typing(&self) -> bool {
+        self.0.is_some()
+    }
 }
 
+/// Enter was pressed in the `TextInput`
+#[derive(Event, Debug, Clone, Copy)]
+pub struct TextInputSubmitted(pub Entity);
+
 /// Function to create a pretty button with predefined styling
-fn get_pretty_button() -> ButtonBundle {
+fn get_pretty_button(theme: &Theme) -> ButtonBundle {
     ButtonBundle {
         style: Style {
             padding: UiRect::all(Val::Px(10.)),
@@ -115,8 +438,8 @@ fn get_pretty_button() -> ButtonBundle {
             ..Default::default()
         },
         z_index: ZIndex::Local(2),
-        border_color: BorderColor(color_palette::BLACK),
-        background_color: BackgroundColor(color_palette::WHITE),
+        border_color: BorderColor(theme.border()),
+        background_color: BackgroundColor(theme.background()),
         ..Default::default()
     }
 }
@@ -132,20 +455,21 @@ pub fn pretty_button_behavior(
         (Changed<Interaction>, With<PrettyNode>),
     >,
     mut commands: Commands,
-    mut window: Query<&mut Window>,
+    mut windows: Query<&mut Window>,
+    theme: Res<Theme>,
 ) {
-    let window = window.get_single_mut();
-    // Just in case, because it can happen
-    if window.is_err() {
-        return;
-    }
-    let mut window = window.unwrap();
+    // The one of the button, i.e. a panel popped out, unless it's pressed with the keyboard
+    let mut window = windows
+        .iter_mut()
+        .find(|window| window.cursor_position().is_some());
     for (entity, interaction, bg) in &query {
         match *interaction {
             Interaction::Pressed => {
                 // Scale down the button to indicate it's pressed
                 // Optionally, you can change the style as well, e.g., change the border color
-                window.cursor.icon = CursorIcon::Default;
+                if let Some(ref mut window) = window {
+                    window.cursor.icon = CursorIcon::Default;
+                }
                 // Play a click sound
             }
             Interaction::Hovered => {
@@ -154,7 +478,7 @@ pub fn pretty_button_behavior(
                     Duration::from_millis(200),
                     UiBackgroundColorLens {
                         start: bg.0,
-                        end: color_palette::DARK,
+                        end: theme.hover(),
                     },
                 );
                 // Necessary check if entity exists. It may have been deleted as this system doesn't run last
@@ -163,7 +487,9 @@ pub fn pretty_button_behavior(
                 }
                 // Tilt the button slightly when hovered
 
-                window.cursor.icon = CursorIcon::Grab;
+                if let Some(ref mut window) = window {
+                    window.cursor.icon = CursorIcon::Grab;
+                }
                 // Change the cursor to indicate that the button is interactable
             }
             Interaction::None => {
@@ -172,14 +498,16 @@ pub fn pretty_button_behavior(
                     Duration::from_millis(200),
                     UiBackgroundColorLens {
                         start: bg.0,
-                        end: color_palette::WHITE,
+                        end: theme.background(),
                     },
                 );
                 // Necessary check if entity exists. It may have been deleted as this system doesn't run last
                 if let Some(mut e) = commands.get_entity(entity) {
                     e.insert(Animator::new(tween));
                 }
-                window.cursor.icon = CursorIcon::Default;
+                if let Some(ref mut window) = window {
+                    window.cursor.icon = CursorIcon::Default;
+                }
 
                 // Reset cursor style
             }
@@ -187,6 +515,91 @@ pub fn pretty_button_behavior(
     }
 }
 
+fn focus_text_inputs(
+    query: Query<(Entity, Ref<Interaction>), With<TextInput>>,
+    mut focus: ResMut<TextInputFocus>,
+) {
+    for (entity, interaction) in &query {
+        if interaction.is_changed() && *interaction == Interaction::Pressed {
+            focus.0 = Some(entity);
+        }
+    }
+}
+fn type_into_text_input(
+    mut events: EventReader<KeyboardInput>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut focus: ResMut<TextInputFocus>,
+    mut inputs: Query<&mut TextInput>,
+    mut clipboard: NonSendMut<AppClipboard>,
+    mut submitted: EventWriter<TextInputSubmitted>,
+) {
+    let Some(entity) = focus.0 else {
+        return;
+    };
+    // Despawned, i.e. a panel closed
+    let Ok(mut input) = inputs.get_mut(entity) else {
+        focus.0 = None;
+        return;
+    };
+    // The key that focused it, i.e. Enter on a field picked with the keyboard
+    if focus.is_changed() {
+        events.clear();
+        return;
+    }
+    let ctrl = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    for event in events.read() {
+        if event.state != ButtonState::Pressed {
+            continue;
+        }
+        match &event.logical_key {
+            Key::Character(c) if ctrl && c.eq_ignore_ascii_case("v") => {
+                // Shared over chats, they often come with a line break
+                if let Some(text) = clipboard.text() {
+                    input.insert(text.trim());
+                }
+            }
+            Key::Paste => {
+                if let Some(text) = clipboard.text() {
+                    input.insert(text.trim());
+                }
+            }
+            Key::Character(c) if !ctrl => input.insert(c),
+            Key::Space => input.insert(" "),
+            Key::Backspace => input.backspace(),
+            Key::Delete => input.delete(),
+            Key::ArrowLeft => input.move_left(),
+            Key::ArrowRight => input.move_right(),
+            Key::Home => input.home(),
+            Key::End => input.end(),
+            Key::Enter => {
+                submitted.send(TextInputSubmitted(entity));
+            }
+            Key::Escape => {
+                focus.0 = None;
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+fn update_text_inputs(
+    focus: Res<TextInputFocus>,
+    language: Res<Language>,
+    inputs: Query<(Entity, Ref<TextInput>, &Children)>,
+    mut texts: Query<&mut Text>,
+) {
+    for (entity, input, children) in &inputs {
+        if !input.is_changed() && !focus.is_changed() && !language.is_changed() {
+            continue;
+        }
+        let value = input.display(focus.0 == Some(entity));
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value.clone_from(&value);
+        }
+    }
+}
+
 fn init_ui(mut commands: Commands, mut spawner: UiSpawner) {
     let root = NodeBundle {
         style: Style {
@@ -226,28 +639,63 @@ fn init_ui(mut commands: Commands, mut spawner: UiSpawner) {
         },
         ..Default::default()
     };
-    let stream_window = commands
-        .spawn(NodeBundle {
-            style: Style {
-                width: Val::Px(crate::h264_stream::WIDTH as f32),
-                height: Val::Px(crate::h264_stream::HEIGHT as f32),
-                justify_content: JustifyContent::SpaceBetween,
-                justify_self: JustifySelf::Center,
-                align_self: AlignSelf::Center,
-                border: UiRect::all(Val::Px(5.)),
-
+    let (video_tile, stream_window) = spawn_video_tile(&mut spawner, STREAM_IMAGE_HANDLE);
+    let video_grid = commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Px(eye_spy_core::h264_stream::WIDTH as f32),
+                    height: Val::Px(eye_spy_core::h264_stream::HEIGHT as f32),
+                    flex_wrap: FlexWrap::Wrap,
+                    justify_self: JustifySelf::Center,
+                    align_self: AlignSelf::Center,
+                    ..Default::default()
+                },
                 ..Default::default()
             },
-
-            border_color: BorderColor(color_palette::BLACK),
-            ..Default::default()
-        })
-        .insert(UiImage::new(STREAM_IMAGE_HANDLE).with_flip_x())
+            VideoGrid,
+        ))
+        .add_child(video_tile)
+        .id();
+    let status_text = commands
+        .spawn((
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: 48.,
+                    font: spawner.ui_elements.font.clone(),
+                    color: color_palette::WHITE,
+                },
+            ),
+            StreamStatusText,
+        ))
+        .id();
+    let status_overlay = commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    display: Display::None,
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..Default::default()
+                },
+                background_color: BackgroundColor(color_palette::DIM),
+                ..Default::default()
+            },
+            StreamStatusOverlay,
+        ))
+        .add_child(status_text)
         .id();
+    commands.entity(stream_window).add_child(status_overlay);
     let mut root = commands.spawn(root);
     let mut containers = UiContainers {
         root: root.id(),
         stream_window,
+        video_tile,
+        video_grid,
         host_bar: Entity::from_raw(0),
     };
     root.with_children(|p| {
@@ -256,17 +704,412 @@ fn init_ui(mut commands: Commands, mut spawner: UiSpawner) {
 
         let mut right_bar = p.spawn(right_side_box);
 
-        let mut btn_disconnect = spawner.spawn_pretty_button_with_text("Disconnect", 32.);
+        let mut btn_disconnect = spawner.spawn_translated_button("disconnect", 32.);
         btn_disconnect.insert(DisconnectButton);
-        right_bar.add_child(stream_window);
-        right_bar.add_child(btn_disconnect.id());
+        let btn_disconnect = btn_disconnect.id();
+        let mut btn_loopback = spawner.spawn_translated_button("call_myself", 32.);
+        btn_loopback.insert(LoopbackButton);
+        let btn_loopback = btn_loopback.id();
+        let mut btn_privacy = spawner.spawn_translated_button("privacy_mode_off", 32.);
+        btn_privacy.insert(PrivacyButton);
+        let btn_privacy = btn_privacy.id();
+        let error_text = spawner
+            .spawn_pretty_text("", 24.)
+            .insert(StreamErrorText)
+            .id();
+        let mut btn_rotate = spawner.spawn_translated_button("rotate", 24.);
+        btn_rotate.insert(RotateStreamButton);
+        let btn_rotate = btn_rotate.id();
+        let mut btn_mirror = spawner.spawn_translated_button("mirror", 24.);
+        btn_mirror.insert(MirrorStreamButton);
+        let btn_mirror = btn_mirror.id();
+        let mut btn_quality = spawner.spawn_pretty_button_with_text("", 24.);
+        btn_quality.insert(VideoQualityButton);
+        let btn_quality = btn_quality.id();
+        let mut btn_low_bandwidth = spawner.spawn_pretty_button_with_text("", 24.);
+        btn_low_bandwidth.insert(LowBandwidthButton);
+        let btn_low_bandwidth = btn_low_bandwidth.id();
+        let mut btn_send_clipboard = spawner.spawn_translated_button("send_clipboard", 24.);
+        btn_send_clipboard.insert(SendClipboardButton);
+        let btn_send_clipboard = btn_send_clipboard.id();
+        let orientation_controls = spawner
+            .commands
+            .spawn((
+                NodeBundle {
+                    style: Style {
+                        display: Display::None,
+                        column_gap: Val::Px(10.),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                StreamOrientationControls,
+            ))
+            .push_children(&[
+                btn_rotate,
+                btn_mirror,
+                btn_quality,
+                btn_low_bandwidth,
+                btn_send_clipboard,
+            ])
+            .id();
+        right_bar.add_child(video_grid);
+        right_bar.add_child(orientation_controls);
+        right_bar.add_child(error_text);
+        right_bar.add_child(btn_disconnect);
+        right_bar.add_child(btn_loopback);
+        right_bar.add_child(btn_privacy);
+        let fingerprint_text = spawner
+            .spawn_pretty_text("", 24.)
+            .insert(FingerprintText)
+            .id();
+        right_bar.add_child(fingerprint_text);
+
+        let invite_text = spawner.spawn_pretty_text("", 24.).insert(InviteText).id();
+        let mut btn_copy = spawner.spawn_translated_button("copy_invite", 32.);
+        btn_copy.insert(CopyInviteButton);
+        let btn_copy = btn_copy.id();
+        let dial_input = TextInput::new("dial_placeholder").with_filter(InputFilter::NoSpaces);
+        let mut dial_field = spawner.spawn_text_input(dial_input, 24.);
+        dial_field.insert(DialField);
+        let dial_field = dial_field.id();
+        let mut btn_dial = spawner.spawn_translated_button("call", 32.);
+        btn_dial.insert(DialButton);
+        let btn_dial = btn_dial.id();
+        right_bar.add_child(invite_text);
+        right_bar.add_child(btn_copy);
+        right_bar.add_child(dial_field);
+        right_bar.add_child(btn_dial);
+        let mut btn_settings = spawner.spawn_translated_button("settings", 32.);
+        btn_settings.insert(SettingsButton);
+        let btn_settings = btn_settings.id();
+        right_bar.add_child(btn_settings);
     });
     commands.insert_resource(containers);
+    spawn_incoming_call_prompt(&mut spawner);
+    spawn_outgoing_call_prompt(&mut spawner);
+    spawn_camera_indicator(&mut spawner);
+    spawn_screen_share_panel(&mut spawner);
+    spawn_file_transfer_panels(&mut spawner);
+    spawn_clipboard_prompt(&mut spawner);
     spawner
-        .spawn_pretty_button_with_text("Find", 32.)
+        .spawn_translated_button("find_hosts", 32.)
         .insert(FindHostsButton);
 }
 
+/// Colors everything anew and applies the scale
+#[allow(clippy::type_complexity)]
+fn apply_theme(
+    theme: Res<Theme>,
+    mut ui_scale: ResMut<UiScale>,
+    mut texts: Query<&mut Text, With<PrettyNode>>,
+    mut boxes: Query<
+        (&mut BackgroundColor, &mut BorderColor),
+        Or<(With<PrettyPanel>, (With<Button>, With<PrettyNode>))>,
+    >,
+) {
+    if ui_scale.0 != theme.scale {
+        ui_scale.0 = theme.scale;
+    }
+    for mut text in &mut texts {
+        for section in &mut text.sections {
+            section.style.color = theme.text();
+        }
+    }
+    for (mut background, mut border) in &mut boxes {
+        background.0 = theme.background();
+        border.0 = theme.border();
+    }
+}
+
+/// Hidden until a call rings, see `ui_logic::IncomingCallPrompt`
+fn spawn_incoming_call_prompt(spawner: &mut UiSpawner) {
+    let text = spawner
+        .spawn_pretty_text("", 32.)
+        .insert(IncomingCallText)
+        .id();
+    let trust = spawn_peer_trust(spawner);
+    let mut btn_accept = spawner.spawn_translated_button("accept", 32.);
+    btn_accept.insert(AcceptConnectionButton);
+    let btn_accept = btn_accept.id();
+    let mut btn_reject = spawner.spawn_translated_button("reject", 32.);
+    btn_reject.insert(RejectConnectionButton);
+    let btn_reject = btn_reject.id();
+    let mut btn_block = spawner.spawn_translated_button("block", 32.);
+    btn_block.insert(BlockCallerButton);
+    let btn_block = btn_block.id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Percent(30.),
+                left: Val::Percent(35.),
+                width: Val::Percent(30.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            6,
+        )
+        .insert(IncomingCallPrompt)
+        .push_children(&[text, trust[0], trust[1], btn_accept, btn_reject, btn_block]);
+}
+
+/// The fingerprint and trust of the peer, and the button to trust its changed key.
+/// Filled in by `ui_logic::update_peer_trust`
+fn spawn_peer_trust(spawner: &mut UiSpawner) -> [Entity; 2] {
+    let text = spawner
+        .spawn_pretty_text("", 24.)
+        .insert(PeerTrustText)
+        .id();
+    let mut btn_trust = spawner.spawn_translated_button("trust_key", 32.);
+    btn_trust.insert(TrustPeerButton);
+    [text, btn_trust.id()]
+}
+
+/// Hidden until a call is placed, see `ui_logic::OutgoingCallPrompt`
+fn spawn_outgoing_call_prompt(spawner: &mut UiSpawner) {
+    // A square turning round for as long as the prompt is there
+    let spin = Tween::new(
+        EaseFunction::SineInOut,
+        Duration::from_millis(900),
+        TransformRotateZLens {
+            start: 0.,
+            end: std::f32::consts::PI,
+        },
+    )
+    .with_repeat_count(RepeatCount::Infinite);
+    let border = spawner.theme.border();
+    let spinner = spawner
+        .commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Px(24.),
+                    height: Val::Px(24.),
+                    border: UiRect::all(Val::Px(4.)),
+                    ..Default::default()
+                },
+                border_color: BorderColor(border),
+                ..Default::default()
+            },
+            Animator::new(spin),
+        ))
+        .id();
+    let text = spawner
+        .spawn_pretty_text("", 32.)
+        .insert(OutgoingCallText)
+        .id();
+    let status = spawner
+        .commands
+        .spawn(NodeBundle {
+            style: Style {
+                align_items: AlignItems::Center,
+                column_gap: Val::Px(20.),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .push_children(&[spinner, text])
+        .id();
+    let trust = spawn_peer_trust(spawner);
+    let mut btn_cancel = spawner.spawn_translated_button("cancel", 32.);
+    btn_cancel.insert(CancelCallButton);
+    let btn_cancel = btn_cancel.id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Percent(30.),
+                left: Val::Percent(35.),
+                width: Val::Percent(30.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            6,
+        )
+        .insert(OutgoingCallPrompt)
+        .push_children(&[status, trust[0], trust[1], btn_cancel]);
+}
+
+/// Hidden while the camera is closed, see `ui_logic::CameraInUseIndicator`
+fn spawn_camera_indicator(spawner: &mut UiSpawner) {
+    let dot = spawner
+        .commands
+        .spawn(NodeBundle {
+            style: Style {
+                width: Val::Px(12.),
+                height: Val::Px(12.),
+                ..Default::default()
+            },
+            background_color: BackgroundColor(color_palette::RECORDING),
+            border_radius: BorderRadius::MAX,
+            ..Default::default()
+        })
+        .id();
+    let text = spawner.spawn_translated_text("camera_in_use", 24.).id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Px(10.),
+                left: Val::Px(10.),
+                align_items: AlignItems::Center,
+                column_gap: Val::Px(8.),
+                ..Default::default()
+            },
+            7,
+        )
+        .insert(CameraInUseIndicator)
+        .push_children(&[dot, text]);
+}
+
+/// Hidden until the peer shares its screen, see `screen_share`
+fn spawn_screen_share_panel(spawner: &mut UiSpawner) {
+    let title = spawner.spawn_translated_text("shared_screen", 24.).id();
+    let image = spawner
+        .commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Px(SCREEN_SHARE_WIDTH),
+                    aspect_ratio: Some(
+                        eye_spy_core::h264_stream::WIDTH as f32
+                            / eye_spy_core::h264_stream::HEIGHT as f32,
+                    ),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            UiImage::new(SCREEN_IMAGE_HANDLE),
+            ScreenShareImage,
+        ))
+        .id();
+    let mut handle = spawner.spawn_pretty_button();
+    handle.insert((
+        Style {
+            position_type: PositionType::Absolute,
+            right: Val::Px(0.),
+            bottom: Val::Px(0.),
+            width: Val::Px(20.),
+            height: Val::Px(20.),
+            border: UiRect::all(Val::Px(2.)),
+            ..Default::default()
+        },
+        ScreenShareResizeHandle,
+    ));
+    let handle = handle.id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                bottom: Val::Percent(2.),
+                left: Val::Percent(2.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            4,
+        )
+        .insert(ScreenSharePanel)
+        .push_children(&[title, image, handle]);
+}
+
+/// The prompt about the files the peer offers, the files on their way and the drop target,
+/// all hidden until there's something to show, see `file_transfer`
+fn spawn_file_transfer_panels(spawner: &mut UiSpawner) {
+    let text = spawner
+        .spawn_pretty_text("", 24.)
+        .insert(FileOfferText)
+        .id();
+    let mut btn_save = spawner.spawn_translated_button("save_file", 32.);
+    btn_save.insert(SaveFileButton);
+    let btn_save = btn_save.id();
+    let mut btn_decline = spawner.spawn_translated_button("decline_file", 32.);
+    btn_decline.insert(DeclineFileButton);
+    let btn_decline = btn_decline.id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Percent(30.),
+                left: Val::Percent(35.),
+                width: Val::Percent(30.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            6,
+        )
+        .insert(FileOfferPrompt)
+        .push_children(&[text, btn_save, btn_decline]);
+
+    let text = spawner
+        .spawn_pretty_text("", 20.)
+        .insert(FileTransfersText)
+        .id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                bottom: Val::Percent(2.),
+                right: Val::Percent(2.),
+                ..Default::default()
+            },
+            5,
+        )
+        .insert(FileTransfersPanel)
+        .add_child(text);
+
+    let text = spawner.spawn_translated_text("drop_file", 32.).id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Percent(5.),
+                left: Val::Percent(5.),
+                width: Val::Percent(90.),
+                height: Val::Percent(90.),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            8,
+        )
+        .insert(FileDropTarget)
+        .add_child(text);
+}
+
+/// Hidden until the peer sends a text for the clipboard, see `shared_clipboard`
+fn spawn_clipboard_prompt(spawner: &mut UiSpawner) {
+    let text = spawner
+        .spawn_pretty_text("", 24.)
+        .insert(ClipboardPromptText)
+        .id();
+    let mut btn_copy = spawner.spawn_translated_button("copy_clipboard", 32.);
+    btn_copy.insert(CopyClipboardButton);
+    let btn_copy = btn_copy.id();
+    let mut btn_ignore = spawner.spawn_translated_button("ignore_clipboard", 32.);
+    btn_ignore.insert(IgnoreClipboardButton);
+    let btn_ignore = btn_ignore.id();
+    spawner
+        .spawn_pretty_panel(
+            Style {
+                display: Display::None,
+                top: Val::Percent(10.),
+                left: Val::Percent(35.),
+                width: Val::Percent(30.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(10.),
+                ..Default::default()
+            },
+            6,
+        )
+        .insert(ClipboardPrompt)
+        .push_children(&[text, btn_copy, btn_ignore]);
+}
+
 // struct TransformRotationLens {
 //     start: Quat,
 //     end: Quat,
@@ -298,3 +1141,42 @@ fn init_ui(mut commands: Commands, mut spawner: UiSpawner) {
 //         target.scale = start + (end - start) * ratio;
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::{InputFilter, TextInput};
+
+    #[test]
+    fn test_text_input() {
+        let mut input = TextInput::new("dial_placeholder").with_max_chars(5);
+        input.insert("ab ć");
+        assert_eq!(input.text(), "ab ć");
+        input.move_left();
+        input.move_left();
+        input.backspace();
+        assert_eq!(input.display(true), "a| ć");
+        input.delete();
+        input.insert("xyzw");
+        // Cut at the limit
+        assert_eq!(input.text(), "axyzć");
+        assert_eq!(input.display(true), "axyz|ć");
+        input.home();
+        input.set_text("ą");
+        assert_eq!(input.display(false), "ą");
+        input.clear();
+        assert_eq!(input.text(), "");
+    }
+
+    #[test]
+    fn test_text_input_filter() {
+        let mut input = TextInput::new("dial_placeholder")
+            .with_filter(InputFilter::NoSpaces)
+            .secret();
+        input.insert(" 10.0.0.1:\t5000\n");
+        assert_eq!(input.text(), "10.0.0.1:5000");
+        input.home();
+        input.move_right();
+        assert_eq!(input.display(true), "*|************");
+        assert_eq!(input.display(false), "*".repeat(13));
+    }
+}
@@ -1,47 +1,328 @@
 //! Module for UI states and logic.
+//! All of it works without a mouse: Tab and the arrows move the keyboard focus through
+//! the buttons and the host entries, Enter presses the focused one and Escape gives up
+//! the call, see `keyboard_navigation` and `escape_call`.
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 use bevy::ecs::world::CommandQueue;
 use bevy::prelude::*;
 use bevy::tasks::futures_lite::future;
 use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
-use buttons::{DisconnectButton, FindHostsButton};
-use mdns_sd::ServiceInfo;
+use bevy::time::common_conditions::on_timer;
+use buttons::{
+    AcceptConnectionButton, BlockCallerButton, CancelCallButton, CopyInviteButton, DialButton,
+    DialField, DisconnectButton, FindHostsButton, LoopbackButton, PrivacyButton,
+    RejectConnectionButton, TrustPeerButton,
+};
+use eye_spy_core::h264_stream::outgoing::OutgoingStreamEvent;
+use eye_spy_core::mdns::{self, DiscoveredPeer};
+use eye_spy_core::static_peers::StaticPeer;
+use eye_spy_core::Invite;
+use scp_client::identity::PeerTrust;
+use scp_client::probe::{probe, PeerCapabilities};
 
-use crate::connection_state_bevy::{IncomingVideoStreamState, OutgoingVideoStreamState};
-use crate::mdns;
-use crate::ui::{UiContainers, UiSpawner};
+use crate::connection_state_bevy::{
+    CallingPeer, DialEvent, IncomingVideoStreamState, LoopbackCallEvent, OutgoingStreamHealthEvent,
+    OutgoingVideoStreamState, ScpConnectionState,
+};
+use crate::hotkeys::PrivacyMode;
+use crate::locale::{tr, Language, Translated};
+use crate::plugin::CallManagerBevy;
+use crate::pop_out::WindowFocus;
+use crate::settings::PeerListChangedEvent;
+use crate::toast::ToastEvent;
+use crate::ui::{
+    color_palette, TextInput, TextInputFocus, TextInputSubmitted, Theme, UiContainers, UiSpawner,
+};
 
 pub struct UILogicPlugin;
 
 impl Plugin for UILogicPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<AvailableHosts>();
+        app.init_resource::<KeyboardFocus>();
+        app.insert_non_send_resource(AppClipboard::new());
         app.add_event::<FindHostsEvent>();
+        app.add_event::<HostLatenciesMeasured>();
         app.add_systems(
             Update,
             on_host_button_click.run_if(in_state(OutgoingVideoStreamState::Off)),
         );
-        app.add_systems(Update, (check_disconnect_button, check_find_hosts_button));
+        app.add_systems(
+            Update,
+            (
+                check_disconnect_button,
+                check_find_hosts_button,
+                check_loopback_button,
+                check_copy_invite_button,
+                check_dial_button,
+                check_incoming_call_buttons,
+                update_incoming_call_prompt,
+                check_trust_peer_button,
+                check_cancel_call_button,
+                check_privacy_button,
+            )
+                .chain(),
+        );
+        // After `apply_theme`, so the warning keeps its color
+        app.add_systems(PostUpdate, update_peer_trust);
+        app.add_systems(
+            Update,
+            update_privacy_button.run_if(resource_changed::<PrivacyMode>),
+        );
+        app.add_systems(
+            Update,
+            update_outgoing_call_prompt
+                .run_if(state_changed::<ScpConnectionState>.or_else(resource_changed::<Language>)),
+        );
+        app.add_systems(
+            Update,
+            (
+                keyboard_navigation,
+                escape_call,
+                show_keyboard_focus
+                    .run_if(resource_changed::<KeyboardFocus>.or_else(resource_changed::<Theme>)),
+            )
+                .chain(),
+        );
 
         app.add_systems(
             Update,
             update_available_hosts_system.run_if(on_event::<FindHostsEvent>()),
         );
-        app.add_systems(Update, handle_tasks);
+        app.add_systems(
+            Update,
+            show_outgoing_stream_errors.run_if(on_event::<OutgoingStreamHealthEvent>()),
+        );
+        app.add_systems(
+            OnEnter(OutgoingVideoStreamState::Off),
+            clear_outgoing_stream_errors,
+        );
+        app.add_systems(
+            Update,
+            (
+                handle_tasks,
+                show_fingerprint,
+                show_invite,
+                show_camera_in_use,
+            ),
+        );
         app.add_systems(
             Update,
             update_host_list.run_if(resource_changed::<AvailableHosts>),
         );
+        app.add_systems(
+            Update,
+            (
+                refresh_host_latencies.run_if(on_timer(RTT_REFRESH)),
+                update_host_latencies.run_if(on_event::<HostLatenciesMeasured>()),
+            ),
+        );
     }
 }
 
+/// Waited for the answer of every host found, see `scp_client::probe`
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
 #[derive(Resource, Debug, Default, Deref, DerefMut)]
-pub struct AvailableHosts(Vec<ServiceInfo>);
+pub struct AvailableHosts(Vec<FoundHost>);
 
-#[derive(Component, Deref, DerefMut)]
-pub struct HostButton(pub IpAddr);
+/// How often the round trip times to the hosts found are measured again
+const RTT_REFRESH: Duration = Duration::from_secs(5);
+
+/// A host found over mDNS, and what it answered to the probe
+/// * `paths` - its addresses, the fastest first
+/// * `capabilities` - None if it didn't answer at the fastest address
+/// * `is_static` - it's listed rather than found, see `eye_spy_core::static_peers`
+#[derive(Debug, Clone)]
+pub struct FoundHost {
+    pub peer: DiscoveredPeer,
+    pub paths: Vec<HostPath>,
+    pub capabilities: Option<PeerCapabilities>,
+    pub is_static: bool,
+}
+
+/// An address of a host and the round trip time to it, see `mdns::measure_rtt`.
+/// None if it cannot be reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostPath {
+    pub addr: SocketAddr,
+    pub rtt: Option<Duration>,
+}
+
+impl FoundHost {
+    pub(crate) fn probe(peer: DiscoveredPeer) -> Self {
+        let paths = peer
+            .addrs
+            .iter()
+            .map(|&addr| HostPath { addr, rtt: None })
+            .collect();
+        let mut host = Self {
+            peer,
+            paths,
+            capabilities: None,
+            is_static: false,
+        };
+        host.measure();
+        host.capabilities = host.paths.first().and_then(|path| {
+            probe(path.addr, PROBE_TIMEOUT)
+                .inspect_err(|e| info!("{} didn't answer the probe: {e}", path.addr))
+                .ok()
+        });
+        host
+    }
+    /// Label of the host in the list, see `describe_host`. The static ones are badged
+    pub(crate) fn label(&self) -> String {
+        let label = describe_host(self.peer.name(), self.capabilities.as_ref());
+        if self.is_static {
+            tr!("host_static", label = label)
+        } else {
+            label
+        }
+    }
+    /// Measure the round trip times of the paths again
+    pub(crate) fn measure(&mut self) {
+        for path in &mut self.paths {
+            path.rtt = mdns::measure_rtt(path.addr, PROBE_TIMEOUT);
+        }
+        sort_paths(&mut self.paths);
+    }
+}
+
+/// Finds the hosts of `services` and probes them, then the static peers that weren't found
+/// * `beacon_port` - also listen for the beacons, see `mdns::find_all_hosts`
+/// # Blocking
+/// This function blocks until every host answered, or its probe timed out
+pub(crate) fn find_hosts(
+    services: &[String],
+    beacon_port: Option<u16>,
+    static_peers: &[StaticPeer],
+) -> Vec<FoundHost> {
+    let found: Vec<DiscoveredPeer> = services
+        .iter()
+        .flat_map(|service| mdns::find_all_hosts(service, beacon_port))
+        .collect();
+    let listed: Vec<DiscoveredPeer> = static_peers
+        .iter()
+        .filter(|peer| !peer.is_found(&found))
+        .map(StaticPeer::to_discovered)
+        .collect();
+    found
+        .into_iter()
+        .map(FoundHost::probe)
+        .chain(listed.into_iter().map(|peer| FoundHost {
+            is_static: true,
+            ..FoundHost::probe(peer)
+        }))
+        .collect()
+}
+
+/// The fastest paths first, the ones that cannot be reached last
+fn sort_paths(paths: &mut [HostPath]) {
+    paths.sort_by_key(|path| (path.rtt.is_none(), path.rtt));
+}
+
+/// Calls the host, with the settings picked for what it answered to the probe
+/// * `label` - the host as described in the list, see `describe_host`
+#[derive(Component)]
+pub struct HostButton {
+    pub addr: SocketAddr,
+    pub capabilities: Option<PeerCapabilities>,
+    pub label: String,
+}
+
+/// Marker for the text telling the user what's wrong with the outgoing stream
+#[derive(Component)]
+pub struct StreamErrorText;
+
+/// Marker for the text showing the fingerprint of this install, to compare with the peer's
+#[derive(Component)]
+pub struct FingerprintText;
+
+/// Marker for the text showing the invite to call this client
+#[derive(Component)]
+pub struct InviteText;
+
+/// Marker for the prompt to answer an incoming call, shown while it rings
+#[derive(Component)]
+pub struct IncomingCallPrompt;
+
+/// Marker for the text telling who's calling
+#[derive(Component)]
+pub struct IncomingCallText;
+
+/// Marker for the prompt shown while a call is placed, until the peer answers
+#[derive(Component)]
+pub struct OutgoingCallPrompt;
+
+/// Marker for the text telling who's called
+#[derive(Component)]
+pub struct OutgoingCallText;
+
+/// Marker for the texts telling the fingerprint of the peer calling or called,
+/// and whether its key is the one remembered, see `PeerTrust`
+#[derive(Component)]
+pub struct PeerTrustText;
+
+/// Marker for the badge shown while the camera captures, so the user knows it's on.
+/// The camera is opened as a call starts and closed as it ends.
+/// In privacy mode it tells the camera is kept closed instead
+#[derive(Component)]
+pub struct CameraInUseIndicator;
+
+/// The button picked with Tab and Shift+Tab or the arrows, Enter or Space presses it.
+/// It's outlined in the focus color of the `Theme`
+#[derive(Resource, Debug, Default)]
+pub struct KeyboardFocus(pub Option<Entity>);
+
+/// Marker for the buttons Left and Right step, rather than moving the keyboard focus off them
+#[derive(Component)]
+pub struct Slider;
+
+/// Width of the outline of the focused button
+const FOCUS_RING: Val = Val::Px(3.);
+
+/// The system clipboard, None when there's none, i.e. without a display server
+pub struct AppClipboard(Option<arboard::Clipboard>);
+
+impl AppClipboard {
+    fn new() -> Self {
+        let clipboard = arboard::Clipboard::new()
+            .map_err(|e| warn!("Cannot access the clipboard: {e}"))
+            .ok();
+        Self(clipboard)
+    }
+    /// The text to paste, None if there's none
+    pub fn text(&mut self) -> Option<String> {
+        match self.0.as_mut().map(|c| c.get_text()) {
+            Some(Ok(text)) => Some(text),
+            Some(Err(e)) => {
+                warn!("Cannot paste: {e}");
+                None
+            }
+            None => {
+                warn!("No clipboard to paste from");
+                None
+            }
+        }
+    }
+    /// Copy `text`, false if it couldn't be
+    pub fn set_text(&mut self, text: String) -> bool {
+        match self.0.as_mut().map(|c| c.set_text(text)) {
+            Some(Ok(())) => true,
+            Some(Err(e)) => {
+                warn!("Cannot copy: {e}");
+                false
+            }
+            None => {
+                warn!("No clipboard to copy to");
+                false
+            }
+        }
+    }
+}
 
 pub mod buttons {
     use bevy::prelude::Component;
@@ -52,9 +333,37 @@ pub mod buttons {
     #[derive(Component)]
     pub struct FindHostsButton;
     #[derive(Component)]
+    pub struct LoopbackButton;
+    #[derive(Component)]
+    pub struct CopyInviteButton;
+    #[derive(Component)]
+    pub struct DialField;
+    #[derive(Component)]
+    pub struct DialButton;
+    #[derive(Component)]
     pub struct AcceptConnectionButton;
     #[derive(Component)]
     pub struct RejectConnectionButton;
+    #[derive(Component)]
+    pub struct BlockCallerButton;
+    #[derive(Component)]
+    pub struct CancelCallButton;
+    #[derive(Component)]
+    pub struct TrustPeerButton;
+    #[derive(Component)]
+    pub struct SettingsButton;
+    #[derive(Component)]
+    pub struct RotateStreamButton;
+    #[derive(Component)]
+    pub struct MirrorStreamButton;
+    #[derive(Component)]
+    pub struct VideoQualityButton;
+    #[derive(Component)]
+    pub struct LowBandwidthButton;
+    #[derive(Component)]
+    pub struct PrivacyButton;
+    #[derive(Component)]
+    pub struct SendClipboardButton;
 }
 
 #[derive(Event)]
@@ -64,15 +373,22 @@ pub struct FindHostsEvent;
 #[derive(Component)]
 struct UpdateHosts(Task<CommandQueue>);
 
+/// The round trip times of `AvailableHosts` were measured again
+#[derive(Event)]
+struct HostLatenciesMeasured;
+
 /**************************************/
 /************* SYSTEMS ****************/
 /**************************************/
 
-fn update_available_hosts_system(mut commands: Commands) {
+fn update_available_hosts_system(mut commands: Commands, call: Res<CallManagerBevy>) {
     let task_pool = AsyncComputeTaskPool::get();
     let entity = commands.spawn_empty().id();
+    let services = call.0.discovery_services().to_vec();
+    let beacon_port = call.0.beacon_port();
+    let static_peers = call.0.static_peers().to_vec();
     let task = task_pool.spawn(async move {
-        let hosts = mdns::find_all_hosts();
+        let hosts = find_hosts(&services, beacon_port, &static_peers);
         info!("{:?}", hosts);
         let mut command_queue = CommandQueue::default();
         command_queue.push(move |world: &mut World| {
@@ -95,34 +411,149 @@ fn handle_tasks(mut commands: Commands, mut transform_tasks: Query<(Entity, &mut
     }
 }
 
+/// Measure the round trip times to the hosts found again, unless they're being found.
+/// The list isn't rebuilt, so the keyboard focus stays on its button
+fn refresh_host_latencies(
+    mut commands: Commands,
+    available_hosts: Res<AvailableHosts>,
+    tasks: Query<(), With<UpdateHosts>>,
+) {
+    if available_hosts.is_empty() || !tasks.is_empty() {
+        return;
+    }
+    let mut hosts = available_hosts.0.clone();
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        for host in &mut hosts {
+            host.measure();
+        }
+        let mut command_queue = CommandQueue::default();
+        command_queue.push(move |world: &mut World| {
+            if let Some(mut available_hosts) = world.get_resource_mut::<AvailableHosts>() {
+                for host in available_hosts.bypass_change_detection().iter_mut() {
+                    if let Some(measured) = hosts.iter().find(|measured| measured.peer == host.peer)
+                    {
+                        host.paths.clone_from(&measured.paths);
+                    }
+                }
+            }
+            world.send_event(HostLatenciesMeasured);
+        });
+        command_queue
+    });
+    commands.spawn(UpdateHosts(task));
+}
+
+fn update_host_latencies(
+    available_hosts: Res<AvailableHosts>,
+    buttons: Query<(&HostButton, &Children)>,
+    mut texts: Query<&mut Text>,
+) {
+    for (button, children) in &buttons {
+        let Some(path) = available_hosts
+            .iter()
+            .flat_map(|host| &host.paths)
+            .find(|path| path.addr == button.addr)
+        else {
+            continue;
+        };
+        let mut iter = texts.iter_many_mut(children);
+        while let Some(mut text) = iter.fetch_next() {
+            text.sections[0].value = describe_path(&button.label, path.rtt);
+        }
+    }
+}
+
+/// Label of the host in the list, i.e. "kitchen.local. (640x480 at 30 fps)"
+pub(crate) fn describe_host(name: &str, capabilities: Option<&PeerCapabilities>) -> String {
+    match capabilities {
+        None => tr!("host_no_answer", name = name),
+        Some(capabilities) if !capabilities.compatible() => tr!("host_incompatible", name = name),
+        Some(capabilities) if capabilities.busy => tr!("host_busy", name = name),
+        Some(capabilities) => match capabilities.best_mode() {
+            Some(mode) => tr!("host_mode", name = name, mode = mode),
+            None => tr!("host_no_camera", name = name),
+        },
+    }
+}
+
+/// The label of a host with the round trip time to it, i.e. "kitchen.local. (busy) - 3 ms"
+pub(crate) fn describe_path(label: &str, rtt: Option<Duration>) -> String {
+    match rtt {
+        Some(rtt) => tr!("path_rtt", label = label, ms = rtt.as_millis()),
+        None => tr!("path_unreachable", label = label),
+    }
+}
+
 fn update_host_list(
     mut commands: Commands,
     ui_containers: Res<UiContainers>,
     available_hosts: Res<AvailableHosts>,
+    call: Res<CallManagerBevy>,
     mut spawner: UiSpawner,
 ) {
     if let Some(mut list) = commands.get_entity(ui_containers.host_bar) {
         list.despawn_descendants();
         for host in &available_hosts.0 {
-            let mut btn = spawner.spawn_pretty_button_with_text(host.get_hostname(), 32.);
-            if let Some(ip_addr) = host.get_addresses_v4().iter().next() {
-                btn.insert(HostButton(IpAddr::V4(**ip_addr)));
+            let label = host.label();
+            if host.paths.is_empty() {
+                let btn = spawner.spawn_pretty_button_with_text(&label, 32.);
+                list.add_child(btn.id());
+            }
+            // A button for every address, so the user can pick the path
+            for path in &host.paths {
+                let label = match host.paths.len() {
+                    1 => label.clone(),
+                    _ => tr!("host_via", label = label, ip = path.addr.ip()),
+                };
+                let mut btn =
+                    spawner.spawn_pretty_button_with_text(&describe_path(&label, path.rtt), 32.);
+                btn.insert(HostButton {
+                    addr: path.addr,
+                    capabilities: host.capabilities.clone(),
+                    label,
+                });
+                list.add_child(btn.id());
             }
-            list.add_child(btn.id());
         }
         let mut btn = spawner.spawn_pretty_button_with_text("127.0.0.1", 32.);
-        btn.insert(HostButton(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        btn.insert(HostButton {
+            addr: SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                call.0.scp().local_addr().port(),
+            ),
+            capabilities: None,
+            label: "127.0.0.1".to_string(),
+        });
         list.add_child(btn.id());
     }
 }
 
-/// Spawns a task to try and connect. It will change the state to connecting, and at the end will
-/// ConnectionEvent or return the state to off
-fn on_host_button_click(query: Query<(&Interaction, &HostButton), Changed<Interaction>>) {
-    for (interaction, addr) in &query {
-        if interaction == &Interaction::Pressed {
-            // Start streaming video to this host
+/// Calls the host, or hands the ongoing call over to it, i.e. another device of the user.
+/// Busy and incompatible hosts aren't called, they'd refuse anyway
+fn on_host_button_click(
+    query: Query<(&Interaction, &HostButton), Changed<Interaction>>,
+    mut call: ResMut<CallManagerBevy>,
+    mut calling: ResMut<CallingPeer>,
+    mut scp_state: ResMut<NextState<ScpConnectionState>>,
+) {
+    for (interaction, host) in &query {
+        if interaction != &Interaction::Pressed {
+            continue;
+        }
+        if call.0.session().is_some() {
+            call.0.transfer(host.addr);
+            continue;
+        }
+        match host.capabilities {
+            Some(ref capabilities) if capabilities.busy || !capabilities.compatible() => {
+                warn!("Not calling {}, it's busy or incompatible", host.addr);
+                continue;
+            }
+            Some(ref capabilities) => call.0.call_probed(host.addr, capabilities),
+            None => call.0.call(host.addr),
         }
+        calling.0.clone_from(&host.label);
+        scp_state.set(ScpConnectionState::Connecting);
     }
 }
 
@@ -151,3 +582,508 @@ fn check_find_hosts_button(
         writer.send(FindHostsEvent);
     }
 }
+
+fn check_loopback_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<LoopbackButton>)>,
+    mut writer: EventWriter<LoopbackCallEvent>,
+) {
+    for interaction in &query {
+        if interaction != &Interaction::Pressed {
+            continue;
+        }
+        writer.send(LoopbackCallEvent);
+    }
+}
+
+fn check_privacy_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<PrivacyButton>)>,
+    mut privacy: ResMut<PrivacyMode>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            privacy.0 = !privacy.0;
+        }
+    }
+}
+fn update_privacy_button(
+    privacy: Res<PrivacyMode>,
+    buttons: Query<&Children, With<PrivacyButton>>,
+    mut texts: Query<(&mut Translated, &mut Text)>,
+) {
+    let key = match privacy.0 {
+        true => "privacy_mode_on",
+        false => "privacy_mode_off",
+    };
+    for children in &buttons {
+        let mut iter = texts.iter_many_mut(children);
+        while let Some((mut translated, mut text)) = iter.fetch_next() {
+            translated.0 = key;
+            text.sections[0].value = tr!(key);
+        }
+    }
+}
+
+fn show_outgoing_stream_errors(
+    mut events: EventReader<OutgoingStreamHealthEvent>,
+    mut query: Query<&mut Text, With<StreamErrorText>>,
+    mut toasts: EventWriter<ToastEvent>,
+) {
+    for event in events.read() {
+        if let OutgoingStreamEvent::DeviceLost(_) = &**event {
+            toasts.send(ToastEvent::warning(tr!("camera_lost")));
+        }
+        let message = match &**event {
+            OutgoingStreamEvent::CaptureError(e) => tr!("camera_error", error = e),
+            OutgoingStreamEvent::DeviceLost(_) => tr!("camera_lost"),
+            OutgoingStreamEvent::DeviceRecovered | OutgoingStreamEvent::Reconfigured(_) => {
+                String::new()
+            }
+            OutgoingStreamEvent::SendError(e) => tr!("send_error", error = e),
+            // Forwarded as `MotionEvent` instead
+            OutgoingStreamEvent::MotionStarted | OutgoingStreamEvent::MotionStopped => continue,
+        };
+        for mut text in &mut query {
+            text.sections[0].value.clone_from(&message);
+        }
+    }
+}
+
+fn clear_outgoing_stream_errors(mut query: Query<&mut Text, With<StreamErrorText>>) {
+    for mut text in &mut query {
+        text.sections[0].value.clear();
+    }
+}
+fn show_fingerprint(
+    call: Res<CallManagerBevy>,
+    mut query: Query<&mut Text, Added<FingerprintText>>,
+) {
+    for mut text in &mut query {
+        let fingerprint = call.0.scp().identity().fingerprint();
+        text.sections[0].value = tr!("fingerprint", fingerprint = fingerprint);
+    }
+}
+#[allow(clippy::type_complexity)]
+fn check_incoming_call_buttons(
+    accept: Query<&Interaction, (Changed<Interaction>, With<AcceptConnectionButton>)>,
+    reject: Query<&Interaction, (Changed<Interaction>, With<RejectConnectionButton>)>,
+    block: Query<&Interaction, (Changed<Interaction>, With<BlockCallerButton>)>,
+    mut call: ResMut<CallManagerBevy>,
+    mut changed: EventWriter<PeerListChangedEvent>,
+) {
+    let pressed = |interaction: &Interaction| interaction == &Interaction::Pressed;
+    if accept.iter().any(pressed) {
+        call.0.accept();
+    } else if reject.iter().any(pressed) {
+        call.0.refuse();
+    } else if block.iter().any(pressed) {
+        call.0.block();
+        changed.send(PeerListChangedEvent);
+    }
+}
+/// Shows the prompt while a call rings, the call manager knows best when it stops
+fn update_incoming_call_prompt(
+    call: Res<CallManagerBevy>,
+    mut prompt: Query<&mut Style, With<IncomingCallPrompt>>,
+    mut text: Query<&mut Text, With<IncomingCallText>>,
+) {
+    let ringing = call.0.ringing();
+    let display = if ringing.is_some() {
+        Display::Flex
+    } else {
+        Display::None
+    };
+    // Nothing to do until the call is answered or given up
+    if prompt.iter().all(|style| style.display == display) {
+        return;
+    }
+    for mut style in &mut prompt {
+        style.display = display;
+    }
+    let Some((ip, _)) = ringing else {
+        return;
+    };
+    let message = tr!("calling", ip = ip);
+    for mut text in &mut text {
+        text.sections[0].value.clone_from(&message);
+    }
+}
+/// The user compared the fingerprints with the peer, its changed key is trusted from now on
+fn check_trust_peer_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<TrustPeerButton>)>,
+    mut call: ResMut<CallManagerBevy>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            call.0.trust_peer();
+        }
+    }
+}
+/// Polled, the peer calling or called is known only to the call manager.
+/// The trust button is there only for a changed key
+fn update_peer_trust(
+    call: Res<CallManagerBevy>,
+    theme: Res<Theme>,
+    mut shown: Local<Option<(String, bool)>>,
+    mut texts: Query<&mut Text, With<PeerTrustText>>,
+    mut buttons: Query<&mut Style, With<TrustPeerButton>>,
+) {
+    // None until the peer called answers
+    let peer = call
+        .0
+        .ringing()
+        .or(call.0.called())
+        .map(|(_, identity)| *identity);
+    let (message, color) = match peer {
+        None => (String::new(), theme.text()),
+        Some(None) => (tr!("peer_unverified"), color_palette::WARNING),
+        Some(Some(identity)) => {
+            let (trust, color) = match identity.trust {
+                PeerTrust::New => (tr!("peer_new"), theme.text()),
+                PeerTrust::Known => (tr!("peer_known"), theme.text()),
+                PeerTrust::Changed => (tr!("peer_changed"), color_palette::WARNING),
+            };
+            let fingerprint = tr!("fingerprint", fingerprint = identity.fingerprint());
+            (format!("{fingerprint}\n{trust}"), color)
+        }
+    };
+    let changed = peer
+        .flatten()
+        .is_some_and(|identity| identity.trust == PeerTrust::Changed);
+    let state = (message, changed);
+    if shown.as_ref() == Some(&state) && !theme.is_changed() {
+        return;
+    }
+    for mut text in &mut texts {
+        let section = &mut text.sections[0];
+        section.value.clone_from(&state.0);
+        section.style.color = color;
+    }
+    for mut style in &mut buttons {
+        style.display = if changed {
+            Display::Flex
+        } else {
+            Display::None
+        };
+    }
+    *shown = Some(state);
+}
+/// Shows the prompt from the call until it's answered or given up, the state tells which
+fn update_outgoing_call_prompt(
+    scp_state: Res<State<ScpConnectionState>>,
+    calling: Res<CallingPeer>,
+    mut prompt: Query<&mut Style, With<OutgoingCallPrompt>>,
+    mut text: Query<&mut Text, With<OutgoingCallText>>,
+) {
+    let message = match scp_state.get() {
+        ScpConnectionState::Connecting => Some(tr!("calling_peer", name = calling.0)),
+        ScpConnectionState::Ringing => Some(tr!("ringing_peer", name = calling.0)),
+        ScpConnectionState::Off | ScpConnectionState::Connected => None,
+    };
+    for mut style in &mut prompt {
+        style.display = match message {
+            Some(_) => Display::Flex,
+            None => Display::None,
+        };
+    }
+    let Some(message) = message else {
+        return;
+    };
+    for mut text in &mut text {
+        text.sections[0].value.clone_from(&message);
+    }
+}
+/// The peer stops ringing, `ScpConnectionState` goes back to `Off` once it's given up
+fn check_cancel_call_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<CancelCallButton>)>,
+    call: Res<CallManagerBevy>,
+) {
+    for interaction in &query {
+        if interaction == &Interaction::Pressed {
+            call.0.cancel();
+        }
+    }
+}
+/// Polled, the stream thread opens and closes the camera on its own.
+/// The camera still open in privacy mode is about to be closed, it's told as it is
+fn show_camera_in_use(
+    call: Res<CallManagerBevy>,
+    privacy: Res<PrivacyMode>,
+    mut shown: Local<Option<(bool, bool)>>,
+    mut indicators: Query<(&mut Style, &Children), With<CameraInUseIndicator>>,
+    mut texts: Query<(&mut Translated, &mut Text)>,
+    mut dots: Query<&mut BackgroundColor, Without<Text>>,
+) {
+    let in_use = call.0.outgoing().camera_in_use();
+    if *shown == Some((in_use, privacy.0)) {
+        return;
+    }
+    *shown = Some((in_use, privacy.0));
+    let (key, color) = match in_use {
+        true => ("camera_in_use", color_palette::RECORDING),
+        false => ("camera_closed", color_palette::CLOSED),
+    };
+    for (mut style, children) in &mut indicators {
+        style.display = match in_use || privacy.0 {
+            true => Display::Flex,
+            false => Display::None,
+        };
+        let mut iter = texts.iter_many_mut(children);
+        while let Some((mut translated, mut text)) = iter.fetch_next() {
+            translated.0 = key;
+            text.sections[0].value = tr!(key);
+        }
+        let mut iter = dots.iter_many_mut(children);
+        while let Some(mut dot) = iter.fetch_next() {
+            dot.0 = color;
+        }
+    }
+}
+/// Shown again whenever the call manager changes, its name may be another one
+fn show_invite(
+    call: Res<CallManagerBevy>,
+    added: Query<(), Added<InviteText>>,
+    mut query: Query<&mut Text, With<InviteText>>,
+) {
+    if !call.is_changed() && added.is_empty() {
+        return;
+    }
+    for mut text in &mut query {
+        text.sections[0].value = call.0.invite().to_string();
+    }
+}
+fn check_copy_invite_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<CopyInviteButton>)>,
+    call: Res<CallManagerBevy>,
+    mut clipboard: NonSendMut<AppClipboard>,
+) {
+    for interaction in &query {
+        if interaction != &Interaction::Pressed {
+            continue;
+        }
+        let invite = call.0.invite().to_string();
+        let copied = clipboard.0.as_mut().map(|c| c.set_text(invite.clone()));
+        match copied {
+            Some(Ok(())) => info!("Invite copied: {invite}"),
+            // It can still be copied from the log
+            Some(Err(e)) => warn!("Cannot copy the invite {invite}: {e}"),
+            None => warn!("No clipboard to copy the invite {invite} to"),
+        }
+    }
+}
+/// Calls what's in the dial field, once the button is pressed or Enter is
+fn check_dial_button(
+    query: Query<&Interaction, (Changed<Interaction>, With<DialButton>)>,
+    mut submitted: EventReader<TextInputSubmitted>,
+    mut fields: Query<(Entity, &mut TextInput), With<DialField>>,
+    mut focus: ResMut<TextInputFocus>,
+    mut writer: EventWriter<DialEvent>,
+) {
+    let Ok((field, mut input)) = fields.get_single_mut() else {
+        return;
+    };
+    let pressed = query
+        .iter()
+        .any(|interaction| interaction == &Interaction::Pressed);
+    if !pressed && !submitted.read().any(|event| event.0 == field) {
+        return;
+    }
+    if let Some(invite) = dial_input(input.text()) {
+        writer.send(DialEvent(invite));
+        input.clear();
+        if focus.0 == Some(field) {
+            focus.0 = None;
+        }
+    }
+}
+/// The invite in the dial field, or a bare `ip:port`. None if it's neither
+fn dial_input(text: &str) -> Option<Invite> {
+    let text = text.trim();
+    let invite = if Invite::is_invite(text) {
+        text.parse()
+    } else {
+        text.parse::<SocketAddr>()
+            .map(|addr| Invite { addr, name: None })
+            .map_err(Into::into)
+    };
+    invite
+        .inspect_err(|e| warn!("Cannot call {text}: {e}"))
+        .ok()
+}
+/// Tab or Down and Right move the focus through the buttons of the focused window in reading order,
+/// Shift+Tab or Up and Left back. Enter or Space presses the focused one for a frame, as if it was clicked
+#[allow(clippy::too_many_arguments)]
+fn keyboard_navigation(
+    keys: Res<ButtonInput<KeyCode>>,
+    typing: Res<TextInputFocus>,
+    buttons: Query<(Entity, &Node, &GlobalTransform), With<Button>>,
+    sliders: Query<(), With<Slider>>,
+    windows: WindowFocus,
+    mut interactions: Query<&mut Interaction>,
+    mut focus: ResMut<KeyboardFocus>,
+    mut pressed: Local<Option<Entity>>,
+) {
+    if let Some(entity) = pressed.take() {
+        if let Ok(mut interaction) = interactions.get_mut(entity) {
+            interaction.set_if_neq(Interaction::None);
+        }
+    }
+    // The keys go to the field then, the Enter or Escape leaving it included
+    if typing.is_typing() || typing.is_changed() {
+        return;
+    }
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let on_slider = focus.0.is_some_and(|focused| sliders.contains(focused));
+    let step = if keys.just_pressed(KeyCode::Tab) {
+        Some(shift)
+    } else if keys.just_pressed(KeyCode::ArrowDown)
+        || keys.just_pressed(KeyCode::ArrowRight) && !on_slider
+    {
+        Some(false)
+    } else if keys.just_pressed(KeyCode::ArrowUp)
+        || keys.just_pressed(KeyCode::ArrowLeft) && !on_slider
+    {
+        Some(true)
+    } else {
+        None
+    };
+    if let Some(backwards) = step {
+        // Hidden ones have no size, the ones of a panel popped out are laid out in its window
+        let mut visible: Vec<_> = buttons
+            .iter()
+            .filter(|(entity, node, _)| node.size() != Vec2::ZERO && windows.is_focused(*entity))
+            .map(|(entity, _, transform)| (entity, transform.translation()))
+            .collect();
+        visible.sort_by(|(_, a), (_, b)| a.y.total_cmp(&b.y).then(a.x.total_cmp(&b.x)));
+        let current = focus
+            .0
+            .and_then(|focused| visible.iter().position(|(entity, _)| *entity == focused));
+        let next = match (current, backwards) {
+            _ if visible.is_empty() => None,
+            (Some(i), false) => Some((i + 1) % visible.len()),
+            (Some(i), true) => Some((i + visible.len() - 1) % visible.len()),
+            (None, false) => Some(0),
+            (None, true) => Some(visible.len() - 1),
+        };
+        focus.0 = next.map(|i| visible[i].0);
+    }
+    if keys.any_just_pressed([KeyCode::Enter, KeyCode::NumpadEnter, KeyCode::Space]) {
+        let Some(focused) = focus.0 else {
+            return;
+        };
+        match interactions.get_mut(focused) {
+            Ok(mut interaction) => {
+                *interaction = Interaction::Pressed;
+                *pressed = Some(focused);
+            }
+            // Despawned, i.e. a removed peer
+            Err(_) => focus.0 = None,
+        }
+    }
+}
+/// Escape cancels the call being placed, or else hangs up the ongoing one, like the buttons.
+/// Out of calls it drops the keyboard focus
+#[allow(clippy::too_many_arguments)]
+fn escape_call(
+    keys: Res<ButtonInput<KeyCode>>,
+    typing: Res<TextInputFocus>,
+    scp_state: Res<State<ScpConnectionState>>,
+    stream_in: Res<State<IncomingVideoStreamState>>,
+    stream_out: Res<State<OutgoingVideoStreamState>>,
+    call: Res<CallManagerBevy>,
+    mut stream_in_state: ResMut<NextState<IncomingVideoStreamState>>,
+    mut stream_out_state: ResMut<NextState<OutgoingVideoStreamState>>,
+    mut focus: ResMut<KeyboardFocus>,
+) {
+    // It leaves the field then
+    if !keys.just_pressed(KeyCode::Escape) || typing.is_typing() || typing.is_changed() {
+        return;
+    }
+    match scp_state.get() {
+        ScpConnectionState::Connecting | ScpConnectionState::Ringing => call.0.cancel(),
+        _ if *stream_in.get() != IncomingVideoStreamState::Off
+            || *stream_out.get() != OutgoingVideoStreamState::Off =>
+        {
+            stream_in_state.set(IncomingVideoStreamState::Off);
+            stream_out_state.set(OutgoingVideoStreamState::Off);
+        }
+        _ => focus.0 = None,
+    }
+}
+fn show_keyboard_focus(
+    focus: Res<KeyboardFocus>,
+    theme: Res<Theme>,
+    buttons: Query<(), With<Button>>,
+    mut commands: Commands,
+    mut previous: Local<Option<Entity>>,
+) {
+    if let Some(entity) = previous.filter(|entity| buttons.contains(*entity)) {
+        commands.entity(entity).remove::<Outline>();
+    }
+    if let Some(entity) = focus.0.filter(|entity| buttons.contains(*entity)) {
+        commands
+            .entity(entity)
+            .insert(Outline::new(FOCUS_RING, Val::Px(2.), theme.focus()));
+    }
+    *previous = focus.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use scp_client::client::{VideoEncoding, VideoMode};
+    use scp_client::probe::PeerCapabilities;
+    use scp_client::scp::PROTOCOL_VERSION;
+
+    use super::{describe_host, describe_path, sort_paths, HostPath};
+
+    #[test]
+    fn test_describe_host() {
+        let mut capabilities = PeerCapabilities {
+            protocol_version: PROTOCOL_VERSION,
+            video_encodings: vec![VideoEncoding::H264],
+            audio_encodings: Vec::new(),
+            video_modes: vec![VideoMode {
+                width: 640,
+                height: 480,
+                fps: 30,
+            }],
+            busy: false,
+        };
+        assert_eq!(
+            describe_host("kitchen", Some(&capabilities)),
+            "kitchen (640x480 at 30 fps)"
+        );
+        capabilities.busy = true;
+        assert_eq!(
+            describe_host("kitchen", Some(&capabilities)),
+            "kitchen (busy)"
+        );
+        capabilities.protocol_version += 1;
+        assert_eq!(
+            describe_host("kitchen", Some(&capabilities)),
+            "kitchen (incompatible)"
+        );
+        assert_eq!(describe_host("kitchen", None), "kitchen (no answer)");
+    }
+    #[test]
+    fn test_paths() {
+        let path = |port, rtt: Option<u64>| HostPath {
+            addr: SocketAddr::from(([192, 168, 1, 12], port)),
+            rtt: rtt.map(Duration::from_millis),
+        };
+        let mut paths = [path(1, None), path(2, Some(40)), path(3, Some(3))];
+        sort_paths(&mut paths);
+        assert_eq!(paths, [path(3, Some(3)), path(2, Some(40)), path(1, None)]);
+
+        assert_eq!(
+            describe_path("kitchen (busy)", paths[0].rtt),
+            "kitchen (busy) - 3 ms"
+        );
+        assert_eq!(
+            describe_path("kitchen (busy)", None),
+            "kitchen (busy) - unreachable"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! The incoming streams laid out in a grid, a `VideoTile` for every remote peer.
+//! Calls have a single peer for now, whose tile is always there and shows the placeholder
+//! outside of calls, see `UiContainers::stream_window`. The grid takes 1 to 4 tiles,
+//! see `grid_shape`, and is sized by `stream_view` after the stream of the peer.
+//! Every tile is labeled with the name of its peer, and whether it's muted.
+
+use bevy::prelude::*;
+use scp_client::client::SessionConfig;
+
+use crate::connection_state_bevy::{
+    CallingPeer, ConnectionEvent, IncomingVideoStreamState, LoopbackCallEvent,
+};
+use crate::locale::{tr, Language};
+use crate::ui::{color_palette, UiContainers, UiSpawner};
+
+/// More peers than that don't fit, their tiles are hidden
+pub const MAX_TILES: usize = 4;
+const TILE_FONT_SIZE: f32 = 24.;
+
+pub struct VideoTilePlugin;
+
+impl Plugin for VideoTilePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                on_connection.run_if(on_event::<ConnectionEvent>()),
+                on_loopback_call.run_if(on_event::<LoopbackCallEvent>()),
+                layout_video_tiles,
+                update_tile_labels,
+            )
+                .chain()
+                .run_if(resource_exists::<UiContainers>),
+        );
+        app.add_systems(OnEnter(IncomingVideoStreamState::Off), clear_tiles);
+    }
+}
+
+/// Holds the tiles, in rows of up to 2
+#[derive(Component)]
+pub struct VideoGrid;
+
+/// The stream of a remote peer
+/// * `label` - who it is, empty while nobody's there
+/// * `muted` - no audio comes from the peer
+#[derive(Component, Debug, Default, Clone, PartialEq, Eq)]
+pub struct VideoTile {
+    pub label: String,
+    pub muted: bool,
+}
+
+/// Marker for the box naming the peer of the tile, hidden while nobody's there
+#[derive(Component)]
+pub struct VideoTileLabel;
+
+/// Columns and rows of the grid for `count` tiles, wider than tall when they don't fill it
+pub fn grid_shape(count: usize) -> UVec2 {
+    match count.min(MAX_TILES) {
+        0 | 1 => UVec2::new(1, 1),
+        2 => UVec2::new(2, 1),
+        _ => UVec2::new(2, 2),
+    }
+}
+
+/// Spawns an empty tile showing `image`, to be added to the `VideoGrid`.
+/// Returns the tile and the node of the image, which is turned and scaled with the stream
+pub fn spawn_video_tile(spawner: &mut UiSpawner, image: Handle<Image>) -> (Entity, Entity) {
+    let stream = spawner
+        .commands
+        .spawn(NodeBundle {
+            style: Style {
+                width: Val::Percent(100.),
+                height: Val::Percent(100.),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(UiImage::new(image).with_flip_x())
+        .id();
+    // Over the stream whatever the theme, like the status of the stream
+    let label = spawner
+        .commands
+        .spawn(TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: TILE_FONT_SIZE,
+                font: spawner.ui_elements.font.clone(),
+                color: color_palette::WHITE,
+            },
+        ))
+        .id();
+    let label_box = spawner
+        .commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    display: Display::None,
+                    position_type: PositionType::Absolute,
+                    left: Val::Px(0.),
+                    bottom: Val::Px(0.),
+                    padding: UiRect::all(Val::Px(5.)),
+                    ..Default::default()
+                },
+                background_color: BackgroundColor(color_palette::DIM),
+                z_index: ZIndex::Local(1),
+                ..Default::default()
+            },
+            VideoTileLabel,
+        ))
+        .add_child(label)
+        .id();
+    let tile = spawner
+        .commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    border: UiRect::all(Val::Px(5.)),
+                    ..Default::default()
+                },
+                border_color: BorderColor(color_palette::BLACK),
+                ..Default::default()
+            },
+            VideoTile::default(),
+        ))
+        .push_children(&[stream, label_box])
+        .id();
+    (tile, stream)
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+/// Every tile gets an equal share of the grid, the ones that don't fit are hidden
+fn layout_video_tiles(
+    grids: Query<&Children, With<VideoGrid>>,
+    added: Query<(), Added<VideoTile>>,
+    mut removed: RemovedComponents<VideoTile>,
+    mut tiles: Query<&mut Style, With<VideoTile>>,
+) {
+    if added.is_empty() && removed.read().next().is_none() {
+        return;
+    }
+    for children in &grids {
+        let count = tiles.iter_many(children).count();
+        let shape = grid_shape(count);
+        let mut iter = tiles.iter_many_mut(children);
+        let mut i = 0;
+        while let Some(mut style) = iter.fetch_next() {
+            style.display = if i < MAX_TILES {
+                Display::Flex
+            } else {
+                Display::None
+            };
+            style.width = Val::Percent(100. / shape.x as f32);
+            style.height = Val::Percent(100. / shape.y as f32);
+            i += 1;
+        }
+    }
+}
+fn update_tile_labels(
+    language: Res<Language>,
+    tiles: Query<(Ref<VideoTile>, &Children)>,
+    mut boxes: Query<(&mut Style, &Children), With<VideoTileLabel>>,
+    mut texts: Query<&mut Text>,
+) {
+    for (tile, children) in &tiles {
+        if !tile.is_changed() && !language.is_changed() {
+            continue;
+        }
+        let label = match tile.muted {
+            true => tr!("tile_muted", name = tile.label),
+            false => tile.label.clone(),
+        };
+        let mut iter = boxes.iter_many_mut(children);
+        while let Some((mut style, children)) = iter.fetch_next() {
+            style.display = match tile.label.is_empty() {
+                true => Display::None,
+                false => Display::Flex,
+            };
+            let mut texts = texts.iter_many_mut(children);
+            while let Some(mut text) = texts.fetch_next() {
+                text.sections[0].value.clone_from(&label);
+            }
+        }
+    }
+}
+/// Labels the tile of the peer, by the name it was called by, or else its address
+fn on_connection(
+    mut events: EventReader<ConnectionEvent>,
+    calling: Res<CallingPeer>,
+    containers: Res<UiContainers>,
+    mut tiles: Query<&mut VideoTile>,
+) {
+    for ConnectionEvent(config) in events.read() {
+        let Ok(mut tile) = tiles.get_mut(containers.video_tile) else {
+            continue;
+        };
+        tile.set_if_neq(tile_of(config, &calling.0));
+    }
+}
+fn tile_of(config: &SessionConfig, called: &str) -> VideoTile {
+    VideoTile {
+        label: match called {
+            "" => config.ip.to_string(),
+            name => name.to_string(),
+        },
+        muted: config.codecs.audio.is_none(),
+    }
+}
+fn on_loopback_call(containers: Res<UiContainers>, mut tiles: Query<&mut VideoTile>) {
+    if let Ok(mut tile) = tiles.get_mut(containers.video_tile) {
+        tile.label = tr!("tile_loopback");
+        tile.muted = false;
+    }
+}
+fn clear_tiles(mut tiles: Query<&mut VideoTile>) {
+    for mut tile in &mut tiles {
+        tile.set_if_neq(VideoTile::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::UVec2;
+
+    use super::grid_shape;
+
+    #[test]
+    fn test_grid_shape() {
+        assert_eq!(grid_shape(0), UVec2::new(1, 1));
+        assert_eq!(grid_shape(1), UVec2::new(1, 1));
+        assert_eq!(grid_shape(2), UVec2::new(2, 1));
+        assert_eq!(grid_shape(3), UVec2::new(2, 2));
+        assert_eq!(grid_shape(4), UVec2::new(2, 2));
+        // The rest is hidden
+        assert_eq!(grid_shape(7), UVec2::new(2, 2));
+    }
+}
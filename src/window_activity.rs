@@ -0,0 +1,112 @@
+//! Saves CPU while nobody looks at the app.
+//! The outgoing stream is throttled when no window of the app is focused or the main one
+//! is minimized, and the incoming frames aren't uploaded to the GPU while minimized.
+//! While minimized the peer is also asked for its reduced stream.
+//! Everything goes back to full rate once the window is back.
+
+use bevy::prelude::*;
+use bevy::window::{PrimaryWindow, WindowFocused, WindowOccluded};
+
+use scp_client::scp::ControlMessage;
+
+use crate::connection_state_bevy::SendControlEvent;
+use crate::plugin::CallManagerBevy;
+
+/// Frame rate of the outgoing stream while the window is unfocused or minimized
+const THROTTLED_FPS: u32 = 5;
+
+pub struct WindowActivityPlugin;
+
+impl Plugin for WindowActivityPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WindowActivity>();
+        app.add_systems(
+            PreUpdate,
+            (
+                track_window_activity,
+                (throttle_outgoing_stream, request_reduced_stream),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// What the user can see of the app
+/// * `focused` - a window has the keyboard focus, a panel popped out included
+/// * `minimized` - the main window is completely hidden, i.e. minimized
+#[derive(Resource, Debug)]
+pub struct WindowActivity {
+    pub focused: bool,
+    pub minimized: bool,
+}
+
+impl Default for WindowActivity {
+    fn default() -> Self {
+        Self {
+            focused: true,
+            minimized: false,
+        }
+    }
+}
+
+impl WindowActivity {
+    fn is_throttled(&self) -> bool {
+        !self.focused || self.minimized
+    }
+}
+
+/// Run condition: the window can be seen, so it's worth rendering the incoming stream
+pub fn window_visible(activity: Res<WindowActivity>) -> bool {
+    !activity.minimized
+}
+
+/**************************************/
+/************* SYSTEMS ****************/
+/**************************************/
+
+fn track_window_activity(
+    mut focused: EventReader<WindowFocused>,
+    mut occluded: EventReader<WindowOccluded>,
+    windows: Query<&Window>,
+    primary: Query<Entity, With<PrimaryWindow>>,
+    mut activity: ResMut<WindowActivity>,
+) {
+    // The focus moving to a panel popped out leaves the app focused
+    if focused.read().count() > 0 {
+        activity.focused = windows.iter().any(|window| window.focused);
+    }
+    // Only the latest state of the main window matters, the stream is shown there
+    if let Some(event) = occluded
+        .read()
+        .filter(|event| primary.contains(event.window))
+        .last()
+    {
+        activity.minimized = event.occluded;
+    }
+}
+
+fn throttle_outgoing_stream(activity: Res<WindowActivity>, call: Res<CallManagerBevy>) {
+    if !activity.is_changed() {
+        return;
+    }
+    if activity.is_throttled() {
+        debug!("Window inactive, throttling the outgoing stream to {THROTTLED_FPS} fps");
+        call.0.outgoing().set_max_fps(Some(THROTTLED_FPS));
+    } else {
+        debug!("Window active, outgoing stream back to full rate");
+        call.0.outgoing().set_max_fps(None);
+    }
+}
+/// Nothing of the incoming stream is shown while minimized, so the full one is wasted
+fn request_reduced_stream(
+    activity: Res<WindowActivity>,
+    mut requested: Local<bool>,
+    mut writer: EventWriter<SendControlEvent>,
+) {
+    if activity.minimized != *requested {
+        *requested = activity.minimized;
+        writer.send(SendControlEvent(ControlMessage::LowResolution(
+            activity.minimized,
+        )));
+    }
+}